@@ -0,0 +1,27 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+
+#[path = "../../src/parse.rs"]
+#[allow(dead_code)]
+mod parse;
+
+fuzz_target!(|data: &[u8]| {
+    let text = String::from_utf8_lossy(data);
+
+    if let Some(value) = parse::first_value(&text) {
+        assert!(!value.is_empty());
+        assert_eq!(value, value.trim());
+        assert!(!value.contains('\n'));
+    }
+
+    for device in parse::blkid_export(&text) {
+        assert!(!device.is_empty());
+        assert!(device.iter().all(|(key, _)| !key.contains('=') && !key.contains('\n')));
+    }
+
+    for device in parse::blkid_lines(&text) {
+        assert_eq!(device[0].0, "DEVNAME");
+        assert!(device.iter().all(|(_, value)| !value.contains('"') && !value.contains('\n')));
+    }
+});
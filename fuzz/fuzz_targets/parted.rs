@@ -0,0 +1,22 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+
+#[path = "../../src/parse.rs"]
+#[allow(dead_code)]
+mod parse;
+
+fuzz_target!(|data: &[u8]| {
+    let text = String::from_utf8_lossy(data);
+
+    let _ = parse::parted_disk_size_bytes(&text);
+    let _ = parse::parted_machine_label(&text);
+    let _ = parse::parted_extended(&text);
+
+    for partition in parse::parted_partitions(&text) {
+        // Sizes only come from whole-unit fields, never from a partial parse
+        if let (Some(start), Some(end)) = (partition.start, partition.end) {
+            let _ = end.checked_sub(start);
+        }
+    }
+});
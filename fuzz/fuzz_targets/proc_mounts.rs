@@ -0,0 +1,17 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+
+#[path = "../../src/parse.rs"]
+#[allow(dead_code)]
+mod parse;
+
+fuzz_target!(|data: &[u8]| {
+    let text = String::from_utf8_lossy(data);
+
+    for entry in parse::proc_mounts(&text) {
+        // Whitespace-split fields are never empty, and unescaping keeps at least one byte
+        assert!(!entry.source.is_empty());
+        assert!(!entry.target.is_empty());
+    }
+});
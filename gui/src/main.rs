@@ -0,0 +1,340 @@
+//! Desktop frontend for rpi-fs-shrink: pick a disk, choose partition sizes, watch progress.
+//!
+//! Dialogs are drawn by zenity, which ships with the Raspberry Pi OS desktop and most
+//! GNOME desktops. The partitioning itself is left to the rpi-fs-shrink binary: its
+//! `plan` subcommand previews the layout as the desktop user, and the run is started
+//! through pkexec so polkit asks for the password. Its size limits, SD card rule
+//! and step count come from the crpart library the binary is built with.
+
+use anyhow::{bail, Context, Result};
+use std::io::{BufRead, BufReader, Read, Write};
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+
+use crpart::{MAX_ROOT_SIZE_GB, MIN_ROOT_SIZE_GB};
+
+const TITLE: &str = "Raspberry Pi Partitioning";
+
+struct Disk {
+    path: String,
+    size_bytes: u64,
+    model: String,
+}
+
+impl Disk {
+    fn size_gb(&self) -> u64 {
+        self.size_bytes / (1024 * 1024 * 1024)
+    }
+
+    /// rpi-fs-shrink refuses swap and /var partitions on SD cards
+    fn is_sd_card(&self) -> bool {
+        crpart::is_sd_card(&self.path)
+    }
+}
+
+struct Sizes {
+    root_gb: u64,
+    swap_gb: u64,
+    var_gb: u64,
+}
+
+fn main() {
+    if let Err(e) = run() {
+        eprintln!("Error: {:#}", e);
+        let _ = Command::new("zenity")
+            .args(["--error", "--no-markup", &format!("--title={}", TITLE), &format!("--text={:#}", e)])
+            .status();
+        std::process::exit(1);
+    }
+}
+
+fn run() -> Result<()> {
+    if find_program("zenity").is_none() {
+        bail!("crpart-gui needs zenity to draw its dialogs");
+    }
+    let cli = cli_path()?;
+
+    let Some(disk) = pick_disk()? else {
+        return Ok(());
+    };
+    let Some(sizes) = pick_sizes(&disk)? else {
+        return Ok(());
+    };
+
+    // The plan review below is the confirmation, so old data is deleted without asking again
+    let mut args = vec![
+        "--yes".to_string(),
+        "-d".to_string(),
+        disk.path.clone(),
+        "-r".to_string(),
+        format!("{}G", sizes.root_gb),
+    ];
+    if sizes.swap_gb > 0 {
+        args.extend(["-s".to_string(), format!("{}G", sizes.swap_gb)]);
+    }
+    if sizes.var_gb > 0 {
+        args.extend(["-v".to_string(), format!("{}G", sizes.var_gb)]);
+    }
+
+    if !review_plan(&cli, &args)? {
+        return Ok(());
+    }
+    apply(&cli, &args)
+}
+
+/// Run a zenity dialog; None when the user cancels or closes it
+fn zenity(args: &[&str]) -> Result<Option<String>> {
+    let output = Command::new("zenity")
+        .args(args)
+        .arg(format!("--title={}", TITLE))
+        .output()
+        .context("Failed to run zenity")?;
+
+    if !output.status.success() {
+        return Ok(None);
+    }
+    Ok(Some(String::from_utf8_lossy(&output.stdout).trim().to_string()))
+}
+
+fn find_program(name: &str) -> Option<PathBuf> {
+    std::env::split_paths(&std::env::var_os("PATH")?)
+        .map(|dir| dir.join(name))
+        .find(|path| path.is_file())
+}
+
+/// rpi-fs-shrink next to this binary (same build or install), else from PATH.
+/// pkexec needs the absolute path.
+fn cli_path() -> Result<PathBuf> {
+    let sibling = std::env::current_exe()
+        .ok()
+        .and_then(|exe| exe.parent().map(|dir| dir.join("rpi-fs-shrink")))
+        .filter(|path| path.is_file());
+
+    sibling
+        .or_else(|| find_program("rpi-fs-shrink"))
+        .context("rpi-fs-shrink not found next to crpart-gui or in PATH")
+}
+
+/// Whole disks as lsblk sees them, skipping virtual ones
+fn list_disks() -> Result<Vec<Disk>> {
+    let output = Command::new("lsblk")
+        .args(["-J", "-b", "-d", "-o", "NAME,SIZE,MODEL,TYPE"])
+        .output()
+        .context("Failed to run lsblk")?;
+    let json: serde_json::Value = serde_json::from_slice(&output.stdout).context("Failed to parse lsblk output")?;
+
+    let mut disks = Vec::new();
+    for device in json["blockdevices"].as_array().into_iter().flatten() {
+        let name = device["name"].as_str().unwrap_or_default();
+        // Older lsblk prints sizes as strings even with -J
+        let size_bytes = device["size"]
+            .as_u64()
+            .or_else(|| device["size"].as_str().and_then(|s| s.parse().ok()))
+            .unwrap_or(0);
+        if device["type"].as_str() != Some("disk") || name.starts_with("zram") || size_bytes == 0 {
+            continue;
+        }
+        disks.push(Disk {
+            path: format!("/dev/{}", name),
+            size_bytes,
+            model: device["model"].as_str().unwrap_or_default().trim().to_string(),
+        });
+    }
+    Ok(disks)
+}
+
+fn pick_disk() -> Result<Option<Disk>> {
+    let disks = list_disks()?;
+    if disks.is_empty() {
+        bail!("No disks found; insert the SD card or USB disk to partition");
+    }
+
+    let mut args = vec![
+        "--list".to_string(),
+        "--text=Pick the disk to partition. Everything except its boot and root filesystems will be replaced.".to_string(),
+        "--column=Disk".to_string(),
+        "--column=Size".to_string(),
+        "--column=Model".to_string(),
+        "--width=560".to_string(),
+        "--height=320".to_string(),
+    ];
+    for disk in &disks {
+        args.extend([disk.path.clone(), format!("{} GB", disk.size_gb()), disk.model.clone()]);
+    }
+
+    let args: Vec<&str> = args.iter().map(String::as_str).collect();
+    let Some(choice) = zenity(&args)? else {
+        return Ok(None);
+    };
+    Ok(disks.into_iter().find(|disk| disk.path == choice))
+}
+
+fn scale(text: &str, min: u64, max: u64, value: u64) -> Result<Option<u64>> {
+    let answer = zenity(&[
+        "--scale",
+        &format!("--text={}", text),
+        &format!("--min-value={}", min),
+        &format!("--max-value={}", max),
+        &format!("--value={}", value.clamp(min, max)),
+        "--step=1",
+    ])?;
+    Ok(answer.and_then(|value| value.parse().ok()))
+}
+
+/// Ask for the partition sizes with sliders; /home gets the rest of the disk
+fn pick_sizes(disk: &Disk) -> Result<Option<Sizes>> {
+    let disk_gb = disk.size_gb();
+    // rpi-fs-shrink keeps at least half of the disk for /home
+    let max_root = MAX_ROOT_SIZE_GB.min(disk_gb / 2);
+    if max_root < MIN_ROOT_SIZE_GB {
+        bail!("{} is {} GB; at least {} GB are needed", disk.path, disk_gb, MIN_ROOT_SIZE_GB * 2);
+    }
+
+    let Some(root_gb) = scale("Root filesystem (/) size in GB", MIN_ROOT_SIZE_GB, max_root, 16)? else {
+        return Ok(None);
+    };
+
+    let (mut swap_gb, mut var_gb) = (0, 0);
+    if !disk.is_sd_card() {
+        let left = disk_gb / 2 - root_gb.min(disk_gb / 2);
+        if left > 0 {
+            let Some(swap) = scale("Swap partition size in GB (0 for none)", 0, left.min(16), 0)? else {
+                return Ok(None);
+            };
+            swap_gb = swap;
+        }
+        let left = left - swap_gb;
+        if left > 0 {
+            let Some(var) = scale("/var partition size in GB (0 for none)", 0, left, 0)? else {
+                return Ok(None);
+            };
+            var_gb = var;
+        }
+    }
+
+    Ok(Some(Sizes { root_gb, swap_gb, var_gb }))
+}
+
+/// Preview the layout with `rpi-fs-shrink plan` (no privilege needed) and ask to go ahead
+fn review_plan(cli: &Path, args: &[String]) -> Result<bool> {
+    let mut child = Command::new(cli)
+        .arg("plan")
+        .args(args)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .context(format!("Failed to run {}", cli.display()))?;
+
+    // Answer the "Press Enter to continue" prompt
+    if let Some(mut stdin) = child.stdin.take() {
+        let _ = stdin.write_all(b"\n");
+    }
+    let output = child.wait_with_output()?;
+    let mut text = String::from_utf8_lossy(&output.stdout).into_owned();
+    text.push_str(&String::from_utf8_lossy(&output.stderr));
+
+    if !output.status.success() {
+        show_text("The layout can't be applied", &text)?;
+        return Ok(false);
+    }
+
+    let mut viewer = Command::new("zenity")
+        .args([
+            "--text-info",
+            &format!("--title={}", TITLE),
+            "--width=720",
+            "--height=560",
+            "--checkbox=I have a backup of this disk. Apply the layout.",
+        ])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::null())
+        .spawn()
+        .context("Failed to run zenity")?;
+    if let Some(mut stdin) = viewer.stdin.take() {
+        let _ = stdin.write_all(text.as_bytes());
+    }
+    Ok(viewer.wait()?.success())
+}
+
+fn show_text(title: &str, text: &str) -> Result<()> {
+    let mut viewer = Command::new("zenity")
+        .args(["--text-info", &format!("--title={}", title), "--width=720", "--height=560"])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::null())
+        .spawn()
+        .context("Failed to run zenity")?;
+    if let Some(mut stdin) = viewer.stdin.take() {
+        let _ = stdin.write_all(text.as_bytes());
+    }
+    viewer.wait()?;
+    Ok(())
+}
+
+/// Number of a "Step 6b: ..." heading; other output lines have none
+fn step_number(line: &str) -> Option<u32> {
+    line.trim_start().starts_with("Step ").then(|| crpart::step_number(line)).flatten()
+}
+
+/// Run rpi-fs-shrink through pkexec, turning its step headings into a progress bar
+fn apply(cli: &Path, args: &[String]) -> Result<()> {
+    let mut child = Command::new("pkexec")
+        .arg(cli)
+        .args(args)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .context("Failed to run pkexec")?;
+
+    // The run asks twice for Enter: after showing the arguments and before writing
+    if let Some(mut stdin) = child.stdin.take() {
+        let _ = stdin.write_all(b"\n\n");
+    }
+
+    let mut stderr = child.stderr.take().context("No stderr from rpi-fs-shrink")?;
+    let stderr_reader = std::thread::spawn(move || {
+        let mut text = String::new();
+        let _ = stderr.read_to_string(&mut text);
+        text
+    });
+
+    let mut progress = Command::new("zenity")
+        .args(["--progress", &format!("--title={}", TITLE), "--text=Starting...", "--percentage=0", "--no-cancel", "--width=480"])
+        .stdin(Stdio::piped())
+        .spawn()
+        .context("Failed to run zenity")?;
+    let mut progress_input = progress.stdin.take();
+
+    let mut log = Vec::new();
+    let stdout = child.stdout.take().context("No stdout from rpi-fs-shrink")?;
+    for line in BufReader::new(stdout).lines() {
+        let line = line?;
+        if let (Some(step), Some(input)) = (step_number(&line), progress_input.as_mut()) {
+            // Writes fail if the dialog was closed; the run carries on regardless
+            let _ = writeln!(input, "{}", (crpart::run_percent(step, 0.0) as u32).min(99));
+            let _ = writeln!(input, "# {}", line.trim());
+        }
+        log.push(line);
+    }
+
+    let status = child.wait()?;
+    let errors = stderr_reader.join().unwrap_or_default();
+    if let Some(ref mut input) = progress_input {
+        let _ = writeln!(input, "100");
+    }
+    drop(progress_input);
+    let _ = progress.wait();
+
+    if status.success() {
+        zenity(&["--info", "--no-markup", "--text=Done. The disk is ready to boot in the Pi."])?;
+        return Ok(());
+    }
+
+    let tail = log.len().saturating_sub(40);
+    let mut text = log[tail..].join("\n");
+    text.push('\n');
+    text.push_str(&errors);
+    show_text("Partitioning failed", &text)?;
+    bail!("rpi-fs-shrink failed ({})", status)
+}
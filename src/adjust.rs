@@ -0,0 +1,251 @@
+//! Moving space between two adjacent partitions the tool created (`adjust`), for
+//! a /home or /var sized wrong the first time: one filesystem shrinks, the
+//! boundary between the partitions moves, and the other filesystem grows into
+//! the freed space. The partition behind the boundary has its data moved, since
+//! its start changes, journaled so an interrupted move is finished by the next
+//! run on the disk. Every step goes to the audit log before it starts.
+
+use anyhow::{bail, Context, Result};
+use std::process::Command;
+
+use crate::shrinkpart::{is_ext, minimum_bytes, recreate_keeping_entry, resize_filesystem};
+use crate::{audit, parse, prompt, relocate, DiskInfo, MountPaths, SECTOR_SIZE};
+
+struct Side {
+    name: String,
+    number: u32,
+    device: String,
+    fstype: String,
+    start: u64,
+    end: u64,
+}
+
+impl Side {
+    fn bytes(&self) -> u64 {
+        (self.end - self.start + 1) * SECTOR_SIZE
+    }
+
+    /// `bytes` of its data moving to `start`, the partition keeping its end
+    fn moved_to(&self, start: u64, bytes: u64) -> relocate::PartitionMove {
+        relocate::PartitionMove { number: self.number, fstype: self.fstype.clone(), from: self.start, to: start, bytes, end: self.end }
+    }
+}
+
+/// "+20G" or "-20G" as signed bytes
+fn parse_delta(text: &str) -> Result<i64> {
+    let text = text.trim();
+    let (sign, size) = match text.split_at_checked(1) {
+        Some(("+", size)) => (1, size),
+        Some(("-", size)) => (-1, size),
+        _ => bail!("Size change {} needs a sign, e.g. +20G or -20G", text),
+    };
+    Ok(sign * i64::try_from(crate::parse_size(size)?)?)
+}
+
+/// The partition the disk's plan calls `name`
+fn find_side(disk_info: &DiskInfo, disk: &serde_json::Value, name: &str) -> Result<Side> {
+    let entry = disk["partitions"]
+        .as_array()
+        .and_then(|partitions| partitions.iter().find(|partition| partition["name"] == name))
+        .with_context(|| format!("{} has no {} partition", disk_info.device, name))?;
+    let start = entry["start"].as_u64().unwrap_or_default();
+    let number = crate::roles::partition_numbers(&disk_info.device)
+        .into_iter()
+        .find(|&number| crate::get_partition_bounds(&disk_info.device, number).is_ok_and(|(s, _)| s == start))
+        .with_context(|| format!("Could not find the {} partition's number", name))?;
+    let (start, end) = crate::get_partition_bounds(&disk_info.device, number)?;
+    let device = crate::get_partition_device(&disk_info.device, number)?;
+    let fstype = entry["fstype"].as_str().unwrap_or_default().to_string();
+    Ok(Side { name: name.to_string(), number, device, fstype, start, end })
+}
+
+fn mounted_at(device: &str) -> Result<Option<String>> {
+    let mounts = std::fs::read_to_string("/proc/mounts").context("Failed to read /proc/mounts")?;
+    Ok(parse::proc_mounts(&mounts).into_iter().find(|entry| entry.source == device).map(|entry| entry.target))
+}
+
+/// Read-only check of the filesystem of `side`, now at `device`
+fn check_readonly(side: &Side, device: &str) -> Result<()> {
+    let (program, args) =
+        if is_ext(&side.fstype) { ("e2fsck", ["-f", "-n", device]) } else { ("btrfs", ["check", "--readonly", device]) };
+    let output = Command::new(program).args(args).output().context(format!("Failed to run {}", program))?;
+    if !output.status.success() {
+        bail!("{} found errors on {} ({}): {}", program, device, side.name, String::from_utf8_lossy(&output.stderr).trim());
+    }
+    println!("  {} ({}): {} clean", device, side.name, side.fstype);
+    Ok(())
+}
+
+/// Move `deltas` (partition name, "+SIZE" or "-SIZE") between two adjacent
+/// partitions of `device`; the changes have to cancel out
+pub fn adjust(device: &str, deltas: &[(&str, String)], dry_run: bool, resume_unidentified: bool, audit_log: Option<&str>, mounts: &MountPaths) -> Result<()> {
+    let [(first_name, first_delta), (second_name, second_delta)] = deltas else {
+        bail!("Give the two partitions to move space between, e.g. --home +20G --var -20G");
+    };
+    let (first_delta, second_delta) = (parse_delta(first_delta)?, parse_delta(second_delta)?);
+    if first_delta == 0 || first_delta + second_delta != 0 {
+        bail!("--{} and --{} have to cancel out, e.g. --{} +20G --{} -20G", first_name, second_name, first_name, second_name);
+    }
+
+    let disk_info = crate::get_disk_info(device)?;
+    // A move an earlier adjust didn't finish goes first; the sizes below depend on it
+    let journal = relocate::lock_journal(&disk_info, dry_run)?;
+    relocate::resume_interrupted_move(&journal, &disk_info, dry_run, resume_unidentified)?;
+    let disk = crate::plandiff::disk_json(&disk_info.device)?;
+    let first = find_side(&disk_info, &disk, first_name)?;
+    let second = find_side(&disk_info, &disk, second_name)?;
+    // Whole alignment units keep the moved boundary and start aligned
+    let alignment = disk_info.alignment();
+    let sectors = first_delta.unsigned_abs() / SECTOR_SIZE / alignment * alignment;
+    if sectors == 0 {
+        bail!("Moving less than {} KiB changes nothing at this disk's alignment", alignment * SECTOR_SIZE / 1024);
+    }
+    // `front` ends where `back` starts; the boundary between them moves
+    let (front, back, front_grows) =
+        if first.start < second.start { (first, second, first_delta > 0) } else { (second, first, first_delta < 0) };
+
+    let extended = crate::mbr::existing_extended(&disk_info.device);
+    for number in crate::roles::partition_numbers(&disk_info.device) {
+        if number == front.number || number == back.number || Some(number) == extended {
+            continue;
+        }
+        let (start, _) = crate::get_partition_bounds(&disk_info.device, number)?;
+        if start > front.end && start < back.start {
+            bail!("Partition {} lies between {} and {}; adjust only moves space between neighbours", number, front.name, back.name);
+        }
+    }
+    for side in [&front, &back] {
+        if disk_info.partition_table == "msdos" && side.number > 4 {
+            bail!("{} is a logical partition; adjust only handles primary and GPT partitions", side.name);
+        }
+        if !is_ext(&side.fstype) && side.fstype != "btrfs" {
+            bail!("{} holds {}; adjust handles ext2/3/4 and btrfs", side.name, side.fstype);
+        }
+        if let Some(mount_point) = mounted_at(&side.device)? {
+            bail!("{} ({}) is mounted at {}; unmount it first", side.device, side.name, mount_point);
+        }
+    }
+    if disk_info.partition_table == "gpt" && !crate::command_exists("sgdisk") {
+        bail!("Adjusting partitions on GPT disks needs sgdisk (gdisk package) to keep the PARTUUIDs");
+    }
+
+    let moved = sectors * SECTOR_SIZE;
+    let (shrinking, growing) = if front_grows { (&back, &front) } else { (&front, &back) };
+    if moved >= shrinking.bytes() {
+        bail!("{} is only {} MB", shrinking.name, shrinking.bytes() / (1024 * 1024));
+    }
+    println!("Moving {} MB from {} to {} on {}:", moved / (1024 * 1024), shrinking.name, growing.name, disk_info.device);
+    for side in [&front, &back] {
+        let new_bytes = if side.number == growing.number { side.bytes() + moved } else { side.bytes() - moved };
+        println!(
+            "  {} (partition {}, {}): {} MB -> {} MB",
+            side.name,
+            side.number,
+            side.fstype,
+            side.bytes() / (1024 * 1024),
+            new_bytes / (1024 * 1024)
+        );
+    }
+    let back_moved = if front_grows { back.bytes() - moved } else { back.bytes() };
+    println!("  {} MB of {} data move {} on the disk", back_moved / (1024 * 1024), back.name, if front_grows { "forward" } else { "back" });
+
+    let shrunk_bytes = shrinking.bytes() - moved;
+    // An unmounted btrfs has to be mounted for its minimum; a dry run leaves it alone
+    if is_ext(&shrinking.fstype) || !dry_run {
+        if is_ext(&shrinking.fstype) && !dry_run {
+            crate::check_filesystem(&shrinking.device)?;
+        }
+        let minimum = minimum_bytes(&shrinking.device, &shrinking.fstype, None, mounts)?;
+        println!("  {} minimum: {} MB", shrinking.name, minimum.div_ceil(1024 * 1024));
+        if shrunk_bytes < minimum {
+            bail!("{} can't shrink below {} MB", shrinking.name, minimum.div_ceil(1024 * 1024));
+        }
+    }
+    if dry_run {
+        println!("\nDry run: nothing changed");
+        return Ok(());
+    }
+
+    println!("\nWARNING: This will modify the partition table of {} and move data!", disk_info.device);
+    prompt::wait_for_enter("Press Enter to continue or Ctrl+C to cancel...")?;
+
+    let audit = match audit_log {
+        Some(path) => audit::AuditLog::open(path, &disk_info.device, &disk_info.identity)?,
+        None => audit::AuditLog::disabled(),
+    };
+    let uuids = (crate::get_uuid(&front.device)?, crate::get_uuid(&back.device)?);
+    audit.record("adjust", &format!("{} MB from {} to {}", moved / (1024 * 1024), shrinking.name, growing.name))?;
+
+    // Steps done so far, printed if one fails so the disk's state is known
+    let mut done: Vec<String> = Vec::new();
+    let result = (|| -> Result<()> {
+        println!("\nStep 1: Shrinking {}...", shrinking.name);
+        audit.record("shrink-filesystem", &format!("{} to {} bytes", shrinking.device, shrunk_bytes))?;
+        resize_filesystem(&shrinking.device, &shrinking.fstype, Some(shrunk_bytes), None, mounts)?;
+        done.push(format!("{} filesystem shrunk to {} bytes", shrinking.name, shrunk_bytes));
+
+        if front_grows {
+            println!("\nStep 2: Moving {} forward...", back.name);
+            let new_start = back.start + sectors;
+            audit.record("move-partition", &format!("{} sectors {}+{} bytes to {}", back.name, back.start, shrunk_bytes, new_start))?;
+            relocate::move_partition_data(&journal, &disk_info, &back.moved_to(new_start, shrunk_bytes), "adjust")?;
+            done.push(format!("{} data moved from sector {} to {}", back.name, back.start, new_start));
+            audit.record("resize-partition", &format!("{} partition {} to sectors {}-{}", back.name, back.number, new_start, back.end))?;
+            recreate_keeping_entry(&disk_info, back.number, &back.fstype, new_start, back.end)?;
+            relocate::finish_move(&journal)?;
+            done.push(format!("{} partition starts at sector {}", back.name, new_start));
+
+            println!("\nStep 3: Growing {}...", front.name);
+            audit.record("resize-partition", &format!("{} partition {} to sectors {}-{}", front.name, front.number, front.start, front.end + sectors))?;
+            recreate_keeping_entry(&disk_info, front.number, &front.fstype, front.start, front.end + sectors)?;
+            done.push(format!("{} partition ends at sector {}", front.name, front.end + sectors));
+        } else {
+            println!("\nStep 2: Shrinking the {} partition...", front.name);
+            audit.record("resize-partition", &format!("{} partition {} to sectors {}-{}", front.name, front.number, front.start, front.end - sectors))?;
+            recreate_keeping_entry(&disk_info, front.number, &front.fstype, front.start, front.end - sectors)?;
+            done.push(format!("{} partition ends at sector {}", front.name, front.end - sectors));
+
+            println!("\nStep 3: Moving {} back...", back.name);
+            let new_start = back.start - sectors;
+            audit.record("move-partition", &format!("{} sectors {}+{} bytes to {}", back.name, back.start, back.bytes(), new_start))?;
+            relocate::move_partition_data(&journal, &disk_info, &back.moved_to(new_start, back.bytes()), "adjust")?;
+            done.push(format!("{} data moved from sector {} to {}", back.name, back.start, new_start));
+            audit.record("resize-partition", &format!("{} partition {} to sectors {}-{}", back.name, back.number, new_start, back.end))?;
+            recreate_keeping_entry(&disk_info, back.number, &back.fstype, new_start, back.end)?;
+            relocate::finish_move(&journal)?;
+            done.push(format!("{} partition starts at sector {}", back.name, new_start));
+        }
+
+        println!("\nStep 4: Growing the {} filesystem...", growing.name);
+        let grown = crate::get_partition_device(&disk_info.device, growing.number)?;
+        if is_ext(&growing.fstype) {
+            // resize2fs refuses to grow a filesystem that wasn't checked since it moved
+            crate::check_filesystem(&grown)?;
+        }
+        audit.record("grow-filesystem", &grown)?;
+        resize_filesystem(&grown, &growing.fstype, None, None, mounts)?;
+        done.push(format!("{} grown to fill its partition", growing.name));
+        Ok(())
+    })();
+    if let Err(err) = result {
+        println!("\nadjust stopped; completed steps:");
+        for step in &done {
+            println!("  {}", step);
+        }
+        let _ = audit.record("adjust-failed", &format!("{:#} after: {}", err, done.join("; ")));
+        return Err(err);
+    }
+
+    println!("\nStep 5: Verifying...");
+    for (side, uuid) in [(&front, &uuids.0), (&back, &uuids.1)] {
+        let device = crate::get_partition_device(&disk_info.device, side.number)?;
+        check_readonly(side, &device)?;
+        let now = crate::get_uuid(&device)?;
+        if now != *uuid {
+            bail!("{} ({}) has UUID {} after the move, expected {}", device, side.name, now, uuid);
+        }
+    }
+    audit.record("adjust-complete", &format!("{} MB from {} to {}", moved / (1024 * 1024), shrinking.name, growing.name))?;
+    println!("\n=== Moved {} MB from {} to {} ===", moved / (1024 * 1024), shrinking.name, growing.name);
+    Ok(())
+}
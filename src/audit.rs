@@ -0,0 +1,233 @@
+use anyhow::{bail, Context, Result};
+use serde_json::{json, Value};
+use std::io::{BufRead, BufReader, Write};
+use std::os::unix::fs::OpenOptionsExt;
+use std::process::{Command, Stdio};
+
+/// `prev` of the first entry in a log
+const GENESIS: &str = "0000000000000000000000000000000000000000000000000000000000000000";
+
+/// Append-only record of the destructive steps of a run, one JSON object per line.
+/// Each entry carries the hash of the one before it, so editing or removing an
+/// entry breaks the chain from that point on (see `audit-verify`).
+pub struct AuditLog {
+    path: Option<String>,
+    device: String,
+    identity: crate::identity::DiskIdentity,
+}
+
+impl AuditLog {
+    /// A log that records nothing, for runs without --audit-log
+    pub fn disabled() -> Self {
+        AuditLog { path: None, device: String::new(), identity: Default::default() }
+    }
+
+    pub fn open(path: &str, device: &str, identity: &crate::identity::DiskIdentity) -> Result<Self> {
+        let log = AuditLog { path: Some(path.to_string()), device: device.to_string(), identity: identity.clone() };
+        // Fail before touching the disk if the log can't be written or is already broken
+        last_entry(path)?;
+        Ok(log)
+    }
+
+    /// Append an entry for `action` on the target disk
+    pub fn record(&self, action: &str, details: &str) -> Result<()> {
+        let Some(ref path) = self.path else {
+            return Ok(());
+        };
+
+        let (seq, prev) = match last_entry(path)? {
+            Some(entry) => (entry["seq"].as_u64().unwrap_or(0) + 1, entry["hash"].as_str().unwrap_or_default().to_string()),
+            None => (1, GENESIS.to_string()),
+        };
+        let mut entry = json!({
+            "seq": seq,
+            "time": utc_timestamp(),
+            "action": action,
+            "device": self.device,
+            "serial": self.identity.serial,
+            "wwn": self.identity.wwn,
+            "details": details,
+            "user": std::env::var("SUDO_USER").unwrap_or_default(),
+            "prev": prev,
+        });
+        entry["hash"] = Value::String(entry_hash(&entry)?);
+
+        let mut file = std::fs::OpenOptions::new()
+            .append(true)
+            .create(true)
+            .mode(0o600)
+            .open(path)
+            .context(format!("Failed to open audit log {}", path))?;
+        writeln!(file, "{}", entry).context(format!("Failed to write audit log {}", path))?;
+        // The entry must be on disk before the action it records starts
+        file.sync_all().context(format!("Failed to sync audit log {}", path))?;
+        Ok(())
+    }
+}
+
+/// SHA-256 over the entry without its own hash; serde_json writes keys sorted, so
+/// the text is the same when the entry is read back
+fn entry_hash(entry: &Value) -> Result<String> {
+    let mut unhashed = entry.clone();
+    if let Some(object) = unhashed.as_object_mut() {
+        object.remove("hash");
+    }
+    sha256(unhashed.to_string().as_bytes())
+}
+
+/// SHA-256 of `data` in hex, from `sha256sum`. The crate has no hashing
+/// dependency, and coreutils (or busybox, in the rescue image) has sha256sum
+/// on every system a run works on; the manifests and boot checks use it too.
+pub fn sha256(data: &[u8]) -> Result<String> {
+    let mut child = Command::new("sha256sum")
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+        .context("Failed to run sha256sum")?;
+    child.stdin.take().context("No stdin for sha256sum")?.write_all(data)?;
+    let output = child.wait_with_output()?;
+    if !output.status.success() {
+        bail!("sha256sum failed");
+    }
+    String::from_utf8_lossy(&output.stdout)
+        .split_whitespace()
+        .next()
+        .map(str::to_string)
+        .context("sha256sum printed no hash")
+}
+
+fn last_entry(path: &str) -> Result<Option<Value>> {
+    let file = match std::fs::File::open(path) {
+        Ok(file) => file,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e).context(format!("Failed to read audit log {}", path)),
+    };
+    let mut last = None;
+    for line in BufReader::new(file).lines() {
+        let line = line?;
+        if !line.trim().is_empty() {
+            last = Some(line);
+        }
+    }
+    match last {
+        Some(line) => Ok(Some(
+            serde_json::from_str(&line).context(format!("Last line of audit log {} is not a valid entry", path))?,
+        )),
+        None => Ok(None),
+    }
+}
+
+/// Check every entry's hash and its link to the entry before
+pub fn verify(path: &str) -> Result<()> {
+    let file = std::fs::File::open(path).context(format!("Failed to read audit log {}", path))?;
+    let mut prev = GENESIS.to_string();
+    let mut count = 0;
+    for (index, line) in BufReader::new(file).lines().enumerate() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let number = index + 1;
+        let entry: Value = serde_json::from_str(&line).context(format!("Line {} is not valid JSON", number))?;
+        if entry["prev"].as_str() != Some(prev.as_str()) {
+            bail!("Line {}: chain broken, an entry before it was changed or removed", number);
+        }
+        let hash = entry["hash"].as_str().unwrap_or_default();
+        if entry_hash(&entry)? != hash {
+            bail!("Line {}: entry was modified after it was written", number);
+        }
+        prev = hash.to_string();
+        count += 1;
+    }
+    println!("{} entries, chain intact", count);
+    println!("  Last hash: {}", prev);
+    Ok(())
+}
+
+/// Current time as RFC 3339 in UTC, e.g. 2024-05-01T12:00:00Z
+pub fn utc_timestamp() -> String {
+    let secs = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0);
+    format_utc(secs)
+}
+
+/// `secs` since the Unix epoch as RFC 3339 in UTC
+fn format_utc(secs: u64) -> String {
+    let (days, rest) = (secs / 86400, secs % 86400);
+
+    // Civil date from days since 1970-01-01 (Howard Hinnant's algorithm)
+    let z = days as i64 + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z - era * 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z",
+        year,
+        month,
+        day,
+        rest / 3600,
+        rest % 3600 / 60,
+        rest % 60
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn timestamps_follow_the_civil_calendar() {
+        assert_eq!(format_utc(0), "1970-01-01T00:00:00Z");
+        assert_eq!(format_utc(951_782_400), "2000-02-29T00:00:00Z");
+        assert_eq!(format_utc(951_868_799), "2000-02-29T23:59:59Z");
+        assert_eq!(format_utc(1_709_251_199), "2024-02-29T23:59:59Z");
+        assert_eq!(format_utc(1_735_689_599), "2024-12-31T23:59:59Z");
+        assert_eq!(format_utc(4_107_542_400), "2100-03-01T00:00:00Z");
+        assert_eq!(utc_timestamp().len(), 20);
+    }
+
+    #[test]
+    fn verify_catches_edited_removed_and_reordered_entries() {
+        let dir = std::env::temp_dir().join(format!("rpi-fs-shrink-audit-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("audit.log").to_string_lossy().into_owned();
+        let log = AuditLog::open(&path, "/dev/sdx", &Default::default()).unwrap();
+        for (action, details) in [("shrink-filesystem", "to 8G"), ("move-partition", "2 to 3"), ("resize-partition", "2"), ("write-fstab", "")] {
+            log.record(action, details).unwrap();
+        }
+        assert!(verify(&path).is_ok());
+        let lines: Vec<String> = std::fs::read_to_string(&path).unwrap().lines().map(str::to_string).collect();
+        let check = |lines: Vec<String>| {
+            let broken = dir.join("broken.log").to_string_lossy().into_owned();
+            std::fs::write(&broken, lines.join("\n") + "\n").unwrap();
+            verify(&broken).map_err(|err| err.to_string())
+        };
+
+        let mut edited = lines.clone();
+        edited[1] = edited[1].replace("2 to 3", "2 to 4");
+        assert!(check(edited).unwrap_err().contains("Line 2: entry was modified"));
+        let mut removed = lines.clone();
+        removed.remove(1);
+        assert!(check(removed).unwrap_err().contains("Line 2: chain broken"));
+        let mut reordered = lines.clone();
+        reordered.swap(1, 2);
+        assert!(check(reordered).unwrap_err().contains("Line 2: chain broken"));
+        // Blank lines are no entries
+        let mut spaced = lines.clone();
+        spaced.insert(2, String::new());
+        assert!(check(spaced).is_ok());
+
+        // A broken log isn't appended to
+        std::fs::write(&path, "not json\n").unwrap();
+        assert!(AuditLog::open(&path, "/dev/sdx", &Default::default()).is_err());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,286 @@
+//! Runs on several disks at once, e.g. a USB hub full of card readers. Every
+//! device gets its own run of this binary with separate mounts, status file,
+//! audit log, report and log, and a table shows how far each one got.
+
+use anyhow::{bail, Context, Result};
+use clap::Parser;
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io::{IsTerminal, Write};
+use std::path::Path;
+use std::process::{Command, Stdio};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::timing::format_duration;
+use crate::{prompt, Args};
+
+/// How often the status table is refreshed
+const REFRESH_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Where a device is in the batch
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum State {
+    Queued,
+    Running,
+    Done(bool),
+}
+
+/// One device of the batch and the files its run writes
+struct Device {
+    path: String,
+    /// Short name for the per-device directories, e.g. "sdb"
+    name: String,
+    dir: String,
+    mount_base: String,
+}
+
+impl Device {
+    fn file(&self, name: &str) -> String {
+        format!("{}/{}", self.dir, name)
+    }
+}
+
+/// Options every run gets from the batch; each device needs its own value
+const PER_DEVICE_OPTIONS: &[&str] = &["--mount-base", "--status-file", "--audit-log", "--report", "--plan-json", "--visual-svg"];
+
+/// Parse the run options as a run on `device` would, refusing those that would
+/// point every run at the same device or file
+fn parse_run_args(run_options: &[String], device: &str) -> Result<Args> {
+    if run_options.iter().any(|option| option.starts_with("--device") || (option.starts_with("-d") && !option.starts_with("--"))) {
+        bail!("--device can't be given to batch: the devices are its own arguments");
+    }
+    let args = Args::try_parse_from(
+        std::iter::once("rpi-fs-shrink".to_string())
+            .chain(run_options.iter().cloned())
+            .chain(["--device".to_string(), device.to_string()]),
+    )
+    .context("Invalid run options for batch")?;
+    let given = [
+        args.mount_base.is_some(),
+        args.status_file.is_some(),
+        args.audit_log.is_some(),
+        args.report.is_some(),
+        args.plan_json.is_some(),
+        args.visual_svg.is_some(),
+    ];
+    if let Some(index) = given.iter().position(|&given| given) {
+        bail!(
+            "{} can't be given to batch: every device gets its own (under --log-dir and the mount base)",
+            PER_DEVICE_OPTIONS[index]
+        );
+    }
+    Ok(args)
+}
+
+/// Resolve the devices to their /dev nodes, so one disk named two ways
+/// (/dev/sdb and /dev/disk/by-id/...) is refused instead of run on twice
+fn resolve_devices(devices: &[String], log_dir: &str, mount_base: &str) -> Result<Vec<Device>> {
+    let mut resolved: Vec<Device> = Vec::new();
+    for device in devices {
+        let path = std::fs::canonicalize(device).context(format!("Device {} does not exist", device))?;
+        let path = path.to_string_lossy().to_string();
+        let name = Path::new(&path).file_name().map(|name| name.to_string_lossy().to_string()).unwrap_or_default();
+        if let Some(other) = resolved.iter().find(|other| other.path == path || other.name == name) {
+            bail!("{} and {} are the same device, or would share {}/{}", other.path, device, log_dir, name);
+        }
+        resolved.push(Device {
+            dir: format!("{}/{}", log_dir, name),
+            mount_base: format!("{}/{}", mount_base, name),
+            path,
+            name,
+        });
+    }
+    Ok(resolved)
+}
+
+/// Arguments of the run for one device: the batch's options plus its own paths
+fn run_args(device: &Device, args: &[String]) -> Vec<String> {
+    let mut all = args.to_vec();
+    for (option, value) in [
+        ("--device", device.path.clone()),
+        ("--mount-base", device.mount_base.clone()),
+        ("--status-file", device.file("status.json")),
+        ("--audit-log", device.file("audit.log")),
+        ("--report", device.file("report.json")),
+    ] {
+        all.push(option.to_string());
+        all.push(value);
+    }
+    all
+}
+
+/// Run on one device with its output going to its log; whether it succeeded
+fn run_device(device: &Device, args: &[String]) -> Result<bool> {
+    let log_path = device.file("run.log");
+    let log = File::create(&log_path).context(format!("Failed to create {}", log_path))?;
+    let exe = std::env::current_exe().context("Failed to locate rpi-fs-shrink")?;
+    let mut child = Command::new(exe)
+        .args(run_args(device, args))
+        .stdin(Stdio::piped())
+        .stdout(log.try_clone()?)
+        .stderr(log)
+        .spawn()
+        .context(format!("Failed to start the run on {}", device.path))?;
+    // The batch was confirmed once; answer both "Press Enter" prompts of the run
+    if let Some(mut stdin) = child.stdin.take() {
+        let _ = stdin.write_all(b"\n\n");
+    }
+    Ok(child.wait()?.success())
+}
+
+/// The status file of a device's run, once it wrote one
+fn read_status(device: &Device) -> Option<serde_json::Value> {
+    let text = std::fs::read_to_string(device.file("status.json")).ok()?;
+    serde_json::from_str(&text).ok()
+}
+
+/// One line of the status table
+fn status_line(device: &Device, state: State) -> String {
+    let status = read_status(device);
+    let field = |name: &str| status.as_ref().and_then(|status| status.get(name).cloned()).unwrap_or_default();
+    let (state_name, step) = match state {
+        State::Queued => ("queued", String::new()),
+        State::Running => ("running", field("step").as_str().unwrap_or("starting").to_string()),
+        State::Done(true) => ("done", field("step").as_str().unwrap_or_default().to_string()),
+        State::Done(false) => (
+            "FAILED",
+            field("error").as_str().map(str::to_string).unwrap_or_else(|| format!("see {}", device.file("run.log"))),
+        ),
+    };
+    let percent = match state {
+        State::Queued => String::new(),
+        State::Done(true) => "100%".to_string(),
+        _ => field("percent").as_f64().map(|percent| format!("{:.0}%", percent)).unwrap_or_default(),
+    };
+    let elapsed = field("elapsed_seconds")
+        .as_u64()
+        .filter(|_| state != State::Queued)
+        .map(|seconds| format_duration(Duration::from_secs(seconds)))
+        .unwrap_or_default();
+    let mut step = step;
+    if step.chars().count() > 40 {
+        step = step.chars().take(39).collect::<String>() + "…";
+    }
+    format!("  {:<20} {:<8} {:>5} {:>9}  {}", device.path, state_name, percent, elapsed, step).trim_end().to_string()
+}
+
+fn status_table(devices: &[Device], states: &[State]) -> Vec<String> {
+    let mut lines = vec![format!("  {:<20} {:<8} {:>5} {:>9}  {}", "DEVICE", "STATE", "DONE", "ELAPSED", "STEP")];
+    lines.extend(devices.iter().zip(states).map(|(device, &state)| status_line(device, state)));
+    lines
+}
+
+/// Provision `devices` with the same run options, at most `parallel` at a time
+pub fn run_batch(devices: &[String], parallel: usize, log_dir: &str, mount_base: &str, run_options: &[String]) -> Result<()> {
+    if parallel == 0 {
+        bail!("--parallel must be at least 1");
+    }
+    let devices = resolve_devices(devices, log_dir, mount_base)?;
+    let args = parse_run_args(run_options, &devices[0].path)?;
+    let policy = crate::policy::load_config()?.devices;
+    for device in &devices {
+        crate::policy::check_device(&policy, &device.path)?;
+    }
+    if !args.dry_run {
+        crate::privilege::require("Repartitioning")?;
+    }
+
+    // Once for all runs: parallel runs would each try to install missing tools,
+    // and package managers don't run concurrently
+    if let Some(ref dir) = args.tools_dir {
+        crate::tools::use_tools_dir(dir)?;
+    }
+    let container = crate::container::detect_container();
+    crate::check_dependencies(
+        args.dry_run,
+        container.is_none() && crate::privilege::is_root() && args.tools_dir.is_none(),
+        &crate::extra_dependencies(&args),
+    )?;
+
+    println!("Batch of {} devices, {} at a time:", devices.len(), parallel.min(devices.len()));
+    for device in &devices {
+        println!("  {} (mounts under {}, logs in {})", device.path, device.mount_base, device.dir);
+        std::fs::create_dir_all(&device.dir).context(format!("Failed to create {}", device.dir))?;
+        // A status file left by an earlier batch would show up as this run's
+        let _ = std::fs::remove_file(device.file("status.json"));
+    }
+    println!("  Options: {}", run_options.join(" "));
+    if !args.dry_run {
+        println!("\nWARNING: This will modify the partitions of every device above!");
+        prompt::wait_for_enter("Press Enter to continue or Ctrl+C to cancel...")?;
+    }
+
+    let devices = Arc::new(devices);
+    let run_options = Arc::new(run_options.to_vec());
+    let states = Arc::new(Mutex::new(vec![State::Queued; devices.len()]));
+    let queue = Arc::new(Mutex::new((0..devices.len()).collect::<VecDeque<_>>()));
+    let started = Instant::now();
+    let workers: Vec<_> = (0..parallel.min(devices.len()))
+        .map(|_| {
+            let (devices, run_options, states, queue) =
+                (Arc::clone(&devices), Arc::clone(&run_options), Arc::clone(&states), Arc::clone(&queue));
+            std::thread::spawn(move || {
+                loop {
+                    let Some(index) = queue.lock().unwrap().pop_front() else {
+                        break;
+                    };
+                    states.lock().unwrap()[index] = State::Running;
+                    let ok = match run_device(&devices[index], &run_options) {
+                        Ok(ok) => ok,
+                        Err(err) => {
+                            eprintln!("{}: {:#}", devices[index].path, err);
+                            false
+                        }
+                    };
+                    states.lock().unwrap()[index] = State::Done(ok);
+                }
+            })
+        })
+        .collect();
+
+    // Redraw the table in place on a terminal; elsewhere print it when it changes
+    let terminal = std::io::stdout().is_terminal();
+    let mut shown: Vec<String> = Vec::new();
+    println!();
+    loop {
+        let current = states.lock().unwrap().clone();
+        let finished = current.iter().all(|state| matches!(state, State::Done(_)));
+        let table = status_table(&devices, &current);
+        if table != shown {
+            if terminal && !shown.is_empty() {
+                print!("\x1b[{}A", shown.len());
+            }
+            for line in &table {
+                if terminal {
+                    println!("{}\x1b[K", line);
+                } else {
+                    println!("{}", line);
+                }
+            }
+            if !terminal {
+                println!();
+            }
+            shown = table;
+        }
+        if finished {
+            break;
+        }
+        std::thread::sleep(REFRESH_INTERVAL);
+    }
+    for worker in workers {
+        let _ = worker.join();
+    }
+
+    let states = states.lock().unwrap();
+    let failed: Vec<&Device> =
+        devices.iter().zip(states.iter()).filter(|(_, state)| **state == State::Done(false)).map(|(device, _)| device).collect();
+    println!("\n{} of {} devices done in {}", devices.len() - failed.len(), devices.len(), format_duration(started.elapsed()));
+    for device in &failed {
+        println!("  {} failed; log: {}", device.path, device.file("run.log"));
+    }
+    if !failed.is_empty() {
+        bail!("{} of {} runs failed", failed.len(), devices.len());
+    }
+    Ok(())
+}
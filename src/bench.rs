@@ -0,0 +1,162 @@
+use crate::blockcopy::{open_direct, AlignedBuffer};
+use anyhow::{bail, Context, Result};
+use std::fs::File;
+use std::os::unix::fs::FileExt;
+use std::time::{Duration, Instant};
+
+const SEQ_BLOCK: usize = 4 * 1024 * 1024;
+const RANDOM_BLOCK: usize = 4096;
+
+/// Random 4K writes per second below which /var on this medium will feel slow
+/// (the SD Association A1 class guarantees 500)
+pub const MIN_VAR_WRITE_IOPS: f64 = 500.0;
+
+/// Sequential throughput below which swap does more harm than good
+pub const MIN_SWAP_MBPS: f64 = 10.0;
+
+#[derive(Debug, Default)]
+pub struct BenchResult {
+    pub seq_read_mbps: f64,
+    pub seq_write_mbps: Option<f64>,
+    pub random_read_iops: f64,
+    pub random_write_iops: Option<f64>,
+    pub direct_io: bool,
+}
+
+/// xorshift64*, enough to scatter offsets without a dependency
+struct Offsets(u64);
+
+impl Offsets {
+    fn new() -> Self {
+        let seed = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map(|d| d.as_nanos() as u64)
+            .unwrap_or(0x9e3779b97f4a7c15);
+        Offsets(seed | 1)
+    }
+
+    fn next_block(&mut self, blocks: u64) -> u64 {
+        self.0 ^= self.0 >> 12;
+        self.0 ^= self.0 << 25;
+        self.0 ^= self.0 >> 27;
+        self.0.wrapping_mul(0x2545f4914f6cdd1d) % blocks
+    }
+}
+
+fn device_size(file: &File, device: &str) -> Result<u64> {
+    use std::io::{Seek, SeekFrom};
+    let mut file = file;
+    file.seek(SeekFrom::End(0)).context(format!("Failed to determine size of {}", device))
+}
+
+/// Sequential pass over `total` bytes starting in the middle of the disk.
+/// In write mode every block is read and the same bytes written back.
+fn sequential(file: &File, size: u64, total: u64, write: bool) -> Result<f64> {
+    let mut buffer = AlignedBuffer::new(SEQ_BLOCK);
+    let base = (size / 2 / SEQ_BLOCK as u64) * SEQ_BLOCK as u64;
+    let blocks = (total / SEQ_BLOCK as u64).max(1);
+
+    let mut busy = Duration::ZERO;
+    for i in 0..blocks {
+        let offset = base + i * SEQ_BLOCK as u64;
+        let start = Instant::now();
+        file.read_exact_at(buffer.as_mut_slice(SEQ_BLOCK), offset).context("Sequential read failed")?;
+        if write {
+            // Only the write is timed; the read just supplies the original data
+            let start = Instant::now();
+            file.write_all_at(buffer.as_slice(SEQ_BLOCK), offset).context("Sequential write failed")?;
+            file.sync_data().context("Sync failed")?;
+            busy += start.elapsed();
+        } else {
+            busy += start.elapsed();
+        }
+    }
+
+    Ok((blocks * SEQ_BLOCK as u64) as f64 / (1024.0 * 1024.0) / busy.as_secs_f64().max(0.001))
+}
+
+/// 4K operations at random aligned offsets for at most `duration`
+fn random(file: &File, size: u64, duration: Duration, write: bool) -> Result<f64> {
+    let mut buffer = AlignedBuffer::new(RANDOM_BLOCK);
+    let blocks = size / RANDOM_BLOCK as u64;
+    let mut offsets = Offsets::new();
+
+    let mut ops = 0u64;
+    let mut busy = Duration::ZERO;
+    let deadline = Instant::now() + duration;
+    while Instant::now() < deadline {
+        let offset = offsets.next_block(blocks) * RANDOM_BLOCK as u64;
+        let start = Instant::now();
+        file.read_exact_at(buffer.as_mut_slice(RANDOM_BLOCK), offset).context("Random read failed")?;
+        if write {
+            let start = Instant::now();
+            file.write_all_at(buffer.as_slice(RANDOM_BLOCK), offset).context("Random write failed")?;
+            file.sync_data().context("Sync failed")?;
+            busy += start.elapsed();
+        } else {
+            busy += start.elapsed();
+        }
+        ops += 1;
+    }
+
+    Ok(ops as f64 / busy.as_secs_f64().max(0.001))
+}
+
+/// Measure the target. Reads are always safe; with `write` every written block
+/// holds the data that was just read from it, so contents are preserved, but the
+/// disk must not be mounted anywhere.
+pub fn run_bench(device: &str, seq_bytes: u64, random_duration: Duration, write: bool) -> Result<BenchResult> {
+    if write && crate::is_device_mounted(device)? {
+        bail!("{} has mounted partitions; unmount them before running write tests", device);
+    }
+
+    let (file, direct_io) = open_direct(device, write)?;
+    let size = device_size(&file, device)?;
+    if size < 2 * seq_bytes {
+        bail!("{} is too small to benchmark ({} bytes)", device, size);
+    }
+
+    let mut result = BenchResult { direct_io, ..Default::default() };
+
+    println!("  Sequential read ({} MB)...", seq_bytes / (1024 * 1024));
+    result.seq_read_mbps = sequential(&file, size, seq_bytes, false)?;
+    println!("  Random 4K read ({}s)...", random_duration.as_secs());
+    result.random_read_iops = random(&file, size, random_duration, false)?;
+
+    if write {
+        println!("  Sequential write ({} MB, rewriting existing data)...", seq_bytes / (1024 * 1024));
+        result.seq_write_mbps = Some(sequential(&file, size, seq_bytes, true)?);
+        println!("  Random 4K write ({}s, rewriting existing data)...", random_duration.as_secs());
+        result.random_write_iops = Some(random(&file, size, random_duration, true)?);
+    }
+
+    Ok(result)
+}
+
+pub fn print_bench(device: &str, result: &BenchResult, is_sd_card: bool) {
+    println!("\nBenchmark results for {}{}:", device, if result.direct_io { "" } else { " (buffered, page cache may inflate reads)" });
+    println!("  Sequential read:  {:>8.1} MB/s", result.seq_read_mbps);
+    if let Some(mbps) = result.seq_write_mbps {
+        println!("  Sequential write: {:>8.1} MB/s", mbps);
+    }
+    println!("  Random 4K read:   {:>8.0} IOPS", result.random_read_iops);
+    if let Some(iops) = result.random_write_iops {
+        println!("  Random 4K write:  {:>8.0} IOPS", iops);
+    }
+
+    let swap_mbps = result.seq_write_mbps.unwrap_or(result.seq_read_mbps);
+    if swap_mbps < MIN_SWAP_MBPS {
+        println!("  WARNING: under {} MB/s - swap on this disk is not recommended", MIN_SWAP_MBPS);
+    }
+    match result.random_write_iops {
+        Some(iops) if iops < MIN_VAR_WRITE_IOPS => {
+            println!(
+                "  WARNING: under {} random write IOPS - a separate /var on this {} will be slow",
+                MIN_VAR_WRITE_IOPS,
+                if is_sd_card { "SD card" } else { "disk" }
+            );
+        }
+        None if is_sd_card => println!("  Run with --write to check whether this SD card is fast enough for /var"),
+        _ => {}
+    }
+}
@@ -0,0 +1,171 @@
+//! Probing filesystems and partition entries through libblkid instead of
+//! parsing the blkid command's output. The library reports failures with
+//! errno and return codes, where the command's output changes with its
+//! version and busybox's blkid prints something else again. Without
+//! libblkid, `values`, `find_device` and `scan` run the command instead,
+//! with the arguments and output format of whichever blkid is installed.
+
+use anyhow::{anyhow, bail, Context, Result};
+use std::ffi::{c_char, c_int, c_void, CStr, CString};
+use std::process::Command;
+use std::sync::OnceLock;
+
+use crate::busybox;
+use crate::dynlib::Library;
+
+/// BLKID_PARTS_ENTRY_DETAILS: report the partition's own entry (PART_ENTRY_UUID, ...)
+const PARTS_ENTRY_DETAILS: c_int = 1 << 2;
+
+type Probe = *mut c_void;
+
+struct Api {
+    new_probe_from_filename: unsafe extern "C" fn(*const c_char) -> Probe,
+    enable_partitions: unsafe extern "C" fn(Probe, c_int) -> c_int,
+    set_partitions_flags: unsafe extern "C" fn(Probe, c_int) -> c_int,
+    do_safeprobe: unsafe extern "C" fn(Probe) -> c_int,
+    numof_values: unsafe extern "C" fn(Probe) -> c_int,
+    get_value: unsafe extern "C" fn(Probe, c_int, *mut *const c_char, *mut *const c_char, *mut usize) -> c_int,
+    free_probe: unsafe extern "C" fn(Probe),
+    evaluate_tag: unsafe extern "C" fn(*const c_char, *const c_char, *mut *mut c_void) -> *mut c_char,
+    _library: Library,
+}
+
+fn api() -> Option<&'static Api> {
+    static API: OnceLock<Option<Api>> = OnceLock::new();
+    API.get_or_init(|| {
+        let library = Library::open("libblkid.so.1")?;
+        // SAFETY: the types match the declarations in blkid.h
+        unsafe {
+            Some(Api {
+                new_probe_from_filename: library.symbol("blkid_new_probe_from_filename")?,
+                enable_partitions: library.symbol("blkid_probe_enable_partitions")?,
+                set_partitions_flags: library.symbol("blkid_probe_set_partitions_flags")?,
+                do_safeprobe: library.symbol("blkid_do_safeprobe")?,
+                numof_values: library.symbol("blkid_probe_numof_values")?,
+                get_value: library.symbol("blkid_probe_get_value")?,
+                free_probe: library.symbol("blkid_free_probe")?,
+                evaluate_tag: library.symbol("blkid_evaluate_tag")?,
+                _library: library,
+            })
+        }
+    })
+    .as_ref()
+}
+
+/// Name and value pairs blkid reports for a device
+pub type Values = Vec<(String, String)>;
+
+/// The non-empty value of `key` in `values`
+pub fn value(values: &Values, key: &str) -> Option<String> {
+    values.iter().find(|(k, _)| k == key).map(|(_, v)| v.clone()).filter(|v| !v.is_empty())
+}
+
+/// Read the values of a probe that succeeded. PART_ENTRY_UUID also appears as
+/// PARTUUID, the name the blkid command and fstab use.
+fn probe_values(api: &Api, probe: Probe) -> Values {
+    let mut values = Values::new();
+    // SAFETY: the probe is live; libblkid owns the returned strings until it is freed
+    let count = unsafe { (api.numof_values)(probe) };
+    for n in 0..count {
+        let (mut name, mut data, mut length) = (std::ptr::null(), std::ptr::null(), 0usize);
+        if unsafe { (api.get_value)(probe, n, &mut name, &mut data, &mut length) } != 0 || name.is_null() || data.is_null() {
+            continue;
+        }
+        let name = unsafe { CStr::from_ptr(name) }.to_string_lossy().to_string();
+        let data = unsafe { CStr::from_ptr(data) }.to_string_lossy().to_string();
+        if name == "PART_ENTRY_UUID" {
+            values.push(("PARTUUID".to_string(), data.clone()));
+        }
+        values.push((name, data));
+    }
+    values
+}
+
+/// Probe `device` for a filesystem and its partition entry (TYPE, UUID, LABEL,
+/// PARTUUID, ...); None when libblkid isn't installed
+fn probe(device: &str) -> Option<Result<Values>> {
+    let api = api()?;
+    Some((|| {
+        let path = CString::new(device)?;
+        // SAFETY: the path is NUL-terminated
+        let probe = unsafe { (api.new_probe_from_filename)(path.as_ptr()) };
+        if probe.is_null() {
+            bail!("Failed to open {} for probing: {}", device, std::io::Error::last_os_error());
+        }
+        // SAFETY: the probe is live until free_probe below
+        let result = unsafe {
+            (api.enable_partitions)(probe, 1);
+            (api.set_partitions_flags)(probe, PARTS_ENTRY_DETAILS);
+            (api.do_safeprobe)(probe)
+        };
+        let values = match result {
+            0 => Ok(probe_values(api, probe)),
+            1 => Ok(Values::new()),
+            -2 => Err(anyhow!("{} carries more than one filesystem signature; wipe the stale one", device)),
+            _ => Err(anyhow!("Failed to probe {}: {}", device, std::io::Error::last_os_error())),
+        };
+        unsafe { (api.free_probe)(probe) };
+        values
+    })())
+}
+
+/// Device holding `token` (`UUID=...`, `PARTUUID=...`, `LABEL=...`). The outer
+/// None means libblkid isn't installed, the inner one that no device has it.
+fn evaluate_tag(token: &str) -> Option<Option<String>> {
+    let api = api()?;
+    let Ok(token) = CString::new(token) else {
+        return Some(None);
+    };
+    // SAFETY: a NULL value makes libblkid split NAME=value itself; a NULL cache
+    // makes it use and free a private one
+    let device = unsafe { (api.evaluate_tag)(token.as_ptr(), std::ptr::null(), std::ptr::null_mut()) };
+    if device.is_null() {
+        return Some(None);
+    }
+    let path = unsafe { CStr::from_ptr(device) }.to_string_lossy().to_string();
+    // SAFETY: the string was allocated by libblkid with malloc
+    unsafe { libc::free(device.cast()) };
+    Some(Some(path))
+}
+
+/// Run blkid with `args`. busybox's blkid takes no options and prints one line
+/// per device, so there it gets only the device (if any) and its lines are read.
+fn run_blkid(args: &[&str], device: Option<&str>) -> Result<Vec<Values>> {
+    let busybox = busybox::is_busybox("blkid");
+    let mut command = Command::new("blkid");
+    if !busybox {
+        command.args(args);
+    }
+    let output = command.args(device).output().context("Failed to run blkid")?;
+    let text = String::from_utf8_lossy(&output.stdout);
+    Ok(if busybox { crate::parse::blkid_lines(&text) } else { crate::parse::blkid_export(&text) })
+}
+
+/// What blkid knows about `device`: through libblkid when installed, else the
+/// blkid command. busybox's blkid has no PARTUUID.
+pub fn values(device: &str) -> Result<Values> {
+    if let Some(values) = probe(device) {
+        return values;
+    }
+    Ok(run_blkid(&["-o", "export"], Some(device))?.into_iter().next().unwrap_or_default())
+}
+
+/// Device holding `token` (`UUID=...`, `PARTUUID=...`, `LABEL=...`), through
+/// libblkid, findfs (busybox) or `blkid -t`
+pub fn find_device(token: &str) -> Option<String> {
+    if let Some(device) = evaluate_tag(token) {
+        return device;
+    }
+    let output = if busybox::is_busybox("blkid") {
+        Command::new("findfs").arg(token).output().ok()?
+    } else {
+        Command::new("blkid").args(["-t", token, "-o", "device"]).output().ok()?
+    };
+    crate::parse::first_value(&String::from_utf8_lossy(&output.stdout))
+}
+
+/// Every block device with a filesystem or partition entry, as blkid sees it
+/// right now (no cache, which may still hold the old table). Each starts with DEVNAME.
+pub fn scan() -> Result<Vec<Values>> {
+    run_blkid(&["-c", "/dev/null", "-o", "export"], None)
+}
@@ -0,0 +1,118 @@
+use anyhow::{bail, Context, Result};
+use std::alloc::{alloc_zeroed, dealloc, Layout};
+use std::fs::{File, OpenOptions};
+use std::os::unix::fs::OpenOptionsExt;
+use std::os::unix::io::AsRawFd;
+
+/// Largest chunk a move copies and verifies as a unit
+pub const EXTENT_BYTES: usize = 4 * 1024 * 1024;
+
+/// O_DIRECT needs buffers, offsets and lengths aligned to the logical block size
+pub const DIRECT_ALIGN: usize = 4096;
+
+/// Heap buffer aligned for O_DIRECT
+pub struct AlignedBuffer {
+    ptr: *mut u8,
+    layout: Layout,
+}
+
+// The buffer is plain owned memory, handed between the reading and writing threads of a move
+unsafe impl Send for AlignedBuffer {}
+
+impl AlignedBuffer {
+    pub fn new(size: usize) -> Self {
+        let layout = Layout::from_size_align(size, DIRECT_ALIGN).expect("valid buffer layout");
+        let ptr = unsafe { alloc_zeroed(layout) };
+        if ptr.is_null() {
+            std::alloc::handle_alloc_error(layout);
+        }
+        AlignedBuffer { ptr, layout }
+    }
+
+    pub fn as_slice(&self, len: usize) -> &[u8] {
+        unsafe { std::slice::from_raw_parts(self.ptr, len.min(self.layout.size())) }
+    }
+
+    pub fn as_mut_slice(&mut self, len: usize) -> &mut [u8] {
+        unsafe { std::slice::from_raw_parts_mut(self.ptr, len.min(self.layout.size())) }
+    }
+}
+
+impl Drop for AlignedBuffer {
+    fn drop(&mut self) {
+        unsafe { dealloc(self.ptr, self.layout) }
+    }
+}
+
+/// FNV-1a, enough to catch a write that did not land as read
+pub fn checksum(data: &[u8]) -> u64 {
+    let mut hash: u64 = 0xcbf29ce484222325;
+    for byte in data {
+        hash ^= *byte as u64;
+        hash = hash.wrapping_mul(0x100000001b3);
+    }
+    hash
+}
+
+/// Open with O_DIRECT when the file or device supports it, buffered otherwise
+pub fn open_direct(path: &str, write: bool) -> Result<(File, bool)> {
+    let direct = OpenOptions::new()
+        .read(true)
+        .write(write)
+        .custom_flags(libc::O_DIRECT)
+        .open(path);
+
+    match direct {
+        Ok(file) => Ok((file, true)),
+        Err(_) => {
+            let file = OpenOptions::new()
+                .read(true)
+                .write(write)
+                .open(path)
+                .context(format!("Failed to open {}", path))?;
+            Ok((file, false))
+        }
+    }
+}
+
+/// Write back `len` bytes of `file` at `offset` and drop them from the page
+/// cache, so the read that follows comes from the device. Only needed when
+/// O_DIRECT wasn't available: a buffered read right after the write would
+/// otherwise be answered from the cache and prove nothing.
+pub fn drop_cached(file: &File, offset: u64, len: usize) -> Result<()> {
+    file.sync_data().context("Failed to flush before verifying")?;
+    // SAFETY: the descriptor belongs to `file`, which outlives the call
+    let ret = unsafe { libc::posix_fadvise(file.as_raw_fd(), offset as libc::off_t, len as libc::off_t, libc::POSIX_FADV_DONTNEED) };
+    if ret != 0 {
+        bail!("posix_fadvise failed: {}", std::io::Error::from_raw_os_error(ret));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::FileExt;
+
+    #[test]
+    fn drop_cached_keeps_what_was_written() {
+        let path = std::env::temp_dir().join(format!("rpi-fs-shrink-blockcopy-{}", std::process::id()));
+        std::fs::write(&path, vec![0u8; 2 * DIRECT_ALIGN]).unwrap();
+        let (file, _) = open_direct(path.to_str().unwrap(), true).unwrap();
+        let mut buffer = AlignedBuffer::new(DIRECT_ALIGN);
+        buffer.as_mut_slice(DIRECT_ALIGN).fill(0x5a);
+        file.write_all_at(buffer.as_slice(DIRECT_ALIGN), DIRECT_ALIGN as u64).unwrap();
+        drop_cached(&file, DIRECT_ALIGN as u64, DIRECT_ALIGN).unwrap();
+        let mut verify = AlignedBuffer::new(DIRECT_ALIGN);
+        file.read_exact_at(verify.as_mut_slice(DIRECT_ALIGN), DIRECT_ALIGN as u64).unwrap();
+        assert_eq!(checksum(verify.as_slice(DIRECT_ALIGN)), checksum(&[0x5a; DIRECT_ALIGN]));
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn checksum_is_fnv1a() {
+        assert_eq!(checksum(b""), 0xcbf29ce484222325);
+        assert_eq!(checksum(b"a"), 0xaf63dc4c8601ec8c);
+        assert_ne!(checksum(&[0, 1]), checksum(&[1, 0]));
+    }
+}
@@ -0,0 +1,134 @@
+//! The firmware (boot) partition before and after the run: every file it held
+//! is still there with the same contents, apart from the ones the run edits,
+//! and the boot chain config.txt describes is complete.
+
+use anyhow::{bail, Context, Result};
+use std::path::Path;
+use std::process::Command;
+
+use crate::verify::{self, Manifest};
+use crate::{mount_device, unmount_quiet, MountPaths};
+
+/// Files on the boot partition the run itself writes or edits
+const EDITED_BY_RUN: &[&str] = &[
+    "config.txt",
+    "cmdline.txt",
+    "cmdline-tryboot.txt",
+    "tryboot.txt",
+    "autoboot.txt",
+    "ssh",
+    "userconf.txt",
+    "wpa_supplicant.conf",
+    "user-data",
+    "meta-data",
+    "network-config",
+];
+
+fn with_boot_read_only<T>(boot_device: &str, mounts: &MountPaths, f: impl FnOnce(&str) -> Result<T>) -> Result<T> {
+    let mount_point = mounts.boot();
+    std::fs::create_dir_all(&mount_point).context(format!("Failed to create {}", mount_point))?;
+    mount_device(boot_device, &mount_point, true).context(format!("Failed to mount {} read-only", boot_device))?;
+    let result = f(&mount_point);
+    unmount_quiet(&mount_point);
+    result
+}
+
+/// Checksums of every file on the boot partition
+pub fn snapshot(boot_device: &str, mounts: &MountPaths) -> Result<Manifest> {
+    with_boot_read_only(boot_device, mounts, verify::build_manifest)
+}
+
+/// What the boot chain needs that isn't on the partition mounted at `boot`:
+/// config.txt, cmdline.txt, and the kernel and initramfs config.txt names
+fn missing_essentials(boot: &str) -> Vec<String> {
+    let exists = |name: &str| Path::new(&format!("{}/{}", boot, name)).exists();
+    let mut missing = Vec::new();
+    let Ok(config) = std::fs::read_to_string(format!("{}/config.txt", boot)) else {
+        return vec!["config.txt".to_string()];
+    };
+    let settings: Vec<&str> = config
+        .lines()
+        .map(|line| line.split('#').next().unwrap_or("").trim())
+        .filter(|line| !line.is_empty() && !line.starts_with('['))
+        .collect();
+
+    if !exists("cmdline.txt") && !settings.iter().any(|line| line.starts_with("cmdline=")) {
+        missing.push("cmdline.txt".to_string());
+    }
+    let kernels: Vec<&str> = settings.iter().filter_map(|line| line.strip_prefix("kernel=")).map(str::trim).collect();
+    if kernels.is_empty() {
+        let any_kernel = std::fs::read_dir(boot)
+            .map(|entries| {
+                entries.flatten().any(|entry| {
+                    let name = entry.file_name().to_string_lossy().to_ascii_lowercase();
+                    name.starts_with("kernel") && name.ends_with(".img")
+                })
+            })
+            .unwrap_or(false);
+        if !any_kernel {
+            missing.push("kernel*.img".to_string());
+        }
+    }
+    // Other sections may name kernels for other models, so one of them is enough
+    if !kernels.is_empty() && !kernels.iter().any(|kernel| exists(kernel)) {
+        missing.push(format!("kernel {}", kernels.join(" or ")));
+    }
+    for line in &settings {
+        if let Some(file) = line.strip_prefix("initramfs ").and_then(|rest| rest.split_whitespace().next())
+            && !exists(file)
+        {
+            missing.push(format!("initramfs {}", file));
+        }
+    }
+    missing
+}
+
+/// Compare the boot partition with its state before the run and check its boot
+/// chain; files starting with one of `regenerated` were rebuilt by the run
+pub fn verify(boot_device: &str, before: &Manifest, regenerated: &[&str], mounts: &MountPaths) -> Result<()> {
+    let (differences, missing) = with_boot_read_only(boot_device, mounts, |boot| {
+        let after = verify::build_manifest(boot)?;
+        Ok((verify::compare_manifests(before, &after), missing_essentials(boot)))
+    })?;
+
+    let (expected, unexpected): (Vec<&String>, Vec<&String>) = differences.iter().partition(|difference| {
+        let path = difference.split_once(": ").map_or(difference.as_str(), |(_, path)| path);
+        EDITED_BY_RUN.iter().any(|name| path.eq_ignore_ascii_case(name))
+            || regenerated.iter().any(|prefix| path.to_ascii_lowercase().starts_with(prefix))
+    });
+    for difference in &expected {
+        println!("  {} (edited by this run)", difference);
+    }
+    for difference in &unexpected {
+        println!("  Warning: {}", difference);
+    }
+    for file in &missing {
+        println!("  Warning: boot chain incomplete, no {}", file);
+    }
+    if unexpected.is_empty() && missing.is_empty() {
+        println!("  {} files checked; nothing else in the boot chain changed", before.len());
+        return Ok(());
+    }
+    crate::findings::report(
+        "boot-partition-changed",
+        &format!(
+            "The boot partition {} changed in ways this run doesn't explain. The new layout is in place,\n\
+            but check the boot partition before booting the target.",
+            boot_device
+        ),
+    )
+}
+
+/// Check the boot partition's FAT read-only; it must be unmounted
+pub fn fsck(boot_device: &str) -> Result<()> {
+    let output = Command::new("fsck.fat").args(["-n", boot_device]).output().context("Failed to run fsck.fat")?;
+    if !output.status.success() {
+        let stdout = String::from_utf8_lossy(&output.stdout);
+        for line in stdout.lines().filter(|line| !line.trim().is_empty()) {
+            println!("    {}", line);
+        }
+        bail!("fsck.fat found errors on the boot partition {}; repair it with fsck.fat -a before booting", boot_device);
+    }
+    println!("  {}: FAT filesystem clean", boot_device);
+    Ok(())
+}
@@ -0,0 +1,225 @@
+//! Booting an image under QEMU (`boot-test`, and `--boot-test` after a run on an
+//! image file) to see that the migrated system comes up: the console is watched
+//! for a login prompt or a marker line. The image itself is never written; the
+//! boot runs on a qcow2 overlay that is thrown away afterwards.
+
+use anyhow::{bail, Context, Result};
+use clap::ValueEnum;
+use std::io::{BufRead, BufReader, Write};
+use std::path::Path;
+use std::process::{Command, Stdio};
+use std::sync::mpsc;
+use std::time::{Duration, Instant};
+
+use crate::{mount_device, unmount_quiet, MountPaths};
+
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Machine {
+    /// QEMU's Raspberry Pi 3B model, with the image's own kernel and device tree
+    Raspi3b,
+    /// Generic arm64 virtual machine; needs a --kernel with virtio drivers
+    Virt,
+}
+
+pub struct BootTestOptions {
+    pub machine: Machine,
+    pub kernel: Option<String>,
+    pub dtb: Option<String>,
+    pub initrd: Option<String>,
+    /// Console text that means the system is up
+    pub marker: String,
+    pub timeout: Duration,
+    /// Keep the whole console output here
+    pub log: Option<String>,
+}
+
+impl Default for BootTestOptions {
+    fn default() -> Self {
+        BootTestOptions {
+            machine: Machine::Raspi3b,
+            kernel: None,
+            dtb: None,
+            initrd: None,
+            marker: "login:".to_string(),
+            timeout: Duration::from_secs(600),
+            log: None,
+        }
+    }
+}
+
+/// Kernel and device tree the image boots on a Pi 3B
+const RASPI3B_KERNEL: &str = "kernel8.img";
+const RASPI3B_DTBS: &[&str] = &["bcm2710-rpi-3-b.dtb", "bcm2710-rpi-3-b-plus.dtb"];
+
+/// Console lines that mean the boot failed, without waiting for the timeout
+const FAILURES: &[&str] = &["Kernel panic", "You are in emergency mode", "Give root password for maintenance", "ALERT!  "];
+
+/// Copy the kernel and device tree out of the image's firmware partition into
+/// `work`, and read its cmdline.txt
+fn extract_boot_files(image: &str, options: &BootTestOptions, work: &str, mounts: &MountPaths) -> Result<(String, Option<String>, String)> {
+    crate::privilege::require("Reading the kernel from the image")?;
+    let device = crate::container::attach_image(image)?;
+    let result = (|| -> Result<(String, Option<String>, String)> {
+        let disk_info = crate::get_disk_info(&device)?;
+        let boot_device = crate::get_partition_device(&device, disk_info.roles.boot)?;
+        let boot = mounts.path("boottest");
+        std::fs::create_dir_all(&boot).context(format!("Failed to create {}", boot))?;
+        mount_device(&boot_device, &boot, true).context(format!("Failed to mount {} read-only", boot_device))?;
+        let files = (|| -> Result<(String, Option<String>, String)> {
+            let copy = |name: &str| -> Result<String> {
+                let target = format!("{}/{}", work, name);
+                std::fs::copy(format!("{}/{}", boot, name), &target).context(format!("No {} on the firmware partition of {}", name, image))?;
+                Ok(target)
+            };
+            let kernel = match options.kernel {
+                Some(ref kernel) => kernel.clone(),
+                None => copy(RASPI3B_KERNEL)?,
+            };
+            let dtb = match (&options.dtb, options.machine) {
+                (Some(dtb), _) => Some(dtb.clone()),
+                (None, Machine::Raspi3b) => {
+                    let Some(name) = RASPI3B_DTBS.iter().find(|name| Path::new(&format!("{}/{}", boot, name)).exists()) else {
+                        bail!("No Pi 3B device tree ({}) on the firmware partition; give one with --dtb", RASPI3B_DTBS.join(" or "));
+                    };
+                    Some(copy(name)?)
+                }
+                (None, Machine::Virt) => None,
+            };
+            let cmdline = std::fs::read_to_string(format!("{}/cmdline.txt", boot)).context(format!("No cmdline.txt on {}", boot_device))?;
+            Ok((kernel, dtb, cmdline))
+        })();
+        unmount_quiet(&boot);
+        files
+    })();
+    let detached = crate::container::detach_loop(&device);
+    let files = result?;
+    detached?;
+    Ok(files)
+}
+
+/// Boot `image` under QEMU and wait for the console to show `options.marker`
+pub fn boot_test(image: &str, options: &BootTestOptions, mounts: &MountPaths) -> Result<()> {
+    for tool in ["qemu-system-aarch64", "qemu-img"] {
+        if !crate::command_exists(tool) {
+            bail!("{} not found; install qemu-system-arm and qemu-utils", tool);
+        }
+    }
+    if !Path::new(image).is_file() {
+        bail!("{} is not an image file; boot-test boots images, not disks", image);
+    }
+    if options.machine == Machine::Virt && options.kernel.is_none() {
+        bail!("--machine virt needs --kernel: Raspberry Pi kernels have no virtio drivers");
+    }
+
+    let work = std::env::temp_dir().join(format!("rpi-fs-shrink-boottest-{}", std::process::id())).to_string_lossy().to_string();
+    std::fs::create_dir_all(&work).context(format!("Failed to create {}", work))?;
+    let result = run_qemu(image, options, &work, mounts);
+    let _ = std::fs::remove_dir_all(&work);
+    result
+}
+
+fn run_qemu(image: &str, options: &BootTestOptions, work: &str, mounts: &MountPaths) -> Result<()> {
+    println!("Boot test of {} ({:?})", image, options.machine);
+    let (kernel, dtb, cmdline) = extract_boot_files(image, options, work, mounts)?;
+    let cmdline = crate::cmdline::with_serial_console(&cmdline, "ttyAMA0,115200");
+
+    // The Pi model only takes SD cards whose size is a power of two
+    let image_bytes = std::fs::metadata(image).context(format!("Failed to read {}", image))?.len();
+    let overlay = format!("{}/overlay.qcow2", work);
+    let absolute = std::fs::canonicalize(image).context(format!("Failed to resolve {}", image))?;
+    let mut create = Command::new("qemu-img");
+    create.args(["create", "-q", "-f", "qcow2", "-F", "raw", "-b"]).arg(&absolute).arg(&overlay);
+    if options.machine == Machine::Raspi3b {
+        create.arg(image_bytes.next_power_of_two().to_string());
+    }
+    let status = create.status().context("Failed to run qemu-img")?;
+    if !status.success() {
+        bail!("qemu-img could not create an overlay for {}", image);
+    }
+
+    let mut qemu = Command::new("qemu-system-aarch64");
+    match options.machine {
+        Machine::Raspi3b => {
+            qemu.args(["-M", "raspi3b", "-m", "1G"]);
+            qemu.args(["-drive", &format!("file={},format=qcow2,if=sd", overlay)]);
+        }
+        Machine::Virt => {
+            qemu.args(["-M", "virt", "-cpu", "cortex-a72", "-smp", "2", "-m", "2G"]);
+            qemu.args(["-drive", &format!("file={},format=qcow2,if=none,id=disk", overlay), "-device", "virtio-blk-pci,drive=disk"]);
+        }
+    }
+    qemu.args(["-kernel", &kernel, "-append", cmdline.trim()]);
+    if let Some(ref dtb) = dtb {
+        qemu.args(["-dtb", dtb]);
+    }
+    if let Some(ref initrd) = options.initrd {
+        qemu.args(["-initrd", initrd]);
+    }
+    qemu.args(["-nographic", "-no-reboot", "-serial", "stdio", "-monitor", "none"]);
+    println!("  Kernel: {}", kernel);
+    println!("  Command line: {}", cmdline.trim());
+    println!("  Waiting up to {} s for {:?} on the console...", options.timeout.as_secs(), options.marker);
+
+    let mut child = qemu.stdin(Stdio::null()).stdout(Stdio::piped()).stderr(Stdio::piped()).spawn().context("Failed to start qemu-system-aarch64")?;
+    let stdout = child.stdout.take().context("No console output from QEMU")?;
+    let (sender, receiver) = mpsc::channel();
+    std::thread::spawn(move || {
+        for line in BufReader::new(stdout).lines() {
+            if sender.send(line.unwrap_or_default()).is_err() {
+                break;
+            }
+        }
+    });
+
+    let mut log = options.log.as_deref().map(std::fs::File::create).transpose().context("Failed to create the console log")?;
+    let mut tail: Vec<String> = Vec::new();
+    let started = Instant::now();
+    let outcome = loop {
+        let Some(left) = options.timeout.checked_sub(started.elapsed()) else {
+            break Err(format!("no {:?} on the console after {} s", options.marker, options.timeout.as_secs()));
+        };
+        match receiver.recv_timeout(left) {
+            Ok(line) => {
+                if let Some(ref mut log) = log {
+                    writeln!(log, "{}", line)?;
+                }
+                if line.contains(&options.marker) {
+                    break Ok(());
+                }
+                if let Some(failure) = FAILURES.iter().find(|failure| line.contains(*failure)) {
+                    tail.push(line.clone());
+                    break Err(format!("the console shows {:?}", failure.trim()));
+                }
+                tail.push(line);
+                if tail.len() > 20 {
+                    tail.remove(0);
+                }
+            }
+            Err(mpsc::RecvTimeoutError::Timeout) => continue,
+            Err(mpsc::RecvTimeoutError::Disconnected) => {
+                let status = child.wait()?;
+                let mut stderr = String::new();
+                if let Some(mut pipe) = child.stderr.take() {
+                    let _ = std::io::Read::read_to_string(&mut pipe, &mut stderr);
+                }
+                break Err(format!("QEMU stopped ({}) before the system came up {}", status, stderr.trim()));
+            }
+        }
+    };
+    let _ = child.kill();
+    let _ = child.wait();
+
+    match outcome {
+        Ok(()) => {
+            println!("  Booted: {:?} after {} s", options.marker, started.elapsed().as_secs());
+            Ok(())
+        }
+        Err(reason) => {
+            println!("  Last console lines:");
+            for line in &tail {
+                println!("    {}", line);
+            }
+            bail!("Boot test of {} failed: {}", image, reason)
+        }
+    }
+}
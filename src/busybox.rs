@@ -0,0 +1,47 @@
+//! Rescue initramfs environments often carry busybox's applets in place of
+//! util-linux: blkid only prints `DEVICE: KEY="value"` lines, losetup has no
+//! long options and no --show, and findfs stands in for `blkid -t`. The
+//! callers ask here which kind they got and build their arguments to match.
+
+use std::collections::HashMap;
+use std::os::unix::fs::PermissionsExt;
+use std::path::PathBuf;
+use std::process::{Command, Stdio};
+use std::sync::Mutex;
+
+/// The executable `command` runs from $PATH, found without `which`, which a
+/// minimal busybox build may leave out
+pub fn find_in_path(command: &str) -> Option<PathBuf> {
+    if command.contains('/') {
+        return Some(PathBuf::from(command)).filter(is_executable);
+    }
+    let path = std::env::var_os("PATH")?;
+    std::env::split_paths(&path).map(|dir| dir.join(command)).find(is_executable)
+}
+
+fn is_executable(path: &PathBuf) -> bool {
+    std::fs::metadata(path).is_ok_and(|meta| meta.is_file() && meta.permissions().mode() & 0o111 != 0)
+}
+
+/// Whether `command` is a busybox applet: a link to the busybox binary, or a
+/// copy that introduces itself as BusyBox in its help
+pub fn is_busybox(command: &str) -> bool {
+    static SEEN: Mutex<Option<HashMap<String, bool>>> = Mutex::new(None);
+    if let Some(&known) = SEEN.lock().unwrap().get_or_insert_with(HashMap::new).get(command) {
+        return known;
+    }
+    let busybox = find_in_path(command).is_some_and(|path| {
+        let resolved = std::fs::canonicalize(&path).unwrap_or(path);
+        resolved.file_name().is_some_and(|name| name.to_string_lossy().starts_with("busybox"))
+            || Command::new(command)
+                .arg("--help")
+                .stdin(Stdio::null())
+                .output()
+                .is_ok_and(|output| {
+                    String::from_utf8_lossy(&output.stdout).contains("BusyBox")
+                        || String::from_utf8_lossy(&output.stderr).contains("BusyBox")
+                })
+    });
+    SEEN.lock().unwrap().get_or_insert_with(HashMap::new).insert(command.to_string(), busybox);
+    busybox
+}
@@ -0,0 +1,354 @@
+use anyhow::{bail, Context, Result};
+use std::os::unix::fs::{MetadataExt, PermissionsExt};
+use std::path::Path;
+
+const CLEANUP_UNIT_NAME: &str = "rpi-fs-shrink-cleanup.service";
+
+/// Move `root/name` aside to `root/name.old`; the mountpoint is recreated
+/// afterwards by `recreate_skeleton`
+pub fn retire_directory(root: &str, name: &str) -> Result<bool> {
+    let current = format!("{}/{}", root, name);
+    let retired = format!("{}/{}.old", root, name);
+
+    let Ok(meta) = std::fs::symlink_metadata(&current) else {
+        return Ok(false);
+    };
+    if !meta.is_dir() {
+        return Ok(false);
+    }
+
+    if Path::new(&retired).exists() {
+        println!("  Removing stale {}...", retired);
+        remove_contents(&retired)?;
+        std::fs::remove_dir(&retired).context(format!("Failed to remove {}", retired))?;
+    }
+
+    std::fs::rename(&current, &retired).context(format!("Failed to rename {} to {}", current, retired))?;
+
+    println!("  Kept original /{} as /{}.old", name, name);
+    Ok(true)
+}
+
+/// Number of files and their total size under `path`, not crossing into other filesystems
+pub fn tree_usage(path: &str) -> Result<(u64, u64)> {
+    let device = std::fs::symlink_metadata(path).context(format!("Failed to read {}", path))?.dev();
+    let mut pending = vec![std::path::PathBuf::from(path)];
+    let (mut files, mut bytes) = (0, 0);
+    while let Some(dir) = pending.pop() {
+        for entry in std::fs::read_dir(&dir).context(format!("Failed to read {}", dir.display()))? {
+            let entry = entry?;
+            let meta = entry.metadata()?;
+            // A mount point, of a directory or a single file, and what is below it belong to another filesystem
+            if meta.dev() != device {
+                continue;
+            }
+            if meta.is_dir() {
+                pending.push(entry.path());
+            } else {
+                files += 1;
+                bytes += meta.len();
+            }
+        }
+    }
+    Ok((files, bytes))
+}
+
+/// Print how many files and bytes deleting everything under `paths` removes, and
+/// ask for confirmation unless `assume_yes`. True when there is nothing to delete.
+pub fn confirm_deletion(paths: &[String], assume_yes: bool) -> Result<bool> {
+    let mut total = (0, 0);
+    let mut lines = Vec::new();
+    for path in paths.iter().filter(|path| Path::new(path).is_dir()) {
+        let (files, bytes) = tree_usage(path)?;
+        lines.push(format!("  {}: {} files, {}", path, files, crate::timing::format_bytes(bytes)));
+        total = (total.0 + files, total.1 + bytes);
+    }
+    if total.0 == 0 {
+        return Ok(true);
+    }
+
+    println!("  About to delete:");
+    for line in &lines {
+        println!("  {}", line);
+    }
+    println!("  Total: {} files, {}", total.0, crate::timing::format_bytes(total.1));
+    if assume_yes {
+        return Ok(true);
+    }
+    if crate::prompt::unattended() {
+        println!("  Unattended runs only delete with --yes");
+        return Ok(false);
+    }
+
+    println!("  Type 'yes' to delete them (--yes skips this question):");
+    let mut input = String::new();
+    std::io::stdin().read_line(&mut input)?;
+    Ok(input.trim().eq_ignore_ascii_case("yes"))
+}
+
+/// Delete everything inside `path` but keep the directory itself, printing
+/// progress as it goes. Entries are unlinked directly; symlinks are not followed,
+/// and like `tree_usage` it stays on `path`'s filesystem: whatever is mounted
+/// below it is left alone, with the directories leading there.
+pub fn remove_contents(path: &str) -> Result<()> {
+    if !Path::new(path).exists() {
+        return Ok(());
+    }
+    let device = std::fs::symlink_metadata(path).context(format!("Failed to read {}", path))?.dev();
+    remove_contents_on(path, device)
+}
+
+fn remove_contents_on(path: &str, device: u64) -> Result<()> {
+    let (total, _) = tree_usage(path)?;
+    println!("  Deleting contents of {} ({} files)...", path, total);
+    let mut progress = DeleteProgress { total, deleted: 0, last_report: std::time::Instant::now() };
+    let mut kept = Vec::new();
+    for entry in std::fs::read_dir(path).context(format!("Failed to read {}", path))? {
+        remove_entry(&entry?.path(), device, &mut progress, &mut kept)?;
+    }
+    if total > 0 {
+        println!("\r  Deleted {}/{} files          ", progress.deleted, total);
+    }
+    for mount in &kept {
+        println!("  Left {} alone: another filesystem is mounted there", mount.display());
+    }
+    Ok(())
+}
+
+struct DeleteProgress {
+    total: u64,
+    deleted: u64,
+    last_report: std::time::Instant,
+}
+
+/// Delete `path` and what is under it on filesystem `device`; mount points of
+/// other filesystems are added to `kept`. Whether `path` itself is gone.
+fn remove_entry(path: &Path, device: u64, progress: &mut DeleteProgress, kept: &mut Vec<std::path::PathBuf>) -> Result<bool> {
+    let meta = std::fs::symlink_metadata(path).context(format!("Failed to stat {}", path.display()))?;
+    if meta.dev() != device {
+        kept.push(path.to_path_buf());
+        return Ok(false);
+    }
+    if meta.is_dir() {
+        let mut emptied = true;
+        for entry in std::fs::read_dir(path).context(format!("Failed to read {}", path.display()))? {
+            emptied &= remove_entry(&entry?.path(), device, progress, kept)?;
+        }
+        if !emptied {
+            return Ok(false);
+        }
+        std::fs::remove_dir(path).context(format!("Failed to delete {}", path.display()))?;
+        return Ok(true);
+    }
+
+    std::fs::remove_file(path).context(format!("Failed to delete {}", path.display()))?;
+    progress.deleted += 1;
+    if progress.last_report.elapsed() >= std::time::Duration::from_secs(1) {
+        print!("\r  Deleted {}/{} files ({}%)", progress.deleted, progress.total, progress.deleted * 100 / progress.total.max(1));
+        let _ = std::io::Write::flush(&mut std::io::stdout());
+        progress.last_report = std::time::Instant::now();
+    }
+    Ok(true)
+}
+
+/// The oneshot unit that deletes the `.old` copies once the new mounts for
+/// every name are up, then disables itself
+fn cleanup_unit(names: &[&str]) -> String {
+    let mountpoints: Vec<String> = names.iter().map(|name| format!("/{}", name)).collect();
+    let retired: Vec<String> = names.iter().map(|name| format!("/{}.old", name)).collect();
+
+    let mut unit = String::new();
+    unit.push_str("[Unit]\n");
+    unit.push_str("Description=Remove data left behind by rpi-fs-shrink\n");
+    unit.push_str(&format!("RequiresMountsFor={}\n", mountpoints.join(" ")));
+    unit.push_str("After=local-fs.target\n");
+    for path in &retired {
+        unit.push_str(&format!("ConditionPathExists=|{}\n", path));
+    }
+    unit.push_str("\n[Service]\nType=oneshot\n");
+    // Refuse to delete anything unless each path really is a separate mount
+    for path in &mountpoints {
+        unit.push_str(&format!("ExecStartPre=/bin/mountpoint -q {}\n", path));
+    }
+    unit.push_str(&format!("ExecStart=/bin/rm -rf {}\n", retired.join(" ")));
+    unit.push_str(&format!(
+        "ExecStartPost=/bin/rm -f /etc/systemd/system/multi-user.target.wants/{}\n",
+        CLEANUP_UNIT_NAME
+    ));
+    unit.push_str("\n[Install]\nWantedBy=multi-user.target\n");
+    unit
+}
+
+/// Install [`cleanup_unit`] in the target and enable it
+pub fn install_cleanup_unit(root: &str, names: &[&str]) -> Result<()> {
+    let unit = cleanup_unit(names);
+    let retired: Vec<String> = names.iter().map(|name| format!("/{}.old", name)).collect();
+
+    let unit_dir = format!("{}/etc/systemd/system", root);
+    let wants_dir = format!("{}/multi-user.target.wants", unit_dir);
+    std::fs::create_dir_all(&wants_dir).context("Failed to create systemd unit directory")?;
+    std::fs::write(format!("{}/{}", unit_dir, CLEANUP_UNIT_NAME), unit).context("Failed to write cleanup unit")?;
+
+    let link = format!("{}/{}", wants_dir, CLEANUP_UNIT_NAME);
+    if std::fs::symlink_metadata(&link).is_err() {
+        std::os::unix::fs::symlink(format!("/etc/systemd/system/{}", CLEANUP_UNIT_NAME), &link)
+            .context("Failed to enable cleanup unit")?;
+    }
+
+    println!("  {} will be removed after the first successful boot", retired.join(" and "));
+    Ok(())
+}
+
+/// Directories recreated under an emptied mountpoint, so a boot where the new
+/// partition fails to mount still finds the paths services expect
+pub const VAR_SKELETON: &[(&str, u32)] = &[
+    ("cache", 0o755),
+    ("lib", 0o755),
+    ("log", 0o755),
+    ("spool", 0o755),
+    ("tmp", 0o1777),
+];
+
+/// Make sure `root/name` exists as a root-owned 0755 mountpoint and, for /var,
+/// holds the standard skeleton with the right permissions
+pub fn recreate_skeleton(root: &str, name: &str) -> Result<()> {
+    let mountpoint = format!("{}/{}", root, name);
+    ensure_directory(&mountpoint, 0o755)?;
+
+    if name == "var" {
+        for (dir, mode) in VAR_SKELETON {
+            ensure_directory(&format!("{}/{}", mountpoint, dir), *mode)?;
+        }
+    }
+
+    println!("  /{} mountpoint ready", name);
+    Ok(())
+}
+
+/// Check the top directory of a freshly populated partition is root-owned with
+/// the expected mode and fix it if not
+pub fn verify_mount_root(mount_point: &str, name: &str) -> Result<()> {
+    let meta = std::fs::metadata(mount_point).context(format!("Failed to stat {}", mount_point))?;
+    let mode = meta.mode() & 0o7777;
+    if mode != 0o755 || meta.uid() != 0 || meta.gid() != 0 {
+        println!(
+            "  /{} had mode {:o} owner {}:{}, resetting to 755 root:root",
+            name,
+            mode,
+            meta.uid(),
+            meta.gid()
+        );
+        ensure_directory(mount_point, 0o755)?;
+    }
+    Ok(())
+}
+
+fn ensure_directory(path: &str, mode: u32) -> Result<()> {
+    if !Path::new(path).is_dir() {
+        std::fs::create_dir_all(path).context(format!("Failed to create {}", path))?;
+    }
+    std::fs::set_permissions(path, std::fs::Permissions::from_mode(mode))
+        .context(format!("Failed to set permissions on {}", path))?;
+    std::os::unix::fs::chown(path, Some(0), Some(0)).context(format!("Failed to set owner of {}", path))?;
+
+    let actual = std::fs::metadata(path).context(format!("Failed to stat {}", path))?.mode() & 0o7777;
+    if actual != mode {
+        bail!("{} has mode {:o} after setting {:o}", path, actual, mode);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_root(name: &str) -> String {
+        let root = std::env::temp_dir().join(format!("rpi-fs-shrink-cleanup-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&root);
+        std::fs::create_dir_all(&root).unwrap();
+        root.to_string_lossy().into_owned()
+    }
+
+    #[test]
+    fn cleanup_unit_deletes_only_behind_mounted_partitions() {
+        let unit = cleanup_unit(&["var", "home"]);
+        assert!(unit.contains("RequiresMountsFor=/var /home\n"));
+        assert!(unit.contains("ConditionPathExists=|/var.old\nConditionPathExists=|/home.old\n"));
+        let checks = unit.find("ExecStartPre=/bin/mountpoint -q /var\nExecStartPre=/bin/mountpoint -q /home\n").unwrap();
+        let delete = unit.find("ExecStart=/bin/rm -rf /var.old /home.old\n").unwrap();
+        assert!(checks < delete);
+        assert!(unit.contains(&format!("ExecStartPost=/bin/rm -f /etc/systemd/system/multi-user.target.wants/{}\n", CLEANUP_UNIT_NAME)));
+        assert!(unit.ends_with("[Install]\nWantedBy=multi-user.target\n"));
+    }
+
+    #[test]
+    fn retired_directories_replace_a_stale_old_copy() {
+        let root = temp_root("retire");
+        std::fs::create_dir_all(format!("{}/var/log", root)).unwrap();
+        std::fs::write(format!("{}/var/log/syslog", root), "new").unwrap();
+        std::fs::create_dir_all(format!("{}/var.old/cache", root)).unwrap();
+        std::fs::write(format!("{}/var.old/cache/stale", root), "old").unwrap();
+        std::fs::write(format!("{}/home", root), "not a directory").unwrap();
+
+        assert!(retire_directory(&root, "var").unwrap());
+        assert!(!Path::new(&format!("{}/var", root)).exists());
+        assert_eq!(std::fs::read_to_string(format!("{}/var.old/log/syslog", root)).unwrap(), "new");
+        assert!(!Path::new(&format!("{}/var.old/cache", root)).exists());
+        // Nothing to retire: a file, or nothing at all
+        assert!(!retire_directory(&root, "home").unwrap());
+        assert!(!retire_directory(&root, "srv").unwrap());
+        assert!(!Path::new(&format!("{}/home.old", root)).exists());
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn skeletons_are_root_owned_with_their_modes() {
+        // Giving the directories to root needs root
+        if !crate::privilege::is_root() {
+            return;
+        }
+        let root = temp_root("skeleton");
+        // A leftover /var with the wrong mode and owner, and /var/tmp without its sticky bit
+        std::fs::create_dir_all(format!("{}/var/tmp", root)).unwrap();
+        std::fs::set_permissions(format!("{}/var", root), std::fs::Permissions::from_mode(0o700)).unwrap();
+        std::fs::set_permissions(format!("{}/var/tmp", root), std::fs::Permissions::from_mode(0o777)).unwrap();
+        std::os::unix::fs::chown(format!("{}/var", root), Some(1000), Some(1000)).unwrap();
+
+        recreate_skeleton(&root, "var").unwrap();
+        recreate_skeleton(&root, "home").unwrap();
+        let mode_and_owner = |path: &str| {
+            let meta = std::fs::metadata(format!("{}/{}", root, path)).unwrap();
+            (meta.mode() & 0o7777, meta.uid(), meta.gid())
+        };
+        assert_eq!(mode_and_owner("var"), (0o755, 0, 0));
+        for (dir, mode) in VAR_SKELETON {
+            assert_eq!(mode_and_owner(&format!("var/{}", dir)), (*mode, 0, 0), "{}", dir);
+        }
+        assert_eq!(mode_and_owner("var/tmp").0, 0o1777);
+        assert_eq!(mode_and_owner("home"), (0o755, 0, 0));
+        // /home gets no /var skeleton
+        assert_eq!(std::fs::read_dir(format!("{}/home", root)).unwrap().count(), 0);
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn deletion_stops_at_other_filesystems() {
+        let root = temp_root("remove");
+        std::fs::create_dir_all(format!("{}/var/lib/apt", root)).unwrap();
+        std::fs::write(format!("{}/var/lib/apt/lists", root), "x").unwrap();
+        std::fs::write(format!("{}/var/log", root), "y").unwrap();
+        std::os::unix::fs::symlink("/etc", format!("{}/var/etc", root)).unwrap();
+        let var = format!("{}/var", root);
+        let device = std::fs::symlink_metadata(&var).unwrap().dev();
+
+        // Entries on another filesystem than the one given are kept, and so is what leads to them
+        remove_contents_on(&var, device + 1).unwrap();
+        assert_eq!(std::fs::read_dir(&var).unwrap().count(), 3);
+
+        remove_contents(&var).unwrap();
+        assert_eq!(std::fs::read_dir(&var).unwrap().count(), 0);
+        assert!(Path::new("/etc").is_dir());
+        assert_eq!(tree_usage(&var).unwrap(), (0, 0));
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+}
@@ -0,0 +1,146 @@
+//! Edits to the kernel command line (cmdline.txt) as pure functions: the file's
+//! text in, the new text out. cmdline.txt is one line of space separated
+//! parameters; the edits keep the order of the parameters they don't touch and
+//! end the line with a newline.
+
+/// What --swap-mode zswap puts on the command line
+pub const ZSWAP_PARAMS: &[&str] = &["zswap.enabled=1", "zswap.compressor=zstd", "zswap.max_pool_percent=20"];
+
+/// `cmdline` without the parameters starting with `prefix`, and `params` appended
+fn replacing(cmdline: &str, prefix: &str, params: &[&str]) -> String {
+    let mut kept: Vec<&str> = cmdline.split_whitespace().filter(|param| !param.starts_with(prefix)).collect();
+    kept.extend_from_slice(params);
+    format!("{}\n", kept.join(" "))
+}
+
+/// `cmdline` with every parameter naming `PARTUUID=old` (root=, resume=, ...)
+/// pointed at `new`. Unchanged, byte for byte, when nothing names `old`.
+pub fn with_partuuid(cmdline: &str, old: &str, new: &str) -> String {
+    let old_source = format!("PARTUUID={}", old);
+    let names_old = |param: &str| param.split_once('=').is_some_and(|(_, value)| value.eq_ignore_ascii_case(&old_source));
+    if !cmdline.split_whitespace().any(names_old) {
+        return cmdline.to_string();
+    }
+    let params: Vec<String> = cmdline
+        .split_whitespace()
+        .map(|param| match param.split_once('=') {
+            Some((name, _)) if names_old(param) => format!("{}=PARTUUID={}", name, new),
+            _ => param.to_string(),
+        })
+        .collect();
+    format!("{}\n", params.join(" "))
+}
+
+/// `cmdline` resuming from `source` after hibernation; an older resume= is replaced
+pub fn with_resume(cmdline: &str, source: &str) -> String {
+    replacing(cmdline, "resume=", &[&format!("resume={}", source)])
+}
+
+/// `cmdline` with zswap enabled; zswap settings already on it are replaced
+pub fn with_zswap(cmdline: &str) -> String {
+    replacing(cmdline, "zswap.", ZSWAP_PARAMS)
+}
+
+/// `cmdline` writing its console to `console` only, without the boot splash
+/// and `quiet`, so every kernel and systemd message reaches it
+pub fn with_serial_console(cmdline: &str, console: &str) -> String {
+    let params: Vec<&str> =
+        cmdline.split_whitespace().filter(|param| !matches!(*param, "quiet" | "splash" | "plymouth.ignore-serial-consoles")).collect();
+    replacing(&params.join(" "), "console=", &[&format!("console={}", console)])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RPI_OS: &str = "console=serial0,115200 console=tty1 root=PARTUUID=738a4d67-02 rootfstype=ext4 fsck.repair=yes rootwait quiet init=/usr/lib/raspberrypi-sys-mods/firstboot\n";
+
+    #[test]
+    fn root_partuuid_is_replaced() {
+        assert_eq!(
+            with_partuuid(RPI_OS, "738a4d67-02", "9e1f03c2-02"),
+            "console=serial0,115200 console=tty1 root=PARTUUID=9e1f03c2-02 rootfstype=ext4 fsck.repair=yes rootwait quiet init=/usr/lib/raspberrypi-sys-mods/firstboot\n"
+        );
+    }
+
+    #[test]
+    fn gpt_partuuids_match_case_insensitively() {
+        let cmdline = "root=PARTUUID=5C3B1D2A-8F41-4E6B-9D3C-1A2B3C4D5E6F rootwait\n";
+        assert_eq!(
+            with_partuuid(cmdline, "5c3b1d2a-8f41-4e6b-9d3c-1a2b3c4d5e6f", "0f0e0d0c-0b0a-0908-0706-050403020100"),
+            "root=PARTUUID=0f0e0d0c-0b0a-0908-0706-050403020100 rootwait\n"
+        );
+    }
+
+    #[test]
+    fn resume_on_the_same_partition_moves_with_root() {
+        let cmdline = "root=PARTUUID=738a4d67-02 resume=PARTUUID=738a4d67-02 rootwait";
+        assert_eq!(with_partuuid(cmdline, "738a4d67-02", "9e1f03c2-02"), "root=PARTUUID=9e1f03c2-02 resume=PARTUUID=9e1f03c2-02 rootwait\n");
+    }
+
+    #[test]
+    fn longer_partuuids_sharing_a_prefix_are_left_alone() {
+        let cmdline = "root=PARTUUID=738a4d67-022 rootwait\n";
+        assert_eq!(with_partuuid(cmdline, "738a4d67-02", "9e1f03c2-02"), cmdline);
+    }
+
+    #[test]
+    fn other_root_forms_are_left_alone_byte_for_byte() {
+        for cmdline in [
+            "console=tty1  root=/dev/mmcblk0p2 rootfstype=ext4 rootwait",
+            "root=UUID=1b2c3d4e-5f60-7182-93a4-b5c6d7e8f901 rootwait\n",
+            "root=LABEL=rootfs rootwait\n",
+            "",
+        ] {
+            assert_eq!(with_partuuid(cmdline, "738a4d67-02", "9e1f03c2-02"), cmdline);
+        }
+    }
+
+    #[test]
+    fn edits_normalise_spacing_and_end_the_line() {
+        let cmdline = "console=tty1   root=PARTUUID=738a4d67-02\trootwait";
+        assert_eq!(with_partuuid(cmdline, "738a4d67-02", "9e1f03c2-02"), "console=tty1 root=PARTUUID=9e1f03c2-02 rootwait\n");
+    }
+
+    #[test]
+    fn quoted_parameters_survive() {
+        let cmdline = "root=PARTUUID=738a4d67-02 systemd.run=\"/boot/firstrun.sh\" quiet\n";
+        assert_eq!(
+            with_resume(cmdline, "PARTUUID=738a4d67-03"),
+            "root=PARTUUID=738a4d67-02 systemd.run=\"/boot/firstrun.sh\" quiet resume=PARTUUID=738a4d67-03\n"
+        );
+    }
+
+    #[test]
+    fn resume_replaces_an_older_one() {
+        let cmdline = "root=PARTUUID=738a4d67-02 resume=/dev/sda3 rootwait\n";
+        assert_eq!(with_resume(cmdline, "PARTUUID=738a4d67-03"), "root=PARTUUID=738a4d67-02 rootwait resume=PARTUUID=738a4d67-03\n");
+        assert_eq!(with_resume(&with_resume(cmdline, "PARTUUID=x"), "PARTUUID=x"), with_resume(cmdline, "PARTUUID=x"));
+    }
+
+    #[test]
+    fn zswap_replaces_existing_settings() {
+        let cmdline = "root=PARTUUID=738a4d67-02 zswap.enabled=0 zswap.compressor=lzo rootwait\n";
+        assert_eq!(
+            with_zswap(cmdline),
+            "root=PARTUUID=738a4d67-02 rootwait zswap.enabled=1 zswap.compressor=zstd zswap.max_pool_percent=20\n"
+        );
+        assert_eq!(with_zswap(&with_zswap(RPI_OS)), with_zswap(RPI_OS));
+    }
+
+    #[test]
+    fn serial_console_replaces_the_others_and_the_splash() {
+        assert_eq!(
+            with_serial_console(RPI_OS, "ttyAMA0,115200"),
+            "root=PARTUUID=738a4d67-02 rootfstype=ext4 fsck.repair=yes rootwait init=/usr/lib/raspberrypi-sys-mods/firstboot console=ttyAMA0,115200\n"
+        );
+        let desktop = "console=tty1 root=PARTUUID=738a4d67-02 rootwait quiet splash plymouth.ignore-serial-consoles\n";
+        assert_eq!(with_serial_console(desktop, "ttyAMA0"), "root=PARTUUID=738a4d67-02 rootwait console=ttyAMA0\n");
+    }
+
+    #[test]
+    fn edits_of_an_empty_file_give_one_line() {
+        assert_eq!(with_resume("", "PARTUUID=738a4d67-03"), "resume=PARTUUID=738a4d67-03\n");
+        assert_eq!(with_zswap("\n"), "zswap.enabled=1 zswap.compressor=zstd zswap.max_pool_percent=20\n");
+    }
+}
@@ -0,0 +1,106 @@
+use anyhow::{bail, Result};
+use clap::ValueEnum;
+
+use crate::format::FormatJob;
+
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OnCollision {
+    /// Reformat a new filesystem whose UUID another device already has
+    Regenerate,
+    /// Only warn
+    Warn,
+}
+
+type Device = Vec<(String, String)>;
+
+fn value<'a>(device: &'a Device, key: &str) -> Option<&'a str> {
+    device.iter().find(|(k, _)| k == key).map(|(_, v)| v.as_str()).filter(|v| !v.is_empty())
+}
+
+fn same_device(a: &str, b: &str) -> bool {
+    let resolve = |path: &str| std::fs::canonicalize(path).unwrap_or_else(|_| path.into());
+    resolve(a) == resolve(b)
+}
+
+/// The scan entry for `path`
+fn find<'a>(devices: &'a [Device], path: &str) -> Option<&'a Device> {
+    devices.iter().find(|device| value(device, "DEVNAME").is_some_and(|name| same_device(name, path)))
+}
+
+/// Other devices than `path` with the same `key` as it has
+fn clashes(devices: &[Device], path: &str, key: &str) -> Option<(String, Vec<String>)> {
+    let own = value(find(devices, path)?, key)?.to_string();
+    let others: Vec<String> = devices
+        .iter()
+        .filter(|device| value(device, key) == Some(own.as_str()))
+        .filter_map(|device| value(device, "DEVNAME"))
+        .filter(|name| !same_device(name, path))
+        .map(str::to_string)
+        .collect();
+    (!others.is_empty()).then_some((own, others))
+}
+
+/// Look for the new filesystems' UUIDs and labels on other block devices (cloned
+/// cards share them). Returns the jobs to run again for a new UUID: those with a
+/// taken one, unless it was reused on purpose or `mode` says to warn only.
+/// `existing` are the partitions the target keeps (boot and root), which can
+/// only be warned about.
+pub fn check(jobs: &[FormatJob], existing: &[(&str, &str)], mode: OnCollision) -> Result<Vec<FormatJob>> {
+    let devices = crate::blkid::scan()?;
+    let mut redo = Vec::new();
+
+    for job in jobs {
+        if let Some((uuid, others)) = clashes(&devices, &job.device, "UUID") {
+            if job.uuid.is_some() || mode == OnCollision::Warn {
+                crate::findings::report(
+                    "uuid-collision",
+                    &format!(
+                        "UUID {} of {} ({}) is also on {}; fstab entries by UUID may pick the wrong one while both are attached",
+                        uuid,
+                        job.name,
+                        job.device,
+                        others.join(", ")
+                    ),
+                )?;
+            } else {
+                println!("  UUID {} of {} ({}) is also on {}, formatting it again", uuid, job.name, job.device, others.join(", "));
+                redo.push(job.clone());
+            }
+        }
+        if let Some((label, others)) = clashes(&devices, &job.device, "LABEL") {
+            crate::findings::report("label-collision", &format!("label {} of {} ({}) is also on {}", label, job.name, job.device, others.join(", ")))?;
+        }
+    }
+
+    for (name, path) in existing {
+        for key in ["UUID", "PARTUUID"] {
+            if let Some((id, others)) = clashes(&devices, path, key) {
+                crate::findings::report(
+                    "uuid-collision",
+                    &format!(
+                        "{} {} of {} ({}) is also on {}; a system with both attached may mount or boot the wrong one",
+                        key,
+                        id,
+                        name,
+                        path,
+                        others.join(", ")
+                    ),
+                )?;
+            }
+        }
+    }
+
+    Ok(redo)
+}
+
+/// Make sure the filesystems formatted again now have UUIDs of their own
+pub fn confirm_regenerated(redo: &[FormatJob]) -> Result<()> {
+    let devices = crate::blkid::scan()?;
+    for job in redo {
+        if let Some((uuid, others)) = clashes(&devices, &job.device, "UUID") {
+            bail!("{} ({}) still has UUID {}, which {} also has", job.name, job.device, uuid, others.join(", "));
+        }
+    }
+    println!("  New UUIDs generated for {}", redo.iter().map(|job| job.name.as_str()).collect::<Vec<_>>().join(", "));
+    Ok(())
+}
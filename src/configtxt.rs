@@ -0,0 +1,307 @@
+//! Editing the firmware's config.txt: settings are changed where they are and
+//! new ones appended in an `[all]` section, so comments, conditional sections
+//! and the order of everything else stay as they were.
+
+use anyhow::{bail, Context, Result};
+use std::fs::File;
+use std::io::Write;
+use std::path::Path;
+
+/// Comment above the settings this tool appends
+const MARKER: &str = "# Added by rpi-fs-shrink";
+
+/// Settings written `key value` instead of `key=value`
+const SPACE_SEPARATED: &[&str] = &["initramfs", "include"];
+
+/// Settings that can be given several times, each line adding one
+const REPEATABLE: &[&str] = &["dtoverlay", "dtparam", "include"];
+
+/// One change from the command line: `key=value` sets (or, for dtoverlay and
+/// dtparam, adds) a setting, `-key` removes it and `-key=value` removes one value
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Change {
+    Set(String, String),
+    Add(String, String),
+    Remove(String, Option<String>),
+}
+
+pub fn parse_change(text: &str) -> Result<Change> {
+    let (remove, rest) = match text.trim().strip_prefix('-') {
+        Some(rest) => (true, rest),
+        None => (false, text.trim()),
+    };
+    let (key, value) = match setting(rest) {
+        Some((key, value)) => (key.to_ascii_lowercase(), value.to_string()),
+        None => (rest.to_ascii_lowercase(), String::new()),
+    };
+    if key.is_empty() || !key.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') {
+        bail!("Invalid config.txt setting \"{}\" (expected key=value, -key or -key=value)", text);
+    }
+    Ok(match (remove, value.is_empty()) {
+        (true, true) => Change::Remove(key, None),
+        (true, false) => Change::Remove(key, Some(value)),
+        (false, true) => bail!("config.txt setting \"{}\" has no value (use -{} to remove it)", text, key),
+        (false, false) if REPEATABLE.contains(&key.as_str()) => Change::Add(key, value),
+        (false, false) => Change::Set(key, value),
+    })
+}
+
+/// config.txt as lines, for editing in place
+#[derive(Debug, Clone)]
+pub struct ConfigTxt {
+    lines: Vec<String>,
+    trailing_newline: bool,
+}
+
+/// Key and value of a setting line; None for comments, blank lines and section filters
+fn setting(line: &str) -> Option<(&str, &str)> {
+    let line = line.trim();
+    if line.is_empty() || line.starts_with('#') || line.starts_with('[') {
+        return None;
+    }
+    let split = line.find(|c: char| c == '=' || c.is_whitespace())?;
+    Some((&line[..split], line[split + 1..].trim()))
+}
+
+fn format_setting(key: &str, value: &str) -> String {
+    if SPACE_SEPARATED.contains(&key) { format!("{} {}", key, value) } else { format!("{}={}", key, value) }
+}
+
+impl ConfigTxt {
+    pub fn parse(text: &str) -> Self {
+        ConfigTxt { lines: text.lines().map(str::to_string).collect(), trailing_newline: text.is_empty() || text.ends_with('\n') }
+    }
+
+    pub fn render(&self) -> String {
+        let mut text = self.lines.join("\n");
+        if self.trailing_newline && !self.lines.is_empty() {
+            text.push('\n');
+        }
+        text
+    }
+
+    /// Each line with whether it only applies to some models: it follows a
+    /// section filter other than `[all]`
+    fn scoped(&self) -> Vec<(usize, bool)> {
+        let mut conditional = false;
+        let mut scoped = Vec::new();
+        for (index, line) in self.lines.iter().enumerate() {
+            let trimmed = line.trim();
+            if trimmed.starts_with('[') {
+                conditional = !trimmed.eq_ignore_ascii_case("[all]");
+            }
+            scoped.push((index, conditional));
+        }
+        scoped
+    }
+
+    /// Value of `key` that applies to every model, the last one if it is set twice
+    pub fn get(&self, key: &str) -> Option<&str> {
+        self.scoped()
+            .into_iter()
+            .filter(|&(_, conditional)| !conditional)
+            .filter_map(|(index, _)| setting(&self.lines[index]))
+            .filter(|(name, _)| name.eq_ignore_ascii_case(key))
+            .map(|(_, value)| value)
+            .next_back()
+    }
+
+    /// Whether `key=value` is set for every model, for settings that can be
+    /// given several times (dtoverlay, dtparam)
+    pub fn contains(&self, key: &str, value: &str) -> bool {
+        self.scoped().into_iter().filter(|&(_, conditional)| !conditional).any(|(index, _)| {
+            setting(&self.lines[index]).is_some_and(|(name, existing)| name.eq_ignore_ascii_case(key) && existing == value)
+        })
+    }
+
+    /// Set a setting that takes one value: the line that sets it last is changed
+    /// if it applies to every model, otherwise the setting is appended
+    pub fn set(&mut self, key: &str, value: &str) {
+        let last = self
+            .scoped()
+            .into_iter()
+            .rfind(|&(index, _)| setting(&self.lines[index]).is_some_and(|(name, _)| name.eq_ignore_ascii_case(key)));
+        match last {
+            Some((index, false)) => {
+                let indent: String = self.lines[index].chars().take_while(|c| c.is_whitespace()).collect();
+                self.lines[index] = format!("{}{}", indent, format_setting(key, value));
+            }
+            _ => self.append(format_setting(key, value)),
+        }
+    }
+
+    /// Add `key=value` for a setting that can be given several times, unless it is there
+    pub fn add(&mut self, key: &str, value: &str) {
+        if !self.contains(key, value) {
+            self.append(format_setting(key, value));
+        }
+    }
+
+    /// Remove the lines setting `key` (only those with `value`, if given) for
+    /// every model; whether any were removed
+    pub fn remove(&mut self, key: &str, value: Option<&str>) -> bool {
+        let doomed: Vec<usize> = self
+            .scoped()
+            .into_iter()
+            .filter(|&(index, conditional)| {
+                !conditional
+                    && setting(&self.lines[index]).is_some_and(|(name, existing)| {
+                        name.eq_ignore_ascii_case(key) && value.is_none_or(|value| existing == value)
+                    })
+            })
+            .map(|(index, _)| index)
+            .collect();
+        for &index in doomed.iter().rev() {
+            self.lines.remove(index);
+        }
+        !doomed.is_empty()
+    }
+
+    /// Apply a change, describing what it did
+    pub fn apply(&mut self, change: &Change) -> String {
+        match change {
+            Change::Set(key, value) => {
+                let before = self.get(key).map(str::to_string);
+                self.set(key, value);
+                match before {
+                    Some(before) if before == *value => format!("{} already {}", key, value),
+                    Some(before) => format!("{}: {} -> {}", key, before, value),
+                    None => format!("{} set to {}", key, value),
+                }
+            }
+            Change::Add(key, value) if self.contains(key, value) => format!("{}={} already present", key, value),
+            Change::Add(key, value) => {
+                self.add(key, value);
+                format!("{}={} added", key, value)
+            }
+            Change::Remove(key, value) => {
+                let shown = value.as_ref().map_or(key.clone(), |value| format!("{}={}", key, value));
+                if self.remove(key, value.as_deref()) { format!("{} removed", shown) } else { format!("{} was not set", shown) }
+            }
+        }
+    }
+
+    /// Append a line where it applies to every model, below the marker comment
+    fn append(&mut self, line: String) {
+        let conditional_at_end = self.scoped().last().is_some_and(|&(_, conditional)| conditional);
+        if !self.lines.iter().any(|existing| existing.trim() == MARKER) || conditional_at_end {
+            if self.lines.last().is_some_and(|last| !last.trim().is_empty()) {
+                self.lines.push(String::new());
+            }
+            self.lines.push(MARKER.to_string());
+            if conditional_at_end {
+                self.lines.push("[all]".to_string());
+            }
+        }
+        self.lines.push(line);
+        self.trailing_newline = true;
+    }
+}
+
+/// Replace `path` so a crash leaves either the old or the new file, never a
+/// truncated one: the firmware doesn't boot without config.txt
+pub fn write_atomic(path: &str, content: &str) -> Result<()> {
+    let temp = format!("{}.rpi-fs-shrink.tmp", path);
+    let mut file = File::create(&temp).context(format!("Failed to create {}", temp))?;
+    file.write_all(content.as_bytes()).context(format!("Failed to write {}", temp))?;
+    file.sync_all().context(format!("Failed to flush {}", temp))?;
+    std::fs::rename(&temp, path).context(format!("Failed to replace {}", path))?;
+    if let Some(dir) = Path::new(path).parent()
+        && let Ok(dir) = File::open(dir)
+    {
+        let _ = dir.sync_all();
+    }
+    Ok(())
+}
+
+/// Edit the config.txt at `path`; it is only rewritten when the edit changed
+/// something. Returns whether it did.
+pub fn edit(path: &str, f: impl FnOnce(&mut ConfigTxt)) -> Result<bool> {
+    let text = std::fs::read_to_string(path).context(format!("Failed to read {}", path))?;
+    let mut config = ConfigTxt::parse(&text);
+    f(&mut config);
+    let edited = config.render();
+    if edited == text {
+        return Ok(false);
+    }
+    write_atomic(path, &edited)?;
+    Ok(true)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PI_OS: &str = "# For more options and information see\n\
+        # http://rptl.io/configtxt\n\
+        \n\
+        # Uncomment some or all of these to enable the optional hardware interfaces\n\
+        #dtparam=i2c_arm=on\n\
+        dtparam=audio=on\n\
+        camera_auto_detect=1\n\
+        \n\
+        [cm4]\n\
+        otg_mode=1\n\
+        \n\
+        [pi4]\n\
+        arm_boost=1\n";
+
+    #[test]
+    fn untouched_files_render_unchanged() {
+        for text in [PI_OS, "", "kernel=kernel8.img", "\n\n# only comments\n"] {
+            assert_eq!(ConfigTxt::parse(text).render(), text);
+        }
+    }
+
+    #[test]
+    fn settings_change_in_place() {
+        let mut config = ConfigTxt::parse("# camera\ncamera_auto_detect=1\ndisable_overscan=1\n");
+        config.set("camera_auto_detect", "0");
+        assert_eq!(config.render(), "# camera\ncamera_auto_detect=0\ndisable_overscan=1\n");
+    }
+
+    #[test]
+    fn new_settings_go_into_an_all_section() {
+        let mut config = ConfigTxt::parse(PI_OS);
+        config.set("initramfs", "initramfs8 followkernel");
+        config.add("dtoverlay", "overlay");
+        let rendered = config.render();
+        assert!(rendered.starts_with(PI_OS), "{}", rendered);
+        assert!(rendered.ends_with("\n\n# Added by rpi-fs-shrink\n[all]\ninitramfs initramfs8 followkernel\ndtoverlay=overlay\n"));
+        assert_eq!(config.get("initramfs"), Some("initramfs8 followkernel"));
+        // The Pi 4 section sets arm_boost only for the Pi 4
+        assert_eq!(config.get("arm_boost"), None);
+    }
+
+    #[test]
+    fn conditional_settings_are_overridden_not_edited() {
+        let mut config = ConfigTxt::parse("[pi4]\nkernel=kernel8.img\n");
+        config.set("kernel", "vmlinuz");
+        assert_eq!(config.render(), "[pi4]\nkernel=kernel8.img\n\n# Added by rpi-fs-shrink\n[all]\nkernel=vmlinuz\n");
+    }
+
+    #[test]
+    fn repeated_settings_are_added_once_and_removed() {
+        let mut config = ConfigTxt::parse(PI_OS);
+        config.add("dtparam", "audio=on");
+        assert_eq!(config.render(), PI_OS);
+        config.add("dtoverlay", "overlay");
+        config.add("dtoverlay", "overlay");
+        assert_eq!(config.render().matches("dtoverlay=overlay").count(), 1);
+        assert!(config.remove("dtoverlay", Some("overlay")));
+        assert!(!config.contains("dtoverlay", "overlay"));
+        // The commented-out setting stays a comment
+        assert!(config.render().contains("#dtparam=i2c_arm=on"));
+    }
+
+    #[test]
+    fn command_line_changes_parse() {
+        assert_eq!(parse_change("arm_boost=1").unwrap(), Change::Set("arm_boost".into(), "1".into()));
+        assert_eq!(parse_change("dtoverlay=overlay").unwrap(), Change::Add("dtoverlay".into(), "overlay".into()));
+        assert_eq!(parse_change("-dtoverlay=vc4-kms-v3d").unwrap(), Change::Remove("dtoverlay".into(), Some("vc4-kms-v3d".into())));
+        assert_eq!(parse_change("-arm_boost").unwrap(), Change::Remove("arm_boost".into(), None));
+        for text in ["", "arm_boost", "=1", "[pi4]", "# comment"] {
+            assert!(parse_change(text).is_err(), "{}", text);
+        }
+    }
+}
@@ -0,0 +1,184 @@
+use anyhow::{bail, Context, Result};
+use std::ffi::CString;
+use std::path::Path;
+use std::process::Command;
+
+/// Identify the container runtime we are running under, if any
+pub fn detect_container() -> Option<String> {
+    if let Ok(kind) = std::env::var("container") {
+        if !kind.is_empty() {
+            return Some(kind);
+        }
+    }
+
+    if Path::new("/.dockerenv").exists() {
+        return Some("docker".to_string());
+    }
+    if Path::new("/run/.containerenv").exists() {
+        return Some("podman".to_string());
+    }
+
+    let cgroup = std::fs::read_to_string("/proc/1/cgroup").unwrap_or_default();
+    for (marker, kind) in [("kubepods", "kubernetes"), ("docker", "docker"), ("lxc", "lxc")] {
+        if cgroup.contains(marker) {
+            return Some(kind.to_string());
+        }
+    }
+
+    None
+}
+
+fn is_writable(path: &str) -> bool {
+    let Ok(c_path) = CString::new(path) else {
+        return false;
+    };
+    unsafe { libc::access(c_path.as_ptr(), libc::W_OK) == 0 }
+}
+
+/// Pick the directory under which target filesystems get mounted.
+/// /mnt is used on a normal host; containers often have it read-only or shared.
+pub fn default_mount_base(in_container: bool) -> String {
+    if !in_container && is_writable("/mnt") {
+        return "/mnt".to_string();
+    }
+
+    std::env::temp_dir()
+        .join(format!("rpi-fs-shrink-{}", std::process::id()))
+        .to_string_lossy()
+        .to_string()
+}
+
+/// Check that the container can reach `target` before anything depends on it:
+/// a disk must have been passed in (`--device`) and be openable under the
+/// device cgroup, and an image file needs loop devices
+pub fn check_passthrough(target: &str) -> Result<()> {
+    if target.contains("://") {
+        return Ok(());
+    }
+    let path = Path::new(target);
+    if path.is_file() {
+        if !Path::new("/dev/loop-control").exists() {
+            bail!(
+                "Image files are attached to loop devices, and this container has no /dev/loop-control; \
+                run it with --privileged, or with --device /dev/loop-control and the /dev/loopN devices"
+            );
+        }
+        return Ok(());
+    }
+    let Ok(meta) = std::fs::metadata(path) else {
+        bail!("{} isn't in this container; pass the disk in with `--device {}` (docker or podman run)", target, target);
+    };
+    if !std::os::unix::fs::FileTypeExt::is_block_device(&meta.file_type()) {
+        bail!("{} is neither a block device nor an image file", target);
+    }
+    // Only root gets past the permissions, so a refusal then comes from the device cgroup
+    if crate::privilege::is_root()
+        && let Err(err) = std::fs::File::open(path)
+        && err.kind() == std::io::ErrorKind::PermissionDenied
+    {
+        bail!(
+            "The container may not open {} ({}); run it with `--device {}` rather than a bind mount of the node, or with --privileged",
+            target,
+            err,
+            target
+        );
+    }
+    Ok(())
+}
+
+/// Create the device node of `node` (/dev/loop0p2, /dev/sda3, ...) from its
+/// major:minor in sysfs. A container's /dev is a copy made at start, with no
+/// udev and often no uevents, so partitions added later get no node of their
+/// own. Returns false when the kernel doesn't know the device (yet).
+pub fn make_node(node: &str) -> Result<bool> {
+    let name = node.trim_start_matches("/dev/");
+    let Ok(numbers) = std::fs::read_to_string(format!("/sys/class/block/{}/dev", name)) else {
+        return Ok(false);
+    };
+    let Some((major, minor)) = numbers.trim().split_once(':') else {
+        bail!("Unreadable device numbers for {}: {}", name, numbers.trim());
+    };
+    let (major, minor): (u32, u32) = (major.parse()?, minor.parse()?);
+    let c_node = CString::new(node)?;
+    // SAFETY: the path is NUL-terminated; mknod only reads it
+    if unsafe { libc::mknod(c_node.as_ptr(), libc::S_IFBLK | 0o660, libc::makedev(major, minor)) } != 0 {
+        let err = std::io::Error::last_os_error();
+        // Created meanwhile by whatever does run in the container
+        if err.kind() == std::io::ErrorKind::AlreadyExists {
+            return Ok(true);
+        }
+        bail!("Failed to create {} ({}:{}): {}", node, major, minor, err);
+    }
+    Ok(true)
+}
+
+fn losetup(args: &[String]) -> Result<String> {
+    let output = Command::new("losetup").args(args).output().context("Failed to run losetup")?;
+    if !output.status.success() {
+        bail!("{}", String::from_utf8_lossy(&output.stderr).trim());
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).trim().to_string())
+}
+
+/// Attach `file` to a free loop device, from byte `offset` and for `size` bytes
+/// when given. busybox's losetup has no long options, no --show and no size
+/// limit, so there the free device is asked for first and then used.
+pub fn attach_loop(file: &str, partscan: bool, offset: Option<u64>, size: Option<u64>) -> Result<String> {
+    let mut args: Vec<String> = Vec::new();
+    if crate::busybox::is_busybox("losetup") {
+        if size.is_some() {
+            bail!("busybox losetup can't limit a loop device's size; install util-linux's losetup");
+        }
+        if partscan {
+            args.push("-P".to_string());
+        }
+        if let Some(offset) = offset {
+            args.extend(["-o".to_string(), offset.to_string()]);
+        }
+        let device = losetup(&["-f".to_string()])?;
+        args.extend([device.clone(), file.to_string()]);
+        losetup(&args)?;
+        return Ok(device);
+    }
+    args.extend(["--find".to_string(), "--show".to_string()]);
+    if partscan {
+        args.push("--partscan".to_string());
+    }
+    if let Some(offset) = offset {
+        args.extend(["--offset".to_string(), offset.to_string()]);
+    }
+    if let Some(size) = size {
+        args.extend(["--sizelimit".to_string(), size.to_string()]);
+    }
+    args.push(file.to_string());
+    losetup(&args)
+}
+
+/// Attach an image file to a free loop device with partition scanning
+pub fn attach_image(image: &str) -> Result<String> {
+    let device = attach_loop(image, true, None, None)
+        .context(format!("Failed to attach {} to a loop device (is the container privileged?)", image))?;
+    // Without partition scanning (some container runtimes) the partitions come from kpartx
+    crate::reread_partitions(&device);
+    println!("  Attached {} to {}", image, device);
+    Ok(device)
+}
+
+pub fn detach_loop(device: &str) -> Result<()> {
+    // kpartx mappings keep the loop device busy
+    if crate::sysfs::has_holders(device) && crate::command_exists("kpartx") {
+        let _ = Command::new("kpartx").args(["-d", device]).status();
+    }
+    // -d rather than --detach, which busybox's losetup doesn't know
+    let status = Command::new("losetup")
+        .args(["-d", device])
+        .status()
+        .context("Failed to run losetup")?;
+
+    if !status.success() {
+        bail!("Failed to detach {}", device);
+    }
+
+    println!("  Detached {}", device);
+    Ok(())
+}
@@ -0,0 +1,102 @@
+use anyhow::Result;
+use std::path::Path;
+
+use crate::timing::format_bytes;
+
+/// Below this, copying container storage along with /var is not worth a mention
+const LARGE_STORE_BYTES: u64 = 2 * 1024 * 1024 * 1024;
+
+/// Engine, storage directory, and the directories in it that hold image layers
+/// and container state, which a fresh pull recreates
+const ENGINES: &[(&str, &str, &[&str])] = &[
+    ("Docker", "/var/lib/docker", &["overlay2", "image", "containers", "buildkit"]),
+    (
+        "Podman",
+        "/var/lib/containers",
+        &["storage/overlay", "storage/overlay-images", "storage/overlay-layers", "storage/overlay-containers"],
+    ),
+];
+
+/// Container storage found on the target
+#[derive(Debug, Clone)]
+pub struct Store {
+    pub engine: &'static str,
+    /// Path on the target, e.g. /var/lib/docker
+    pub path: &'static str,
+    pub bytes: u64,
+    /// Bytes in image layers and container state
+    pub layer_bytes: u64,
+    layer_dirs: &'static [&'static str],
+}
+
+impl Store {
+    /// --exclude patterns that leave out the image layers and container state
+    pub fn layer_patterns(&self) -> Vec<String> {
+        self.layer_dirs.iter().map(|dir| format!("{}/{}", self.path, dir)).collect()
+    }
+}
+
+/// Docker and Podman storage on the target root mounted at `root`
+pub fn detect(root: &str) -> Result<Vec<Store>> {
+    let mut stores = Vec::new();
+    for (engine, path, layer_dirs) in ENGINES {
+        let full = format!("{}{}", root, path);
+        if !Path::new(&full).is_dir() {
+            continue;
+        }
+        let (_, bytes) = crate::cleanup::tree_usage(&full)?;
+        let mut layer_bytes = 0;
+        for dir in layer_dirs.iter() {
+            let layers = format!("{}/{}", full, dir);
+            if Path::new(&layers).is_dir() {
+                layer_bytes += crate::cleanup::tree_usage(&layers)?.1;
+            }
+        }
+        stores.push(Store { engine, path, bytes, layer_bytes, layer_dirs });
+    }
+    Ok(stores)
+}
+
+/// Where a container storage partition is mounted: Docker's directory unless
+/// only Podman's was found
+pub fn partition_target(stores: &[Store]) -> &'static str {
+    stores.first().map(|store| store.path).unwrap_or(ENGINES[0].1)
+}
+
+/// `partition_target` for the target root mounted at `root`, without sizing the stores
+pub fn partition_target_in(root: &str) -> &'static str {
+    ENGINES
+        .iter()
+        .map(|(_, path, _)| *path)
+        .find(|path| Path::new(&format!("{}{}", root, path)).is_dir())
+        .unwrap_or(ENGINES[0].1)
+}
+
+pub fn print_stores(stores: &[Store], partition: Option<&str>, skip_layers: bool) {
+    if stores.is_empty() {
+        return;
+    }
+    println!("\nContainer storage on the target:");
+    for store in stores {
+        println!(
+            "  {}: {} ({}, of which {} image layers and containers)",
+            store.engine,
+            store.path,
+            format_bytes(store.bytes),
+            format_bytes(store.layer_bytes)
+        );
+        if partition == Some(store.path) {
+            println!("    Moved to its own partition");
+        }
+        if skip_layers {
+            println!("    Image layers and containers are not copied; only volumes and settings are");
+        }
+    }
+    if skip_layers {
+        println!("  Warning: every image and container is lost. Images have to be pulled (or built) again and");
+        println!("  containers recreated on the target; data in named volumes is kept");
+    } else if partition.is_none() && stores.iter().any(|store| store.bytes >= LARGE_STORE_BYTES) {
+        println!("  Copying this is likely to take most of the migration time. --container-size gives it its");
+        println!("  own partition, and --skip-container-layers leaves images and containers behind");
+    }
+}
@@ -0,0 +1,362 @@
+//! The tool the /var, /home and container storage copies run with: rsync by
+//! default (`--rsync-path`, `--rsync-args`), or tar or cpio where rsync isn't
+//! available (`--copy-backend`).
+//!
+//! All three keep what `rsync -a` keeps: owner and group (numerically),
+//! permissions including setuid/setgid/sticky, modification times, symlinks,
+//! device nodes, FIFOs and sockets, and none of them leave the source
+//! filesystem. Like rsync without -H, -A and -X, tar and cpio aren't asked
+//! for ACLs or extended attributes. For tar and cpio the file list is built
+//! here, so `--exclude` patterns match exactly as they do for rsync, and the
+//! archive is streamed from one process to the other through this one,
+//! which counts its bytes for progress.
+
+use anyhow::{bail, Context, Result};
+use clap::ValueEnum;
+use regex::Regex;
+use std::io::{Read, Write};
+use std::os::unix::fs::MetadataExt;
+use std::path::Path;
+use std::process::{Command, Stdio};
+use std::sync::OnceLock;
+
+use crate::exclude::Excludes;
+use crate::{privilege, progress, thermal};
+
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CopyBackend {
+    Rsync,
+    Tar,
+    Cpio,
+}
+
+impl CopyBackend {
+    pub fn name(self) -> &'static str {
+        match self {
+            CopyBackend::Rsync => "rsync",
+            CopyBackend::Tar => "tar",
+            CopyBackend::Cpio => "cpio",
+        }
+    }
+}
+
+struct CopyTool {
+    backend: CopyBackend,
+    rsync_path: String,
+    rsync_args: Vec<String>,
+}
+
+static TOOL: OnceLock<CopyTool> = OnceLock::new();
+
+/// Choose the copy tool for this run; without a call, copies use the rsync in $PATH
+pub fn configure(backend: CopyBackend, rsync_path: Option<&str>, rsync_args: Option<&str>) -> Result<()> {
+    if backend != CopyBackend::Rsync && (rsync_path.is_some() || rsync_args.is_some()) {
+        bail!("--rsync-path and --rsync-args only apply to --copy-backend rsync");
+    }
+    if let Some(path) = rsync_path
+        && crate::busybox::find_in_path(path).is_none()
+    {
+        bail!("--rsync-path {}: not an executable", path);
+    }
+    let rsync_args = match rsync_args {
+        Some(args) => crate::format::split_args(args).context("Invalid --rsync-args")?,
+        None => Vec::new(),
+    };
+    let tool = CopyTool { backend, rsync_path: rsync_path.unwrap_or("rsync").to_string(), rsync_args };
+    if TOOL.set(tool).is_err() {
+        bail!("The copy tool is already chosen");
+    }
+    Ok(())
+}
+
+fn tool() -> &'static CopyTool {
+    TOOL.get_or_init(|| CopyTool { backend: CopyBackend::Rsync, rsync_path: "rsync".to_string(), rsync_args: Vec::new() })
+}
+
+/// What a copy takes from its tree
+#[derive(Debug, Default)]
+pub struct Selection<'a> {
+    /// Only this top-level entry of the tree
+    pub entry: Option<&'a str>,
+    /// Delete each copied file (not directory) from the source once it is copied
+    pub remove_source: bool,
+}
+
+/// Copy the contents of `source` into `dest`, leaving out what `excludes`
+/// names for /`tree`
+pub fn copy(source: &str, dest: &str, tree: &str, excludes: &Excludes, selection: &Selection) -> Result<()> {
+    let tool = tool();
+    match tool.backend {
+        CopyBackend::Rsync => {
+            let mut args = excludes.rsync_args(tree);
+            if let Some(entry) = selection.entry {
+                args.extend(entry_filter(entry));
+            }
+            if selection.remove_source {
+                args.push("--remove-source-files".to_string());
+            }
+            // Last, so they win
+            args.extend(tool.rsync_args.iter().cloned());
+            rsync_tree(&tool.rsync_path, source, dest, tree, &args)
+        }
+        backend => {
+            let (paths, bytes) = file_list(source, tree, excludes, selection.entry)?;
+            stream_tree(backend, source, dest, tree, &paths, bytes)?;
+            if selection.remove_source {
+                for path in &paths {
+                    let full = Path::new(source).join(path);
+                    if !std::fs::symlink_metadata(&full).is_ok_and(|meta| meta.is_dir()) {
+                        std::fs::remove_file(&full).context(format!("Failed to delete {}", full.display()))?;
+                    }
+                }
+            }
+            Ok(())
+        }
+    }
+}
+
+/// rsync filter for one top-level entry: wildcard characters in the name are literal
+fn entry_filter(name: &str) -> Vec<String> {
+    let mut escaped = String::new();
+    for c in name.chars() {
+        if matches!(c, '*' | '?' | '[' | '\\') {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    vec![format!("--include=/{}/***", escaped), "--exclude=/*".to_string()]
+}
+
+/// rsync the contents of `source` into `dest` with `args` on top of the archive
+/// options, following its progress
+fn rsync_tree(rsync: &str, source: &str, dest: &str, tree: &str, args: &[String]) -> Result<()> {
+    // Overall progress of the whole tree; without incremental recursion rsync
+    // knows the file count up front, so its percentage doesn't jump back
+    let mut child = Command::new(rsync)
+        .args(["-avx", "--info=progress2", "--no-inc-recursive"])
+        .args(args)
+        .args([&format!("{}/", source), &format!("{}/", dest)])
+        .args(privilege::is_capability_run().then_some("--super"))
+        .stdout(Stdio::piped())
+        .spawn()
+        .context(format!("Failed to run rsync for /{}", tree))?;
+    let _pausable = thermal::pausable(child.id());
+    if let Some(stdout) = child.stdout.take() {
+        follow_rsync_progress(stdout)?;
+    }
+    let status = child.wait().context(format!("Failed to run rsync for /{}", tree))?;
+
+    if !status.success() {
+        bail!("rsync failed for /{}", tree);
+    }
+    Ok(())
+}
+
+/// Pass rsync's output through to the terminal, reporting its progress lines
+/// ("  1,234,567  45%  10.00MB/s    0:01:23 (xfr#12, to-chk=100/200)") on the way
+fn follow_rsync_progress(mut output: impl Read) -> Result<()> {
+    let progress_re = Regex::new(r"^\s*([\d,]+)\s+(\d+)%\s+\S+\s+(\d+):(\d+):(\d+)")?;
+    let mut stdout = std::io::stdout();
+    let mut line = Vec::new();
+    let mut buffer = [0u8; 4096];
+    loop {
+        let read = output.read(&mut buffer)?;
+        if read == 0 {
+            return Ok(());
+        }
+        stdout.write_all(&buffer[..read])?;
+        stdout.flush()?;
+        for &byte in &buffer[..read] {
+            if byte != b'\r' && byte != b'\n' {
+                line.push(byte);
+                continue;
+            }
+            if let Some(caps) = progress_re.captures(&String::from_utf8_lossy(&line)) {
+                let bytes = caps[1].replace(',', "").parse().unwrap_or(0);
+                let percent: f64 = caps[2].parse().unwrap_or(0.0);
+                let field = |index: usize| caps[index].parse::<u64>().unwrap_or(0);
+                let eta = std::time::Duration::from_secs(field(3) * 3600 + field(4) * 60 + field(5));
+                progress::step_progress(bytes, Some(percent / 100.0), Some(eta));
+            }
+            line.clear();
+        }
+    }
+}
+
+/// Paths under `source` (relative, `entry` and below when given) that the copy
+/// of /`tree` takes, each directory after its contents as cpio wants them, and
+/// the bytes of their files. Other filesystems mounted inside are left alone.
+fn file_list(source: &str, tree: &str, excludes: &Excludes, entry: Option<&str>) -> Result<(Vec<String>, u64)> {
+    let device = std::fs::symlink_metadata(source).context(format!("Failed to read {}", source))?.dev();
+    let mut paths = Vec::new();
+    let mut bytes = 0;
+    let top = match entry {
+        Some(entry) => vec![entry.to_string()],
+        None => {
+            let mut names: Vec<String> = std::fs::read_dir(source)
+                .context(format!("Failed to read {}", source))?
+                .filter_map(|entry| entry.ok())
+                .map(|entry| entry.file_name().to_string_lossy().to_string())
+                .collect();
+            names.sort();
+            names
+        }
+    };
+    for name in top {
+        collect(source, &name, tree, excludes, device, &mut paths, &mut bytes)?;
+    }
+    Ok((paths, bytes))
+}
+
+fn collect(source: &str, relative: &str, tree: &str, excludes: &Excludes, device: u64, paths: &mut Vec<String>, bytes: &mut u64) -> Result<()> {
+    let full = format!("{}/{}", source, relative);
+    let meta = std::fs::symlink_metadata(&full).context(format!("Failed to read {}", full))?;
+    if excludes.is_excluded(tree, relative, meta.is_dir()) {
+        return Ok(());
+    }
+    if meta.is_dir() && meta.dev() == device {
+        let mut names: Vec<String> = std::fs::read_dir(&full)
+            .context(format!("Failed to read {}", full))?
+            .filter_map(|entry| entry.ok())
+            .map(|entry| entry.file_name().to_string_lossy().to_string())
+            .collect();
+        names.sort();
+        for name in names {
+            collect(source, &format!("{}/{}", relative, name), tree, excludes, device, paths, bytes)?;
+        }
+    }
+    // A mount point is copied as the empty directory it is on this filesystem
+    if meta.is_file() {
+        *bytes += meta.len();
+    }
+    paths.push(relative.to_string());
+    Ok(())
+}
+
+/// Archive `paths` of `source` with tar or cpio and unpack the stream in `dest`
+fn stream_tree(backend: CopyBackend, source: &str, dest: &str, tree: &str, paths: &[String], bytes: u64) -> Result<()> {
+    let name = backend.name();
+    let mut create = Command::new(name);
+    let mut extract = Command::new(name);
+    match backend {
+        CopyBackend::Tar => {
+            // --directory comes first: it only applies to the names after it
+            create.arg(format!("--directory={}", source));
+            create.args(["--create", "--file=-", "--no-recursion", "--numeric-owner", "--null", "--files-from=-"]);
+            extract.arg(format!("--directory={}", dest));
+            extract.args(["--extract", "--file=-", "--same-permissions", "--same-owner", "--numeric-owner"]);
+        }
+        CopyBackend::Cpio => {
+            create.args(["-o", "-0", "-H", "newc"]).current_dir(source);
+            extract.args(["-i", "-d", "-m", "-u"]).current_dir(dest);
+        }
+        CopyBackend::Rsync => unreachable!("rsync copies with rsync_tree"),
+    }
+    println!("  Copying {} entries of /{} with {}...", paths.len(), tree, name);
+    let mut creator = create
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+        .context(format!("Failed to run {} for /{}", name, tree))?;
+    let mut extractor = match extract.stdin(Stdio::piped()).stdout(Stdio::null()).spawn() {
+        Ok(child) => child,
+        Err(err) => {
+            let _ = creator.kill();
+            let _ = creator.wait();
+            return Err(err).context(format!("Failed to run {} for /{}", name, tree));
+        }
+    };
+    let _pausable = (thermal::pausable(creator.id()), thermal::pausable(extractor.id()));
+
+    let list: Vec<u8> = paths.iter().flat_map(|path| path.bytes().chain([0])).collect();
+    let mut list_in = creator.stdin.take().context("No stdin")?;
+    let feeder = std::thread::spawn(move || list_in.write_all(&list));
+
+    let relayed = (|| -> Result<()> {
+        let mut archive = creator.stdout.take().context("No stdout")?;
+        let mut unpack = extractor.stdin.take().context("No stdin")?;
+        let mut buffer = vec![0u8; 1 << 20];
+        let mut done = 0u64;
+        loop {
+            let read = archive.read(&mut buffer)?;
+            if read == 0 {
+                return Ok(());
+            }
+            unpack.write_all(&buffer[..read])?;
+            done += read as u64;
+            // Headers and padding make the stream a little longer than the data
+            progress::step_progress(done, (bytes > 0).then(|| done as f64 / bytes as f64), None);
+        }
+    })();
+    let fed = feeder.join().map_err(|_| anyhow::anyhow!("The file list writer panicked"))?;
+    let created = creator.wait()?;
+    let extracted = extractor.wait()?;
+    relayed.context(format!("Failed to pass the {} archive of /{} on", name, tree))?;
+    fed.context(format!("Failed to pass the file list of /{} to {}", tree, name))?;
+    if !created.success() || !extracted.success() {
+        bail!("{} failed for /{}", name, tree);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::PermissionsExt;
+
+    fn scratch(name: &str) -> String {
+        let dir = std::env::temp_dir().join(format!("rpi-fs-shrink-copy-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir.to_string_lossy().to_string()
+    }
+
+    #[test]
+    fn entry_names_are_matched_literally() {
+        assert_eq!(entry_filter("pi"), vec!["--include=/pi/***", "--exclude=/*"]);
+        assert_eq!(entry_filter("a*b"), vec!["--include=/a\\*b/***", "--exclude=/*"]);
+    }
+
+    #[test]
+    fn file_list_puts_directories_after_their_contents_and_leaves_out_excludes() {
+        let source = scratch("list");
+        std::fs::create_dir_all(format!("{}/pi/.cache", source)).unwrap();
+        std::fs::write(format!("{}/pi/notes", source), "12345").unwrap();
+        std::fs::write(format!("{}/pi/.cache/big", source), "x").unwrap();
+        std::fs::create_dir(format!("{}/bob", source)).unwrap();
+        let excludes = Excludes::new(&["/home/*/.cache".to_string()]).unwrap();
+
+        let (paths, bytes) = file_list(&source, "home", &excludes, None).unwrap();
+        assert_eq!(paths, vec!["bob", "pi/notes", "pi"]);
+        assert_eq!(bytes, 5);
+        let (paths, _) = file_list(&source, "home", &excludes, Some("pi")).unwrap();
+        assert_eq!(paths, vec!["pi/notes", "pi"]);
+        std::fs::remove_dir_all(&source).unwrap();
+    }
+
+    #[test]
+    fn tar_keeps_modes_times_and_symlinks() {
+        if !crate::command_exists("tar") {
+            return;
+        }
+        let source = scratch("tar-source");
+        let dest = scratch("tar-dest");
+        std::fs::create_dir(format!("{}/pi", source)).unwrap();
+        let file = format!("{}/pi/secret", source);
+        std::fs::write(&file, "data").unwrap();
+        std::fs::set_permissions(&file, std::fs::Permissions::from_mode(0o4750)).unwrap();
+        std::os::unix::fs::symlink("secret", format!("{}/pi/link", source)).unwrap();
+        let old = std::time::SystemTime::UNIX_EPOCH + std::time::Duration::from_secs(1_600_000_000);
+        std::fs::File::options().write(true).open(&file).unwrap().set_modified(old).unwrap();
+
+        let (paths, bytes) = file_list(&source, "home", &Excludes::new(&[]).unwrap(), None).unwrap();
+        stream_tree(CopyBackend::Tar, &source, &dest, "home", &paths, bytes).unwrap();
+
+        let copied = std::fs::symlink_metadata(format!("{}/pi/secret", dest)).unwrap();
+        assert_eq!(copied.permissions().mode() & 0o7777, 0o4750);
+        assert_eq!(copied.modified().unwrap(), old);
+        assert_eq!(std::fs::read_link(format!("{}/pi/link", dest)).unwrap(), Path::new("secret"));
+        std::fs::remove_dir_all(&source).unwrap();
+        std::fs::remove_dir_all(&dest).unwrap();
+    }
+}
@@ -0,0 +1,134 @@
+use anyhow::Result;
+use std::path::Path;
+
+use crate::timing::format_bytes;
+
+/// Database, data directory under /var, systemd units that run it, and how to
+/// check it after the move
+const DATABASES: &[(&str, &str, &[&str], &str)] = &[
+    (
+        "MySQL/MariaDB",
+        "/var/lib/mysql",
+        &["mysql.service", "mariadb.service", "mysqld.service"],
+        "mysqlcheck --all-databases",
+    ),
+    ("PostgreSQL", "/var/lib/postgresql", &["postgresql.service"], "pg_amcheck --all"),
+    ("InfluxDB", "/var/lib/influxdb", &["influxdb.service", "influxd.service"], "influx_inspect verify (1.x) or influxd inspect verify-tsm (2.x)"),
+];
+
+/// A database with data under /var on the target
+#[derive(Debug, Clone)]
+pub struct Database {
+    pub name: &'static str,
+    pub path: &'static str,
+    pub bytes: u64,
+    /// Whether the target starts it at boot
+    pub enabled: bool,
+    /// A lock or pid file the server leaves behind when it didn't shut down cleanly
+    pub unclean: Option<String>,
+    pub check: &'static str,
+}
+
+impl Database {
+    pub fn to_json(&self) -> serde_json::Value {
+        serde_json::json!({
+            "name": self.name,
+            "path": self.path,
+            "bytes": self.bytes,
+            "enabled": self.enabled,
+            "clean_shutdown": self.unclean.is_none(),
+            "unclean_marker": self.unclean,
+            "check": self.check,
+        })
+    }
+}
+
+/// Databases with data under /var on the target root mounted at `root`
+pub fn detect(root: &str) -> Result<Vec<Database>> {
+    let mut found = Vec::new();
+    for (name, path, units, check) in DATABASES {
+        let full = format!("{}{}", root, path);
+        let Ok(mut entries) = std::fs::read_dir(&full) else {
+            continue;
+        };
+        // Packages create the directory; an empty one holds nothing to lose
+        if entries.next().is_none() {
+            continue;
+        }
+        let (_, bytes) = crate::cleanup::tree_usage(&full)?;
+        found.push(Database {
+            name,
+            path,
+            bytes,
+            enabled: units.iter().any(|unit| unit_enabled(root, unit)),
+            unclean: unclean_marker(root, path),
+            check,
+        });
+    }
+    Ok(found)
+}
+
+/// Enabled through a wants link of a boot target, and not masked
+fn unit_enabled(root: &str, unit: &str) -> bool {
+    let system = format!("{}/etc/systemd/system", root);
+    if std::fs::read_link(format!("{}/{}", system, unit)).is_ok_and(|target| target == Path::new("/dev/null")) {
+        return false;
+    }
+    ["multi-user.target.wants", "graphical.target.wants"]
+        .iter()
+        .any(|wants| Path::new(&format!("{}/{}/{}", system, wants, unit)).exists())
+}
+
+/// A pid file in the data directory (or one level down, where PostgreSQL keeps a
+/// cluster per version), which a clean shutdown removes
+fn unclean_marker(root: &str, path: &str) -> Option<String> {
+    let full = format!("{}{}", root, path);
+    let mut dirs = vec![full.clone()];
+    for entry in std::fs::read_dir(&full).ok()?.flatten() {
+        if entry.file_type().is_ok_and(|t| t.is_dir()) {
+            dirs.push(entry.path().to_string_lossy().to_string());
+            // PostgreSQL: /var/lib/postgresql/<version>/<cluster>
+            if let Ok(clusters) = std::fs::read_dir(entry.path()) {
+                dirs.extend(clusters.flatten().filter(|c| c.file_type().is_ok_and(|t| t.is_dir())).map(|c| c.path().to_string_lossy().to_string()));
+            }
+        }
+    }
+    dirs.iter()
+        .filter_map(|dir| std::fs::read_dir(dir).ok())
+        .flat_map(|entries| entries.flatten())
+        .map(|entry| entry.path().to_string_lossy().to_string())
+        .find(|file| file.ends_with(".pid") && Path::new(file).is_file())
+        .map(|file| file[root.len()..].to_string())
+}
+
+pub fn print_databases(databases: &[Database], copied: bool) {
+    if databases.is_empty() {
+        return;
+    }
+    println!("\nDatabases with data under /var:");
+    for database in databases {
+        println!(
+            "  {}: {} ({}){}",
+            database.name,
+            database.path,
+            format_bytes(database.bytes),
+            if database.enabled { ", started at boot" } else { "" }
+        );
+        if let Some(ref marker) = database.unclean {
+            println!("    Not shut down cleanly: {} is still there", marker);
+        }
+        if copied {
+            println!("    Check after the first boot: {}", database.check);
+        }
+    }
+    if copied {
+        println!("  The files are copied as they are on disk, without the server running to flush or");
+        println!("  check them. A server that was shut down cleanly starts normally; one that wasn't");
+        println!("  replays its log on first start, and a torn write from the crash is copied along");
+    }
+}
+
+/// Databases the /var copy takes along in a state their server has to recover from
+pub fn unclean(databases: &[Database]) -> Vec<&Database> {
+    databases.iter().filter(|database| database.unclean.is_some()).collect()
+}
@@ -0,0 +1,157 @@
+//! How fragmented root is before the shrink (`--defrag report`), and an
+//! e4defrag pass to fix it (`--defrag run`). resize2fs relocates the blocks
+//! above the new end extent by extent, so a filesystem whose files are in
+//! many small pieces takes far longer to shrink than one with the same data
+//! in a few large ones.
+
+use anyhow::{bail, Context, Result};
+use clap::ValueEnum;
+use std::process::Command;
+
+/// e4defrag's score from which it recommends defragmenting
+const NEEDS_DEFRAG_SCORE: u64 = 56;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum DefragMode {
+    /// Show root's fragmentation and what defragmenting would save in the plan
+    Report,
+    /// Show it, then defragment root with e4defrag before the shrink
+    Run,
+}
+
+/// What `e4defrag -c` found
+#[derive(Debug, Clone, PartialEq)]
+pub struct Fragmentation {
+    pub extents: u64,
+    /// Extents the same files would take if each were contiguous
+    pub best_extents: u64,
+    pub extent_kib: u64,
+    /// 0-30 no problem, 31-55 a little fragmented, 56 and up needs defragmenting
+    pub score: u64,
+    /// The most fragmented files with their extents now and at best
+    pub worst: Vec<(String, u64, u64)>,
+}
+
+impl Fragmentation {
+    /// Share of the extents defragmenting would merge away
+    pub fn saving_percent(&self) -> u64 {
+        if self.extents == 0 {
+            return 0;
+        }
+        self.extents.saturating_sub(self.best_extents) * 100 / self.extents
+    }
+
+    pub fn print(&self) {
+        let verdict = match self.score {
+            0..=30 => "no problem",
+            31..NEEDS_DEFRAG_SCORE => "a little fragmented",
+            _ => "needs defragmenting",
+        };
+        println!("  Extents: {} now, {} at best, {} KB each on average", self.extents, self.best_extents, self.extent_kib);
+        println!("  Fragmentation score: {} ({})", self.score, verdict);
+        for (path, now, best) in &self.worst {
+            println!("    {} ({} extents, {} at best)", path, now, best);
+        }
+        println!(
+            "  Defragmenting first would leave resize2fs {} fewer extents to move ({}%){}",
+            self.extents.saturating_sub(self.best_extents),
+            self.saving_percent(),
+            if self.score < NEEDS_DEFRAG_SCORE { ", hardly worth the pass" } else { "; see --defrag run" }
+        );
+    }
+}
+
+/// Read `e4defrag -c` output: the numbered list of fragmented files, whose
+/// long paths wrap onto a line of their own, and the totals below it
+fn parse_report(text: &str) -> Option<Fragmentation> {
+    let total = |name: &str| {
+        text.lines().find_map(|line| line.trim().strip_prefix(name)).map(str::trim).map(|value| value.trim_end_matches("KB").trim())
+    };
+    let (extents, best_extents) = total("Total/best extents")?.split_once('/')?;
+    let mut worst = Vec::new();
+    let mut pending: Option<String> = None;
+    for line in text.lines().skip_while(|line| !line.starts_with("<Fragmented files>")).skip(1) {
+        if line.trim().is_empty() {
+            break;
+        }
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        let (path, counts) = match fields.as_slice() {
+            [number, path] if number.ends_with('.') => {
+                pending = Some(path.to_string());
+                continue;
+            }
+            [number, path, counts, ..] if number.ends_with('.') => (path.to_string(), *counts),
+            [counts, ..] => match pending.take() {
+                Some(path) => (path, *counts),
+                None => continue,
+            },
+            [] => continue,
+        };
+        if let Some((now, best)) = counts.split_once('/')
+            && let (Ok(now), Ok(best)) = (now.parse(), best.parse())
+        {
+            worst.push((path, now, best));
+        }
+    }
+    Some(Fragmentation {
+        extents: extents.parse().ok()?,
+        best_extents: best_extents.parse().ok()?,
+        extent_kib: total("Average size per extent")?.parse().ok()?,
+        score: total("Fragmentation score")?.parse().ok()?,
+        worst,
+    })
+}
+
+/// Fragmentation of the filesystem mounted at `mount_point`
+pub fn report(mount_point: &str) -> Result<Fragmentation> {
+    let output = Command::new("e4defrag").args(["-c", mount_point]).output().context("Failed to run e4defrag")?;
+    let text = String::from_utf8_lossy(&output.stdout);
+    if !output.status.success() {
+        bail!("e4defrag -c {} failed: {}", mount_point, String::from_utf8_lossy(&output.stderr).trim());
+    }
+    parse_report(&text).context(format!("e4defrag -c {} printed no totals", mount_point))
+}
+
+/// Defragment the filesystem mounted read-write at `mount_point`
+pub fn run(mount_point: &str) -> Result<()> {
+    let status = Command::new("e4defrag").arg(mount_point).status().context("Failed to run e4defrag")?;
+    if !status.success() {
+        bail!("e4defrag {} failed", mount_point);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn report_gives_totals_and_worst_files() {
+        let text = "e4defrag 1.47.0 (5-Feb-2023)
+<Fragmented files>                             now/best       size/ext
+1. /mnt/var/log/journal/system.journal          412/1           20 KB
+2. /mnt/usr/share/doc/nodejs/api/all.html.gz.uncompressed
+                                                  7/1           588 KB
+3. /mnt/etc/ld.so.cache                           2/1            24 KB
+
+ Total/best extents\t\t\t\t93709/81244
+ Average size per extent\t\t\t 26 KB
+ Fragmentation score\t\t\t\t61
+ [0-30 no problem: 31-55 a little bit fragmented: 56- needs defrag]
+ This directory (/mnt) needs defragmentation.
+ Done.
+";
+        let fragmentation = parse_report(text).unwrap();
+        assert_eq!((fragmentation.extents, fragmentation.best_extents, fragmentation.extent_kib, fragmentation.score), (93709, 81244, 26, 61));
+        assert_eq!(
+            fragmentation.worst,
+            vec![
+                ("/mnt/var/log/journal/system.journal".to_string(), 412, 1),
+                ("/mnt/usr/share/doc/nodejs/api/all.html.gz.uncompressed".to_string(), 7, 1),
+                ("/mnt/etc/ld.so.cache".to_string(), 2, 1),
+            ]
+        );
+        assert_eq!(fragmentation.saving_percent(), 13);
+        assert!(parse_report("e4defrag 1.47.0\n Done.\n").is_none());
+    }
+}
@@ -0,0 +1,249 @@
+use crate::blockcopy::checksum;
+use anyhow::{bail, Context, Result};
+use std::fs::{File, OpenOptions};
+use std::io::{BufReader, BufWriter, Read, Write};
+use std::os::unix::fs::FileExt;
+
+const MAGIC: &[u8; 8] = b"CRPDELT1";
+
+/// Unit of comparison; small enough that a changed config file ships little data
+const BLOCK_BYTES: usize = 64 * 1024;
+
+fn device_len(file: &File) -> Result<u64> {
+    use std::io::{Seek, SeekFrom};
+    let mut file = file;
+    Ok(file.seek(SeekFrom::End(0))?)
+}
+
+/// Read one block at `offset`, zero-padded past the end of the file
+fn read_block(file: &File, len: u64, offset: u64, buffer: &mut [u8]) -> Result<()> {
+    buffer.fill(0);
+    if offset < len {
+        let available = ((len - offset) as usize).min(buffer.len());
+        file.read_exact_at(&mut buffer[..available], offset)?;
+    }
+    Ok(())
+}
+
+/// Write the blocks of `new` that differ from `old` to `delta`.
+///
+/// Format: magic, block size (u32), old size, new size (u64), then per changed
+/// block its index (u64), a checksum of the old block (u64) and the new data.
+/// All integers little endian.
+pub fn create_delta(old: &str, new: &str, delta: &str) -> Result<()> {
+    let old_file = File::open(old).context(format!("Failed to open {}", old))?;
+    let new_file = File::open(new).context(format!("Failed to open {}", new))?;
+    let old_len = device_len(&old_file)?;
+    let new_len = device_len(&new_file)?;
+
+    let mut out = BufWriter::new(File::create(delta).context(format!("Failed to create {}", delta))?);
+    out.write_all(MAGIC)?;
+    out.write_all(&(BLOCK_BYTES as u32).to_le_bytes())?;
+    out.write_all(&old_len.to_le_bytes())?;
+    out.write_all(&new_len.to_le_bytes())?;
+
+    let mut old_block = vec![0u8; BLOCK_BYTES];
+    let mut new_block = vec![0u8; BLOCK_BYTES];
+    let blocks = new_len.div_ceil(BLOCK_BYTES as u64);
+    let mut changed = 0u64;
+
+    for index in 0..blocks {
+        let offset = index * BLOCK_BYTES as u64;
+        read_block(&old_file, old_len, offset, &mut old_block).context(format!("Failed to read {}", old))?;
+        read_block(&new_file, new_len, offset, &mut new_block).context(format!("Failed to read {}", new))?;
+        if old_block != new_block {
+            out.write_all(&index.to_le_bytes())?;
+            out.write_all(&checksum(&old_block).to_le_bytes())?;
+            out.write_all(&new_block)?;
+            changed += 1;
+        }
+    }
+    out.flush()?;
+
+    println!(
+        "  {} of {} blocks changed ({} MB of {} MB)",
+        changed,
+        blocks,
+        changed * BLOCK_BYTES as u64 / (1024 * 1024),
+        new_len / (1024 * 1024)
+    );
+    Ok(())
+}
+
+struct Header {
+    block_bytes: usize,
+    old_len: u64,
+    new_len: u64,
+}
+
+fn read_u64(reader: &mut impl Read) -> std::io::Result<u64> {
+    let mut bytes = [0u8; 8];
+    reader.read_exact(&mut bytes)?;
+    Ok(u64::from_le_bytes(bytes))
+}
+
+fn read_header(reader: &mut impl Read, delta: &str) -> Result<Header> {
+    let mut magic = [0u8; 8];
+    reader.read_exact(&mut magic).context(format!("{} is too short", delta))?;
+    if &magic != MAGIC {
+        bail!("{} is not a delta file", delta);
+    }
+    let mut block = [0u8; 4];
+    reader.read_exact(&mut block)?;
+    let block_bytes = u32::from_le_bytes(block) as usize;
+    if block_bytes == 0 || block_bytes > 16 * 1024 * 1024 {
+        bail!("{} has an invalid block size {}", delta, block_bytes);
+    }
+    Ok(Header { block_bytes, old_len: read_u64(reader)?, new_len: read_u64(reader)? })
+}
+
+/// Visit every record of a delta: block index, old checksum and new data
+fn for_each_record(delta: &str, mut visit: impl FnMut(&Header, u64, u64, &[u8]) -> Result<()>) -> Result<Header> {
+    let mut reader = BufReader::new(File::open(delta).context(format!("Failed to open {}", delta))?);
+    let header = read_header(&mut reader, delta)?;
+    let mut data = vec![0u8; header.block_bytes];
+
+    loop {
+        let index = match read_u64(&mut reader) {
+            Ok(index) => index,
+            Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => break,
+            Err(e) => return Err(e).context(format!("Failed to read {}", delta)),
+        };
+        let old_sum = read_u64(&mut reader).context(format!("{} is truncated", delta))?;
+        reader.read_exact(&mut data).context(format!("{} is truncated", delta))?;
+        visit(&header, index, old_sum, &data)?;
+    }
+    Ok(header)
+}
+
+/// Apply a delta to `target` (an image file or a disk holding the old image).
+/// Every block to be replaced is checked against the old image first, so a
+/// delta for a different base is refused before anything is written.
+pub fn apply_delta(target: &str, delta: &str) -> Result<()> {
+    let file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open(target)
+        .context(format!("Failed to open {}", target))?;
+    let target_len = device_len(&file)?;
+    let is_file = std::fs::metadata(target)?.is_file();
+
+    println!("  Verifying {} matches the delta's base image...", target);
+    let mut current = Vec::new();
+    let mut records = 0u64;
+    let header = for_each_record(delta, |header, index, old_sum, _| {
+        current.resize(header.block_bytes, 0);
+        read_block(&file, target_len.min(header.old_len), index * header.block_bytes as u64, &mut current)?;
+        if checksum(&current) != old_sum {
+            bail!("Block {} of {} does not match the delta's base image; nothing was written", index, target);
+        }
+        records += 1;
+        Ok(())
+    })?;
+
+    if header.new_len > target_len {
+        if !is_file {
+            bail!(
+                "{} is {} MB but the new image needs {} MB",
+                target,
+                target_len / (1024 * 1024),
+                header.new_len / (1024 * 1024)
+            );
+        }
+        file.set_len(header.new_len).context(format!("Failed to grow {}", target))?;
+    }
+
+    println!("  Writing {} changed blocks...", records);
+    for_each_record(delta, |header, index, _, data| {
+        let offset = index * header.block_bytes as u64;
+        let len = (header.new_len.saturating_sub(offset) as usize).min(data.len());
+        file.write_all_at(&data[..len], offset).context(format!("Failed to write block {} of {}", index, target))
+    })?;
+
+    if is_file && header.new_len < target_len {
+        file.set_len(header.new_len).context(format!("Failed to truncate {}", target))?;
+    }
+    file.sync_all().context(format!("Failed to flush {}", target))?;
+
+    println!("  {} updated ({} blocks)", target, records);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn image(len: usize, seed: u8) -> Vec<u8> {
+        (0..len).map(|i| (i % 241) as u8 ^ seed).collect()
+    }
+
+    #[test]
+    fn deltas_round_trip_and_refuse_other_bases() {
+        let dir = std::env::temp_dir().join(format!("rpi-fs-shrink-delta-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = |name: &str| dir.join(name).to_string_lossy().into_owned();
+        let old = image(5 * BLOCK_BYTES + 100, 0);
+        // Two blocks changed and the image grown by a block and a half
+        let mut new = old.clone();
+        new[BLOCK_BYTES + 7] ^= 0xff;
+        new[4 * BLOCK_BYTES] ^= 0xff;
+        new.extend(image(BLOCK_BYTES * 3 / 2, 9));
+        std::fs::write(path("old"), &old).unwrap();
+        std::fs::write(path("new"), &new).unwrap();
+
+        create_delta(&path("old"), &path("new"), &path("delta")).unwrap();
+        let delta_len = std::fs::metadata(path("delta")).unwrap().len() as usize;
+        // Header, then the two changed blocks, the partly changed last one and the new one
+        assert_eq!(delta_len, 28 + 4 * (16 + BLOCK_BYTES));
+        std::fs::write(path("target"), &old).unwrap();
+        apply_delta(&path("target"), &path("delta")).unwrap();
+        assert_eq!(std::fs::read(path("target")).unwrap(), new);
+
+        // Shrinking works the other way round
+        create_delta(&path("new"), &path("old"), &path("back")).unwrap();
+        apply_delta(&path("target"), &path("back")).unwrap();
+        assert_eq!(std::fs::read(path("target")).unwrap(), old);
+
+        // A target that doesn't hold the base is left alone
+        let other = image(old.len(), 3);
+        std::fs::write(path("target"), &other).unwrap();
+        assert!(apply_delta(&path("target"), &path("delta")).is_err());
+        assert_eq!(std::fs::read(path("target")).unwrap(), other);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn truncated_or_corrupt_deltas_write_nothing() {
+        let dir = std::env::temp_dir().join(format!("rpi-fs-shrink-delta-bad-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = |name: &str| dir.join(name).to_string_lossy().into_owned();
+        let old = image(3 * BLOCK_BYTES, 0);
+        let mut new = old.clone();
+        new[0] ^= 1;
+        new[2 * BLOCK_BYTES] ^= 1;
+        std::fs::write(path("old"), &old).unwrap();
+        std::fs::write(path("new"), &new).unwrap();
+        create_delta(&path("old"), &path("new"), &path("delta")).unwrap();
+        let delta = std::fs::read(path("delta")).unwrap();
+
+        // Cut off in the second record: the check pass fails before the first is written
+        std::fs::write(path("cut"), &delta[..delta.len() - 10]).unwrap();
+        std::fs::write(path("target"), &old).unwrap();
+        let err = apply_delta(&path("target"), &path("cut")).unwrap_err();
+        assert!(format!("{:#}", err).contains("truncated"), "{:#}", err);
+        assert_eq!(std::fs::read(path("target")).unwrap(), old);
+
+        let mut corrupt = delta.clone();
+        corrupt[0] = b'X';
+        std::fs::write(path("corrupt"), &corrupt).unwrap();
+        assert!(apply_delta(&path("target"), &path("corrupt")).is_err());
+        let mut bad_block = delta;
+        bad_block[8..12].copy_from_slice(&0u32.to_le_bytes());
+        std::fs::write(path("bad-block"), &bad_block).unwrap();
+        assert!(apply_delta(&path("target"), &path("bad-block")).is_err());
+        std::fs::write(path("short"), b"CRPD").unwrap();
+        assert!(apply_delta(&path("target"), &path("short")).is_err());
+        assert_eq!(std::fs::read(path("target")).unwrap(), old);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,38 @@
+//! Shared libraries loaded at run time rather than linked, so the binary still
+//! starts where they aren't installed (busybox systems, minimal containers) and
+//! the callers fall back to the command-line tools there
+
+use std::ffi::{c_void, CString};
+
+/// A library opened with dlopen, kept open for the life of the process
+pub struct Library(*mut c_void);
+
+// SAFETY: the handle is only passed to dlsym, which is thread-safe
+unsafe impl Send for Library {}
+unsafe impl Sync for Library {}
+
+impl Library {
+    /// Open `name` (a soname such as `libblkid.so.1`), or None when it isn't installed
+    pub fn open(name: &str) -> Option<Library> {
+        let name = CString::new(name).ok()?;
+        // SAFETY: the name is NUL-terminated; the libraries opened here run no
+        // constructors that depend on the caller
+        let handle = unsafe { libc::dlopen(name.as_ptr(), libc::RTLD_NOW | libc::RTLD_LOCAL) };
+        (!handle.is_null()).then_some(Library(handle))
+    }
+
+    /// Address of `name` as a function pointer of type `T`
+    ///
+    /// # Safety
+    /// `T` must be an `extern "C"` function pointer type matching the symbol's C signature.
+    pub unsafe fn symbol<T: Copy>(&self, name: &str) -> Option<T> {
+        let name = CString::new(name).ok()?;
+        // SAFETY: the handle came from dlopen and is never closed
+        let address = unsafe { libc::dlsym(self.0, name.as_ptr()) };
+        if address.is_null() {
+            return None;
+        }
+        // SAFETY: the caller vouches that T is the symbol's function pointer type
+        Some(unsafe { std::mem::transmute_copy::<*mut c_void, T>(&address) })
+    }
+}
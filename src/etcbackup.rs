@@ -0,0 +1,75 @@
+//! Copies of the target's /etc on the firmware partition (`--etc-backup`): one
+//! from before the run and one from after it. The firmware partition is FAT, so
+//! if the new fstab leaves the target unbootable, the old configuration can be
+//! read back on any machine.
+
+use anyhow::{bail, Context, Result};
+use std::path::PathBuf;
+use std::process::Command;
+
+use crate::firmware::BootLayout;
+use crate::{mount_at, unmount_quiet, MountPaths};
+
+/// Start of the archive names; the boot partition check expects files named so
+pub const PREFIX: &str = "config-backup-";
+
+/// The archive of /etc from before the run, kept on the host until the end
+pub struct EtcBackup {
+    before: PathBuf,
+    stamp: String,
+}
+
+/// Pack `root`/etc into the gzip'd tar `output`, keeping numeric owners
+fn archive(root: &str, output: &str) -> Result<u64> {
+    let status = Command::new("tar")
+        .args(["--create", "--gzip", "--numeric-owner", "--file", output, "--directory", root, "etc"])
+        .status()
+        .context("Failed to run tar")?;
+    if !status.success() {
+        let _ = std::fs::remove_file(output);
+        bail!("tar could not archive {}/etc to {}", root, output);
+    }
+    Ok(std::fs::metadata(output).map(|metadata| metadata.len()).unwrap_or(0))
+}
+
+/// Archive the /etc of the target root mounted at `root`, before the run changes it
+pub fn save_before(root: &str) -> Result<EtcBackup> {
+    // FAT has no colons in names: 2024-05-01T12:00:00Z becomes 20240501-120000
+    let stamp = crate::audit::utc_timestamp().replace(['-', ':', 'Z'], "").replace('T', "-");
+    let before = std::env::temp_dir().join(format!("rpi-fs-shrink-etc-{}.tar.gz", std::process::id()));
+    let bytes = archive(root, &before.to_string_lossy())?;
+    println!("  /etc archived ({})", crate::timing::format_bytes(bytes));
+    Ok(EtcBackup { before, stamp })
+}
+
+impl EtcBackup {
+    fn name(&self, when: &str) -> String {
+        format!("{}{}-{}.tar.gz", PREFIX, self.stamp, when)
+    }
+
+    /// Write the archive from before and one of `root`/etc as it is now to the
+    /// firmware partition `boot_device`. A full partition only costs the backup.
+    pub fn write(&self, root: &str, boot_device: &str, layout: BootLayout, mounts: &MountPaths) -> Result<()> {
+        let boot = mounts.boot();
+        mount_at(boot_device, &boot)?;
+        let result = (|| -> Result<()> {
+            let before = format!("{}/{}", boot, self.name("before"));
+            std::fs::copy(&self.before, &before).map_err(|err| {
+                let _ = std::fs::remove_file(&before);
+                anyhow::anyhow!("Failed to copy the /etc archive to {}: {}", boot_device, err)
+            })?;
+            archive(root, &format!("{}/{}", boot, self.name("after")))?;
+            Ok(())
+        })();
+        unmount_quiet(&boot);
+        let _ = std::fs::remove_file(&self.before);
+        match result {
+            Ok(()) => {
+                println!("  {}", layout.path(&self.name("before")));
+                println!("  {}", layout.path(&self.name("after")));
+            }
+            Err(err) => println!("  Warning: /etc not saved to the firmware partition (is it full?): {:#}", err),
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,201 @@
+use anyhow::{bail, Context, Result};
+use std::os::unix::fs::{MetadataExt, PermissionsExt};
+use std::path::Path;
+
+use crate::glob::glob_match;
+use crate::verify::Manifest;
+
+/// Patterns left out of the /var and /home copies, matched the way rsync does:
+/// a leading / anchors a pattern at the target's root (`/var/cache`), a pattern
+/// without / matches a name at any depth (`lost+found`), one with an inner /
+/// matches the end of a path, `*` and `?` stay within a path component, `**`
+/// crosses them, and a trailing / matches directories only
+#[derive(Debug, Clone)]
+pub struct Excludes {
+    patterns: Vec<String>,
+}
+
+impl Excludes {
+    pub fn new(patterns: &[String]) -> Result<Self> {
+        for pattern in patterns {
+            let trimmed = pattern.trim_end_matches('/');
+            if trimmed.is_empty() {
+                bail!("--exclude {}: the whole filesystem can't be excluded", pattern);
+            }
+            if let Some(anchored) = trimmed.strip_prefix('/') {
+                if !anchored.contains('/') {
+                    bail!("--exclude {}: a pattern has to name something inside /var or /home", pattern);
+                }
+            }
+        }
+        Ok(Excludes { patterns: patterns.to_vec() })
+    }
+
+    /// Exclude `pattern` as well, for patterns the run adds itself
+    pub fn add(&mut self, pattern: String) {
+        self.patterns.push(pattern);
+    }
+
+    /// The patterns that apply to the copy of /`tree` (e.g. var, or var/lib/docker),
+    /// anchored at its top
+    fn for_tree(&self, tree: &str) -> Vec<String> {
+        self.patterns
+            .iter()
+            .filter_map(|pattern| match pattern.strip_prefix('/') {
+                Some(anchored) => {
+                    let mut rest = anchored;
+                    for component in tree.split('/') {
+                        let (first, remainder) = rest.split_once('/')?;
+                        if !glob_match(first, component) {
+                            return None;
+                        }
+                        rest = remainder;
+                    }
+                    Some(format!("/{}", rest))
+                }
+                None => Some(pattern.clone()),
+            })
+            .collect()
+    }
+
+    /// rsync arguments for the copy of /`tree`
+    pub fn rsync_args(&self, tree: &str) -> Vec<String> {
+        self.for_tree(tree).into_iter().map(|pattern| format!("--exclude={}", pattern)).collect()
+    }
+
+    /// Whether `path`, relative to /`tree`, is excluded itself or through a parent directory
+    pub fn is_excluded(&self, tree: &str, path: &str, is_dir: bool) -> bool {
+        let patterns = self.for_tree(tree);
+        let components: Vec<&str> = path.split('/').filter(|c| !c.is_empty()).collect();
+        (1..=components.len()).any(|depth| {
+            let prefix = components[..depth].join("/");
+            let prefix_is_dir = depth < components.len() || is_dir;
+            patterns.iter().any(|pattern| pattern_matches(pattern, &prefix, prefix_is_dir))
+        })
+    }
+
+    /// Drop the entries of a manifest of /`tree` that the copy leaves out
+    pub fn filter_manifest(&self, tree: &str, manifest: &Manifest) -> Manifest {
+        manifest
+            .iter()
+            .filter(|(path, _)| !self.is_excluded(tree, path, false))
+            .map(|(path, hash)| (path.clone(), hash.clone()))
+            .collect()
+    }
+
+    /// Create the directories the copy of /`tree` left out as empty directories
+    /// in `dest`, with the owner and mode they have in `source`. Returns how many.
+    pub fn recreate_excluded_dirs(&self, tree: &str, source: &str, dest: &str) -> Result<usize> {
+        if self.for_tree(tree).is_empty() {
+            return Ok(0);
+        }
+        let device = std::fs::symlink_metadata(source).context(format!("Failed to read {}", source))?.dev();
+        let mut pending = vec![String::new()];
+        let mut created = 0;
+        while let Some(dir) = pending.pop() {
+            let full = format!("{}/{}", source, dir);
+            for entry in std::fs::read_dir(&full).context(format!("Failed to read {}", full))? {
+                let entry = entry?;
+                let meta = entry.metadata()?;
+                if !meta.is_dir() || meta.dev() != device {
+                    continue;
+                }
+                let relative = format!("{}{}", dir, entry.file_name().to_string_lossy());
+                if self.is_excluded(tree, &relative, true) {
+                    recreate_dir(&format!("{}/{}", dest, relative), &meta)?;
+                    println!("    /{}/{} left out, recreated empty", tree, relative);
+                    created += 1;
+                } else {
+                    pending.push(format!("{}/", relative));
+                }
+            }
+        }
+        Ok(created)
+    }
+}
+
+fn recreate_dir(path: &str, meta: &std::fs::Metadata) -> Result<()> {
+    if !Path::new(path).is_dir() {
+        std::fs::create_dir(path).context(format!("Failed to create {}", path))?;
+    }
+    std::os::unix::fs::chown(path, Some(meta.uid()), Some(meta.gid())).context(format!("Failed to set owner of {}", path))?;
+    std::fs::set_permissions(path, std::fs::Permissions::from_mode(meta.mode() & 0o7777))
+        .context(format!("Failed to set permissions on {}", path))
+}
+
+/// rsync's rule for one pattern against a path relative to the transfer root
+fn pattern_matches(pattern: &str, path: &str, is_dir: bool) -> bool {
+    let (pattern, dir_only) = match pattern.strip_suffix('/') {
+        Some(pattern) => (pattern, true),
+        None => (pattern, false),
+    };
+    if dir_only && !is_dir {
+        return false;
+    }
+    if let Some(anchored) = pattern.strip_prefix('/') {
+        return glob_match(anchored, path);
+    }
+    if pattern.contains('/') || pattern.contains("**") {
+        // Any trailing run of whole components
+        return glob_match(pattern, path)
+            || path.match_indices('/').any(|(index, _)| glob_match(pattern, &path[index + 1..]));
+    }
+    glob_match(pattern, path.rsplit('/').next().unwrap_or(path))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn excludes(patterns: &[&str]) -> Excludes {
+        Excludes::new(&patterns.iter().map(|pattern| pattern.to_string()).collect::<Vec<_>>()).unwrap()
+    }
+
+    #[test]
+    fn patterns_follow_rsyncs_rules() {
+        let cases: &[(&str, &str, bool, bool)] = &[
+            // No slash: a name at any depth
+            ("lost+found", "lost+found", true, true),
+            ("*.tmp", "pi/work/a.tmp", false, true),
+            ("*.tmp", "pi/work/a.tmpx", false, false),
+            // Anchored at the transfer root
+            ("/cache", "cache", true, true),
+            ("/cache", "lib/cache", true, false),
+            // An inner slash matches the end of the path
+            ("lib/apt", "lib/apt", true, true),
+            ("lib/apt", "x/lib/apt", true, true),
+            ("lib/apt", "xlib/apt", true, false),
+            // * stays in one component, ** crosses them
+            ("/*/.cache", "pi/.cache", true, true),
+            ("/*/.cache", "pi/x/.cache", true, false),
+            ("/**/.cache", "pi/x/.cache", true, true),
+            // A trailing slash only matches directories
+            ("tmp/", "tmp", true, true),
+            ("tmp/", "tmp", false, false),
+        ];
+        for &(pattern, path, is_dir, expected) in cases {
+            assert_eq!(pattern_matches(pattern, path, is_dir), expected, "{} against {}", pattern, path);
+        }
+    }
+
+    #[test]
+    fn anchored_patterns_are_rebased_on_the_copied_tree() {
+        let excludes = excludes(&["/var/cache", "/home/*/.cache", "/var/lib/docker/tmp", "*.tmp"]);
+        assert_eq!(excludes.for_tree("var"), vec!["/cache", "/lib/docker/tmp", "*.tmp"]);
+        assert_eq!(excludes.for_tree("home"), vec!["/*/.cache", "*.tmp"]);
+        assert_eq!(excludes.for_tree("var/lib/docker"), vec!["/tmp", "*.tmp"]);
+        assert_eq!(excludes.rsync_args("home"), vec!["--exclude=/*/.cache", "--exclude=*.tmp"]);
+
+        assert!(excludes.is_excluded("var", "cache/apt/pkgcache.bin", false));
+        assert!(excludes.is_excluded("home", "pi/.cache", true));
+        assert!(!excludes.is_excluded("home", "pi/.config", true));
+        assert!(excludes.is_excluded("var", "log/a.tmp", false));
+    }
+
+    #[test]
+    fn patterns_outside_var_and_home_are_refused() {
+        assert!(Excludes::new(&["/".to_string()]).is_err());
+        assert!(Excludes::new(&["/var".to_string()]).is_err());
+        assert!(Excludes::new(&["/var/cache/".to_string()]).is_ok());
+    }
+}
@@ -0,0 +1,128 @@
+use anyhow::{bail, Result};
+use std::process::Command;
+
+/// Tolerance when --expect-size has none; covers the gap between the
+/// decimal size printed on a card and its binary size
+const DEFAULT_TOLERANCE_PERCENT: f64 = 10.0;
+
+#[derive(Debug, Clone, Copy)]
+pub struct SizeExpectation {
+    pub bytes: u64,
+    pub tolerance_percent: f64,
+}
+
+/// Parse `32G`, `32G±10%` or `32G+-10%`
+pub fn parse_expect_size(value: &str) -> Result<SizeExpectation> {
+    let (size, tolerance) = match value.split_once('±').or_else(|| value.split_once("+-")) {
+        Some((size, tolerance)) => (size, Some(tolerance)),
+        None => (value, None),
+    };
+
+    let tolerance_percent = match tolerance {
+        Some(tolerance) => {
+            let Some(Ok(percent)) = tolerance.trim().strip_suffix('%').map(|p| p.trim().parse::<f64>()) else {
+                bail!("Invalid tolerance in --expect-size {}; use e.g. 32G±10%", value);
+            };
+            if !(0.0..100.0).contains(&percent) {
+                bail!("--expect-size tolerance must be between 0% and 100%");
+            }
+            percent
+        }
+        None => DEFAULT_TOLERANCE_PERCENT,
+    };
+
+    Ok(SizeExpectation { bytes: crate::parse_size(size)?, tolerance_percent })
+}
+
+pub fn check_size(expect: &SizeExpectation, device: &str, actual: u64) -> Result<()> {
+    let slack = expect.bytes as f64 * expect.tolerance_percent / 100.0;
+    let min = (expect.bytes as f64 - slack) as u64;
+    let max = (expect.bytes as f64 + slack) as u64;
+    if actual < min || actual > max {
+        bail!(
+            "{} is {} MB, expected {} MB ±{}% ({}-{} MB); refusing to touch a disk that is not the intended one",
+            device,
+            actual / (1024 * 1024),
+            expect.bytes / (1024 * 1024),
+            expect.tolerance_percent,
+            min / (1024 * 1024),
+            max / (1024 * 1024)
+        );
+    }
+    Ok(())
+}
+
+/// Model name of the disk as reported by lsblk, or the card name for
+/// mmcblk devices, which have no model (empty when unknown)
+pub fn disk_model(device: &str) -> String {
+    let model = Command::new("lsblk")
+        .args(["-dno", "MODEL", device])
+        .output()
+        .ok()
+        .map(|o| String::from_utf8_lossy(&o.stdout).trim().to_string())
+        .unwrap_or_default();
+    if !model.is_empty() {
+        return model;
+    }
+
+    let name = device.rsplit('/').next().unwrap_or(device);
+    std::fs::read_to_string(format!("/sys/block/{}/device/name", name))
+        .map(|name| name.trim().to_string())
+        .unwrap_or_default()
+}
+
+/// Model must contain `expected` (case-insensitive), so `SanDisk` matches `SanDisk Ultra`
+pub fn check_model(expected: &str, device: &str) -> Result<()> {
+    let model = disk_model(device);
+    if !model.to_lowercase().contains(&expected.to_lowercase()) {
+        bail!("{} has model '{}', expected '{}'", device, model, expected);
+    }
+    Ok(())
+}
+
+pub fn check_serial(expected: &str, device: &str) -> Result<()> {
+    let serial = crate::identity::disk_serial(device);
+    if serial != expected {
+        bail!("{} has serial '{}', expected '{}'", device, serial, expected);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const GIB: u64 = 1024 * 1024 * 1024;
+
+    #[test]
+    fn tolerance_is_written_either_way() {
+        for value in ["32G±5%", "32G+-5%", "32G ± 5 %"] {
+            let expect = parse_expect_size(value).unwrap();
+            assert_eq!((expect.bytes, expect.tolerance_percent), (32 * GIB, 5.0), "{}", value);
+        }
+        let expect = parse_expect_size("32G").unwrap();
+        assert_eq!((expect.bytes, expect.tolerance_percent), (32 * GIB, DEFAULT_TOLERANCE_PERCENT));
+    }
+
+    #[test]
+    fn malformed_tolerances_are_refused() {
+        for value in ["32G±5", "32G±five%", "32G±%", "32G+-100%", "32G±-1%", "±10%", "32X±10%"] {
+            assert!(parse_expect_size(value).is_err(), "{}", value);
+        }
+    }
+
+    #[test]
+    fn sizes_at_the_tolerance_bounds_pass() {
+        let expect = SizeExpectation { bytes: 100 * GIB, tolerance_percent: 10.0 };
+        for actual in [90 * GIB, 100 * GIB, 110 * GIB] {
+            assert!(check_size(&expect, "/dev/sdb", actual).is_ok(), "{}", actual);
+        }
+        for actual in [90 * GIB - 1, 110 * GIB + 1, 0] {
+            assert!(check_size(&expect, "/dev/sdb", actual).is_err(), "{}", actual);
+        }
+        // No tolerance: exactly the size
+        let exact = SizeExpectation { bytes: 32 * GIB, tolerance_percent: 0.0 };
+        assert!(check_size(&exact, "/dev/sdb", 32 * GIB).is_ok());
+        assert!(check_size(&exact, "/dev/sdb", 32 * GIB - 512).is_err());
+    }
+}
@@ -0,0 +1,339 @@
+//! Safety checks that warn or refuse, and how a run treats them. Every check has
+//! a stable code (W001, ...) and a name; `explain` describes it, and `--report`
+//! lists what a run found. By default a warning is printed and the run goes on,
+//! and a refusal stops it. `--strict` makes warnings stop the run too,
+//! `--lenient` turns refusals into warnings, and `--allow ID` accepts one check
+//! whatever the mode.
+
+use anyhow::{bail, Result};
+use std::sync::Mutex;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Severity {
+    /// Printed; the run goes on unless --strict
+    Warning,
+    /// Stops the run unless --lenient or --allow
+    Refusal,
+}
+
+impl Severity {
+    fn name(self) -> &'static str {
+        match self {
+            Severity::Warning => "warning",
+            Severity::Refusal => "refusal",
+        }
+    }
+}
+
+pub struct Check {
+    /// Stable code; never reused once a check is removed
+    pub code: &'static str,
+    pub id: &'static str,
+    pub severity: Severity,
+    pub summary: &'static str,
+    /// What `explain` prints: why the check exists and what to do about it
+    pub details: &'static str,
+}
+
+/// Every check a run can report. New checks take the next free code.
+pub const CHECKS: &[Check] = &[
+    Check {
+        code: "W001",
+        id: "active-root-disk",
+        severity: Severity::Refusal,
+        summary: "the target disk holds the running system",
+        details: "Repartitioning the disk the running system booted from moves data under mounted filesystems. \
+            Boot from a LiveUSB or another disk and run from there. --allow-active-disk is the same as allowing this check.",
+    },
+    Check {
+        code: "W002",
+        id: "sd-card-swap",
+        severity: Severity::Refusal,
+        summary: "a swap partition or file on an SD card",
+        details: "Swap writes the same blocks over and over, which wears out SD cards. \
+            --swap-mode zram or zswap swaps to compressed RAM instead.",
+    },
+    Check {
+        code: "W003",
+        id: "sd-card-var",
+        severity: Severity::Refusal,
+        summary: "a separate /var on an SD card",
+        details: "Logs and caches on /var write constantly, and a small /var partition concentrates the wear. \
+            Leave /var on root on SD cards, or move the system to an SSD.",
+    },
+    Check {
+        code: "W004",
+        id: "slow-swap-disk",
+        severity: Severity::Refusal,
+        summary: "the disk reads slower than --min-swap-mbps",
+        details: "Swap on a slow disk stalls the system whenever it is used. Measure the disk with `bench`, \
+            use a faster disk or adapter, or swap to compressed RAM with --swap-mode zram.",
+    },
+    Check {
+        code: "W005",
+        id: "cold-databases",
+        severity: Severity::Refusal,
+        summary: "a database on /var wasn't shut down cleanly",
+        details: "A pid file left in a database's data directory means it was running when the system went down; \
+            a copy takes along whatever state the crash left. Boot the target once and shut it down cleanly. \
+            --accept-cold-databases is the same as allowing this check.",
+    },
+    Check {
+        code: "W006",
+        id: "ownership-mismatch",
+        severity: Severity::Refusal,
+        summary: "copied files differ from the originals in owner or mode",
+        details: "The /var, /home or container copies don't keep the owners and modes of the originals, so services \
+            may fail to start. The run stops before fstab is switched, and the disk still boots its old layout. \
+            The differences are listed above the refusal.",
+    },
+    Check {
+        code: "W007",
+        id: "boot-partition-changed",
+        severity: Severity::Refusal,
+        summary: "the boot partition changed in ways the run doesn't explain",
+        details: "Files on the boot partition changed or went missing besides the ones the run edits. \
+            The new layout is in place; check config.txt, cmdline.txt and the kernel before booting the target.",
+    },
+    Check {
+        code: "W008",
+        id: "fsck-errors",
+        severity: Severity::Warning,
+        summary: "e2fsck found errors on root",
+        details: "e2fsck -f -y returned non-zero: it repaired errors, or found some it couldn't repair. \
+            Run it again by hand to see which before trusting the filesystem.",
+    },
+    Check {
+        code: "W009",
+        id: "uuid-collision",
+        severity: Severity::Warning,
+        summary: "a filesystem's UUID or PARTUUID is also on another attached disk",
+        details: "Two disks with the same UUID or PARTUUID make mounts by UUID pick either one. \
+            Common with cards written from the same image. Give one of them a new UUID (tune2fs -U random).",
+    },
+    Check {
+        code: "W010",
+        id: "label-collision",
+        severity: Severity::Warning,
+        summary: "a filesystem's label is also on another attached disk",
+        details: "Mounts by LABEL pick either filesystem while both disks are attached. Relabel one of them.",
+    },
+    Check {
+        code: "W011",
+        id: "firmware-fstab",
+        severity: Severity::Warning,
+        summary: "fstab doesn't mount the firmware partition",
+        details: "Without an fstab entry for /boot/firmware (or /boot), kernel and firmware updates are written \
+            to root, and the Pi keeps booting the old ones. Add the entry to the target's /etc/fstab.",
+    },
+    Check {
+        code: "W012",
+        id: "seed-format",
+        severity: Severity::Warning,
+        summary: "the seed user-data isn't a cloud-config or script",
+        details: "cloud-init only acts on user-data starting with #cloud-config or #!. Check the file given to --seed.",
+    },
+    Check {
+        code: "W013",
+        id: "kernel-hibernation",
+        severity: Severity::Warning,
+        summary: "the target's kernel is built without hibernation",
+        details: "resume= on the command line does nothing without CONFIG_HIBERNATION; the swap still works as swap.",
+    },
+    Check {
+        code: "W014",
+        id: "zswap-without-swap",
+        severity: Severity::Warning,
+        summary: "zswap is enabled but no swap is left behind it",
+        details: "zswap compresses pages on their way to a swap device; without one it does nothing. \
+            Keep the existing swap, give --swap-size, or use --swap-mode zram.",
+    },
+    Check {
+        code: "W015",
+        id: "table-mismatch",
+        severity: Severity::Refusal,
+        summary: "the partition table read back differs from the plan",
+        details: "After the partitions are created the table is read back with sfdisk and from the kernel, and a GPT's \
+            headers and CRCs are checked. A difference means parted, sgdisk or the kernel wrote something else than \
+            planned; the run stops before anything is formatted. The differences are listed above the refusal.",
+    },
+    Check {
+        code: "W016",
+        id: "smoke-test",
+        severity: Severity::Warning,
+        summary: "a check run in the migrated root failed or couldn't run",
+        details: "--smoke-test runs the target's own dpkg (or pacman), getent and systemd-analyze in a chroot once the \
+            data is migrated. A failure means the target will likely hit the same problem at boot: a package database \
+            it can't read, users it can't look up, or an fstab systemd rejects. The data is already on the new \
+            partitions; fix the target (its fstab, /var, /etc/nsswitch.conf) before booting it. The check can't run \
+            as a normal user or when the target's programs are for another architecture without qemu-user binfmt.",
+    },
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Mode {
+    Default,
+    Strict,
+    Lenient,
+}
+
+/// A check a run reported, and what came of it
+struct Finding {
+    check: &'static Check,
+    message: String,
+    outcome: &'static str,
+}
+
+struct Policy {
+    mode: Mode,
+    allowed: Vec<&'static str>,
+    found: Vec<Finding>,
+}
+
+static POLICY: Mutex<Policy> = Mutex::new(Policy { mode: Mode::Default, allowed: Vec::new(), found: Vec::new() });
+
+/// The check with code or name `key`
+fn find(key: &str) -> Option<&'static Check> {
+    CHECKS.iter().find(|check| check.id == key || check.code.eq_ignore_ascii_case(key))
+}
+
+fn known_checks() -> String {
+    CHECKS.iter().map(|check| format!("  {} {:<24} {}", check.code, check.id, check.summary)).collect::<Vec<_>>().join("\n")
+}
+
+/// Set how checks are treated for this run; `allowed` are check codes or names
+pub fn configure(strict: bool, lenient: bool, allowed: &[String]) -> Result<()> {
+    let mut ids = Vec::new();
+    for key in allowed {
+        match find(key) {
+            Some(check) => ids.push(check.id),
+            None => bail!("--allow {}: no such check. Known checks:\n{}", key, known_checks()),
+        }
+    }
+    let mut policy = POLICY.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+    policy.mode = match (strict, lenient) {
+        (true, _) => Mode::Strict,
+        (_, true) => Mode::Lenient,
+        _ => Mode::Default,
+    };
+    policy.allowed = ids;
+    policy.found.clear();
+    Ok(())
+}
+
+/// Report that check `id` found `message`: print it, or fail the run if the
+/// policy says so. Allowed warnings are recorded but not printed.
+pub fn report(id: &'static str, message: &str) -> Result<()> {
+    let Some(check) = find(id) else {
+        bail!("Internal error: no check named {}", id);
+    };
+    let mut policy = POLICY.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+    let allowed = policy.allowed.contains(&id);
+    let outcome = match (allowed, check.severity, policy.mode) {
+        (true, Severity::Warning, _) => "suppressed",
+        (true, Severity::Refusal, _) => {
+            println!("  Allowed[{} {}]: {}", check.code, id, message);
+            "allowed"
+        }
+        (false, Severity::Warning, Mode::Strict) | (false, Severity::Refusal, Mode::Default | Mode::Strict) => "refused",
+        (false, Severity::Warning, _) => {
+            println!("  Warning[{} {}]: {}", check.code, id, message);
+            "warned"
+        }
+        (false, Severity::Refusal, Mode::Lenient) => {
+            println!("  Warning[{} {}]: {} (going ahead: --lenient)", check.code, id, message);
+            "warned"
+        }
+    };
+    policy.found.push(Finding { check, message: message.to_string(), outcome });
+    if outcome != "refused" {
+        return Ok(());
+    }
+    if check.severity == Severity::Warning {
+        bail!("{}\n[{} {}] --strict makes this warning fatal; --allow {} accepts it (rpi-fs-shrink explain {})", message, check.code, id, check.code, check.code)
+    }
+    bail!("{}\n[{} {}] Refused; --allow {} (or --lenient) goes ahead anyway (rpi-fs-shrink explain {})", message, check.code, id, check.code, check.code)
+}
+
+/// What the run's checks found so far, for --report
+pub fn to_json() -> serde_json::Value {
+    let policy = POLICY.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+    serde_json::Value::Array(
+        policy
+            .found
+            .iter()
+            .map(|finding| {
+                serde_json::json!({
+                    "code": finding.check.code,
+                    "id": finding.check.id,
+                    "severity": finding.check.severity.name(),
+                    "message": finding.message,
+                    "outcome": finding.outcome,
+                })
+            })
+            .collect(),
+    )
+}
+
+/// Describe the check with code or name `key`, or list them all (`explain`)
+pub fn explain(key: Option<&str>, json: bool) -> Result<()> {
+    let checks: Vec<&Check> = match key {
+        Some(key) => match find(key) {
+            Some(check) => vec![check],
+            None => bail!("No check {}. Known checks:\n{}", key, known_checks()),
+        },
+        None => CHECKS.iter().collect(),
+    };
+    if json {
+        let entries: Vec<serde_json::Value> = checks
+            .iter()
+            .map(|check| {
+                serde_json::json!({
+                    "code": check.code,
+                    "id": check.id,
+                    "severity": check.severity.name(),
+                    "summary": check.summary,
+                    "details": check.details,
+                })
+            })
+            .collect();
+        println!("{:#}", serde_json::Value::Array(entries));
+        return Ok(());
+    }
+    if key.is_none() {
+        println!("{}", known_checks());
+        println!("\nrpi-fs-shrink explain CODE describes one of them");
+        return Ok(());
+    }
+    for check in checks {
+        println!("{} {} ({})", check.code, check.id, check.severity.name());
+        println!("  Found when {}.\n", check.summary);
+        println!("  {}", check.details);
+        let handling = match check.severity {
+            Severity::Warning => "Printed and the run goes on; --strict stops the run, --allow suppresses it.",
+            Severity::Refusal => "Stops the run; --lenient or --allow goes ahead with a warning.",
+        };
+        println!("\n  {}", handling);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn codes_and_names_are_unique() {
+        for (index, check) in CHECKS.iter().enumerate() {
+            assert!(CHECKS[index + 1..].iter().all(|other| other.code != check.code && other.id != check.id), "{} repeats", check.id);
+        }
+    }
+
+    #[test]
+    fn checks_are_found_by_code_or_name() {
+        assert_eq!(find("W009").map(|check| check.id), Some("uuid-collision"));
+        assert_eq!(find("w009").map(|check| check.id), Some("uuid-collision"));
+        assert_eq!(find("sd-card-swap").map(|check| check.code), Some("W002"));
+        assert!(find("W999").is_none());
+    }
+}
@@ -0,0 +1,76 @@
+use anyhow::Result;
+use std::path::Path;
+
+/// Where the target mounts its firmware (boot) partition, and so where
+/// cmdline.txt, config.txt and the first-boot files appear on the running system
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BootLayout {
+    /// Raspberry Pi OS up to bullseye
+    Boot,
+    /// Raspberry Pi OS bookworm and later, and Ubuntu
+    Firmware,
+}
+
+impl BootLayout {
+    /// Work out the layout of the target root mounted at `root`: its fstab
+    /// first, then the mountpoint directory, then the Debian release
+    pub fn detect(root: &str) -> Self {
+        let fstab = std::fs::read_to_string(format!("{}/etc/fstab", root)).unwrap_or_default();
+        if let Ok(entries) = crate::fstab::parse_fstab(&fstab) {
+            if entries.iter().any(|entry| entry.target == "/boot/firmware") {
+                return BootLayout::Firmware;
+            }
+            if entries.iter().any(|entry| entry.target == "/boot" && entry.fstype == "vfat") {
+                return BootLayout::Boot;
+            }
+        }
+
+        if Path::new(&format!("{}/boot/firmware", root)).is_dir() {
+            return BootLayout::Firmware;
+        }
+        match debian_version(root) {
+            Some(version) if version >= 12 => BootLayout::Firmware,
+            _ => BootLayout::Boot,
+        }
+    }
+
+    pub fn mountpoint(self) -> &'static str {
+        match self {
+            BootLayout::Boot => "/boot",
+            BootLayout::Firmware => "/boot/firmware",
+        }
+    }
+
+    /// Path of `file` on the firmware partition as the target sees it
+    pub fn path(self, file: &str) -> String {
+        format!("{}/{}", self.mountpoint(), file)
+    }
+
+    pub fn describe(self) -> &'static str {
+        match self {
+            BootLayout::Boot => "firmware partition at /boot (bullseye and older)",
+            BootLayout::Firmware => "firmware partition at /boot/firmware (bookworm and later)",
+        }
+    }
+}
+
+/// Major Debian version from the target's /etc/debian_version ("12.5" -> 12)
+fn debian_version(root: &str) -> Option<u32> {
+    let version = std::fs::read_to_string(format!("{}/etc/debian_version", root)).ok()?;
+    version.trim().split('.').next()?.parse().ok()
+}
+
+/// Warn when the target's fstab doesn't mount the firmware partition where its
+/// layout expects it; kernel and bootloader updates would then miss the partition
+pub fn check_fstab_entry(fstab_content: &str, layout: BootLayout) -> Result<()> {
+    let Ok(entries) = crate::fstab::parse_fstab(fstab_content) else {
+        return Ok(());
+    };
+    if !entries.iter().any(|entry| entry.target == layout.mountpoint()) {
+        crate::findings::report(
+            "firmware-fstab",
+            &format!("/etc/fstab has no entry for {}; the target's kernel updates won't reach the firmware partition", layout.mountpoint()),
+        )?;
+    }
+    Ok(())
+}
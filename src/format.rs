@@ -0,0 +1,281 @@
+//! Formatting the new partitions. The jobs are independent, so they run side
+//! by side on scoped threads, at most `--jobs` at a time.
+//!
+//! This is all the concurrency a run has. The work is external processes
+//! (mkfs, rsync, sha256sum) on one disk, so an async runtime would only wait on
+//! child processes that threads already wait on, and the crate stays without
+//! one. Deep-verify hashing doesn't overlap the copies either: both read the
+//! same disk, and on the SD cards and USB sticks most runs target the two
+//! streams slow each other down more than running them in turn. Hashing also
+//! needs a copy to be complete before its manifest means anything.
+
+use anyhow::{bail, Context, Result};
+use std::process::Command;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+
+/// One mkfs/mkswap invocation for a freshly created partition
+#[derive(Debug, Clone)]
+pub struct FormatJob {
+    pub name: String,
+    pub device: String,
+    pub program: String,
+    pub args: Vec<String>,
+    /// Filesystem type as UDisks2 names it
+    pub fstype: String,
+    pub label: Option<String>,
+    pub uuid: Option<String>,
+    /// Discard the partition's blocks while formatting
+    pub discard: bool,
+    /// Options the user passed through with --mkfs-args.<partition>
+    pub extra_args: Vec<String>,
+}
+
+impl FormatJob {
+    fn new(name: &str, device: &str, program: &str, args: &[&str], fstype: &str) -> Self {
+        let mut all_args: Vec<String> = args.iter().map(|a| a.to_string()).collect();
+        all_args.push(device.to_string());
+        FormatJob {
+            name: name.to_string(),
+            device: device.to_string(),
+            program: program.to_string(),
+            args: all_args,
+            fstype: fstype.to_string(),
+            label: None,
+            uuid: None,
+            discard: false,
+            extra_args: Vec::new(),
+        }
+    }
+
+    /// Insert options in front of the user's extra options and the device,
+    /// which stays the last argument, whatever order the builders ran in
+    fn insert_args(&mut self, args: &[&str]) {
+        let at = self.args.len().saturating_sub(1 + self.extra_args.len());
+        self.args.splice(at..at, args.iter().map(|a| a.to_string()));
+    }
+
+    /// Create the filesystem with a given UUID (mkswap, mkfs.ext4 and mkfs.btrfs all take -U)
+    pub fn with_uuid(mut self, uuid: Option<&str>) -> Self {
+        if let Some(uuid) = uuid {
+            self.insert_args(&["-U", uuid]);
+            self.uuid = Some(uuid.to_string());
+        }
+        self
+    }
+
+    /// Start the new filesystem trimmed, or explicitly not. mkfs.ext4 and
+    /// mkfs.btrfs discard on their own (and are told not to otherwise); mkswap
+    /// and mkfs.vfat can't, so `run_format_jobs` runs blkdiscard before them.
+    pub fn with_discard(mut self, discard: bool) -> Self {
+        match (self.program.as_str(), discard) {
+            ("mkfs.ext4", true) => self.insert_args(&["-E", "discard"]),
+            ("mkfs.ext4", false) => self.insert_args(&["-E", "nodiscard"]),
+            ("mkfs.btrfs", false) => self.insert_args(&["--nodiscard"]),
+            _ => {}
+        }
+        self.discard = discard;
+        self
+    }
+
+    /// Pass `extra` to the formatter after the options chosen here, so they win
+    pub fn with_extra_args(mut self, extra: Option<&Vec<String>>) -> Self {
+        if let Some(extra) = extra {
+            let device = self.args.pop().unwrap_or_default();
+            self.args.extend(extra.iter().cloned());
+            self.args.push(device);
+            self.extra_args.extend(extra.iter().cloned());
+        }
+        self
+    }
+
+    /// Whether the blocks have to be discarded before the formatter runs
+    fn discard_first(&self) -> bool {
+        self.discard && matches!(self.program.as_str(), "mkswap" | "mkfs.vfat")
+    }
+
+    /// Create the filesystem through UDisks2's Block.Format instead, so polkit
+    /// authorizes it and the desktop sees it. UDisks2 can't set a UUID, and it
+    /// picks the FAT size itself (the Pi firmware boots from FAT16 and FAT32).
+    pub fn via_udisks(mut self) -> Result<Self> {
+        if self.uuid.is_some() {
+            bail!("--reuse-uuids can't be combined with --udisks: UDisks2 can't format with a given UUID");
+        }
+        if !self.extra_args.is_empty() {
+            bail!("--mkfs-args can't be combined with --udisks: UDisks2 doesn't take mkfs options");
+        }
+        self.args = crate::udisks::format_args(&self.device, &self.fstype, self.label.as_deref(), self.discard);
+        self.program = "gdbus".to_string();
+        Ok(self)
+    }
+
+    pub fn swap(device: &str) -> Self {
+        FormatJob::new("swap", device, "mkswap", &[], "swap")
+    }
+
+    pub fn btrfs(name: &str, device: &str) -> Self {
+        FormatJob::new(name, device, "mkfs.btrfs", &["-f"], "btrfs")
+    }
+
+    pub fn ext4(name: &str, device: &str) -> Self {
+        FormatJob::new(name, device, "mkfs.ext4", &["-F"], "ext4")
+    }
+
+    pub fn vfat(name: &str, device: &str, label: &str, fat32: bool) -> Self {
+        let job = if fat32 {
+            FormatJob::new(name, device, "mkfs.vfat", &["-F", "32", "-n", label], "vfat")
+        } else {
+            FormatJob::new(name, device, "mkfs.vfat", &["-n", label], "vfat")
+        };
+        FormatJob { label: Some(label.to_string()), ..job }
+    }
+}
+
+/// Split a --mkfs-args value into arguments the way a shell would: on
+/// whitespace, with single and double quotes grouping
+pub fn split_args(text: &str) -> Result<Vec<String>> {
+    let mut args = Vec::new();
+    let mut current: Option<String> = None;
+    let mut quote = None;
+    for c in text.chars() {
+        match (quote, c) {
+            (Some(q), c) if c == q => quote = None,
+            (Some(_), c) => current.get_or_insert_with(String::new).push(c),
+            (None, '\'' | '"') => {
+                quote = Some(c);
+                current.get_or_insert_with(String::new);
+            }
+            (None, c) if c.is_whitespace() => args.extend(current.take()),
+            (None, c) => current.get_or_insert_with(String::new).push(c),
+        }
+    }
+    if quote.is_some() {
+        bail!("Unbalanced quote in \"{}\"", text);
+    }
+    args.extend(current);
+    Ok(args)
+}
+
+/// Bytes zeroed at each end of a partition when wipefs isn't there: every
+/// signature blkid probes for (filesystems, swap, LUKS, LVM, md RAID) lies within
+const WIPE_BYTES: u64 = 1024 * 1024;
+
+/// Erase the filesystem and RAID signatures a new partition inherited from
+/// whatever used its sectors before, so blkid and udev see only the new
+/// filesystem. Returns the kinds of signature found.
+fn wipe_signatures(device: &str) -> Result<Vec<String>> {
+    if crate::command_exists("wipefs") {
+        let output = Command::new("wipefs").args(["--all", device]).output().context("Failed to run wipefs")?;
+        if !output.status.success() {
+            bail!("wipefs failed on {}: {}", device, String::from_utf8_lossy(&output.stderr).trim());
+        }
+        // "/dev/sda3: 2 bytes were erased at offset 0x00000438 (ext4): 53 ef"
+        return Ok(String::from_utf8_lossy(&output.stdout)
+            .lines()
+            .filter_map(|line| line.split_once(" (").and_then(|(_, rest)| rest.split_once(')')))
+            .map(|(kind, _)| kind.to_string())
+            .collect());
+    }
+    use std::io::{Seek, SeekFrom, Write};
+    let mut file = std::fs::OpenOptions::new().write(true).open(device).context(format!("Failed to open {}", device))?;
+    let size = file.seek(SeekFrom::End(0))?;
+    let zeros = vec![0u8; WIPE_BYTES.min(size) as usize];
+    for offset in [0, size.saturating_sub(WIPE_BYTES)] {
+        file.seek(SeekFrom::Start(offset))?;
+        file.write_all(&zeros).context(format!("Failed to zero the start and end of {}", device))?;
+    }
+    file.sync_all()?;
+    Ok(Vec::new())
+}
+
+/// Run format jobs with at most `max_parallel` running at once.
+/// Each job's output is printed as a block when it finishes so runs don't interleave.
+pub fn run_format_jobs(jobs: &[FormatJob], max_parallel: usize) -> Result<()> {
+    let workers = max_parallel.clamp(1, jobs.len().max(1));
+    let next = AtomicUsize::new(0);
+    let failures = Mutex::new(Vec::new());
+
+    std::thread::scope(|scope| {
+        for _ in 0..workers {
+            scope.spawn(|| loop {
+                let index = next.fetch_add(1, Ordering::SeqCst);
+                let Some(job) = jobs.get(index) else {
+                    break;
+                };
+
+                println!("  Formatting {} ({}) with {}...", job.device, job.name, job.program);
+                let start = std::time::Instant::now();
+                // UDisks2 wipes signatures itself as part of Block.Format
+                if job.program != "gdbus" {
+                    match wipe_signatures(&job.device) {
+                        Ok(kinds) if !kinds.is_empty() => println!("  Erased stale signatures on {}: {}", job.device, kinds.join(", ")),
+                        Ok(_) => {}
+                        Err(e) => {
+                            println!("  {:#}", e);
+                            failures.lock().unwrap().push(format!("wiping {}", job.device));
+                            continue;
+                        }
+                    }
+                }
+                if job.discard_first() {
+                    // Only an optimization; the filesystem is fine without it
+                    match Command::new("blkdiscard").arg(&job.device).output() {
+                        Ok(output) if output.status.success() => {}
+                        _ => println!("  Could not discard {}, formatting it untrimmed", job.device),
+                    }
+                }
+                let result = Command::new(&job.program)
+                    .args(&job.args)
+                    .output()
+                    .context(format!("Failed to run {}", job.program));
+
+                match result {
+                    Ok(output) if output.status.success() => {
+                        println!("  {} formatted in {:.1}s", job.device, start.elapsed().as_secs_f64());
+                    }
+                    Ok(output) => {
+                        let stderr = String::from_utf8_lossy(&output.stderr).trim().to_string();
+                        println!("  {} failed on {}:\n{}", job.program, job.device, stderr);
+                        failures.lock().unwrap().push(format!("{} on {}", job.program, job.device));
+                    }
+                    Err(e) => {
+                        println!("  {:#}", e);
+                        failures.lock().unwrap().push(format!("{} on {}", job.program, job.device));
+                    }
+                }
+            });
+        }
+    });
+
+    let failures = failures.into_inner().unwrap();
+    if !failures.is_empty() {
+        bail!("Formatting failed: {}", failures.join(", "));
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn user_options_come_after_the_chosen_ones() {
+        let extra = vec!["-E".to_string(), "nodiscard,stride=4".to_string()];
+        // Discard is decided after the jobs are built with the user's options
+        let job = FormatJob::ext4("/home", "/dev/sda4").with_uuid(Some("1234")).with_extra_args(Some(&extra)).with_discard(true);
+        assert_eq!(job.args, ["-F", "-U", "1234", "-E", "discard", "-E", "nodiscard,stride=4", "/dev/sda4"]);
+        assert_eq!(job.extra_args, extra);
+
+        let job = FormatJob::btrfs("/var", "/dev/sda3").with_extra_args(Some(&vec!["-K".to_string()])).with_discard(false);
+        assert_eq!(job.args, ["-f", "--nodiscard", "-K", "/dev/sda3"]);
+        assert_eq!(FormatJob::swap("/dev/sda2").with_discard(true).args, ["/dev/sda2"]);
+    }
+
+    #[test]
+    fn mkfs_args_split_like_a_shell() {
+        assert_eq!(split_args("-E 'stride=4, stripe_width=8'  -m 1").unwrap(), ["-E", "stride=4, stripe_width=8", "-m", "1"]);
+        assert_eq!(split_args("-L \"\"").unwrap(), ["-L", ""]);
+        assert!(split_args("-L 'home").is_err());
+    }
+}
@@ -0,0 +1,499 @@
+use anyhow::{bail, Context, Result};
+use std::path::Path;
+use std::process::Command;
+
+#[derive(Debug, Clone)]
+pub struct FstabEntry {
+    pub line: usize,
+    pub source: String,
+    pub target: String,
+    pub fstype: String,
+    pub options: String,
+}
+
+/// Parse the non-comment lines of an fstab. Lines with fewer than three fields
+/// are reported as errors rather than skipped.
+pub fn parse_fstab(content: &str) -> Result<Vec<FstabEntry>> {
+    let mut entries = Vec::new();
+
+    for (index, raw) in content.lines().enumerate() {
+        let line = raw.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        if fields.len() < 3 {
+            bail!("fstab line {}: expected at least 3 fields: {}", index + 1, line);
+        }
+
+        entries.push(FstabEntry {
+            line: index + 1,
+            source: fields[0].to_string(),
+            target: fields[1].to_string(),
+            fstype: fields[2].to_string(),
+            options: fields.get(3).unwrap_or(&"defaults").to_string(),
+        });
+    }
+
+    Ok(entries)
+}
+
+/// Resolve a UUID=/PARTUUID=/LABEL=/PARTLABEL= or /dev source to a device on this host
+pub fn resolve_source(source: &str) -> Option<String> {
+    let token = ["UUID=", "PARTUUID=", "LABEL=", "PARTLABEL="]
+        .iter()
+        .find(|prefix| source.starts_with(*prefix))
+        .map(|_| source.to_string());
+
+    match token {
+        Some(token) => crate::blkid::find_device(&token),
+        None if source.starts_with("/dev/") => Path::new(source).exists().then(|| source.to_string()),
+        None => None,
+    }
+}
+
+pub fn device_fstype(device: &str) -> Option<String> {
+    crate::blkid::value(&crate::blkid::values(device).ok()?, "TYPE")
+}
+
+/// Check every block-device entry of the target's fstab before anyone tries to boot it:
+/// the source must resolve via blkid, the fs type must match what is on the device and
+/// the mountpoint must exist on the target root. Finishes with `findmnt --verify` when available.
+pub fn validate_fstab(root: &str) -> Result<()> {
+    let fstab_path = format!("{}/etc/fstab", root);
+    let content = std::fs::read_to_string(&fstab_path).context(format!("Failed to read {}", fstab_path))?;
+    let entries = parse_fstab(&content)?;
+
+    let mut errors = Vec::new();
+    let mut checked = 0;
+
+    for entry in &entries {
+        let is_block = entry.source.starts_with("/dev/") || entry.source.contains('=');
+        if !is_block || entry.options.split(',').any(|o| o == "noauto" || o == "nofail") {
+            continue;
+        }
+        checked += 1;
+        let errors_before = errors.len();
+
+        let Some(device) = resolve_source(&entry.source) else {
+            errors.push(format!("line {}: {} does not resolve to any device", entry.line, entry.source));
+            continue;
+        };
+
+        match device_fstype(&device) {
+            Some(actual) if entry.fstype != "auto" && actual != entry.fstype => errors.push(format!(
+                "line {}: {} is {} but fstab says {}",
+                entry.line, entry.source, actual, entry.fstype
+            )),
+            None => errors.push(format!("line {}: {} ({}) has no filesystem", entry.line, entry.source, device)),
+            _ => {}
+        }
+
+        if entry.fstype != "swap" && !Path::new(&format!("{}{}", root, entry.target)).is_dir() {
+            errors.push(format!("line {}: mountpoint {} does not exist on the target", entry.line, entry.target));
+        }
+
+        if errors.len() == errors_before {
+            println!("    {} -> {} ({}) ok", entry.source, entry.target, device);
+        }
+    }
+
+    if crate::command_exists("findmnt") {
+        let output = Command::new("findmnt")
+            .args(["--verify", "--tab-file", &fstab_path])
+            .output()
+            .context("Failed to run findmnt --verify")?;
+        if !output.status.success() {
+            let report = String::from_utf8_lossy(&output.stdout);
+            for line in report.lines().filter(|l| l.contains("[E]")) {
+                errors.push(format!("findmnt: {}", line.trim()));
+            }
+        }
+    }
+
+    if !errors.is_empty() {
+        for error in &errors {
+            println!("    {}", error);
+        }
+        bail!("{} has {} problem(s); fix them before booting the target", fstab_path, errors.len());
+    }
+
+    println!("  fstab validated: {} block device entries resolve and match", checked);
+    Ok(())
+}
+
+/// First and last line of the entries a run appends
+pub const BLOCK_BEGIN: &str = "# BEGIN rpi-fs-shrink";
+pub const BLOCK_END: &str = "# END rpi-fs-shrink";
+
+/// Header of the entries appended by releases before the provenance block; the
+/// entries ran up to the next blank line
+const LEGACY_MARKER: &str = "# Added by rpi-fs-shrink";
+
+/// Prefix of the swap entries a run commented out
+pub const DISABLED_PREFIX: &str = "# Disabled by rpi-fs-shrink: ";
+
+/// Which run wrote the entries of a block
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Provenance {
+    pub version: String,
+    pub date: String,
+    /// SHA-256 of the plan (as --plan-json writes it) the run applied
+    pub plan_hash: String,
+}
+
+impl Provenance {
+    pub fn new(plan_hash: &str) -> Self {
+        Provenance {
+            version: env!("CARGO_PKG_VERSION").to_string(),
+            date: crate::audit::utc_timestamp(),
+            plan_hash: plan_hash.to_string(),
+        }
+    }
+
+    pub fn describe(&self) -> String {
+        format!("rpi-fs-shrink {} on {}, plan {}", self.version, self.date, &self.plan_hash[..self.plan_hash.len().min(12)])
+    }
+}
+
+/// `entries` between the block markers, headed by where they came from
+pub fn provenance_block(provenance: &Provenance, entries: &[String]) -> String {
+    let mut block = format!(
+        "{}\n# version: {}\n# date: {}\n# plan: sha256:{}\n# Remove with: rpi-fs-shrink fstab-undo /etc/fstab\n",
+        BLOCK_BEGIN, provenance.version, provenance.date, provenance.plan_hash
+    );
+    for entry in entries {
+        block.push_str(&format!("{}\n", entry));
+    }
+    block.push_str(&format!("{}\n", BLOCK_END));
+    block
+}
+
+/// The provenance of the block in `content`, if a run wrote one
+pub fn read_provenance(content: &str) -> Option<Provenance> {
+    let mut lines = content.lines().map(str::trim).skip_while(|line| *line != BLOCK_BEGIN).skip(1);
+    let mut field = |name: &str| lines.next()?.strip_prefix(&format!("# {}: ", name)).map(str::to_string);
+    let version = field("version")?;
+    let date = field("date")?;
+    let plan_hash = field("plan")?.trim_start_matches("sha256:").to_string();
+    Some(Provenance { version, date, plan_hash })
+}
+
+/// `content` without the entries earlier runs appended, in a block or under the
+/// old header; returns the removed entries
+pub fn strip_block(content: &str) -> (String, Vec<String>) {
+    let mut kept: Vec<&str> = Vec::new();
+    let mut removed = Vec::new();
+    let mut in_block = false;
+    let mut in_legacy = false;
+    for line in content.lines() {
+        let trimmed = line.trim();
+        if in_block {
+            if trimmed == BLOCK_END {
+                in_block = false;
+            } else if !trimmed.is_empty() && !trimmed.starts_with('#') {
+                removed.push(line.to_string());
+            }
+            continue;
+        }
+        if in_legacy {
+            if !trimmed.is_empty() {
+                if !trimmed.starts_with('#') {
+                    removed.push(line.to_string());
+                }
+                continue;
+            }
+            in_legacy = false;
+        }
+        if trimmed == BLOCK_BEGIN || trimmed == LEGACY_MARKER {
+            in_block = trimmed == BLOCK_BEGIN;
+            in_legacy = !in_block;
+            // The blank line the run put in front of its entries goes with them
+            if kept.last().is_some_and(|last| last.trim().is_empty()) {
+                kept.pop();
+            }
+            continue;
+        }
+        kept.push(line);
+    }
+    let mut stripped = kept.join("\n");
+    if !stripped.is_empty() {
+        stripped.push('\n');
+    }
+    (stripped, removed)
+}
+
+/// `content` as it was before the run: its entries removed and the swap entries
+/// it disabled enabled again. PARTUUIDs it rewrote stay, as the partitions keep them.
+pub fn undo(content: &str) -> (String, Vec<String>, Vec<String>) {
+    let (stripped, removed) = strip_block(content);
+    let mut enabled = Vec::new();
+    let mut restored = String::new();
+    for line in stripped.lines() {
+        match line.strip_prefix(DISABLED_PREFIX) {
+            Some(original) => {
+                enabled.push(original.to_string());
+                restored.push_str(original);
+            }
+            None => restored.push_str(line),
+        }
+        restored.push('\n');
+    }
+    (restored, removed, enabled)
+}
+
+/// An fstab entry as runs write it
+pub fn fstab_line(source: &str, target: &str, fstype: &str, options: &str, pass: u8) -> String {
+    format!("{}  {}  {}  {}  0  {}", source, target, fstype, options, pass)
+}
+
+/// `content` with the entries mounting `PARTUUID=old` pointed at `new`; each
+/// line keeps its own spacing, and comments are left alone
+fn with_partuuid(content: &str, old: &str, new: &str) -> String {
+    let old_source = format!("PARTUUID={}", old);
+    let mut updated = String::new();
+    for line in content.split_inclusive('\n') {
+        let indent = line.len() - line.trim_start().len();
+        let source = line[indent..].split_whitespace().next().unwrap_or("");
+        if source.eq_ignore_ascii_case(&old_source) {
+            updated.push_str(&line[..indent]);
+            updated.push_str(&format!("PARTUUID={}", new));
+            updated.push_str(&line[indent + source.len()..]);
+        } else {
+            updated.push_str(line);
+        }
+    }
+    updated
+}
+
+/// `content` with changed PARTUUIDs replaced, the swap entries of
+/// `disabled_swap` commented out and `new_entries` appended in a block headed by
+/// `provenance`. The block of an earlier run is replaced, and entries that are
+/// present elsewhere are skipped. Returns the new fstab and what changed.
+pub fn update(
+    content: &str,
+    partuuid_changes: &[(String, String)],
+    mut new_entries: Vec<String>,
+    disabled_swap: &[String],
+    provenance: Option<&Provenance>,
+) -> Result<(String, Vec<String>)> {
+    let mut notes = Vec::new();
+    let mut content = content.to_string();
+    if provenance.is_some() {
+        let (stripped, removed) = strip_block(&content);
+        if !removed.is_empty() {
+            notes.push(match read_provenance(&content) {
+                Some(earlier) => format!("Replacing the entries written by {}", earlier.describe()),
+                None => "Replacing the entries written by an earlier run".to_string(),
+            });
+            content = stripped;
+        }
+    }
+    for (old, new) in partuuid_changes {
+        content = with_partuuid(&content, old, new);
+        notes.push(format!("PARTUUID={} -> PARTUUID={}", old, new));
+    }
+
+    // An entry the new swap reuses (same file, or --reuse-uuids) stays
+    let kept: Vec<&str> = new_entries.iter().filter_map(|entry| entry.split_whitespace().next()).collect();
+    let disabled: Vec<usize> = parse_fstab(&content)?
+        .iter()
+        .filter(|e| e.fstype == "swap" && disabled_swap.contains(&e.source) && !kept.contains(&e.source.as_str()))
+        .map(|e| e.line)
+        .collect();
+    if !disabled.is_empty() {
+        content = content
+            .lines()
+            .enumerate()
+            .map(|(index, line)| {
+                if disabled.contains(&(index + 1)) {
+                    notes.push(format!("Old swap disabled: {}", line.trim()));
+                    format!("{}{}\n", DISABLED_PREFIX, line)
+                } else {
+                    format!("{}\n", line)
+                }
+            })
+            .collect();
+    }
+
+    let existing = parse_fstab(&content)?;
+    new_entries.retain(|entry| {
+        let mut fields = entry.split_whitespace();
+        let (source, target) = (fields.next().unwrap_or(""), fields.next().unwrap_or(""));
+        let present = existing.iter().any(|e| e.source == source && e.target == target);
+        if present {
+            notes.push(format!("{} {} already in fstab", source, target));
+        }
+        !present
+    });
+
+    if let Some(provenance) = provenance
+        && !new_entries.is_empty()
+    {
+        if !content.is_empty() && !content.ends_with('\n') {
+            content.push('\n');
+        }
+        content.push('\n');
+        content.push_str(&provenance_block(provenance, &new_entries));
+    }
+    Ok((content, notes))
+}
+
+/// Undo what runs wrote to the fstab at `path`, keeping a copy of it next to it
+pub fn run_undo(path: &str, dry_run: bool) -> Result<()> {
+    let content = std::fs::read_to_string(path).context(format!("Failed to read {}", path))?;
+    let (restored, removed, enabled) = undo(&content);
+    if removed.is_empty() && enabled.is_empty() {
+        println!("{} has no entries from rpi-fs-shrink", path);
+        return Ok(());
+    }
+    if let Some(provenance) = read_provenance(&content) {
+        println!("Entries written by {}:", provenance.describe());
+    }
+    for entry in &removed {
+        println!("  remove: {}", entry);
+    }
+    for entry in &enabled {
+        println!("  enable: {}", entry);
+    }
+    if dry_run {
+        println!("Dry run: {} not changed", path);
+        return Ok(());
+    }
+    let backup = format!("{}.before-undo", path);
+    std::fs::write(&backup, &content).context(format!("Failed to write {}", backup))?;
+    std::fs::write(path, restored).context(format!("Failed to write {}", path))?;
+    println!("{} restored (previous version in {})", path, backup);
+    println!(
+        "Data isn't moved back: restore /var and /home from /var.old and /home.old on root (while the cleanup\n\
+        service hasn't removed them yet) or copy them from the new partitions before booting"
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RPI_OS: &str = "proc            /proc           proc    defaults          0       0\n\
+        PARTUUID=738a4d67-01  /boot/firmware  vfat    defaults          0       2\n\
+        PARTUUID=738a4d67-02  /               ext4    defaults,noatime  0       1\n\
+        # a swapfile is not a swap partition, no line here\n\
+        #   use  dphys-swapfile swap[on|off]  for that\n";
+
+    fn provenance() -> Provenance {
+        Provenance { version: "0.1.0".to_string(), date: "2026-01-02T03:04:05Z".to_string(), plan_hash: "ab".repeat(32) }
+    }
+
+    fn home() -> Vec<String> {
+        vec![fstab_line("UUID=5d2f0a6e-1c3b-4f7e-9a8d-2b4c6e8f0a1c", "/home", "ext4", "defaults", 2)]
+    }
+
+    fn changes(old: &str, new: &str) -> Vec<(String, String)> {
+        vec![(old.to_string(), new.to_string())]
+    }
+
+    #[test]
+    fn raspberry_pi_os_gets_a_block_and_the_new_root() {
+        let (updated, notes) = update(RPI_OS, &changes("738a4d67-02", "9e1f03c2-02"), home(), &[], Some(&provenance())).unwrap();
+        assert!(updated.starts_with(&RPI_OS[..RPI_OS.find("PARTUUID=738a4d67-02").unwrap()]));
+        assert!(updated.contains("PARTUUID=9e1f03c2-02  /               ext4    defaults,noatime  0       1\n"));
+        assert!(!updated.contains("738a4d67-02"));
+        assert!(updated.contains("\n\n# BEGIN rpi-fs-shrink\n# version: 0.1.0\n"));
+        assert!(updated.ends_with("UUID=5d2f0a6e-1c3b-4f7e-9a8d-2b4c6e8f0a1c  /home  ext4  defaults  0  2\n# END rpi-fs-shrink\n"));
+        assert_eq!(notes, vec!["PARTUUID=738a4d67-02 -> PARTUUID=9e1f03c2-02"]);
+        assert_eq!(read_provenance(&updated), Some(provenance()));
+    }
+
+    #[test]
+    fn tabs_survive_a_partuuid_change() {
+        let fstab = "PARTUUID=738a4d67-01\t/boot\tvfat\tdefaults\t0\t2\n\tPARTUUID=738a4d67-02\t/\text4\tdefaults,noatime\t0\t1\n";
+        let (updated, _) = update(fstab, &changes("738a4d67-02", "9e1f03c2-02"), Vec::new(), &[], None).unwrap();
+        assert_eq!(updated, "PARTUUID=738a4d67-01\t/boot\tvfat\tdefaults\t0\t2\n\tPARTUUID=9e1f03c2-02\t/\text4\tdefaults,noatime\t0\t1\n");
+    }
+
+    #[test]
+    fn comments_and_similar_partuuids_are_left_alone() {
+        let fstab = "# root was PARTUUID=738a4d67-02 before the move\n\
+            #PARTUUID=738a4d67-02 /old ext4 defaults 0 0\n\
+            PARTUUID=738a4d67-022 /data ext4 defaults 0 2\n\
+            PARTUUID=738a4d67-02 / ext4 defaults 0 1\n";
+        let (updated, _) = update(fstab, &changes("738a4d67-02", "9e1f03c2-02"), Vec::new(), &[], None).unwrap();
+        assert_eq!(updated, fstab.replace("PARTUUID=738a4d67-02 / ", "PARTUUID=9e1f03c2-02 / "));
+    }
+
+    #[test]
+    fn gpt_partuuids_match_case_insensitively() {
+        let fstab = "PARTUUID=5C3B1D2A-8F41-4E6B-9D3C-1A2B3C4D5E6F / ext4 defaults 0 1\n";
+        let (updated, _) =
+            update(fstab, &changes("5c3b1d2a-8f41-4e6b-9d3c-1a2b3c4d5e6f", "0f0e0d0c-0b0a-0908-0706-050403020100"), Vec::new(), &[], None).unwrap();
+        assert_eq!(updated, "PARTUUID=0f0e0d0c-0b0a-0908-0706-050403020100 / ext4 defaults 0 1\n");
+    }
+
+    #[test]
+    fn label_and_uuid_entries_are_kept_as_they_are() {
+        let fstab = "LABEL=rootfs / ext4 defaults,noatime 0 1\nUUID=4A1B-2C3D /boot/firmware vfat defaults 0 2\n/dev/sda1 /mnt/usb auto nofail 0 0\n";
+        let (updated, notes) = update(fstab, &[], home(), &[], Some(&provenance())).unwrap();
+        assert!(updated.starts_with(fstab));
+        assert!(notes.is_empty());
+    }
+
+    #[test]
+    fn a_second_run_replaces_the_block_of_the_first() {
+        let (first, _) = update(RPI_OS, &[], home(), &[], Some(&provenance())).unwrap();
+        let (second, notes) = update(&first, &[], home(), &[], Some(&provenance())).unwrap();
+        assert_eq!(second, first);
+        assert_eq!(notes.len(), 1);
+        assert!(notes[0].starts_with("Replacing the entries written by rpi-fs-shrink 0.1.0"));
+    }
+
+    #[test]
+    fn legacy_entries_are_replaced() {
+        let fstab = format!("{}\n# Added by rpi-fs-shrink\nUUID=0000 /home ext4 defaults 0 2\n", RPI_OS);
+        let (updated, notes) = update(&fstab, &[], home(), &[], Some(&provenance())).unwrap();
+        assert!(!updated.contains("UUID=0000"));
+        assert!(!updated.contains("# Added by"));
+        assert_eq!(notes, vec!["Replacing the entries written by an earlier run"]);
+    }
+
+    #[test]
+    fn replaced_swap_is_commented_out_unless_reused() {
+        let fstab = "PARTUUID=738a4d67-02 / ext4 defaults 0 1\n/var/swap none swap sw 0 0\nUUID=77aa-swap none swap sw 0 0\n";
+        let disabled = vec!["/var/swap".to_string(), "UUID=77aa-swap".to_string()];
+        let new = vec![fstab_line("UUID=77aa-swap", "none", "swap", "sw", 0)];
+        let (updated, notes) = update(fstab, &[], new, &disabled, Some(&provenance())).unwrap();
+        assert!(updated.contains("\n# Disabled by rpi-fs-shrink: /var/swap none swap sw 0 0\nUUID=77aa-swap none swap sw 0 0\n"));
+        assert_eq!(notes, vec!["Old swap disabled: /var/swap none swap sw 0 0", "UUID=77aa-swap none already in fstab"]);
+        // Nothing new to add, so no empty block
+        assert!(!updated.contains(BLOCK_BEGIN));
+    }
+
+    #[test]
+    fn a_missing_final_newline_is_added_before_the_block() {
+        let fstab = "PARTUUID=738a4d67-02 / ext4 defaults 0 1";
+        let (updated, _) = update(fstab, &[], home(), &[], Some(&provenance())).unwrap();
+        assert!(updated.starts_with("PARTUUID=738a4d67-02 / ext4 defaults 0 1\n\n# BEGIN rpi-fs-shrink\n"));
+    }
+
+    #[test]
+    fn without_provenance_nothing_is_appended() {
+        let (updated, _) = update(RPI_OS, &[], home(), &[], None).unwrap();
+        assert_eq!(updated, RPI_OS);
+    }
+
+    #[test]
+    fn undo_restores_the_original() {
+        let fstab = format!("{}/var/swap none swap sw 0 0\n", RPI_OS);
+        let (updated, _) = update(&fstab, &[], home(), &["/var/swap".to_string()], Some(&provenance())).unwrap();
+        let (restored, removed, enabled) = undo(&updated);
+        assert_eq!(restored, fstab);
+        assert_eq!(removed, home());
+        assert_eq!(enabled, vec!["/var/swap none swap sw 0 0"]);
+    }
+
+    #[test]
+    fn short_lines_are_errors() {
+        assert!(update("PARTUUID=738a4d67-02 /\n", &[], home(), &[], None).is_err());
+    }
+}
@@ -0,0 +1,99 @@
+//! Shell-style pattern matching for path-like names, shared by `--exclude`
+//! and the site device policy.
+
+/// Shell-style match: `*` and `?` don't match `/`, `**` does, `[...]` is a character class
+pub fn glob_match(pattern: &str, text: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let text: Vec<char> = text.chars().collect();
+    glob_at(&pattern, &text)
+}
+
+fn glob_at(pattern: &[char], text: &[char]) -> bool {
+    match pattern.first() {
+        None => text.is_empty(),
+        Some('*') if pattern.get(1) == Some(&'*') => {
+            let rest = &pattern[2..];
+            (0..=text.len()).any(|skip| glob_at(rest, &text[skip..]))
+        }
+        Some('*') => {
+            let rest = &pattern[1..];
+            let limit = text.iter().position(|&c| c == '/').unwrap_or(text.len());
+            (0..=limit).any(|skip| glob_at(rest, &text[skip..]))
+        }
+        Some('?') => text.first().is_some_and(|&c| c != '/') && glob_at(&pattern[1..], &text[1..]),
+        Some('[') => match (class_end(pattern), text.first()) {
+            (Some(end), Some(&c)) => c != '/' && class_matches(&pattern[1..end], c) && glob_at(&pattern[end + 1..], &text[1..]),
+            (Some(_), None) => false,
+            // An unclosed [ is an ordinary character
+            (None, _) => text.first() == Some(&'[') && glob_at(&pattern[1..], &text[1..]),
+        },
+        Some(&p) => text.first() == Some(&p) && glob_at(&pattern[1..], &text[1..]),
+    }
+}
+
+/// Index of the ] closing the class that starts at pattern[0]
+fn class_end(pattern: &[char]) -> Option<usize> {
+    // A ] right after [ or [! is part of the class
+    let start = if pattern.get(1) == Some(&'!') { 3 } else { 2 };
+    pattern.iter().skip(start).position(|&c| c == ']').map(|index| index + start)
+}
+
+fn class_matches(class: &[char], c: char) -> bool {
+    let (negated, class) = match class.first() {
+        Some('!') | Some('^') => (true, &class[1..]),
+        _ => (false, class),
+    };
+    let mut found = false;
+    let mut index = 0;
+    while index < class.len() {
+        if index + 2 < class.len() && class[index + 1] == '-' {
+            found |= (class[index]..=class[index + 2]).contains(&c);
+            index += 3;
+        } else {
+            found |= class[index] == c;
+            index += 1;
+        }
+    }
+    found != negated
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn patterns_match_like_the_shell() {
+        let cases: &[(&str, &str, bool)] = &[
+            ("sda", "sda", true),
+            ("sda", "sdab", false),
+            ("sd?", "sdb", true),
+            ("sd?", "sd", false),
+            ("mmcblk*", "mmcblk0", true),
+            ("mmcblk*", "mmcblk", true),
+            ("*.tmp", "a.tmp", true),
+            ("*.tmp", "a.tmpx", false),
+            // * and ? stay within a path component, ** crosses them
+            ("/dev/*", "/dev/sda", true),
+            ("/dev/*", "/dev/disk/by-id/usb-x", false),
+            ("/dev/**", "/dev/disk/by-id/usb-x", true),
+            ("a?c", "a/c", false),
+            ("**/.cache", "pi/x/.cache", true),
+            // Classes, ranges and negation
+            ("sd[b-z]", "sdc", true),
+            ("sd[b-z]", "sda", false),
+            ("sd[!a]", "sdb", true),
+            ("sd[^a]", "sda", false),
+            ("nvme[0-9]n1", "nvme0n1", true),
+            ("[]x]", "]", true),
+            ("a[/]b", "a/b", false),
+            // An unclosed [ is an ordinary character
+            ("a[b", "a[b", true),
+            ("a[b", "ab", false),
+            ("", "", true),
+            ("", "a", false),
+        ];
+        for &(pattern, text, expected) in cases {
+            assert_eq!(glob_match(pattern, text), expected, "{} against {}", pattern, text);
+        }
+    }
+}
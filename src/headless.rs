@@ -0,0 +1,262 @@
+use anyhow::{anyhow, bail, Context, Result};
+use std::os::unix::fs::PermissionsExt;
+use std::path::Path;
+
+use crate::firmware::BootLayout;
+use crate::{mount_at, unmount_quiet, MountPaths};
+
+/// First-boot configuration injected into the target while it is mounted
+#[derive(Debug, Default)]
+pub struct HeadlessOptions {
+    pub enable_ssh: bool,
+    pub ssh_key: Option<String>,
+    pub wifi: Option<(String, String)>,
+    pub wifi_country: Option<String>,
+    pub user: Option<(String, String)>,
+}
+
+impl HeadlessOptions {
+    pub fn is_empty(&self) -> bool {
+        !self.enable_ssh && self.ssh_key.is_none() && self.wifi.is_none() && self.user.is_none()
+    }
+}
+
+/// Parse `SSID:PSK`
+pub fn parse_wifi(value: &str) -> Result<(String, String)> {
+    let (ssid, psk) = value
+        .split_once(':')
+        .ok_or_else(|| anyhow!("Wi-Fi must be given as SSID:PSK"))?;
+
+    if ssid.is_empty() || ssid.len() > 32 {
+        bail!("Wi-Fi SSID must be 1-32 characters");
+    }
+    if psk.len() < 8 || psk.len() > 63 {
+        bail!("Wi-Fi PSK must be 8-63 characters");
+    }
+
+    Ok((ssid.to_string(), psk.to_string()))
+}
+
+/// Parse `name:hash` where hash is a crypt(3) string (e.g. from `openssl passwd -6`)
+pub fn parse_user(value: &str) -> Result<(String, String)> {
+    let (name, hash) = value
+        .split_once(':')
+        .ok_or_else(|| anyhow!("User must be given as name:hash"))?;
+
+    let name_re = regex::Regex::new(r"^[a-z_][a-z0-9_-]{0,31}$")?;
+    if !name_re.is_match(name) {
+        bail!("Invalid user name: {}", name);
+    }
+    if !hash.starts_with('$') {
+        bail!("User password must be a crypt hash (e.g. from `openssl passwd -6`), not plain text");
+    }
+
+    Ok((name.to_string(), hash.to_string()))
+}
+
+/// Apply headless options. Expects the target root and the new /home to be mounted.
+pub fn apply_headless(options: &HeadlessOptions, boot_device: &str, mounts: &MountPaths) -> Result<()> {
+    let boot = mounts.boot();
+    let layout = BootLayout::detect(&mounts.root());
+    mount_at(boot_device, &boot)?;
+
+    let result = (|| -> Result<()> {
+        if options.enable_ssh {
+            std::fs::write(format!("{}/ssh", boot), "").context("Failed to enable SSH")?;
+            println!("  SSH enabled ({})", layout.path("ssh"));
+        }
+
+        if let Some((ref name, ref hash)) = options.user {
+            std::fs::write(format!("{}/userconf.txt", boot), userconf(name, hash))
+                .context("Failed to write userconf.txt")?;
+            println!("  First user {} configured ({})", name, layout.path("userconf.txt"));
+        }
+
+        if let Some((ref ssid, ref psk)) = options.wifi {
+            write_wifi(ssid, psk, options.wifi_country.as_deref(), layout, mounts)?;
+        }
+
+        if let Some(ref key) = options.ssh_key {
+            let user = options.user.as_ref().map(|(name, _)| name.as_str());
+            install_ssh_key(key, user, mounts)?;
+        }
+
+        Ok(())
+    })();
+
+    unmount_quiet(&boot);
+    result
+}
+
+/// userconf.txt: the first user, created or renamed from uid 1000 on first boot
+fn userconf(name: &str, hash: &str) -> String {
+    format!("{}:{}\n", name, hash)
+}
+
+/// NetworkManager keyfile for a WPA-PSK network
+fn nm_keyfile(ssid: &str, psk: &str) -> String {
+    format!(
+        "[connection]\nid={ssid}\ntype=wifi\nautoconnect=true\n\n\
+         [wifi]\nmode=infrastructure\nssid={ssid}\n\n\
+         [wifi-security]\nkey-mgmt=wpa-psk\npsk={psk}\n\n\
+         [ipv4]\nmethod=auto\n\n[ipv6]\nmethod=auto\n"
+    )
+}
+
+/// wpa_supplicant.conf for a WPA-PSK network
+fn wpa_supplicant_conf(ssid: &str, psk: &str, country: Option<&str>) -> String {
+    let mut conf = String::from("ctrl_interface=DIR=/var/run/wpa_supplicant GROUP=netdev\nupdate_config=1\n");
+    if let Some(country) = country {
+        conf.push_str(&format!("country={}\n", country));
+    }
+    conf.push_str(&format!("\nnetwork={{\n    ssid=\"{}\"\n    psk=\"{}\"\n}}\n", ssid, psk));
+    conf
+}
+
+fn write_wifi(ssid: &str, psk: &str, country: Option<&str>, layout: BootLayout, mounts: &MountPaths) -> Result<()> {
+    let root = mounts.root();
+    let nm_dir = format!("{}/etc/NetworkManager/system-connections", root);
+
+    if Path::new(&format!("{}/etc/NetworkManager", root)).exists() {
+        // Bookworm and later: NetworkManager keyfile
+        std::fs::create_dir_all(&nm_dir).context("Failed to create NetworkManager connection directory")?;
+        let path = format!("{}/{}.nmconnection", nm_dir, ssid.replace('/', "_"));
+        std::fs::write(&path, nm_keyfile(ssid, psk)).context("Failed to write NetworkManager connection")?;
+        // NetworkManager ignores keyfiles readable by other users
+        std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o600))?;
+        println!("  Wi-Fi {} configured ({})", ssid, path.trim_start_matches(&root));
+    } else {
+        // Older images copy boot/wpa_supplicant.conf into place on first boot
+        std::fs::write(format!("{}/wpa_supplicant.conf", mounts.boot()), wpa_supplicant_conf(ssid, psk, country))
+            .context("Failed to write wpa_supplicant.conf")?;
+        println!("  Wi-Fi {} configured ({})", ssid, layout.path("wpa_supplicant.conf"));
+    }
+
+    Ok(())
+}
+
+/// Find uid, gid and home directory for `user` in the target's /etc/passwd,
+/// or the first regular user (uid 1000) when no name is given
+fn lookup_user(user: Option<&str>, root: &str) -> Result<Option<(String, u32, u32, String)>> {
+    let passwd = std::fs::read_to_string(format!("{}/etc/passwd", root)).context("Failed to read target /etc/passwd")?;
+
+    for line in passwd.lines() {
+        let fields: Vec<&str> = line.split(':').collect();
+        if fields.len() < 6 {
+            continue;
+        }
+        let uid = fields[2].parse::<u32>().unwrap_or(0);
+        let matches = match user {
+            Some(name) => fields[0] == name,
+            None => uid == 1000,
+        };
+        if matches {
+            let gid = fields[3].parse::<u32>().unwrap_or(uid);
+            return Ok(Some((fields[0].to_string(), uid, gid, fields[5].to_string())));
+        }
+    }
+
+    Ok(None)
+}
+
+fn install_ssh_key(key_file: &str, user: Option<&str>, mounts: &MountPaths) -> Result<()> {
+    let key = std::fs::read_to_string(key_file).context(format!("Failed to read {}", key_file))?;
+
+    let (name, uid, gid, home) = match lookup_user(user, &mounts.root())? {
+        Some(found) => found,
+        // userconf.txt renames/creates the uid 1000 account on first boot
+        None => match user {
+            Some(name) => (name.to_string(), 1000, 1000, format!("/home/{}", name)),
+            None => bail!("No user found in the target to install the SSH key for; use --user"),
+        },
+    };
+
+    // /home now lives on its own partition
+    let home_dir = match home.strip_prefix("/home/") {
+        Some(rest) => format!("{}/{}", mounts.home(), rest),
+        None => format!("{}{}", mounts.root(), home),
+    };
+
+    let ssh_dir = format!("{}/.ssh", home_dir);
+    std::fs::create_dir_all(&ssh_dir).context(format!("Failed to create {}", ssh_dir))?;
+
+    let auth_keys = format!("{}/authorized_keys", ssh_dir);
+    let mut content = std::fs::read_to_string(&auth_keys).unwrap_or_default();
+    if !content.contains(key.trim()) {
+        if !content.is_empty() && !content.ends_with('\n') {
+            content.push('\n');
+        }
+        content.push_str(key.trim());
+        content.push('\n');
+    }
+    std::fs::write(&auth_keys, content).context("Failed to write authorized_keys")?;
+
+    for (path, mode) in [(&home_dir, None), (&ssh_dir, Some(0o700)), (&auth_keys, Some(0o600))] {
+        std::os::unix::fs::chown(path, Some(uid), Some(gid)).context(format!("Failed to chown {}", path))?;
+        if let Some(mode) = mode {
+            std::fs::set_permissions(path, std::fs::Permissions::from_mode(mode))?;
+        }
+    }
+
+    println!("  SSH key installed for {} ({})", name, home.trim_end_matches('/'));
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn wifi_and_user_arguments_are_checked() {
+        assert_eq!(parse_wifi("home:correct horse").unwrap(), ("home".to_string(), "correct horse".to_string()));
+        // The PSK may hold colons; the SSID ends at the first
+        assert_eq!(parse_wifi("home:a:b:c:d:e").unwrap().1, "a:b:c:d:e");
+        for value in ["home", ":password1", "home:short", &format!("{}:password1", "x".repeat(33)), &format!("home:{}", "p".repeat(64))] {
+            assert!(parse_wifi(value).is_err(), "{}", value);
+        }
+        assert_eq!(parse_user("pi:$6$salt$hash").unwrap(), ("pi".to_string(), "$6$salt$hash".to_string()));
+        for value in ["pi", "pi:raspberry", "Pi:$6$x", "1pi:$6$x", ":$6$x"] {
+            assert!(parse_user(value).is_err(), "{}", value);
+        }
+    }
+
+    #[test]
+    fn networkmanager_keyfile_holds_the_network() {
+        let keyfile = nm_keyfile("home", "correct horse");
+        assert!(keyfile.starts_with("[connection]\nid=home\ntype=wifi\nautoconnect=true\n\n[wifi]\n"), "{}", keyfile);
+        assert!(keyfile.contains("\n[wifi]\nmode=infrastructure\nssid=home\n"));
+        assert!(keyfile.contains("\n[wifi-security]\nkey-mgmt=wpa-psk\npsk=correct horse\n"));
+        assert!(keyfile.ends_with("[ipv4]\nmethod=auto\n\n[ipv6]\nmethod=auto\n"));
+    }
+
+    #[test]
+    fn wpa_supplicant_conf_sets_the_country_when_given() {
+        assert_eq!(
+            wpa_supplicant_conf("home", "correct horse", Some("GB")),
+            "ctrl_interface=DIR=/var/run/wpa_supplicant GROUP=netdev\nupdate_config=1\ncountry=GB\n\n\
+            network={\n    ssid=\"home\"\n    psk=\"correct horse\"\n}\n"
+        );
+        assert!(!wpa_supplicant_conf("home", "correct horse", None).contains("country="));
+    }
+
+    #[test]
+    fn userconf_is_one_line() {
+        assert_eq!(userconf("pi", "$6$salt$hash"), "pi:$6$salt$hash\n");
+    }
+
+    #[test]
+    fn ssh_keys_go_to_the_named_user_or_uid_1000() {
+        let root = std::env::temp_dir().join(format!("rpi-fs-shrink-headless-{}", std::process::id()));
+        std::fs::create_dir_all(root.join("etc")).unwrap();
+        std::fs::write(
+            root.join("etc/passwd"),
+            "root:x:0:0:root:/root:/bin/bash\npi:x:1000:1000:,,,:/home/pi:/bin/bash\nalice:x:1001:1002::/home/alice:/bin/bash\n",
+        )
+        .unwrap();
+        let root_path = root.to_string_lossy().into_owned();
+        assert_eq!(lookup_user(None, &root_path).unwrap(), Some(("pi".to_string(), 1000, 1000, "/home/pi".to_string())));
+        assert_eq!(lookup_user(Some("alice"), &root_path).unwrap(), Some(("alice".to_string(), 1001, 1002, "/home/alice".to_string())));
+        assert_eq!(lookup_user(Some("bob"), &root_path).unwrap(), None);
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+}
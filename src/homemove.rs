@@ -0,0 +1,316 @@
+//! Moving /home out of root before the shrink (`--migrate-strategy move`), for
+//! a root whose data only fits the new root size without /home. Root is first
+//! shrunk just far enough to free the region /home's partition will take, the
+//! /home filesystem is made there through a loop device at that offset, and
+//! /home moves over one top-level entry at a time, each original deleted once
+//! it is copied. Root then shrinks to its final size with /home already gone,
+//! and Step 6 creates the partition around the filesystem.
+//!
+//! A journal on root (/home/.rpi-fs-shrink-move) records the region, the disk's
+//! serial and WWN, a hash of its partition table and the entries already moved,
+//! so a rerun of an interrupted run picks up where it stopped instead of
+//! formatting the region over moved data. The rerun refuses to go on with a
+//! copy of the card in another reader, or a disk whose table changed since.
+//! The journal is deleted in Step 10c, once the /home partition is in the table.
+
+use anyhow::{bail, Context, Result};
+use clap::ValueEnum;
+use std::io::Write;
+use std::path::Path;
+
+use crate::identity::DiskIdentity;
+use crate::verify::{self, Manifest};
+use crate::{copytool, exclude, format, hooks, mount_at, unmount_quiet, MountPaths, SECTOR_SIZE};
+
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MigrateStrategy {
+    /// Copy /home after the shrink; root must hold all the data at its new size
+    Copy,
+    /// Move /home into its partition's region before the shrink, freeing root as it goes
+    Move,
+}
+
+/// Name of the journal in root's /home
+pub const JOURNAL: &str = ".rpi-fs-shrink-move";
+
+/// What the journal records: where the /home filesystem was made, on which
+/// disk, and the top-level entries of /home moved into it
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct Journal {
+    /// First sector and length in sectors of the region
+    pub region: Option<(u64, u64)>,
+    pub disk: Option<DiskIdentity>,
+    /// SHA-256 of the partition table once root was shrunk out of the region
+    pub table: Option<String>,
+    pub done: Vec<String>,
+}
+
+/// Read journal text: a `region START SECTORS` line, a `disk serial=S wwn=W`
+/// line, a `table SHA256` line, then `done NAME` lines. A line cut off by a
+/// crash (no newline) is ignored.
+pub fn parse_journal(text: &str) -> Result<Journal> {
+    let mut journal = Journal::default();
+    let complete = match text.rfind('\n') {
+        Some(end) => &text[..end],
+        None => "",
+    };
+    for line in complete.lines() {
+        match line.split_once(' ') {
+            Some(("region", rest)) => {
+                let numbers: Vec<u64> = rest.split_whitespace().filter_map(|n| n.parse().ok()).collect();
+                let [start, sectors] = numbers[..] else {
+                    bail!("Bad region line in {}: {}", JOURNAL, line);
+                };
+                journal.region = Some((start, sectors));
+            }
+            Some(("disk", rest)) => {
+                let mut disk = DiskIdentity::default();
+                for (key, value) in rest.split_whitespace().filter_map(|field| field.split_once('=')) {
+                    match key {
+                        "serial" => disk.serial = value.to_string(),
+                        "wwn" => disk.wwn = value.to_string(),
+                        _ => {}
+                    }
+                }
+                journal.disk = Some(disk);
+            }
+            Some(("table", hash)) => journal.table = Some(hash.to_string()),
+            Some(("done", name)) => journal.done.push(name.to_string()),
+            _ => bail!("Unknown line in {}: {}", JOURNAL, line),
+        }
+    }
+    Ok(journal)
+}
+
+/// Last sector of root while /home's region from `home_start` is freed: as
+/// close to it as whole 4K blocks allow
+pub fn interim_root_end(root_start: u64, home_start: u64) -> u64 {
+    let bytes = (home_start - root_start) * SECTOR_SIZE / 4096 * 4096;
+    root_start + bytes / SECTOR_SIZE - 1
+}
+
+/// Split a deep-verify manifest of root into the files that stay on root and
+/// those under /home, which Step 1c moves off it. /home's part is `None` when
+/// the journal is among them: an interrupted run had already moved some of
+/// /home when the manifest was taken, so the rest can't be checked against it.
+pub fn split_manifest(manifest: &Manifest) -> (Manifest, Option<Manifest>) {
+    let (home, root): (Manifest, Manifest) = manifest.clone().into_iter().partition(|(path, _)| path.starts_with("home/"));
+    let home = verify::subtree(&home, "home");
+    let interrupted = home.contains_key(JOURNAL);
+    (root, (!interrupted).then_some(home))
+}
+
+/// Delete the journal from root mounted read-write at `root`, once the /home
+/// partition made around the moved filesystem is in the table
+pub fn remove_journal(root: &str) -> Result<()> {
+    let path = format!("{}/home/{}", root, JOURNAL);
+    match std::fs::remove_file(&path) {
+        Err(err) if err.kind() != std::io::ErrorKind::NotFound => Err(err).context(format!("Failed to remove {}", path)),
+        _ => Ok(()),
+    }
+}
+
+/// The filesystem /home was moved into
+pub struct MovedHome {
+    pub uuid: String,
+    pub bytes: u64,
+    pub entries: usize,
+}
+
+/// Remove the directories under and including `path` that the move left empty;
+/// directories still holding excluded files stay
+fn prune_empty_dirs(path: &Path) {
+    let Ok(metadata) = std::fs::symlink_metadata(path) else {
+        return;
+    };
+    if !metadata.is_dir() {
+        return;
+    }
+    if let Ok(entries) = std::fs::read_dir(path) {
+        for entry in entries.flatten() {
+            prune_empty_dirs(&entry.path());
+        }
+    }
+    let _ = std::fs::remove_dir(path);
+}
+
+/// Attach the `sectors` sectors of `disk` from sector `start` to a loop device
+fn attach_region(disk: &str, start: u64, sectors: u64) -> Result<String> {
+    crate::container::attach_loop(disk, false, Some(start * SECTOR_SIZE), Some(sectors * SECTOR_SIZE))
+        .context(format!("Failed to attach sectors {}-{} of {}", start, start + sectors - 1, disk))
+}
+
+pub struct MoveOptions<'a> {
+    pub disk: &'a str,
+    pub identity: &'a DiskIdentity,
+    pub root_device: &'a str,
+    pub home_start: u64,
+    pub home_sectors: u64,
+    pub uuid: Option<&'a str>,
+    pub mkfs_args: Option<&'a Vec<String>>,
+    pub excludes: &'a exclude::Excludes,
+    pub hooks: &'a hooks::Hooks,
+}
+
+/// Move root's /home into a new ext4 in /home's region. Root must already end
+/// before `home_start`.
+pub fn move_home(options: &MoveOptions, mounts: &MountPaths) -> Result<MovedHome> {
+    let root = mounts.root();
+    mount_at(options.root_device, &root)?;
+    let result = (|| -> Result<MovedHome> {
+        let journal_path = format!("{}/home/{}", root, JOURNAL);
+        let journal = match std::fs::read_to_string(&journal_path) {
+            Ok(text) => parse_journal(&text)?,
+            Err(_) => Journal::default(),
+        };
+        let region = (options.home_start, options.home_sectors);
+        if let Some(previous) = journal.region
+            && previous != region
+        {
+            bail!(
+                "An interrupted earlier run moved /home into sectors {}-{}, but this layout puts /home at {}-{}; \
+                rerun with the earlier run's sizes",
+                previous.0,
+                previous.0 + previous.1 - 1,
+                region.0,
+                region.0 + region.1 - 1
+            );
+        }
+
+        // The table as Step 1c left it; a resume needs it unchanged
+        let table = crate::tablecheck::table_hash(options.disk)?;
+        if journal.region.is_some() && (journal.disk.is_none() || journal.table.is_none()) {
+            bail!("{} doesn't say which disk and partition table it was written for; refusing to resume", journal_path);
+        }
+        if journal.table.as_ref().is_some_and(|previous| *previous != table) {
+            bail!(
+                "The partition table of {} changed since the interrupted move in {}; refusing to resume onto it. \
+                The moved part of /home is in sectors {}-{}",
+                options.disk,
+                journal_path,
+                region.0,
+                region.0 + region.1 - 1
+            );
+        }
+        if let Some(ref previous) = journal.disk
+            && previous.differs_from(options.identity)
+        {
+            bail!(
+                "The interrupted move in {} was made on another disk ({}); this is {} ({}). \
+                A copy of a card doesn't hold the /home moved on the original",
+                journal_path,
+                previous.describe(),
+                options.disk,
+                options.identity.describe()
+            );
+        }
+
+        let device = attach_region(options.disk, options.home_start, options.home_sectors)?;
+        let moved = (|| -> Result<MovedHome> {
+            if journal.region.is_some() {
+                println!("  Resuming an earlier move: {} entries already on the /home filesystem", journal.done.len());
+            } else {
+                let job = format::FormatJob::ext4("/home", &device).with_uuid(options.uuid).with_extra_args(options.mkfs_args);
+                format::run_format_jobs(&[job], 1)?;
+            }
+            let mut log = std::fs::OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(&journal_path)
+                .context(format!("Failed to open {}", journal_path))?;
+            if journal.region.is_none() {
+                writeln!(log, "region {} {}", region.0, region.1)?;
+                // Serials and WWNs have no spaces; the model, which may, isn't needed to tell disks apart
+                writeln!(log, "disk serial={} wwn={}", options.identity.serial, options.identity.wwn)?;
+                writeln!(log, "table {}", table)?;
+                log.sync_all()?;
+            }
+
+            let home = mounts.home();
+            mount_at(&device, &home)?;
+            let copied = (|| -> Result<MovedHome> {
+                let source = format!("{}/home", root);
+                hooks::run(options.hooks, "home", "pre", &root, &source, &home)?;
+                let mut names: Vec<String> = std::fs::read_dir(&source)
+                    .context(format!("Failed to read {}", source))?
+                    .filter_map(|entry| entry.ok())
+                    .map(|entry| entry.file_name().to_string_lossy().to_string())
+                    .filter(|name| name != JOURNAL)
+                    .collect();
+                names.sort();
+                let mut entries = journal.done.len();
+                for name in names.iter().filter(|name| !journal.done.contains(name)) {
+                    println!("  Moving /home/{}...", name);
+                    let selection = copytool::Selection { entry: Some(name), remove_source: true };
+                    copytool::copy(&source, &home, "home", options.excludes, &selection)?;
+                    prune_empty_dirs(&Path::new(&source).join(name));
+                    writeln!(log, "done {}", name)?;
+                    log.sync_all()?;
+                    entries += 1;
+                }
+                options.excludes.recreate_excluded_dirs("home", &source, &home)?;
+                hooks::run(options.hooks, "home", "post", &root, &source, &home)?;
+                let (_, bytes) = crate::cleanup::tree_usage(&home)?;
+                Ok(MovedHome { uuid: crate::get_uuid(&device)?, bytes, entries })
+            })();
+            unmount_quiet(&home);
+            copied
+        })();
+        let detached = crate::container::detach_loop(&device);
+        let moved = moved?;
+        detached?;
+        Ok(moved)
+    })();
+    unmount_quiet(&root);
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn journal_records_region_and_moved_entries() {
+        let journal = parse_journal("region 34078720 90177536\ndone alice\ndone bob\n").unwrap();
+        assert_eq!(journal, Journal { region: Some((34078720, 90177536)), disk: None, table: None, done: vec!["alice".into(), "bob".into()] });
+        assert_eq!(parse_journal("").unwrap(), Journal::default());
+    }
+
+    #[test]
+    fn journal_names_the_disk() {
+        let journal = parse_journal("region 34078720 90177536\ndisk serial=0x1c2d3e4f wwn=\ntable 9f86d081\ndone alice\n").unwrap();
+        assert_eq!(journal.table.as_deref(), Some("9f86d081"));
+        let disk = journal.disk.unwrap();
+        assert_eq!((disk.serial.as_str(), disk.wwn.as_str()), ("0x1c2d3e4f", ""));
+    }
+
+    #[test]
+    fn journal_line_cut_off_by_a_crash_is_ignored() {
+        let journal = parse_journal("region 34078720 90177536\ndone alice\ndone bo").unwrap();
+        assert_eq!(journal.done, vec!["alice".to_string()]);
+        assert!(parse_journal("region 1\n").is_err());
+    }
+
+    #[test]
+    fn deep_verify_manifest_splits_off_home() {
+        let hashes = |paths: &[&str]| -> Manifest { paths.iter().map(|path| (path.to_string(), "9f86d081".to_string())).collect() };
+        let before = hashes(&["etc/fstab", "home/alice/notes.txt", "home/bob/.bashrc", "homework"]);
+        let (root, home) = split_manifest(&before);
+        assert_eq!(root, hashes(&["etc/fstab", "homework"]));
+        assert_eq!(home, Some(hashes(&["alice/notes.txt", "bob/.bashrc"])));
+
+        // After the move root's /home holds only the journal and excluded files, which the root part leaves out
+        let after = hashes(&["etc/fstab", "home/.rpi-fs-shrink-move", "home/alice/.cache/x", "homework"]);
+        assert!(verify::compare_manifests(&root, &split_manifest(&after).0).is_empty());
+
+        // Hashed during a resumed move: /home is partly gone already
+        let resumed = hashes(&["etc/fstab", "home/.rpi-fs-shrink-move", "home/bob/.bashrc"]);
+        assert_eq!(split_manifest(&resumed), (hashes(&["etc/fstab"]), None));
+    }
+
+    #[test]
+    fn interim_root_ends_on_a_whole_block_before_home() {
+        assert_eq!(interim_root_end(1056768, 34078720), 34078719);
+        assert_eq!(interim_root_end(8192, 8203), 8199);
+    }
+}
@@ -0,0 +1,87 @@
+//! Commands a layout file runs around the copy of a mountpoint, for data that
+//! needs more than a file copy: a database dump to take first, a cache to drop,
+//! a path to rewrite afterwards.
+//!
+//! ```toml
+//! [hooks.var]
+//! pre = "/usr/local/sbin/dump-databases.sh"
+//! post = "chroot \"$RPI_FS_SHRINK_ROOT\" /usr/local/sbin/check-dumps.sh"
+//! ```
+//!
+//! Hooks run on the host with `sh -c`, while the target is mounted; the
+//! environment says where (see `run`). A failing hook stops the run before
+//! fstab is changed, so the target still boots its old layout.
+
+use anyhow::{bail, Context, Result};
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::process::Command;
+
+/// Mountpoints with hooks, as named in the layout file
+pub const MOUNTPOINTS: &[&str] = &["var", "containers", "home"];
+
+#[derive(Debug, Default, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Hook {
+    /// Before the copy
+    pub pre: Option<String>,
+    /// After the copy, before the copy is checked
+    pub post: Option<String>,
+}
+
+pub type Hooks = BTreeMap<String, Hook>;
+
+/// Refuse hooks for mountpoints the tool doesn't copy
+pub fn validate(hooks: &Hooks, path: &str) -> Result<()> {
+    for name in hooks.keys() {
+        if !MOUNTPOINTS.contains(&name.as_str()) {
+            bail!("[hooks.{}] in {}: hooks run around the copy of {}", name, path, MOUNTPOINTS.join(", "));
+        }
+    }
+    Ok(())
+}
+
+/// Hooks for the plan: one line per command
+pub fn describe(hooks: &Hooks) -> Vec<String> {
+    let mut lines = Vec::new();
+    for (name, hook) in hooks {
+        for (phase, command) in [("pre", &hook.pre), ("post", &hook.post)] {
+            if let Some(command) = command {
+                lines.push(format!("{} {}: {}", phase, name, command));
+            }
+        }
+    }
+    lines
+}
+
+/// Run the `phase` ("pre" or "post") hook of `name`, if the layout has one. The
+/// command sees RPI_FS_SHRINK_HOOK (the phase), RPI_FS_SHRINK_MOUNTPOINT (e.g.
+/// /var), RPI_FS_SHRINK_ROOT (the target root), RPI_FS_SHRINK_SOURCE (the data
+/// on root) and RPI_FS_SHRINK_DEST (the new partition).
+pub fn run(hooks: &Hooks, name: &str, phase: &str, root: &str, source: &str, dest: &str) -> Result<()> {
+    let Some(hook) = hooks.get(name) else {
+        return Ok(());
+    };
+    let command = match phase {
+        "pre" => &hook.pre,
+        _ => &hook.post,
+    };
+    let Some(command) = command else {
+        return Ok(());
+    };
+    let mountpoint = source.strip_prefix(root).unwrap_or(source);
+    println!("  Running {} hook for {}: {}", phase, mountpoint, command);
+    let status = Command::new("sh")
+        .args(["-c", command])
+        .env("RPI_FS_SHRINK_HOOK", phase)
+        .env("RPI_FS_SHRINK_MOUNTPOINT", mountpoint)
+        .env("RPI_FS_SHRINK_ROOT", root)
+        .env("RPI_FS_SHRINK_SOURCE", source)
+        .env("RPI_FS_SHRINK_DEST", dest)
+        .status()
+        .context(format!("Failed to run the {} hook for {}", phase, mountpoint))?;
+    if !status.success() {
+        bail!("The {} hook for {} failed ({}); fstab was not updated, so the disk still boots its original layout", phase, mountpoint, status);
+    }
+    Ok(())
+}
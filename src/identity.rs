@@ -0,0 +1,226 @@
+use anyhow::{bail, Context, Result};
+use std::io::Read;
+use std::path::Path;
+use std::process::Command;
+
+/// Serial number of the disk as reported by lsblk (empty when unknown)
+pub fn disk_serial(device: &str) -> String {
+    Command::new("lsblk")
+        .args(["-dno", "SERIAL", device])
+        .output()
+        .ok()
+        .and_then(|o| crate::parse::first_value(&String::from_utf8_lossy(&o.stdout)))
+        .unwrap_or_default()
+}
+
+/// What tells the physical disk apart when /dev names shift between boots or
+/// the disk moves to another adapter. Empty fields are unknown: image files,
+/// and many SD card readers and USB bridges, report no serial or WWN.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct DiskIdentity {
+    pub serial: String,
+    pub wwn: String,
+    pub model: String,
+}
+
+impl DiskIdentity {
+    pub fn read(device: &str) -> Self {
+        DiskIdentity {
+            serial: disk_serial(device),
+            wwn: crate::sysfs::lsblk_value(device, "WWN").unwrap_or_default(),
+            model: crate::expect::disk_model(device),
+        }
+    }
+
+    /// As JSON, with null for what the disk doesn't report
+    pub fn to_json(&self) -> serde_json::Value {
+        let known = |value: &str| if value.is_empty() { serde_json::Value::Null } else { serde_json::json!(value) };
+        serde_json::json!({ "serial": known(&self.serial), "wwn": known(&self.wwn), "model": known(&self.model) })
+    }
+
+    pub fn from_json(value: &serde_json::Value) -> Self {
+        let field = |name: &str| value[name].as_str().unwrap_or_default().to_string();
+        DiskIdentity { serial: field("serial"), wwn: field("wwn"), model: field("model") }
+    }
+
+    /// "model SanDisk SDSSDA240G, serial 162457400811, WWN 0x5001b44e..." for the fields known
+    pub fn describe(&self) -> String {
+        let fields: Vec<String> = [("model", &self.model), ("serial", &self.serial), ("WWN", &self.wwn)]
+            .iter()
+            .filter(|(_, value)| !value.is_empty())
+            .map(|(name, value)| format!("{} {}", name, value))
+            .collect();
+        if fields.is_empty() { "no model, serial or WWN reported".to_string() } else { fields.join(", ") }
+    }
+
+    /// Whether both sides report something `differs_from` can compare: a WWN
+    /// each, or a serial each
+    pub fn comparable_with(&self, other: &DiskIdentity) -> bool {
+        (!self.wwn.is_empty() && !other.wwn.is_empty()) || (!self.serial.is_empty() && !other.serial.is_empty())
+    }
+
+    /// Whether `other` is a different disk: the WWN decides where both have one,
+    /// else the serial. Nothing known on either side can't tell them apart.
+    pub fn differs_from(&self, other: &DiskIdentity) -> bool {
+        if !self.wwn.is_empty() && !other.wwn.is_empty() {
+            return !self.wwn.eq_ignore_ascii_case(&other.wwn);
+        }
+        !self.serial.is_empty() && !other.serial.is_empty() && self.serial != other.serial
+    }
+}
+
+fn random_hex(bytes: usize) -> Result<String> {
+    let mut buffer = vec![0u8; bytes];
+    std::fs::File::open("/dev/urandom")
+        .and_then(|mut f| f.read_exact(&mut buffer))
+        .context("Failed to read /dev/urandom")?;
+
+    Ok(buffer.iter().map(|b| format!("{:02x}", b)).collect())
+}
+
+/// Expand `{serial}` and `{random}` in a hostname pattern and make the result a valid hostname
+pub fn expand_hostname(pattern: &str, serial: &str) -> Result<String> {
+    let mut hostname = pattern.to_string();
+
+    if hostname.contains("{serial}") {
+        if serial.is_empty() {
+            bail!("Hostname pattern uses {{serial}} but the disk reports no serial number");
+        }
+        hostname = hostname.replace("{serial}", serial.trim_start_matches("0x"));
+    }
+    if hostname.contains("{random}") {
+        hostname = hostname.replace("{random}", &random_hex(3)?);
+    }
+
+    let hostname: String = hostname
+        .to_lowercase()
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() || c == '-' { c } else { '-' })
+        .collect();
+    let hostname = hostname.trim_matches('-');
+    let hostname = &hostname[..hostname.len().min(63)];
+
+    if hostname.is_empty() {
+        bail!("Hostname pattern {} expands to an empty hostname", pattern);
+    }
+
+    Ok(hostname.to_string())
+}
+
+/// Write /etc/hostname and the 127.0.1.1 line of /etc/hosts in the target root
+pub fn set_hostname(root: &str, hostname: &str) -> Result<()> {
+    let hostname_path = format!("{}/etc/hostname", root);
+    let old = std::fs::read_to_string(&hostname_path).unwrap_or_default().trim().to_string();
+    std::fs::write(&hostname_path, format!("{}\n", hostname)).context("Failed to write /etc/hostname")?;
+
+    let hosts_path = format!("{}/etc/hosts", root);
+    let hosts = std::fs::read_to_string(&hosts_path).unwrap_or_default();
+    let mut found = false;
+    let mut updated: Vec<String> = hosts
+        .lines()
+        .map(|line| {
+            if line.starts_with("127.0.1.1") {
+                found = true;
+                format!("127.0.1.1\t{}", hostname)
+            } else {
+                line.to_string()
+            }
+        })
+        .collect();
+    if !found {
+        updated.push(format!("127.0.1.1\t{}", hostname));
+    }
+    std::fs::write(&hosts_path, updated.join("\n") + "\n").context("Failed to write /etc/hosts")?;
+
+    if old.is_empty() {
+        println!("  Hostname set to {}", hostname);
+    } else {
+        println!("  Hostname {} -> {}", old, hostname);
+    }
+    Ok(())
+}
+
+const SSH_KEYGEN_UNIT: &str = "\
+[Unit]
+Description=Regenerate SSH host keys (rpi-fs-shrink)
+Before=ssh.service sshd.service
+ConditionPathExists=!/etc/ssh/ssh_host_ed25519_key
+
+[Service]
+Type=oneshot
+ExecStart=/usr/bin/ssh-keygen -A
+
+[Install]
+WantedBy=multi-user.target
+";
+
+/// Clear machine-id and SSH host keys so every clone gets its own on first boot.
+/// `var_root` is where the target's /var currently lives.
+pub fn reset_identity(root: &str, var_root: &str) -> Result<()> {
+    // An empty machine-id makes systemd generate a new one on first boot
+    let machine_id = format!("{}/etc/machine-id", root);
+    if Path::new(&machine_id).exists() {
+        std::fs::write(&machine_id, "").context("Failed to truncate /etc/machine-id")?;
+        println!("  Cleared /etc/machine-id");
+    }
+
+    let dbus_id = format!("{}/lib/dbus/machine-id", var_root);
+    if let Ok(meta) = std::fs::symlink_metadata(&dbus_id) {
+        if meta.file_type().is_file() {
+            std::fs::remove_file(&dbus_id).context("Failed to remove dbus machine-id")?;
+            std::os::unix::fs::symlink("/etc/machine-id", &dbus_id).context("Failed to link dbus machine-id")?;
+            println!("  Linked /var/lib/dbus/machine-id to /etc/machine-id");
+        }
+    }
+
+    let ssh_dir = format!("{}/etc/ssh", root);
+    if let Ok(entries) = std::fs::read_dir(&ssh_dir) {
+        let mut removed = 0;
+        for entry in entries.flatten() {
+            if entry.file_name().to_string_lossy().starts_with("ssh_host_") {
+                std::fs::remove_file(entry.path()).context(format!("Failed to remove {}", entry.path().display()))?;
+                removed += 1;
+            }
+        }
+        println!("  Removed {} SSH host key files", removed);
+
+        let unit_dir = format!("{}/etc/systemd/system", root);
+        let wants_dir = format!("{}/multi-user.target.wants", unit_dir);
+        std::fs::create_dir_all(&wants_dir).context("Failed to create systemd unit directory")?;
+        std::fs::write(format!("{}/rpi-fs-shrink-ssh-keygen.service", unit_dir), SSH_KEYGEN_UNIT)
+            .context("Failed to write SSH keygen unit")?;
+        let link = format!("{}/rpi-fs-shrink-ssh-keygen.service", wants_dir);
+        if std::fs::symlink_metadata(&link).is_err() {
+            std::os::unix::fs::symlink("/etc/systemd/system/rpi-fs-shrink-ssh-keygen.service", &link)
+                .context("Failed to enable SSH keygen unit")?;
+        }
+        println!("  SSH host keys will be regenerated on first boot");
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn identity(serial: &str, wwn: &str) -> DiskIdentity {
+        DiskIdentity { serial: serial.to_string(), wwn: wwn.to_string(), model: "SSD".to_string() }
+    }
+
+    #[test]
+    fn wwn_decides_before_the_serial() {
+        assert!(!identity("A1", "0x5001b44e").differs_from(&identity("B2", "0x5001B44E")));
+        assert!(identity("A1", "0x5001b44e").differs_from(&identity("A1", "0x5002")));
+        assert!(identity("A1", "").differs_from(&identity("B2", "0x5001b44e")));
+    }
+
+    #[test]
+    fn unknown_identities_are_not_told_apart() {
+        assert!(!identity("", "").differs_from(&identity("B2", "0x5001b44e")));
+        assert!(!identity("", "").comparable_with(&identity("B2", "0x5001b44e")));
+        assert!(!identity("A1", "").comparable_with(&identity("", "0x5001b44e")));
+        assert!(identity("A1", "").comparable_with(&identity("B2", "0x5001b44e")));
+        assert_eq!(DiskIdentity::from_json(&identity("A1", "").to_json()), identity("A1", ""));
+    }
+}
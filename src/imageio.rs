@@ -0,0 +1,178 @@
+use anyhow::{bail, Context, Result};
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::Path;
+use std::process::{Command, Stdio};
+
+const CHUNK_BYTES: usize = 1024 * 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Compression {
+    Xz,
+    Zstd,
+    Gzip,
+}
+
+impl Compression {
+    fn program(self) -> &'static str {
+        match self {
+            Compression::Xz => "xz",
+            Compression::Zstd => "zstd",
+            Compression::Gzip => "gzip",
+        }
+    }
+
+    fn extension(self) -> &'static str {
+        match self {
+            Compression::Xz => ".xz",
+            Compression::Zstd => ".zst",
+            Compression::Gzip => ".gz",
+        }
+    }
+
+    fn compress_args(self) -> &'static [&'static str] {
+        match self {
+            Compression::Xz => &["-c", "-T0"],
+            Compression::Zstd => &["-c", "-T0", "-q"],
+            Compression::Gzip => &["-c"],
+        }
+    }
+}
+
+/// Compression of an image file, judged by its extension
+pub fn compression(path: &str) -> Option<Compression> {
+    [Compression::Xz, Compression::Zstd, Compression::Gzip]
+        .into_iter()
+        .find(|c| path.ends_with(c.extension()))
+}
+
+fn strip_compression(path: &str) -> &str {
+    match compression(path) {
+        Some(c) => &path[..path.len() - c.extension().len()],
+        None => path,
+    }
+}
+
+/// Stream-decompress `src` into `dst`, leaving runs of zeros as holes
+fn decompress(src: &str, kind: Compression, dst: &str) -> Result<()> {
+    println!("Decompressing {} to {}...", src, dst);
+    let mut child = Command::new(kind.program())
+        .args(["-dc", src])
+        .stdout(Stdio::piped())
+        .spawn()
+        .context(format!("Failed to run {}", kind.program()))?;
+
+    let mut stdout = child.stdout.take().context("No decompressor output")?;
+    let mut out = File::create(dst).context(format!("Failed to create {}", dst))?;
+    let mut buffer = vec![0u8; CHUNK_BYTES];
+    let mut total = 0u64;
+
+    loop {
+        // Fill the whole chunk so zero detection sees aligned blocks
+        let mut filled = 0;
+        while filled < buffer.len() {
+            let n = stdout.read(&mut buffer[filled..]).context("Failed to read decompressed data")?;
+            if n == 0 {
+                break;
+            }
+            filled += n;
+        }
+        if filled == 0 {
+            break;
+        }
+
+        if buffer[..filled].iter().all(|&b| b == 0) {
+            out.seek(SeekFrom::Current(filled as i64))?;
+        } else {
+            out.write_all(&buffer[..filled]).context(format!("Failed to write {}", dst))?;
+        }
+        total += filled as u64;
+    }
+    out.set_len(total).context(format!("Failed to size {}", dst))?;
+
+    if !child.wait()?.success() {
+        bail!("{} failed to decompress {}", kind.program(), src);
+    }
+    Ok(())
+}
+
+/// Stream-compress `src` into `dst` through a temporary name, so a failure never
+/// leaves a truncated archive under the final name
+fn compress(src: &str, kind: Compression, dst: &str) -> Result<()> {
+    println!("Compressing {} to {}...", src, dst);
+    let partial = format!("{}.partial", dst);
+    let input = File::open(src).context(format!("Failed to open {}", src))?;
+    let output = File::create(&partial).context(format!("Failed to create {}", partial))?;
+
+    let status = Command::new(kind.program())
+        .args(kind.compress_args())
+        .stdin(input)
+        .stdout(output)
+        .status()
+        .context(format!("Failed to run {}", kind.program()))?;
+
+    if !status.success() {
+        let _ = std::fs::remove_file(&partial);
+        bail!("{} failed to compress {}", kind.program(), src);
+    }
+    std::fs::rename(&partial, dst).context(format!("Failed to rename {} to {}", partial, dst))?;
+    Ok(())
+}
+
+/// Run `f` on an uncompressed, writable image made from `image` and store the
+/// result at `output` (or back at `image`), compressing by extension on either side.
+/// Only one uncompressed copy ever exists on disk. With `write_back` false the
+/// result of `f` is discarded, for inspection and dry runs.
+pub fn process_image(image: &str, output: Option<&str>, write_back: bool, f: impl FnOnce(&str) -> Result<()>) -> Result<()> {
+    if !Path::new(image).is_file() {
+        bail!("{} is not an image file", image);
+    }
+
+    let target = output.unwrap_or(image);
+    let input_kind = compression(image);
+    let output_kind = compression(target);
+
+    // Fast paths without compression
+    if input_kind.is_none() && output_kind.is_none() {
+        if target != image {
+            println!("Copying {} to {}...", image, target);
+            let status = Command::new("cp")
+                .args(["--sparse=always", image, target])
+                .status()
+                .context("Failed to run cp")?;
+            if !status.success() {
+                bail!("Failed to copy {} to {}", image, target);
+            }
+        }
+        return f(target);
+    }
+
+    let working = match output_kind {
+        None if target != image => target.to_string(),
+        _ => format!("{}.work", strip_compression(target)),
+    };
+
+    match input_kind {
+        Some(kind) => decompress(image, kind, &working)?,
+        None => {
+            let status = Command::new("cp")
+                .args(["--sparse=always", image, &working])
+                .status()
+                .context("Failed to run cp")?;
+            if !status.success() {
+                bail!("Failed to copy {} to {}", image, working);
+            }
+        }
+    }
+
+    let result = f(&working).and_then(|_| match (write_back, output_kind) {
+        (true, Some(kind)) => compress(&working, kind, target),
+        (true, None) => std::fs::rename(&working, target).context(format!("Failed to move {} to {}", working, target)),
+        (false, _) => Ok(()),
+    });
+
+    if Path::new(&working).exists() && working != target {
+        let _ = std::fs::remove_file(&working);
+    }
+    result
+}
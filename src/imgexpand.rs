@@ -0,0 +1,123 @@
+use crate::container;
+use crate::template::Variables;
+use anyhow::{bail, Context, Result};
+use serde::Deserialize;
+use std::process::Command;
+
+/// Partition layout to pre-apply to an image, using the same sizes as the command line.
+/// Values can use `${DISK_SIZE}`, `${RAM_SIZE}` and `${SERIAL}` (see template.rs),
+/// and be size rules like `clamp(2*ram, 1G, 8G)` (see sizeexpr.rs).
+///
+/// ```toml
+/// root = "8G"
+/// swap = "clamp(2*ram, 1G, 8G)"
+/// swap_mode = "file"
+/// var = "4G"
+/// recovery = "256M"
+/// home = "rest"
+///
+/// [hooks.var]
+/// pre = "/usr/local/sbin/dump-databases.sh"
+/// ```
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct LayoutFile {
+    pub root: String,
+    pub swap: Option<String>,
+    pub swap_mode: Option<String>,
+    pub var: Option<String>,
+    pub recovery: Option<String>,
+    /// Only "rest": /home always gets what the other partitions leave
+    pub home: Option<String>,
+    /// Commands around the copy of a mountpoint (see hooks.rs)
+    #[serde(default)]
+    pub hooks: crate::hooks::Hooks,
+}
+
+pub fn load_layout(path: &str) -> Result<LayoutFile> {
+    let content = std::fs::read_to_string(path).context(format!("Failed to read {}", path))?;
+    let layout: LayoutFile = toml::from_str(&content).context(format!("Invalid layout file {}", path))?;
+    if let Some(ref home) = layout.home
+        && !crate::sizeexpr::is_rest(home)
+    {
+        bail!("home = \"{}\" in {}: /home always takes the rest of the disk, so only \"rest\" is accepted", home, path);
+    }
+    crate::hooks::validate(&layout.hooks, path)?;
+    Ok(layout)
+}
+
+impl LayoutFile {
+    /// The layout with its `${NAME}` variables filled in
+    pub fn expand(self, variables: &Variables) -> Result<LayoutFile> {
+        let expand = |value: Option<String>| value.map(|value| variables.expand(&value)).transpose();
+        Ok(LayoutFile {
+            root: variables.expand(&self.root)?,
+            swap: expand(self.swap)?,
+            swap_mode: expand(self.swap_mode)?,
+            var: expand(self.var)?,
+            recovery: expand(self.recovery)?,
+            home: self.home,
+            hooks: self.hooks,
+        })
+    }
+}
+
+/// Command line for the main operation that applies `layout` to `image`
+pub fn layout_args(image: &str, layout: &LayoutFile, mount_base: Option<&str>) -> Vec<String> {
+    let mut args = vec!["rpi-fs-shrink".to_string(), "-d".to_string(), image.to_string()];
+    args.extend(["-r".to_string(), layout.root.clone()]);
+    if let Some(ref swap) = layout.swap {
+        args.extend(["-s".to_string(), swap.clone()]);
+    }
+    if let Some(ref mode) = layout.swap_mode {
+        args.extend(["--swap-mode".to_string(), mode.clone()]);
+    }
+    if let Some(ref var) = layout.var {
+        args.extend(["-v".to_string(), var.clone()]);
+    }
+    if let Some(ref recovery) = layout.recovery {
+        args.extend(["--recovery-size".to_string(), recovery.clone()]);
+    }
+    if let Some(base) = mount_base {
+        args.extend(["--mount-base".to_string(), base.to_string()]);
+    }
+    args
+}
+
+/// Grow an image file (sparsely) to `size` bytes so a layout can fill the target card.
+/// A GPT backup header is moved to the new end with sgdisk.
+pub fn grow_image(image: &str, size: u64) -> Result<()> {
+    let current = std::fs::metadata(image)?.len();
+    if size <= current {
+        bail!(
+            "{} is already {} MB; target size {} MB must be larger",
+            image,
+            current / (1024 * 1024),
+            size / (1024 * 1024)
+        );
+    }
+
+    let loop_device = container::attach_image(image)?;
+    let table = crate::get_partition_table_type(&loop_device);
+    container::detach_loop(&loop_device)?;
+    let table = table?;
+    if table == "gpt" && !crate::command_exists("sgdisk") {
+        bail!("Expanding GPT images needs sgdisk (gdisk package)");
+    }
+
+    println!("Growing {} from {} MB to {} MB...", image, current / (1024 * 1024), size / (1024 * 1024));
+    std::fs::OpenOptions::new()
+        .write(true)
+        .open(image)
+        .and_then(|file| file.set_len(size))
+        .context(format!("Failed to grow {}", image))?;
+
+    if table == "gpt" {
+        let status = Command::new("sgdisk").args(["-e", image]).status().context("Failed to run sgdisk")?;
+        if !status.success() {
+            bail!("sgdisk -e failed to move the GPT backup header of {}", image);
+        }
+    }
+
+    Ok(())
+}
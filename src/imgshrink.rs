@@ -0,0 +1,157 @@
+use crate::{container, parse, references, relocate, MountPaths, SECTOR_SIZE};
+use anyhow::{bail, Context, Result};
+use std::path::Path;
+use std::process::Command;
+
+/// Sectors kept after the last partition of a GPT image for the backup header
+pub const GPT_BACKUP_SECTORS: u64 = 34;
+
+/// First-boot helpers that grow root back to the card size, newest first
+const EXPAND_HOOKS: &[&str] = &["usr/lib/raspberrypi-sys-mods/firstboot", "usr/lib/raspi-config/init_resize.sh"];
+
+/// Size of an ext4 filesystem in bytes, from its superblock
+pub fn ext4_size_bytes(device: &str) -> Result<u64> {
+    let output = Command::new("dumpe2fs")
+        .args(["-h", device])
+        .output()
+        .context("Failed to run dumpe2fs")?;
+
+    if !output.status.success() {
+        bail!("dumpe2fs failed on {}", device);
+    }
+
+    let text = String::from_utf8_lossy(&output.stdout);
+    let field = |name: &str| -> Option<u64> {
+        text.lines()
+            .find_map(|line| line.strip_prefix(name))
+            .and_then(|value| value.trim_start_matches(':').trim().parse().ok())
+    };
+
+    match (field("Block count"), field("Block size")) {
+        (Some(count), Some(size)) => Ok(count * size),
+        _ => bail!("Could not read block count of {}", device),
+    }
+}
+
+/// Make the kernel command line run the distribution's resize helper on first boot
+fn add_expand_hook(root_device: &str, boot_device: &str, mounts: &MountPaths) -> Result<()> {
+    crate::mount_at(root_device, &mounts.root())?;
+    let hook = EXPAND_HOOKS
+        .iter()
+        .find(|hook| Path::new(&format!("{}/{}", mounts.root(), hook)).exists())
+        .map(|hook| format!("/{}", hook));
+    crate::unmount_quiet(&mounts.root());
+
+    let Some(hook) = hook else {
+        bail!("Image has no first-boot resize helper (raspberrypi-sys-mods or raspi-config); use it without --auto-expand");
+    };
+
+    crate::mount_at(boot_device, &mounts.boot())?;
+    let cmdline_path = format!("{}/cmdline.txt", mounts.boot());
+    let result = (|| -> Result<()> {
+        let cmdline = std::fs::read_to_string(&cmdline_path).context(format!("Failed to read {}", cmdline_path))?;
+        let mut args: Vec<&str> = cmdline.split_whitespace().filter(|arg| !arg.starts_with("init=")).collect();
+        let init = format!("init={}", hook);
+        args.push(&init);
+        std::fs::write(&cmdline_path, format!("{}\n", args.join(" "))).context(format!("Failed to write {}", cmdline_path))?;
+        println!("  First boot will run {} to expand root", hook);
+        Ok(())
+    })();
+    crate::unmount_quiet(&mounts.boot());
+    result
+}
+
+/// Shrink an image file in place the way PiShrink does: root filesystem to its
+/// minimum, root partition to match, then truncate the file behind the last partition
+pub fn shrink_image(image: &str, auto_expand: bool, mounts: &MountPaths) -> Result<()> {
+    let original_bytes = std::fs::metadata(image)?.len();
+
+    let loop_device = container::attach_image(image)?;
+    let result = shrink_attached(&loop_device, auto_expand, mounts);
+    container::detach_loop(&loop_device)?;
+    let (end_sector, is_gpt) = result?;
+
+    let new_bytes = (end_sector + 1 + if is_gpt { GPT_BACKUP_SECTORS } else { 0 }) * SECTOR_SIZE;
+    println!("\nTruncating {} to {} MB...", image, new_bytes / (1024 * 1024));
+    std::fs::OpenOptions::new()
+        .write(true)
+        .open(image)
+        .and_then(|file| file.set_len(new_bytes))
+        .context(format!("Failed to truncate {}", image))?;
+
+    if is_gpt {
+        // The backup header was cut off with the tail of the image; rewrite it at the new end
+        let status = Command::new("sgdisk").args(["-e", image]).status().context("Failed to run sgdisk")?;
+        if !status.success() {
+            bail!("sgdisk -e failed to relocate the GPT backup header of {}", image);
+        }
+    }
+
+    println!(
+        "\n=== Image shrunk from {} MB to {} MB ===",
+        original_bytes / (1024 * 1024),
+        new_bytes / (1024 * 1024)
+    );
+    Ok(())
+}
+
+/// Returns the last sector in use and whether the table is GPT
+fn shrink_attached(loop_device: &str, auto_expand: bool, mounts: &MountPaths) -> Result<(u64, bool)> {
+    let disk_info = crate::get_disk_info(loop_device)?;
+    let is_gpt = disk_info.partition_table == "gpt";
+    if is_gpt && !crate::command_exists("sgdisk") {
+        bail!("Shrinking GPT images needs sgdisk (gdisk package)");
+    }
+
+    let output = Command::new("parted")
+        .args(["-s", loop_device, "unit", "s", "print"])
+        .output()
+        .context("Failed to run parted")?;
+    let partitions = parse::parted_partitions(&String::from_utf8_lossy(&output.stdout));
+    let last = partitions.iter().map(|p| p.number).max().unwrap_or(0);
+    if last != disk_info.roles.root {
+        bail!("Expected the root partition last; this image has {} partitions, root is {}", partitions.len(), disk_info.roles.root);
+    }
+    let (root_start, _) = crate::get_partition_bounds(loop_device, disk_info.roles.root)?;
+
+    println!("Step 1: Checking filesystem...");
+    crate::check_filesystem(&disk_info.root_partition)?;
+
+    println!("\nStep 2: Shrinking root filesystem to its minimum size...");
+    let status = Command::new("resize2fs")
+        .args(["-M", &disk_info.root_partition])
+        .status()
+        .context("Failed to run resize2fs")?;
+    if !status.success() {
+        bail!("resize2fs -M failed");
+    }
+    let fs_bytes = ext4_size_bytes(&disk_info.root_partition)?;
+    println!("  Root filesystem is now {} MB", fs_bytes / (1024 * 1024));
+
+    println!("\nStep 3: Shrinking root partition...");
+    let old_partuuid = relocate::get_partuuid(&disk_info.root_partition)?;
+    let root_end = root_start + fs_bytes.div_ceil(SECTOR_SIZE) - 1;
+    crate::resize_root_partition(&disk_info, root_start, root_end)?;
+
+    let boot_device = crate::get_partition_device(loop_device, disk_info.roles.boot)?;
+    let root_device = crate::get_partition_device(loop_device, disk_info.roles.root)?;
+
+    // Recreating the entry gives it a new PARTUUID on GPT
+    let new_partuuid = relocate::get_partuuid(&root_device)?;
+    if !old_partuuid.is_empty() && old_partuuid != new_partuuid {
+        println!("\nStep 3b: Updating references to the root PARTUUID...");
+        let changes = [(old_partuuid, new_partuuid)];
+        references::print_references(&references::update_boot_references(&boot_device, &changes, mounts)?);
+        crate::mount_at(&root_device, &mounts.root())?;
+        let changed = references::update_references(&mounts.root(), &changes);
+        crate::unmount_quiet(&mounts.root());
+        references::print_references(&changed?);
+    }
+
+    if auto_expand {
+        println!("\nStep 4: Adding first-boot expansion...");
+        add_expand_hook(&root_device, &boot_device, mounts)?;
+    }
+
+    Ok((root_end, is_gpt))
+}
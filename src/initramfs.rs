@@ -0,0 +1,381 @@
+//! Generating the target's initramfs when the new layout needs one. Raspberry Pi
+//! OS boots without an initramfs, but a LUKS or LVM root, resume from
+//! hibernation and an overlay root only work through one. The target's own
+//! tool (update-initramfs or mkinitcpio) runs in a chroot, and config.txt gets
+//! an `initramfs` line unless the firmware already finds the image.
+
+use anyhow::{bail, Context, Result};
+use clap::ValueEnum;
+use std::path::Path;
+use std::process::Command;
+
+use crate::configtxt;
+use crate::firmware::BootLayout;
+use crate::model::Family;
+use crate::stack::RootStack;
+use crate::{mount_at, unmount_quiet};
+
+/// initramfs-tools hook of Raspberry Pi OS bookworm that copies new images to the
+/// firmware partition, where `auto_initramfs=1` has the firmware find them
+const FIRMWARE_HOOK: &str = "etc/initramfs/post-update.d/z50-raspi-firmware";
+
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InitramfsMode {
+    /// Only when the target needs one (LUKS or LVM root, hibernation, overlay root)
+    Auto,
+    /// Regenerate it in any case
+    Always,
+    /// Never; the target has to run update-initramfs itself
+    Never,
+}
+
+/// Tool the target builds its initramfs with
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Generator {
+    InitramfsTools,
+    Mkinitcpio,
+}
+
+impl Generator {
+    fn detect(root: &str) -> Option<Self> {
+        let exists = |path: &str| Path::new(&format!("{}/{}", root, path)).exists();
+        if exists("usr/sbin/update-initramfs") || exists("sbin/update-initramfs") {
+            Some(Generator::InitramfsTools)
+        } else if exists("usr/bin/mkinitcpio") {
+            Some(Generator::Mkinitcpio)
+        } else {
+            None
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            Generator::InitramfsTools => "update-initramfs",
+            Generator::Mkinitcpio => "mkinitcpio",
+        }
+    }
+}
+
+/// Why the target needs an initramfs; empty when it boots fine without one
+pub fn reasons(stack: &RootStack, hibernate: bool, cmdline: &str) -> Vec<&'static str> {
+    let mut reasons = Vec::new();
+    if stack.luks.is_some() {
+        reasons.push("the root filesystem is on LUKS");
+    }
+    if stack.lvm.is_some() {
+        reasons.push("the root filesystem is on LVM");
+    }
+    if hibernate {
+        reasons.push("resume from hibernation");
+    }
+    if cmdline.split_whitespace().any(|param| param == "boot=overlay") {
+        reasons.push("overlay root (boot=overlay)");
+    }
+    reasons
+}
+
+/// Kernel versions installed on the target
+fn kernel_versions(root: &str) -> Vec<String> {
+    let mut versions: Vec<String> = ["lib/modules", "usr/lib/modules"]
+        .iter()
+        .filter_map(|dir| std::fs::read_dir(format!("{}/{}", root, dir)).ok())
+        .flat_map(|entries| entries.flatten().map(|entry| entry.file_name().to_string_lossy().to_string()))
+        .collect();
+    versions.sort();
+    versions.dedup();
+    versions
+}
+
+/// Kernel image the firmware loads on `family`, unless config.txt names one
+fn kernel_image(config: &configtxt::ConfigTxt, family: Option<Family>) -> Option<String> {
+    if let Some(kernel) = config.get("kernel") {
+        return Some(kernel.to_string());
+    }
+    if config.get("arm_64bit") == Some("1") {
+        return Some("kernel8.img".to_string());
+    }
+    Some(
+        match family? {
+            Family::Pi5 | Family::Pi500 | Family::Cm5 => "kernel_2712.img",
+            Family::Pi4 | Family::Pi400 | Family::Cm4 => "kernel7l.img",
+            Family::Pi2 | Family::Pi3 | Family::Zero2 | Family::Cm3 => "kernel7.img",
+            Family::Pi1 | Family::Zero => "kernel.img",
+        }
+        .to_string(),
+    )
+}
+
+/// The installed kernel version built as `kernel` ("5.15.84-v8+" for kernel8.img)
+fn version_for_kernel<'a>(kernel: &str, versions: &'a [String]) -> Option<&'a String> {
+    let flavour = |version: &str| version.rsplit('-').next().unwrap_or("").trim_end_matches('+').to_string();
+    let wanted = match kernel {
+        "kernel8.img" => "v8",
+        "kernel7l.img" => "v7l",
+        "kernel7.img" => "v7",
+        "kernel_2712.img" => "2712",
+        _ => "",
+    };
+    versions.iter().find(|version| {
+        let flavour = flavour(version);
+        if wanted.is_empty() { !flavour.starts_with('v') && flavour != "2712" } else { flavour == wanted }
+    })
+}
+
+/// Run `args` in the target root
+fn chroot(root: &str, args: &[&str]) -> Result<()> {
+    println!("  chroot: {}", args.join(" "));
+    let status = Command::new("chroot").arg(root).args(args).status().context("Failed to run chroot")?;
+    if !status.success() {
+        bail!("{} failed in the target", args[0]);
+    }
+    Ok(())
+}
+
+fn mount_command(args: &[&str]) -> Result<()> {
+    let status = Command::new("mount").args(args).status().context("Failed to run mount")?;
+    if !status.success() {
+        bail!("mount {} failed", args.join(" "));
+    }
+    Ok(())
+}
+
+/// Whether the target's programs run on this machine; one of another
+/// architecture only runs through qemu-user binfmt
+pub fn runs_here(root: &str) -> bool {
+    Command::new("chroot").args([root, "/bin/true"]).status().map(|status| status.success()).unwrap_or(false)
+}
+
+/// Mount what programs in the target root need (/proc, /sys, /dev, a separate
+/// /var and, when given, the firmware partition), returning the mount points in
+/// the order to unmount them
+pub fn prepare_chroot(root: &str, boot: Option<(&str, BootLayout)>, var: Option<&str>) -> Result<Vec<String>> {
+    let mut mounted = Vec::new();
+    let result = (|| -> Result<()> {
+        for (dir, args) in [("proc", &["-t", "proc", "proc"][..]), ("sys", &["-t", "sysfs", "sysfs"]), ("dev", &["--bind", "/dev"])] {
+            let target = format!("{}/{}", root, dir);
+            std::fs::create_dir_all(&target).context(format!("Failed to create {}", target))?;
+            mount_command(&[args, &[target.as_str()]].concat())?;
+            mounted.push(target);
+        }
+        if let Some(var) = var {
+            let target = format!("{}/var", root);
+            mount_command(&["--bind", var, &target])?;
+            mounted.push(target);
+        }
+        if let Some((boot_device, layout)) = boot {
+            let boot = format!("{}{}", root, layout.mountpoint());
+            mount_at(boot_device, &boot)?;
+            mounted.push(boot);
+        }
+        Ok(())
+    })();
+    if let Err(err) = result {
+        release_chroot(&mounted);
+        return Err(err);
+    }
+    mounted.reverse();
+    Ok(mounted)
+}
+
+pub fn release_chroot(mounted: &[String]) {
+    for mount_point in mounted {
+        unmount_quiet(mount_point);
+    }
+}
+
+/// Make sure the firmware loads the image the generator wrote, adding an
+/// `initramfs` line to config.txt when nothing else does
+fn point_firmware(root: &str, generator: Generator, layout: BootLayout, family: Option<Family>) -> Result<()> {
+    let boot = format!("{}{}", root, layout.mountpoint());
+    let config_path = format!("{}/config.txt", boot);
+    let config = configtxt::ConfigTxt::parse(&std::fs::read_to_string(&config_path).context("Failed to read config.txt")?);
+    if let Some(line) = config.get("initramfs") {
+        println!("  config.txt already loads initramfs {}", line);
+        return Ok(());
+    }
+    if config.get("auto_initramfs") == Some("1") {
+        println!("  config.txt has auto_initramfs=1; the firmware finds the new image");
+        return Ok(());
+    }
+    if generator == Generator::InitramfsTools && Path::new(&format!("{}/{}", root, FIRMWARE_HOOK)).exists() {
+        configtxt::edit(&config_path, |config| config.set("auto_initramfs", "1"))?;
+        println!("  {}: auto_initramfs=1", layout.path("config.txt"));
+        return Ok(());
+    }
+
+    let image = match generator {
+        Generator::Mkinitcpio => "initramfs-linux.img".to_string(),
+        Generator::InitramfsTools => {
+            let versions = kernel_versions(root);
+            if versions.is_empty() {
+                bail!("The target has no kernel modules under /lib/modules to build an initramfs for");
+            }
+            let version = match versions.as_slice() {
+                [only] => only,
+                _ => {
+                    let kernel = kernel_image(&config, family);
+                    match kernel.as_deref().and_then(|kernel| version_for_kernel(kernel, &versions)) {
+                        Some(version) => version,
+                        None => bail!(
+                            "Can't tell which of the kernels {} the target boots; give --target-model or set kernel= in config.txt",
+                            versions.join(", ")
+                        ),
+                    }
+                }
+            };
+            format!("initrd.img-{}", version)
+        }
+    };
+    // Images land in the target's /boot; the firmware only reads its own partition
+    let generated = format!("{}/boot/{}", root, image);
+    let on_firmware = format!("{}/{}", boot, image);
+    if !Path::new(&on_firmware).exists() {
+        if !Path::new(&generated).exists() {
+            bail!("{} didn't produce /boot/{}", generator.name(), image);
+        }
+        std::fs::copy(&generated, &on_firmware).context(format!("Failed to copy {} to the firmware partition", image))?;
+        println!("  Copied /boot/{} to {} (copy it again after kernel updates)", image, layout.path(&image));
+    }
+    configtxt::edit(&config_path, |config| config.set("initramfs", &format!("{} followkernel", image)))?;
+    println!("  {}: initramfs {} followkernel", layout.path("config.txt"), image);
+    Ok(())
+}
+
+/// Build the target's initramfs with its own tool in a chroot and have the
+/// firmware load it
+pub fn generate(root: &str, boot_device: &str, var: Option<&str>, family: Option<Family>) -> Result<()> {
+    let Some(generator) = Generator::detect(root) else {
+        bail!("The target has neither update-initramfs (initramfs-tools) nor mkinitcpio; install one and rerun, or use --initramfs never");
+    };
+    if !crate::privilege::is_root() {
+        bail!("Generating the initramfs runs the target's {} in a chroot, which needs root", generator.name());
+    }
+    if !runs_here(root) {
+        bail!(
+            "The target's programs don't run on this machine (an arm64 target on x86 needs qemu-user-static with binfmt);\n\
+            run {} on the target after the first boot, or use --initramfs never",
+            generator.name()
+        );
+    }
+
+    let layout = BootLayout::detect(root);
+    let mounted = prepare_chroot(root, Some((boot_device, layout)), var)?;
+    let result = (|| -> Result<()> {
+        match generator {
+            Generator::InitramfsTools => {
+                for version in kernel_versions(root) {
+                    let exists = Path::new(&format!("{}/boot/initrd.img-{}", root, version)).exists();
+                    chroot(root, &["update-initramfs", if exists { "-u" } else { "-c" }, "-k", &version])?;
+                }
+            }
+            Generator::Mkinitcpio => chroot(root, &["mkinitcpio", "-P"])?,
+        }
+        point_firmware(root, generator, layout, family)
+    })();
+    release_chroot(&mounted);
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn stack(luks: bool, lvm: bool) -> RootStack {
+        RootStack {
+            partition: "/dev/sda2".to_string(),
+            luks: luks.then(|| crate::stack::LuksLayer { mapper: "/dev/mapper/crpart_root".to_string(), header_bytes: 16 << 20 }),
+            lvm: lvm.then(|| crate::stack::LvmLayer {
+                vg: "vg0".to_string(),
+                pv: "/dev/sda2".to_string(),
+                lv_path: "/dev/vg0/root".to_string(),
+                extent_bytes: 4 << 20,
+                pe_start_bytes: 1 << 20,
+                other_extents: 0,
+            }),
+            fs_device: "/dev/sda2".to_string(),
+        }
+    }
+
+    /// A target root with `config` in its config.txt and a kernel per version
+    fn target(name: &str, config: &str, versions: &[&str]) -> String {
+        let root = std::env::temp_dir().join(format!("rpi-fs-shrink-initramfs-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&root);
+        let root = root.to_string_lossy().into_owned();
+        std::fs::create_dir_all(format!("{}/boot/firmware", root)).unwrap();
+        std::fs::write(format!("{}/boot/firmware/config.txt", root), config).unwrap();
+        for version in versions {
+            std::fs::create_dir_all(format!("{}/lib/modules/{}", root, version)).unwrap();
+            std::fs::write(format!("{}/boot/initrd.img-{}", root, version), version).unwrap();
+        }
+        root
+    }
+
+    fn config(root: &str) -> String {
+        std::fs::read_to_string(format!("{}/boot/firmware/config.txt", root)).unwrap()
+    }
+
+    #[test]
+    fn plain_roots_need_no_initramfs() {
+        assert!(reasons(&stack(false, false), false, "console=tty1 root=PARTUUID=6c586e13-02 rootwait").is_empty());
+        assert_eq!(
+            reasons(&stack(true, true), true, "boot=overlay root=/dev/mapper/vg0-root"),
+            ["the root filesystem is on LUKS", "the root filesystem is on LVM", "resume from hibernation", "overlay root (boot=overlay)"]
+        );
+        // Only the whole parameter counts
+        assert!(reasons(&stack(false, false), false, "boot=overlayfs").is_empty());
+    }
+
+    #[test]
+    fn the_kernel_the_firmware_loads_picks_the_initramfs() {
+        let config = |text: &str| configtxt::ConfigTxt::parse(text);
+        assert_eq!(kernel_image(&config("kernel=vmlinuz\n"), Some(Family::Pi4)).as_deref(), Some("vmlinuz"));
+        assert_eq!(kernel_image(&config("arm_64bit=1\n"), Some(Family::Pi3)).as_deref(), Some("kernel8.img"));
+        assert_eq!(kernel_image(&config(""), Some(Family::Pi5)).as_deref(), Some("kernel_2712.img"));
+        assert_eq!(kernel_image(&config(""), Some(Family::Zero)).as_deref(), Some("kernel.img"));
+        assert_eq!(kernel_image(&config(""), None), None);
+
+        let versions: Vec<String> = ["6.1.21+", "6.1.21-v7+", "6.1.21-v7l+", "6.1.21-v8+", "6.6.31+rpt-rpi-2712"].map(String::from).to_vec();
+        assert_eq!(version_for_kernel("kernel8.img", &versions).map(String::as_str), Some("6.1.21-v8+"));
+        assert_eq!(version_for_kernel("kernel7l.img", &versions).map(String::as_str), Some("6.1.21-v7l+"));
+        assert_eq!(version_for_kernel("kernel7.img", &versions).map(String::as_str), Some("6.1.21-v7+"));
+        assert_eq!(version_for_kernel("kernel_2712.img", &versions).map(String::as_str), Some("6.6.31+rpt-rpi-2712"));
+        assert_eq!(version_for_kernel("kernel.img", &versions).map(String::as_str), Some("6.1.21+"));
+        assert_eq!(version_for_kernel("kernel8.img", &versions[..1]), None);
+    }
+
+    #[test]
+    fn config_txt_is_left_alone_when_the_firmware_already_finds_the_image() {
+        for existing in ["initramfs initrd.img followkernel\n", "auto_initramfs=1\n"] {
+            let root = target("existing", existing, &["6.1.21-v8+"]);
+            point_firmware(&root, Generator::InitramfsTools, BootLayout::Firmware, None).unwrap();
+            assert_eq!(config(&root), existing);
+            std::fs::remove_dir_all(&root).unwrap();
+        }
+    }
+
+    #[test]
+    fn bookworm_hook_gets_auto_initramfs() {
+        let root = target("hook", "arm_64bit=1\n", &["6.1.21-v8+"]);
+        std::fs::create_dir_all(format!("{}/etc/initramfs/post-update.d", root)).unwrap();
+        std::fs::write(format!("{}/{}", root, FIRMWARE_HOOK), "").unwrap();
+        point_firmware(&root, Generator::InitramfsTools, BootLayout::Firmware, None).unwrap();
+        assert!(config(&root).lines().any(|line| line == "auto_initramfs=1"), "{}", config(&root));
+        assert!(!config(&root).contains("initramfs initrd"));
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn without_the_hook_the_image_is_copied_and_named() {
+        let root = target("copy", "arm_64bit=1\n", &["6.1.21-v7l+", "6.1.21-v8+"]);
+        point_firmware(&root, Generator::InitramfsTools, BootLayout::Firmware, Some(Family::Pi4)).unwrap();
+        assert!(config(&root).lines().any(|line| line == "initramfs initrd.img-6.1.21-v8+ followkernel"), "{}", config(&root));
+        assert_eq!(std::fs::read_to_string(format!("{}/boot/firmware/initrd.img-6.1.21-v8+", root)).unwrap(), "6.1.21-v8+");
+        std::fs::remove_dir_all(&root).unwrap();
+
+        // Two kernels and nothing to choose between them
+        let root = target("ambiguous", "", &["6.1.21-v7l+", "6.1.21-v8+"]);
+        let err = point_firmware(&root, Generator::InitramfsTools, BootLayout::Firmware, None).err().unwrap().to_string();
+        assert!(err.contains("--target-model"), "{}", err);
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+}
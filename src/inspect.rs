@@ -0,0 +1,254 @@
+use crate::{container, fstab, policy, privilege, shrinkpart, sysfs, timing, wear, SECTOR_SIZE};
+use anyhow::{bail, Result};
+use std::path::Path;
+use std::process::Command;
+
+/// Run `f` on `device`, attaching image files to a loop device for the duration
+fn with_disk<T>(device: &str, f: impl FnOnce(&str) -> Result<T>) -> Result<T> {
+    if !Path::new(device).is_file() {
+        return f(device);
+    }
+
+    privilege::require("Attaching an image file to a loop device")?;
+    let loop_device = container::attach_image(device)?;
+    let result = f(&loop_device);
+    container::detach_loop(&loop_device)?;
+    result
+}
+
+fn print_unprivileged_note(device: &str) {
+    if !privilege::can_open(device) {
+        println!(
+            "\nNote: no read access to {}. Sizes and types come from sysfs and the udev database \
+            instead of parted and blkid, and filesystem contents are not examined. \
+            Run with sudo for the full picture.",
+            device
+        );
+    }
+}
+
+/// Report on a disk without changing anything; works as a normal user
+pub fn inspect(device: &str) -> Result<()> {
+    with_disk(device, |device| {
+        let disk_info = crate::get_disk_info(device)?;
+        crate::print_disk_info(&disk_info);
+
+        let known = |value: &str| if value.is_empty() { "unknown".to_string() } else { value.to_string() };
+        println!("  Model: {}", known(&disk_info.identity.model));
+        println!("  Serial: {}", known(&disk_info.identity.serial));
+        println!("  WWN: {}", known(&disk_info.identity.wwn));
+
+        // lsblk reads the udev database, which is world-readable
+        println!("\nPartitions:");
+        let output = Command::new("lsblk")
+            .args(["-o", "NAME,SIZE,TYPE,FSTYPE,LABEL,PARTLABEL,MOUNTPOINT", &disk_info.device])
+            .output();
+        match output {
+            Ok(output) if output.status.success() => {
+                for line in String::from_utf8_lossy(&output.stdout).lines() {
+                    println!("  {}", line);
+                }
+            }
+            _ => println!("  (lsblk unavailable)"),
+        }
+
+        print_unprivileged_note(&disk_info.device);
+        Ok(())
+    })
+}
+
+#[derive(Default)]
+struct Checks {
+    failures: u32,
+}
+
+impl Checks {
+    fn pass(&mut self, message: String) {
+        println!("  ok    {}", message);
+    }
+
+    fn fail(&mut self, message: String) {
+        println!("  FAIL  {}", message);
+        self.failures += 1;
+    }
+}
+
+/// Run the pre-flight safety checks a real run would do, without a layout and
+/// without changing anything. Fails if any check fails.
+pub fn check(device: &str) -> Result<()> {
+    let mut checks = Checks::default();
+    println!("Checking {}...", device);
+
+    match policy::load_config().and_then(|config| policy::check_device(&config.devices, device)) {
+        Ok(()) => checks.pass(format!("permitted by {}", policy::CONFIG_PATH)),
+        Err(e) => checks.fail(e.to_string()),
+    }
+
+    with_disk(device, |device| {
+        let disk_info = match crate::get_disk_info(device) {
+            Ok(disk_info) => disk_info,
+            Err(e) => {
+                checks.fail(e.to_string());
+                return Ok(());
+            }
+        };
+        checks.pass(format!(
+            "{} GB disk with a {} partition table",
+            disk_info.size_bytes / (1024 * 1024 * 1024),
+            disk_info.partition_table
+        ));
+
+        match crate::is_active_root_disk(&disk_info.device) {
+            Ok(false) => checks.pass("not the disk the running system boots from".to_string()),
+            Ok(true) => checks.fail(format!("{} is the active root disk", disk_info.device)),
+            Err(e) => checks.fail(e.to_string()),
+        }
+
+        match crate::is_device_mounted(&disk_info.device) {
+            Ok(false) => checks.pass("no partitions mounted".to_string()),
+            Ok(true) => checks.fail(format!("{} has mounted partitions", disk_info.device)),
+            Err(e) => checks.fail(e.to_string()),
+        }
+
+        let root = &disk_info.root_partition;
+        let fs_type = fstab::device_fstype(root)
+            .or_else(|| privilege::fallback(root, "filesystem type", || sysfs::fstype(root)));
+        match fs_type.as_deref() {
+            Some("ext4") => checks.pass(format!("root {} is ext4", root)),
+            Some(kind @ ("crypto_LUKS" | "LVM2_member")) => {
+                checks.pass(format!("root {} is {}; the filesystem inside is checked when the run opens it", root, kind))
+            }
+            Some(kind) => checks.fail(format!("root {} is {}, only ext4 can be shrunk", root, kind)),
+            None => checks.fail(format!("no filesystem found on root {}", root)),
+        }
+
+        if let Some(report) = wear::read_wear(&disk_info.device) {
+            if report.near_end_of_life() {
+                checks.fail(format!("{} reports it is near the end of its life", disk_info.device));
+            } else {
+                checks.pass("wear indicators normal".to_string());
+            }
+        }
+
+        print_unprivileged_note(&disk_info.device);
+        Ok(())
+    })?;
+
+    if checks.failures > 0 {
+        bail!("{} check(s) failed", checks.failures);
+    }
+    println!("\nAll checks passed");
+    Ok(())
+}
+
+/// Root sizes `analyze` shows the outcome of, in GB
+const ROOT_SIZE_CHOICES: &[u64] = &[8, 16, 32, 64];
+
+/// Size and used bytes of the ext2/3/4 or btrfs filesystem on `device`, from its superblock
+fn filesystem_usage(device: &str, fstype: &str) -> Option<(u64, u64)> {
+    let (program, args): (&str, &[&str]) = match fstype {
+        "ext2" | "ext3" | "ext4" => ("dumpe2fs", &["-h"]),
+        "btrfs" => ("btrfs", &["inspect-internal", "dump-super"]),
+        _ => return None,
+    };
+    let output = Command::new(program).args(args).arg(device).output().ok()?;
+    let text = String::from_utf8_lossy(&output.stdout);
+    let field = |name: &str| -> Option<u64> {
+        text.lines()
+            .find_map(|line| line.strip_prefix(name))
+            .and_then(|value| value.trim_start_matches(':').trim().parse().ok())
+    };
+    if fstype == "btrfs" {
+        return Some((field("total_bytes")?, field("bytes_used")?));
+    }
+    let block_size = field("Block size")?;
+    let blocks = field("Block count")?;
+    Some((blocks * block_size, (blocks - field("Free blocks")?) * block_size))
+}
+
+/// Report how much space shrinking would free, without changing anything: every
+/// shrinkable filesystem's size, use and minimum, and the contiguous space behind
+/// root for a few --root-size choices
+pub fn analyze(device: &str) -> Result<()> {
+    with_disk(device, |device| {
+        let disk_info = crate::get_disk_info(device)?;
+        let readable = privilege::can_open(&disk_info.device);
+        let mut partitions = Vec::new();
+        for number in crate::roles::partition_numbers(&disk_info.device) {
+            let (start, end) = crate::get_partition_bounds(&disk_info.device, number)?;
+            partitions.push((number, start, end));
+        }
+
+        println!("Filesystems on {}:", disk_info.device);
+        let mut root_minimum = None;
+        for &(number, start, end) in &partitions {
+            let path = sysfs::partition_path(&disk_info.device, number);
+            let Some(fstype) = fstab::device_fstype(&path).or_else(|| sysfs::fstype(&path)) else {
+                continue;
+            };
+            if !matches!(fstype.as_str(), "ext2" | "ext3" | "ext4" | "btrfs") {
+                continue;
+            }
+            let role = if number == disk_info.roles.root { " (root)" } else { "" };
+            let size = (end - start + 1) * SECTOR_SIZE;
+            let usage = if readable { filesystem_usage(&path, &fstype) } else { None };
+            let Some((_, used)) = usage else {
+                println!("  {}{}: {}, {} (use unknown without read access)", path, role, fstype, timing::format_bytes(size));
+                continue;
+            };
+            let minimum = if fstype == "btrfs" { None } else { shrinkpart::ext_minimum_bytes(&path).ok() };
+            if number == disk_info.roles.root {
+                root_minimum = minimum.or(Some(used));
+            }
+            println!(
+                "  {}{}: {}, {}, {} used, minimum {}",
+                path,
+                role,
+                fstype,
+                timing::format_bytes(size),
+                timing::format_bytes(used),
+                match minimum {
+                    Some(minimum) => timing::format_bytes(minimum),
+                    // min-dev-size needs the filesystem mounted
+                    None => format!("at least {} (mount it for btrfs's exact minimum)", timing::format_bytes(used)),
+                }
+            );
+        }
+
+        let Some(&(_, root_start, root_end)) = partitions.iter().find(|(number, _, _)| *number == disk_info.roles.root) else {
+            bail!("Could not find root partition {} on {}", disk_info.roles.root, disk_info.device);
+        };
+        // The run fills the space behind root up to the next partition or the end of the disk
+        let next = partitions.iter().filter(|(_, start, _)| *start > root_end).map(|(_, start, _)| *start).min();
+        let last_usable = match next {
+            Some(start) => start - 1,
+            None => {
+                disk_info.size_sectors - 1 - if disk_info.partition_table == "gpt" { crate::imgshrink::GPT_BACKUP_SECTORS } else { 0 }
+            }
+        };
+        let root_bytes = (root_end - root_start + 1) * SECTOR_SIZE;
+        println!("\nRoot size choices (root starts at sector {}, now {}):", root_start, timing::format_bytes(root_bytes));
+        for &gb in ROOT_SIZE_CHOICES {
+            let size = gb * 1024 * 1024 * 1024;
+            let root_end = crate::align_sector(root_start + size / SECTOR_SIZE, disk_info.alignment());
+            match root_minimum {
+                _ if size >= root_bytes => {
+                    println!("  --root-size {}G: no shrink, root is already {}", gb, timing::format_bytes(root_bytes))
+                }
+                Some(minimum) if size < minimum => {
+                    println!("  --root-size {}G: too small, root needs at least {}", gb, timing::format_bytes(minimum))
+                }
+                _ => {
+                    let free = (last_usable + 1).saturating_sub(root_end) * SECTOR_SIZE;
+                    println!("  --root-size {}G: {} contiguous behind root", gb, timing::format_bytes(free));
+                }
+            }
+        }
+        if let Some(start) = next {
+            println!("  (a partition starts at sector {}; pack or delete it for more)", start);
+        }
+
+        print_unprivileged_note(&disk_info.device);
+        Ok(())
+    })
+}
@@ -0,0 +1,54 @@
+//! What rpi-fs-shrink and its desktop frontend (crpart-gui) share: the limits
+//! on the root size, how an SD card is recognized, and the numbering of the
+//! run's "Step N:" headings, which progress is reported against.
+
+/// Smallest and largest root filesystem a run makes, in GiB
+pub const MIN_ROOT_SIZE_GB: u64 = 8;
+pub const MAX_ROOT_SIZE_GB: u64 = 64;
+
+/// Number of the last step of a run; headings and step labels start with their number
+pub const LAST_STEP: u32 = 13;
+
+/// Whether `device` is an SD card (or eMMC), which gets no swap or /var partition
+pub fn is_sd_card(device: &str) -> bool {
+    device.contains("mmcblk")
+}
+
+/// Leading number of a step label or heading: 10 for "10b Verifying migrated
+/// data", 6 for "Step 6b: Formatting..."
+pub fn step_number(label: &str) -> Option<u32> {
+    let label = label.trim_start();
+    let label = label.strip_prefix("Step ").unwrap_or(label);
+    let digits: String = label.chars().take_while(|c| c.is_ascii_digit()).collect();
+    digits.parse().ok()
+}
+
+/// Percent of a run done at the start of step `step`, `fraction` of the way through it
+pub fn run_percent(step: u32, fraction: f64) -> f64 {
+    (step as f64 + fraction) * 100.0 / (LAST_STEP as f64 + 1.0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn step_numbers_come_from_labels_and_headings() {
+        assert_eq!(step_number("10b Verifying migrated data"), Some(10));
+        assert_eq!(step_number("  Step 6b: Formatting 3 partitions"), Some(6));
+        assert_eq!(step_number("Starting"), None);
+        assert_eq!(run_percent(0, 0.0), 0.0);
+        assert_eq!(run_percent(LAST_STEP, 1.0), 100.0);
+    }
+
+    #[test]
+    fn last_step_is_the_highest_heading_of_a_run() {
+        let highest = include_str!("main.rs")
+            .lines()
+            .filter_map(|line| line.trim_start().strip_prefix("println!(\"").map(|text| text.trim_start_matches("\\n")))
+            .filter(|text| text.starts_with("Step "))
+            .filter_map(step_number)
+            .max();
+        assert_eq!(highest, Some(LAST_STEP));
+    }
+}
@@ -0,0 +1,98 @@
+//! Mounting through libmount instead of the mount and umount commands. The
+//! library hands back the same message mount would print, without a locale
+//! or busybox in between, and a return code instead of an exit status to
+//! guess from. Without libmount the callers run the commands as before.
+
+use anyhow::{bail, Result};
+use std::ffi::{c_char, c_int, c_void, CStr, CString};
+use std::sync::OnceLock;
+
+use crate::dynlib::Library;
+
+type Context = *mut c_void;
+
+struct Api {
+    new_context: unsafe extern "C" fn() -> Context,
+    free_context: unsafe extern "C" fn(Context),
+    set_source: unsafe extern "C" fn(Context, *const c_char) -> c_int,
+    set_target: unsafe extern "C" fn(Context, *const c_char) -> c_int,
+    set_options: unsafe extern "C" fn(Context, *const c_char) -> c_int,
+    mount: unsafe extern "C" fn(Context) -> c_int,
+    umount: unsafe extern "C" fn(Context) -> c_int,
+    get_excode: unsafe extern "C" fn(Context, c_int, *mut c_char, usize) -> c_int,
+    _library: Library,
+}
+
+fn api() -> Option<&'static Api> {
+    static API: OnceLock<Option<Api>> = OnceLock::new();
+    API.get_or_init(|| {
+        let library = Library::open("libmount.so.1")?;
+        // SAFETY: the types match the declarations in libmount.h
+        unsafe {
+            Some(Api {
+                new_context: library.symbol("mnt_new_context")?,
+                free_context: library.symbol("mnt_free_context")?,
+                set_source: library.symbol("mnt_context_set_source")?,
+                set_target: library.symbol("mnt_context_set_target")?,
+                set_options: library.symbol("mnt_context_set_options")?,
+                mount: library.symbol("mnt_context_mount")?,
+                umount: library.symbol("mnt_context_umount")?,
+                get_excode: library.symbol("mnt_context_get_excode")?,
+                _library: library,
+            })
+        }
+    })
+    .as_ref()
+}
+
+enum Operation {
+    Mount,
+    Umount,
+}
+
+fn run(api: &Api, operation: Operation, source: Option<&str>, target: &str, options: Option<&str>) -> Result<()> {
+    let source = source.map(CString::new).transpose()?;
+    let target = CString::new(target)?;
+    let options = options.map(CString::new).transpose()?;
+    // SAFETY: the strings outlive the context, which copies them anyway
+    let context = unsafe { (api.new_context)() };
+    if context.is_null() {
+        bail!("Failed to set up a libmount context");
+    }
+    let mut message = [0 as c_char; 512];
+    let (rc, code) = unsafe {
+        if let Some(ref source) = source {
+            (api.set_source)(context, source.as_ptr());
+        }
+        (api.set_target)(context, target.as_ptr());
+        if let Some(ref options) = options {
+            (api.set_options)(context, options.as_ptr());
+        }
+        let rc = match operation {
+            Operation::Mount => (api.mount)(context),
+            Operation::Umount => (api.umount)(context),
+        };
+        (rc, (api.get_excode)(context, rc, message.as_mut_ptr(), message.len()))
+    };
+    unsafe { (api.free_context)(context) };
+    if rc == 0 && code == 0 {
+        return Ok(());
+    }
+    // SAFETY: get_excode NUL-terminates what it writes, and the buffer starts zeroed
+    let message = unsafe { CStr::from_ptr(message.as_ptr()) }.to_string_lossy().to_string();
+    if message.is_empty() {
+        bail!("libmount returned {} (exit code {})", rc, code);
+    }
+    bail!("{}", message)
+}
+
+/// Mount `source` at `target` (or change the mount at `target` when `source` is
+/// None) with mount(8) `options`; None when libmount isn't installed
+pub fn mount(source: Option<&str>, target: &str, options: Option<&str>) -> Option<Result<()>> {
+    Some(run(api()?, Operation::Mount, source, target, options))
+}
+
+/// Unmount `target`; None when libmount isn't installed
+pub fn umount(target: &str) -> Option<Result<()>> {
+    Some(run(api()?, Operation::Umount, None, target, None))
+}
@@ -1,32 +1,414 @@
 use anyhow::{anyhow, bail, Context, Result};
-use clap::Parser;
+use clap::{Parser, Subcommand, ValueEnum};
+use nix::mount::MsFlags;
 use regex::Regex;
+use std::collections::BTreeMap;
 use std::path::Path;
 use std::process::{Command, Stdio};
 
+use crpart::{MAX_ROOT_SIZE_GB, MIN_ROOT_SIZE_GB};
+
+mod adjust;
+mod audit;
+mod batch;
+mod bench;
+mod blkid;
+mod blockcopy;
+mod bootcheck;
+mod boottest;
+mod busybox;
+mod cleanup;
+mod cmdline;
+mod collision;
+mod configtxt;
+mod container;
+mod container_storage;
+mod copytool;
+mod databases;
+mod defrag;
+mod delta;
+mod dynlib;
+mod etcbackup;
+mod exclude;
+mod expect;
+mod findings;
+mod firmware;
+mod format;
+mod fstab;
+mod glob;
+mod headless;
+mod homemove;
+mod hooks;
+mod identity;
+mod imgexpand;
+mod imageio;
+mod imgshrink;
+mod initramfs;
+mod inspect;
+mod libmount;
+mod mbr;
+mod model;
+mod nbd;
+mod ownership;
+mod pack;
+mod parse;
+mod plandiff;
+mod platform;
+mod preview;
+mod policy;
+mod privilege;
+mod progress;
+mod prompt;
+mod recovery;
+mod references;
+mod relocate;
+mod rescue;
+mod roles;
+mod seed;
+mod selfupdate;
+mod serve;
+mod shrinkpart;
+mod simulate;
+mod smoketest;
+mod sizeexpr;
+mod stack;
+mod staging;
+mod swap;
+mod sysfs;
+mod systemd;
+mod tablecheck;
+mod template;
+mod thermal;
+mod timing;
+mod tools;
+mod tryboot;
+mod udisks;
+mod uevent;
+mod utilization;
+mod verify;
+mod visual;
+mod wear;
+mod wizard;
+
 const SECTOR_SIZE: u64 = 512;
 const ALIGNMENT: u64 = 2048; // Sector alignment boundary
-const MIN_ROOT_SIZE_GB: u64 = 8;
-const MAX_ROOT_SIZE_GB: u64 = 64;
+/// Larger erase blocks than this are taken for a bogus report
+const MAX_ERASE_BLOCK_SECTORS: u64 = 131072;
+const MIN_RECOVERY_SIZE_MB: u64 = 64;
+const DEFAULT_RECOVERY_SIZE: &str = "256M";
+const ROOT_PART_LABEL: &str = "rootfs";
 
 #[derive(Parser, Debug)]
 #[command(author, version, about = "Shrink RPi root filesystem and create partitions", long_about = None)]
+#[command(subcommand_negates_reqs = true)]
 struct Args {
+    #[command(subcommand)]
+    command: Option<Commands>,
+
     /// Root filesystem size (e.g., 8G, 16G). Min: 8G, Max: 64G
-    #[arg(short = 'r', long, value_name = "SIZE")]
-    root_size: String,
+    #[arg(short = 'r', long, value_name = "SIZE", required_unless_present = "layout")]
+    root_size: Option<String>,
 
-    /// Swap partition size (e.g., 4G, 8G). Not created on SD cards
+    /// TOML file with root, swap, var and recovery sizes (as for img-expand), with
+    /// ${DISK_SIZE}, ${RAM_SIZE} and ${SERIAL} filled in for the target; options
+    /// given on the command line win
+    #[arg(long, value_name = "FILE")]
+    layout: Option<String>,
+
+    /// Swap size (e.g., 4G, 8G): the partition or file size, or the zram size;
+    /// "auto" sizes it from the target's RAM
     #[arg(short = 's', long, value_name = "SIZE")]
     swap_size: Option<String>,
 
-    /// /var partition size (e.g., 4G, 8G). Not created on SD cards
+    /// Kind of swap to set up (default: partition with --swap-size, else none).
+    /// Partitions and files are not created on SD cards
+    #[arg(long, value_enum, value_name = "MODE")]
+    swap_mode: Option<swap::SwapMode>,
+
+    /// RAM of the target (e.g., 4G), for --swap-size auto, --hibernate and size
+    /// rules; without it the RAM of this Pi
+    #[arg(long, value_name = "SIZE")]
+    target_ram: Option<String>,
+
+    /// Set the target up to hibernate to its swap partition (which must hold all its RAM)
+    #[arg(long)]
+    hibernate: bool,
+
+    /// Build the target's initramfs in a chroot: when the target needs one
+    /// (LUKS or LVM root, --hibernate, overlay root), always or never
+    #[arg(long, value_enum, value_name = "WHEN", default_value_t = initramfs::InitramfsMode::Auto)]
+    initramfs: initramfs::InitramfsMode,
+
+    /// Raspberry Pi model of the target (e.g., pi4, zero2w, pi5, cm4) for model
+    /// defaults; without it the model of this Pi
+    #[arg(long, value_name = "MODEL")]
+    target_model: Option<String>,
+
+    /// /var partition size (e.g., 4G, 8G), or "auto" for the target model's default.
+    /// Not created on SD cards
     #[arg(short = 'v', long, value_name = "SIZE")]
     var_size: Option<String>,
 
-    /// Target device (e.g., /dev/mmcblk0, /dev/sda)
-    #[arg(short = 'd', long, value_name = "DEVICE")]
-    device: String,
+    /// Partition for Docker/Podman storage inside /var (e.g., 32G); needs --var-size
+    #[arg(long, value_name = "SIZE", requires = "var_size")]
+    container_size: Option<String>,
+
+    /// Don't copy Docker/Podman image layers and containers; they are lost and
+    /// have to be pulled and recreated on the target
+    #[arg(long)]
+    skip_container_layers: bool,
+
+    /// Recovery partition size (e.g., 256M). Placed in front of root by moving root
+    #[arg(long, value_name = "SIZE")]
+    recovery_size: Option<String>,
+
+    /// Rescue boot files (directory) or initramfs to install on the recovery partition
+    #[arg(long, value_name = "IMAGE")]
+    recovery: Option<String>,
+
+    /// cloud-init user-data to seed the target with (NoCloud)
+    #[arg(long, value_name = "FILE")]
+    seed: Option<String>,
+
+    /// cloud-init network-config to seed alongside --seed
+    #[arg(long, value_name = "FILE", requires = "seed")]
+    seed_network: Option<String>,
+
+    /// Write seed files to a dedicated CIDATA partition instead of the boot partition
+    #[arg(long, requires = "seed")]
+    seed_partition: bool,
+
+    /// Enable the SSH server on first boot
+    #[arg(long)]
+    enable_ssh: bool,
+
+    /// Public key file to install into the first user's authorized_keys
+    #[arg(long, value_name = "FILE")]
+    ssh_key: Option<String>,
+
+    /// Wi-Fi network to configure, as SSID:PSK
+    #[arg(long, value_name = "SSID:PSK")]
+    wifi: Option<String>,
+
+    /// Wi-Fi regulatory country code (e.g., GB, US)
+    #[arg(long, value_name = "CC", requires = "wifi")]
+    wifi_country: Option<String>,
+
+    /// First user to create on boot, as name:hash (hash from `openssl passwd -6`)
+    #[arg(long, value_name = "NAME:HASH")]
+    user: Option<String>,
+
+    /// Hostname for the target; {serial} and {random} are expanded per disk
+    #[arg(long, value_name = "PATTERN")]
+    hostname: Option<String>,
+
+    /// Clear machine-id and SSH host keys so they are regenerated on first boot
+    #[arg(long)]
+    reset_identity: bool,
+
+    /// Change the target's config.txt: key=value, -key or -key=value (repeatable)
+    #[arg(long = "config-txt", value_name = "SETTING")]
+    config_txt: Vec<String>,
+
+    /// Target device (e.g., /dev/mmcblk0, /dev/sda) or disk image file
+    #[arg(short = 'd', long, value_name = "DEVICE", required = true)]
+    device: Option<String>,
+
+    /// Refuse the device unless its size matches, e.g. 32G or 32G±10% (default ±10%)
+    #[arg(long, value_name = "SIZE[±N%]")]
+    expect_size: Option<String>,
+
+    /// Refuse the device unless its model contains this text (case-insensitive)
+    #[arg(long, value_name = "TEXT")]
+    expect_model: Option<String>,
+
+    /// Refuse the device unless its serial number is exactly this
+    #[arg(long, value_name = "SERIAL")]
+    expect_serial: Option<String>,
+
+    /// Refuse to create swap if the target reads slower than this (MB/s)
+    #[arg(long, value_name = "MBPS")]
+    min_swap_mbps: Option<f64>,
+
+    /// Leave matching files out of the /var and /home copies (rsync pattern, e.g.
+    /// /var/cache or /home/*/.cache); excluded directories are recreated empty
+    #[arg(long, value_name = "PATTERN")]
+    exclude: Vec<String>,
+
+    /// Extra mkfs.ext4 options for /home, e.g. "-O bigalloc -C 64k"
+    #[arg(long = "mkfs-args.home", value_name = "ARGS", allow_hyphen_values = true)]
+    mkfs_args_home: Option<String>,
+
+    /// Extra mkfs.btrfs options for /var
+    #[arg(long = "mkfs-args.var", value_name = "ARGS", allow_hyphen_values = true)]
+    mkfs_args_var: Option<String>,
+
+    /// Extra mkfs.ext4 options for the container storage partition
+    #[arg(long = "mkfs-args.containers", value_name = "ARGS", allow_hyphen_values = true)]
+    mkfs_args_containers: Option<String>,
+
+    /// Extra mkswap options for the swap partition
+    #[arg(long = "mkfs-args.swap", value_name = "ARGS", allow_hyphen_values = true)]
+    mkfs_args_swap: Option<String>,
+
+    /// Extra mkfs.vfat options for the recovery partition
+    #[arg(long = "mkfs-args.recovery", value_name = "ARGS", allow_hyphen_values = true)]
+    mkfs_args_recovery: Option<String>,
+
+    /// Extra mkfs.vfat options for the CIDATA partition
+    #[arg(long = "mkfs-args.cidata", value_name = "ARGS", allow_hyphen_values = true)]
+    mkfs_args_cidata: Option<String>,
+
+    /// What to do when another device has the UUID of a new filesystem
+    #[arg(long, value_enum, value_name = "ACTION", default_value_t = collision::OnCollision::Regenerate)]
+    on_collision: collision::OnCollision,
+
+    /// Don't discard the new partitions while formatting them (for devices whose
+    /// firmware mishandles TRIM)
+    #[arg(long)]
+    no_discard: bool,
+
+    /// Stop the copies while the host is at its hard throttle temperature or
+    /// under-volted, and resume once it recovered
+    #[arg(long)]
+    thermal_pause: bool,
+
+    /// Copy /var even when a database on it wasn't shut down cleanly
+    #[arg(long)]
+    accept_cold_databases: bool,
+
+    /// Delete the original /var and /home right away instead of keeping
+    /// them as /var.old and /home.old until the first successful boot
+    #[arg(long)]
+    purge_now: bool,
+
+    /// How /home gets to its partition: copied after root is shrunk, or moved
+    /// before, freeing root as it goes, for data that only fits the new root
+    /// size without /home
+    #[arg(long, value_enum, value_name = "STRATEGY", default_value_t = homemove::MigrateStrategy::Copy)]
+    #[arg(conflicts_with_all = ["tryboot", "recovery", "recovery_size"])]
+    migrate_strategy: homemove::MigrateStrategy,
+
+    /// Shrink root's ext4 in one resize2fs pass, or in up to four smaller
+    /// ones with e2fsck between them, for large shrinks of fragmented filesystems
+    #[arg(long, value_enum, value_name = "STRATEGY", default_value_t = staging::ShrinkStrategy::Single)]
+    shrink_strategy: staging::ShrinkStrategy,
+
+    /// Report root's fragmentation (e4defrag -c) in the plan, or report it
+    /// and defragment root with e4defrag before the shrink
+    #[arg(long, value_enum, value_name = "MODE")]
+    defrag: Option<defrag::DefragMode>,
+
+    /// Copy /var, /home and container storage with rsync, or with tar or cpio
+    /// where rsync isn't available
+    #[arg(long, value_enum, value_name = "TOOL", default_value_t = copytool::CopyBackend::Rsync)]
+    copy_backend: copytool::CopyBackend,
+
+    /// rsync binary to copy with instead of the one in $PATH
+    #[arg(long, value_name = "PATH")]
+    rsync_path: Option<String>,
+
+    /// Extra rsync options, after the ones chosen here, e.g. "--bwlimit=20M -HAX"
+    #[arg(long, value_name = "ARGS", allow_hyphen_values = true)]
+    rsync_args: Option<String>,
+
+    /// Format /var, /home and swap with the UUIDs the target's fstab already
+    /// uses, so re-runs keep existing fstab entries and backups valid
+    #[arg(long)]
+    reuse_uuids: bool,
+
+    /// Stage the new layout for a trial boot with the firmware's tryboot and
+    /// commit it only after that boot succeeds (Pi 4/5 firmware)
+    #[arg(long, conflicts_with_all = ["purge_now", "recovery", "recovery_size"])]
+    tryboot: bool,
+
+    /// Delete old data without asking (--purge-now, and .old copies left by an earlier run)
+    #[arg(long)]
+    yes: bool,
+
+    /// Skip the "Press Enter" confirmations, for CI pipelines (also RPI_FS_SHRINK_UNATTENDED=1);
+    /// deleting old data still needs --yes
+    #[arg(long, global = true)]
+    unattended: bool,
+
+    /// Compare owner, group and mode of the copied files with the originals
+    /// before fstab is updated
+    #[arg(long, value_enum, value_name = "MODE", default_value_t = ownership::OwnershipCheck::Sample)]
+    ownership_check: ownership::OwnershipCheck,
+
+    /// Hash every file before and after the operation and compare (slow)
+    #[arg(long)]
+    deep_verify: bool,
+
+    /// Also check the boot partition's FAT filesystem (read-only) after the run
+    #[arg(long)]
+    fsck_boot: bool,
+
+    /// After the migration, chroot into the target and check that its package
+    /// database, user lookups and fstab mount units work
+    #[arg(long)]
+    smoke_test: bool,
+
+    /// After a run on an image file, boot it under QEMU (Pi 3B model) and wait
+    /// for a login prompt; see the boot-test subcommand for more options
+    #[arg(long)]
+    boot_test: bool,
+
+    /// Save the target's /etc from before and after the run to the firmware
+    /// partition (config-backup-<date>-before/after.tar.gz), readable from any machine
+    #[arg(long)]
+    etc_backup: bool,
+
+    /// How new fstab entries refer to their partitions (partlabel needs GPT)
+    #[arg(long, value_enum, default_value_t = FstabRef::Uuid)]
+    fstab_ref: FstabRef,
+
+    /// Convert an MBR disk to GPT after the root resize, making room for more than
+    /// four partitions (the Pi 3 and older can't boot from GPT)
+    #[arg(long)]
+    convert_gpt: bool,
+
+    /// Maximum number of partitions formatted in parallel (default: CPU count)
+    #[arg(long, value_name = "N")]
+    jobs: Option<usize>,
+
+    /// Mount and format through UDisks2 (polkit-authorized, visible to the desktop)
+    #[arg(long, conflicts_with = "reuse_uuids")]
+    udisks: bool,
+
+    /// Run external tools from this directory first (checked against its SHA256SUMS)
+    /// and never install packages, for stations without network access
+    #[arg(long, value_name = "DIR")]
+    tools_dir: Option<String>,
+
+    /// Directory to mount target filesystems under (default: /mnt, or a temp dir in containers)
+    #[arg(long, value_name = "DIR")]
+    mount_base: Option<String>,
+
+    /// Draw the current and planned layouts as bars scaled to the disk
+    #[arg(long)]
+    visual: bool,
+
+    /// Also write the layout diagram as an SVG file
+    #[arg(long, value_name = "FILE")]
+    visual_svg: Option<String>,
+
+    /// Write the planned layout as JSON, for `diff` against other plans or the disk
+    #[arg(long, value_name = "FILE")]
+    plan_json: Option<String>,
+
+    /// Write a JSON summary of the run (layout, per-step timings, data moved) here
+    #[arg(long, value_name = "FILE")]
+    report: Option<String>,
+
+    /// Keep a JSON status file (step, percent, ETA) up to date while running;
+    /// without a path it goes to /run/rpi-fs-shrink/status.json
+    #[arg(long, value_name = "FILE", num_args = 0..=1, default_missing_value = progress::DEFAULT_STATUS_FILE)]
+    status_file: Option<String>,
+
+    /// Append every destructive step to this hash-chained audit log
+    #[arg(long, value_name = "FILE")]
+    audit_log: Option<String>,
 
     /// Dry run - show what would be done without making changes
     #[arg(long)]
@@ -35,31 +417,572 @@ struct Args {
     /// Skip inactive disk check (dangerous - allows running on active root disk)
     #[arg(long)]
     allow_active_disk: bool,
+
+    /// Finish an interrupted move even though neither the disk nor its journal has a serial or WWN to match
+    #[arg(long)]
+    resume_unidentified: bool,
+
+    /// Commands around the copies, from the layout file's [hooks] tables
+    #[arg(skip)]
+    hooks: hooks::Hooks,
+
+    /// Treat every warning as fatal
+    #[arg(long)]
+    strict: bool,
+
+    /// Turn refusals into warnings and go ahead (see the check list in the README)
+    #[arg(long, conflicts_with = "strict")]
+    lenient: bool,
+
+    /// Accept one check by its code or name (e.g., W002 or sd-card-swap), whatever the mode; repeatable
+    #[arg(long = "allow", value_name = "ID")]
+    allow: Vec<String>,
 }
 
-#[derive(Debug)]
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+enum FstabRef {
+    /// Filesystem UUID (changes whenever the partition is reformatted)
+    Uuid,
+    /// GPT partition name (survives reformatting)
+    Partlabel,
+}
+
+#[derive(Subcommand, Debug)]
+enum Commands {
+    /// Measure sequential and random 4K performance of a disk
+    Bench {
+        /// Disk to measure (e.g., /dev/mmcblk0, /dev/sda)
+        device: String,
+
+        /// Also measure writes by rewriting blocks with their own data (disk must be unmounted)
+        #[arg(long)]
+        write: bool,
+
+        /// Amount of data for the sequential tests (e.g., 256M)
+        #[arg(long, value_name = "SIZE", default_value = "256M")]
+        size: String,
+
+        /// Seconds to run each random 4K test
+        #[arg(long, value_name = "SECS", default_value_t = 5)]
+        seconds: u64,
+    },
+
+    /// Shrink a Raspberry Pi image file to the minimum size (like PiShrink)
+    ImgShrink {
+        /// Image to shrink
+        input: String,
+
+        /// Write the shrunk image here and leave the input untouched
+        output: Option<String>,
+
+        /// Grow root back to the card size on first boot
+        #[arg(long)]
+        auto_expand: bool,
+
+        /// Directory to mount the image's filesystems under
+        #[arg(long, value_name = "DIR")]
+        mount_base: Option<String>,
+    },
+
+    /// Move space between two adjacent partitions a run created (e.g., --home +20G --var -20G)
+    Adjust {
+        /// Device with the partitions (e.g., /dev/sda)
+        device: String,
+
+        /// Size change of /home (e.g., +20G or -20G)
+        #[arg(long, value_name = "DELTA", allow_hyphen_values = true)]
+        home: Option<String>,
+
+        /// Size change of /var
+        #[arg(long, value_name = "DELTA", allow_hyphen_values = true)]
+        var: Option<String>,
+
+        /// Size change of the container storage partition
+        #[arg(long, value_name = "DELTA", allow_hyphen_values = true)]
+        containers: Option<String>,
+
+        /// Show what would change without changing anything
+        #[arg(long)]
+        dry_run: bool,
+
+        /// Append every step to this hash-chained audit log
+        #[arg(long, value_name = "FILE")]
+        audit_log: Option<String>,
+
+        /// Directory to mount btrfs filesystems under for resizing
+        #[arg(long, value_name = "DIR")]
+        mount_base: Option<String>,
+
+        /// Finish an interrupted move even though neither the disk nor its journal has a serial or WWN to match
+        #[arg(long)]
+        resume_unidentified: bool,
+    },
+
+    /// Close the gaps deleted partitions left, so the free space becomes one region at the end
+    Pack {
+        /// Device to pack (e.g., /dev/sda)
+        device: String,
+
+        /// Show the moves without making them
+        #[arg(long)]
+        dry_run: bool,
+
+        /// Append every move to this hash-chained audit log
+        #[arg(long, value_name = "FILE")]
+        audit_log: Option<String>,
+
+        /// Finish an interrupted move even though neither the disk nor its journal has a serial or WWN to match
+        #[arg(long)]
+        resume_unidentified: bool,
+    },
+
+    /// Shrink one ext4 or btrfs partition anywhere on a disk, keeping its start
+    ShrinkPart {
+        /// Partition device (e.g., /dev/sda3), or where it is mounted (btrfs shrinks online)
+        partition: String,
+
+        /// New filesystem size (e.g., 20G)
+        #[arg(long, value_name = "SIZE")]
+        to: String,
+
+        /// Show what would change without changing anything
+        #[arg(long)]
+        dry_run: bool,
+
+        /// Directory to mount an unmounted btrfs under for the resize
+        #[arg(long, value_name = "DIR")]
+        mount_base: Option<String>,
+
+        /// Shrink ext4 in one resize2fs pass or in staged ones (see the main options)
+        #[arg(long, value_enum, value_name = "STRATEGY", default_value_t = staging::ShrinkStrategy::Single)]
+        shrink_strategy: staging::ShrinkStrategy,
+    },
+
+    /// Grow an image to a card size and apply a partition layout inside it
+    ImgExpand {
+        /// Image to expand in place
+        image: String,
+
+        /// Target capacity (e.g., 64G)
+        #[arg(long, value_name = "SIZE")]
+        size: String,
+
+        /// TOML file with root, swap, var and recovery sizes
+        #[arg(long, value_name = "FILE")]
+        layout: String,
+
+        /// Directory to mount the image's filesystems under
+        #[arg(long, value_name = "DIR")]
+        mount_base: Option<String>,
+    },
+
+    /// Write the blocks that differ between two images to a delta file
+    ImgDelta {
+        /// Image the targets currently hold
+        old: String,
+
+        /// Image they should end up with
+        new: String,
+
+        /// Delta file to write
+        delta: String,
+    },
+
+    /// Apply a delta file to an image or a disk holding the old image
+    ImgPatch {
+        /// Image file or disk (e.g., /dev/sdb) to update
+        target: String,
+
+        /// Delta file from img-delta
+        delta: String,
+    },
+
+    /// Show the size, partitions, model and wear of a disk
+    Inspect {
+        /// Disk to inspect (e.g., /dev/mmcblk0, /dev/sda)
+        device: String,
+    },
+
+    /// Show how much space shrinking would free, for choosing --root-size
+    Analyze {
+        /// Disk or image to analyze (e.g., /dev/sda, raspios.img)
+        device: String,
+    },
+
+    /// Run the pre-flight safety checks against a disk without changing it
+    Check {
+        /// Disk to check (e.g., /dev/mmcblk0, /dev/sda)
+        device: String,
+    },
+
+    /// Serve the inspect/check/plan/apply API over HTTP for provisioning appliances
+    Serve {
+        /// Address to listen on; `:8080` listens on every address, with the token in cleartext
+        #[arg(long, value_name = "ADDR", default_value = "127.0.0.1:8080")]
+        listen: String,
+
+        /// File holding the bearer token clients must send (default: CRPART_TOKEN)
+        #[arg(long, value_name = "FILE")]
+        token_file: Option<String>,
+    },
+
+    /// Boot an image under QEMU and wait for a login prompt, to see that it comes up
+    BootTest {
+        /// Image file to boot; it isn't changed, the boot runs on a throwaway overlay
+        image: String,
+
+        /// QEMU machine: the Pi 3B model with the image's kernel, or generic virt
+        #[arg(long, value_enum, default_value_t = boottest::Machine::Raspi3b)]
+        machine: boottest::Machine,
+
+        /// Kernel to boot instead of the image's kernel8.img (needed for virt)
+        #[arg(long, value_name = "FILE")]
+        kernel: Option<String>,
+
+        /// Device tree to use instead of the image's Pi 3B one
+        #[arg(long, value_name = "FILE")]
+        dtb: Option<String>,
+
+        /// Initramfs to boot with --kernel
+        #[arg(long, value_name = "FILE")]
+        initrd: Option<String>,
+
+        /// Console text that means the system is up (e.g., a line a marker service prints)
+        #[arg(long, value_name = "TEXT", default_value = "login:")]
+        marker: String,
+
+        /// Seconds to wait for the marker
+        #[arg(long, value_name = "SECS", default_value_t = 600)]
+        timeout: u64,
+
+        /// Save the whole console output here
+        #[arg(long, value_name = "FILE")]
+        log: Option<String>,
+
+        /// Directory for the temporary mount of the firmware partition
+        #[arg(long, value_name = "DIR")]
+        mount_base: Option<String>,
+    },
+
+    /// Describe a warning or refusal by its code (e.g., W009) or name; without one, list them all
+    Explain {
+        /// Check code or name
+        check: Option<String>,
+
+        /// Print as JSON
+        #[arg(long)]
+        json: bool,
+    },
+
+    /// Check that an audit log's hash chain is intact
+    AuditVerify {
+        /// Audit log written with --audit-log
+        log: String,
+    },
+
+    /// Remove the fstab entries a run added and enable the swap it disabled
+    FstabUndo {
+        /// fstab to restore, e.g. /mnt/root/etc/fstab
+        file: String,
+
+        /// Show what would change without writing
+        #[arg(long)]
+        dry_run: bool,
+    },
+
+    /// Show what changes between two plan files (--plan-json), or between a plan and a disk
+    Diff {
+        /// Plan file, or a disk (e.g., /dev/sda) to compare as it is now
+        old: String,
+
+        /// Plan file or disk to compare with
+        new: String,
+
+        /// Print the differences as JSON
+        #[arg(long)]
+        json: bool,
+    },
+
+    /// Check a disk against the plan file a run applied to it (--plan-json) and report drift
+    VerifyPlan {
+        /// Plan file written by the run
+        plan: String,
+
+        /// Disk the plan was applied to (e.g., /dev/sda)
+        device: String,
+
+        /// Print the differences as JSON
+        #[arg(long)]
+        json: bool,
+
+        /// Directory to mount the target's root under to read its fstab
+        #[arg(long, value_name = "DIR")]
+        mount_base: Option<String>,
+    },
+
+    /// Run on several disks at once, each with its own mounts, status file, audit log and log
+    Batch {
+        /// Disks to provision (e.g., /dev/sdb /dev/sdc /dev/sdd)
+        #[arg(required = true)]
+        devices: Vec<String>,
+
+        /// Maximum number of devices worked on at the same time
+        #[arg(long, value_name = "N", default_value_t = 2)]
+        parallel: usize,
+
+        /// Directory for each device's log, status file, audit log and report
+        #[arg(long, value_name = "DIR", default_value = "/var/log/rpi-fs-shrink/batch")]
+        log_dir: String,
+
+        /// Directory each device's filesystems are mounted under, one subdirectory per device
+        #[arg(long, value_name = "DIR")]
+        mount_base: Option<String>,
+
+        /// Options of every run, after --, e.g. -- -r 8G -v 4G
+        #[arg(last = true, allow_hyphen_values = true, required = true)]
+        args: Vec<String>,
+    },
+
+    /// Answer a few questions about the target and get a layout proposed, planned and applied
+    Wizard {
+        /// Disk or image to set up; asked for when not given
+        device: Option<String>,
+    },
+
+    /// Show what the main operation would do; same options as a run, implies --dry-run
+    Plan {
+        /// Options of the run, e.g. -d /dev/sda -r 8G -v 4G
+        #[arg(trailing_var_arg = true, allow_hyphen_values = true, required = true)]
+        args: Vec<String>,
+    },
+
+    /// Build a bootable rescue SD image with rpi-fs-shrink in its initramfs
+    MakeRescue {
+        /// Image file to write
+        #[arg(long, value_name = "FILE")]
+        out: String,
+
+        /// Boot partition files of a Raspberry Pi OS image (firmware, kernels, device trees)
+        #[arg(long, value_name = "DIR")]
+        boot_files: String,
+
+        /// Static busybox for the Pi
+        #[arg(long, value_name = "FILE")]
+        busybox: String,
+
+        /// Static rpi-fs-shrink for the Pi (default: this binary)
+        #[arg(long, value_name = "FILE")]
+        binary: Option<String>,
+
+        /// More static tools (parted, e2fsprogs, rsync, ...) with a SHA256SUMS, as for --tools-dir
+        #[arg(long, value_name = "DIR")]
+        tools_dir: Option<String>,
+
+        /// /lib/modules/<version> of the kernel, for filesystems built as modules (btrfs)
+        #[arg(long, value_name = "DIR")]
+        modules: Option<String>,
+
+        /// Directory to mount the image's partition under
+        #[arg(long, value_name = "DIR")]
+        mount_base: Option<String>,
+    },
+
+    /// Replace this binary with the latest GitHub release (checksum-verified)
+    SelfUpdate {
+        /// Only report whether a newer release exists
+        #[arg(long)]
+        check: bool,
+
+        /// Install this release tag (e.g., v0.2.0) instead of the latest
+        #[arg(long, value_name = "TAG")]
+        version: Option<String>,
+
+        /// Install even when the release isn't newer than this binary
+        #[arg(long)]
+        force: bool,
+
+        /// Also require the release's minisign signature to match this public key
+        #[arg(long, value_name = "KEY")]
+        minisign_key: Option<String>,
+    },
+}
+
+/// A block device set up for the run, released again at the end
+enum Attachment {
+    Loop(String),
+    Nbd(String),
+}
+
+impl Attachment {
+    fn device(&self) -> &str {
+        match self {
+            Attachment::Loop(device) | Attachment::Nbd(device) => device,
+        }
+    }
+
+    fn release(&self) -> Result<()> {
+        match self {
+            Attachment::Loop(device) => container::detach_loop(device),
+            Attachment::Nbd(device) => nbd::disconnect(device),
+        }
+    }
+}
+
+/// What a run opened to get at the disk: the loop or NBD attachment and root's
+/// LUKS/LVM stack. The run closes them on its way out; when it stops early with
+/// an error, dropping this closes whatever is still open.
+struct OpenDisk {
+    attachment: Option<Attachment>,
+    stack: Option<stack::RootStack>,
+}
+
+impl OpenDisk {
+    /// Remember `root_stack` as open (a plain root has nothing to close)
+    fn opened_stack(&mut self, root_stack: &stack::RootStack) {
+        self.stack = (!root_stack.is_plain()).then(|| root_stack.clone());
+    }
+
+    fn close_stack(&mut self) -> Result<()> {
+        match self.stack.take() {
+            Some(root_stack) => stack::close_root_stack(&root_stack),
+            None => Ok(()),
+        }
+    }
+
+    /// Close the stack and detach the disk
+    fn release(&mut self) -> Result<()> {
+        self.close_stack()?;
+        match self.attachment.take() {
+            Some(attachment) => attachment.release(),
+            None => Ok(()),
+        }
+    }
+}
+
+impl Drop for OpenDisk {
+    fn drop(&mut self) {
+        if self.stack.is_none() && self.attachment.is_none() {
+            return;
+        }
+        println!("\nReleasing the disk after the error...");
+        if let Err(err) = self.close_stack() {
+            eprintln!("  {:#}", err);
+        }
+        if let Some(attachment) = self.attachment.take()
+            && let Err(err) = attachment.release()
+        {
+            eprintln!("  {:#}", err);
+        }
+    }
+}
+
+/// Where the target's filesystems are mounted while they are worked on
+#[derive(Debug, Clone)]
+struct MountPaths {
+    base: String,
+}
+
+impl MountPaths {
+    fn root(&self) -> String {
+        self.path("root")
+    }
+
+    fn var(&self) -> String {
+        self.path("var")
+    }
+
+    fn home(&self) -> String {
+        self.path("home")
+    }
+
+    fn boot(&self) -> String {
+        self.path("boot")
+    }
+
+    fn path(&self, name: &str) -> String {
+        format!("{}/{}", self.base, name)
+    }
+}
+
+#[derive(Debug, Clone)]
 struct DiskInfo {
     device: String,
     size_bytes: u64,
     size_sectors: u64,
     is_sd_card: bool,
     root_partition: String,
+    /// Which partitions are boot and root (1 and 2 on Raspberry Pi OS images)
+    roles: roles::Roles,
+    /// Partition table type as parted names it ("msdos", "gpt")
+    partition_table: String,
+    /// Erase block size an SD card or eMMC reports
+    erase_block_bytes: Option<u64>,
+    /// Serial, WWN and model, which stay with the disk when its /dev name changes
+    identity: identity::DiskIdentity,
+}
+
+impl DiskInfo {
+    /// Sectors partition boundaries are aligned to: 1 MiB, or a multiple of it that
+    /// is also a whole number of erase blocks
+    fn alignment(&self) -> u64 {
+        match self.erase_block_bytes.map(|bytes| bytes / SECTOR_SIZE) {
+            Some(erase) if erase > 0 && ALIGNMENT / gcd(ALIGNMENT, erase) * erase <= MAX_ERASE_BLOCK_SECTORS => {
+                ALIGNMENT / gcd(ALIGNMENT, erase) * erase
+            }
+            _ => ALIGNMENT,
+        }
+    }
+}
+
+fn gcd(a: u64, b: u64) -> u64 {
+    if b == 0 { a } else { gcd(b, a % b) }
+}
+
+/// Requested sizes in bytes; None for partitions that aren't created
+#[derive(Debug, Clone, Default)]
+struct PartitionSizes {
+    root: u64,
+    recovery: Option<u64>,
+    swap: Option<u64>,
+    var: Option<u64>,
+    containers: Option<u64>,
+    cidata: Option<u64>,
 }
 
 #[derive(Debug)]
 struct PartitionLayout {
+    recovery_size_bytes: u64,
     root_size_bytes: u64,
     swap_size_bytes: u64,
     var_size_bytes: u64,
+    containers_size_bytes: u64,
+    cidata_size_bytes: u64,
     home_size_bytes: u64,
+    recovery_start: u64,
+    recovery_end: u64,
     root_start: u64,
     root_end: u64,
     swap_start: u64,
     swap_end: u64,
     var_start: u64,
     var_end: u64,
+    containers_start: u64,
+    containers_end: u64,
+    cidata_start: u64,
+    cidata_end: u64,
     home_start: u64,
     home_end: u64,
+    /// MBR extended partition holding everything behind root as logical partitions
+    extended_start: u64,
+    extended_end: u64,
+    /// Sectors the boundaries are aligned to
+    alignment: u64,
+}
+
+impl PartitionLayout {
+    fn logical(&self) -> bool {
+        self.extended_end > 0
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -67,118 +990,842 @@ struct CreatedPartitions {
     root_device: String,
     swap_device: Option<String>,
     var_device: Option<String>,
+    /// Container storage partition and where it is mounted on the target
+    containers: Option<(String, &'static str)>,
     home_device: String,
 }
 
 fn main() -> Result<()> {
-    let args = Args::parse();
+    let mut args = Args::parse();
+    privilege::pass_to_children()?;
+    prompt::configure(args.unattended);
+
+    // Site device policy comes first, so nothing else ever looks at a denied disk. Every
+    // command is listed, so a new one doesn't compile until it says what it works on.
+    let policy = policy::load_config()?.devices;
+    let targets: Vec<String> = match &args.command {
+        None => args.device.iter().cloned().collect(),
+        Some(
+            Commands::Bench { device, .. }
+            | Commands::Adjust { device, .. }
+            | Commands::Pack { device, .. }
+            | Commands::Inspect { device }
+            | Commands::Analyze { device }
+            | Commands::Check { device }
+            | Commands::VerifyPlan { device, .. },
+        ) => vec![device.clone()],
+        Some(Commands::ShrinkPart { partition, .. }) => shrinkpart::policy_devices(partition)?,
+        Some(Commands::ImgShrink { input, .. }) => vec![input.clone()],
+        Some(Commands::ImgExpand { image, .. } | Commands::BootTest { image, .. }) => vec![image.clone()],
+        Some(Commands::ImgPatch { target, .. }) => vec![target.clone()],
+        Some(Commands::ImgDelta { old, new, .. }) => vec![old.clone(), new.clone()],
+        Some(Commands::Batch { devices, .. }) => devices.clone(),
+        // Either side may be a disk read as it is now
+        Some(Commands::Diff { old, new, .. }) => plandiff::disk_operands(&[old, new]),
+        Some(Commands::Wizard { device }) => device.iter().cloned().collect(),
+        // Checked once its run options are parsed
+        Some(Commands::Plan { .. }) => Vec::new(),
+        // No disk: logs, configuration and build inputs, or (serve) runs that check their own
+        Some(
+            Commands::Serve { .. }
+            | Commands::Explain { .. }
+            | Commands::AuditVerify { .. }
+            | Commands::FstabUndo { .. }
+            | Commands::MakeRescue { .. }
+            | Commands::SelfUpdate { .. },
+        ) => Vec::new(),
+    };
+    // An NBD URL names no local device; it is checked once connected, as the /dev/nbdX it became
+    for target in targets.iter().filter(|target| nbd::parse_nbd_url(target).is_none()) {
+        policy::check_device(&policy, target)?;
+    }
+    // A host without Linux's tools or without access to the disk would only fail halfway through
+    if targets.is_empty() {
+        platform::check(None)?;
+    }
+    for target in &targets {
+        platform::check(Some(target))?;
+    }
+
+    match args.command.take() {
+        Some(command) => run_command(command),
+        None => run_operation(args),
+    }
+}
+
+/// The main operation, unpacking compressed images first
+fn run_operation(mut args: Args) -> Result<()> {
+    // Dry runs only read, and fall back to world-readable information without privilege
+    if !args.dry_run {
+        privilege::require("Repartitioning")?;
+    }
+    if let Some(ref dir) = args.tools_dir {
+        tools::use_tools_dir(dir)?;
+    }
+    let mut allowed = args.allow.clone();
+    if args.allow_active_disk {
+        allowed.push("active-root-disk".to_string());
+    }
+    if args.accept_cold_databases {
+        allowed.push("cold-databases".to_string());
+    }
+    findings::configure(args.strict, args.lenient, &allowed)?;
+    progress::start(args.status_file.clone())?;
+    let result = match args.device.clone().filter(|device| imageio::compression(device).is_some()) {
+        Some(image) => imageio::process_image(&image, None, !args.dry_run, |working| {
+            args.device = Some(working.to_string());
+            run(args)
+        }),
+        None => run(args),
+    };
+    progress::finish(&result);
+    result
+}
 
-    // Check if running as root
-    if !is_root() {
-        bail!("This program must be run as root");
+/// The main shrink-and-split operation on a device or image
+fn run(mut args: Args) -> Result<()> {
+    if let (Some(path), Some(device)) = (args.layout.clone(), args.device.clone()) {
+        apply_layout(&mut args, &path, &device)?;
     }
+    let model = model::detect(args.target_model.as_deref())?;
+    let (model_notes, size_rules) = match args.device.clone() {
+        Some(device) => (model::apply_defaults(&mut args, model.as_ref(), &device)?, evaluate_size_rules(&mut args, &device)?),
+        None => Default::default(),
+    };
+    let (Some(root_size_arg), Some(device_arg)) = (args.root_size.clone(), args.device.clone()) else {
+        bail!("--root-size and --device are required");
+    };
 
     println!("RPi Filesystem Shrink Tool");
     println!("==========================\n");
 
     // Display command line arguments
     println!("Command Line Arguments:");
-    println!("  Device: {}", args.device);
-    println!("  Root size: {}", args.root_size);
+    println!("  Device: {}", device_arg);
+    if let Some(ref layout) = args.layout {
+        println!("  Layout: {}", layout);
+    }
+    for hook in hooks::describe(&args.hooks) {
+        println!("  Hook: {}", hook);
+    }
+    if let Some(ref model) = model {
+        println!("  Target model: {} ({})", model.name, model.source);
+    }
+    for note in &model_notes {
+        println!("  Model default: {}", note);
+    }
+    for (name, rule, bytes) in &size_rules {
+        println!("  Size rule for {}: {} = {}", name, rule, timing::format_bytes(*bytes));
+    }
+    println!("  Root size: {}", root_size_arg);
     if let Some(ref swap) = args.swap_size {
         println!("  Swap size: {}", swap);
     } else {
         println!("  Swap size: None");
     }
+    if let Some(mode) = args.swap_mode {
+        println!("  Swap mode: {}", mode.name());
+    }
+    if let Some(ref ram) = args.target_ram {
+        println!("  Target RAM: {}", ram);
+    }
+    if args.hibernate {
+        println!("  Hibernate: yes");
+    }
     if let Some(ref var) = args.var_size {
         println!("  Var size: {}", var);
     } else {
         println!("  Var size: None");
     }
+    if let Some(ref containers) = args.container_size {
+        println!("  Container storage size: {}", containers);
+    }
+    println!("  Skip container layers: {}", args.skip_container_layers);
+    println!("  Accept cold databases: {}", args.accept_cold_databases);
+    println!("  Thermal pause: {}", args.thermal_pause);
+    println!("  No discard: {}", args.no_discard);
+    println!("  On UUID collision: {:?}", args.on_collision);
+    for (partition, value) in mkfs_arg_values(&args) {
+        println!("  mkfs args ({}): {}", partition, value);
+    }
+    if let Some(ref recovery) = args.recovery_size {
+        println!("  Recovery size: {}", recovery);
+    }
+    if let Some(ref recovery) = args.recovery {
+        println!("  Recovery image: {}", recovery);
+    }
+    if let Some(ref seed) = args.seed {
+        println!("  Seed user-data: {}", seed);
+        if let Some(ref network) = args.seed_network {
+            println!("  Seed network-config: {}", network);
+        }
+        println!("  Seed partition: {}", args.seed_partition);
+    }
+    println!("  Enable SSH: {}", args.enable_ssh);
+    if let Some(ref key) = args.ssh_key {
+        println!("  SSH key: {}", key);
+    }
+    if let Some(ref wifi) = args.wifi {
+        println!("  Wi-Fi SSID: {}", wifi.split(':').next().unwrap_or(""));
+    }
+    if let Some(ref user) = args.user {
+        println!("  User: {}", user.split(':').next().unwrap_or(""));
+    }
+    if let Some(ref hostname) = args.hostname {
+        println!("  Hostname: {}", hostname);
+    }
+    println!("  Reset identity: {}", args.reset_identity);
+    if !args.config_txt.is_empty() {
+        println!("  config.txt changes: {}", args.config_txt.join(" "));
+    }
+    for pattern in &args.exclude {
+        println!("  Exclude: {}", pattern);
+    }
+    println!("  Purge old data now: {}", args.purge_now);
+    if args.migrate_strategy == homemove::MigrateStrategy::Move {
+        println!("  Move /home before the shrink: yes");
+    }
+    if args.copy_backend != copytool::CopyBackend::Rsync {
+        println!("  Copy with: {}", args.copy_backend.name());
+    }
+    if args.shrink_strategy == staging::ShrinkStrategy::Staged {
+        println!("  Shrink root in stages: yes");
+    }
+    if let Some(mode) = args.defrag {
+        println!("  Defragment root: {}", if mode == defrag::DefragMode::Run { "yes" } else { "report only" });
+    }
+    println!("  Reuse UUIDs: {}", args.reuse_uuids);
+    println!("  Ownership check: {:?}", args.ownership_check);
+    println!("  Deep verify: {}", args.deep_verify);
+    if args.fsck_boot {
+        println!("  Check boot FAT: yes");
+    }
+    if args.etc_backup {
+        println!("  Back up /etc to the firmware partition: yes");
+    }
+    if args.boot_test {
+        println!("  Boot test under QEMU: yes");
+    }
+    println!("  fstab references: {:?}", args.fstab_ref);
+    if let Some(ref report) = args.report {
+        println!("  Report: {}", report);
+    }
+    if let Some(ref audit_log) = args.audit_log {
+        println!("  Audit log: {}", audit_log);
+    }
+    if let Some(ref status_file) = args.status_file {
+        println!("  Status file: {}", status_file);
+    }
+    if args.convert_gpt {
+        println!("  Convert to GPT: yes");
+    }
     println!("  Dry run: {}", args.dry_run);
     println!("  Allow active disk: {}", args.allow_active_disk);
-    println!("\nPress Enter to continue...");
-    let mut input = String::new();
-    std::io::stdin().read_line(&mut input)?;
+    prompt::wait_for_enter("\nPress Enter to continue...")?;
+
+    copytool::configure(args.copy_backend, args.rsync_path.as_deref(), args.rsync_args.as_deref())?;
+    staging::configure(args.shrink_strategy)?;
 
     // Check and install dependencies
-    check_dependencies(args.dry_run)?;
+    let extra_dependencies = extra_dependencies(&args);
+    // Inside a container the host's packages are not ours to install
+    let container = container::detect_container();
+    if let Some(ref kind) = container {
+        println!("Running inside a container ({}): missing tools will not be installed\n", kind);
+        container::check_passthrough(&device_arg)?;
+    }
+    // Packages can only be installed by root outside containers, and a tools directory replaces them
+    check_dependencies(
+        args.dry_run,
+        container.is_none() && privilege::is_root() && args.tools_dir.is_none(),
+        &extra_dependencies,
+    )?;
+
+    if args.udisks {
+        udisks::enable()?;
+    }
+
+    let mounts = MountPaths {
+        base: args
+            .mount_base
+            .clone()
+            .unwrap_or_else(|| container::default_mount_base(container.is_some())),
+    };
 
     // Parse sizes
-    let root_size = parse_size(&args.root_size)?;
+    let root_size = parse_size(&root_size_arg)?;
     validate_root_size(root_size)?;
 
+    let mut excludes = exclude::Excludes::new(&args.exclude)?;
     let swap_size = args.swap_size.as_ref().map(|s| parse_size(s)).transpose()?;
+    let mut swap = swap::SwapPlan::new(args.swap_mode, swap_size)?;
+    if args.hibernate {
+        let (ram, _) = swap::target_ram(args.target_ram.as_deref().map(parse_size).transpose()?, args.target_model.is_some())?;
+        swap.check_hibernate(ram)?;
+        swap.hibernate = true;
+    }
     let var_size = args.var_size.as_ref().map(|s| parse_size(s)).transpose()?;
+    let container_size = args.container_size.as_ref().map(|s| parse_size(s)).transpose()?;
+    let recovery_size = match (&args.recovery_size, &args.recovery) {
+        (Some(size), _) => Some(parse_size(size)?),
+        (None, Some(_)) => Some(parse_size(DEFAULT_RECOVERY_SIZE)?),
+        (None, None) => None,
+    };
+    if let Some(size) = recovery_size {
+        if size < MIN_RECOVERY_SIZE_MB * 1024 * 1024 {
+            bail!("Recovery partition must be at least {}M", MIN_RECOVERY_SIZE_MB);
+        }
+    }
+
+    if let Some(ref seed) = args.seed {
+        seed::validate_seed(seed, args.seed_network.as_deref())?;
+    }
+    let headless_options = headless::HeadlessOptions {
+        enable_ssh: args.enable_ssh,
+        ssh_key: args.ssh_key.clone(),
+        wifi: args.wifi.as_deref().map(headless::parse_wifi).transpose()?,
+        wifi_country: args.wifi_country.clone(),
+        user: args.user.as_deref().map(headless::parse_user).transpose()?,
+    };
+
+    let config_changes = args.config_txt.iter().map(|text| configtxt::parse_change(text)).collect::<Result<Vec<_>>>()?;
+
+    let expect_size = args.expect_size.as_deref().map(expect::parse_expect_size).transpose()?;
+
+    let cidata_size = args.seed_partition.then_some(seed::CIDATA_SIZE_MB * 1024 * 1024);
 
     // Get disk information
-    let disk_info = get_disk_info(&args.device)?;
-    println!("Disk Information:");
-    println!("  Device: {}", disk_info.device);
-    println!("  Size: {} GB ({} bytes)", disk_info.size_bytes / (1024 * 1024 * 1024), disk_info.size_bytes);
-    println!("  Is SD Card: {}", disk_info.is_sd_card);
-    println!("  Root Partition: {}\n", disk_info.root_partition);
+    // Image files (e.g. bind-mounted into a CI container) are worked on through a loop device,
+    // remote disks through an NBD connection
+    let attachment = if let Some(target) = nbd::parse_nbd_url(&device_arg) {
+        let target = target?;
+        privilege::require("Connecting an NBD export")?;
+        let device = nbd::connect(&target)?;
+        if let Err(err) = policy::load_config().and_then(|config| policy::check_device(&config.devices, &device)) {
+            let _ = nbd::disconnect(&device);
+            return Err(err);
+        }
+        Some(Attachment::Nbd(device))
+    } else if Path::new(&device_arg).is_file() {
+        privilege::require("Attaching an image file to a loop device")?;
+        Some(Attachment::Loop(container::attach_image(&device_arg)?))
+    } else {
+        None
+    };
+    let mut open_disk = OpenDisk { attachment, stack: None };
+    if args.boot_test && !matches!(open_disk.attachment, Some(Attachment::Loop(_))) {
+        bail!("--boot-test boots image files; {} is not one (boot-test a disk's image instead)", device_arg);
+    }
+    let mut disk_info = get_disk_info(open_disk.attachment.as_ref().map(Attachment::device).unwrap_or(&device_arg))?;
+    print_disk_info(&disk_info);
+    if let Some(ref model) = model {
+        model::check_boot_size(model, &disk_info);
+    }
+    println!();
+
+    // Make sure this is the disk the caller meant before looking any further
+    if let Some(ref expect_size) = expect_size {
+        expect::check_size(expect_size, &disk_info.device, disk_info.size_bytes)?;
+    }
+    if let Some(ref model) = args.expect_model {
+        expect::check_model(model, &disk_info.device)?;
+    }
+    if let Some(ref serial) = args.expect_serial {
+        expect::check_serial(serial, &disk_info.device)?;
+    }
+
+    let hostname = args
+        .hostname
+        .as_deref()
+        .map(|pattern| identity::expand_hostname(pattern, &disk_info.identity.serial))
+        .transpose()?;
+    if let Some(ref hostname) = hostname {
+        println!("Target hostname: {}\n", hostname);
+    }
+
+    // Detect LUKS/LVM layers between the root partition and its filesystem
+    let mut root_stack = stack::detect_root_stack(&disk_info.root_partition)?;
+    open_disk.opened_stack(&root_stack);
 
     // Check if disk is the active root disk
-    if !args.allow_active_disk && is_active_root_disk(&disk_info.device)? {
-        bail!(
-            "ERROR: {} appears to be the active root disk!\n\
-            This program must be run on an INACTIVE disk (e.g., from a LiveUSB).\n\
-            Use --allow-active-disk to override this check (NOT RECOMMENDED).",
-            disk_info.device
-        );
+    if is_active_root_disk(&disk_info.device)? {
+        findings::report(
+            "active-root-disk",
+            &format!(
+                "{} appears to be the active root disk!\n\
+                This program must be run on an INACTIVE disk (e.g., from a LiveUSB).\n\
+                Use --allow-active-disk to override this check (NOT RECOMMENDED).",
+                disk_info.device
+            ),
+        )?;
+    }
+    // A move an earlier run didn't finish has to be finished before anything reads the disk
+    let move_journal = relocate::lock_journal(&disk_info, args.dry_run)?;
+    relocate::resume_interrupted_move(&move_journal, &disk_info, args.dry_run, args.resume_unidentified)?;
+
+    let convert_gpt = args.convert_gpt && disk_info.partition_table == "msdos";
+    if args.convert_gpt && !convert_gpt {
+        println!("{} already uses a {} partition table, --convert-gpt has nothing to do\n", disk_info.device, disk_info.partition_table);
+    }
+    if args.fstab_ref == FstabRef::Partlabel && disk_info.partition_table != "gpt" && !convert_gpt {
+        bail!("--fstab-ref partlabel needs a GPT disk, {} uses {}", disk_info.device, disk_info.partition_table);
     }
 
     // Check SD card constraints - block swap and var on SD cards
     if disk_info.is_sd_card {
-        if swap_size.is_some() {
-            bail!("ERROR: Swap partition is not allowed on SD cards.\nSD cards have limited write cycles and swap would cause excessive wear.");
+        if swap.writes_to_disk() {
+            findings::report(
+                "sd-card-swap",
+                &format!(
+                    "Swap {} is not allowed on SD cards.\nSD cards have limited write cycles and swap would cause excessive wear.\n\
+                    Use --swap-mode zram or zswap to swap to compressed RAM instead.",
+                    swap.mode.name()
+                ),
+            )?;
         }
         if var_size.is_some() {
-            bail!("ERROR: Separate /var partition is not allowed on SD cards.\nSD cards have limited write cycles and separate /var would cause excessive wear.");
+            findings::report(
+                "sd-card-var",
+                "Separate /var partition is not allowed on SD cards.\nSD cards have limited write cycles and separate /var would cause excessive wear.",
+            )?;
+        }
+    }
+
+    if let (Some(min_mbps), true) = (args.min_swap_mbps, swap.writes_to_disk()) {
+        println!("\nChecking disk throughput for swap...");
+        // Only unprivileged dry runs get here without access to the device
+        if !privilege::can_open(&disk_info.device) {
+            println!("  Skipped: measuring {} needs read access to the device", disk_info.device);
+        } else {
+            let result = bench::run_bench(&disk_info.device, 64 * 1024 * 1024, std::time::Duration::from_secs(1), false)?;
+            println!("  Sequential read: {:.1} MB/s", result.seq_read_mbps);
+            if result.seq_read_mbps < min_mbps {
+                findings::report(
+                    "slow-swap-disk",
+                    &format!(
+                        "{} reads at {:.1} MB/s, below --min-swap-mbps {}; refusing to create swap on it",
+                        disk_info.device,
+                        result.seq_read_mbps,
+                        min_mbps
+                    ),
+                )?;
+            }
         }
     }
 
     // Calculate partition layout
-    let layout = calculate_partition_layout(
-        &disk_info,
-        root_size,
-        swap_size,
-        var_size,
-    )?;
+    let sizes = PartitionSizes {
+        root: root_size,
+        recovery: recovery_size,
+        swap: swap.partition_size(),
+        var: var_size,
+        containers: container_size,
+        cidata: cidata_size,
+    };
+    let logical = disk_info.partition_table == "msdos" && !convert_gpt && mbr::plan_logical(&disk_info, &sizes)?;
+    // The layout is planned for the table the disk ends up with
+    let planned_disk = if convert_gpt {
+        DiskInfo { partition_table: "gpt".to_string(), ..disk_info.clone() }
+    } else {
+        disk_info.clone()
+    };
+    let mut layout = calculate_partition_layout(&planned_disk, &sizes, logical)?;
+    // A logical root already sits in an extended partition, which grows instead
+    let grow_extended = logical && disk_info.roles.root >= mbr::FIRST_LOGICAL;
+    if grow_extended && let Some(extended) = mbr::existing_extended(&disk_info.device) {
+        layout.extended_start = get_partition_start(&disk_info.device, extended)?;
+    }
+    let mkfs_args = mkfs_args(&args, &layout)?;
 
     print_layout(&layout);
+    if convert_gpt {
+        println!("  Partition table: converted from MBR to GPT after the root resize; boot and root get new PARTUUIDs");
+    }
+    let mut plan = None;
+    let boot_bounds = get_partition_bounds(&disk_info.device, disk_info.roles.boot)?;
+    // Recorded with the fstab entries, so they can be traced to the plan that wrote them
+    let plan_hash = audit::sha256(plandiff::plan_json(&planned_disk, boot_bounds, &layout, &swap, &root_stack).to_string().as_bytes())?;
+    if args.visual || args.visual_svg.is_some() || args.plan_json.is_some() {
+        let boot = boot_bounds;
+        if let Some(ref path) = args.plan_json {
+            let value = plandiff::plan_json(&planned_disk, boot, &layout, &swap, &root_stack);
+            plandiff::write_plan(path, &value)?;
+            plan = Some(value);
+        }
+        if args.visual {
+            visual::print_layouts(&disk_info, boot, &layout);
+        }
+        if let Some(ref path) = args.visual_svg {
+            visual::write_svg(path, &disk_info, boot, &layout)?;
+        }
+    }
+    for (partition, extra) in &mkfs_args {
+        println!("  Extra mkfs options for {}: {}", partition, extra.join(" "));
+    }
+    if swap.mode != swap::SwapMode::Partition {
+        println!("  Swap: {}", swap.describe());
+    }
+    if swap.mode == swap::SwapMode::Zswap {
+        println!("    zswap compresses pages on their way to swap; the target needs a swap file or partition of its own");
+    }
+
+    let (existing_swap, container_stores, databases) = if args.dry_run && !privilege::can_open(&root_stack.fs_device) {
+        println!("\nSkipped looking for existing swap, container storage and databases: mounting {} needs privilege", root_stack.fs_device);
+        (Vec::new(), Vec::new(), Vec::new())
+    } else {
+        with_root_read_only(&root_stack.fs_device, &mounts, |root| {
+            Ok((swap::detect_existing(root, &disk_info.device)?, container_storage::detect(root)?, databases::detect(root)?))
+        })?
+    };
+    swap::print_existing(&existing_swap, &swap)?;
+
+    // Only a separate /var copies the databases; otherwise their files stay where they are
+    databases::print_databases(&databases, var_size.is_some());
+    let unclean = databases::unclean(&databases);
+    if var_size.is_some() && !unclean.is_empty() {
+        findings::report(
+            "cold-databases",
+            &format!(
+                "{} {} not shut down cleanly; copying {} now takes along whatever the crash left.\n\
+                Start the system once and shut it down cleanly first, or use --accept-cold-databases\n\
+                and run the checks listed above after the first boot.",
+                unclean.iter().map(|database| database.name).collect::<Vec<_>>().join(", "),
+                if unclean.len() == 1 { "was" } else { "were" },
+                if unclean.len() == 1 { "it" } else { "them" }
+            ),
+        )?;
+    }
+
+    // The /var copy leaves out what goes to the container partition; its mountpoint is recreated empty
+    let containers_target = container_size.map(|_| container_storage::partition_target(&container_stores));
+    container_storage::print_stores(&container_stores, containers_target, args.skip_container_layers);
+    if let (Some(target), true) = (containers_target, container_stores.is_empty()) {
+        println!("\nNo container storage found; the container partition is mounted at {} for later use", target);
+    }
+    if let Some(target) = containers_target {
+        excludes.add(target.to_string());
+    }
+    if args.skip_container_layers {
+        for store in &container_stores {
+            for pattern in store.layer_patterns() {
+                excludes.add(pattern);
+            }
+        }
+    }
+    swap.disabled = swap::disabled_sources(&existing_swap, &swap);
+
+    let reused_uuids = if args.reuse_uuids && args.dry_run && !privilege::can_open(&root_stack.fs_device) {
+        println!("\nSkipped reading previous UUIDs: mounting {} needs privilege", root_stack.fs_device);
+        std::collections::HashMap::new()
+    } else if args.reuse_uuids {
+        println!("\nReading previous filesystem UUIDs from the target's fstab...");
+        let uuids = with_root_read_only(&root_stack.fs_device, &mounts, previous_uuids)?;
+        for (mount, uuid) in &uuids {
+            println!("  {}: UUID={}", mount, uuid);
+        }
+        if uuids.is_empty() {
+            println!("  None found; new UUIDs will be generated");
+        }
+        uuids
+    } else {
+        std::collections::HashMap::new()
+    };
+
+    let move_home = args.migrate_strategy == homemove::MigrateStrategy::Move;
+    if move_home {
+        if !root_stack.is_plain() {
+            bail!("--migrate-strategy move needs ext4 directly on the root partition, not under LVM or LUKS");
+        }
+        let interim_end = homemove::interim_root_end(layout.root_start, layout.home_start);
+        println!(
+            "\n/home moves before the shrink: root first shrinks to {} (sectors {} - {}), /home moves into sectors {} - {}",
+            timing::format_bytes((interim_end - layout.root_start + 1) * SECTOR_SIZE),
+            layout.root_start,
+            interim_end,
+            layout.home_start,
+            layout.home_end
+        );
+    }
+
+    let stack_sizes = if root_stack.is_plain() {
+        None
+    } else {
+        let sizes = stack::plan_stack_sizes(&root_stack, layout.root_size_bytes)?;
+        println!();
+        stack::print_stack(&root_stack, &sizes);
+        Some(sizes)
+    };
+
+    // Moving /home first leaves less on root; that is checked once it has moved
+    let root_fs_bytes = stack_sizes.as_ref().map_or(layout.root_size_bytes, |sizes| sizes.fs_bytes);
+    if !move_home {
+        if args.dry_run && !privilege::can_open(&root_stack.fs_device) {
+            println!("\nSkipped checking root's minimum size: reading {} needs privilege", root_stack.fs_device);
+        } else {
+            println!("\nChecking that root's data fits in {}...", timing::format_bytes(root_fs_bytes));
+            check_root_fits(&root_stack.fs_device, root_fs_bytes)?;
+        }
+    }
+
+    if args.defrag.is_some() {
+        if args.dry_run && !privilege::can_open(&root_stack.fs_device) {
+            println!("\nSkipped the fragmentation report: mounting {} needs privilege", root_stack.fs_device);
+        } else {
+            println!("\nFragmentation of root (e4defrag -c)...");
+            with_root_read_only(&root_stack.fs_device, &mounts, defrag::report)?.print();
+        }
+    }
 
     if args.dry_run {
+        let boot = get_partition_bounds(&disk_info.device, disk_info.roles.boot)?;
+        println!("\nResulting partition table (sfdisk format):");
+        print!("{}", simulate::sfdisk_dump(&planned_disk, boot, &layout));
+        preview::print_boot_edits(
+            &planned_disk,
+            &root_stack,
+            &layout,
+            &swap,
+            &reused_uuids,
+            args.fstab_ref,
+            &mounts,
+        )?;
+
+        open_disk.release()?;
         println!("\n=== DRY RUN MODE - No changes will be made ===");
         return Ok(());
     }
 
+    let audit = match args.audit_log {
+        Some(ref path) => audit::AuditLog::open(path, &disk_info.device, &disk_info.identity)?,
+        None => audit::AuditLog::disabled(),
+    };
+
     // Confirm with user
     println!("\nWARNING: This will modify your disk partitions!");
-    println!("Press Enter to continue or Ctrl+C to cancel...");
-    let mut input = String::new();
-    std::io::stdin().read_line(&mut input)?;
+    prompt::wait_for_enter("Press Enter to continue or Ctrl+C to cancel...")?;
+    audit.record(
+        "start",
+        &format!(
+            "root {} swap {} var {} containers {} recovery {} home {} bytes",
+            layout.root_size_bytes,
+            layout.swap_size_bytes,
+            layout.var_size_bytes,
+            layout.containers_size_bytes,
+            layout.recovery_size_bytes,
+            layout.home_size_bytes
+        ),
+    )?;
+
+    thermal::print_host_state();
 
     // Perform the operations
     println!("\n=== Starting partition operations ===\n");
+    let mut timings = timing::StepTimings::new();
+    let _thermal = thermal::start(args.thermal_pause);
+
+    // Before the old swap is removed, which already edits /etc
+    let etc_backup = if args.etc_backup {
+        println!("Saving the target's /etc...");
+        Some(with_root_read_only(&root_stack.fs_device, &mounts, etcbackup::save_before)?)
+    } else {
+        None
+    };
+
+    // Boot and root keep their types and flags; the check after Step 6 compares against these
+    let table_before = tablecheck::read_table(&disk_info.device)?;
+
+    // Old swap goes first: a deleted swap file is neither shrunk around nor copied with /var
+    if existing_swap.iter().any(|existing| existing.is_removed(&swap)) {
+        println!("Step 0: Removing the old swap...");
+        timings.begin("0 Removing the old swap");
+        let removed: Vec<String> =
+            existing_swap.iter().filter(|existing| existing.is_removed(&swap)).map(swap::ExistingSwap::describe).collect();
+        audit.record("remove-swap", &removed.join("; "))?;
+        mount_at(&root_stack.fs_device, &mounts.root())?;
+        let result = swap::remove_existing_files(&existing_swap, &swap, &mounts.root());
+        unmount(&mounts.root())?;
+        result?;
+        swap::remove_partitions(&existing_swap, &disk_info.device)?;
+        println!();
+    }
+
+    // Nothing but the run's own edits may change on the boot partition
+    println!("Step 0a: Recording the boot partition...");
+    timings.begin("0a Recording the boot partition");
+    let boot_device = get_partition_device(&disk_info.device, disk_info.roles.boot)?;
+    let boot_before = bootcheck::snapshot(&boot_device, &mounts)?;
+    println!("  {} files hashed on {}\n", boot_before.len(), boot_device);
+
+    // Fewer, larger extents for resize2fs to move; e4defrag works on a mounted filesystem
+    if args.defrag == Some(defrag::DefragMode::Run) {
+        println!("Step 0b: Defragmenting root...");
+        timings.begin("0b Defragmenting root");
+        audit.record("defragment", &root_stack.fs_device)?;
+        mount_at(&root_stack.fs_device, &mounts.root())?;
+        let result = defrag::run(&mounts.root());
+        unmount(&mounts.root())?;
+        result?;
+        println!();
+    }
 
     // Step 1: Unmount root filesystem (if possible)
     println!("Step 1: Checking filesystem...");
-    check_filesystem(&disk_info.root_partition)?;
+    timings.begin("1 Checking filesystem");
+    check_filesystem(&root_stack.fs_device)?;
+
+    let manifest_before = if args.deep_verify {
+        println!("\nStep 1b: Hashing all files on root (deep verify)...");
+        timings.begin("1b Hashing all files on root (deep verify)");
+        let manifest = root_manifest(&root_stack.fs_device, &mounts)?;
+        println!("  {} files hashed", manifest.len());
+        Some(manifest)
+    } else {
+        None
+    };
 
-    // Step 2: Shrink root filesystem
+    // Read before anything recreates the root partition, which gives GPT a new PARTUUID
+    let old_root_partuuid = relocate::get_partuuid(&disk_info.root_partition)?;
+    let boot_partition = get_partition_device(&disk_info.device, disk_info.roles.boot)?;
+    let old_boot_partuuid = relocate::get_partuuid(&boot_partition)?;
+
+    let moved_home = if move_home {
+        println!("\nStep 1c: Moving /home into its partition's region...");
+        timings.begin("1c Moving /home into its partition's region");
+        let (_, root_end) = get_partition_bounds(&disk_info.device, disk_info.roles.root)?;
+        if root_end >= layout.home_start {
+            let interim_end = homemove::interim_root_end(layout.root_start, layout.home_start);
+            let interim_bytes = (interim_end - layout.root_start + 1) * SECTOR_SIZE;
+            let minimum = shrinkpart::ext_minimum(&root_stack.fs_device)?;
+            if minimum.bytes() > interim_bytes {
+                bail!(
+                    "Root needs {} ({}) even with /home still on it, but only {} is free before /home's region; \
+                    make the partitions between root and /home larger, or free space on root",
+                    timing::format_bytes(minimum.bytes()),
+                    minimum.breakdown(),
+                    timing::format_bytes(interim_bytes)
+                );
+            }
+            audit.record("shrink-filesystem", &format!("{} to {} bytes to free /home's region", root_stack.fs_device, interim_bytes))?;
+            shrink_root_filesystem(&root_stack.fs_device, interim_bytes)?;
+            audit.record("resize-partition", &format!("{} to sectors {}-{}", disk_info.root_partition, layout.root_start, interim_end))?;
+            resize_root_partition(&disk_info, layout.root_start, interim_end)?;
+        } else {
+            println!("  Root already ends before /home's region (an earlier run shrank it)");
+        }
+        audit.record("move", &format!("/home to sectors {}-{}", layout.home_start, layout.home_end))?;
+        let options = homemove::MoveOptions {
+            disk: &disk_info.device,
+            identity: &disk_info.identity,
+            root_device: &root_stack.fs_device,
+            home_start: layout.home_start,
+            home_sectors: layout.home_end - layout.home_start + 1,
+            uuid: reused_uuids.get("/home").map(String::as_str),
+            mkfs_args: mkfs_args.get("home"),
+            excludes: &excludes,
+            hooks: &args.hooks,
+        };
+        let moved = homemove::move_home(&options, &mounts)?;
+        timings.add_bytes(moved.bytes);
+        println!("  {} entries of /home moved ({})", moved.entries, timing::format_bytes(moved.bytes));
+        // resize2fs wants a check after root was mounted read-write
+        check_filesystem(&root_stack.fs_device)?;
+        Some(moved)
+    } else {
+        None
+    };
+
+    // Step 2: Shrink root filesystem (and any LVM/LUKS layers below it)
     println!("\nStep 2: Shrinking root filesystem to {} bytes...", layout.root_size_bytes);
-    shrink_root_filesystem(&disk_info.root_partition, layout.root_size_bytes)?;
+    timings.begin("2 Shrink root filesystem");
+    // Counted again now that the check has run (and /home may have moved off root)
+    check_root_fits(&root_stack.fs_device, root_fs_bytes)?;
+    audit.record("shrink-filesystem", &format!("{} to {} bytes", root_stack.fs_device, layout.root_size_bytes))?;
+    match stack_sizes {
+        Some(ref sizes) => stack::shrink_root_stack(&root_stack, sizes)?,
+        None => shrink_root_filesystem(&root_stack.fs_device, layout.root_size_bytes)?,
+    }
 
-    // Step 3: Resize root partition
-    println!("\nStep 3: Resizing root partition...");
-    resize_root_partition(&disk_info, layout.root_end)?;
+    // Step 3: Resize root partition, moving it first when a recovery partition goes in front
+    resize_root_step(&disk_info, &layout, &root_stack, &mut open_disk, &move_journal, &audit, &mut timings)?;
+
+    // Only now is the end of the disk free for the backup GPT
+    if convert_gpt {
+        println!("\nStep 3a: Converting the partition table to GPT...");
+        timings.begin("3a Converting the partition table to GPT");
+        audit.record("convert-gpt", &disk_info.device)?;
+        mbr::convert_to_gpt(&disk_info.device)?;
+        disk_info.partition_table = "gpt".to_string();
+        set_partition_name(&disk_info.device, disk_info.roles.root, ROOT_PART_LABEL)?;
+    }
+
+    let recovery_device = if layout.recovery_size_bytes > 0 {
+        if !root_stack.is_plain() {
+            root_stack = stack::detect_root_stack(&disk_info.root_partition)?;
+            open_disk.opened_stack(&root_stack);
+        }
+        println!("\nStep 3b: Creating recovery partition...");
+        timings.begin("3b Creating recovery partition");
+        Some(create_partition(&disk_info, &audit, "recovery", "primary", "fat32", layout.recovery_start, layout.recovery_end)?)
+    } else {
+        None
+    };
+
+    if let Some(ref before) = manifest_before {
+        println!("\nStep 3d: Verifying root contents after resize (deep verify)...");
+        timings.begin("3d Verifying root contents after resize (deep verify)");
+        let after = root_manifest(&root_stack.fs_device, &mounts)?;
+        if moved_home.is_some() {
+            // /home left root in Step 1c; it is checked on its own partition in Step 10b
+            let (before, _) = homemove::split_manifest(before);
+            let (after, _) = homemove::split_manifest(&after);
+            verify::check_manifests("Root filesystem", &before, &after)?;
+        } else {
+            verify::check_manifests("Root filesystem", before, &after)?;
+        }
+    }
+
+    // Recreating the root entry can change its PARTUUID on GPT disks
+    let mut partuuid_changes = Vec::new();
+    let new_root_partuuid = relocate::get_partuuid(&disk_info.root_partition)?;
+    if !old_root_partuuid.is_empty() && old_root_partuuid != new_root_partuuid {
+        partuuid_changes.push((old_root_partuuid, new_root_partuuid));
+    }
+    // Converting to GPT changes the boot PARTUUID as well, which fstab refers to
+    let new_boot_partuuid = relocate::get_partuuid(&boot_partition)?;
+    if !old_boot_partuuid.is_empty() && old_boot_partuuid != new_boot_partuuid {
+        partuuid_changes.push((old_boot_partuuid, new_boot_partuuid));
+    }
+    // Files on the boot partition rewritten for them, which its check at the end expects
+    let mut boot_references = Vec::new();
+    if !partuuid_changes.is_empty() {
+        println!("\nUpdating boot partition references to changed PARTUUIDs...");
+        let boot_device = get_partition_device(&disk_info.device, disk_info.roles.boot)?;
+        let changes: Vec<String> = partuuid_changes.iter().map(|(old, new)| format!("PARTUUID {} to {}", old, new)).collect();
+        audit.record("write-boot-references", &changes.join("; "))?;
+        boot_references = references::update_boot_references(&boot_device, &partuuid_changes, &mounts)?;
+        references::print_references(&boot_references);
+    }
+
+    // The extended partition fills the space behind root, so it comes after recovery takes its primary entry
+    if layout.logical() {
+        println!("\nStep 3e: Preparing the extended partition...");
+        timings.begin("3e Preparing the extended partition");
+        audit.record("extended-partition", &format!("sectors {}-{}", layout.extended_start, layout.extended_end))?;
+        if grow_extended {
+            mbr::grow_extended(&disk_info.device, layout.extended_end)?;
+        } else {
+            mbr::create_extended(&disk_info.device, layout.extended_start, layout.extended_end)?;
+        }
+    }
+
+    // Everything behind root is logical when it goes into an extended partition
+    let kind = if layout.logical() { "logical" } else { "primary" };
 
     // Step 4: Create swap partition (if requested)
     let swap_device = if layout.swap_size_bytes > 0 {
         println!("\nStep 4: Creating swap partition...");
-        Some(create_swap_partition(&disk_info, layout.swap_start, layout.swap_end)?)
+        timings.begin("4 Creating swap partition");
+        Some(create_partition(&disk_info, &audit, "swap", kind, "linux-swap", layout.swap_start, layout.swap_end)?)
     } else {
         None
     };
@@ -186,56 +1833,855 @@ fn main() -> Result<()> {
     // Step 5: Create /var partition (if requested)
     let var_device = if layout.var_size_bytes > 0 {
         println!("\nStep 5: Creating /var partition...");
-        Some(create_var_partition(&disk_info, layout.var_start, layout.var_end)?)
+        timings.begin("5 Creating /var partition");
+        Some(create_partition(&disk_info, &audit, "/var", kind, "btrfs", layout.var_start, layout.var_end)?)
+    } else {
+        None
+    };
+
+    // Step 5a: Create the container storage partition (if requested)
+    let containers_device = if layout.containers_size_bytes > 0 {
+        println!("\nStep 5a: Creating container storage partition...");
+        timings.begin("5a Creating container storage partition");
+        Some(create_partition(&disk_info, &audit, "containers", kind, "ext4", layout.containers_start, layout.containers_end)?)
+    } else {
+        None
+    };
+
+    // Step 5b: Create CIDATA seed partition (if requested)
+    let cidata_device = if layout.cidata_size_bytes > 0 {
+        println!("\nStep 5b: Creating CIDATA partition...");
+        timings.begin("5b Creating CIDATA partition");
+        Some(create_partition(&disk_info, &audit, "CIDATA", kind, "fat16", layout.cidata_start, layout.cidata_end)?)
     } else {
         None
     };
 
     // Step 6: Create /home partition
     println!("\nStep 6: Creating /home partition...");
-    let home_device = create_home_partition(&disk_info, layout.home_start, layout.home_end)?;
+    timings.begin("6 Creating /home partition");
+    let home_device = create_partition(&disk_info, &audit, "/home", kind, "ext4", layout.home_start, layout.home_end)?;
+    if let Some(ref moved) = moved_home {
+        let uuid = get_uuid(&home_device)?;
+        if uuid != moved.uuid {
+            bail!("{} shows UUID {}, not the /home filesystem moved in Step 1c ({})", home_device, uuid, moved.uuid);
+        }
+        println!("  {} holds the moved /home", home_device);
+    }
+
+    println!("\nStep 6a: Verifying the partition table...");
+    timings.begin("6a Verifying the partition table");
+    let boot_bounds = get_partition_bounds(&disk_info.device, disk_info.roles.boot)?;
+    tablecheck::verify(&disk_info, boot_bounds, &layout, &table_before)?;
+
+    // Step 6b: Format the new partitions; they are independent so run them side by side
+    let mut format_jobs = Vec::new();
+    if let Some(ref device) = recovery_device {
+        // FAT32 so the firmware can boot from it
+        format_jobs.push(format::FormatJob::vfat("recovery", device, "RECOVERY", true).with_extra_args(mkfs_args.get("recovery")));
+    }
+    if let Some(ref device) = swap_device {
+        format_jobs.push(
+            format::FormatJob::swap(device)
+                .with_uuid(reused_uuids.get("swap").map(String::as_str))
+                .with_extra_args(mkfs_args.get("swap")),
+        );
+    }
+    if let Some(ref device) = var_device {
+        format_jobs.push(
+            format::FormatJob::btrfs("/var", device)
+                .with_uuid(reused_uuids.get("/var").map(String::as_str))
+                .with_extra_args(mkfs_args.get("var")),
+        );
+    }
+    if let Some(ref device) = containers_device {
+        format_jobs.push(format::FormatJob::ext4("containers", device).with_extra_args(mkfs_args.get("containers")));
+    }
+    if let Some(ref device) = cidata_device {
+        // cloud-init finds the NoCloud datasource by this volume label
+        format_jobs.push(format::FormatJob::vfat("CIDATA", device, "CIDATA", false).with_extra_args(mkfs_args.get("cidata")));
+    }
+    if moved_home.is_none() {
+        format_jobs.push(
+            format::FormatJob::ext4("/home", &home_device)
+                .with_uuid(reused_uuids.get("/home").map(String::as_str))
+                .with_extra_args(mkfs_args.get("home")),
+        );
+    }
+
+    let jobs = args
+        .jobs
+        .unwrap_or_else(|| std::thread::available_parallelism().map(|n| n.get()).unwrap_or(1));
+    println!("\nStep 6b: Formatting {} partitions ({} at a time)...", format_jobs.len(), jobs.min(format_jobs.len()));
+    timings.begin("6b Format partitions");
+    let discard = !args.no_discard && sysfs::discard_supported(&disk_info.device);
+    println!("  {}", if discard { "Discarding the new partitions while formatting" } else { "Formatting without discard" });
+    format_jobs = format_jobs.into_iter().map(|job| job.with_discard(discard)).collect();
+    if udisks::enabled() {
+        format_jobs = format_jobs.into_iter().map(format::FormatJob::via_udisks).collect::<Result<_>>()?;
+    }
+    for job in &format_jobs {
+        audit.record("format", &format!("{} ({})", job.device, job.fstype))?;
+    }
+    format::run_format_jobs(&format_jobs, jobs)?;
+    println!("  Checking other block devices for the same UUIDs and labels...");
+    let boot_device = get_partition_device(&disk_info.device, disk_info.roles.boot)?;
+    let existing = [("boot", boot_device.as_str()), ("root", disk_info.root_partition.as_str())];
+    let redo = collision::check(&format_jobs, &existing, args.on_collision)?;
+    if !redo.is_empty() {
+        // Without -U the formatters pick a random UUID
+        for job in &redo {
+            audit.record("format", &format!("{} ({}) for a new UUID", job.device, job.fstype))?;
+        }
+        format::run_format_jobs(&redo, jobs)?;
+        collision::confirm_regenerated(&redo)?;
+    }
+
+    if let (Some(device), Some(image)) = (&recovery_device, &args.recovery) {
+        println!("\nStep 6c: Populating recovery partition...");
+        timings.begin("6c Populating recovery partition");
+        let boot_device = get_partition_device(&disk_info.device, disk_info.roles.boot)?;
+        recovery::populate_recovery(device, &boot_device, image, &mounts)?;
+    }
 
     let created_partitions = CreatedPartitions {
-        root_device: disk_info.root_partition.clone(),
+        root_device: root_stack.fs_device.clone(),
         swap_device,
         var_device: var_device.clone(),
+        containers: containers_device.clone().zip(containers_target),
         home_device: home_device.clone(),
     };
 
     println!("\n=== Partitions created successfully! ===");
+    if let Some(ref device) = recovery_device {
+        println!("Recovery partition: {}", device);
+    }
 
     // Step 7: Migrate data and update fstab (always enabled)
     println!("\n=== Starting data migration ===\n");
 
     println!("Step 7: Creating mount points...");
-    create_mount_points()?;
+    timings.begin("7 Creating mount points");
+    create_mount_points(&mounts)?;
 
     println!("\nStep 8: Mounting partitions...");
-    mount_partitions(&created_partitions)?;
+    timings.begin("8 Mounting partitions");
+    mount_partitions(&created_partitions, &mounts)?;
 
     if var_device.is_some() {
         println!("\nStep 9: Migrating /var data...");
-        migrate_var_data()?;
+        timings.begin("9 Migrating /var data");
+        audit.record("migrate", "/var")?;
+        let source = format!("{}/var", mounts.root());
+        hooks::run(&args.hooks, "var", "pre", &mounts.root(), &source, &mounts.var())?;
+        let bytes = migrate_var_data(&mounts, &excludes)?;
+        timings.add_bytes(bytes);
+        hooks::run(&args.hooks, "var", "post", &mounts.root(), &source, &mounts.var())?;
+    }
+
+    if let Some((_, target)) = created_partitions.containers {
+        println!("\nStep 9a: Migrating container storage...");
+        timings.begin("9a Migrating container storage");
+        audit.record("migrate", target)?;
+        let source = format!("{}{}", mounts.root(), target);
+        hooks::run(&args.hooks, "containers", "pre", &mounts.root(), &source, &mounts.path("containers"))?;
+        let bytes = migrate_container_storage(&mounts, target, &excludes)?;
+        timings.add_bytes(bytes);
+        hooks::run(&args.hooks, "containers", "post", &mounts.root(), &source, &mounts.path("containers"))?;
+    }
+
+    if moved_home.is_none() {
+        println!("\nStep 10: Migrating /home data...");
+        timings.begin("10 Migrating /home data");
+        audit.record("migrate", "/home")?;
+        let source = format!("{}/home", mounts.root());
+        hooks::run(&args.hooks, "home", "pre", &mounts.root(), &source, &mounts.home())?;
+        let bytes = migrate_home_data(&mounts, &excludes)?;
+        timings.add_bytes(bytes);
+        hooks::run(&args.hooks, "home", "post", &mounts.root(), &source, &mounts.home())?;
+    }
+
+    if args.ownership_check != ownership::OwnershipCheck::Off {
+        println!("\nStep 10a: Checking ownership and permissions of the copies...");
+        timings.begin("10a Checking ownership and permissions of the copies");
+        let mut checked = 0;
+        let mut problems = Vec::new();
+        if var_device.is_some() {
+            let source = format!("{}/var", mounts.root());
+            problems.extend(ownership::compare_tree("var", &source, &mounts.var(), &excludes, args.ownership_check, &mut checked)?);
+            problems.extend(ownership::check_var_log(&mounts.root(), &mounts.var())?);
+        }
+        if let Some((_, target)) = created_partitions.containers {
+            let source = format!("{}{}", mounts.root(), target);
+            let tree = target.trim_start_matches('/');
+            let dest = mounts.path("containers");
+            problems.extend(ownership::compare_tree(tree, &source, &dest, &excludes, args.ownership_check, &mut checked)?);
+        }
+        // A moved /home left nothing on root to compare with
+        if moved_home.is_none() {
+            let source = format!("{}/home", mounts.root());
+            problems.extend(ownership::compare_tree("home", &source, &mounts.home(), &excludes, args.ownership_check, &mut checked)?);
+        }
+        problems.extend(ownership::check_home_owners(&mounts.root(), &mounts.home())?);
+
+        println!("  {} entries compared", checked);
+        if !problems.is_empty() {
+            for problem in problems.iter().take(50) {
+                println!("  {}", problem);
+            }
+            if problems.len() > 50 {
+                println!("  ... and {} more", problems.len() - 50);
+            }
+            let message = format!(
+                "{} ownership or permission problems in the copies; fstab was not updated, so the disk still boots \
+                with its original layout. Use --ownership-check off to go ahead anyway",
+                problems.len()
+            );
+            if let Err(err) = findings::report("ownership-mismatch", &message) {
+                unmount_all(&mounts)?;
+                return Err(err);
+            }
+        } else {
+            println!("  Owners and modes match");
+        }
+    }
+
+    if let Some(ref before) = manifest_before {
+        println!("\nStep 10b: Verifying migrated data (deep verify)...");
+        timings.begin("10b Verifying migrated data (deep verify)");
+        if var_device.is_some() {
+            let after = verify::build_manifest(&mounts.var())?;
+            verify::check_manifests("/var", &excludes.filter_manifest("var", &verify::subtree(before, "var")), &after)?;
+        }
+        if let Some((_, target)) = created_partitions.containers {
+            let tree = target.trim_start_matches('/');
+            let after = verify::build_manifest(&mounts.path("containers"))?;
+            verify::check_manifests(target, &excludes.filter_manifest(tree, &verify::subtree(before, tree)), &after)?;
+        }
+        let home_before = if moved_home.is_some() { homemove::split_manifest(before).1 } else { Some(verify::subtree(before, "home")) };
+        match home_before {
+            Some(home_before) => {
+                let after = verify::build_manifest(&mounts.home())?;
+                verify::check_manifests("/home", &excludes.filter_manifest("home", &home_before), &after)?;
+            }
+            None => println!("  /home: not verified; an interrupted earlier run had moved part of it before Step 1b"),
+        }
+    }
+
+    let mut migrated = Vec::new();
+    if var_device.is_some() {
+        migrated.push("var");
+    }
+    migrated.push("home");
+
+    // Purging deletes the originals now; otherwise only .old copies left by an earlier run go.
+    // A tryboot trial needs the originals for falling back, and its commit removes them.
+    let to_delete: Vec<String> = migrated
+        .iter()
+        .filter(|_| !args.tryboot)
+        .map(|name| {
+            if args.purge_now {
+                format!("{}/{}", mounts.root(), name)
+            } else {
+                format!("{}/{}.old", mounts.root(), name)
+            }
+        })
+        .collect();
+    if !cleanup::confirm_deletion(&to_delete, args.yes)? {
+        unmount_all(&mounts)?;
+        bail!(
+            "Deletion declined; nothing was deleted. The data was copied to the new partitions, \
+            but fstab was not updated, so the disk still boots with its original layout"
+        );
+    }
+
+    // Root stays read-only until every copy has succeeded (and verified, if asked)
+    println!("\nStep 10c: Remounting root read-write...");
+    timings.begin("10c Remounting root read-write");
+    remount(&mounts.root(), "rw")?;
+    if moved_home.is_some() {
+        // The /home partition is in the table since Step 6, so a rerun no longer resumes the move
+        homemove::remove_journal(&mounts.root())?;
+    }
+
+    if args.tryboot {
+        println!("\nStep 10d: Keeping original data in place for the trial boot...");
+        timings.begin("10d Keeping original data in place for the trial boot");
+    } else if args.purge_now {
+        println!("\nStep 10d: Removing migrated data from root...");
+        timings.begin("10d Removing migrated data from root");
+        for name in &migrated {
+            audit.record("delete", &format!("/{} on root", name))?;
+            cleanup::remove_contents(&format!("{}/{}", mounts.root(), name))?;
+        }
+    } else {
+        println!("\nStep 10d: Keeping original data until first boot...");
+        timings.begin("10d Keeping original data until first boot");
+        let mut retired = Vec::new();
+        for name in &migrated {
+            if cleanup::retire_directory(&mounts.root(), name)? {
+                audit.record("retire", &format!("/{} on root to /{}.old", name, name))?;
+                retired.push(*name);
+            }
+        }
+        if !retired.is_empty() {
+            cleanup::install_cleanup_unit(&mounts.root(), &retired)?;
+        }
+    }
+
+    println!("\nStep 10e: Recreating mountpoints on root...");
+    timings.begin("10e Recreating mountpoints on root");
+    for name in &migrated {
+        cleanup::recreate_skeleton(&mounts.root(), name)?;
+        cleanup::verify_mount_root(&mounts.path(name), name)?;
+    }
+
+    if !matches!(swap.mode, swap::SwapMode::None | swap::SwapMode::Partition) {
+        println!("\nStep 10f: Setting up swap ({})...", swap.describe());
+        timings.begin("10f Setting up swap");
+        audit.record("swap", &swap.describe())?;
+        let boot_device = get_partition_device(&disk_info.device, disk_info.roles.boot)?;
+        swap::apply(&swap, &mounts.root(), &boot_device, &mounts)?;
+    }
+
+    println!("\nStep 11: Updating /etc/fstab...");
+    timings.begin("11 Updating /etc/fstab");
+    audit.record("write-fstab", "/etc/fstab on root")?;
+    let fstab_path = format!("{}/etc/fstab", mounts.root());
+    let original_fstab = std::fs::read_to_string(&fstab_path).context(format!("Failed to read {}", fstab_path))?;
+    update_fstab(&created_partitions, &swap, &partuuid_changes, args.fstab_ref, &fstab::Provenance::new(&plan_hash), &mounts)?;
+
+    println!("\nStep 11a: Checking for other references to changed partition IDs...");
+    timings.begin("11a Checking for other references to changed partition IDs");
+    let mut id_changes = partuuid_changes.clone();
+    id_changes.extend(references::replaced_ids(&original_fstab, &created_ids(&created_partitions)?)?);
+    let references = references::update_references(&mounts.root(), &id_changes)?;
+    references::print_references(&references);
+
+    println!("  Validating /etc/fstab...");
+    fstab::validate_fstab(&mounts.root())?;
+
+    // The plan file now records what was applied, for verify-plan to check against
+    if let (Some(path), Some(plan)) = (&args.plan_json, &plan) {
+        plandiff::write_plan(path, &plandiff::applied(plan, &disk_info.device, &mounts.root())?)?;
+    }
+
+    if let Some(ref user_data) = args.seed {
+        println!("\nStep 11b: Writing cloud-init seed...");
+        timings.begin("11b Writing cloud-init seed");
+        audit.record("write-seed", user_data)?;
+        let seed_device = match cidata_device {
+            Some(ref device) => device.clone(),
+            None => get_partition_device(&disk_info.device, disk_info.roles.boot)?,
+        };
+        seed::write_seed(&seed_device, user_data, args.seed_network.as_deref(), &mounts)?;
+    }
+
+    if !headless_options.is_empty() {
+        println!("\nStep 11c: Applying headless setup...");
+        timings.begin("11c Applying headless setup");
+        audit.record("headless-setup", "boot partition and root")?;
+        let boot_device = get_partition_device(&disk_info.device, disk_info.roles.boot)?;
+        headless::apply_headless(&headless_options, &boot_device, &mounts)?;
+    }
+
+    if hostname.is_some() || args.reset_identity {
+        println!("\nStep 11d: Updating system identity...");
+        timings.begin("11d Updating system identity");
+        audit.record("identity", hostname.as_deref().unwrap_or("reset"))?;
+        if let Some(ref hostname) = hostname {
+            identity::set_hostname(&mounts.root(), hostname)?;
+        }
+        if args.reset_identity {
+            let var_root = if var_device.is_some() { mounts.var() } else { format!("{}/var", mounts.root()) };
+            identity::reset_identity(&mounts.root(), &var_root)?;
+        }
+    }
+
+    if args.tryboot {
+        println!("\nStep 11e: Staging the new layout for a tryboot trial...");
+        timings.begin("11e Staging the new layout for a tryboot trial");
+        audit.record("stage-tryboot", "fstab.tryboot, tryboot.txt, commit service")?;
+        // The root PARTUUID change applies to both layouts; it is the same partition
+        let current_layout_fstab = updated_fstab(&original_fstab, &partuuid_changes, Vec::new(), &[], None)?;
+        let boot_device = get_partition_device(&disk_info.device, disk_info.roles.boot)?;
+        tryboot::stage(&mounts.root(), &current_layout_fstab, &migrated, &boot_device, &mounts)?;
+    }
+
+    if swap.hibernate
+        && let Some(ref swap_device) = created_partitions.swap_device
+    {
+        println!("\nStep 11f: Configuring resume from hibernation...");
+        timings.begin("11f Configuring resume from hibernation");
+        audit.record("hibernate", swap_device)?;
+        let boot_device = get_partition_device(&disk_info.device, disk_info.roles.boot)?;
+        swap::configure_resume(&mounts.root(), swap_device, &boot_device, &mounts)?;
+    }
+
+    if !config_changes.is_empty() {
+        println!("\nStep 11g: Editing config.txt...");
+        timings.begin("11g Editing config.txt");
+        audit.record("config-txt", &args.config_txt.join(" "))?;
+        let boot_device = get_partition_device(&disk_info.device, disk_info.roles.boot)?;
+        let boot = mounts.boot();
+        mount_at(&boot_device, &boot)?;
+        let mut descriptions = Vec::new();
+        let result = configtxt::edit(&format!("{}/config.txt", boot), |config| {
+            descriptions = config_changes.iter().map(|change| config.apply(change)).collect();
+        });
+        unmount_quiet(&boot);
+        result?;
+        for description in descriptions {
+            println!("  {}", description);
+        }
+    }
+
+    let initramfs_reasons = match args.initramfs {
+        initramfs::InitramfsMode::Never => Vec::new(),
+        mode => {
+            let boot = mounts.boot();
+            mount_at(&boot_device, &boot)?;
+            let cmdline = std::fs::read_to_string(format!("{}/cmdline.txt", boot)).unwrap_or_default();
+            unmount_quiet(&boot);
+            let reasons = initramfs::reasons(&root_stack, swap.hibernate, &cmdline);
+            if reasons.is_empty() && mode == initramfs::InitramfsMode::Always { vec!["--initramfs always"] } else { reasons }
+        }
+    };
+    if !initramfs_reasons.is_empty() {
+        println!("\nStep 11h: Generating the initramfs...");
+        timings.begin("11h Generating the initramfs");
+        println!("  Needed for: {}", initramfs_reasons.join(", "));
+        audit.record("initramfs", &initramfs_reasons.join(", "))?;
+        let var = var_device.is_some().then(|| mounts.var());
+        initramfs::generate(&mounts.root(), &boot_device, var.as_deref(), model.as_ref().map(|model| model.family))?;
+    } else if args.initramfs == initramfs::InitramfsMode::Never && swap.hibernate {
+        println!("\n  Note: --initramfs never; run update-initramfs -u on the target so it resumes from the new swap");
+    }
+
+    if let Some(ref etc_backup) = etc_backup {
+        println!("\nStep 11i: Saving /etc to the firmware partition...");
+        timings.begin("11i Saving /etc to the firmware partition");
+        audit.record("etc-backup", &boot_device)?;
+        etc_backup.write(&mounts.root(), &boot_device, firmware::BootLayout::detect(&mounts.root()), &mounts)?;
+    }
+
+    if args.smoke_test {
+        println!("\nStep 11j: Smoke-testing the target in a chroot...");
+        timings.begin("11j Smoke-testing the target");
+        audit.record("smoke-test", &mounts.root())?;
+        let var = var_device.is_some().then(|| mounts.var());
+        smoketest::run(&mounts.root(), var.as_deref())?;
+    }
+
+    // Measured before the unmount, so nothing has to be mounted again to see it
+    let filesystems = measure_filesystems(&created_partitions, &mounts);
+
+    println!("\nStep 12: Unmounting partitions...");
+    timings.begin("12 Unmounting partitions");
+    unmount_all(&mounts)?;
+    open_disk.close_stack()?;
+
+    println!("\nStep 12a: Verifying the boot partition...");
+    timings.begin("12a Verifying the boot partition");
+    let mut regenerated: Vec<&str> = if initramfs_reasons.is_empty() { Vec::new() } else { vec!["initramfs", "initrd"] };
+    if etc_backup.is_some() {
+        regenerated.push(etcbackup::PREFIX);
+    }
+    let rewritten: Vec<String> = boot_references
+        .iter()
+        .filter(|reference| reference.fixed)
+        .map(|reference| reference.path.trim_start_matches("boot:/").to_ascii_lowercase())
+        .collect();
+    regenerated.extend(rewritten.iter().map(String::as_str));
+    bootcheck::verify(&boot_device, &boot_before, &regenerated, &mounts)?;
+    if args.fsck_boot {
+        bootcheck::fsck(&boot_device)?;
+    }
+
+    open_disk.release()?;
+
+    if args.boot_test {
+        println!("\nStep 13: Boot test under QEMU...");
+        timings.begin("13 Boot test under QEMU");
+        boottest::boot_test(&device_arg, &boottest::BootTestOptions::default(), &mounts)?;
+    }
+
+    audit.record("complete", "")?;
+    timings.finish();
+
+    println!("\n=== Migration complete! ===");
+    timings.print_table();
+    let root_before = table_before
+        .entries
+        .iter()
+        .find(|entry| entry.number == disk_info.roles.root)
+        .map_or(0, |entry| (entry.end - entry.start + 1) * SECTOR_SIZE);
+    let space = utilization::Summary {
+        filesystems,
+        root_before,
+        root_after: layout.root_size_bytes,
+        data_bytes: timings.total_bytes(),
+    };
+    space.print();
+    if let Some(ref path) = args.report {
+        write_report(path, &report_json(&disk_info, &layout, &swap, &databases, &mkfs_args, &timings, &space))?;
+    }
+    if args.tryboot {
+        println!("\nAll data has been copied and the new layout is staged.");
+        println!("Boot the disk as usual (it still uses the old layout), then try the new one with:");
+        println!("  sudo reboot '0 tryboot'");
+        println!("If that boot mounts the new partitions, the layout is committed; otherwise the next boot is the old layout.");
+    } else {
+        println!("\nAll data has been migrated and fstab updated.");
+        println!("You can now boot from this disk.");
+    }
+
+    Ok(())
+}
+
+/// Step 3: resize the root partition. When a recovery partition goes in front
+/// of root, root's data moves to its new start first, journaled in
+/// `move_journal`; its LUKS/LVM stack is closed for that and stays closed.
+fn resize_root_step(
+    disk_info: &DiskInfo,
+    layout: &PartitionLayout,
+    root_stack: &stack::RootStack,
+    open_disk: &mut OpenDisk,
+    move_journal: &relocate::JournalLock,
+    audit: &audit::AuditLog,
+    timings: &mut timing::StepTimings,
+) -> Result<()> {
+    let moving = layout.recovery_size_bytes > 0;
+    if moving {
+        println!("\nStep 3: Moving root partition to make room for recovery partition...");
+        timings.begin("3 Moving root partition to make room for recovery partition");
+        // Mapped layers must not be open while the blocks underneath them move
+        open_disk.close_stack()?;
+        audit.record(
+            "move-partition",
+            &format!("{} from sector {} to {}", disk_info.root_partition, layout.root_start, layout.recovery_start),
+        )?;
+        let root_move = relocate::PartitionMove {
+            number: disk_info.roles.root,
+            // A resume recreates the entry with this type: LUKS and LVM roots keep theirs
+            fstype: root_stack.partition_fstype().to_string(),
+            from: layout.recovery_start,
+            to: layout.root_start,
+            bytes: layout.root_size_bytes,
+            end: layout.root_end,
+        };
+        relocate::move_partition_data(move_journal, disk_info, &root_move, "run")?;
+        timings.add_bytes(layout.root_size_bytes);
+    } else {
+        println!("\nStep 3: Resizing root partition...");
+        timings.begin("3 Resizing root partition");
+    }
+    audit.record(
+        "resize-partition",
+        &format!("{} to sectors {}-{}", disk_info.root_partition, layout.root_start, layout.root_end),
+    )?;
+    resize_root_partition(disk_info, layout.root_start, layout.root_end)?;
+    if moving {
+        relocate::finish_move(move_journal)?;
+    }
+    Ok(())
+}
+
+fn report_json(
+    disk_info: &DiskInfo,
+    layout: &PartitionLayout,
+    swap: &swap::SwapPlan,
+    databases: &[databases::Database],
+    mkfs_args: &BTreeMap<&'static str, Vec<String>>,
+    timings: &timing::StepTimings,
+    space: &utilization::Summary,
+) -> serde_json::Value {
+    serde_json::json!({
+        "device": disk_info.device,
+        "serial": disk_info.identity.serial,
+        "disk": disk_info.identity.to_json(),
+        "size_bytes": disk_info.size_bytes,
+        "partition_table": disk_info.partition_table,
+        "layout": {
+            "recovery_bytes": layout.recovery_size_bytes,
+            "root_bytes": layout.root_size_bytes,
+            "swap_bytes": layout.swap_size_bytes,
+            "var_bytes": layout.var_size_bytes,
+            "containers_bytes": layout.containers_size_bytes,
+            "cidata_bytes": layout.cidata_size_bytes,
+            "home_bytes": layout.home_size_bytes,
+            "logical": layout.logical(),
+        },
+        "swap": { "mode": swap.mode.name(), "bytes": swap.size },
+        "databases": databases.iter().map(databases::Database::to_json).collect::<Vec<_>>(),
+        "mkfs_args": mkfs_args,
+        "timings": timings.to_json(),
+        "space": space.to_json(),
+        "findings": findings::to_json(),
+    })
+}
+
+fn write_report(path: &str, report: &serde_json::Value) -> Result<()> {
+    std::fs::write(path, format!("{:#}\n", report)).context(format!("Failed to write report {}", path))?;
+    println!("\nReport written to {}", path);
+    Ok(())
+}
+
+/// The --mkfs-args.<partition> values given, by partition
+fn mkfs_arg_values(args: &Args) -> Vec<(&'static str, &String)> {
+    [
+        ("home", &args.mkfs_args_home),
+        ("var", &args.mkfs_args_var),
+        ("containers", &args.mkfs_args_containers),
+        ("swap", &args.mkfs_args_swap),
+        ("recovery", &args.mkfs_args_recovery),
+        ("cidata", &args.mkfs_args_cidata),
+    ]
+    .into_iter()
+    .filter_map(|(partition, value)| value.as_ref().map(|value| (partition, value)))
+    .collect()
+}
+
+/// Take the sizes not given on the command line from a layout file, with its
+/// variables filled in for `device`
+fn apply_layout(args: &mut Args, path: &str, device: &str) -> Result<()> {
+    let ram = args.target_ram.as_deref().map(parse_size).transpose()?;
+    let layout = imgexpand::load_layout(path)?.expand(&template::Variables::for_device(device, ram))?;
+    args.root_size.get_or_insert(layout.root);
+    if args.swap_size.is_none() {
+        args.swap_size = layout.swap;
+    }
+    if args.var_size.is_none() {
+        args.var_size = layout.var;
+    }
+    if args.recovery_size.is_none() {
+        args.recovery_size = layout.recovery;
+    }
+    args.hooks = layout.hooks;
+    if let (None, Some(mode)) = (args.swap_mode, layout.swap_mode) {
+        args.swap_mode =
+            Some(swap::SwapMode::from_str(&mode, true).map_err(|e| anyhow!("Invalid swap_mode in {}: {}", path, e))?);
+    }
+    Ok(())
+}
+
+/// Replace `--swap-size auto` and size rules such as `clamp(2*ram, 1G, 8G)` among
+/// the sizes by the bytes they come to on `device`; the rules evaluated, for the summary
+fn evaluate_size_rules(args: &mut Args, device: &str) -> Result<Vec<(&'static str, String, u64)>> {
+    let target_ram = args.target_ram.as_deref().map(parse_size).transpose()?;
+    let inputs = sizeexpr::Inputs { disk: template::disk_bytes(device), ram: target_ram.or_else(template::ram_bytes) };
+    let mut evaluated = Vec::new();
+    if args.swap_size.as_deref().is_some_and(|size| size.eq_ignore_ascii_case("auto")) {
+        let (ram, from) = swap::target_ram(target_ram, args.target_model.is_some())?;
+        let bytes = swap::auto_size(ram, args.hibernate);
+        args.swap_size = Some(bytes.to_string());
+        let rule = format!("auto for {} of RAM ({}{})", timing::format_bytes(ram), from, if args.hibernate { ", hibernation" } else { "" });
+        evaluated.push(("swap", rule, bytes));
+    }
+    for (name, value) in [
+        ("root", &mut args.root_size),
+        ("swap", &mut args.swap_size),
+        ("/var", &mut args.var_size),
+        ("container storage", &mut args.container_size),
+        ("recovery", &mut args.recovery_size),
+    ] {
+        if let Some(rule) = value.take_if(|value| parse_size(value).is_err()) {
+            let bytes = sizeexpr::evaluate(&rule, inputs).context(format!("Invalid {} size", name))?;
+            *value = Some(bytes.to_string());
+            evaluated.push((name, rule, bytes));
+        }
+    }
+    Ok(evaluated)
+}
+
+/// Split the --mkfs-args.<partition> values, refusing those for partitions the layout doesn't create
+fn mkfs_args(args: &Args, layout: &PartitionLayout) -> Result<BTreeMap<&'static str, Vec<String>>> {
+    let mut parsed = BTreeMap::new();
+    for (partition, value) in mkfs_arg_values(args) {
+        let created = match partition {
+            "var" => layout.var_size_bytes > 0,
+            "containers" => layout.containers_size_bytes > 0,
+            "swap" => layout.swap_size_bytes > 0,
+            "recovery" => layout.recovery_size_bytes > 0,
+            "cidata" => layout.cidata_size_bytes > 0,
+            _ => true,
+        };
+        if !created {
+            bail!("--mkfs-args.{} given, but this layout has no {} partition", partition, partition);
+        }
+        let split = format::split_args(value).context(format!("Invalid --mkfs-args.{}", partition))?;
+        if !split.is_empty() {
+            parsed.insert(partition, split);
+        }
+    }
+    Ok(parsed)
+}
+
+fn run_command(command: Commands) -> Result<()> {
+    match command {
+        Commands::Bench { device, write, size, seconds } => {
+            let seq_bytes = parse_size(&size)?;
+            println!("Benchmarking {}...", device);
+            let result = bench::run_bench(&device, seq_bytes, std::time::Duration::from_secs(seconds), write)?;
+            bench::print_bench(&device, &result, crpart::is_sd_card(&device));
+            if let Some(ref report) = wear::read_wear(&device) {
+                wear::print_wear(report);
+            }
+            Ok(())
+        }
+        Commands::ImgShrink { input, output, auto_expand, mount_base } => {
+            privilege::require("img-shrink")?;
+            let mounts = MountPaths {
+                base: mount_base
+                    .unwrap_or_else(|| container::default_mount_base(container::detect_container().is_some())),
+            };
+            imageio::process_image(&input, output.as_deref(), true, |image| {
+                imgshrink::shrink_image(image, auto_expand, &mounts)
+            })
+        }
+        Commands::Adjust { device, home, var, containers, dry_run, audit_log, mount_base, resume_unidentified } => {
+            if !dry_run {
+                privilege::require("adjust")?;
+            }
+            let mounts = MountPaths {
+                base: mount_base
+                    .unwrap_or_else(|| container::default_mount_base(container::detect_container().is_some())),
+            };
+            let deltas: Vec<(&str, String)> = [("home", home), ("var", var), ("containers", containers)]
+                .into_iter()
+                .filter_map(|(name, delta)| Some((name, delta?)))
+                .collect();
+            adjust::adjust(&device, &deltas, dry_run, resume_unidentified, audit_log.as_deref(), &mounts)
+        }
+        Commands::Pack { device, dry_run, audit_log, resume_unidentified } => {
+            if !dry_run {
+                privilege::require("pack")?;
+            }
+            pack::pack(&device, dry_run, resume_unidentified, audit_log.as_deref())
+        }
+        Commands::ShrinkPart { partition, to, dry_run, mount_base, shrink_strategy } => {
+            if !dry_run {
+                privilege::require("shrink-part")?;
+            }
+            staging::configure(shrink_strategy)?;
+            let mounts = MountPaths {
+                base: mount_base
+                    .unwrap_or_else(|| container::default_mount_base(container::detect_container().is_some())),
+            };
+            shrinkpart::shrink_partition(&partition, &to, dry_run, &mounts)
+        }
+        Commands::Serve { listen, token_file } => serve::serve(&listen, serve::load_token(token_file.as_deref())?),
+        Commands::Inspect { device } => inspect::inspect(&device),
+        Commands::Analyze { device } => inspect::analyze(&device),
+        Commands::AuditVerify { log } => audit::verify(&log),
+        Commands::Explain { check, json } => findings::explain(check.as_deref(), json),
+        Commands::BootTest { image, machine, kernel, dtb, initrd, marker, timeout, log, mount_base } => {
+            let mounts = MountPaths {
+                base: mount_base
+                    .unwrap_or_else(|| container::default_mount_base(container::detect_container().is_some())),
+            };
+            let options = boottest::BootTestOptions {
+                machine,
+                kernel,
+                dtb,
+                initrd,
+                marker,
+                timeout: std::time::Duration::from_secs(timeout),
+                log,
+            };
+            boottest::boot_test(&image, &options, &mounts)
+        }
+        Commands::Check { device } => inspect::check(&device),
+        Commands::Diff { old, new, json } => plandiff::run_diff(&old, &new, json),
+        Commands::FstabUndo { file, dry_run } => fstab::run_undo(&file, dry_run),
+        Commands::VerifyPlan { plan, device, json, mount_base } => {
+            let mounts = MountPaths {
+                base: mount_base
+                    .unwrap_or_else(|| container::default_mount_base(container::detect_container().is_some())),
+            };
+            plandiff::verify_plan(&plan, &device, &mounts, json)
+        }
+        Commands::Batch { devices, parallel, log_dir, mount_base, args } => {
+            let mount_base =
+                mount_base.unwrap_or_else(|| container::default_mount_base(container::detect_container().is_some()));
+            batch::run_batch(&devices, parallel, &log_dir, &mount_base, &args)
+        }
+        Commands::MakeRescue { out, boot_files, busybox, binary, tools_dir, modules, mount_base } => {
+            let mounts = MountPaths {
+                base: mount_base
+                    .unwrap_or_else(|| container::default_mount_base(container::detect_container().is_some())),
+            };
+            let binary = match binary {
+                Some(binary) => binary,
+                None => std::env::current_exe().context("Failed to locate rpi-fs-shrink")?.to_string_lossy().to_string(),
+            };
+            let sources = rescue::RescueSources { boot_files, binary, busybox, tools_dir, modules };
+            rescue::make_rescue(&out, &sources, &mounts)
+        }
+        Commands::SelfUpdate { check, version, force, minisign_key } => {
+            selfupdate::run_self_update(check, version.as_deref(), force, minisign_key.as_deref())
+        }
+        Commands::Wizard { device } => wizard::run_wizard(device),
+        Commands::Plan { args } => {
+            let mut args = Args::try_parse_from(std::iter::once("rpi-fs-shrink".to_string()).chain(args))?;
+            args.dry_run = true;
+            // NBD URLs are checked as their local device once connected
+            if let Some(ref device) = args.device
+                && nbd::parse_nbd_url(device).is_none()
+            {
+                policy::check_device(&policy::load_config()?.devices, device)?;
+            }
+            run_operation(args)
+        }
+        Commands::ImgDelta { old, new, delta } => {
+            println!("Comparing {} with {}...", old, new);
+            imageio::process_image(&old, None, false, |old| {
+                imageio::process_image(&new, None, false, |new| delta::create_delta(old, new, &delta))
+            })
+        }
+        Commands::ImgPatch { target, delta } => {
+            println!("Applying {} to {}...", delta, target);
+            if !Path::new(&target).is_file() {
+                if is_device_mounted(&target)? {
+                    bail!("{} has mounted partitions; unmount them first", target);
+                }
+                return delta::apply_delta(&target, &delta);
+            }
+            imageio::process_image(&target, None, true, |image| delta::apply_delta(image, &delta))
+        }
+        Commands::ImgExpand { image, size, layout, mount_base } => {
+            privilege::require("img-expand")?;
+            let size = parse_size(&size)?;
+            // An image has no serial number; its disk size is the one it grows to
+            let layout =
+                imgexpand::load_layout(&layout)?.expand(&template::Variables::new(Some(size), template::ram_bytes(), None))?;
+            // Check the layout up front so a bad size fails before the image grows
+            let inputs = sizeexpr::Inputs { disk: Some(size), ram: template::ram_bytes() };
+            if layout.swap.as_deref().is_some_and(|swap| swap.eq_ignore_ascii_case("auto")) {
+                swap::target_ram(None, false)?;
+            }
+            for value in [Some(&layout.root), layout.swap.as_ref(), layout.var.as_ref(), layout.recovery.as_ref()]
+                .into_iter()
+                .flatten()
+            {
+                if parse_size(value).is_err() && !value.eq_ignore_ascii_case("auto") {
+                    sizeexpr::evaluate(value, inputs)?;
+                }
+            }
+            imageio::process_image(&image, None, true, |working| {
+                let mut args = Args::try_parse_from(imgexpand::layout_args(working, &layout, mount_base.as_deref()))?;
+                args.hooks = layout.hooks.clone();
+                imgexpand::grow_image(working, size)?;
+                run(args)
+            })
+        }
     }
-
-    println!("\nStep 10: Migrating /home data...");
-    migrate_home_data()?;
-
-    println!("\nStep 11: Updating /etc/fstab...");
-    update_fstab(&created_partitions)?;
-
-    println!("\nStep 12: Unmounting partitions...");
-    unmount_all()?;
-
-    println!("\n=== Migration complete! ===");
-    println!("\nAll data has been migrated and fstab updated.");
-    println!("You can now boot from this disk.");
-
-    Ok(())
 }
 
-fn is_root() -> bool {
-    unsafe { libc::geteuid() == 0 }
+/// Whether any filesystem on `device` or one of its partitions is mounted
+fn is_device_mounted(device: &str) -> Result<bool> {
+    let mounts = std::fs::read_to_string("/proc/mounts").context("Failed to read /proc/mounts")?;
+    Ok(parse::proc_mounts(&mounts).iter().any(|entry| sysfs::is_on_device(device, &entry.source)))
 }
 
 fn is_active_root_disk(device: &str) -> Result<bool> {
@@ -243,12 +2689,11 @@ fn is_active_root_disk(device: &str) -> Result<bool> {
     let mounts = std::fs::read_to_string("/proc/mounts")
         .context("Failed to read /proc/mounts")?;
 
-    for line in mounts.lines() {
-        let parts: Vec<&str> = line.split_whitespace().collect();
-        if parts.len() >= 2 && parts[1] == "/" {
-            let root_device = parts[0];
+    for entry in parse::proc_mounts(&mounts) {
+        if entry.target == "/" {
+            let root_device = entry.source.as_str();
             // Check if this device or any partition on it is the root
-            if root_device.starts_with(device) || device.starts_with(root_device) {
+            if sysfs::is_on_device(device, root_device) {
                 return Ok(true);
             }
         }
@@ -257,20 +2702,55 @@ fn is_active_root_disk(device: &str) -> Result<bool> {
     Ok(false)
 }
 
-fn check_dependencies(dry_run: bool) -> Result<()> {
+/// Tools only some options need, with the packages that have them
+fn extra_dependencies(args: &Args) -> Vec<(&'static str, &'static str)> {
+    let mut extra = Vec::new();
+    if args.recovery_size.is_some() || args.recovery.is_some() || args.seed_partition {
+        extra.push(("mkfs.vfat", "dosfstools"));
+    }
+    if args.swap_mode == Some(swap::SwapMode::File) {
+        extra.push(("fallocate", "util-linux"));
+    }
+    if args.convert_gpt {
+        extra.push(("sgdisk", "gdisk"));
+    }
+    if args.fsck_boot {
+        extra.push(("fsck.fat", "dosfstools"));
+    }
+    if args.etc_backup {
+        extra.push(("tar", "tar"));
+    }
+    match args.copy_backend {
+        copytool::CopyBackend::Rsync if args.rsync_path.is_none() => extra.push(("rsync", "rsync")),
+        copytool::CopyBackend::Rsync => {}
+        copytool::CopyBackend::Tar => extra.push(("tar", "tar")),
+        copytool::CopyBackend::Cpio => extra.push(("cpio", "cpio")),
+    }
+    if args.defrag.is_some() {
+        extra.push(("e4defrag", "e2fsprogs"));
+    }
+    if args.boot_test {
+        extra.push(("qemu-system-aarch64", "qemu-system-arm"));
+        extra.push(("qemu-img", "qemu-utils"));
+    }
+    extra
+}
+
+fn check_dependencies(dry_run: bool, install: bool, extra: &[(&str, &str)]) -> Result<()> {
     println!("Checking dependencies...");
 
-    let dependencies = vec![
+    let mut dependencies = vec![
         ("parted", "parted"),
         ("resize2fs", "e2fsprogs"),
         ("mkfs.ext4", "e2fsprogs"),
         ("mkfs.btrfs", "btrfs-progs"),
         ("mkswap", "util-linux"),
-        ("rsync", "rsync"),
         ("mount", "mount"),
         ("umount", "mount"),
         ("blkid", "util-linux"),
+        ("sfdisk", "fdisk"),
     ];
+    dependencies.extend_from_slice(extra);
 
     let mut missing = Vec::new();
 
@@ -286,6 +2766,11 @@ fn check_dependencies(dry_run: bool) -> Result<()> {
     if !missing.is_empty() {
         if dry_run {
             println!("\nWould install: {:?}", missing);
+        } else if !install {
+            bail!(
+                "Missing required tools (packages: {:?}); install them first or add them to the container image or --tools-dir",
+                missing
+            );
         } else {
             println!("\nInstalling missing dependencies...");
             install_packages(&missing)?;
@@ -297,13 +2782,7 @@ fn check_dependencies(dry_run: bool) -> Result<()> {
 }
 
 fn command_exists(cmd: &str) -> bool {
-    Command::new("which")
-        .arg(cmd)
-        .stdout(Stdio::null())
-        .stderr(Stdio::null())
-        .status()
-        .map(|s| s.success())
-        .unwrap_or(false)
+    busybox::find_in_path(cmd).is_some()
 }
 
 fn install_packages(packages: &[&str]) -> Result<()> {
@@ -387,6 +2866,25 @@ fn validate_root_size(size: u64) -> Result<()> {
     Ok(())
 }
 
+fn print_disk_info(disk_info: &DiskInfo) {
+    println!("Disk Information:");
+    println!("  Device: {}", disk_info.device);
+    println!("  Size: {} GB ({} bytes)", disk_info.size_bytes / (1024 * 1024 * 1024), disk_info.size_bytes);
+    println!("  Is SD Card: {}", disk_info.is_sd_card);
+    println!("  Partition Table: {}", disk_info.partition_table);
+    println!("  Root Partition: {}", disk_info.root_partition);
+    println!("  Identity: {}", disk_info.identity.describe());
+    if !disk_info.roles.is_conventional() {
+        println!(
+            "  Boot and root are partitions {} and {} (found by {})",
+            disk_info.roles.boot, disk_info.roles.root, disk_info.roles.found_by
+        );
+    }
+    if let Some(ref report) = wear::read_wear(&disk_info.device) {
+        wear::print_wear(report);
+    }
+}
+
 fn get_disk_info(device: &str) -> Result<DiskInfo> {
     // Normalize device path
     let device = if !device.starts_with("/dev/") {
@@ -401,7 +2899,7 @@ fn get_disk_info(device: &str) -> Result<DiskInfo> {
     }
 
     // Determine if it's an SD card
-    let is_sd_card = device.contains("mmcblk");
+    let is_sd_card = crpart::is_sd_card(&device);
 
     // Get disk size using parted
     let output = Command::new("parted")
@@ -412,20 +2910,18 @@ fn get_disk_info(device: &str) -> Result<DiskInfo> {
     let stdout = String::from_utf8_lossy(&output.stdout);
 
     // Parse disk size
-    let size_re = Regex::new(r"Disk /[^:]+:\s*(\d+)B")?;
-    let size_bytes = size_re
-        .captures(&stdout)
-        .and_then(|c| c[1].parse::<u64>().ok())
+    let size_bytes = parse::parted_disk_size_bytes(&stdout)
+        .or_else(|| privilege::fallback(&device, "size", || sysfs::disk_size_bytes(&device)))
         .ok_or_else(|| anyhow!("Could not determine disk size"))?;
 
     let size_sectors = size_bytes / SECTOR_SIZE;
+    let partition_table = get_partition_table_type(&device)?;
+    let erase_block_bytes = if is_sd_card { sysfs::erase_block_bytes(&device) } else { None };
+    let identity = identity::DiskIdentity::read(&device);
 
-    // Determine root partition (usually partition 2 on RPi)
-    let root_partition = if is_sd_card {
-        format!("{}2", device)
-    } else {
-        format!("{}p2", device)
-    };
+    // Root is partition 2 on Raspberry Pi OS images, but not on NOOBS or multi-boot cards
+    let roles = roles::identify(&device)?;
+    let root_partition = sysfs::partition_path(&device, roles.root);
 
     // Verify root partition exists
     if !Path::new(&root_partition).exists() {
@@ -438,71 +2934,125 @@ fn get_disk_info(device: &str) -> Result<DiskInfo> {
         size_sectors,
         is_sd_card,
         root_partition,
+        roles,
+        partition_table,
+        erase_block_bytes,
+        identity,
     })
 }
 
-fn align_sector(sector: u64) -> u64 {
-    sector.div_ceil(ALIGNMENT) * ALIGNMENT
+fn align_sector(sector: u64, alignment: u64) -> u64 {
+    sector.div_ceil(alignment) * alignment
 }
 
-fn calculate_partition_layout(
-    disk_info: &DiskInfo,
-    root_size: u64,
-    swap_size: Option<u64>,
-    var_size: Option<u64>,
-) -> Result<PartitionLayout> {
-    let swap_size = swap_size.unwrap_or(0);
-    let var_size = var_size.unwrap_or(0);
+fn calculate_partition_layout(disk_info: &DiskInfo, sizes: &PartitionSizes, logical: bool) -> Result<PartitionLayout> {
+    // Get current root partition start sector
+    let current_root_start = get_partition_start(&disk_info.device, disk_info.roles.root)?;
+
+    compute_partition_layout(disk_info, current_root_start, sizes, logical)
+}
+
+/// Pure layout arithmetic, separated from the disk queries so it can be simulated.
+/// With `logical` the partitions behind root go into an extended partition.
+fn compute_partition_layout(disk_info: &DiskInfo, current_root_start: u64, sizes: &PartitionSizes, logical: bool) -> Result<PartitionLayout> {
+    let root_size = sizes.root;
+    let recovery_size = sizes.recovery.unwrap_or(0);
+    let swap_size = sizes.swap.unwrap_or(0);
+    let var_size = sizes.var.unwrap_or(0);
+    let containers_size = sizes.containers.unwrap_or(0);
+    let cidata_size = sizes.cidata.unwrap_or(0);
+    let alignment = disk_info.alignment();
+    let align = |sector| align_sector(sector, alignment);
+    // A logical partition starts one alignment unit in, behind the EBR that links it
+    let next_start = |previous_end: u64| align(previous_end + 1) + if logical { alignment } else { 0 };
 
     // Convert to sectors
     let root_size_sectors = root_size / SECTOR_SIZE;
     let swap_size_sectors = swap_size / SECTOR_SIZE;
     let var_size_sectors = var_size / SECTOR_SIZE;
 
-    // Get current root partition start sector
-    let root_start = get_partition_start(&disk_info.device, 2)?;
+    // A recovery partition takes over the old root start and root moves up behind it
+    let (recovery_start, recovery_end, root_start) = if recovery_size > 0 {
+        let recovery_end = align(current_root_start + recovery_size / SECTOR_SIZE) - 1;
+        (current_root_start, recovery_end, recovery_end + 1)
+    } else {
+        (0, 0, current_root_start)
+    };
 
     // Calculate partition boundaries (aligned)
-    let root_end = align_sector(root_start + root_size_sectors) - 1;
+    let root_end = align(root_start + root_size_sectors) - 1;
 
     let swap_start = if swap_size > 0 {
-        align_sector(root_end + 1)
+        next_start(root_end)
     } else {
         0
     };
     let swap_end = if swap_size > 0 {
-        align_sector(swap_start + swap_size_sectors) - 1
+        align(swap_start + swap_size_sectors) - 1
     } else {
         0
     };
 
     let var_start = if var_size > 0 {
         if swap_size > 0 {
-            align_sector(swap_end + 1)
+            next_start(swap_end)
         } else {
-            align_sector(root_end + 1)
+            next_start(root_end)
         }
     } else {
         0
     };
     let var_end = if var_size > 0 {
-        align_sector(var_start + var_size_sectors) - 1
+        align(var_start + var_size_sectors) - 1
     } else {
         0
     };
 
-    let home_start = if var_size > 0 {
-        align_sector(var_end + 1)
+    let last_end = if var_size > 0 {
+        var_end
     } else if swap_size > 0 {
-        align_sector(swap_end + 1)
+        swap_end
+    } else {
+        root_end
+    };
+
+    // Container storage is mounted inside /var and follows it
+    let (containers_start, containers_end) = if containers_size > 0 {
+        let start = next_start(last_end);
+        (start, align(start + containers_size / SECTOR_SIZE) - 1)
+    } else {
+        (0, 0)
+    };
+    let last_end = if containers_size > 0 { containers_end } else { last_end };
+
+    // CIDATA seed partition sits directly in front of /home
+    let (cidata_start, cidata_end) = if cidata_size > 0 {
+        let start = next_start(last_end);
+        (start, align(start + cidata_size / SECTOR_SIZE) - 1)
     } else {
-        align_sector(root_end + 1)
+        (0, 0)
     };
 
-    // Home partition gets the rest
-    let home_end = disk_info.size_sectors - 1;
+    let home_start = if cidata_size > 0 {
+        next_start(cidata_end)
+    } else {
+        next_start(last_end)
+    };
+
+    // Home partition gets the rest. With a known erase block it ends on one as
+    // well, clear of the backup GPT in the last 33 sectors.
+    let home_end = if alignment > ALIGNMENT {
+        let mut end = disk_info.size_sectors / alignment * alignment;
+        if disk_info.partition_table == "gpt" && disk_info.size_sectors - end < 33 {
+            end -= alignment;
+        }
+        end - 1
+    } else {
+        disk_info.size_sectors - 1
+    };
 
     let home_size_bytes = (home_end - home_start + 1) * SECTOR_SIZE;
+    let (extended_start, extended_end) = if logical { (align(root_end + 1), home_end) } else { (0, 0) };
 
     // Validate that /home is at least half the disk
     let min_home_size = disk_info.size_bytes / 2;
@@ -515,22 +3065,39 @@ fn calculate_partition_layout(
     }
 
     Ok(PartitionLayout {
+        recovery_size_bytes: recovery_size,
         root_size_bytes: root_size,
         swap_size_bytes: swap_size,
         var_size_bytes: var_size,
+        containers_size_bytes: containers_size,
+        cidata_size_bytes: cidata_size,
         home_size_bytes,
+        recovery_start,
+        recovery_end,
         root_start,
         root_end,
         swap_start,
         swap_end,
         var_start,
         var_end,
+        containers_start,
+        containers_end,
+        cidata_start,
+        cidata_end,
         home_start,
         home_end,
+        extended_start,
+        extended_end,
+        alignment,
     })
 }
 
 fn get_partition_start(device: &str, partition_num: u32) -> Result<u64> {
+    Ok(get_partition_bounds(device, partition_num)?.0)
+}
+
+/// Start and end sector of a partition
+fn get_partition_bounds(device: &str, partition_num: u32) -> Result<(u64, u64)> {
     let output = Command::new("parted")
         .args([device, "unit", "s", "print"])
         .output()
@@ -539,22 +3106,61 @@ fn get_partition_start(device: &str, partition_num: u32) -> Result<u64> {
     let stdout = String::from_utf8_lossy(&output.stdout);
 
     // Parse partition table
-    let re = Regex::new(&format!(r"^\s*{}\s+(\d+)s", partition_num))?;
-    for line in stdout.lines() {
-        if let Some(caps) = re.captures(line) {
-            return caps[1].parse::<u64>().context("Failed to parse start sector");
-        }
+    let partition = parse::parted_partitions(&stdout).into_iter().find(|p| p.number == partition_num);
+    if let Some(parse::PartedPartition { start: Some(start), end: Some(end), .. }) = partition {
+        return Ok((start, end));
+    }
+    if let Some(bounds) = privilege::fallback(device, &format!("partition {} bounds", partition_num), || {
+        sysfs::partition_bounds(device, partition_num)
+    }) {
+        return Ok(bounds);
     }
 
     bail!("Could not find partition {} start sector", partition_num)
 }
 
+/// Partition table type as parted reports it ("msdos", "gpt", ...)
+fn get_partition_table_type(device: &str) -> Result<String> {
+    let output = Command::new("parted")
+        .args(["-s", "-m", device, "unit", "s", "print"])
+        .output()
+        .context("Failed to run parted")?;
+
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    parse::parted_machine_label(&stdout)
+        .or_else(|| privilege::fallback(device, "partition table type", || sysfs::partition_table_type(device)))
+        .context(format!("Could not determine partition table type of {}", device))
+}
+
 fn print_layout(layout: &PartitionLayout) {
     println!("Partition Layout:");
+    if layout.alignment > ALIGNMENT {
+        println!(
+            "  Alignment: {} sectors ({}), starts and ends on erase block boundaries",
+            layout.alignment,
+            timing::format_bytes(layout.alignment * SECTOR_SIZE)
+        );
+        if layout.root_start % layout.alignment != 0 {
+            println!("    Root keeps its start at sector {}, which is not on an erase block boundary", layout.root_start);
+        }
+    } else {
+        println!("  Alignment: {} sectors (1 MiB)", layout.alignment);
+    }
+    if layout.recovery_size_bytes > 0 {
+        println!("  Recovery (FAT32):");
+        println!("    Size: {} MB", layout.recovery_size_bytes / (1024 * 1024));
+        println!("    Sectors: {} - {}", layout.recovery_start, layout.recovery_end);
+    }
+
     println!("  Root (/):");
     println!("    Size: {} GB", layout.root_size_bytes / (1024 * 1024 * 1024));
     println!("    Sectors: {} - {}", layout.root_start, layout.root_end);
 
+    if layout.logical() {
+        println!("  Extended (MBR), holding the partitions below as logical ones:");
+        println!("    Sectors: {} - {}", layout.extended_start, layout.extended_end);
+    }
+
     if layout.swap_size_bytes > 0 {
         println!("  Swap:");
         println!("    Size: {} GB", layout.swap_size_bytes / (1024 * 1024 * 1024));
@@ -567,6 +3173,18 @@ fn print_layout(layout: &PartitionLayout) {
         println!("    Sectors: {} - {}", layout.var_start, layout.var_end);
     }
 
+    if layout.containers_size_bytes > 0 {
+        println!("  Container storage (ext4):");
+        println!("    Size: {} GB", layout.containers_size_bytes / (1024 * 1024 * 1024));
+        println!("    Sectors: {} - {}", layout.containers_start, layout.containers_end);
+    }
+
+    if layout.cidata_size_bytes > 0 {
+        println!("  CIDATA (FAT):");
+        println!("    Size: {} MB", layout.cidata_size_bytes / (1024 * 1024));
+        println!("    Sectors: {} - {}", layout.cidata_start, layout.cidata_end);
+    }
+
     println!("  /home (ext4):");
     println!("    Size: {} GB", layout.home_size_bytes / (1024 * 1024 * 1024));
     println!("    Sectors: {} - {}", layout.home_start, layout.home_end);
@@ -581,39 +3199,99 @@ fn check_filesystem(partition: &str) -> Result<()> {
         .context("Failed to run e2fsck")?;
 
     if !status.success() {
-        println!("  Warning: e2fsck returned non-zero status, continuing anyway...");
+        findings::report("fsck-errors", &format!("e2fsck returned non-zero status on {}, continuing anyway...", partition))?;
     }
 
     Ok(())
 }
 
+/// Refuse a root size the ext4 on `device` can't shrink to, with what takes up
+/// its minimum, before resize2fs fails partway through
+fn check_root_fits(device: &str, size: u64) -> Result<()> {
+    let minimum = shrinkpart::ext_minimum(device)?;
+    println!("  Root needs at least {} ({})", timing::format_bytes(minimum.bytes()), minimum.breakdown());
+    if minimum.bytes() > size {
+        bail!(
+            "Root's filesystem needs at least {} ({}), but would get {}; use a --root-size {} larger, or free space on root",
+            timing::format_bytes(minimum.bytes()),
+            minimum.breakdown(),
+            timing::format_bytes(size),
+            timing::format_bytes(minimum.bytes() - size)
+        );
+    }
+    Ok(())
+}
+
 fn shrink_root_filesystem(partition: &str, new_size: u64) -> Result<()> {
-    // Convert to 4K blocks (resize2fs uses 4K blocks)
-    let blocks = new_size / 4096;
+    let stages = if staging::strategy() == staging::ShrinkStrategy::Staged {
+        staging::stages(imgshrink::ext4_size_bytes(partition)?, new_size)
+    } else {
+        vec![new_size]
+    };
+    if stages.len() > 1 {
+        println!("  Shrinking in {} passes with a filesystem check between them", stages.len());
+    }
+
+    for (pass, size) in stages.iter().enumerate() {
+        // Convert to 4K blocks (resize2fs uses 4K blocks)
+        let blocks = size / 4096;
+        let started = std::time::Instant::now();
+        let mut resize2fs = Command::new("resize2fs");
+        if stages.len() > 1 {
+            println!("  Pass {}/{}: shrinking filesystem to {} 4K blocks ({})...", pass + 1, stages.len(), blocks, timing::format_bytes(*size));
+            // Progress bars for each phase of the pass
+            resize2fs.arg("-p");
+        } else {
+            println!("  Shrinking filesystem to {} 4K blocks...", blocks);
+        }
 
-    println!("  Shrinking filesystem to {} 4K blocks...", blocks);
+        let status = resize2fs
+            .args([partition, &format!("{}K", blocks * 4)])
+            .status()
+            .context("Failed to run resize2fs")?;
 
-    let status = Command::new("resize2fs")
-        .args([partition, &format!("{}K", blocks * 4)])
-        .status()
-        .context("Failed to run resize2fs")?;
+        if !status.success() {
+            if pass > 0 {
+                bail!("resize2fs failed in pass {} of {}; the filesystem was left at {}", pass + 1, stages.len(), timing::format_bytes(stages[pass - 1]));
+            }
+            bail!("resize2fs failed");
+        }
 
-    if !status.success() {
-        bail!("resize2fs failed");
+        if pass + 1 < stages.len() {
+            println!("  Pass {}/{} done in {}", pass + 1, stages.len(), timing::format_duration(started.elapsed()));
+            check_filesystem(partition)?;
+        }
     }
 
     println!("  Filesystem shrunk successfully");
     Ok(())
 }
 
-fn resize_root_partition(disk_info: &DiskInfo, new_end_sector: u64) -> Result<()> {
-    println!("  Resizing partition 2 to end at sector {}...", new_end_sector);
+fn resize_root_partition(disk_info: &DiskInfo, start: u64, new_end_sector: u64) -> Result<()> {
+    let root = disk_info.roles.root;
+    println!("  Resizing partition {} to sectors {} - {}...", root, start, new_end_sector);
+    recreate_partition(disk_info, root, "ext4", start, new_end_sector)?;
 
-    // Get current partition info
-    let start = get_partition_start(&disk_info.device, 2)?;
+    // mkpart names GPT entries "primary"; give root its conventional name back
+    if disk_info.partition_table == "gpt" {
+        set_partition_name(&disk_info.device, root, ROOT_PART_LABEL)?;
+    }
+
+    println!("  Partition resized successfully");
+    Ok(())
+}
 
-    // Use parted to resize the partition
-    let commands = format!("rm 2\nmkpart primary ext4 {}s {}s\nquit\n", start, new_end_sector);
+/// Remove partition `number` and create it again from `start` to `end`, keeping
+/// its number. The filesystem in it is untouched.
+fn recreate_partition(disk_info: &DiskInfo, number: u32, fs_type: &str, start: u64, end: u64) -> Result<()> {
+    // Use parted to resize the partition. An MBR partition past 4 sits in the extended
+    // partition; removing a logical renumbers the ones after it, so it has to be
+    // the last to come back under its old number.
+    let kind = if disk_info.partition_table == "msdos" && number > 4 { "logical" } else { "primary" };
+    if kind == "logical" && roles::partition_numbers(&disk_info.device).iter().any(|&n| n > number) {
+        bail!("Partition {} is a logical partition followed by others; resizing it would renumber them", number);
+    }
+    let commands = format!("rm {}\nmkpart {} {} {}s {}s\nquit\n", number, kind, fs_type, start, end);
 
     let mut child = Command::new("parted")
         .args([&disk_info.device])
@@ -635,181 +3313,134 @@ fn resize_root_partition(disk_info: &DiskInfo, new_end_sector: u64) -> Result<()
     }
 
     // Inform kernel of partition changes
-    let _ = Command::new("partprobe")
-        .arg(&disk_info.device)
-        .status();
-
-    println!("  Partition resized successfully");
+    reread_partitions(&disk_info.device);
     Ok(())
 }
 
-fn get_next_partition_number(device: &str) -> Result<u32> {
+/// Number parted gives the next primary (or GPT) partition: the lowest free
+/// one, which after a deleted partition is a gap rather than one past the highest.
+/// Logical partitions are numbered from 5 on.
+fn get_next_partition_number(disk_info: &DiskInfo, logical: bool) -> Result<u32> {
     let output = Command::new("parted")
-        .args([device, "print"])
+        .args([&disk_info.device, "print"])
         .output()
         .context("Failed to run parted")?;
 
     let stdout = String::from_utf8_lossy(&output.stdout);
-    let re = Regex::new(r"^\s*(\d+)\s+")?;
+    let existing: Vec<u32> = parse::parted_partitions(&stdout).iter().map(|p| p.number).collect();
 
-    let mut max_num = 0;
-    for line in stdout.lines() {
-        if let Some(caps) = re.captures(line) {
-            if let Ok(num) = caps[1].parse::<u32>() {
-                max_num = max_num.max(num);
-            }
-        }
+    if logical {
+        return Ok(roles::next_logical_number(&existing));
     }
-
-    Ok(max_num + 1)
+    roles::next_free_number(&existing, &disk_info.partition_table)
+        .ok_or_else(|| anyhow!("{} has no free primary partition entry left", disk_info.device))
 }
 
-fn create_swap_partition(disk_info: &DiskInfo, start: u64, end: u64) -> Result<String> {
-    let part_num = get_next_partition_number(&disk_info.device)?;
-
-    println!("  Creating swap partition {} from sector {} to {}...", part_num, start, end);
+/// GPT partition name for a partition created as `name` ("/var" -> "var")
+fn part_label(name: &str) -> String {
+    name.trim_start_matches('/').to_lowercase()
+}
 
+fn set_partition_name(device: &str, part_num: u32, label: &str) -> Result<()> {
     let status = Command::new("parted")
-        .args([
-            &disk_info.device,
-            "mkpart",
-            "primary",
-            "linux-swap",
-            &format!("{}s", start),
-            &format!("{}s", end),
-        ])
-        .status()
-        .context("Failed to create swap partition")?;
-
-    if !status.success() {
-        bail!("Failed to create swap partition");
-    }
-
-    // Inform kernel
-    let _ = Command::new("partprobe").arg(&disk_info.device).status();
-
-    // Format as swap
-    let swap_device = get_partition_device(&disk_info.device, part_num)?;
-    println!("  Formatting {} as swap...", swap_device);
-
-    let status = Command::new("mkswap")
-        .arg(&swap_device)
+        .args(["-s", device, "name", &part_num.to_string(), label])
         .status()
-        .context("Failed to run mkswap")?;
+        .context("Failed to run parted name")?;
 
     if !status.success() {
-        bail!("mkswap failed");
+        bail!("Failed to name partition {} on {} as {}", part_num, device, label);
     }
 
-    println!("  Swap partition created: {}", swap_device);
-    Ok(swap_device)
+    println!("  Partition {} named {}", part_num, label);
+    Ok(())
 }
 
-fn create_var_partition(disk_info: &DiskInfo, start: u64, end: u64) -> Result<String> {
-    let part_num = get_next_partition_number(&disk_info.device)?;
-
-    println!("  Creating /var partition {} from sector {} to {}...", part_num, start, end);
+/// Create partition `name` of `kind` ("primary" or "logical") from `start` to `end`
+fn create_partition(
+    disk_info: &DiskInfo,
+    audit: &audit::AuditLog,
+    name: &str,
+    kind: &str,
+    fs_type: &str,
+    start: u64,
+    end: u64,
+) -> Result<String> {
+    let part_num = get_next_partition_number(disk_info, kind == "logical")?;
+
+    println!("  Creating {} partition {} from sector {} to {}...", name, part_num, start, end);
+    audit.record("create-partition", &format!("{} partition {} at sectors {}-{}", name, part_num, start, end))?;
 
     let status = Command::new("parted")
         .args([
             &disk_info.device,
             "mkpart",
-            "primary",
-            "btrfs",
+            kind,
+            fs_type,
             &format!("{}s", start),
             &format!("{}s", end),
         ])
         .status()
-        .context("Failed to create /var partition")?;
-
-    if !status.success() {
-        bail!("Failed to create /var partition");
-    }
-
-    // Inform kernel
-    let _ = Command::new("partprobe").arg(&disk_info.device).status();
-
-    // Format as btrfs
-    let var_device = get_partition_device(&disk_info.device, part_num)?;
-    println!("  Formatting {} as btrfs...", var_device);
-
-    let status = Command::new("mkfs.btrfs")
-        .args(["-f", &var_device])
-        .status()
-        .context("Failed to run mkfs.btrfs")?;
+        .context(format!("Failed to create {} partition", name))?;
 
     if !status.success() {
-        bail!("mkfs.btrfs failed");
+        bail!("Failed to create {} partition", name);
     }
 
-    println!("  /var partition created: {}", var_device);
-    Ok(var_device)
-}
-
-fn create_home_partition(disk_info: &DiskInfo, start: u64, end: u64) -> Result<String> {
-    let part_num = get_next_partition_number(&disk_info.device)?;
-
-    println!("  Creating /home partition {} from sector {} to {}...", part_num, start, end);
-
-    let status = Command::new("parted")
-        .args([
-            &disk_info.device,
-            "mkpart",
-            "primary",
-            "ext4",
-            &format!("{}s", start),
-            &format!("{}s", end),
-        ])
-        .status()
-        .context("Failed to create /home partition")?;
-
-    if !status.success() {
-        bail!("Failed to create /home partition");
+    if disk_info.partition_table == "gpt" {
+        set_partition_name(&disk_info.device, part_num, &part_label(name))?;
     }
 
     // Inform kernel
-    let _ = Command::new("partprobe").arg(&disk_info.device).status();
+    reread_partitions(&disk_info.device);
 
-    // Format as ext4
-    let home_device = get_partition_device(&disk_info.device, part_num)?;
-    println!("  Formatting {} as ext4...", home_device);
-
-    let status = Command::new("mkfs.ext4")
-        .args(["-F", &home_device])
-        .status()
-        .context("Failed to run mkfs.ext4")?;
+    let device = get_partition_device(&disk_info.device, part_num)?;
+    println!("  {} partition created: {}", name, device);
+    Ok(device)
+}
 
-    if !status.success() {
-        bail!("mkfs.ext4 failed");
+/// Tell the kernel about a changed partition table. partprobe covers disks and
+/// loop devices attached with --partscan. Device-mapper disks and loop devices
+/// without partition scanning get their partitions as kpartx mappings instead.
+fn reread_partitions(device: &str) {
+    let needs_kpartx = sysfs::is_device_mapper(device) || sysfs::loop_partscan(device) == Some(false);
+    if needs_kpartx && command_exists("kpartx") {
+        let _ = Command::new("kpartx").args(["-u", "-s", device]).status();
+    } else {
+        let _ = Command::new("partprobe").arg(device).status();
+    }
+    if command_exists("udevadm") {
+        let _ = Command::new("udevadm").arg("settle").status();
     }
-
-    println!("  /home partition created: {}", home_device);
-    Ok(home_device)
 }
 
 fn get_partition_device(device: &str, partition_num: u32) -> Result<String> {
-    let partition_device = if device.contains("mmcblk") || device.contains("nvme") {
-        format!("{}p{}", device, partition_num)
-    } else {
-        format!("{}{}", device, partition_num)
-    };
-
-    // Wait a bit for the device to appear
-    std::thread::sleep(std::time::Duration::from_secs(2));
-
-    if !Path::new(&partition_device).exists() {
-        bail!("Partition device {} does not exist after creation", partition_device);
+    // The node of a partition that was just added comes with its uevent
+    let partition_device = sysfs::partition_path(device, partition_num);
+    // Nothing makes nodes in a container's /dev; the kernel still lists the partition in sysfs
+    if !Path::new(&partition_device).exists()
+        && !partition_device.starts_with("/dev/mapper/")
+        && container::detect_container().is_some()
+    {
+        let started = std::time::Instant::now();
+        while !container::make_node(&partition_device)? {
+            if started.elapsed() > uevent::NODE_TIMEOUT {
+                bail!("Partition {} of {} did not appear in /sys/class/block", partition_num, device);
+            }
+            std::thread::sleep(std::time::Duration::from_millis(100));
+        }
+        return Ok(partition_device);
     }
-
+    uevent::wait_for_node(&partition_device, true, uevent::NODE_TIMEOUT)
+        .context(format!("Partition {} of {} has no device node", partition_num, device))?;
     Ok(partition_device)
 }
 
-fn create_mount_points() -> Result<()> {
-    let mount_points = vec!["/mnt/root", "/mnt/var", "/mnt/home"];
+fn create_mount_points(mounts: &MountPaths) -> Result<()> {
+    let mount_points = vec![mounts.root(), mounts.var(), mounts.path("containers"), mounts.home()];
 
     for mount_point in mount_points {
-        if !Path::new(mount_point).exists() {
-            std::fs::create_dir_all(mount_point)
+        if !Path::new(&mount_point).exists() {
+            std::fs::create_dir_all(&mount_point)
                 .context(format!("Failed to create {}", mount_point))?;
             println!("  Created {}", mount_point);
         } else {
@@ -820,201 +3451,366 @@ fn create_mount_points() -> Result<()> {
     Ok(())
 }
 
-fn mount_partitions(partitions: &CreatedPartitions) -> Result<()> {
-    // Mount root partition
-    println!("  Mounting {} at /mnt/root...", partitions.root_device);
-    let status = Command::new("mount")
-        .args([&partitions.root_device, "/mnt/root"])
-        .status()
-        .context("Failed to mount root partition")?;
-
-    if !status.success() {
-        bail!("Failed to mount root partition");
-    }
+fn mount_partitions(partitions: &CreatedPartitions, mounts: &MountPaths) -> Result<()> {
+    // Mount root partition read-only so the copy phase can't modify the source
+    println!("  Mounting {} at {} (read-only)...", partitions.root_device, mounts.root());
+    mount_device(&partitions.root_device, &mounts.root(), true).context("Failed to mount root partition")?;
 
     // Mount /var partition if it exists
     if let Some(ref var_device) = partitions.var_device {
-        println!("  Mounting {} at /mnt/var...", var_device);
-        let status = Command::new("mount")
-            .args([var_device.as_str(), "/mnt/var"])
-            .status()
-            .context("Failed to mount /var partition")?;
+        println!("  Mounting {} at {}...", var_device, mounts.var());
+        mount_device(var_device, &mounts.var(), false).context("Failed to mount /var partition")?;
+    }
 
-        if !status.success() {
-            bail!("Failed to mount /var partition");
-        }
+    if let Some((ref device, _)) = partitions.containers {
+        println!("  Mounting {} at {}...", device, mounts.path("containers"));
+        mount_device(device, &mounts.path("containers"), false).context("Failed to mount container storage partition")?;
     }
 
     // Mount /home partition
-    println!("  Mounting {} at /mnt/home...", partitions.home_device);
-    let status = Command::new("mount")
-        .args([&partitions.home_device, "/mnt/home"])
-        .status()
-        .context("Failed to mount /home partition")?;
-
-    if !status.success() {
-        bail!("Failed to mount /home partition");
-    }
+    println!("  Mounting {} at {}...", partitions.home_device, mounts.home());
+    mount_device(&partitions.home_device, &mounts.home(), false).context("Failed to mount /home partition")?;
 
     println!("  All partitions mounted successfully");
     Ok(())
 }
 
-fn migrate_var_data() -> Result<()> {
-    println!("  Copying /mnt/root/var/* to /mnt/var/...");
+fn mount_at(device: &str, mount_point: &str) -> Result<()> {
+    if !Path::new(mount_point).exists() {
+        std::fs::create_dir_all(mount_point).context(format!("Failed to create {}", mount_point))?;
+    }
 
-    // Check if /mnt/root/var exists and has content
-    if !Path::new("/mnt/root/var").exists() {
-        println!("  /mnt/root/var does not exist, skipping migration");
-        return Ok(());
+    mount_device(device, mount_point, false).context(format!("Failed to mount {} at {}", device, mount_point))
+}
+
+/// Mount `device` at `mount_point`. util-linux mount refuses non-root users even
+/// with CAP_SYS_ADMIN, so capability runs use the mount syscall directly.
+fn mount_device(device: &str, mount_point: &str, read_only: bool) -> Result<()> {
+    // UDisks2 picks its own mount point; the requested one becomes a symlink to it
+    if udisks::enabled() {
+        let path = udisks::mount(device, read_only)?;
+        if Path::new(mount_point).exists() {
+            std::fs::remove_dir(mount_point).context(format!("{} must be an empty directory", mount_point))?;
+        }
+        return std::os::unix::fs::symlink(&path, mount_point)
+            .context(format!("Failed to link {} to {}", mount_point, path));
     }
 
-    // Use rsync to copy with progress
-    let status = Command::new("rsync")
-        .args([
-            "-avx",
-            "--progress",
-            "/mnt/root/var/",
-            "/mnt/var/",
-        ])
-        .status()
-        .context("Failed to run rsync for /var")?;
+    if privilege::is_capability_run() {
+        let fstype = fstab::device_fstype(device).ok_or_else(|| anyhow!("No filesystem found on {}", device))?;
+        let flags = if read_only { MsFlags::MS_RDONLY } else { MsFlags::empty() };
+        return nix::mount::mount(Some(device), mount_point, Some(fstype.as_str()), flags, None::<&str>)
+            .context(format!("mount {} {}", device, mount_point));
+    }
+
+    if let Some(result) = libmount::mount(Some(device), mount_point, read_only.then_some("ro")) {
+        return result.context(format!("mount {} {}", device, mount_point));
+    }
 
+    let mut command = Command::new("mount");
+    if read_only {
+        command.args(["-o", "ro"]);
+    }
+    let status = command.args([device, mount_point]).status().context("Failed to run mount")?;
     if !status.success() {
-        bail!("rsync failed for /var");
+        bail!("mount {} {} failed", device, mount_point);
+    }
+    Ok(())
+}
+
+/// Device mounted at `mount_point` (resolving symlinks) according to /proc/mounts
+fn mounted_device(mount_point: &str) -> Result<String> {
+    let target = std::fs::canonicalize(mount_point).context(format!("Failed to resolve {}", mount_point))?;
+    let mounts = std::fs::read_to_string("/proc/mounts").context("Failed to read /proc/mounts")?;
+    parse::proc_mounts(&mounts)
+        .into_iter()
+        .rev()
+        .find(|entry| Path::new(&entry.target) == target)
+        .map(|entry| entry.source)
+        .context(format!("Nothing is mounted at {}", mount_point))
+}
+
+fn remount(mount_point: &str, mode: &str) -> Result<()> {
+    // UDisks2 has no remount; mount again with the new mode
+    if udisks::enabled() {
+        let device = mounted_device(mount_point)?;
+        unmount(mount_point)?;
+        return mount_device(&device, mount_point, mode == "ro");
+    }
+
+    if privilege::is_capability_run() {
+        let flags = if mode == "ro" { MsFlags::MS_REMOUNT | MsFlags::MS_RDONLY } else { MsFlags::MS_REMOUNT };
+        return nix::mount::mount(None::<&str>, mount_point, None::<&str>, flags, None::<&str>)
+            .context(format!("Failed to remount {} {}", mount_point, mode));
+    }
+
+    if let Some(result) = libmount::mount(None, mount_point, Some(&format!("remount,{}", mode))) {
+        return result.context(format!("Failed to remount {} {}", mount_point, mode));
     }
 
-    println!("  Deleting /mnt/root/var/*...");
-    let status = Command::new("rm")
-        .args(["-rf", "/mnt/root/var/*"])
+    let status = Command::new("mount")
+        .args(["-o", &format!("remount,{}", mode), mount_point])
         .status()
-        .context("Failed to delete /mnt/root/var/*")?;
+        .context(format!("Failed to remount {}", mount_point))?;
 
     if !status.success() {
-        bail!("Failed to delete /mnt/root/var/*");
+        bail!("Failed to remount {} {}", mount_point, mode);
     }
 
-    println!("  /var migration complete");
     Ok(())
 }
 
-fn migrate_home_data() -> Result<()> {
-    println!("  Copying /mnt/root/home/* to /mnt/home/...");
+fn unmount(mount_point: &str) -> Result<()> {
+    let is_link = std::fs::symlink_metadata(mount_point).map(|m| m.file_type().is_symlink()).unwrap_or(false);
+    if udisks::enabled() && is_link {
+        udisks::unmount(&mounted_device(mount_point)?)?;
+        std::fs::remove_file(mount_point).context(format!("Failed to remove {}", mount_point))?;
+        return std::fs::create_dir(mount_point).context(format!("Failed to recreate {}", mount_point));
+    }
 
-    // Check if /mnt/root/home exists and has content
-    if !Path::new("/mnt/root/home").exists() {
-        println!("  /mnt/root/home does not exist, skipping migration");
-        return Ok(());
+    if privilege::is_capability_run() {
+        return nix::mount::umount(mount_point).context(format!("umount {}", mount_point));
     }
 
-    // Use rsync to copy with progress
-    let status = Command::new("rsync")
-        .args([
-            "-avx",
-            "--progress",
-            "/mnt/root/home/",
-            "/mnt/home/",
-        ])
-        .status()
-        .context("Failed to run rsync for /home")?;
+    if let Some(result) = libmount::umount(mount_point) {
+        return result.context(format!("umount {}", mount_point));
+    }
 
+    let status = Command::new("umount").arg(mount_point).status().context("Failed to run umount")?;
     if !status.success() {
-        bail!("rsync failed for /home");
+        bail!("umount {} failed", mount_point);
     }
+    Ok(())
+}
 
-    println!("  Deleting /mnt/root/home/*...");
-    let status = Command::new("rm")
-        .args(["-rf", "/mnt/root/home/*"])
-        .status()
-        .context("Failed to delete /mnt/root/home/*")?;
+fn unmount_quiet(mount_point: &str) {
+    let _ = unmount(mount_point);
+}
 
-    if !status.success() {
-        bail!("Failed to delete /mnt/root/home/*");
+/// Mount the root filesystem read-only just long enough to hash it
+fn root_manifest(fs_device: &str, mounts: &MountPaths) -> Result<verify::Manifest> {
+    with_root_read_only(fs_device, mounts, verify::build_manifest)
+}
+
+/// Run `f` on the root filesystem mounted read-only, unmounting afterwards
+fn with_root_read_only<T>(fs_device: &str, mounts: &MountPaths, f: impl FnOnce(&str) -> Result<T>) -> Result<T> {
+    let mount_point = mounts.root();
+    if !Path::new(&mount_point).exists() {
+        std::fs::create_dir_all(&mount_point).context(format!("Failed to create {}", mount_point))?;
     }
 
-    println!("  /home migration complete");
-    Ok(())
+    mount_device(fs_device, &mount_point, true).context(format!("Failed to mount {} read-only", fs_device))?;
+
+    let result = f(&mount_point);
+    unmount_quiet(&mount_point);
+    result
 }
 
-fn get_uuid(device: &str) -> Result<String> {
-    let output = Command::new("blkid")
-        .args(["-s", "UUID", "-o", "value", device])
-        .output()
-        .context(format!("Failed to get UUID for {}", device))?;
+/// Filesystem UUIDs the target's fstab already uses for /var, /home and swap
+fn previous_uuids(root: &str) -> Result<std::collections::HashMap<String, String>> {
+    let fstab_path = format!("{}/etc/fstab", root);
+    let content = std::fs::read_to_string(&fstab_path).context(format!("Failed to read {}", fstab_path))?;
+
+    let mut uuids = std::collections::HashMap::new();
+    for entry in fstab::parse_fstab(&content)? {
+        let Some(uuid) = entry.source.strip_prefix("UUID=") else {
+            continue;
+        };
+        let key = if entry.fstype == "swap" { "swap" } else { entry.target.as_str() };
+        if matches!(key, "/var" | "/home" | "swap") {
+            uuids.insert(key.to_string(), uuid.to_string());
+        }
+    }
+    Ok(uuids)
+}
 
-    if !output.status.success() {
-        bail!("Failed to get UUID for {}", device);
+/// fstab target, filesystem UUID and PARTUUID of the partitions a run created
+fn created_ids(created: &CreatedPartitions) -> Result<Vec<(&'static str, String, String)>> {
+    let mut ids = Vec::new();
+    let devices = [
+        ("swap", created.swap_device.as_deref()),
+        ("/var", created.var_device.as_deref()),
+        ("/home", Some(created.home_device.as_str())),
+    ];
+    for (target, device) in devices.into_iter().chain(created.containers.as_ref().map(|(device, target)| (*target, Some(device.as_str())))) {
+        if let Some(device) = device {
+            ids.push((target, get_uuid(device).unwrap_or_default(), relocate::get_partuuid(device)?));
+        }
     }
+    Ok(ids)
+}
 
-    let uuid = String::from_utf8_lossy(&output.stdout).trim().to_string();
-    if uuid.is_empty() {
-        bail!("UUID is empty for {}", device);
+/// Returns the number of bytes copied
+fn migrate_var_data(mounts: &MountPaths, excludes: &exclude::Excludes) -> Result<u64> {
+    copy_tree(&format!("{}/var", mounts.root()), &mounts.var(), "var", excludes)
+}
+
+/// Returns the number of bytes copied
+fn migrate_home_data(mounts: &MountPaths, excludes: &exclude::Excludes) -> Result<u64> {
+    copy_tree(&format!("{}/home", mounts.root()), &mounts.home(), "home", excludes)
+}
+
+/// Copy Docker/Podman storage at `target` (e.g. /var/lib/docker) to its partition.
+/// Returns the number of bytes copied
+fn migrate_container_storage(mounts: &MountPaths, target: &str, excludes: &exclude::Excludes) -> Result<u64> {
+    let source = format!("{}{}", mounts.root(), target);
+    copy_tree(&source, &mounts.path("containers"), target.trim_start_matches('/'), excludes)
+}
+
+/// Copy the contents of /`tree` from `source` to `dest` with the chosen copy
+/// tool, leaving out what `excludes` names. Returns the number of bytes copied
+fn copy_tree(source: &str, dest: &str, tree: &str, excludes: &exclude::Excludes) -> Result<u64> {
+    println!("  Copying {}/* to {}/...", source, dest);
+
+    // Check if the source exists and has content
+    if !Path::new(source).exists() {
+        println!("  {} does not exist, skipping migration", source);
+        return Ok(0);
     }
 
-    Ok(uuid)
+    copytool::copy(source, dest, tree, excludes, &copytool::Selection::default())?;
+    excludes.recreate_excluded_dirs(tree, source, dest)?;
+
+    let (_, bytes) = cleanup::tree_usage(dest)?;
+    println!("  /{} copy complete", tree);
+    Ok(bytes)
 }
 
-fn update_fstab(partitions: &CreatedPartitions) -> Result<()> {
-    let fstab_path = "/mnt/root/etc/fstab";
+fn get_uuid(device: &str) -> Result<String> {
+    let values = blkid::values(device).context(format!("Failed to get UUID for {}", device))?;
+    blkid::value(&values, "UUID").ok_or_else(|| anyhow!("UUID is empty for {}", device))
+}
+
+fn update_fstab(
+    partitions: &CreatedPartitions,
+    swap: &swap::SwapPlan,
+    partuuid_changes: &[(String, String)],
+    reference: FstabRef,
+    provenance: &fstab::Provenance,
+    mounts: &MountPaths,
+) -> Result<()> {
+    let fstab_path = format!("{}/etc/fstab", mounts.root());
 
     // Read existing fstab
-    let mut fstab_content = std::fs::read_to_string(fstab_path)
-        .context("Failed to read /mnt/root/etc/fstab")?;
+    let fstab_content = std::fs::read_to_string(&fstab_path)
+        .context(format!("Failed to read {}", fstab_path))?;
+
+    let boot_layout = firmware::BootLayout::detect(&mounts.root());
+    println!("  Target has its {}", boot_layout.describe());
+    firmware::check_fstab_entry(&fstab_content, boot_layout)?;
 
-    println!("  Getting UUIDs for new partitions...");
+    println!("  Getting references for new partitions...");
+
+    // Partitions are named on GPT by create_partition, so PARTLABEL needs no lookup
+    let source = |device: &str, name: &str| -> Result<String> {
+        match reference {
+            FstabRef::Uuid => Ok(format!("UUID={}", get_uuid(device)?)),
+            FstabRef::Partlabel => Ok(format!("PARTLABEL={}", part_label(name))),
+        }
+    };
 
-    // Get UUIDs for new partitions
     let mut new_entries = Vec::new();
 
     if let Some(ref swap_device) = partitions.swap_device {
-        let uuid = get_uuid(swap_device)?;
-        println!("    Swap: UUID={}", uuid);
-        new_entries.push(format!("UUID={}  none  swap  sw  0  0", uuid));
+        let swap_source = source(swap_device, "swap")?;
+        println!("    Swap: {}", swap_source);
+        new_entries.push(fstab::fstab_line(&swap_source, "none", "swap", "sw", 0));
+    }
+    if let Some(entry) = swap.fstab_entry() {
+        println!("    Swap: {}", swap::SWAP_FILE);
+        new_entries.push(entry);
     }
 
     if let Some(ref var_device) = partitions.var_device {
-        let uuid = get_uuid(var_device)?;
-        println!("    /var: UUID={}", uuid);
-        new_entries.push(format!("UUID={}  /var  btrfs  defaults  0  2", uuid));
+        let var_source = source(var_device, "/var")?;
+        println!("    /var: {}", var_source);
+
+        // A separate /var has to be mounted before journald flushes to it and tmpfiles runs
+        let version = systemd::target_systemd_version(&mounts.root());
+        match version {
+            Some(v) if v >= systemd::MIN_X_SYSTEMD_BEFORE => println!("    Target systemd {} supports x-systemd ordering options", v),
+            Some(v) => println!("    Target systemd {} is too old for x-systemd.before, using drop-ins", v),
+            None => println!("    Could not determine target systemd version, using drop-ins"),
+        }
+        let options = systemd::var_mount_options(version);
+        if options == "defaults" {
+            systemd::install_var_ordering_dropins(&mounts.root())?;
+        }
+        new_entries.push(fstab::fstab_line(&var_source, "/var", "btrfs", &options, 2));
     }
 
-    let home_uuid = get_uuid(&partitions.home_device)?;
-    println!("    /home: UUID={}", home_uuid);
-    new_entries.push(format!("UUID={}  /home  ext4  defaults  0  2", home_uuid));
-
-    // Add new entries to fstab
-    fstab_content.push_str("\n# Added by rpi-fs-shrink\n");
-    for entry in new_entries {
-        fstab_content.push_str(&format!("{}\n", entry));
+    // systemd mounts it after /var, which its path is inside of
+    if let Some((ref device, target)) = partitions.containers {
+        let containers_source = source(device, "containers")?;
+        println!("    {}: {}", target, containers_source);
+        new_entries.push(fstab::fstab_line(&containers_source, target, "ext4", "defaults", 2));
     }
 
+    let home_source = source(&partitions.home_device, "/home")?;
+    println!("    /home: {}", home_source);
+    new_entries.push(fstab::fstab_line(&home_source, "/home", "ext4", "defaults", 2));
+
+    let fstab_content = updated_fstab(&fstab_content, partuuid_changes, new_entries, &swap.disabled, Some(provenance))?;
+
     // Write updated fstab
-    std::fs::write(fstab_path, fstab_content)
-        .context("Failed to write /mnt/root/etc/fstab")?;
+    std::fs::write(&fstab_path, fstab_content)
+        .context(format!("Failed to write {}", fstab_path))?;
 
     println!("  /etc/fstab updated successfully");
     Ok(())
 }
 
-fn unmount_all() -> Result<()> {
-    let mount_points = vec!["/mnt/var", "/mnt/home", "/mnt/root"];
+/// `fstab::update`, printing what it changed
+fn updated_fstab(
+    fstab_content: &str,
+    partuuid_changes: &[(String, String)],
+    new_entries: Vec<String>,
+    disabled_swap: &[String],
+    provenance: Option<&fstab::Provenance>,
+) -> Result<String> {
+    let (updated, notes) = fstab::update(fstab_content, partuuid_changes, new_entries, disabled_swap, provenance)?;
+    for note in notes {
+        println!("  {}", note);
+    }
+    Ok(updated)
+}
+
+/// Space used and free on root and each new filesystem, while they are mounted;
+/// one that can't be read only gets a warning
+fn measure_filesystems(partitions: &CreatedPartitions, mounts: &MountPaths) -> Vec<utilization::Usage> {
+    let mut filesystems = vec![("/", partitions.root_device.clone(), mounts.root())];
+    if let Some(ref device) = partitions.var_device {
+        filesystems.push(("/var", device.clone(), mounts.var()));
+    }
+    if let Some((ref device, target)) = partitions.containers {
+        filesystems.push((target, device.clone(), mounts.path("containers")));
+    }
+    filesystems.push(("/home", partitions.home_device.clone(), mounts.home()));
+
+    filesystems
+        .into_iter()
+        .filter_map(|(target, device, path)| match utilization::measure(target, &device, &path) {
+            Ok(usage) => Some(usage),
+            Err(e) => {
+                println!("  Warning: {:#}", e);
+                None
+            }
+        })
+        .collect()
+}
+
+fn unmount_all(mounts: &MountPaths) -> Result<()> {
+    let mount_points = vec![mounts.path("containers"), mounts.var(), mounts.home(), mounts.root()];
 
     for mount_point in mount_points {
-        if Path::new(mount_point).exists() {
+        if Path::new(&mount_point).exists() {
             println!("  Unmounting {}...", mount_point);
-            let status = Command::new("umount")
-                .arg(mount_point)
-                .status();
-
-            match status {
-                Ok(s) if s.success() => {
+            match unmount(&mount_point) {
+                Ok(()) => {
                     println!("    {} unmounted", mount_point);
                 }
-                Ok(_) => {
-                    println!("    Warning: Failed to unmount {} (may not be mounted)", mount_point);
-                }
                 Err(e) => {
-                    println!("    Warning: Error unmounting {}: {}", mount_point, e);
+                    println!("    Warning: Failed to unmount {} (may not be mounted): {}", mount_point, e);
                 }
             }
         }
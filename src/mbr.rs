@@ -0,0 +1,201 @@
+use anyhow::{bail, Context, Result};
+use std::process::Command;
+
+use crate::{parse, roles, sysfs, DiskInfo, PartitionSizes};
+
+/// Entries in an MBR partition table
+pub const MAX_PRIMARY: usize = 4;
+
+/// Partition numbers parted gives logical partitions start here
+pub const FIRST_LOGICAL: u32 = 5;
+
+/// Partitions the run creates behind root, where an extended partition can take them
+pub fn created_behind_root(sizes: &PartitionSizes) -> usize {
+    [sizes.swap, sizes.var, sizes.containers, sizes.cidata].iter().flatten().count() + 1
+}
+
+/// Partitions on `disk` that stay: all but swap partitions, which the new layout takes over
+fn kept_partitions(disk: &str) -> usize {
+    roles::partition_numbers(disk)
+        .into_iter()
+        .filter(|&n| sysfs::fstype(&sysfs::partition_path(disk, n)).as_deref() != Some("swap"))
+        .count()
+}
+
+/// Number of the extended partition on `disk`, if it has one
+pub fn existing_extended(disk: &str) -> Option<u32> {
+    let output = Command::new("parted").args(["-s", disk, "unit", "s", "print"]).output().ok()?;
+    parse::parted_extended(&String::from_utf8_lossy(&output.stdout))
+}
+
+/// Whether the partitions behind root go into an extended partition as logical
+/// ones on this MBR disk: when root already is a logical partition, or when they
+/// don't fit as primaries. Refuses plans neither way can hold, before anything
+/// on the disk changes.
+pub fn plan_logical(disk: &DiskInfo, sizes: &PartitionSizes) -> Result<bool> {
+    plan_logical_with(disk, sizes, kept_partitions(&disk.device), existing_extended(&disk.device))
+}
+
+/// `plan_logical` for a disk with `kept` partitions staying and its extended
+/// partition, if any, numbered `extended`
+pub fn plan_logical_with(disk: &DiskInfo, sizes: &PartitionSizes, kept: usize, extended: Option<u32>) -> Result<bool> {
+    if disk.roles.root >= FIRST_LOGICAL {
+        if sizes.recovery.is_some() {
+            bail!(
+                "A recovery partition takes over the start of root, which on {} is a logical partition;\n\
+                it needs root as a primary partition (or use --convert-gpt)",
+                disk.device
+            );
+        }
+        return Ok(true);
+    }
+
+    // The ones it keeps and the ones the run creates
+    let needed = kept + usize::from(sizes.recovery.is_some()) + created_behind_root(sizes);
+    if needed <= MAX_PRIMARY {
+        return Ok(false);
+    }
+    if let Some(extended) = extended {
+        bail!(
+            "The plan leaves {} with {} partitions, more than the {} primaries MBR holds, and partition {}\n\
+            is an extended partition root isn't in. Use --convert-gpt to switch the disk to GPT.",
+            disk.device,
+            needed,
+            MAX_PRIMARY,
+            extended
+        );
+    }
+    // Boot, root, whatever else stays, a recovery partition and the extended one
+    let primaries = needed - created_behind_root(sizes) + 1;
+    if primaries > MAX_PRIMARY {
+        bail!(
+            "{} keeps {} primary partitions, which leaves no entry for an extended partition.\n\
+            Use --convert-gpt to switch it to GPT (the Pi 4, 400, 5 and CM4 boot from GPT; the Pi 3\n\
+            and older don't), or leave out the recovery partition.",
+            disk.device,
+            primaries - 1
+        );
+    }
+    Ok(true)
+}
+
+/// Create the extended partition new logical partitions go into
+pub fn create_extended(disk: &str, start: u64, end: u64) -> Result<u32> {
+    let output = Command::new("parted")
+        .args([disk, "unit", "s", "print"])
+        .output()
+        .context("Failed to run parted")?;
+    let existing: Vec<u32> =
+        parse::parted_partitions(&String::from_utf8_lossy(&output.stdout)).iter().map(|p| p.number).collect();
+    let number = roles::next_free_number(&existing, "msdos")
+        .ok_or_else(|| anyhow::anyhow!("{} has no free primary partition entry left for an extended partition", disk))?;
+
+    println!("  Creating extended partition {} from sector {} to {}...", number, start, end);
+    let status = Command::new("parted")
+        .args(["-s", disk, "mkpart", "extended", &format!("{}s", start), &format!("{}s", end)])
+        .status()
+        .context("Failed to create extended partition")?;
+    if !status.success() {
+        bail!("Failed to create extended partition on {}", disk);
+    }
+    crate::reread_partitions(disk);
+    Ok(number)
+}
+
+/// Grow the extended partition root sits in to `end`, making room for more logicals
+pub fn grow_extended(disk: &str, end: u64) -> Result<()> {
+    let number = existing_extended(disk).ok_or_else(|| anyhow::anyhow!("{} has a logical root but no extended partition", disk))?;
+    println!("  Growing extended partition {} to end at sector {}...", number, end);
+    let status = Command::new("parted")
+        .args(["-s", disk, "resizepart", &number.to_string(), &format!("{}s", end)])
+        .status()
+        .context("Failed to run parted resizepart")?;
+    if !status.success() {
+        bail!("Failed to grow extended partition {} of {}", number, disk);
+    }
+    crate::reread_partitions(disk);
+    Ok(())
+}
+
+/// Convert the MBR table of `disk` to GPT in place. Partitions keep their numbers
+/// and sectors; every PARTUUID changes, and the backup table goes into the last
+/// 33 sectors, which have to be free.
+pub fn convert_to_gpt(disk: &str) -> Result<()> {
+    let output = Command::new("sgdisk")
+        .args(["--mbrtogpt", disk])
+        .output()
+        .context("Failed to run sgdisk")?;
+    if !output.status.success() {
+        bail!("Failed to convert {} to GPT: {}", disk, String::from_utf8_lossy(&output.stderr).trim());
+    }
+    crate::reread_partitions(disk);
+    println!("  {} now has a GPT partition table", disk);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const GIB: u64 = 1024 * 1024 * 1024;
+
+    fn disk(root: u32) -> DiskInfo {
+        DiskInfo {
+            device: "/dev/sda".to_string(),
+            size_bytes: 64 * GIB,
+            size_sectors: 64 * GIB / crate::SECTOR_SIZE,
+            is_sd_card: false,
+            root_partition: format!("/dev/sda{}", root),
+            roles: roles::Roles { boot: 1, root, found_by: "position" },
+            partition_table: "msdos".to_string(),
+            erase_block_bytes: None,
+            identity: Default::default(),
+        }
+    }
+
+    fn sizes(recovery: bool, swap: bool, var: bool) -> PartitionSizes {
+        PartitionSizes {
+            root: 8 * GIB,
+            recovery: recovery.then_some(256 * 1024 * 1024),
+            swap: swap.then_some(2 * GIB),
+            var: var.then_some(4 * GIB),
+            containers: None,
+            cidata: None,
+        }
+    }
+
+    #[test]
+    fn partitions_behind_root_include_home() {
+        assert_eq!(created_behind_root(&sizes(false, false, false)), 1);
+        assert_eq!(created_behind_root(&sizes(true, true, true)), 3);
+    }
+
+    #[test]
+    fn primaries_until_the_table_is_full() {
+        // Boot, root, swap and /home fill the four entries
+        assert!(!plan_logical_with(&disk(2), &sizes(false, true, false), 2, None).unwrap());
+        // /var as well: boot, root and an extended partition with swap, /var and /home
+        assert!(plan_logical_with(&disk(2), &sizes(false, true, true), 2, None).unwrap());
+        // With a recovery partition the extended one takes the last entry
+        assert!(plan_logical_with(&disk(2), &sizes(true, true, false), 2, None).unwrap());
+    }
+
+    #[test]
+    fn logical_root_keeps_everything_logical() {
+        // NOOBS: recovery 1, extended 2, settings 5, boot 6, root 7
+        let noobs = DiskInfo { roles: roles::Roles { boot: 6, root: 7, found_by: "cmdline.txt" }, ..disk(7) };
+        assert!(plan_logical_with(&noobs, &sizes(false, false, false), 5, Some(2)).unwrap());
+        let err = plan_logical_with(&noobs, &sizes(true, false, false), 5, Some(2)).unwrap_err();
+        assert!(err.to_string().contains("logical partition"), "{}", err);
+    }
+
+    #[test]
+    fn full_tables_are_refused() {
+        // An extended partition root isn't in can't take the new partitions
+        let err = plan_logical_with(&disk(2), &sizes(false, true, true), 3, Some(3)).unwrap_err();
+        assert!(err.to_string().contains("partition 3"), "{}", err);
+        // Three primaries kept and a recovery partition leave no entry for the extended one
+        let err = plan_logical_with(&disk(2), &sizes(true, true, false), 3, None).unwrap_err();
+        assert!(err.to_string().contains("no entry for an extended partition"), "{}", err);
+    }
+}
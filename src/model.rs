@@ -0,0 +1,211 @@
+//! Raspberry Pi model of the target, from `--target-model` or, when running on
+//! a Pi, the device tree, and the defaults that depend on it.
+
+use anyhow::{bail, Result};
+
+use crate::timing::format_bytes;
+use crate::{Args, DiskInfo};
+
+const MIB: u64 = 1024 * 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Family {
+    Pi1,
+    Pi2,
+    Pi3,
+    Pi4,
+    Pi400,
+    Pi5,
+    Pi500,
+    Zero,
+    Zero2,
+    Cm3,
+    Cm4,
+    Cm5,
+}
+
+#[derive(Debug, Clone)]
+pub struct Model {
+    pub name: String,
+    pub family: Family,
+    /// Where the model came from, for the plan
+    pub source: &'static str,
+}
+
+impl Family {
+    pub fn name(self) -> &'static str {
+        match self {
+            Family::Pi1 => "Pi 1",
+            Family::Pi2 => "Pi 2",
+            Family::Pi3 => "Pi 3",
+            Family::Pi4 => "Pi 4",
+            Family::Pi400 => "Pi 400",
+            Family::Pi5 => "Pi 5",
+            Family::Pi500 => "Pi 500",
+            Family::Zero => "Pi Zero",
+            Family::Zero2 => "Pi Zero 2",
+            Family::Cm3 => "Pi CM3",
+            Family::Cm4 => "Pi CM4",
+            Family::Cm5 => "Pi CM5",
+        }
+    }
+
+    /// Whether the boot ROM or firmware can boot from a GPT disk
+    pub fn boots_gpt(self) -> bool {
+        matches!(self, Family::Pi4 | Family::Pi400 | Family::Pi5 | Family::Pi500 | Family::Cm4 | Family::Cm5)
+    }
+
+    /// RAM of models that only come with one size
+    pub fn fixed_ram(self) -> Option<u64> {
+        matches!(self, Family::Zero | Family::Zero2).then_some(512 * MIB)
+    }
+
+    /// Models with 512M of RAM or less, which boot from SD cards
+    fn low_memory(self) -> bool {
+        matches!(self, Family::Zero | Family::Zero2 | Family::Pi1)
+    }
+
+    fn pi5_class(self) -> bool {
+        matches!(self, Family::Pi5 | Family::Pi500 | Family::Cm5)
+    }
+
+    /// Boot partition size Raspberry Pi OS needs for kernel and initramfs updates
+    fn recommended_boot(self) -> u64 {
+        if self.boots_gpt() { 512 * MIB } else { 256 * MIB }
+    }
+}
+
+/// Read a model name, as the device tree gives it ("Raspberry Pi 4 Model B Rev 1.4")
+/// or short ("pi4", "zero2w", "cm4", "500")
+pub fn parse_model(text: &str) -> Option<Family> {
+    let lower = text.to_ascii_lowercase().replace("raspberry", "").replace("compute module", "cm");
+    let compact: String = lower.chars().filter(|c| !c.is_whitespace()).collect();
+    let rest = compact.strip_prefix("rpi").or_else(|| compact.strip_prefix("pi")).unwrap_or(&compact);
+    let family = [
+        ("zero2", Family::Zero2),
+        ("zero", Family::Zero),
+        ("cm5", Family::Cm5),
+        ("cm4", Family::Cm4),
+        ("cm3", Family::Cm3),
+        ("500", Family::Pi500),
+        ("400", Family::Pi400),
+        ("5", Family::Pi5),
+        ("4", Family::Pi4),
+        ("3", Family::Pi3),
+        ("2", Family::Pi2),
+        ("1", Family::Pi1),
+        // The first Pi has no number: "Raspberry Pi Model B Rev 2"
+        ("model", Family::Pi1),
+    ]
+    .into_iter()
+    .find(|(prefix, _)| rest.starts_with(prefix))
+    .map(|(_, family)| family)?;
+    Some(family)
+}
+
+/// The target's model: `--target-model`, or this machine when it is a Pi
+pub fn detect(hint: Option<&str>) -> Result<Option<Model>> {
+    if let Some(hint) = hint {
+        let Some(family) = parse_model(hint) else {
+            bail!("Unknown Raspberry Pi model {} (e.g. pi3, zero2w, pi4, 400, pi5, cm4)", hint);
+        };
+        return Ok(Some(Model { name: format!("Raspberry {}", family.name()), family, source: "--target-model" }));
+    }
+    let model = std::fs::read_to_string("/proc/device-tree/model").unwrap_or_default();
+    let model = model.trim_end_matches('\0').trim();
+    Ok(model
+        .starts_with("Raspberry Pi")
+        .then(|| parse_model(model))
+        .flatten()
+        .map(|family| Model { name: model.to_string(), family, source: "this Pi" }))
+}
+
+/// Fill in what the caller left to the tool (`--swap-size auto`, `--var-size auto`,
+/// the RAM of single-size models) for the target's model on `device`, and refuse
+/// options the model can't boot with. Returns each choice with its reason.
+pub fn apply_defaults(args: &mut Args, model: Option<&Model>, device: &str) -> Result<Vec<String>> {
+    let mut notes = Vec::new();
+    let is_auto = |value: &Option<String>| value.as_deref().is_some_and(|value| value.eq_ignore_ascii_case("auto"));
+    let sd_card = crpart::is_sd_card(device);
+    let nvme = device.contains("nvme");
+
+    if let Some(model) = model {
+        if args.convert_gpt && !model.family.boots_gpt() {
+            bail!("--convert-gpt: {} can't boot from a GPT disk (only the Pi 4, 400, 5, 500, CM4 and CM5 can)", model.family.name());
+        }
+        if args.target_ram.is_none()
+            && let Some(ram) = model.family.fixed_ram()
+        {
+            args.target_ram = Some(format!("{}M", ram / MIB));
+            notes.push(format!("target RAM {}: every {} has that much", format_bytes(ram), model.family.name()));
+        }
+        if is_auto(&args.swap_size) && args.swap_mode.is_none() && model.family.low_memory() && sd_card {
+            args.swap_size = None;
+            args.swap_mode = Some(crate::swap::SwapMode::Zram);
+            notes.push(format!(
+                "swap as zram (half the RAM) instead of a partition: a {} runs from its SD card, which swap would wear out",
+                model.family.name()
+            ));
+        }
+    }
+
+    if is_auto(&args.var_size) {
+        let (rule, reason) = match model {
+            Some(model) if model.family.pi5_class() && nvme => (
+                "clamp(10% of disk, 16G, 64G)",
+                format!("a {} on NVMe has the speed for databases and containers under /var", model.family.name()),
+            ),
+            Some(model) => ("clamp(5% of disk, 4G, 16G)", format!("the usual share for a {}", model.family.name())),
+            None => ("clamp(5% of disk, 4G, 16G)", "no model known, the usual share".to_string()),
+        };
+        args.var_size = Some(rule.to_string());
+        notes.push(format!("/var {}: {}", rule, reason));
+    }
+    Ok(notes)
+}
+
+/// Recommend a larger boot partition when the target's is small for its model
+pub fn check_boot_size(model: &Model, disk_info: &DiskInfo) {
+    let Ok((start, end)) = crate::get_partition_bounds(&disk_info.device, disk_info.roles.boot) else {
+        return;
+    };
+    let bytes = (end - start + 1) * crate::SECTOR_SIZE;
+    let recommended = model.family.recommended_boot();
+    // Partitions made as "256M" come out a little smaller than 256 MiB
+    if bytes < recommended * 9 / 10 {
+        println!(
+            "  Note: the boot partition has {}; {} is recommended for a {} so kernel and initramfs updates fit.\n\
+            This tool doesn't resize it; Raspberry Pi Imager's current images have that size.",
+            format_bytes(bytes),
+            format_bytes(recommended),
+            model.family.name()
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn device_tree_and_short_names_parse() {
+        for (text, expected) in [
+            ("Raspberry Pi 4 Model B Rev 1.4", Family::Pi4),
+            ("Raspberry Pi 5 Model B Rev 1.0", Family::Pi5),
+            ("Raspberry Pi 400 Rev 1.0", Family::Pi400),
+            ("Raspberry Pi Zero 2 W Rev 1.0", Family::Zero2),
+            ("Raspberry Pi Zero W Rev 1.1", Family::Zero),
+            ("Raspberry Pi 3 Model B Plus Rev 1.3", Family::Pi3),
+            ("Raspberry Pi Compute Module 4 Rev 1.0", Family::Cm4),
+            ("Raspberry Pi Model B Rev 2", Family::Pi1),
+            ("pi5", Family::Pi5),
+            ("rpi4", Family::Pi4),
+            ("zero2w", Family::Zero2),
+            ("CM5", Family::Cm5),
+            ("500", Family::Pi500),
+        ] {
+            assert_eq!(parse_model(text), Some(expected), "{}", text);
+        }
+        assert_eq!(parse_model("Rock Pi"), None);
+    }
+}
@@ -0,0 +1,157 @@
+use anyhow::{anyhow, bail, Context, Result};
+use std::path::Path;
+use std::process::Command;
+
+const DEFAULT_PORT: u16 = 10809;
+
+#[derive(Debug, PartialEq, Eq)]
+pub struct NbdTarget {
+    pub host: String,
+    pub port: u16,
+    pub export: Option<String>,
+}
+
+/// Parse `nbd://host[:port][/export]`; None if `device` is not an NBD URL
+pub fn parse_nbd_url(device: &str) -> Option<Result<NbdTarget>> {
+    let rest = device.strip_prefix("nbd://")?;
+    let (authority, export) = match rest.split_once('/') {
+        Some((authority, export)) if !export.is_empty() => (authority, Some(export.to_string())),
+        Some((authority, _)) => (authority, None),
+        None => (rest, None),
+    };
+
+    // A bracketed IPv6 address without a port ends in ']'
+    let port_split = if authority.ends_with(']') { None } else { authority.rsplit_once(':') };
+    let (host, port) = match port_split {
+        Some((host, port)) => match port.parse() {
+            Ok(port) => (host, port),
+            Err(_) => return Some(Err(anyhow!("Invalid port in {}", device))),
+        },
+        None => (authority, DEFAULT_PORT),
+    };
+    if host.is_empty() || host == "[]" {
+        return Some(Err(anyhow!("No host in {}", device)));
+    }
+    // Without brackets an IPv6 address's last group would be read as the port
+    if host.contains(':') && !(host.starts_with('[') && host.ends_with(']')) {
+        return Some(Err(anyhow!("IPv6 hosts go in brackets, e.g. nbd://[{}]:{}/export", authority, DEFAULT_PORT)));
+    }
+
+    Some(Ok(NbdTarget { host: host.trim_matches(['[', ']']).to_string(), port, export }))
+}
+
+/// Load the nbd module with partition support and return the first unused /dev/nbdN
+fn free_nbd_device() -> Result<String> {
+    if !Path::new("/sys/module/nbd").exists() {
+        let status = Command::new("modprobe")
+            .args(["nbd", "max_part=16"])
+            .status()
+            .context("Failed to run modprobe")?;
+        if !status.success() {
+            bail!("Failed to load the nbd kernel module");
+        }
+    }
+
+    let max_part = std::fs::read_to_string("/sys/module/nbd/parameters/max_part").unwrap_or_default();
+    if max_part.trim() == "0" {
+        bail!("The nbd module was loaded without partition support; reload it with `modprobe -r nbd && modprobe nbd max_part=16`");
+    }
+
+    for index in 0..64 {
+        let name = format!("nbd{}", index);
+        if !Path::new(&format!("/sys/block/{}", name)).exists() {
+            break;
+        }
+        // A connected device has a pid file for its client
+        if !Path::new(&format!("/sys/block/{}/pid", name)).exists() {
+            return Ok(format!("/dev/{}", name));
+        }
+    }
+    bail!("No free /dev/nbd device")
+}
+
+/// Connect an NBD export and return the local device
+pub fn connect(target: &NbdTarget) -> Result<String> {
+    let device = free_nbd_device()?;
+
+    let port = target.port.to_string();
+    let mut args = vec![target.host.as_str(), port.as_str(), device.as_str()];
+    if let Some(ref export) = target.export {
+        args.extend(["-N", export.as_str()]);
+    }
+
+    let output = Command::new("nbd-client")
+        .args(&args)
+        .output()
+        .context("Failed to run nbd-client")?;
+
+    if !output.status.success() {
+        bail!(
+            "Failed to connect nbd://{}:{} to {}: {}",
+            target.host,
+            target.port,
+            device,
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+
+    // Partitions show up asynchronously
+    crate::reread_partitions(&device);
+    println!("  Connected nbd://{}:{} to {}", target.host, target.port, device);
+    Ok(device)
+}
+
+pub fn disconnect(device: &str) -> Result<()> {
+    let status = Command::new("nbd-client")
+        .args(["-d", device])
+        .status()
+        .context("Failed to run nbd-client")?;
+
+    if !status.success() {
+        bail!("Failed to disconnect {}", device);
+    }
+
+    println!("  Disconnected {}", device);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn target(host: &str, port: u16, export: Option<&str>) -> NbdTarget {
+        NbdTarget { host: host.to_string(), port, export: export.map(str::to_string) }
+    }
+
+    #[test]
+    fn urls_without_port_or_export_get_the_defaults() {
+        assert_eq!(parse_nbd_url("nbd://pi-images").unwrap().unwrap(), target("pi-images", DEFAULT_PORT, None));
+        assert_eq!(parse_nbd_url("nbd://10.0.0.5:10810/card").unwrap().unwrap(), target("10.0.0.5", 10810, Some("card")));
+        // An empty export is the server's default one
+        assert_eq!(parse_nbd_url("nbd://pi-images/").unwrap().unwrap(), target("pi-images", DEFAULT_PORT, None));
+        assert_eq!(parse_nbd_url("nbd://pi-images:10810/").unwrap().unwrap(), target("pi-images", 10810, None));
+    }
+
+    #[test]
+    fn ipv6_hosts_are_bracketed() {
+        assert_eq!(parse_nbd_url("nbd://[fd00::5]").unwrap().unwrap(), target("fd00::5", DEFAULT_PORT, None));
+        assert_eq!(parse_nbd_url("nbd://[fd00::5]:10810/card").unwrap().unwrap(), target("fd00::5", 10810, Some("card")));
+        assert!(parse_nbd_url("nbd://fd00::5").unwrap().is_err());
+        assert!(parse_nbd_url("nbd://[]:10809").unwrap().is_err());
+    }
+
+    #[test]
+    fn bad_ports_and_missing_hosts_are_refused() {
+        for url in ["nbd://pi-images:", "nbd://pi-images:nbd", "nbd://pi-images:70000", "nbd://:10809/card", "nbd:///card"] {
+            assert!(parse_nbd_url(url).unwrap().is_err(), "{}", url);
+        }
+    }
+
+    #[test]
+    fn devices_are_not_urls() {
+        // A connected /dev/nbdX is used as it is
+        for device in ["/dev/nbd0", "/dev/sda", "disk.img", "NBD://pi-images", "http://pi-images/card"] {
+            assert!(parse_nbd_url(device).is_none(), "{}", device);
+        }
+    }
+}
@@ -0,0 +1,145 @@
+use anyhow::{Context, Result};
+use clap::ValueEnum;
+use std::os::unix::fs::MetadataExt;
+use std::path::Path;
+
+use crate::exclude::Excludes;
+
+/// In sample mode every directory is checked, and one in this many other entries
+const SAMPLE_EVERY: u64 = 50;
+
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OwnershipCheck {
+    /// Every directory and one in 50 files
+    Sample,
+    /// Every file
+    All,
+    /// No check
+    Off,
+}
+
+/// Compare owner, group, mode and type of the copy of /`tree` at `dest` with its
+/// source. Returns a line per difference, with entries counted in `checked`.
+pub fn compare_tree(
+    tree: &str,
+    source: &str,
+    dest: &str,
+    excludes: &Excludes,
+    mode: OwnershipCheck,
+    checked: &mut u64,
+) -> Result<Vec<String>> {
+    let mut problems = Vec::new();
+    if mode == OwnershipCheck::Off || !Path::new(source).is_dir() {
+        return Ok(problems);
+    }
+    let device = std::fs::symlink_metadata(source).context(format!("Failed to read {}", source))?.dev();
+    let mut pending = vec![String::new()];
+    let mut seen = 0u64;
+    while let Some(dir) = pending.pop() {
+        let full = format!("{}/{}", source, dir);
+        for entry in std::fs::read_dir(&full).context(format!("Failed to read {}", full))? {
+            let entry = entry?;
+            let meta = entry.metadata()?;
+            let relative = format!("{}{}", dir, entry.file_name().to_string_lossy());
+            let is_dir = meta.is_dir();
+            // Other filesystems aren't copied (rsync -x)
+            if is_dir && meta.dev() != device {
+                continue;
+            }
+            let excluded = excludes.is_excluded(tree, &relative, is_dir);
+            if excluded && !is_dir {
+                continue;
+            }
+            if is_dir && !excluded {
+                pending.push(format!("{}/", relative));
+            }
+
+            seen += 1;
+            if mode == OwnershipCheck::Sample && !is_dir && seen % SAMPLE_EVERY != 0 {
+                continue;
+            }
+            *checked += 1;
+            let path = format!("/{}/{}", tree, relative);
+            match std::fs::symlink_metadata(format!("{}/{}", dest, relative)) {
+                Ok(copy) => problems.extend(compare(&path, &meta, &copy)),
+                Err(_) => problems.push(format!("{}: missing from the copy", path)),
+            }
+        }
+    }
+    Ok(problems)
+}
+
+fn compare(path: &str, original: &std::fs::Metadata, copy: &std::fs::Metadata) -> Option<String> {
+    let mut differences = Vec::new();
+    if original.file_type() != copy.file_type() {
+        differences.push("file type differs".to_string());
+    }
+    if (original.uid(), original.gid()) != (copy.uid(), copy.gid()) {
+        differences.push(format!("owner {}:{} -> {}:{}", original.uid(), original.gid(), copy.uid(), copy.gid()));
+    }
+    // Symlink modes are meaningless
+    if !original.file_type().is_symlink() && original.mode() & 0o7777 != copy.mode() & 0o7777 {
+        differences.push(format!("mode {:o} -> {:o}", original.mode() & 0o7777, copy.mode() & 0o7777));
+    }
+    (!differences.is_empty()).then(|| format!("{}: {}", path, differences.join(", ")))
+}
+
+/// Check that the home directories on the new /home (mounted at `home`) belong
+/// to the users of the target root mounted at `root`
+pub fn check_home_owners(root: &str, home: &str) -> Result<Vec<String>> {
+    let passwd = std::fs::read_to_string(format!("{}/etc/passwd", root)).context("Failed to read target /etc/passwd")?;
+    let mut problems = Vec::new();
+    for line in passwd.lines() {
+        let fields: Vec<&str> = line.split(':').collect();
+        if fields.len() < 6 {
+            continue;
+        }
+        let Some(name) = fields[5].strip_prefix("/home/").filter(|name| !name.contains('/')) else {
+            continue;
+        };
+        let (uid, gid) = (fields[2].parse::<u32>().unwrap_or(0), fields[3].parse::<u32>().unwrap_or(0));
+        let Ok(meta) = std::fs::metadata(format!("{}/{}", home, name)) else {
+            // Created on first login, or by userconf on first boot
+            continue;
+        };
+        if meta.uid() != uid {
+            problems.push(format!("/home/{}: owned by uid {}, but user {} has uid {}", name, meta.uid(), fields[0], uid));
+        } else if meta.gid() != gid {
+            problems.push(format!("/home/{}: group {}, but user {} has primary group {}", name, meta.gid(), fields[0], gid));
+        }
+    }
+    Ok(problems)
+}
+
+/// Check that the log directories on the new /var (mounted at `var`) are
+/// writable by the accounts that log there: root for /var/log, and the
+/// systemd-journal group for the persistent journal
+pub fn check_var_log(root: &str, var: &str) -> Result<Vec<String>> {
+    let mut problems = Vec::new();
+    let log = format!("{}/log", var);
+    let Ok(meta) = std::fs::metadata(&log) else {
+        return Ok(problems);
+    };
+    if meta.uid() != 0 || meta.mode() & 0o200 == 0 {
+        problems.push(format!("/var/log: owner uid {} mode {:o}, root can't write to it", meta.uid(), meta.mode() & 0o7777));
+    }
+
+    let journal = format!("{}/journal", log);
+    if let Ok(meta) = std::fs::metadata(&journal) {
+        let group = std::fs::read_to_string(format!("{}/etc/group", root)).unwrap_or_default();
+        let journal_gid = group
+            .lines()
+            .map(|line| line.split(':').collect::<Vec<_>>())
+            .find(|fields| fields.len() >= 3 && fields[0] == "systemd-journal")
+            .and_then(|fields| fields[2].parse::<u32>().ok());
+        if let Some(gid) = journal_gid {
+            if meta.gid() != gid {
+                problems.push(format!("/var/log/journal: group {}, expected systemd-journal ({})", meta.gid(), gid));
+            }
+            if meta.mode() & 0o2000 == 0 {
+                problems.push("/var/log/journal: setgid bit missing, new journal files won't get the systemd-journal group".to_string());
+            }
+        }
+    }
+    Ok(problems)
+}
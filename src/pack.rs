@@ -0,0 +1,159 @@
+//! Closing the gaps deleted partitions leave (`pack`): every partition behind a
+//! gap moves toward the front with journaled, verified block moves, so the free
+//! space ends up as one region at the end of the disk, where a run can put
+//! /home. Each partition moves on its own; when one is interrupted, the next
+//! pack finishes it from the journal, and the one after that moves the rest.
+
+use anyhow::{bail, Context, Result};
+
+use crate::imgshrink::GPT_BACKUP_SECTORS;
+use crate::shrinkpart::recreate_keeping_entry;
+use crate::{audit, parse, prompt, relocate, sysfs, SECTOR_SIZE};
+
+struct Move {
+    number: u32,
+    device: String,
+    fstype: String,
+    from: u64,
+    to: u64,
+    sectors: u64,
+}
+
+/// Partitions of `device` to move, front to back, and the free region they leave
+fn plan(disk_info: &crate::DiskInfo) -> Result<(Vec<Move>, u64, u64)> {
+    let mut partitions = Vec::new();
+    for number in crate::roles::partition_numbers(&disk_info.device) {
+        let (start, end) = crate::get_partition_bounds(&disk_info.device, number)?;
+        partitions.push((number, start, end));
+    }
+    partitions.sort_by_key(|&(_, start, _)| start);
+    let Some(&(_, _, first_end)) = partitions.first() else {
+        bail!("{} has no partitions", disk_info.device);
+    };
+
+    let alignment = disk_info.alignment();
+    let mut moves = Vec::new();
+    let mut previous_end = first_end;
+    for &(number, start, end) in &partitions[1..] {
+        let to = crate::align_sector(previous_end + 1, alignment);
+        let sectors = end - start + 1;
+        if to < start {
+            let device = crate::get_partition_device(&disk_info.device, number)?;
+            let fstype = sysfs::fstype(&device).unwrap_or_default();
+            moves.push(Move { number, device, fstype, from: start, to, sectors });
+            previous_end = to + sectors - 1;
+        } else {
+            previous_end = end;
+        }
+    }
+    let last_usable = disk_info.size_sectors - 1 - if disk_info.partition_table == "gpt" { GPT_BACKUP_SECTORS } else { 0 };
+    let free_start = crate::align_sector(previous_end + 1, alignment);
+    Ok((moves, free_start, last_usable))
+}
+
+/// Close the gaps between the partitions of `device`
+pub fn pack(device: &str, dry_run: bool, resume_unidentified: bool, audit_log: Option<&str>) -> Result<()> {
+    let disk_info = crate::get_disk_info(device)?;
+    // A partition an earlier pack left half moved is finished before the gaps are planned
+    let journal = relocate::lock_journal(&disk_info, dry_run)?;
+    relocate::resume_interrupted_move(&journal, &disk_info, dry_run, resume_unidentified)?;
+    if crate::mbr::existing_extended(&disk_info.device).is_some() {
+        bail!("{} has an extended partition; pack only moves primary and GPT partitions", disk_info.device);
+    }
+    let (moves, free_start, last_usable) = plan(&disk_info)?;
+    let free_mb = (last_usable + 1).saturating_sub(free_start) * SECTOR_SIZE / (1024 * 1024);
+    if moves.is_empty() {
+        println!("{}: no gaps between partitions; {} MB free behind the last one", disk_info.device, free_mb);
+        return Ok(());
+    }
+
+    println!("Packing {}:", disk_info.device);
+    for entry in &moves {
+        println!(
+            "  Partition {} ({}, {} MB): sector {} -> {}",
+            entry.number,
+            if entry.fstype.is_empty() { "no filesystem" } else { &entry.fstype },
+            entry.sectors * SECTOR_SIZE / (1024 * 1024),
+            entry.from,
+            entry.to
+        );
+    }
+    println!("  Free afterwards: sectors {} - {} ({} MB in one region)", free_start, last_usable, free_mb);
+
+    // mkpart takes the lowest free number, so a moved partition only keeps its
+    // number when every number below it is in use
+    let numbers = crate::roles::partition_numbers(&disk_info.device);
+    for entry in &moves {
+        if let Some(hole) = (1..entry.number).find(|number| !numbers.contains(number)) {
+            bail!(
+                "Partition number {} is unused, so partition {} would come back renumbered;\n\
+                renumber the partitions first (sfdisk --reorder {}, or sgdisk --sort {})",
+                hole,
+                entry.number,
+                disk_info.device,
+                disk_info.device
+            );
+        }
+    }
+    if crate::is_active_root_disk(&disk_info.device)? {
+        bail!("{} holds the running system; pack it from another system", disk_info.device);
+    }
+    let mounts = std::fs::read_to_string("/proc/mounts").context("Failed to read /proc/mounts")?;
+    let mounted = parse::proc_mounts(&mounts);
+    for entry in &moves {
+        if let Some(mount) = mounted.iter().find(|mount| mount.source == entry.device) {
+            bail!("Partition {} ({}) is mounted at {}; unmount it first", entry.number, entry.device, mount.target);
+        }
+    }
+    if disk_info.partition_table == "gpt" && !crate::command_exists("sgdisk") {
+        bail!("Packing GPT disks needs sgdisk (gdisk package) to keep the PARTUUIDs");
+    }
+    if dry_run {
+        println!("\nDry run: nothing changed");
+        return Ok(());
+    }
+
+    println!("\nWARNING: This will move partitions on {}!", disk_info.device);
+    prompt::wait_for_enter("Press Enter to continue or Ctrl+C to cancel...")?;
+
+    let audit = match audit_log {
+        Some(path) => audit::AuditLog::open(path, &disk_info.device, &disk_info.identity)?,
+        None => audit::AuditLog::disabled(),
+    };
+    // Front to back: each partition moves into space nothing behind it still uses
+    for (index, entry) in moves.iter().enumerate() {
+        println!("\nStep {}: Moving partition {}...", index + 1, entry.number);
+        let uuid = crate::get_uuid(&entry.device).ok();
+        let end = entry.to + entry.sectors - 1;
+        audit.record(
+            "move-partition",
+            &format!("partition {} sectors {}-{} to {}-{}", entry.number, entry.from, entry.from + entry.sectors - 1, entry.to, end),
+        )?;
+        let part = relocate::PartitionMove {
+            number: entry.number,
+            fstype: entry.fstype.clone(),
+            from: entry.from,
+            to: entry.to,
+            bytes: entry.sectors * SECTOR_SIZE,
+            end,
+        };
+        relocate::move_partition_data(&journal, &disk_info, &part, "pack")?;
+        recreate_keeping_entry(&disk_info, entry.number, &entry.fstype, entry.to, end)?;
+        relocate::finish_move(&journal)?;
+
+        if crate::get_partition_bounds(&disk_info.device, entry.number)? != (entry.to, end) {
+            bail!("Partition {} didn't come back at sectors {}-{}; check the table with parted before using the disk", entry.number, entry.to, end);
+        }
+        let device = crate::get_partition_device(&disk_info.device, entry.number)?;
+        if let Some(uuid) = uuid {
+            let now = crate::get_uuid(&device).unwrap_or_default();
+            if now != uuid {
+                bail!("Partition {} has filesystem UUID {:?} after the move, expected {}", entry.number, now, uuid);
+            }
+            println!("  {} found at its new place (UUID {})", entry.fstype, uuid);
+        }
+    }
+    audit.record("pack-complete", &format!("free sectors {}-{}", free_start, last_usable))?;
+    println!("\n=== {} packed: {} MB free from sector {} ===", disk_info.device, free_mb, free_start);
+    Ok(())
+}
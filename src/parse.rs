@@ -0,0 +1,247 @@
+//! Parsers for the text printed by parted, blkid and lsblk and for /proc/mounts.
+//!
+//! This output varies between tool versions and locales and is not trusted, so
+//! nothing here may panic. The module only depends on std so the fuzz targets in
+//! `fuzz/` can include it directly.
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PartedPartition {
+    pub number: u32,
+    /// Start and end when printed in a whole unit (`unit s` or `unit B`)
+    pub start: Option<u64>,
+    pub end: Option<u64>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MountEntry {
+    pub source: String,
+    pub target: String,
+    pub fstype: String,
+}
+
+/// Integer value of a parted field like "8192s" or "1048576B"
+fn whole_unit(field: &str) -> Option<u64> {
+    let digits = field.strip_suffix('s').or_else(|| field.strip_suffix('B'))?;
+    if digits.is_empty() || !digits.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    digits.parse().ok()
+}
+
+/// Disk size from the "Disk /dev/sda: 1000204886016B" line of `parted unit B print`
+pub fn parted_disk_size_bytes(output: &str) -> Option<u64> {
+    output.lines().find_map(|line| {
+        let rest = line.trim().strip_prefix("Disk /")?;
+        let (_, size) = rest.split_once(':')?;
+        whole_unit(size.trim())
+    })
+}
+
+/// Partition rows of `parted print`: every line whose first field is a number
+pub fn parted_partitions(output: &str) -> Vec<PartedPartition> {
+    output
+        .lines()
+        .filter_map(|line| {
+            let mut fields = line.split_whitespace();
+            let number = fields.next()?.parse::<u32>().ok()?;
+            let start = fields.next().and_then(whole_unit);
+            let end = fields.next().and_then(whole_unit);
+            Some(PartedPartition { number, start, end })
+        })
+        .collect()
+}
+
+/// Number of the extended partition in `parted print` output of an MBR disk,
+/// where Type follows the Number, Start, End and Size columns
+pub fn parted_extended(output: &str) -> Option<u32> {
+    output.lines().find_map(|line| {
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        match fields.as_slice() {
+            [number, _, _, _, "extended", ..] => number.parse().ok(),
+            _ => None,
+        }
+    })
+}
+
+/// Partition table type from `parted -m print`: "BYT;" then "path:size:transport:lss:pss:label:model:flags;"
+pub fn parted_machine_label(output: &str) -> Option<String> {
+    let label = output.lines().nth(1)?.split(':').nth(5)?.trim();
+    (!label.is_empty()).then(|| label.to_string())
+}
+
+/// Value printed by `blkid -s TAG -o value`, `blkid -o device` or `lsblk -dno COLUMN`.
+/// Only the first non-empty line counts when several devices match.
+pub fn first_value(output: &str) -> Option<String> {
+    output.lines().map(str::trim).find(|line| !line.is_empty()).map(|line| line.to_string())
+}
+
+/// Devices in `blkid -o export` output: blocks of KEY=value lines separated by
+/// blank lines, each starting with DEVNAME
+pub fn blkid_export(output: &str) -> Vec<Vec<(String, String)>> {
+    let mut devices = Vec::new();
+    let mut current = Vec::new();
+    for line in output.lines() {
+        let line = line.trim();
+        if line.is_empty() {
+            if !current.is_empty() {
+                devices.push(std::mem::take(&mut current));
+            }
+            continue;
+        }
+        if let Some((key, value)) = line.split_once('=') {
+            current.push((key.to_string(), value.to_string()));
+        }
+    }
+    if !current.is_empty() {
+        devices.push(current);
+    }
+    devices
+}
+
+/// Devices in the plain output of busybox's blkid, one per line:
+/// `/dev/sda1: LABEL="boot" UUID="1234-ABCD" TYPE="vfat"`. Each comes back
+/// like a `blkid -o export` block, starting with DEVNAME.
+pub fn blkid_lines(output: &str) -> Vec<Vec<(String, String)>> {
+    let mut devices = Vec::new();
+    for line in output.lines() {
+        let Some((device, mut rest)) = line.split_once(": ") else {
+            continue;
+        };
+        let mut values = vec![("DEVNAME".to_string(), device.trim().to_string())];
+        while let Some((key, after)) = rest.split_once("=\"") {
+            let Some((value, tail)) = after.split_once('"') else {
+                break;
+            };
+            values.push((key.trim().to_string(), value.to_string()));
+            rest = tail;
+        }
+        devices.push(values);
+    }
+    devices
+}
+
+/// Undo the octal escapes (`\040` for space and so on) the kernel uses in /proc/mounts
+fn unescape_mount_field(field: &str) -> String {
+    let bytes = field.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'\\' && i + 3 < bytes.len() && bytes[i + 1..i + 4].iter().all(|b| (b'0'..=b'7').contains(b)) {
+            let value = (bytes[i + 1] - b'0') as u32 * 64 + (bytes[i + 2] - b'0') as u32 * 8 + (bytes[i + 3] - b'0') as u32;
+            if let Ok(byte) = u8::try_from(value) {
+                out.push(byte);
+                i += 4;
+                continue;
+            }
+        }
+        out.push(bytes[i]);
+        i += 1;
+    }
+    String::from_utf8_lossy(&out).to_string()
+}
+
+/// Entries of /proc/mounts (or /proc/self/mounts)
+pub fn proc_mounts(content: &str) -> Vec<MountEntry> {
+    content
+        .lines()
+        .filter_map(|line| {
+            let mut fields = line.split_whitespace();
+            let source = fields.next()?;
+            let target = fields.next()?;
+            let fstype = fields.next().unwrap_or("");
+            Some(MountEntry {
+                source: unescape_mount_field(source),
+                target: unescape_mount_field(target),
+                fstype: fstype.to_string(),
+            })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PARTED_SECTORS: &str = "\
+Model: Generic STORAGE DEVICE (scsi)
+Disk /dev/sda: 62333952s
+Sector size (logical/physical): 512B/512B
+Partition Table: msdos
+Disk Flags:
+
+Number  Start     End        Size       Type      File system  Flags
+ 1      8192s     1056767s   1048576s   primary   fat32        lba
+ 2      1056768s  17833983s  16777216s  primary   ext4
+";
+
+    #[test]
+    fn parted_rows_and_disk_size() {
+        assert_eq!(parted_disk_size_bytes(PARTED_SECTORS), Some(62333952));
+        assert_eq!(parted_disk_size_bytes("Disk /dev/sda: 31.9GB\n"), None);
+        assert_eq!(parted_disk_size_bytes("Error: unrecognised disk label\n"), None);
+        assert_eq!(
+            parted_partitions(PARTED_SECTORS),
+            vec![
+                PartedPartition { number: 1, start: Some(8192), end: Some(1056767) },
+                PartedPartition { number: 2, start: Some(1056768), end: Some(17833983) },
+            ]
+        );
+        // Other units keep the row but not the bounds; junk isn't a row
+        let human = " 1      4194kB  541MB  537MB  primary  fat32  lba\n 2  s  -5s\nNumber Start\n";
+        assert_eq!(
+            parted_partitions(human),
+            vec![PartedPartition { number: 1, start: None, end: None }, PartedPartition { number: 2, start: None, end: None }]
+        );
+    }
+
+    #[test]
+    fn parted_extended_and_machine_label() {
+        let noobs = " 1  8192s  137215s  129024s  primary  fat32  lba\n 2  137216s  62333951s  62196736s  extended  lba\n";
+        assert_eq!(parted_extended(noobs), Some(2));
+        assert_eq!(parted_extended(PARTED_SECTORS), None);
+        assert_eq!(parted_machine_label("BYT;\n/dev/sda:62333952s:scsi:512:512:msdos:Generic STORAGE DEVICE:;\n").as_deref(), Some("msdos"));
+        assert_eq!(parted_machine_label("BYT;\n/dev/sda:62333952s:scsi:512:512::Generic:;\n"), None);
+        assert_eq!(parted_machine_label("BYT;\n"), None);
+    }
+
+    #[test]
+    fn blkid_values_and_blocks() {
+        assert_eq!(first_value("\n  6c586e13-02 \n6c586e13-03\n").as_deref(), Some("6c586e13-02"));
+        assert_eq!(first_value(" \n"), None);
+
+        let export = "DEVNAME=/dev/sda1\nUUID=1234-ABCD\nTYPE=vfat\n\n\nDEVNAME=/dev/sda2\nLABEL=root=fs\nno equals sign\n";
+        let devices = blkid_export(export);
+        assert_eq!(devices.len(), 2);
+        assert_eq!(devices[0][1], ("UUID".to_string(), "1234-ABCD".to_string()));
+        // Only the first '=' separates; lines without one are dropped
+        assert_eq!(devices[1], vec![("DEVNAME".into(), "/dev/sda2".into()), ("LABEL".into(), "root=fs".into())]);
+        assert!(blkid_export("").is_empty());
+
+        let busybox = "/dev/sda1: LABEL=\"boot fs\" UUID=\"1234-ABCD\" TYPE=\"vfat\"\n/dev/sda2: UUID=\"unterminated\nnot a device line\n";
+        let devices = blkid_lines(busybox);
+        assert_eq!(devices.len(), 2);
+        assert_eq!(devices[0][1], ("LABEL".to_string(), "boot fs".to_string()));
+        assert_eq!(devices[0].len(), 4);
+        assert_eq!(devices[1], vec![("DEVNAME".to_string(), "/dev/sda2".to_string())]);
+    }
+
+    #[test]
+    fn proc_mounts_unescapes_spaces_and_skips_short_lines() {
+        let mounts = "\
+/dev/mmcblk0p2 / ext4 rw,noatime 0 0
+/dev/sda1 /media/pi/My\\040Disk vfat rw 0 0
+/dev/sdb1 /mnt/back\\134slash\\011tab ext4 rw 0 0
+/dev/sdc1 /mnt/odd\\08 ext4 rw 0 0
+/dev/sdd1 /mnt/end\\04
+lonely
+";
+        let entries = proc_mounts(mounts);
+        let targets: Vec<&str> = entries.iter().map(|entry| entry.target.as_str()).collect();
+        assert_eq!(targets, ["/", "/media/pi/My Disk", "/mnt/back\\slash\ttab", "/mnt/odd\\08", "/mnt/end\\04"]);
+        assert_eq!(entries[0], MountEntry { source: "/dev/mmcblk0p2".into(), target: "/".into(), fstype: "ext4".into() });
+        // A line cut short has no filesystem type
+        assert_eq!(entries[4].fstype, "");
+        // \777 doesn't fit a byte and stays as it is
+        assert_eq!(proc_mounts("a /x\\777 ext4")[0].target, "/x\\777");
+    }
+}
@@ -0,0 +1,480 @@
+use anyhow::{bail, Context, Result};
+use serde_json::{json, Value};
+use std::os::unix::fs::FileTypeExt;
+
+use crate::identity::DiskIdentity;
+use crate::stack::RootStack;
+use crate::{sysfs, DiskInfo, MountPaths, PartitionLayout, SECTOR_SIZE};
+
+/// Bumped when the plan file changes shape
+const PLAN_VERSION: u64 = 1;
+
+/// GPT partition names the tool gives, and what a partition of each is called in a plan
+const PART_NAMES: &[(&str, &str)] = &[
+    ("rootfs", "root"),
+    ("recovery", "recovery"),
+    ("swap", "swap"),
+    ("var", "var"),
+    ("containers", "containers"),
+    ("cidata", "cidata"),
+    ("home", "home"),
+];
+
+fn partition(name: &str, start: u64, end: u64, fstype: &str) -> Value {
+    json!({
+        "name": name,
+        "start": start,
+        "end": end,
+        "bytes": (end - start + 1) * SECTOR_SIZE,
+        "fstype": fstype,
+    })
+}
+
+/// The disk as the plan leaves it, for --plan-json. Root keeps the stack it
+/// has, so its type is what the partition holds (LUKS, LVM or ext4), as a disk
+/// reads back.
+pub fn plan_json(
+    disk_info: &DiskInfo,
+    boot: (u64, u64),
+    layout: &PartitionLayout,
+    swap: &crate::swap::SwapPlan,
+    root_stack: &RootStack,
+) -> Value {
+    let mut partitions = vec![partition("boot", boot.0, boot.1, "vfat")];
+    if layout.recovery_size_bytes > 0 {
+        partitions.push(partition("recovery", layout.recovery_start, layout.recovery_end, "vfat"));
+    }
+    partitions.push(partition("root", layout.root_start, layout.root_end, root_stack.partition_fstype()));
+    if layout.logical() {
+        partitions.push(partition("extended", layout.extended_start, layout.extended_end, ""));
+    }
+    let optional = [
+        ("swap", layout.swap_size_bytes, layout.swap_start, layout.swap_end, "swap"),
+        ("var", layout.var_size_bytes, layout.var_start, layout.var_end, "btrfs"),
+        ("containers", layout.containers_size_bytes, layout.containers_start, layout.containers_end, "ext4"),
+        ("cidata", layout.cidata_size_bytes, layout.cidata_start, layout.cidata_end, "vfat"),
+    ];
+    for (name, bytes, start, end, fstype) in optional {
+        if bytes > 0 {
+            partitions.push(partition(name, start, end, fstype));
+        }
+    }
+    partitions.push(partition("home", layout.home_start, layout.home_end, "ext4"));
+
+    json!({
+        "version": PLAN_VERSION,
+        "device": disk_info.device,
+        "disk": disk_info.identity.to_json(),
+        "size_bytes": disk_info.size_bytes,
+        "partition_table": disk_info.partition_table,
+        "alignment": layout.alignment,
+        "swap": { "mode": swap.mode.name(), "bytes": swap.size },
+        "partitions": partitions,
+    })
+}
+
+pub fn write_plan(path: &str, plan: &Value) -> Result<()> {
+    std::fs::write(path, format!("{:#}\n", plan)).context(format!("Failed to write plan {}", path))?;
+    println!("\nPlan written to {}", path);
+    Ok(())
+}
+
+/// What a partition already on the disk is called in a plan: by role for boot
+/// and root, by GPT name where the tool named it, else by filesystem
+fn disk_partition_name(disk_info: &DiskInfo, number: u32, fstype: &str, after_root: bool) -> String {
+    if number == disk_info.roles.boot {
+        return "boot".to_string();
+    }
+    if number == disk_info.roles.root {
+        return "root".to_string();
+    }
+    let path = sysfs::partition_path(&disk_info.device, number);
+    if let Some(label) = sysfs::lsblk_value(&path, "PARTLABEL")
+        && let Some((_, name)) = PART_NAMES.iter().find(|(part_label, _)| *part_label == label)
+    {
+        return name.to_string();
+    }
+    match (fstype, after_root) {
+        ("swap", _) => "swap".to_string(),
+        ("btrfs", _) => "var".to_string(),
+        ("vfat", false) => "recovery".to_string(),
+        ("vfat", true) => "cidata".to_string(),
+        _ => format!("partition {}", number),
+    }
+}
+
+/// The disk as it is, in the shape of a plan file
+pub fn disk_json(device: &str) -> Result<Value> {
+    let disk_info = crate::get_disk_info(device)?;
+    let extended = crate::mbr::existing_extended(&disk_info.device);
+    let root_start = crate::get_partition_bounds(&disk_info.device, disk_info.roles.root)?.0;
+    let mut partitions = Vec::new();
+    let mut ext4_behind_root = Vec::new();
+    for number in crate::roles::partition_numbers(&disk_info.device) {
+        let (start, end) = crate::get_partition_bounds(&disk_info.device, number)?;
+        let path = sysfs::partition_path(&disk_info.device, number);
+        let fstype = sysfs::fstype(&path).unwrap_or_default();
+        let name = if Some(number) == extended {
+            "extended".to_string()
+        } else {
+            disk_partition_name(&disk_info, number, &fstype, start > root_start)
+        };
+        if name.starts_with("partition ") && fstype == "ext4" && start > root_start {
+            ext4_behind_root.push(partitions.len());
+        }
+        let mut entry = partition(&name, start, end, &fstype);
+        if let Some(uuid) = sysfs::lsblk_value(&path, "UUID") {
+            entry["uuid"] = json!(uuid);
+        }
+        partitions.push(entry);
+    }
+    // Unnamed ext4 behind root: /home is the last one, container storage before it
+    if let Some((&home, containers)) = ext4_behind_root.split_last() {
+        partitions[home]["name"] = json!("home");
+        if let [containers] = containers {
+            partitions[*containers]["name"] = json!("containers");
+        }
+    }
+    Ok(json!({
+        "version": PLAN_VERSION,
+        "device": disk_info.device,
+        "disk": disk_info.identity.to_json(),
+        "size_bytes": disk_info.size_bytes,
+        "partition_table": disk_info.partition_table,
+        "partitions": partitions,
+    }))
+}
+
+/// The plan as applied to `device`: with the UUID each filesystem got (matched by
+/// start sector) and the fstab the target at `root` ended up with, which is what
+/// verify-plan checks the disk against later
+pub fn applied(plan: &Value, device: &str, root: &str) -> Result<Value> {
+    let disk = disk_json(device)?;
+    let mut applied = plan.clone();
+    if let Some(partitions) = applied["partitions"].as_array_mut() {
+        for partition in partitions {
+            let on_disk = disk["partitions"].as_array().and_then(|list| list.iter().find(|p| p["start"] == partition["start"]));
+            if let Some(uuid) = on_disk.map(|p| &p["uuid"]).filter(|uuid| !uuid.is_null()) {
+                partition["uuid"] = uuid.clone();
+            }
+        }
+    }
+    applied["fstab"] = json!(read_fstab(root)?);
+    applied["applied"] = json!(true);
+    Ok(applied)
+}
+
+fn read_fstab(root: &str) -> Result<Vec<Value>> {
+    let path = format!("{}/etc/fstab", root);
+    let content = std::fs::read_to_string(&path).context(format!("Failed to read {}", path))?;
+    Ok(crate::fstab::parse_fstab(&content)?
+        .into_iter()
+        .map(|entry| json!({ "source": entry.source, "target": entry.target, "fstype": entry.fstype, "options": entry.options }))
+        .collect())
+}
+
+/// The operands of `diff` that are disks rather than plan files, for the site device policy
+pub fn disk_operands(operands: &[&str]) -> Vec<String> {
+    operands
+        .iter()
+        .filter(|path| std::fs::metadata(path).is_ok_and(|metadata| metadata.file_type().is_block_device()))
+        .map(|path| path.to_string())
+        .collect()
+}
+
+/// A plan file, or the current state of a disk given as its device
+fn load(path: &str) -> Result<Value> {
+    let metadata = std::fs::metadata(path).context(format!("Failed to read {}", path))?;
+    if metadata.file_type().is_block_device() {
+        return disk_json(path);
+    }
+    let text = std::fs::read_to_string(path).context(format!("Failed to read {}", path))?;
+    let plan: Value = serde_json::from_str(&text).context(format!("{} is not a plan file", path))?;
+    match plan["version"].as_u64() {
+        Some(PLAN_VERSION) => Ok(plan),
+        Some(version) => bail!("{} is a version {} plan, this build reads version {}", path, version, PLAN_VERSION),
+        None => bail!("{} is not a plan file (no version)", path),
+    }
+}
+
+/// One difference, as a path into the plan and the value on each side
+struct Change {
+    path: String,
+    old: Value,
+    new: Value,
+}
+
+/// Fields compared at the top level; alignment and swap only exist in plans
+const TOP_FIELDS: &[&str] = &["size_bytes", "partition_table", "alignment"];
+const PARTITION_FIELDS: &[&str] = &["start", "end", "bytes", "fstype", "uuid"];
+const FSTAB_FIELDS: &[&str] = &["source", "fstype", "options"];
+
+fn compare(changes: &mut Vec<Change>, path: String, old: &Value, new: &Value) {
+    // A field one side doesn't know about (a disk has no alignment) isn't drift
+    if !old.is_null() && !new.is_null() && old != new {
+        changes.push(Change { path, old: old.clone(), new: new.clone() });
+    }
+}
+
+fn diff(old: &Value, new: &Value) -> Vec<Change> {
+    let mut changes = Vec::new();
+    for field in TOP_FIELDS {
+        compare(&mut changes, field.to_string(), &old[field], &new[field]);
+    }
+    // A plan from before this field has no "disk"; compare skips what one side lacks
+    for field in ["serial", "wwn"] {
+        compare(&mut changes, format!("disk.{}", field), &old["disk"][field], &new["disk"][field]);
+    }
+    compare(&mut changes, "swap.mode".to_string(), &old["swap"]["mode"], &new["swap"]["mode"]);
+    compare(&mut changes, "swap.bytes".to_string(), &old["swap"]["bytes"], &new["swap"]["bytes"]);
+
+    let partitions = |plan: &Value| plan["partitions"].as_array().cloned().unwrap_or_default();
+    let (old_partitions, new_partitions) = (partitions(old), partitions(new));
+    let find = |list: &[Value], name: &Value| list.iter().find(|p| &p["name"] == name).cloned();
+    for partition in &old_partitions {
+        let name = partition["name"].as_str().unwrap_or_default();
+        match find(&new_partitions, &partition["name"]) {
+            Some(other) => {
+                for field in PARTITION_FIELDS {
+                    compare(&mut changes, format!("partitions.{}.{}", name, field), &partition[field], &other[field]);
+                }
+            }
+            None => changes.push(Change { path: format!("partitions.{}", name), old: partition.clone(), new: Value::Null }),
+        }
+    }
+    for partition in &new_partitions {
+        if find(&old_partitions, &partition["name"]).is_none() {
+            let name = partition["name"].as_str().unwrap_or_default();
+            changes.push(Change { path: format!("partitions.{}", name), old: Value::Null, new: partition.clone() });
+        }
+    }
+    changes
+}
+
+/// fstab entries are told apart by mountpoint, swap entries by source
+fn fstab_key(entry: &Value) -> String {
+    match entry["fstype"].as_str() {
+        Some("swap") => format!("swap {}", entry["source"].as_str().unwrap_or_default()),
+        _ => entry["target"].as_str().unwrap_or_default().to_string(),
+    }
+}
+
+/// Entries missing from, added to or changed in `actual` compared to `expected`
+fn diff_fstab(expected: &[Value], actual: &[Value]) -> Vec<Change> {
+    let mut changes = Vec::new();
+    let find = |list: &[Value], key: &str| list.iter().find(|entry| fstab_key(entry) == key).cloned();
+    for entry in expected {
+        let key = fstab_key(entry);
+        match find(actual, &key) {
+            Some(other) => {
+                for field in FSTAB_FIELDS {
+                    compare(&mut changes, format!("fstab.{}.{}", key, field), &entry[field], &other[field]);
+                }
+            }
+            None => changes.push(Change { path: format!("fstab.{}", key), old: entry.clone(), new: Value::Null }),
+        }
+    }
+    for entry in actual {
+        let key = fstab_key(entry);
+        if find(expected, &key).is_none() {
+            changes.push(Change { path: format!("fstab.{}", key), old: Value::Null, new: entry.clone() });
+        }
+    }
+    changes
+}
+
+fn describe(value: &Value) -> String {
+    if value.get("source").is_some() {
+        return format!(
+            "{} {} {} {}",
+            value["source"].as_str().unwrap_or_default(),
+            value["target"].as_str().unwrap_or_default(),
+            value["fstype"].as_str().unwrap_or_default(),
+            value["options"].as_str().unwrap_or_default()
+        );
+    }
+    format!(
+        "sectors {} - {} ({}){}",
+        value["start"],
+        value["end"],
+        crate::timing::format_bytes(value["bytes"].as_u64().unwrap_or(0)),
+        value["fstype"].as_str().filter(|t| !t.is_empty()).map(|t| format!(", {}", t)).unwrap_or_default()
+    )
+}
+
+/// Print `changes` between `old_label` and `new_label`, as text or JSON; fails
+/// when there are any, so scripts can tell drift from a match
+fn report(changes: &[Change], old_label: &str, new_label: &str, as_json: bool) -> Result<()> {
+    if as_json {
+        let report = json!({
+            "old": old_label,
+            "new": new_label,
+            "equal": changes.is_empty(),
+            "changes": changes.iter().map(|c| json!({ "path": c.path, "old": c.old, "new": c.new })).collect::<Vec<_>>(),
+        });
+        println!("{:#}", report);
+    } else {
+        println!("--- {}\n+++ {}", old_label, new_label);
+        for change in changes {
+            match (&change.old, &change.new) {
+                (Value::Null, new) => println!("+ {}: {}", change.path, describe(new)),
+                (old, Value::Null) => println!("- {}: {}", change.path, describe(old)),
+                (old, new) => println!("~ {}: {} -> {}", change.path, old, new),
+            }
+        }
+        if changes.is_empty() {
+            println!("No differences");
+        }
+    }
+
+    if !changes.is_empty() {
+        bail!("{} difference(s)", changes.len());
+    }
+    Ok(())
+}
+
+/// Compare two plans, or a plan with a disk
+pub fn run_diff(old_path: &str, new_path: &str, as_json: bool) -> Result<()> {
+    let old = load(old_path)?;
+    let new = load(new_path)?;
+    report(&diff(&old, &new), old_path, new_path, as_json)
+}
+
+/// Where `device` is mounted already, if anywhere
+fn mounted_at(device: &str) -> Option<String> {
+    let mounts = std::fs::read_to_string("/proc/mounts").ok()?;
+    let device = std::fs::canonicalize(device).ok()?;
+    crate::parse::proc_mounts(&mounts)
+        .into_iter()
+        .find(|entry| std::fs::canonicalize(&entry.source).is_ok_and(|source| source == device))
+        .map(|entry| entry.target)
+}
+
+/// Check a disk against the plan applied to it: partition table, filesystem
+/// types and UUIDs, and the target's fstab
+pub fn verify_plan(plan_path: &str, device: &str, mounts: &MountPaths, as_json: bool) -> Result<()> {
+    let plan = load(plan_path)?;
+    let disk = disk_json(device)?;
+    let (planned, current) = (DiskIdentity::from_json(&plan["disk"]), DiskIdentity::from_json(&disk["disk"]));
+    if planned.differs_from(&current) {
+        bail!(
+            "{} was applied to another disk ({}); {} is {}",
+            plan_path,
+            planned.describe(),
+            device,
+            current.describe()
+        );
+    }
+    let mut changes = diff(&plan, &disk);
+
+    match plan["fstab"].as_array() {
+        Some(expected) => {
+            let disk_info = crate::get_disk_info(device)?;
+            if let Some(root) = mounted_at(&disk_info.root_partition) {
+                // Checking a running system, on the device itself
+                changes.extend(diff_fstab(expected, &read_fstab(&root)?));
+            } else if crate::privilege::can_open(&disk_info.root_partition) {
+                let actual = crate::with_root_read_only(&disk_info.root_partition, mounts, read_fstab)?;
+                changes.extend(diff_fstab(expected, &actual));
+            } else if !as_json {
+                println!("Skipped checking fstab: mounting {} read-only needs privilege", disk_info.root_partition);
+            }
+        }
+        None if !as_json => {
+            println!("{} was written before a run applied it, so only the partition table is checked", plan_path);
+        }
+        None => {}
+    }
+    report(&changes, plan_path, device, as_json)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::stack::LuksLayer;
+
+    const GIB: u64 = 1024 * 1024 * 1024;
+
+    fn plan(partitions: Value) -> Value {
+        json!({ "version": PLAN_VERSION, "size_bytes": 32 * GIB, "partition_table": "msdos", "partitions": partitions })
+    }
+
+    fn paths(changes: &[Change]) -> Vec<&str> {
+        changes.iter().map(|change| change.path.as_str()).collect()
+    }
+
+    #[test]
+    fn diff_lists_changed_missing_and_added_partitions() {
+        let old = plan(json!([
+            partition("boot", 8192, 1056767, "vfat"),
+            partition("root", 1056768, 17833983, "ext4"),
+            partition("swap", 17833984, 22028287, "swap"),
+        ]));
+        assert!(diff(&old, &old).is_empty());
+
+        let new = plan(json!([
+            partition("boot", 8192, 1056767, "vfat"),
+            partition("root", 1056768, 34611199, "ext4"),
+            partition("home", 34611200, 67108863, "ext4"),
+        ]));
+        let changes = diff(&old, &new);
+        assert_eq!(paths(&changes), ["partitions.root.end", "partitions.root.bytes", "partitions.swap", "partitions.home"]);
+        assert!(changes[2].new.is_null() && changes[3].old.is_null());
+
+        // What only one side knows (a disk has no alignment, an old plan no disk identity) isn't drift
+        let mut aligned = old.clone();
+        aligned["alignment"] = json!(8192);
+        aligned["disk"] = json!({ "serial": "0x1c2d3e4f" });
+        assert!(diff(&old, &aligned).is_empty());
+        let mut other_card = aligned.clone();
+        other_card["disk"]["serial"] = json!("0x99999999");
+        assert_eq!(paths(&diff(&aligned, &other_card)), ["disk.serial"]);
+    }
+
+    #[test]
+    fn fstab_entries_are_keyed_by_mountpoint_and_swap_by_source() {
+        let entry = |source: &str, target: &str, fstype: &str, options: &str| {
+            json!({ "source": source, "target": target, "fstype": fstype, "options": options })
+        };
+        assert_eq!(fstab_key(&entry("UUID=1234", "/home", "ext4", "defaults")), "/home");
+        assert_eq!(fstab_key(&entry("UUID=5678", "none", "swap", "sw")), "swap UUID=5678");
+
+        let expected = vec![
+            entry("PARTUUID=6c586e13-02", "/", "ext4", "defaults,noatime"),
+            entry("UUID=1234", "/home", "ext4", "defaults"),
+            entry("UUID=5678", "none", "swap", "sw"),
+        ];
+        assert!(diff_fstab(&expected, &expected).is_empty());
+        let actual = vec![
+            entry("PARTUUID=6c586e13-02", "/", "ext4", "defaults"),
+            entry("UUID=1234", "/home", "ext4", "defaults"),
+            entry("UUID=9abc", "none", "swap", "sw"),
+        ];
+        assert_eq!(paths(&diff_fstab(&expected, &actual)), ["fstab./.options", "fstab.swap UUID=5678", "fstab.swap UUID=9abc"]);
+    }
+
+    #[test]
+    fn plan_reports_what_the_root_partition_holds() {
+        let disk_info = DiskInfo {
+            device: "/dev/sda".to_string(),
+            size_bytes: 32 * GIB,
+            size_sectors: 32 * GIB / SECTOR_SIZE,
+            is_sd_card: false,
+            root_partition: "/dev/sda2".to_string(),
+            roles: crate::roles::Roles { boot: 1, root: 2, found_by: "position" },
+            partition_table: "msdos".to_string(),
+            erase_block_bytes: None,
+            identity: Default::default(),
+        };
+        let sizes = crate::PartitionSizes { root: 8 * GIB, recovery: None, swap: None, var: None, containers: None, cidata: None };
+        let layout = crate::compute_partition_layout(&disk_info, 1056768, &sizes, false).unwrap();
+        let swap = crate::swap::SwapPlan::new(None, None).unwrap();
+        let mut stack = RootStack { partition: "/dev/sda2".to_string(), luks: None, lvm: None, fs_device: "/dev/sda2".to_string() };
+        let root_fstype = |stack: &RootStack| plan_json(&disk_info, (8192, 1056767), &layout, &swap, stack)["partitions"][1]["fstype"].clone();
+
+        assert_eq!(root_fstype(&stack), "ext4");
+        stack.luks = Some(LuksLayer { mapper: "/dev/mapper/crpart_root".to_string(), header_bytes: 16 * 1024 * 1024 });
+        stack.fs_device = "/dev/mapper/crpart_root".to_string();
+        assert_eq!(root_fstype(&stack), "crypto_LUKS");
+    }
+}
@@ -0,0 +1,70 @@
+//! Refusing to start where the run can't work. Everything here shells out to
+//! Linux tools (parted, e2fsprogs, losetup) and uses Linux interfaces (sysfs,
+//! uevents, loop devices), so builds for another OS stop at compile time. WSL
+//! is Linux but sees the Windows host's disks only in part: WSL1 has no block
+//! devices at all, and WSL2 only the disks passed to it with `wsl --mount --bare`.
+
+use anyhow::{bail, Result};
+use std::os::unix::fs::FileTypeExt;
+
+#[cfg(not(target_os = "linux"))]
+compile_error!(
+    "rpi-fs-shrink runs on Linux only (other systems have no parted, e2fsprogs or loop devices); \
+    use a Linux VM with the card reader passed through to it, or another Raspberry Pi with the card in a USB reader"
+);
+
+/// Windows Subsystem for Linux version from the kernel release: WSL1 reports
+/// "4.4.0-19041-Microsoft", WSL2 "5.15.153.1-microsoft-standard-WSL2"
+fn wsl_version(osrelease: &str) -> Option<u8> {
+    if osrelease.contains("Microsoft") {
+        return Some(1);
+    }
+    let lower = osrelease.to_lowercase();
+    (lower.contains("microsoft") || lower.contains("wsl")).then_some(2)
+}
+
+/// Stop on WSL hosts the run can't work on, before anything looks at `target`
+/// (the disk, image or NBD URL the command works on, if any)
+pub fn check(target: Option<&str>) -> Result<()> {
+    let osrelease = std::fs::read_to_string("/proc/sys/kernel/osrelease").unwrap_or_default();
+    let Some(wsl) = wsl_version(&osrelease) else {
+        return Ok(());
+    };
+    let Some(target) = target else {
+        return Ok(());
+    };
+    if wsl == 1 {
+        bail!(
+            "WSL1 has no block or loop devices to work on; convert the distribution with \
+            `wsl --set-version <distro> 2` and pass the card to it with `wsl --mount --bare`"
+        );
+    }
+    // Image files go through a loop device and NBD exports over the network, both of which WSL2 has
+    if target.contains("://") {
+        return Ok(());
+    }
+    match std::fs::metadata(target) {
+        Ok(meta) if meta.is_file() || meta.file_type().is_block_device() => Ok(()),
+        _ => bail!(
+            "{} is not a block device here. WSL2 only sees the Windows host's disks passed to it: from an \
+            administrator PowerShell run `wsl --mount \\\\.\\PHYSICALDRIVE<n> --bare` (`wmic diskdrive list brief` \
+            lists the numbers), then use the device that appears in lsblk. Or copy the card to an image file \
+            and work on that",
+            target
+        ),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn wsl_is_told_apart_by_its_kernel_release() {
+        assert_eq!(wsl_version("4.4.0-19041-Microsoft\n"), Some(1));
+        assert_eq!(wsl_version("5.15.153.1-microsoft-standard-WSL2\n"), Some(2));
+        assert_eq!(wsl_version("6.6.36.3-microsoft-standard-WSL2+\n"), Some(2));
+        assert_eq!(wsl_version("6.6.31+rpt-rpi-v8\n"), None);
+        assert_eq!(wsl_version("6.8.0-45-generic\n"), None);
+    }
+}
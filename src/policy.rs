@@ -0,0 +1,122 @@
+use anyhow::{bail, Context, Result};
+use serde::Deserialize;
+use std::path::Path;
+
+use crate::glob::glob_match;
+
+pub const CONFIG_PATH: &str = "/etc/crpart.toml";
+
+/// Site configuration, e.g. for classroom machines:
+///
+/// ```toml
+/// [devices]
+/// deny = ["/dev/nvme0n1"]
+/// allow = ["mmcblk*", "sd[b-z]"]
+/// ```
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    #[serde(default)]
+    pub devices: DevicePolicy,
+}
+
+/// Patterns with a `/` match the whole path, others only the device name.
+/// `*`, `?` and `[a-z]` classes are supported as in `--exclude`: `*` stays
+/// within one path component (`/dev/disk/by-id/usb-*`), `**` crosses them.
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct DevicePolicy {
+    /// Devices that are never touched, even if allowed
+    #[serde(default)]
+    pub deny: Vec<String>,
+    /// If non-empty, only matching devices may be touched
+    #[serde(default)]
+    pub allow: Vec<String>,
+}
+
+/// The site configuration, or the default (no restrictions) if there is none
+pub fn load_config() -> Result<Config> {
+    match std::fs::read_to_string(CONFIG_PATH) {
+        Ok(content) => toml::from_str(&content).context(format!("Invalid configuration {}", CONFIG_PATH)),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Config::default()),
+        Err(e) => Err(e).context(format!("Failed to read {}", CONFIG_PATH)),
+    }
+}
+
+fn matches(pattern: &str, path: &str) -> bool {
+    let subject = if pattern.contains('/') {
+        path
+    } else {
+        path.rsplit('/').next().unwrap_or(path)
+    };
+    glob_match(pattern, subject)
+}
+
+/// Refuse `device` unless the policy permits it. Symlinks such as
+/// /dev/disk/by-id/... are checked both as given and resolved.
+/// Image files are not devices and are not restricted.
+pub fn check_device(policy: &DevicePolicy, device: &str) -> Result<()> {
+    if Path::new(device).is_file() {
+        return Ok(());
+    }
+
+    let mut names = vec![device.to_string()];
+    if let Ok(resolved) = std::fs::canonicalize(device) {
+        let resolved = resolved.to_string_lossy().into_owned();
+        if resolved != device {
+            names.push(resolved);
+        }
+    }
+
+    for pattern in &policy.deny {
+        if let Some(name) = names.iter().find(|name| matches(pattern, name)) {
+            bail!("{} is denied by '{}' in {}", name, pattern, CONFIG_PATH);
+        }
+    }
+
+    if !policy.allow.is_empty()
+        && !policy.allow.iter().any(|pattern| names.iter().any(|name| matches(pattern, name)))
+    {
+        bail!("{} is not in the allow list of {}", device, CONFIG_PATH);
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn policy(deny: &[&str], allow: &[&str]) -> DevicePolicy {
+        let owned = |patterns: &[&str]| patterns.iter().map(|pattern| pattern.to_string()).collect();
+        DevicePolicy { deny: owned(deny), allow: owned(allow) }
+    }
+
+    #[test]
+    fn patterns_match_the_name_or_with_a_slash_the_path() {
+        assert!(matches("sd[b-z]", "/dev/sdc"));
+        assert!(!matches("sd[b-z]", "/dev/sda"));
+        assert!(matches("mmcblk*", "/dev/mmcblk0"));
+        assert!(matches("/dev/nvme0n1", "/dev/nvme0n1"));
+        assert!(!matches("/dev/nvme0n1", "/dev/nvme0n1p1"));
+        assert!(matches("/dev/disk/by-id/usb-*", "/dev/disk/by-id/usb-Generic_SD-0:0"));
+        assert!(!matches("/dev/*", "/dev/disk/by-id/usb-x"));
+    }
+
+    #[test]
+    fn deny_wins_over_allow_and_allow_lists_are_exclusive() {
+        let policy = policy(&["/dev/nvme0n1", "sdz"], &["mmcblk*", "sd[b-z]"]);
+        assert!(check_device(&policy, "/dev/rpi-fs-shrink-test/mmcblk7").is_ok());
+        assert!(check_device(&policy, "/dev/rpi-fs-shrink-test/sdy").is_ok());
+        assert!(check_device(&policy, "/dev/rpi-fs-shrink-test/sdz").is_err());
+        assert!(check_device(&policy, "/dev/nvme0n1").is_err());
+        assert!(check_device(&policy, "/dev/rpi-fs-shrink-test/sda").is_err());
+        assert!(check_device(&DevicePolicy::default(), "/dev/rpi-fs-shrink-test/sda").is_ok());
+
+        // Image files aren't devices
+        let image = std::env::temp_dir().join(format!("rpi-fs-shrink-policy-{}.img", std::process::id()));
+        std::fs::write(&image, b"").unwrap();
+        assert!(check_device(&policy, image.to_str().unwrap()).is_ok());
+        std::fs::remove_file(&image).unwrap();
+    }
+}
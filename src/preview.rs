@@ -0,0 +1,149 @@
+use anyhow::{bail, Context, Result};
+use std::collections::HashMap;
+use std::path::Path;
+use std::process::Command;
+
+use crate::firmware::{self, BootLayout};
+use crate::fstab::fstab_line;
+use crate::stack::RootStack;
+use crate::swap::{SwapMode, SwapPlan};
+use crate::{cmdline, container_storage};
+use crate::{
+    get_partition_bounds, get_partition_device, mount_device, part_label, privilege, relocate, systemd, unmount_quiet,
+    updated_fstab, with_root_read_only, DiskInfo, FstabRef, MountPaths, PartitionLayout,
+};
+
+/// Stands in for identifiers that only exist once the partitions are created
+const NEW: &str = "<new>";
+
+/// `diff -u` of `old` against `new`, labelled with `path`; empty when they are the same
+fn unified_diff(path: &str, old: &str, new: &str) -> Result<String> {
+    let base = std::env::temp_dir().join(format!("rpi-fs-shrink-{}", std::process::id()));
+    let (old_file, new_file) = (base.with_extension("old"), base.with_extension("new"));
+    std::fs::write(&old_file, old).context("Failed to write diff input")?;
+    std::fs::write(&new_file, new).context("Failed to write diff input")?;
+
+    let output = Command::new("diff")
+        .args(["-u", "--label", &format!("a{}", path), "--label", &format!("b{}", path)])
+        .args([&old_file, &new_file])
+        .output();
+    let _ = std::fs::remove_file(&old_file);
+    let _ = std::fs::remove_file(&new_file);
+
+    // diff exits 1 when the files differ and 2 on trouble
+    let output = output.context("Failed to run diff")?;
+    if output.status.code() == Some(2) {
+        bail!("diff failed: {}", String::from_utf8_lossy(&output.stderr).trim());
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+fn print_diff(path: &str, old: &str, new: &str) -> Result<()> {
+    let diff = unified_diff(path, old, new)?;
+    if diff.is_empty() {
+        println!("  {}: unchanged", path);
+        return Ok(());
+    }
+    for line in diff.lines() {
+        println!("  {}", line);
+    }
+    Ok(())
+}
+
+/// Show the literal edits a run would make to the target's /etc/fstab and to
+/// cmdline.txt, reading both from read-only mounts. Identifiers of partitions
+/// that don't exist yet are shown as <new>.
+pub fn print_boot_edits(
+    disk_info: &DiskInfo,
+    root_stack: &RootStack,
+    layout: &PartitionLayout,
+    swap: &SwapPlan,
+    reused_uuids: &HashMap<String, String>,
+    reference: FstabRef,
+    mounts: &MountPaths,
+) -> Result<()> {
+    println!("\nChanges to boot configuration:");
+    let fs_device = root_stack.fs_device.as_str();
+    if !privilege::can_open(fs_device) {
+        println!("  Skipped: mounting {} read-only needs privilege", fs_device);
+        return Ok(());
+    }
+
+    // parted recreates the root entry, which gives it a new PARTUUID on GPT (and
+    // so does a conversion to GPT). On MBR the PARTUUID is the disk ID plus the
+    // partition number and stays.
+    let old_partuuid = relocate::get_partuuid(&disk_info.root_partition)?;
+    let partuuid_changes = if disk_info.partition_table == "gpt" && !old_partuuid.is_empty() {
+        vec![(old_partuuid.clone(), NEW.to_string())]
+    } else {
+        Vec::new()
+    };
+
+    let source = |key: &str| match reference {
+        FstabRef::Uuid => format!("UUID={}", reused_uuids.get(key).map(String::as_str).unwrap_or(NEW)),
+        FstabRef::Partlabel => format!("PARTLABEL={}", part_label(key)),
+    };
+
+    // The same plan the run hashes into the fstab block
+    let boot = get_partition_bounds(&disk_info.device, disk_info.roles.boot)?;
+    let plan = crate::plandiff::plan_json(disk_info, boot, layout, swap, root_stack);
+    let provenance = crate::fstab::Provenance::new(&crate::audit::sha256(plan.to_string().as_bytes())?);
+
+    let boot_layout = with_root_read_only(fs_device, mounts, |root| {
+        let fstab_path = format!("{}/etc/fstab", root);
+        let current = std::fs::read_to_string(&fstab_path).context(format!("Failed to read {}", fstab_path))?;
+
+        let mut new_entries = Vec::new();
+        if layout.swap_size_bytes > 0 {
+            new_entries.push(fstab_line(&source("swap"), "none", "swap", "sw", 0));
+        }
+        new_entries.extend(swap.fstab_entry());
+        if layout.var_size_bytes > 0 {
+            let options = systemd::var_mount_options(systemd::target_systemd_version(root));
+            if options == "defaults" {
+                println!("  The target's systemd is too old for x-systemd ordering; /var ordering drop-ins would be installed");
+            }
+            new_entries.push(fstab_line(&source("/var"), "/var", "btrfs", &options, 2));
+        }
+        if layout.containers_size_bytes > 0 {
+            let target = container_storage::partition_target_in(root);
+            new_entries.push(fstab_line(&source("containers"), target, "ext4", "defaults", 2));
+        }
+        new_entries.push(fstab_line(&source("/home"), "/home", "ext4", "defaults", 2));
+
+        let updated = updated_fstab(&current, &partuuid_changes, new_entries, &swap.disabled, Some(&provenance))?;
+        print_diff("/etc/fstab", &current, &updated)?;
+
+        let boot_layout = BootLayout::detect(root);
+        firmware::check_fstab_entry(&current, boot_layout)?;
+        Ok(boot_layout)
+    })?;
+
+    let boot_device = get_partition_device(&disk_info.device, disk_info.roles.boot)?;
+    let boot_mount = mounts.boot();
+    if !Path::new(&boot_mount).exists() {
+        std::fs::create_dir_all(&boot_mount).context(format!("Failed to create {}", boot_mount))?;
+    }
+    mount_device(&boot_device, &boot_mount, true).context(format!("Failed to mount {} read-only", boot_device))?;
+    let result = (|| -> Result<()> {
+        let cmdline_path = format!("{}/cmdline.txt", boot_mount);
+        if !Path::new(&cmdline_path).exists() {
+            println!("  No cmdline.txt on {}", boot_device);
+            return Ok(());
+        }
+        let current = std::fs::read_to_string(&cmdline_path).context("Failed to read cmdline.txt")?;
+        let mut updated = match partuuid_changes.first() {
+            Some((old, new)) => cmdline::with_partuuid(&current, old, new),
+            None => current.clone(),
+        };
+        if swap.mode == SwapMode::Zswap {
+            updated = cmdline::with_zswap(&updated);
+        }
+        if swap.hibernate {
+            updated = cmdline::with_resume(&updated, &format!("PARTUUID={}", NEW));
+        }
+        print_diff(&boot_layout.path("cmdline.txt"), &current, &updated)
+    })();
+    unmount_quiet(&boot_mount);
+    result
+}
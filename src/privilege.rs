@@ -0,0 +1,132 @@
+use anyhow::{bail, Result};
+
+/// Capabilities that together stand in for root when modifying a disk:
+/// opening devices and files regardless of mode, preserving ownership,
+/// and mounting, partition table ioctls and loop devices
+const DISK_CAPABILITIES: &[(u32, &str)] = &[
+    (0, "cap_chown"),
+    (1, "cap_dac_override"),
+    (3, "cap_fowner"),
+    (21, "cap_sys_admin"),
+];
+
+const LINUX_CAPABILITY_VERSION_3: u32 = 0x2008_0522;
+const PR_CAP_AMBIENT: libc::c_int = 47;
+const PR_CAP_AMBIENT_RAISE: libc::c_ulong = 2;
+
+#[repr(C)]
+struct CapHeader {
+    version: u32,
+    pid: libc::c_int,
+}
+
+#[repr(C)]
+#[derive(Default, Clone, Copy)]
+struct CapData {
+    effective: u32,
+    permitted: u32,
+    inheritable: u32,
+}
+
+pub fn is_root() -> bool {
+    unsafe { libc::geteuid() == 0 }
+}
+
+/// Effective capability set from /proc/self/status
+fn effective_capabilities() -> u64 {
+    std::fs::read_to_string("/proc/self/status")
+        .ok()
+        .and_then(|status| {
+            status
+                .lines()
+                .find_map(|line| line.strip_prefix("CapEff:"))
+                .and_then(|value| u64::from_str_radix(value.trim(), 16).ok())
+        })
+        .unwrap_or(0)
+}
+
+fn missing_capabilities() -> Vec<&'static str> {
+    let effective = effective_capabilities();
+    DISK_CAPABILITIES
+        .iter()
+        .filter(|(bit, _)| effective & (1 << bit) == 0)
+        .map(|(_, name)| *name)
+        .collect()
+}
+
+/// True when running as a regular user that holds the disk capabilities,
+/// e.g. from `setcap` on the binary or systemd's AmbientCapabilities=
+pub fn is_capability_run() -> bool {
+    !is_root() && missing_capabilities().is_empty()
+}
+
+/// In capability runs, make the capabilities inheritable and ambient so the
+/// tools run as child processes (parted, resize2fs, mkfs, ...) keep them across exec
+pub fn pass_to_children() -> Result<()> {
+    if !is_capability_run() {
+        return Ok(());
+    }
+
+    let mut header = CapHeader { version: LINUX_CAPABILITY_VERSION_3, pid: 0 };
+    let mut data = [CapData::default(); 2];
+
+    if unsafe { libc::syscall(libc::SYS_capget, &mut header, data.as_mut_ptr()) } != 0 {
+        bail!("capget failed: {}", std::io::Error::last_os_error());
+    }
+    for &(bit, _) in DISK_CAPABILITIES {
+        data[0].inheritable |= data[0].permitted & (1 << bit);
+    }
+    if unsafe { libc::syscall(libc::SYS_capset, &header, data.as_ptr()) } != 0 {
+        bail!("capset failed: {}", std::io::Error::last_os_error());
+    }
+
+    for &(bit, name) in DISK_CAPABILITIES {
+        let raised = unsafe { libc::prctl(PR_CAP_AMBIENT, PR_CAP_AMBIENT_RAISE, bit as libc::c_ulong, 0, 0) };
+        if raised != 0 {
+            bail!("Failed to raise {} for child processes: {}", name, std::io::Error::last_os_error());
+        }
+    }
+    Ok(())
+}
+
+/// Whether `device` can be opened for reading, i.e. tools like parted and
+/// blkid can see it directly
+pub fn can_open(device: &str) -> bool {
+    std::fs::File::open(device).is_ok()
+}
+
+/// For read-only paths: when `device` can't be opened, get `detail` from
+/// world-readable sysfs/udev information instead and say so
+pub fn fallback<T>(device: &str, detail: &str, value: impl FnOnce() -> Option<T>) -> Option<T> {
+    if can_open(device) {
+        return None;
+    }
+    let value = value();
+    if value.is_some() {
+        println!("  Note: reading the {} of {} directly needs privilege; using sysfs/udev information", detail, device);
+    }
+    value
+}
+
+/// Check for the privilege `operation` needs: root, or the disk capabilities.
+/// Only operations that modify disks or mount filesystems call this.
+pub fn require(operation: &str) -> Result<()> {
+    if is_root() {
+        return Ok(());
+    }
+
+    let missing = missing_capabilities();
+    if !missing.is_empty() {
+        let all: Vec<&str> = DISK_CAPABILITIES.iter().map(|(_, name)| *name).collect();
+        bail!(
+            "{} needs root or the capabilities {} (missing {}).\n\
+            Run it with sudo, or grant them to the binary:\n  \
+            sudo setcap {}+ep $(command -v rpi-fs-shrink)",
+            operation,
+            all.join(", "),
+            missing.join(", "),
+            all.join(",")
+        );
+    }
+    Ok(())
+}
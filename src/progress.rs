@@ -0,0 +1,206 @@
+use anyhow::{Context, Result};
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
+
+use crate::timing::{format_bytes, format_duration};
+
+/// Where `--status-file` writes when given without a path
+pub const DEFAULT_STATUS_FILE: &str = "/run/rpi-fs-shrink/status.json";
+
+/// The status file is rewritten at most this often within a step
+const WRITE_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Where the run is, for anything that reports on it while it runs
+struct Progress {
+    started: Instant,
+    step: String,
+    step_started: Instant,
+    /// Fraction of the current step done, for steps that report it
+    step_fraction: Option<f64>,
+    step_eta: Option<Duration>,
+    /// Bytes copied by finished steps, and so far by the current one
+    bytes_done: u64,
+    step_bytes: u64,
+    status_file: Option<String>,
+    last_write: Option<Instant>,
+}
+
+static PROGRESS: Mutex<Option<Progress>> = Mutex::new(None);
+
+/// Set by the SIGUSR1 handler, which can't do more than that safely
+static DUMP_REQUESTED: AtomicBool = AtomicBool::new(false);
+static WATCH_SIGUSR1: std::sync::Once = std::sync::Once::new();
+
+/// Start tracking a run, writing its status to `status_file` if given
+pub fn start(status_file: Option<String>) -> Result<()> {
+    if let Some(parent) = status_file.as_deref().and_then(|path| std::path::Path::new(path).parent()) {
+        std::fs::create_dir_all(parent).context(format!("Failed to create {}", parent.display()))?;
+    }
+    let now = Instant::now();
+    let progress = Progress {
+        started: now,
+        step: "Starting".to_string(),
+        step_started: now,
+        step_fraction: None,
+        step_eta: None,
+        bytes_done: 0,
+        step_bytes: 0,
+        status_file,
+        last_write: None,
+    };
+    write_status(&progress, "running", None)?;
+    *PROGRESS.lock().unwrap() = Some(progress);
+    WATCH_SIGUSR1.call_once(watch_sigusr1);
+    Ok(())
+}
+
+extern "C" fn request_dump(_signal: libc::c_int) {
+    DUMP_REQUESTED.store(true, Ordering::Relaxed);
+}
+
+/// Print where the run is whenever SIGUSR1 arrives, the way dd does. Children
+/// get the default action back when they exec, so this doesn't reach them.
+fn watch_sigusr1() {
+    // SAFETY: the handler only stores to an atomic
+    unsafe {
+        libc::signal(libc::SIGUSR1, request_dump as *const () as libc::sighandler_t);
+    }
+    std::thread::spawn(|| {
+        loop {
+            std::thread::sleep(Duration::from_millis(250));
+            if DUMP_REQUESTED.swap(false, Ordering::Relaxed) {
+                dump();
+            }
+        }
+    });
+}
+
+/// Print the current step, elapsed time, bytes copied and what is left to
+/// stderr and syslog, and refresh the status file
+fn dump() {
+    let mut guard = PROGRESS.lock().unwrap();
+    let Some(progress) = guard.as_mut() else {
+        return;
+    };
+    let mut message = format!(
+        "rpi-fs-shrink: step {}; {} elapsed, {} in this step; {} copied",
+        progress.step,
+        format_duration(progress.started.elapsed()),
+        format_duration(progress.step_started.elapsed()),
+        format_bytes(progress.bytes_done + progress.step_bytes)
+    );
+    match (progress.step_fraction, progress.step_eta) {
+        (Some(fraction), Some(eta)) => {
+            message += &format!("; step {:.0}% done, about {} left in it", fraction * 100.0, format_duration(eta))
+        }
+        (Some(fraction), None) => message += &format!("; step {:.0}% done", fraction * 100.0),
+        _ => message += "; this step doesn't report how far it got",
+    }
+    message += &format!("; run about {:.0}% done", progress.percent());
+
+    eprintln!("{}", message);
+    if let Ok(text) = std::ffi::CString::new(message) {
+        // SAFETY: both strings are NUL-terminated, and the format takes one string
+        unsafe {
+            libc::syslog(libc::LOG_INFO | libc::LOG_USER, c"%s".as_ptr(), text.as_ptr());
+        }
+    }
+    progress.last_write = Some(Instant::now());
+    if let Err(err) = write_status(progress, "running", None) {
+        eprintln!("Warning: {:#}", err);
+        progress.status_file = None;
+    }
+}
+
+fn update(force: bool, change: impl FnOnce(&mut Progress)) {
+    let mut guard = PROGRESS.lock().unwrap();
+    let Some(progress) = guard.as_mut() else {
+        return;
+    };
+    change(progress);
+    if force || progress.last_write.is_none_or(|last| last.elapsed() >= WRITE_INTERVAL) {
+        progress.last_write = Some(Instant::now());
+        // A status file that can't be written doesn't stop the run
+        if let Err(err) = write_status(progress, "running", None) {
+            eprintln!("Warning: {:#}", err);
+            progress.status_file = None;
+        }
+    }
+}
+
+/// The run moved on to step `name`
+pub fn begin_step(name: &str) {
+    update(true, |progress| {
+        progress.step = name.to_string();
+        progress.step_started = Instant::now();
+        progress.step_fraction = None;
+        progress.step_eta = None;
+        progress.bytes_done += progress.step_bytes;
+        progress.step_bytes = 0;
+    });
+}
+
+/// The current step has copied `bytes` in all, and is `fraction` done with
+/// `eta` to go when it can tell
+pub fn step_progress(bytes: u64, fraction: Option<f64>, eta: Option<Duration>) {
+    update(false, |progress| {
+        progress.step_bytes = progress.step_bytes.max(bytes);
+        progress.step_fraction = fraction.map(|fraction| fraction.clamp(0.0, 1.0));
+        progress.step_eta = eta;
+    });
+}
+
+/// The current step has moved `bytes` in all, counted once it finished
+pub fn step_bytes(bytes: u64) {
+    update(true, |progress| progress.step_bytes = progress.step_bytes.max(bytes));
+}
+
+/// Write the final status of the run
+pub fn finish(result: &Result<()>) {
+    let mut guard = PROGRESS.lock().unwrap();
+    let Some(progress) = guard.take() else {
+        return;
+    };
+    let (state, error) = match result {
+        Ok(()) => ("complete", None),
+        Err(err) => ("failed", Some(format!("{:#}", err))),
+    };
+    if let Err(err) = write_status(&progress, state, error) {
+        eprintln!("Warning: {:#}", err);
+    }
+}
+
+impl Progress {
+    /// Whole run done, from the step number and how far the step got
+    fn percent(&self) -> f64 {
+        match crpart::step_number(&self.step) {
+            Some(number) => crpart::run_percent(number, self.step_fraction.unwrap_or(0.0)),
+            None => 0.0,
+        }
+    }
+}
+
+fn write_status(progress: &Progress, state: &str, error: Option<String>) -> Result<()> {
+    let Some(ref path) = progress.status_file else {
+        return Ok(());
+    };
+    let percent = if state == "complete" { 100.0 } else { progress.percent() };
+    let status = serde_json::json!({
+        "state": state,
+        "pid": std::process::id(),
+        "step": progress.step,
+        "percent": (percent * 10.0).round() / 10.0,
+        "last_step": crpart::LAST_STEP,
+        "elapsed_seconds": progress.started.elapsed().as_secs(),
+        "step_elapsed_seconds": progress.step_started.elapsed().as_secs(),
+        "step_percent": progress.step_fraction.map(|fraction| (fraction * 1000.0).round() / 10.0),
+        "step_eta_seconds": progress.step_eta.map(|eta| eta.as_secs()),
+        "bytes_copied": progress.bytes_done + progress.step_bytes,
+        "error": error,
+    });
+    // Readers never see a half-written file
+    let temp = format!("{}.tmp", path);
+    std::fs::write(&temp, format!("{:#}\n", status)).context(format!("Failed to write status file {}", temp))?;
+    std::fs::rename(&temp, path).context(format!("Failed to write status file {}", path))
+}
@@ -0,0 +1,34 @@
+//! The "Press Enter" confirmations before a run changes anything, and running
+//! without them (`--unattended`) in CI pipelines and the container image, which
+//! sets RPI_FS_SHRINK_UNATTENDED=1. Questions that delete data still need
+//! their own `--yes`.
+
+use anyhow::Result;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+/// Set to 1 to run unattended without passing --unattended
+pub const UNATTENDED_ENV: &str = "RPI_FS_SHRINK_UNATTENDED";
+
+static UNATTENDED: AtomicBool = AtomicBool::new(false);
+
+/// Skip the confirmations for the rest of the run when `flag` or the environment asks for it
+pub fn configure(flag: bool) {
+    let from_env = std::env::var(UNATTENDED_ENV).is_ok_and(|value| !matches!(value.as_str(), "" | "0"));
+    UNATTENDED.store(flag || from_env, Ordering::SeqCst);
+}
+
+pub fn unattended() -> bool {
+    UNATTENDED.load(Ordering::SeqCst)
+}
+
+/// Print `message` and wait for Enter, or go straight on when unattended
+pub fn wait_for_enter(message: &str) -> Result<()> {
+    println!("{}", message);
+    if unattended() {
+        println!("  (unattended, continuing)");
+        return Ok(());
+    }
+    let mut input = String::new();
+    std::io::stdin().read_line(&mut input)?;
+    Ok(())
+}
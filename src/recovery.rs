@@ -0,0 +1,107 @@
+use anyhow::{bail, Context, Result};
+use std::path::Path;
+use std::process::Command;
+
+use crate::{mount_at, unmount_quiet, MountPaths};
+
+/// Name the initramfs is stored under on the recovery partition
+const RECOVERY_INITRAMFS: &str = "initramfs-recovery";
+
+pub fn copy_recursive(source: &str, dest: &str) -> Result<()> {
+    // FAT cannot hold ownership or permissions, so only contents are copied
+    let status = Command::new("cp")
+        .args(["-r", "--no-preserve=mode,ownership", source, dest])
+        .status()
+        .context("Failed to run cp")?;
+
+    if !status.success() {
+        bail!("Failed to copy {} to {}", source, dest);
+    }
+
+    Ok(())
+}
+
+fn partition_number(device: &str) -> Result<u32> {
+    let digits: String = device
+        .chars()
+        .rev()
+        .take_while(|c| c.is_ascii_digit())
+        .collect::<Vec<_>>()
+        .into_iter()
+        .rev()
+        .collect();
+
+    digits.parse::<u32>().context(format!("Could not determine partition number of {}", device))
+}
+
+/// Fill the recovery partition from `source` and register it as the tryboot partition.
+///
+/// `source` is either a directory with a complete boot file set, which is copied
+/// as-is, or a single initramfs, which is booted with the firmware and kernel
+/// copied from the target's own boot partition.
+pub fn populate_recovery(recovery_device: &str, boot_device: &str, source: &str, mounts: &MountPaths) -> Result<()> {
+    let source_path = Path::new(source);
+    if !source_path.exists() {
+        bail!("Recovery image {} does not exist", source);
+    }
+
+    let recovery_part = partition_number(recovery_device)?;
+    let boot_part = partition_number(boot_device)?;
+    let recovery_mount = mounts.path("recovery");
+    let boot_mount = mounts.boot();
+
+    mount_at(recovery_device, &recovery_mount)?;
+    if let Err(e) = mount_at(boot_device, &boot_mount) {
+        unmount_quiet(&recovery_mount);
+        return Err(e);
+    }
+
+    let result = (|| -> Result<()> {
+        if source_path.is_dir() {
+            println!("  Copying {} to recovery partition...", source);
+            copy_recursive(&format!("{}/.", source.trim_end_matches('/')), &recovery_mount)?;
+        } else {
+            println!("  Copying firmware and kernel from {}...", boot_device);
+            copy_recursive(&format!("{}/.", boot_mount), &recovery_mount)?;
+
+            // The target's autoboot.txt belongs on the boot partition only
+            let _ = std::fs::remove_file(format!("{}/autoboot.txt", recovery_mount));
+
+            println!("  Installing {} as {}...", source, RECOVERY_INITRAMFS);
+            std::fs::copy(source, format!("{}/{}", recovery_mount, RECOVERY_INITRAMFS))
+                .context("Failed to copy recovery initramfs")?;
+
+            crate::configtxt::edit(&format!("{}/config.txt", recovery_mount), |config| {
+                config.set("initramfs", &format!("{} followkernel", RECOVERY_INITRAMFS))
+            })
+            .context("Failed to write recovery config.txt")?;
+
+            // Boot straight into the initramfs rather than the (possibly broken) root
+            std::fs::write(format!("{}/cmdline.txt", recovery_mount), "console=serial0,115200 console=tty1 rdinit=/init\n")
+                .context("Failed to write recovery cmdline.txt")?;
+        }
+
+        write_autoboot(&boot_mount, boot_part, recovery_part)
+    })();
+
+    unmount_quiet(&boot_mount);
+    unmount_quiet(&recovery_mount);
+    result
+}
+
+/// Normal boots use the boot partition; `reboot '0 tryboot'` boots recovery
+fn write_autoboot(boot_mount: &str, boot_part: u32, recovery_part: u32) -> Result<()> {
+    let autoboot_path = format!("{}/autoboot.txt", boot_mount);
+    if Path::new(&autoboot_path).exists() {
+        println!("  Warning: replacing existing autoboot.txt");
+    }
+
+    let autoboot = format!(
+        "# Added by rpi-fs-shrink\n[all]\ntryboot_a_b=1\nboot_partition={}\n[tryboot]\nboot_partition={}\n",
+        boot_part, recovery_part
+    );
+    std::fs::write(&autoboot_path, autoboot).context("Failed to write autoboot.txt")?;
+
+    println!("  autoboot.txt: tryboot boots partition {}", recovery_part);
+    Ok(())
+}
@@ -0,0 +1,211 @@
+use anyhow::{Context, Result};
+use std::path::Path;
+
+use crate::{mount_at, unmount_quiet, MountPaths};
+
+/// Directories on the target root that hold configuration naming partitions by ID
+/// (initramfs resume config, GRUB, crypttab, kernel command lines, ...)
+const SCAN_DIRS: &[&str] = &["etc", "boot"];
+
+/// Files larger than this are not configuration
+const MAX_FILE_BYTES: u64 = 4 * 1024 * 1024;
+
+#[derive(Debug)]
+pub struct Reference {
+    pub path: String,
+    pub old: String,
+    pub new: String,
+    pub fixed: bool,
+}
+
+fn collect_files(dir: &Path, files: &mut Vec<std::path::PathBuf>) {
+    let Ok(entries) = std::fs::read_dir(dir) else {
+        return;
+    };
+    for entry in entries.flatten() {
+        let Ok(file_type) = entry.file_type() else {
+            continue;
+        };
+        if file_type.is_dir() {
+            collect_files(&entry.path(), files);
+        } else if file_type.is_file() {
+            files.push(entry.path());
+        }
+    }
+}
+
+/// Byte offsets where `id` appears in `text` whatever its case, as a whole
+/// identifier: not with a letter or digit right before or after it
+fn id_positions(text: &[u8], id: &str) -> Vec<usize> {
+    let id = id.as_bytes();
+    if id.is_empty() || text.len() < id.len() {
+        return Vec::new();
+    }
+    let boundary = |byte: Option<&u8>| !byte.is_some_and(u8::is_ascii_alphanumeric);
+    (0..=text.len() - id.len())
+        .filter(|&at| text[at..at + id.len()].eq_ignore_ascii_case(id))
+        .filter(|&at| boundary(at.checked_sub(1).and_then(|before| text.get(before))) && boundary(text.get(at + id.len())))
+        .collect()
+}
+
+/// `text` with each mention of `old` replaced by `new`, in uppercase where the
+/// mention was
+fn replace_id(text: &str, old: &str, new: &str) -> String {
+    let mut replaced = String::with_capacity(text.len());
+    let mut rest = 0;
+    for at in id_positions(text.as_bytes(), old) {
+        let mention = &text[at..at + old.len()];
+        replaced.push_str(&text[rest..at]);
+        let uppercase = mention.bytes().any(|byte| byte.is_ascii_uppercase()) && !mention.bytes().any(|byte| byte.is_ascii_lowercase());
+        replaced.push_str(&if uppercase { new.to_ascii_uppercase() } else { new.to_string() });
+        rest = at + old.len();
+    }
+    replaced.push_str(&text[rest..]);
+    replaced
+}
+
+/// Old and new identifiers of the partitions a run made in place of ones the
+/// target's fstab names: `created` holds the fstab target ("swap" for swap) with
+/// the new filesystem UUID and PARTUUID. A resume setting or crypttab line still
+/// naming the old /var, /home or swap is then rewritten with the rest.
+pub fn replaced_ids(fstab_content: &str, created: &[(&str, String, String)]) -> Result<Vec<(String, String)>> {
+    let mut changes = Vec::new();
+    for entry in crate::fstab::parse_fstab(fstab_content)? {
+        let key = if entry.fstype == "swap" { "swap" } else { entry.target.as_str() };
+        let Some((_, uuid, partuuid)) = created.iter().find(|(target, _, _)| *target == key) else {
+            continue;
+        };
+        let (old, new) = if let Some(old) = entry.source.strip_prefix("UUID=") {
+            (old, uuid)
+        } else if let Some(old) = entry.source.strip_prefix("PARTUUID=") {
+            (old, partuuid)
+        } else {
+            continue;
+        };
+        if !old.is_empty() && !new.is_empty() && !old.eq_ignore_ascii_case(new) {
+            changes.push((old.to_string(), new.clone()));
+        }
+    }
+    Ok(changes)
+}
+
+/// Find every mention of an old UUID/PARTUUID under the target's /etc and /boot.
+/// Text files are rewritten to the new identifier; binary files (an initramfs with
+/// the ID baked in) can't be patched and are only reported.
+pub fn update_references(root: &str, changes: &[(String, String)]) -> Result<Vec<Reference>> {
+    update_files(root, SCAN_DIRS, "", changes)
+}
+
+/// The same for every file on the boot partition `boot_device` (cmdline.txt,
+/// tryboot and extlinux configurations), listed as `boot:/PATH`
+pub fn update_boot_references(boot_device: &str, changes: &[(String, String)], mounts: &MountPaths) -> Result<Vec<Reference>> {
+    if changes.is_empty() {
+        return Ok(Vec::new());
+    }
+    let mount_point = mounts.boot();
+    mount_at(boot_device, &mount_point)?;
+    let result = update_files(&mount_point, &[""], "boot:", changes);
+    unmount_quiet(&mount_point);
+    result
+}
+
+/// Rewrite `changes` in the files under `dirs` of `root`, shown as `prefix/PATH`
+fn update_files(root: &str, dirs: &[&str], prefix: &str, changes: &[(String, String)]) -> Result<Vec<Reference>> {
+    let mut references = Vec::new();
+    if changes.is_empty() {
+        return Ok(references);
+    }
+
+    let mut files = Vec::new();
+    for dir in dirs {
+        collect_files(&Path::new(root).join(dir), &mut files);
+    }
+
+    for file in files {
+        if std::fs::metadata(&file).map(|m| m.len() > MAX_FILE_BYTES).unwrap_or(true) {
+            continue;
+        }
+        let Ok(bytes) = std::fs::read(&file) else {
+            continue;
+        };
+        let display = format!("{}/{}", prefix, file.strip_prefix(root).unwrap_or(&file).display());
+
+        let found: Vec<&(String, String)> = changes.iter().filter(|(old, _)| !id_positions(&bytes, old).is_empty()).collect();
+        if found.is_empty() {
+            continue;
+        }
+
+        match String::from_utf8(bytes) {
+            Ok(mut text) => {
+                for (old, new) in &found {
+                    text = replace_id(&text, old, new);
+                }
+                std::fs::write(&file, text).context(format!("Failed to update {}", display))?;
+                for (old, new) in found {
+                    references.push(Reference { path: display.clone(), old: old.clone(), new: new.clone(), fixed: true });
+                }
+            }
+            Err(_) => {
+                for (old, new) in found {
+                    references.push(Reference { path: display.clone(), old: old.clone(), new: new.clone(), fixed: false });
+                }
+            }
+        }
+    }
+
+    Ok(references)
+}
+
+pub fn print_references(references: &[Reference]) {
+    if references.is_empty() {
+        println!("  No other references to changed identifiers found");
+        return;
+    }
+
+    for reference in references {
+        if reference.fixed {
+            println!("  {}: {} -> {}", reference.path, reference.old, reference.new);
+        } else {
+            println!(
+                "  WARNING: {} (binary) still references {}; regenerate it on the target (e.g. update-initramfs -u)",
+                reference.path, reference.old
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ids_are_replaced_whole_and_whatever_their_case() {
+        let text = "RESUME=UUID=0A1B2C3D-0000-4000-8000-000000000001\nroot=PARTUUID=738a4d67-02 x=738a4d67-020\n";
+        assert_eq!(
+            replace_id(text, "0a1b2c3d-0000-4000-8000-000000000001", "5e6f7a8b-0000-4000-8000-000000000002"),
+            "RESUME=UUID=5E6F7A8B-0000-4000-8000-000000000002\nroot=PARTUUID=738a4d67-02 x=738a4d67-020\n"
+        );
+        // A longer identifier starting with the old one is left alone
+        assert_eq!(replace_id(text, "738A4D67-02", "9e1f03c2-02"), text.replace("=738a4d67-02 ", "=9e1f03c2-02 "));
+        assert!(id_positions(b"abc", "abcd").is_empty());
+    }
+
+    #[test]
+    fn replaced_partitions_pair_old_and_new_ids() {
+        let fstab = "PARTUUID=738a4d67-02  /      ext4  defaults  0 1
+UUID=1111-aaaa  /var   btrfs defaults  0 2
+PARTUUID=738a4d67-05  /home  ext4  defaults  0 2
+UUID=2222-bbbb  none   swap  sw        0 0
+/swapfile       none   swap  sw        0 0
+";
+        let created = [
+            ("/var", "3333-cccc".to_string(), "9e1f03c2-04".to_string()),
+            ("/home", "4444-dddd".to_string(), "9e1f03c2-05".to_string()),
+            ("swap", "2222-BBBB".to_string(), "9e1f03c2-03".to_string()),
+        ];
+        assert_eq!(
+            replaced_ids(fstab, &created).unwrap(),
+            vec![("1111-aaaa".to_string(), "3333-cccc".to_string()), ("738a4d67-05".to_string(), "9e1f03c2-05".to_string())]
+        );
+    }
+}
@@ -0,0 +1,678 @@
+//! Moving a partition's data in place on its disk.
+//!
+//! A move overwrites the data it reads from, so a crash halfway would leave
+//! neither copy whole. It goes chunk by chunk, each no longer than the distance
+//! moved, in the order that never writes over data still to be read: back to
+//! front when moving forward, front to back when moving back. A chunk therefore
+//! never overlaps its own source, and redoing the last one after a crash is
+//! safe. Before each chunk is written, its number and checksum are synced to a
+//! journal on the host, not on the disk being moved; the journal also records
+//! the disk's serial and WWN, a hash of its partition table and the entry the
+//! partition gets afterwards. Once all chunks are written, the whole range is
+//! read back and compared with the recorded checksums. A later run on the same
+//! disk finds the journal and finishes the move before doing anything else.
+//!
+//! Every disk has its own journal under [`JOURNAL_DIR`], named by its WWN or
+//! serial (by its path when it reports neither), and a run holds a lock on it
+//! from before the resume check until it exits, so runs on several disks at
+//! once (`batch`) never read or append to each other's.
+
+use anyhow::{anyhow, bail, Context, Result};
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::os::unix::fs::FileExt;
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::sync_channel;
+use std::time::Instant;
+
+use crate::blockcopy::{checksum, drop_cached, open_direct, AlignedBuffer, DIRECT_ALIGN, EXTENT_BYTES};
+use crate::identity::DiskIdentity;
+use crate::{DiskInfo, SECTOR_SIZE};
+
+/// Where the journals of in-place moves are kept, one per disk
+pub const JOURNAL_DIR: &str = "/var/lib/rpi-fs-shrink";
+
+/// The move journal of one disk, locked against other runs on that disk
+pub struct JournalLock {
+    path: PathBuf,
+    /// Holds the flock; None for a dry run, which only reads the journal
+    _file: Option<File>,
+}
+
+impl JournalLock {
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+/// Name of the journal for the disk `device` with `identity`: the WWN or serial
+/// follow the disk when its /dev name changes; the path is all an image file
+/// or a bare card reader offers
+fn journal_name(device: &str, identity: &DiskIdentity) -> String {
+    let key = if !identity.wwn.is_empty() {
+        format!("wwn-{}", identity.wwn.to_ascii_lowercase())
+    } else if !identity.serial.is_empty() {
+        format!("serial-{}", identity.serial)
+    } else {
+        let path = std::fs::canonicalize(device).map(|path| path.to_string_lossy().to_string()).unwrap_or_else(|_| device.to_string());
+        format!("path{}", path)
+    };
+    let key: String = key.chars().map(|c| if c.is_ascii_alphanumeric() || c == '-' { c } else { '_' }).collect();
+    format!("move-journal-{}", key)
+}
+
+/// Lock the journal of `disk_info`'s disk for this run. Another run on the
+/// same disk holding it is refused rather than waited for.
+pub fn lock_journal(disk_info: &DiskInfo, dry_run: bool) -> Result<JournalLock> {
+    let path = Path::new(JOURNAL_DIR).join(journal_name(&disk_info.device, &disk_info.identity));
+    if dry_run {
+        return Ok(JournalLock { path, _file: None });
+    }
+    std::fs::create_dir_all(JOURNAL_DIR).context(format!("Failed to create {}", JOURNAL_DIR))?;
+    lock_at(path, &disk_info.device)
+}
+
+fn lock_at(path: PathBuf, device: &str) -> Result<JournalLock> {
+    let lock_path = path.with_extension("lock");
+    let file = OpenOptions::new()
+        .create(true)
+        .truncate(false)
+        .write(true)
+        .open(&lock_path)
+        .context(format!("Failed to open {}", lock_path.display()))?;
+    // SAFETY: the descriptor belongs to `file`, which outlives the call
+    if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) } != 0 {
+        let err = std::io::Error::last_os_error();
+        if err.kind() == std::io::ErrorKind::WouldBlock {
+            bail!("Another run is working on {} (it holds {}); wait for it to finish", device, lock_path.display());
+        }
+        return Err(err).context(format!("Failed to lock {}", lock_path.display()));
+    }
+    Ok(JournalLock { path, _file: Some(file) })
+}
+
+/// A partition whose data moves in place, and the entry it gets once it has
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PartitionMove {
+    pub number: u32,
+    /// Filesystem type, for the partition type of the new entry ("" for none)
+    pub fstype: String,
+    pub from: u64,
+    pub to: u64,
+    pub bytes: u64,
+    /// Last sector of the partition at its new place
+    pub end: u64,
+}
+
+/// What the journal records: the move, the disk it is on, and the chunks
+/// started so far with the checksum of their data, in the order written
+#[derive(Debug, PartialEq, Eq)]
+struct Journal {
+    /// The command that started the move: "run", "adjust" or "pack"
+    by: String,
+    part: PartitionMove,
+    chunk: u64,
+    disk: DiskIdentity,
+    /// SHA-256 of the partition table when the move started
+    table: String,
+    chunks: Vec<(u64, u64)>,
+}
+
+impl Journal {
+    fn header(&self) -> String {
+        let fstype = if self.part.fstype.is_empty() { "-" } else { &self.part.fstype };
+        format!(
+            "by {}\nmove {} {} {} {}\npartition {} {} {}\ndisk serial={} wwn={}\ntable {}\n",
+            self.by,
+            self.part.from,
+            self.part.to,
+            self.part.bytes,
+            self.chunk,
+            self.part.number,
+            self.part.end,
+            fstype,
+            self.disk.serial,
+            self.disk.wwn,
+            self.table
+        )
+    }
+
+    /// Chunk numbers in the order they are copied
+    fn order(&self) -> Vec<u64> {
+        chunk_order(self.part.from, self.part.to, self.bytes(), self.chunk)
+    }
+
+    /// Bytes moved: whole O_DIRECT blocks
+    fn bytes(&self) -> u64 {
+        self.part.bytes.div_ceil(DIRECT_ALIGN as u64) * DIRECT_ALIGN as u64
+    }
+
+    /// Offset and length of chunk `index` within the moved range
+    fn range(&self, index: u64) -> (u64, usize) {
+        let offset = index * self.chunk;
+        (offset, self.chunk.min(self.bytes() - offset) as usize)
+    }
+}
+
+/// Read journal text, written by `Journal::header` and one `chunk INDEX SUM`
+/// line per chunk. A line cut off by a crash (no newline) is ignored.
+fn parse_journal(text: &str) -> Result<Journal> {
+    let complete = match text.rfind('\n') {
+        Some(end) => &text[..end],
+        None => "",
+    };
+    let (mut by, mut region, mut partition, mut disk, mut table) = (None, None, None, None, None);
+    let mut chunks = Vec::new();
+    for line in complete.lines() {
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        let number = |field: &str| field.parse::<u64>().context(format!("Bad line in the move journal: {}", line));
+        match fields.as_slice() {
+            ["by", command] => by = Some(command.to_string()),
+            ["move", from, to, bytes, chunk] => region = Some((number(from)?, number(to)?, number(bytes)?, number(chunk)?)),
+            ["partition", part, end, fstype] => {
+                let fstype = if *fstype == "-" { "" } else { fstype };
+                partition = Some((u32::try_from(number(part)?)?, number(end)?, fstype.to_string()));
+            }
+            ["disk", rest @ ..] => {
+                let mut identity = DiskIdentity::default();
+                for (key, value) in rest.iter().filter_map(|field| field.split_once('=')) {
+                    match key {
+                        "serial" => identity.serial = value.to_string(),
+                        "wwn" => identity.wwn = value.to_string(),
+                        _ => {}
+                    }
+                }
+                disk = Some(identity);
+            }
+            ["table", hash] => table = Some(hash.to_string()),
+            ["chunk", index, sum] => {
+                let sum = u64::from_str_radix(sum, 16).context(format!("Bad line in the move journal: {}", line))?;
+                chunks.push((number(index)?, sum));
+            }
+            _ => bail!("Unknown line in the move journal: {}", line),
+        }
+    }
+    let (Some(by), Some((from, to, bytes, chunk)), Some((number, end, fstype)), Some(disk), Some(table)) =
+        (by, region, partition, disk, table)
+    else {
+        bail!("The move journal is incomplete");
+    };
+    let journal = Journal { by, part: PartitionMove { number, fstype, from, to, bytes, end }, chunk, disk, table, chunks };
+    if journal.chunk == 0 || journal.chunks.iter().map(|&(index, _)| index).ne(journal.order().into_iter().take(journal.chunks.len())) {
+        bail!("The move journal doesn't record its chunks in the order they are moved");
+    }
+    Ok(journal)
+}
+
+/// Chunks of `chunk` bytes a `bytes` move from sector `from` to `to` copies,
+/// in an order that never overwrites data still to be read
+fn chunk_order(from: u64, to: u64, bytes: u64, chunk: u64) -> Vec<u64> {
+    let count = bytes.div_ceil(chunk);
+    if to > from { (0..count).rev().collect() } else { (0..count).collect() }
+}
+
+/// Chunk size for a move by `shift` bytes: an extent, or less so no chunk
+/// overlaps where it is written to
+fn chunk_bytes(shift: u64) -> u64 {
+    shift.min(EXTENT_BYTES as u64) / DIRECT_ALIGN as u64 * DIRECT_ALIGN as u64
+}
+
+fn append(file: &mut File, line: &str) -> Result<()> {
+    file.write_all(line.as_bytes()).context("Failed to write the move journal")?;
+    file.sync_data().context("Failed to sync the move journal")
+}
+
+/// Copy the chunks of `journal` not yet done on `device`, recording each in
+/// `journal_file` first, then read the whole range back against the checksums.
+/// Two buffers take turns: the next chunk is read while the current one is
+/// written and verified. Its source never overlaps where the current one goes
+/// (both are no longer than the distance moved), so reading ahead is safe.
+fn copy_chunks(device: &str, journal: &mut Journal, journal_file: &mut File) -> Result<bool> {
+    let (file, direct) = open_direct(device, true)?;
+    let (src, dst) = (journal.part.from * SECTOR_SIZE, journal.part.to * SECTOR_SIZE);
+    let order = journal.order();
+    // The last chunk recorded may have been cut off while it was written; it is done again
+    let resume_at = journal.chunks.len().saturating_sub(1);
+    let ranges: Vec<(u64, u64, usize)> = order.iter().map(|&index| (index, journal.range(index).0, journal.range(index).1)).collect();
+    let mut verify = AlignedBuffer::new(journal.chunk as usize);
+    let start = Instant::now();
+
+    let (full_tx, full_rx) = sync_channel::<(usize, AlignedBuffer, u64)>(1);
+    let (empty_tx, empty_rx) = sync_channel::<AlignedBuffer>(2);
+    for _ in 0..2 {
+        empty_tx.send(AlignedBuffer::new(journal.chunk as usize)).expect("the receiver is still here");
+    }
+    std::thread::scope(|scope| -> Result<()> {
+        let (file, ranges) = (&file, &ranges);
+        let reader = scope.spawn(move || -> Result<()> {
+            for (position, &(index, offset, len)) in ranges.iter().enumerate().skip(resume_at) {
+                // Either fails only once the writer gave up; its error is reported instead
+                let Ok(mut buffer) = empty_rx.recv() else {
+                    return Ok(());
+                };
+                file.read_exact_at(buffer.as_mut_slice(len), src + offset)
+                    .context(format!("Read failed at chunk {} (byte {})", index, src + offset))?;
+                let sum = checksum(buffer.as_slice(len));
+                if full_tx.send((position, buffer, sum)).is_err() {
+                    return Ok(());
+                }
+            }
+            Ok(())
+        });
+
+        let written = (|| -> Result<()> {
+            while let Ok((position, buffer, sum)) = full_rx.recv() {
+                let (index, offset, len) = ranges[position];
+                match journal.chunks.get(position) {
+                    Some(&(_, recorded)) if recorded != sum => {
+                        bail!("Chunk {} no longer holds the data the journal recorded; the disk changed since the move was interrupted", index)
+                    }
+                    Some(_) => {}
+                    None => {
+                        append(journal_file, &format!("chunk {} {:016x}\n", index, sum))?;
+                        journal.chunks.push((index, sum));
+                    }
+                }
+                file.write_all_at(buffer.as_slice(len), dst + offset)
+                    .context(format!("Write failed at chunk {} (byte {})", index, dst + offset))?;
+                // The chunk is on the disk before the journal moves on to the next
+                if direct {
+                    file.sync_data().context(format!("Failed to flush {}", device))?;
+                } else {
+                    drop_cached(file, dst + offset, len)?;
+                }
+                file.read_exact_at(verify.as_mut_slice(len), dst + offset)
+                    .context(format!("Verify read failed at chunk {}", index))?;
+                if checksum(verify.as_slice(len)) != sum {
+                    bail!("Checksum mismatch after writing chunk {} (byte {})", index, dst + offset);
+                }
+                let _ = empty_tx.send(buffer);
+                let done = position as u64 + 1;
+                if done % 256 == 0 || done == order.len() as u64 {
+                    let copied = (done * journal.chunk).min(journal.bytes());
+                    let secs = start.elapsed().as_secs_f64().max(0.001);
+                    println!(
+                        "    {}/{} MB moved ({:.1} MB/s)",
+                        copied / (1024 * 1024),
+                        journal.bytes() / (1024 * 1024),
+                        copied.saturating_sub(resume_at as u64 * journal.chunk) as f64 / (1024.0 * 1024.0) / secs
+                    );
+                }
+            }
+            Ok(())
+        })();
+        // Unblock the reader if the writer stopped early
+        drop(full_rx);
+        drop(empty_tx);
+        let read = reader.join().map_err(|_| anyhow!("The reading thread panicked"))?;
+        written?;
+        read
+    })?;
+
+    println!("  Verifying the moved data...");
+    for &(index, sum) in &journal.chunks {
+        let (offset, len) = journal.range(index);
+        if !direct {
+            drop_cached(&file, dst + offset, len)?;
+        }
+        file.read_exact_at(verify.as_mut_slice(len), dst + offset)
+            .context(format!("Verify read failed at chunk {}", index))?;
+        if checksum(verify.as_slice(len)) != sum {
+            bail!("Chunk {} (byte {}) doesn't match the data moved there", index, dst + offset);
+        }
+    }
+    Ok(direct)
+}
+
+fn read_journal(path: &Path) -> Result<Option<Journal>> {
+    match std::fs::read_to_string(path) {
+        Ok(text) => Ok(Some(parse_journal(&text).context(format!("Failed to read {}", path.display()))?)),
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(None),
+        Err(err) => Err(err).context(format!("Failed to read {}", path.display())),
+    }
+}
+
+/// Move `part`'s data on `path` with `journal_path` recording the progress
+fn journaled_move(path: &str, journal_path: &Path, journal: Journal) -> Result<bool> {
+    let part = &journal.part;
+    let (low, high) = (part.from.min(part.to), part.from.max(part.to));
+    let chunk = chunk_bytes((high - low) * SECTOR_SIZE);
+    if chunk == 0 || (part.from * SECTOR_SIZE) % DIRECT_ALIGN as u64 != 0 || (part.to * SECTOR_SIZE) % DIRECT_ALIGN as u64 != 0 {
+        bail!("Partition data moves by whole {}-byte blocks; sectors {} and {} aren't on one", DIRECT_ALIGN, part.from, part.to);
+    }
+    let mut journal = Journal { chunk, ..journal };
+    if let Some(dir) = journal_path.parent() {
+        std::fs::create_dir_all(dir).context(format!("Failed to create {}", dir.display()))?;
+    }
+    let mut file = OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(journal_path)
+        .context(format!("Failed to create {}", journal_path.display()))?;
+    append(&mut file, &journal.header())?;
+    copy_chunks(path, &mut journal, &mut file)
+}
+
+/// Move `part`'s data on the disk for the command `by`, journaled and verified.
+/// The journal stays until the caller has put the partition entry at its new
+/// place and called [`finish_move`].
+pub fn move_partition_data(lock: &JournalLock, disk_info: &DiskInfo, part: &PartitionMove, by: &str) -> Result<()> {
+    if part.from == part.to {
+        return Ok(());
+    }
+    if let Some(journal) = read_journal(lock.path())? {
+        bail!(
+            "{} records an unfinished move of partition {} ({}); run again to finish it, \
+            or remove the file if the disk was dealt with otherwise",
+            lock.path().display(),
+            journal.part.number,
+            journal.disk.describe()
+        );
+    }
+
+    println!("  Moving {} MB from sector {} to sector {}...", part.bytes / (1024 * 1024), part.from, part.to);
+    let table = crate::tablecheck::table_hash(&disk_info.device)?;
+    let start = Instant::now();
+    let journal =
+        Journal { by: by.to_string(), part: part.clone(), chunk: 0, disk: disk_info.identity.clone(), table, chunks: Vec::new() };
+    let direct = journaled_move(&disk_info.device, lock.path(), journal)?;
+    println!(
+        "  Moved and verified {} MB in {:.1}s{}",
+        part.bytes / (1024 * 1024),
+        start.elapsed().as_secs_f64(),
+        if direct { " (direct I/O)" } else { "" }
+    );
+    Ok(())
+}
+
+/// Drop the journal once the moved partition's entry points at its new place
+pub fn finish_move(lock: &JournalLock) -> Result<()> {
+    std::fs::remove_file(lock.path()).context(format!("Failed to remove {}", lock.path().display()))
+}
+
+/// Finish a move of `disk_info`'s disk that an earlier run left in its journal:
+/// the rest of the data, the check against the recorded checksums and the
+/// partition's new entry. The run that was interrupted stopped there, so this
+/// stops the current one afterwards. Nothing to do when the journal is absent.
+/// A journal of another disk, or one whose disk can't be told apart by serial
+/// or WWN (unless `resume_unidentified`), or a changed partition table is refused.
+pub fn resume_interrupted_move(lock: &JournalLock, disk_info: &DiskInfo, dry_run: bool, resume_unidentified: bool) -> Result<()> {
+    let Some(mut journal) = read_journal(lock.path())? else {
+        return Ok(());
+    };
+    let part = journal.part.clone();
+    check_resume(&journal, disk_info, resume_unidentified, lock.path())?;
+    if crate::tablecheck::table_hash(&disk_info.device)? != journal.table {
+        // The entry was moved just before the journal could be dropped
+        if crate::get_partition_bounds(&disk_info.device, part.number).is_ok_and(|bounds| bounds == (part.to, part.end)) {
+            println!("Partition {} was already moved to sector {}; removing {}", part.number, part.to, lock.path().display());
+            return if dry_run { Ok(()) } else { finish_move(lock) };
+        }
+        bail!(
+            "The partition table of {} changed since the interrupted move in {}; refusing to resume onto it. \
+            Partition {} was being moved from sector {} to {}",
+            disk_info.device,
+            lock.path().display(),
+            part.number,
+            part.from,
+            part.to
+        );
+    }
+    if dry_run {
+        bail!(
+            "An interrupted move of partition {} from sector {} to {} is recorded in {}; run without --dry-run to finish it first",
+            part.number,
+            part.from,
+            part.to,
+            lock.path().display()
+        );
+    }
+    if disk_info.partition_table == "gpt" && !crate::command_exists("sgdisk") {
+        bail!("Finishing the interrupted move of partition {} needs sgdisk (gdisk package) to keep its PARTUUID", part.number);
+    }
+
+    println!(
+        "Finishing the interrupted move of partition {} from sector {} to {} ({} of {} chunks done)...",
+        part.number,
+        part.from,
+        part.to,
+        journal.chunks.len().saturating_sub(1),
+        journal.order().len()
+    );
+    let mut file = OpenOptions::new().append(true).open(lock.path()).context(format!("Failed to open {}", lock.path().display()))?;
+    copy_chunks(&disk_info.device, &mut journal, &mut file)?;
+    crate::shrinkpart::recreate_keeping_entry(disk_info, part.number, &part.fstype, part.to, part.end)?;
+    finish_move(lock)?;
+    let next = match journal.by.as_str() {
+        "pack" => "run pack again to move the partitions behind it",
+        "adjust" => "the side gaining the space still has to grow: its partition with parted resizepart if it is the front one, then its filesystem with resize2fs or btrfs filesystem resize max",
+        _ => "run it again with the same options to carry on",
+    };
+    bail!(
+        "Finished the interrupted move: partition {} now holds sectors {}-{}. The {} it belonged to stopped there; {}",
+        part.number,
+        part.to,
+        part.end,
+        journal.by,
+        next
+    );
+}
+
+/// Refuse to resume `journal` on `disk_info`'s disk unless it is the disk the
+/// journal was written on, or the user vouches for that where nothing can tell
+fn check_resume(journal: &Journal, disk_info: &DiskInfo, resume_unidentified: bool, path: &Path) -> Result<()> {
+    if journal.disk.differs_from(&disk_info.identity) {
+        bail!(
+            "The interrupted move in {} was made on another disk ({}); this is {} ({}). Refusing to resume onto it",
+            path.display(),
+            journal.disk.describe(),
+            disk_info.device,
+            disk_info.identity.describe()
+        );
+    }
+    if !journal.disk.comparable_with(&disk_info.identity) && !resume_unidentified {
+        bail!(
+            "{} records an interrupted move of partition {}, but neither it ({}) nor {} ({}) has a serial or WWN \
+            to check it is the same disk. If it is, finish the move with --resume-unidentified",
+            path.display(),
+            journal.part.number,
+            journal.disk.describe(),
+            disk_info.device,
+            disk_info.identity.describe()
+        );
+    }
+    Ok(())
+}
+
+pub fn get_partuuid(device: &str) -> Result<String> {
+    let values = crate::blkid::values(device).context(format!("Failed to get PARTUUID for {}", device))?;
+    Ok(crate::blkid::value(&values, "PARTUUID").unwrap_or_default())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pattern(len: usize) -> Vec<u8> {
+        (0..len).map(|i| (i / 4096) as u8 ^ (i % 253) as u8).collect()
+    }
+
+    fn part(from: u64, to: u64, bytes: u64) -> PartitionMove {
+        PartitionMove { number: 3, fstype: "ext4".to_string(), from, to, bytes, end: to + bytes / SECTOR_SIZE - 1 }
+    }
+
+    #[test]
+    fn resumes_recreate_encrypted_and_lvm_roots_with_their_type() {
+        for fstype in ["crypto_LUKS", "LVM2_member"] {
+            let journal = Journal {
+                by: "run".to_string(),
+                part: PartitionMove { fstype: fstype.to_string(), ..part(2048, 4096, 4096) },
+                chunk: 4096,
+                disk: DiskIdentity { serial: "A1".to_string(), ..Default::default() },
+                table: "t".to_string(),
+                chunks: vec![(0, 0xbeef)],
+            };
+            let text = format!("{}chunk 0 {:016x}\n", journal.header(), 0xbeef);
+            assert!(text.contains(&format!("partition 3 {} {}\n", journal.part.end, fstype)), "{}", text);
+            // What resume_interrupted_move hands to recreate_keeping_entry
+            assert_eq!(parse_journal(&text).unwrap().part.fstype, fstype);
+        }
+    }
+
+    #[test]
+    fn chunks_never_overwrite_data_still_to_be_read() {
+        assert_eq!(chunk_bytes(64 * 1024 * 1024), EXTENT_BYTES as u64);
+        assert_eq!(chunk_bytes(1024 * 1024), 1024 * 1024);
+        assert_eq!(chunk_bytes(4096 + 512), 4096);
+        assert_eq!(chunk_bytes(2048), 0);
+        assert_eq!(chunk_order(2048, 4096, 10 * 4096, 4096), (0..10).rev().collect::<Vec<_>>());
+        assert_eq!(chunk_order(4096, 2048, 10 * 4096 + 1, 4096), (0..11).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn journal_round_trips_and_ignores_a_cut_off_line() {
+        let mut journal = Journal {
+            by: "adjust".to_string(),
+            part: PartitionMove { fstype: String::new(), ..part(2048, 4096, 1024 * 1024) },
+            chunk: 1024 * 1024,
+            disk: DiskIdentity { serial: "0x1234".to_string(), ..Default::default() },
+            table: "ab12".to_string(),
+            chunks: vec![(0, 0xdead)],
+        };
+        let text = format!("{}chunk 0 {:016x}\nchunk 1 00", journal.header(), 0xdead);
+        assert_eq!(parse_journal(&text).unwrap(), journal);
+        journal.chunks.clear();
+        assert_eq!(parse_journal(&journal.header()).unwrap(), journal);
+        assert!(parse_journal("by pack\nmove 1 2 3 4\n").is_err());
+        assert!(parse_journal(&format!("{}chunk 5 00\n", journal.header())).is_err());
+    }
+
+    #[test]
+    fn interrupted_moves_resume_both_ways() {
+        let dir = std::env::temp_dir().join(format!("rpi-fs-shrink-relocate-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let (image, journal_path) = (dir.join("disk.img"), dir.join("move-journal"));
+        let path = image.to_str().unwrap();
+        let data = pattern(3 * 1024 * 1024 + 8192);
+        let journal = |part: PartitionMove| Journal {
+            by: "pack".to_string(),
+            part,
+            chunk: 0,
+            disk: DiskIdentity::default(),
+            table: "t".to_string(),
+            chunks: Vec::new(),
+        };
+
+        // Forward by 1 MiB: twice as many chunks as the data is MiB long, overlapping
+        for (from, to) in [(0u64, 2048u64), (2048, 0)] {
+            let mut contents = vec![0u8; data.len() + 1024 * 1024];
+            contents[(from * SECTOR_SIZE) as usize..][..data.len()].copy_from_slice(&data);
+            std::fs::write(&image, &contents).unwrap();
+            let _ = std::fs::remove_file(&journal_path);
+            journaled_move(path, &journal_path, journal(part(from, to, data.len() as u64))).unwrap();
+            let moved = std::fs::read(&image).unwrap();
+            assert_eq!(&moved[(to * SECTOR_SIZE) as usize..][..data.len()], &data[..]);
+
+            // Cut off after two chunks, the second only half written: resuming finishes it
+            std::fs::write(&image, &contents).unwrap();
+            let mut journal = parse_journal(&std::fs::read_to_string(&journal_path).unwrap()).unwrap();
+            journal.chunks.truncate(2);
+            let mut partial = contents.clone();
+            for (n, &(index, _)) in journal.chunks.iter().enumerate() {
+                let (offset, len) = journal.range(index);
+                let len = if n == 1 { len / 2 } else { len };
+                let src = (from * SECTOR_SIZE + offset) as usize;
+                let chunk = contents[src..src + len].to_vec();
+                partial[(to * SECTOR_SIZE + offset) as usize..][..len].copy_from_slice(&chunk);
+            }
+            std::fs::write(&image, &partial).unwrap();
+            let lines: String = journal.chunks.iter().map(|(index, sum)| format!("chunk {} {:016x}\n", index, sum)).collect();
+            std::fs::write(&journal_path, format!("{}{}", journal.header(), lines)).unwrap();
+            let mut file = OpenOptions::new().append(true).open(&journal_path).unwrap();
+            copy_chunks(path, &mut journal, &mut file).unwrap();
+            let resumed = std::fs::read(&image).unwrap();
+            assert_eq!(&resumed[(to * SECTOR_SIZE) as usize..][..data.len()], &data[..]);
+            assert_eq!(parse_journal(&std::fs::read_to_string(&journal_path).unwrap()).unwrap().chunks.len(), journal.order().len());
+        }
+
+        // A journal left behind stops the next move
+        assert!(journaled_move(path, &journal_path, journal(part(0, 2048, 4096))).is_err());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn every_disk_has_its_own_journal_and_lock() {
+        let identity = |serial: &str, wwn: &str| DiskIdentity { serial: serial.to_string(), wwn: wwn.to_string(), ..Default::default() };
+        assert_eq!(journal_name("/dev/sdb", &identity("A1", "0x5001B44E")), "move-journal-wwn-0x5001b44e");
+        assert_eq!(journal_name("/dev/sdb", &identity("A1 B/2", "")), "move-journal-serial-A1_B_2");
+        // Card readers without a serial: two cloned cards still get two journals
+        assert_ne!(
+            journal_name("/dev/rpi-fs-shrink-test/sdb", &DiskIdentity::default()),
+            journal_name("/dev/rpi-fs-shrink-test/sdc", &DiskIdentity::default())
+        );
+
+        let path = std::env::temp_dir().join(format!("rpi-fs-shrink-journal-lock-{}", std::process::id()));
+        let lock = lock_at(path.clone(), "/dev/sdb").unwrap();
+        let err = lock_at(path.clone(), "/dev/sdb").err().unwrap().to_string();
+        assert!(err.contains("Another run is working on /dev/sdb"), "{}", err);
+        drop(lock);
+        drop(lock_at(path.clone(), "/dev/sdb").unwrap());
+        std::fs::remove_file(path.with_extension("lock")).unwrap();
+    }
+
+    #[test]
+    fn resumes_need_the_disk_the_journal_names() {
+        let identity = |serial: &str| DiskIdentity { serial: serial.to_string(), ..Default::default() };
+        let journal = |serial: &str| Journal {
+            by: "run".to_string(),
+            part: part(2048, 4096, 4096),
+            chunk: 4096,
+            disk: identity(serial),
+            table: "t".to_string(),
+            chunks: Vec::new(),
+        };
+        let disk = |serial: &str| DiskInfo {
+            device: "/dev/sdb".to_string(),
+            size_bytes: 0,
+            size_sectors: 0,
+            is_sd_card: false,
+            root_partition: "/dev/sdb2".to_string(),
+            roles: crate::roles::Roles { boot: 1, root: 2, found_by: "position" },
+            partition_table: "msdos".to_string(),
+            erase_block_bytes: None,
+            identity: identity(serial),
+        };
+        let path = Path::new("/var/lib/rpi-fs-shrink/move-journal-test");
+        assert!(check_resume(&journal("A1"), &disk("A1"), false, path).is_ok());
+        let err = check_resume(&journal("A1"), &disk("B2"), true, path).err().unwrap().to_string();
+        assert!(err.contains("made on another disk"), "{}", err);
+        // Nothing to compare: only on the user's word
+        let err = check_resume(&journal(""), &disk(""), false, path).err().unwrap().to_string();
+        assert!(err.contains("--resume-unidentified"), "{}", err);
+        assert!(check_resume(&journal("A1"), &disk(""), false, path).is_err());
+        assert!(check_resume(&journal(""), &disk(""), true, path).is_ok());
+    }
+
+    #[test]
+    fn a_changed_chunk_stops_the_copy_and_its_reader() {
+        let dir = std::env::temp_dir().join(format!("rpi-fs-shrink-relocate-changed-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let (image, journal_path) = (dir.join("disk.img"), dir.join("move-journal"));
+        std::fs::write(&image, pattern(8 * 1024 * 1024)).unwrap();
+        let mut journal = Journal {
+            by: "pack".to_string(),
+            part: part(2048, 0, 6 * 1024 * 1024),
+            chunk: 1024 * 1024,
+            disk: DiskIdentity::default(),
+            table: "t".to_string(),
+            // Chunk 0 read back with other data than the journal recorded
+            chunks: vec![(0, 0)],
+        };
+        std::fs::write(&journal_path, format!("{}chunk 0 {:016x}\n", journal.header(), 0)).unwrap();
+        let mut file = OpenOptions::new().append(true).open(&journal_path).unwrap();
+        let err = copy_chunks(image.to_str().unwrap(), &mut journal, &mut file).err().unwrap().to_string();
+        assert!(err.contains("Chunk 0 no longer holds"), "{}", err);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
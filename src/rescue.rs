@@ -0,0 +1,274 @@
+//! A small bootable rescue SD image: the Pi's firmware and kernel, and an
+//! initramfs with a static rpi-fs-shrink, busybox and optional tools. Booting
+//! it gives a shell from which a USB disk can be shrunk, without a second Linux
+//! machine and with nothing mounted from the disk being worked on.
+
+use anyhow::{anyhow, bail, Context, Result};
+use std::os::unix::fs::PermissionsExt;
+use std::path::Path;
+use std::process::Command;
+
+use crate::format::{run_format_jobs, FormatJob};
+use crate::recovery::copy_recursive;
+use crate::{command_exists, container, get_partition_device, mount_at, unmount_quiet, MountPaths};
+
+const MIB: u64 = 1024 * 1024;
+
+/// Name of the initramfs on the rescue image's FAT partition
+const RESCUE_INITRAMFS: &str = "initramfs-rescue";
+
+/// Boot files of the source that the rescue image writes itself
+const REPLACED_FILES: &[&str] = &["config.txt", "cmdline.txt", "autoboot.txt", "tryboot.txt", "cmdline-tryboot.txt"];
+
+const INIT_SCRIPT: &str = "#!/bin/busybox sh
+/bin/busybox --install -s /bin
+export PATH=/bin:/sbin
+mount -t proc proc /proc
+mount -t sysfs sysfs /sys
+mount -t devtmpfs devtmpfs /dev
+mkdir -p /dev/pts && mount -t devpts devpts /dev/pts
+mount -t tmpfs tmpfs /tmp
+mount -t tmpfs tmpfs /run
+# btrfs (for /var) is a module in Raspberry Pi kernels
+[ -d /lib/modules ] && modprobe btrfs 2>/dev/null
+# USB disks take a moment to show up
+sleep 3
+echo
+echo 'rpi-fs-shrink rescue shell'
+echo 'Disks:'
+grep -v -e '^major' -e '^$' /proc/partitions
+echo
+echo 'Shrink a disk with e.g.: rpi-fs-shrink -d /dev/sda -r 8G -v 4G'
+echo 'Power off with: poweroff -f'
+echo
+exec setsid cttyhack sh
+";
+
+/// What goes into the rescue image
+pub struct RescueSources {
+    /// Boot partition files of Raspberry Pi OS: firmware, kernels, device trees and overlays
+    pub boot_files: String,
+    /// rpi-fs-shrink built statically for the Pi
+    pub binary: String,
+    /// busybox built statically for the Pi
+    pub busybox: String,
+    /// More static tools (parted, e2fsprogs, rsync, ...) with a SHA256SUMS, as for --tools-dir
+    pub tools_dir: Option<String>,
+    /// A /lib/modules/<version> tree for the kernel, for filesystems built as modules
+    pub modules: Option<String>,
+}
+
+/// Machine and whether an ELF binary needs a dynamic loader
+fn elf_info(path: &str) -> Result<(u16, bool)> {
+    let data = std::fs::read(path).context(format!("Failed to read {}", path))?;
+    if data.len() < 64 || &data[..4] != b"\x7fELF" || data[5] != 1 {
+        bail!("{} is not a little-endian ELF binary", path);
+    }
+    let u16_at = |at: usize| u16::from_le_bytes([data[at], data[at + 1]]);
+    let u32_at = |at: usize| u32::from_le_bytes(data[at..at + 4].try_into().unwrap());
+    let (phoff, phentsize, phnum) = if data[4] == 2 {
+        (u64::from_le_bytes(data[32..40].try_into().unwrap()) as usize, u16_at(54) as usize, u16_at(56) as usize)
+    } else {
+        (u32_at(28) as usize, u16_at(42) as usize, u16_at(44) as usize)
+    };
+    const PT_INTERP: u32 = 3;
+    let dynamic = (0..phnum)
+        .map(|index| phoff + index * phentsize)
+        .take_while(|&at| at + 4 <= data.len())
+        .any(|at| u32_at(at) == PT_INTERP);
+    Ok((u16_at(18), dynamic))
+}
+
+fn machine_name(machine: u16) -> &'static str {
+    match machine {
+        183 => "aarch64",
+        40 => "arm",
+        62 => "x86_64",
+        _ => "another architecture",
+    }
+}
+
+/// The binary has to run on a Pi without any libraries next to it; returns
+/// whether it is 64-bit
+fn check_pi_binary(path: &str, what: &str) -> Result<bool> {
+    let (machine, dynamic) = elf_info(path)?;
+    if machine != 183 && machine != 40 {
+        bail!(
+            "{} {} is built for {}; the rescue image needs an ARM build \
+            (e.g. cargo build --release --target aarch64-unknown-linux-musl)",
+            what,
+            path,
+            machine_name(machine)
+        );
+    }
+    if dynamic {
+        bail!("{} {} is dynamically linked; the rescue image has no libraries, so it needs a static build", what, path);
+    }
+    Ok(machine == 183)
+}
+
+fn install_file(source: &str, dest: &str) -> Result<()> {
+    std::fs::copy(source, dest).context(format!("Failed to copy {} to {}", source, dest))?;
+    std::fs::set_permissions(dest, std::fs::Permissions::from_mode(0o755)).context(format!("Failed to set permissions on {}", dest))
+}
+
+/// Total size of the files under `path`
+fn tree_bytes(path: &Path) -> u64 {
+    match std::fs::symlink_metadata(path) {
+        Ok(meta) if meta.is_dir() => std::fs::read_dir(path)
+            .map(|entries| entries.flatten().map(|entry| tree_bytes(&entry.path())).sum())
+            .unwrap_or(0),
+        Ok(meta) => meta.len(),
+        Err(_) => 0,
+    }
+}
+
+/// Build the initramfs in `staging` and pack it to `output` as a gzip'd cpio archive
+fn build_initramfs(sources: &RescueSources, staging: &str, output: &str) -> Result<()> {
+    for dir in ["bin", "sbin", "dev", "proc", "sys", "tmp", "run", "mnt", "etc", "lib", "var/log"] {
+        std::fs::create_dir_all(format!("{}/{}", staging, dir)).context(format!("Failed to create {}/{}", staging, dir))?;
+    }
+    install_file(&sources.busybox, &format!("{}/bin/busybox", staging))?;
+    install_file(&sources.binary, &format!("{}/bin/rpi-fs-shrink", staging))?;
+    std::fs::write(format!("{}/init", staging), INIT_SCRIPT).context("Failed to write init")?;
+    std::fs::set_permissions(format!("{}/init", staging), std::fs::Permissions::from_mode(0o755))?;
+    // For tools that still read the mount table from /etc/mtab
+    std::os::unix::fs::symlink("/proc/mounts", format!("{}/etc/mtab", staging)).context("Failed to create /etc/mtab")?;
+
+    if let Some(ref dir) = sources.tools_dir {
+        let count = crate::tools::verify_dir(dir)?;
+        for entry in std::fs::read_dir(dir).context(format!("Failed to read {}", dir))?.flatten() {
+            let name = entry.file_name().to_string_lossy().to_string();
+            let path = entry.path().to_string_lossy().to_string();
+            if name == "SHA256SUMS" || !entry.path().is_file() {
+                continue;
+            }
+            if elf_info(&path).is_ok_and(|(_, dynamic)| dynamic) {
+                println!("  Warning: {} is dynamically linked and won't run in the rescue system", path);
+            }
+            install_file(&path, &format!("{}/bin/{}", staging, name))?;
+        }
+        println!("  {} tools from {}", count, dir);
+    }
+    if let Some(ref modules) = sources.modules {
+        let version = Path::new(modules).file_name().ok_or_else(|| anyhow!("--modules must be a /lib/modules/<version> directory"))?;
+        let dest = format!("{}/lib/modules/{}", staging, version.to_string_lossy());
+        std::fs::create_dir_all(&dest).context(format!("Failed to create {}", dest))?;
+        let status = Command::new("cp").args(["-a", &format!("{}/.", modules), &dest]).status().context("Failed to run cp")?;
+        if !status.success() {
+            bail!("Failed to copy the kernel modules from {}", modules);
+        }
+    }
+
+    let script = format!("cd '{}' && find . | cpio -o -H newc --quiet | gzip -9 > '{}'", staging, output);
+    let status = Command::new("sh").args(["-c", &script]).status().context("Failed to run cpio")?;
+    if !status.success() {
+        bail!("Packing the initramfs failed");
+    }
+    Ok(())
+}
+
+/// Copy the boot files and the initramfs onto the FAT partition mounted at `mount_point`
+fn fill_boot_partition(sources: &RescueSources, initramfs: &str, mount_point: &str, arm_64bit: bool) -> Result<()> {
+    copy_recursive(&format!("{}/.", sources.boot_files.trim_end_matches('/')), mount_point)?;
+    for name in REPLACED_FILES {
+        let _ = std::fs::remove_file(format!("{}/{}", mount_point, name));
+    }
+    // The source's own initramfs images would be loaded instead by auto_initramfs
+    for entry in std::fs::read_dir(mount_point)?.flatten() {
+        let name = entry.file_name().to_string_lossy().to_string();
+        if name.starts_with("initramfs") || name.starts_with("initrd") {
+            let _ = std::fs::remove_file(entry.path());
+        }
+    }
+    std::fs::copy(initramfs, format!("{}/{}", mount_point, RESCUE_INITRAMFS)).context("Failed to copy the initramfs")?;
+
+    let mut config = String::from("# rpi-fs-shrink rescue image\n[all]\n");
+    if arm_64bit {
+        config.push_str("arm_64bit=1\n");
+    }
+    config.push_str(&format!("initramfs {} followkernel\nenable_uart=1\n", RESCUE_INITRAMFS));
+    std::fs::write(format!("{}/config.txt", mount_point), config).context("Failed to write config.txt")?;
+    std::fs::write(format!("{}/cmdline.txt", mount_point), "console=serial0,115200 console=tty1 rdinit=/init\n")
+        .context("Failed to write cmdline.txt")?;
+    Ok(())
+}
+
+/// Write a bootable rescue image to `out`
+pub fn make_rescue(out: &str, sources: &RescueSources, mounts: &MountPaths) -> Result<()> {
+    let missing: Vec<&str> =
+        ["cpio", "gzip", "parted", "mkfs.vfat", "losetup"].into_iter().filter(|tool| !command_exists(tool)).collect();
+    if !missing.is_empty() {
+        bail!("make-rescue needs {}", missing.join(", "));
+    }
+    crate::privilege::require("Building a rescue image")?;
+
+    let boot_files = Path::new(&sources.boot_files);
+    let has = |prefix: &str, suffix: &str| {
+        std::fs::read_dir(boot_files)
+            .map(|entries| {
+                entries.flatten().any(|entry| {
+                    let name = entry.file_name().to_string_lossy().to_string();
+                    name.starts_with(prefix) && name.ends_with(suffix)
+                })
+            })
+            .unwrap_or(false)
+    };
+    if !has("kernel", ".img") {
+        bail!("{} has no kernel*.img; give the boot partition of a Raspberry Pi OS image", sources.boot_files);
+    }
+    if !has("start", ".elf") {
+        println!("  Note: {} has no start*.elf; the image only boots on a Pi 5, whose firmware is in EEPROM", sources.boot_files);
+    }
+    let arm_64bit = check_pi_binary(&sources.binary, "rpi-fs-shrink")?;
+    check_pi_binary(&sources.busybox, "busybox")?;
+
+    let work = format!("{}/rpi-fs-shrink-rescue-{}", std::env::temp_dir().display(), std::process::id());
+    let staging = format!("{}/root", work);
+    let initramfs = format!("{}/{}", work, RESCUE_INITRAMFS);
+    std::fs::create_dir_all(&staging).context(format!("Failed to create {}", staging))?;
+    let result = (|| -> Result<()> {
+        println!("Building the initramfs...");
+        build_initramfs(sources, &staging, &initramfs)?;
+        let initramfs_bytes = std::fs::metadata(&initramfs)?.len();
+        println!("  {} ({} MB)", RESCUE_INITRAMFS, initramfs_bytes.div_ceil(MIB));
+
+        // Room for FAT overhead; FAT32 wants a few tens of MB at least
+        let content = tree_bytes(boot_files) + initramfs_bytes;
+        let image_bytes = ((content * 5 / 4 + 16 * MIB).div_ceil(MIB) * MIB).max(128 * MIB);
+        println!("Creating {} ({} MB)...", out, image_bytes / MIB);
+        std::fs::File::create(out)
+            .and_then(|file| file.set_len(image_bytes))
+            .context(format!("Failed to create {}", out))?;
+        let status = Command::new("parted")
+            .args(["-s", out, "mklabel", "msdos", "mkpart", "primary", "fat32", "4MiB", "100%", "set", "1", "boot", "on"])
+            .status()
+            .context("Failed to run parted")?;
+        if !status.success() {
+            bail!("parted failed to partition {}", out);
+        }
+
+        let loop_device = container::attach_image(out)?;
+        let written = (|| -> Result<()> {
+            let partition = get_partition_device(&loop_device, 1)?;
+            run_format_jobs(&[FormatJob::vfat("rescue", &partition, "RESCUE", true)], 1)?;
+            let mount_point = mounts.path("rescue");
+            mount_at(&partition, &mount_point)?;
+            let filled = fill_boot_partition(sources, &initramfs, &mount_point, arm_64bit);
+            unmount_quiet(&mount_point);
+            filled
+        })();
+        container::detach_loop(&loop_device)?;
+        written
+    })();
+    let _ = std::fs::remove_dir_all(&work);
+    if result.is_err() {
+        let _ = std::fs::remove_file(out);
+    }
+    result?;
+
+    println!("\nRescue image written to {}", out);
+    println!("Write it to an SD card (e.g. dd if={} of=/dev/mmcblk0 bs=4M), boot the Pi from it", out);
+    println!("with the disk to shrink attached over USB, and run rpi-fs-shrink from the shell it opens.");
+    Ok(())
+}
@@ -0,0 +1,201 @@
+//! Which partitions hold the firmware's boot files and the root filesystem.
+//! Raspberry Pi OS images use 1 and 2, but NOOBS-derived and multi-boot cards
+//! put them anywhere (NOOBS: recovery 1, extended 2, settings 5, boot 6, root 7).
+
+use anyhow::{bail, Context, Result};
+use std::path::Path;
+use std::process::Command;
+
+use crate::{parse, sysfs};
+
+/// Filesystems a root partition can carry, directly or under LUKS/LVM
+const ROOT_FSTYPES: &[&str] = &["ext4", "ext3", "ext2", "crypto_LUKS", "LVM2_member"];
+
+#[derive(Debug, Clone, Copy)]
+pub struct Roles {
+    pub boot: u32,
+    pub root: u32,
+    /// How they were told apart, for the disk summary
+    pub found_by: &'static str,
+}
+
+impl Roles {
+    pub fn is_conventional(&self) -> bool {
+        (self.boot, self.root) == (1, 2)
+    }
+}
+
+/// Numbers of the partitions on `device`
+pub fn partition_numbers(device: &str) -> Vec<u32> {
+    let output = Command::new("parted").args(["-s", device, "unit", "s", "print"]).output();
+    let mut numbers: Vec<u32> = output
+        .map(|output| parse::parted_partitions(&String::from_utf8_lossy(&output.stdout)).iter().map(|p| p.number).collect())
+        .unwrap_or_default();
+    if numbers.is_empty() {
+        numbers = (1..=128).filter(|&n| sysfs::partition_bounds(device, n).is_some()).collect();
+    }
+    numbers
+}
+
+/// Find boot and root on `device`: by filesystem type when that is unambiguous,
+/// otherwise by content (a FAT partition with config.txt, and the root its
+/// cmdline.txt names or an ext4 partition with /etc/fstab)
+pub fn identify(device: &str) -> Result<Roles> {
+    let numbers = partition_numbers(device);
+    let fstype = |n: u32| sysfs::fstype(&sysfs::partition_path(device, n)).unwrap_or_default();
+    let typed: Vec<(u32, String)> = numbers.iter().map(|&n| (n, fstype(n))).collect();
+    let fat: Vec<u32> = typed.iter().filter(|(_, t)| t == "vfat").map(|(n, _)| *n).collect();
+    let roots: Vec<u32> = typed.iter().filter(|(_, t)| ROOT_FSTYPES.contains(&t.as_str())).map(|(n, _)| *n).collect();
+
+    // Nothing probed (an unreadable udev database, say): the Raspberry Pi OS layout
+    if typed.iter().all(|(_, t)| t.is_empty()) {
+        return Ok(Roles { boot: 1, root: 2, found_by: "position" });
+    }
+    if let ([boot], [root]) = (fat.as_slice(), roots.as_slice()) {
+        let found_by = if (*boot, *root) == (1, 2) { "position" } else { "filesystem type" };
+        return Ok(Roles { boot: *boot, root: *root, found_by });
+    }
+    if fat.is_empty() || roots.is_empty() {
+        bail!("{} has no FAT boot partition and ext4 (or LUKS/LVM) root partition", device);
+    }
+
+    if !crate::privilege::is_root() && !crate::privilege::is_capability_run() {
+        // Reading the files needs a mount; go by position among the candidates
+        return Ok(Roles { boot: fat[0], root: *roots.iter().find(|&&n| n > fat[0]).unwrap_or(&roots[0]), found_by: "filesystem type only" });
+    }
+    identify_by_content(device, &fat, &roots)
+}
+
+fn identify_by_content(device: &str, fat: &[u32], roots: &[u32]) -> Result<Roles> {
+    let probe = std::env::temp_dir().join(format!("rpi-fs-shrink-probe-{}", std::process::id()));
+    let probe = probe.to_string_lossy().to_string();
+    std::fs::create_dir_all(&probe).context(format!("Failed to create {}", probe))?;
+    let result = (|| {
+        let mut boot = None;
+        for &n in fat {
+            let files = with_mounted(&sysfs::partition_path(device, n), &probe, |dir| {
+                let is_boot = Path::new(&format!("{}/config.txt", dir)).exists()
+                    && !Path::new(&format!("{}/recovery.elf", dir)).exists();
+                let cmdline = std::fs::read_to_string(format!("{}/cmdline.txt", dir)).unwrap_or_default();
+                (is_boot, root_argument(&cmdline))
+            });
+            if let Some((true, root)) = files {
+                boot = Some((n, root));
+                break;
+            }
+        }
+        let Some((boot, root_arg)) = boot else {
+            bail!("None of the FAT partitions of {} holds a config.txt", device);
+        };
+
+        if let Some(root) = root_arg.and_then(|arg| resolve_root(device, roots, &arg)) {
+            return Ok(Roles { boot, root, found_by: "cmdline.txt" });
+        }
+        for &n in roots {
+            let has_fstab = with_mounted(&sysfs::partition_path(device, n), &probe, |dir| {
+                Path::new(&format!("{}/etc/fstab", dir)).exists()
+            });
+            if has_fstab == Some(true) {
+                return Ok(Roles { boot, root: n, found_by: "content" });
+            }
+        }
+        // Encrypted or LVM roots can't be looked into here; take the first
+        Ok(Roles { boot, root: roots[0], found_by: "filesystem type" })
+    })();
+    let _ = std::fs::remove_dir(&probe);
+    result
+}
+
+/// Run `f` on `partition` mounted read-only at `mount_point`; None if it doesn't mount
+fn with_mounted<T>(partition: &str, mount_point: &str, f: impl FnOnce(&str) -> T) -> Option<T> {
+    crate::mount_device(partition, mount_point, true).ok()?;
+    let result = f(mount_point);
+    crate::unmount_quiet(mount_point);
+    Some(result)
+}
+
+/// The root= argument of a kernel command line
+fn root_argument(cmdline: &str) -> Option<String> {
+    cmdline.split_whitespace().find_map(|arg| arg.strip_prefix("root=")).map(str::to_string)
+}
+
+/// Number of the partition among `roots` that a root= argument names
+fn resolve_root(device: &str, roots: &[u32], arg: &str) -> Option<u32> {
+    let matches = |n: u32, column: &str, value: &str| {
+        sysfs::lsblk_value(&sysfs::partition_path(device, n), column).is_some_and(|v| v.eq_ignore_ascii_case(value))
+    };
+    if let Some(partuuid) = arg.strip_prefix("PARTUUID=") {
+        return roots.iter().copied().find(|&n| matches(n, "PARTUUID", partuuid));
+    }
+    if let Some(uuid) = arg.strip_prefix("UUID=") {
+        return roots.iter().copied().find(|&n| matches(n, "UUID", uuid));
+    }
+    if let Some(label) = arg.strip_prefix("LABEL=") {
+        return roots.iter().copied().find(|&n| matches(n, "LABEL", label));
+    }
+    // /dev/mmcblk0p7: the card as the Pi sees it, which may be named differently here
+    let digits: String = arg.chars().rev().take_while(|c| c.is_ascii_digit()).collect::<Vec<_>>().into_iter().rev().collect();
+    let number = digits.parse().ok()?;
+    roots.contains(&number).then_some(number)
+}
+
+/// The lowest partition number parted gives the next primary (or GPT) partition:
+/// the first gap, not one past the highest
+pub fn next_free_number(existing: &[u32], partition_table: &str) -> Option<u32> {
+    let limit = if partition_table == "msdos" { 4 } else { 128 };
+    (1..=limit).find(|n| !existing.contains(n))
+}
+
+/// Number parted gives the next logical partition: the lowest one from 5 up
+/// that isn't taken. The kernel numbers logicals without gaps, so on a real
+/// table that is one past the last logical.
+pub fn next_logical_number(existing: &[u32]) -> u32 {
+    (crate::mbr::FIRST_LOGICAL..).find(|n| !existing.contains(n)).unwrap_or(crate::mbr::FIRST_LOGICAL)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn root_argument_is_read_from_the_command_line() {
+        let cmdline = "console=serial0,115200 console=tty1 root=PARTUUID=6c586e13-02 rootfstype=ext4 fsck.repair=yes rootwait\n";
+        assert_eq!(root_argument(cmdline).as_deref(), Some("PARTUUID=6c586e13-02"));
+        assert_eq!(root_argument("root=/dev/mmcblk0p7 rootwait").as_deref(), Some("/dev/mmcblk0p7"));
+        // rootfstype= and friends aren't root=
+        assert_eq!(root_argument("rootfstype=ext4 rootwait"), None);
+        assert_eq!(root_argument(""), None);
+    }
+
+    #[test]
+    fn device_paths_in_root_resolve_by_their_partition_number() {
+        // The card as the Pi names it, read here through a USB reader as /dev/sda
+        assert_eq!(resolve_root("/dev/sda", &[2, 7], "/dev/mmcblk0p7"), Some(7));
+        assert_eq!(resolve_root("/dev/sda", &[2, 7], "/dev/sda2"), Some(2));
+        // A partition that isn't one of the root candidates, or no number at all
+        assert_eq!(resolve_root("/dev/sda", &[2, 7], "/dev/mmcblk0p5"), None);
+        assert_eq!(resolve_root("/dev/sda", &[2, 7], "/dev/root"), None);
+        // Identifiers are looked up on the disk, which doesn't exist here
+        assert_eq!(resolve_root("/dev/rpi-fs-shrink-test/sdx", &[2], "PARTUUID=6c586e13-02"), None);
+    }
+
+    #[test]
+    fn next_primary_number_fills_the_first_gap() {
+        assert_eq!(next_free_number(&[1, 2], "msdos"), Some(3));
+        assert_eq!(next_free_number(&[1, 3], "msdos"), Some(2));
+        assert_eq!(next_free_number(&[1, 2, 3, 4], "msdos"), None);
+        // Logical partitions don't take primary slots
+        assert_eq!(next_free_number(&[1, 2, 5, 6], "msdos"), Some(3));
+        assert_eq!(next_free_number(&[1, 2, 3, 4], "gpt"), Some(5));
+        assert_eq!(next_free_number(&(1..=128).collect::<Vec<_>>(), "gpt"), None);
+    }
+
+    #[test]
+    fn logical_numbers_start_at_five() {
+        assert_eq!(next_logical_number(&[]), 5);
+        assert_eq!(next_logical_number(&[1, 2, 3, 4]), 5);
+        assert_eq!(next_logical_number(&[1, 2, 3, 5, 6]), 7);
+        // NOOBS: recovery 1, extended 2, settings 5, boot 6, root 7
+        assert_eq!(next_logical_number(&[1, 2, 5, 6, 7]), 8);
+    }
+}
@@ -0,0 +1,57 @@
+use anyhow::{bail, Context, Result};
+use std::path::Path;
+
+use crate::{mount_at, unmount_quiet, MountPaths};
+
+/// Size of the dedicated CIDATA partition created by --seed-partition
+pub const CIDATA_SIZE_MB: u64 = 64;
+
+/// Check the seed files before any disk is touched
+pub fn validate_seed(user_data: &str, network_config: Option<&str>) -> Result<()> {
+    let content = std::fs::read_to_string(user_data).context(format!("Failed to read {}", user_data))?;
+    if !content.starts_with("#cloud-config") && !content.starts_with("#!") {
+        crate::findings::report("seed-format", &format!("{} does not start with #cloud-config; cloud-init may ignore it", user_data))?;
+    }
+
+    if let Some(network_config) = network_config {
+        if !Path::new(network_config).is_file() {
+            bail!("Network config {} does not exist", network_config);
+        }
+    }
+
+    Ok(())
+}
+
+/// Write NoCloud seed files (user-data, meta-data, network-config) to the root of `device`
+pub fn write_seed(device: &str, user_data: &str, network_config: Option<&str>, mounts: &MountPaths) -> Result<()> {
+    let seed_mount = mounts.path("seed");
+    mount_at(device, &seed_mount)?;
+
+    let result = (|| -> Result<()> {
+        std::fs::copy(user_data, format!("{}/user-data", seed_mount)).context("Failed to write user-data")?;
+        println!("  Wrote user-data from {}", user_data);
+
+        if let Some(network_config) = network_config {
+            std::fs::copy(network_config, format!("{}/network-config", seed_mount))
+                .context("Failed to write network-config")?;
+            println!("  Wrote network-config from {}", network_config);
+        }
+
+        // NoCloud requires meta-data to exist; keep an image-provided one
+        let meta_data = format!("{}/meta-data", seed_mount);
+        if !Path::new(&meta_data).exists() {
+            let timestamp = std::time::SystemTime::now()
+                .duration_since(std::time::UNIX_EPOCH)
+                .map(|d| d.as_secs())
+                .unwrap_or(0);
+            std::fs::write(&meta_data, format!("instance-id: rpi-fs-shrink-{}\n", timestamp))
+                .context("Failed to write meta-data")?;
+            println!("  Wrote meta-data");
+        }
+
+        Ok(())
+    })();
+
+    unmount_quiet(&seed_mount);
+    result
+}
@@ -0,0 +1,235 @@
+//! Replacing this binary with a GitHub release. The tool usually runs from a
+//! LiveUSB, where no package manager delivers updates.
+//!
+//! A release carries `rpi-fs-shrink-<arch>-linux` for every architecture, with a
+//! `.sha256` file next to it and optionally a minisign `.minisig` signature.
+
+use anyhow::{anyhow, bail, Context, Result};
+use serde_json::Value;
+use std::io::Write;
+use std::os::unix::fs::PermissionsExt;
+use std::process::{Command, Stdio};
+
+use crate::command_exists;
+
+const REPOSITORY: &str = env!("CARGO_PKG_REPOSITORY");
+
+/// owner/name of the GitHub repository
+fn repository_path() -> &'static str {
+    REPOSITORY.trim_end_matches('/').trim_start_matches("https://github.com/")
+}
+
+/// Release asset built for this machine
+fn asset_name() -> String {
+    format!("rpi-fs-shrink-{}-linux", std::env::consts::ARCH)
+}
+
+/// Part of a pre-release suffix; numbers sort before words, as in semver
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+enum PreRelease {
+    Number(u64),
+    Word(String),
+}
+
+/// A release version; fields in comparison order
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+struct Version {
+    numbers: (u64, u64, u64),
+    /// false for a pre-release, which comes before the release itself
+    release: bool,
+    pre: Vec<PreRelease>,
+}
+
+/// "v0.2.1", "0.2.1" or "0.2.1-rc.1"; build metadata after '+' is ignored
+fn parse_version(text: &str) -> Option<Version> {
+    let text = text.trim().trim_start_matches('v');
+    let text = text.split_once('+').map_or(text, |(version, _)| version);
+    let (core, pre) = match text.split_once('-') {
+        Some((core, pre)) => (core, Some(pre)),
+        None => (text, None),
+    };
+    let mut parts = core.split('.').map(|part| part.parse::<u64>().ok());
+    let numbers = (parts.next()??, parts.next()??, parts.next().map_or(Some(0), |part| part)?);
+    if parts.next().is_some() {
+        return None;
+    }
+    let pre = match pre {
+        Some(pre) if !pre.is_empty() => pre
+            .split('.')
+            .map(|part| part.parse().map(PreRelease::Number).unwrap_or_else(|_| PreRelease::Word(part.to_string())))
+            .collect(),
+        Some(_) => return None,
+        None => Vec::new(),
+    };
+    Some(Version { numbers, release: pre.is_empty(), pre })
+}
+
+fn curl(url: &str, output: Option<&str>) -> Result<Vec<u8>> {
+    let mut command = Command::new("curl");
+    command.args(["-fsSL", "--proto", "=https", "-H", "Accept: application/vnd.github+json"]);
+    // Anonymous API calls are rate-limited per address, which a shared uplink runs into.
+    // The token goes through stdin: an argument is readable in /proc/<pid>/cmdline.
+    let token = std::env::var("GITHUB_TOKEN").ok();
+    if token.is_some() {
+        command.args(["-H", "@-"]).stdin(Stdio::piped());
+    }
+    if let Some(output) = output {
+        command.args(["-o", output]);
+    }
+    let mut child = command.arg(url).stdout(Stdio::piped()).stderr(Stdio::piped()).spawn().context("Failed to run curl")?;
+    if let (Some(token), Some(mut stdin)) = (token, child.stdin.take()) {
+        stdin.write_all(authorization_header(&token).as_bytes()).context("Failed to pass the token to curl")?;
+    }
+    let result = child.wait_with_output().context("Failed to run curl")?;
+    if !result.status.success() {
+        bail!("Download of {} failed: {}", url, String::from_utf8_lossy(&result.stderr).trim());
+    }
+    Ok(result.stdout)
+}
+
+/// Header lines for curl's `-H @-`
+fn authorization_header(token: &str) -> String {
+    format!("Authorization: Bearer {}\n", token.trim())
+}
+
+/// The release `tag`, or the latest one
+fn fetch_release(tag: Option<&str>) -> Result<Value> {
+    let url = match tag {
+        Some(tag) => format!("https://api.github.com/repos/{}/releases/tags/{}", repository_path(), tag),
+        None => format!("https://api.github.com/repos/{}/releases/latest", repository_path()),
+    };
+    let body = curl(&url, None)?;
+    serde_json::from_slice(&body).context("GitHub returned a release that isn't valid JSON")
+}
+
+fn asset_url(release: &Value, name: &str) -> Option<String> {
+    release["assets"]
+        .as_array()?
+        .iter()
+        .find(|asset| asset["name"].as_str() == Some(name))
+        .and_then(|asset| asset["browser_download_url"].as_str())
+        .map(str::to_string)
+}
+
+/// Check the downloaded file against the release's checksum and, with a key, its signature
+fn verify_download(path: &str, release: &Value, asset: &str, minisign_key: Option<&str>) -> Result<()> {
+    let checksum_url = asset_url(release, &format!("{}.sha256", asset))
+        .ok_or_else(|| anyhow!("The release has no {}.sha256; refusing an unverifiable binary", asset))?;
+    let expected = String::from_utf8_lossy(&curl(&checksum_url, None)?)
+        .split_whitespace()
+        .next()
+        .map(str::to_ascii_lowercase)
+        .ok_or_else(|| anyhow!("{}.sha256 is empty", asset))?;
+    let actual = crate::audit::sha256(&std::fs::read(path).context(format!("Failed to read {}", path))?)?;
+    if actual != expected {
+        bail!("Checksum mismatch for {}: expected {}, got {}", asset, expected, actual);
+    }
+    println!("  SHA-256 {} matches", actual);
+
+    let Some(key) = minisign_key else {
+        return Ok(());
+    };
+    let signature_url = asset_url(release, &format!("{}.minisig", asset))
+        .ok_or_else(|| anyhow!("The release has no {}.minisig to check against --minisign-key", asset))?;
+    if !command_exists("minisign") {
+        bail!("--minisign-key needs minisign installed");
+    }
+    let signature = format!("{}.minisig", path);
+    curl(&signature_url, Some(&signature))?;
+    let status = Command::new("minisign")
+        .args(["-V", "-q", "-P", key, "-m", path, "-x", &signature])
+        .status()
+        .context("Failed to run minisign")?;
+    let _ = std::fs::remove_file(&signature);
+    if !status.success() {
+        bail!("The signature of {} doesn't match --minisign-key", asset);
+    }
+    println!("  minisign signature verified");
+    Ok(())
+}
+
+/// Look for a newer release and, unless `check_only`, install it over this binary
+pub fn run_self_update(check_only: bool, tag: Option<&str>, force: bool, minisign_key: Option<&str>) -> Result<()> {
+    if !command_exists("curl") {
+        bail!("self-update downloads with curl, which isn't installed");
+    }
+    let current = env!("CARGO_PKG_VERSION");
+    let release = fetch_release(tag)?;
+    let release_tag = release["tag_name"].as_str().ok_or_else(|| anyhow!("The release has no tag"))?.to_string();
+    println!("Installed: {}", current);
+    println!("Release:   {} ({})", release_tag, release["published_at"].as_str().unwrap_or("unpublished"));
+
+    let newer = match (parse_version(&release_tag), parse_version(current)) {
+        (Some(release), Some(current)) => release > current,
+        _ => bail!("Can't compare release {} with version {}", release_tag, current),
+    };
+    if !newer && !force {
+        println!("Up to date");
+        return Ok(());
+    }
+    if check_only {
+        if newer {
+            println!("An update is available; install it with: rpi-fs-shrink self-update");
+        }
+        return Ok(());
+    }
+
+    let asset = asset_name();
+    let url = asset_url(&release, &asset).ok_or_else(|| anyhow!("Release {} has no {}", release_tag, asset))?;
+    let exe = std::env::current_exe().context("Failed to locate rpi-fs-shrink")?;
+    let exe = std::fs::canonicalize(&exe).unwrap_or(exe);
+    let exe = exe.to_string_lossy().to_string();
+    // Next to the binary, so the rename below replaces it in one step
+    let download = format!("{}.update", exe);
+
+    println!("Downloading {}...", asset);
+    let result = (|| -> Result<()> {
+        curl(&url, Some(&download))?;
+        verify_download(&download, &release, &asset, minisign_key)?;
+        std::fs::set_permissions(&download, std::fs::Permissions::from_mode(0o755))
+            .context(format!("Failed to make {} executable", download))?;
+        // A binary for another architecture or a truncated one fails here, not on the next run
+        let output = Command::new(&download).arg("--version").output().context("The downloaded binary doesn't run here")?;
+        if !output.status.success() {
+            bail!("The downloaded binary doesn't run here");
+        }
+        println!("  {}", String::from_utf8_lossy(&output.stdout).trim());
+        std::fs::rename(&download, &exe).context(format!("Failed to replace {} (run as its owner, e.g. with sudo)", exe))
+    })();
+    if result.is_err() {
+        let _ = std::fs::remove_file(&download);
+    }
+    result?;
+    println!("{} updated to {}", exe, release_tag);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pre_releases_come_before_their_release() {
+        let version = |text| parse_version(text).unwrap();
+        assert_eq!(version("v0.2.1"), version("0.2.1"));
+        assert_eq!(version("0.2"), version("0.2.0"));
+        assert_eq!(version("1.2.0+build.5"), version("1.2.0"));
+        assert!(version("1.2.0-rc1") < version("1.2.0"));
+        assert!(version("1.2.0-rc1") > version("1.1.9"));
+        assert!(version("1.2.0-rc.2") < version("1.2.0-rc.10"));
+        assert!(version("1.2.0-alpha") < version("1.2.0-beta"));
+        assert!(version("1.2.0-1") < version("1.2.0-alpha"));
+    }
+
+    #[test]
+    fn malformed_versions_are_refused() {
+        for text in ["", "v", "1", "1.x.0", "1.2.3.4", "1.2.0-", "latest"] {
+            assert_eq!(parse_version(text), None, "{}", text);
+        }
+    }
+
+    #[test]
+    fn token_header_is_one_line() {
+        assert_eq!(authorization_header("ghp_abc\n"), "Authorization: Bearer ghp_abc\n");
+    }
+}
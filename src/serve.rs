@@ -0,0 +1,529 @@
+use anyhow::{bail, Context, Result};
+use serde::Deserialize;
+use serde_json::json;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::process::{Command, Stdio};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+const MAX_BODY_BYTES: usize = 64 * 1024;
+
+/// Request line and headers together; a longer head is refused
+const MAX_HEADER_BYTES: u64 = 16 * 1024;
+
+/// Finished runs kept for /api/jobs; older ones are forgotten
+const MAX_JOBS: usize = 32;
+
+/// Connections handled at once; more are turned away until one finishes
+const MAX_CONNECTIONS: usize = 16;
+
+/// A client that sends nothing for this long is dropped
+const IO_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Time for a whole request, read and answered; trickling data doesn't extend it
+const REQUEST_DEADLINE: Duration = Duration::from_secs(30);
+
+const INDEX_HTML: &str = include_str!("serve.html");
+
+/// A run started through /api/apply
+struct Job {
+    id: usize,
+    args: Vec<String>,
+    output: Arc<Mutex<String>>,
+    /// None while running, then whether the run succeeded
+    result: Arc<Mutex<Option<bool>>>,
+}
+
+struct Server {
+    token: String,
+    jobs: Mutex<Vec<Job>>,
+    connections: AtomicUsize,
+}
+
+/// Options of a run or plan. Clients name the options; the command line is
+/// built here, so no request can reach a subcommand or an option not listed.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RunOptions {
+    device: String,
+    root_size: String,
+    #[serde(default)]
+    swap_size: Option<String>,
+    #[serde(default)]
+    var_size: Option<String>,
+    #[serde(default)]
+    container_size: Option<String>,
+    #[serde(default)]
+    recovery_size: Option<String>,
+    #[serde(default)]
+    reuse_uuids: bool,
+    #[serde(default)]
+    purge_now: bool,
+    #[serde(default)]
+    yes: bool,
+}
+
+impl RunOptions {
+    fn to_args(&self) -> std::result::Result<Vec<String>, String> {
+        let mut args = vec!["-d".to_string(), checked_value("device", &self.device)?];
+        args.extend(["-r".to_string(), checked_value("root_size", &self.root_size)?]);
+        for (flag, name, value) in [
+            ("-s", "swap_size", &self.swap_size),
+            ("-v", "var_size", &self.var_size),
+            ("--container-size", "container_size", &self.container_size),
+            ("--recovery-size", "recovery_size", &self.recovery_size),
+        ] {
+            if let Some(value) = value {
+                args.extend([flag.to_string(), checked_value(name, value)?]);
+            }
+        }
+        for (flag, set) in [("--reuse-uuids", self.reuse_uuids), ("--purge-now", self.purge_now), ("--yes", self.yes)] {
+            if set {
+                args.push(flag.to_string());
+            }
+        }
+        Ok(args)
+    }
+}
+
+/// A value given by a client, refused when the binary could take it for an
+/// option or a subcommand
+fn checked_value(name: &str, value: &str) -> std::result::Result<String, String> {
+    use clap::CommandFactory;
+    if value.is_empty() || value.starts_with('-') {
+        return Err(format!("{}: {:?} is not allowed", name, value));
+    }
+    if crate::Args::command().get_subcommands().any(|command| command.get_name() == value || command.get_all_aliases().any(|alias| alias == value)) {
+        return Err(format!("{}: {:?} names a subcommand", name, value));
+    }
+    Ok(value.to_string())
+}
+
+struct Request {
+    method: String,
+    path: String,
+    query: String,
+    authorization: Option<String>,
+    body: Vec<u8>,
+}
+
+struct Response {
+    status: u16,
+    content_type: &'static str,
+    body: String,
+}
+
+impl Response {
+    fn json(status: u16, value: serde_json::Value) -> Self {
+        Response { status, content_type: "application/json", body: value.to_string() }
+    }
+
+    fn error(status: u16, message: &str) -> Self {
+        Response::json(status, json!({ "error": message }))
+    }
+}
+
+/// `:8080` listens on all addresses, which sends the token in cleartext
+fn listen_address(listen: &str) -> String {
+    if listen.starts_with(':') {
+        format!("0.0.0.0{}", listen)
+    } else {
+        listen.to_string()
+    }
+}
+
+/// Token from a file, else from CRPART_TOKEN; the server refuses to start without one
+pub fn load_token(token_file: Option<&str>) -> Result<String> {
+    let token = match token_file {
+        Some(path) => std::fs::read_to_string(path).context(format!("Failed to read {}", path))?,
+        None => std::env::var("CRPART_TOKEN").unwrap_or_default(),
+    };
+    let token = token.trim().to_string();
+    if token.len() < 16 {
+        bail!("serve needs a token of at least 16 characters in --token-file or CRPART_TOKEN");
+    }
+    Ok(token)
+}
+
+pub fn serve(listen: &str, token: String) -> Result<()> {
+    let address = listen_address(listen);
+    let listener = TcpListener::bind(&address).context(format!("Failed to listen on {}", address))?;
+    println!("Serving on http://{} (Authorization: Bearer <token>)", address);
+    if !listener.local_addr()?.ip().is_loopback() {
+        println!("  WARNING: not a loopback address; the token crosses the network in cleartext. Use an SSH tunnel or a TLS reverse proxy");
+    }
+
+    let server = Arc::new(Server { token, jobs: Mutex::new(Vec::new()), connections: AtomicUsize::new(0) });
+    for stream in listener.incoming() {
+        let Ok(stream) = stream else {
+            continue;
+        };
+        if stream.set_read_timeout(Some(IO_TIMEOUT)).is_err() || stream.set_write_timeout(Some(IO_TIMEOUT)).is_err() {
+            continue;
+        }
+        if server.connections.fetch_add(1, Ordering::SeqCst) >= MAX_CONNECTIONS {
+            server.connections.fetch_sub(1, Ordering::SeqCst);
+            let _ = write_response(&stream, &Response::error(503, "too many connections"));
+            continue;
+        }
+        let server = Arc::clone(&server);
+        std::thread::spawn(move || {
+            if let Err(e) = handle_connection(&server, stream) {
+                println!("  Request failed: {:#}", e);
+            }
+            server.connections.fetch_sub(1, Ordering::SeqCst);
+        });
+    }
+    Ok(())
+}
+
+/// The connection, failing reads and writes once `deadline` has passed
+struct DeadlineStream<'a> {
+    stream: &'a TcpStream,
+    deadline: Instant,
+}
+
+impl DeadlineStream<'_> {
+    /// Timeout for the next read or write: what is left of the deadline, at most IO_TIMEOUT
+    fn remaining(&self) -> std::io::Result<Duration> {
+        match self.deadline.checked_duration_since(Instant::now()) {
+            Some(left) if !left.is_zero() => Ok(left.min(IO_TIMEOUT)),
+            _ => Err(std::io::Error::new(std::io::ErrorKind::TimedOut, "request deadline passed")),
+        }
+    }
+}
+
+impl Read for DeadlineStream<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        self.stream.set_read_timeout(Some(self.remaining()?))?;
+        let mut stream = self.stream;
+        stream.read(buf)
+    }
+}
+
+impl Write for DeadlineStream<'_> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.stream.set_write_timeout(Some(self.remaining()?))?;
+        let mut stream = self.stream;
+        stream.write(buf)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        let mut stream = self.stream;
+        stream.flush()
+    }
+}
+
+/// Read one line of the request head, failing when it runs past the head's limit
+fn read_head_line(head: &mut impl BufRead, line: &mut String) -> Result<usize> {
+    let read = head.read_line(line)?;
+    if read > 0 && !line.ends_with('\n') {
+        bail!("Request headers too large or cut off");
+    }
+    Ok(read)
+}
+
+fn read_request(stream: impl Read) -> Result<Request> {
+    let mut reader = BufReader::new(stream);
+    let mut head = (&mut reader).take(MAX_HEADER_BYTES);
+    let mut line = String::new();
+    read_head_line(&mut head, &mut line)?;
+    let mut parts = line.split_whitespace();
+    let method = parts.next().unwrap_or_default().to_string();
+    let target = parts.next().unwrap_or_default();
+    let (path, query) = target.split_once('?').unwrap_or((target, ""));
+    let (path, query) = (path.to_string(), query.to_string());
+
+    let mut content_length = 0;
+    let mut authorization = None;
+    loop {
+        let mut header = String::new();
+        if read_head_line(&mut head, &mut header)? == 0 || header.trim().is_empty() {
+            break;
+        }
+        if let Some((name, value)) = header.split_once(':') {
+            match name.trim().to_ascii_lowercase().as_str() {
+                "content-length" => content_length = value.trim().parse().unwrap_or(0),
+                "authorization" => authorization = Some(value.trim().to_string()),
+                _ => {}
+            }
+        }
+    }
+
+    if content_length > MAX_BODY_BYTES {
+        bail!("Request body too large");
+    }
+    let mut body = vec![0u8; content_length];
+    reader.read_exact(&mut body)?;
+    Ok(Request { method, path, query, authorization, body })
+}
+
+fn write_response(mut stream: impl Write, response: &Response) -> Result<()> {
+    let reason = match response.status {
+        200 => "OK",
+        202 => "Accepted",
+        400 => "Bad Request",
+        401 => "Unauthorized",
+        404 => "Not Found",
+        409 => "Conflict",
+        503 => "Service Unavailable",
+        _ => "Internal Server Error",
+    };
+    write!(
+        stream,
+        "HTTP/1.1 {} {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        response.status,
+        reason,
+        response.content_type,
+        response.body.len(),
+        response.body
+    )?;
+    Ok(())
+}
+
+/// Compare without returning early, so the time taken doesn't reveal the token
+fn token_matches(expected: &str, given: &str) -> bool {
+    expected.len() == given.len() && expected.bytes().zip(given.bytes()).fold(0u8, |acc, (a, b)| acc | (a ^ b)) == 0
+}
+
+fn percent_decode(value: &str) -> String {
+    let bytes = value.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'%' if i + 2 < bytes.len() => {
+                let hex = std::str::from_utf8(&bytes[i + 1..i + 3]).ok();
+                match hex.and_then(|hex| u8::from_str_radix(hex, 16).ok()) {
+                    Some(byte) => {
+                        out.push(byte);
+                        i += 3;
+                        continue;
+                    }
+                    None => out.push(b'%'),
+                }
+            }
+            b'+' => out.push(b' '),
+            byte => out.push(byte),
+        }
+        i += 1;
+    }
+    String::from_utf8_lossy(&out).into_owned()
+}
+
+fn query_param(query: &str, name: &str) -> Option<String> {
+    query
+        .split('&')
+        .filter_map(|pair| pair.split_once('='))
+        .find(|(key, _)| *key == name)
+        .map(|(_, value)| percent_decode(value))
+}
+
+fn handle_connection(server: &Server, stream: TcpStream) -> Result<()> {
+    let deadline = Instant::now() + REQUEST_DEADLINE;
+    let request = read_request(DeadlineStream { stream: &stream, deadline })?;
+    let response = if request.method == "GET" && request.path == "/" {
+        // The page holds no data; it asks for the token and calls the API
+        Response { status: 200, content_type: "text/html; charset=utf-8", body: INDEX_HTML.to_string() }
+    } else {
+        let authorized = request
+            .authorization
+            .as_deref()
+            .and_then(|value| value.strip_prefix("Bearer "))
+            .is_some_and(|given| token_matches(&server.token, given.trim()));
+        if authorized {
+            route(server, &request)
+        } else {
+            Response::error(401, "missing or wrong bearer token")
+        }
+    };
+    println!("  {} {} -> {}", request.method, request.path, response.status);
+    write_response(DeadlineStream { stream: &stream, deadline }, &response)
+}
+
+fn route(server: &Server, request: &Request) -> Response {
+    let path = request.path.as_str();
+    match (request.method.as_str(), path) {
+        ("GET", "/api/disks") => match Command::new("lsblk").args(["-J", "-b", "-o", "NAME,SIZE,TYPE,FSTYPE,MODEL,MOUNTPOINT"]).output() {
+            Ok(output) => Response { status: 200, content_type: "application/json", body: String::from_utf8_lossy(&output.stdout).into_owned() },
+            Err(e) => Response::error(500, &e.to_string()),
+        },
+        ("GET", "/api/inspect") | ("GET", "/api/check") => match query_param(&request.query, "device") {
+            Some(device) => match checked_value("device", &device) {
+                Ok(device) => run_to_completion(&[&path["/api/".len()..], &device]),
+                Err(message) => Response::error(400, &message),
+            },
+            None => Response::error(400, "device parameter required"),
+        },
+        ("POST", "/api/plan") => match parse_run_request(&request.body) {
+            Ok(args) => {
+                let mut all = vec!["plan"];
+                all.extend(args.iter().map(String::as_str));
+                run_to_completion(&all)
+            }
+            Err(response) => response,
+        },
+        ("POST", "/api/apply") => match parse_run_request(&request.body) {
+            Ok(args) => start_job(server, args),
+            Err(response) => response,
+        },
+        ("GET", _) if path.starts_with("/api/jobs/") => match path["/api/jobs/".len()..].parse::<usize>() {
+            Ok(id) => job_status(server, id),
+            Err(_) => Response::error(400, "invalid job id"),
+        },
+        _ => Response::error(404, "not found"),
+    }
+}
+
+fn parse_run_request(body: &[u8]) -> std::result::Result<Vec<String>, Response> {
+    let options: RunOptions = serde_json::from_slice(body)
+        .map_err(|e| Response::error(400, &format!("expected {{\"device\": ..., \"root_size\": ..., ...}}: {}", e)))?;
+    options.to_args().map_err(|message| Response::error(400, &message))
+}
+
+/// Command for this binary, run without its confirmation prompts
+fn self_command(args: &[&str]) -> Result<Command> {
+    let exe = std::env::current_exe().context("Failed to locate rpi-fs-shrink")?;
+    let mut command = Command::new(exe);
+    command
+        .arg("--unattended")
+        .args(args)
+        .env(crate::prompt::UNATTENDED_ENV, "1")
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped());
+    Ok(command)
+}
+
+fn run_to_completion(args: &[&str]) -> Response {
+    let result = self_command(args).and_then(|mut command| Ok(command.spawn()?.wait_with_output()?));
+    match result {
+        Ok(output) => {
+            let mut text = String::from_utf8_lossy(&output.stdout).into_owned();
+            text.push_str(&String::from_utf8_lossy(&output.stderr));
+            Response::json(200, json!({ "ok": output.status.success(), "output": text }))
+        }
+        Err(e) => Response::error(500, &format!("{:#}", e)),
+    }
+}
+
+/// Start a run in the background; only one at a time, since runs take the whole disk
+fn start_job(server: &Server, args: Vec<String>) -> Response {
+    let mut jobs = server.jobs.lock().unwrap();
+    if jobs.iter().any(|job| job.result.lock().unwrap().is_none()) {
+        return Response::error(409, "a run is already in progress");
+    }
+
+    let arg_refs: Vec<&str> = args.iter().map(String::as_str).collect();
+    let mut child = match self_command(&arg_refs).and_then(|mut command| Ok(command.spawn()?)) {
+        Ok(child) => child,
+        Err(e) => return Response::error(500, &format!("{:#}", e)),
+    };
+    let output = Arc::new(Mutex::new(String::new()));
+    let result = Arc::new(Mutex::new(None));
+    let readers: Vec<_> = [
+        child.stdout.take().map(|s| Box::new(s) as Box<dyn Read + Send>),
+        child.stderr.take().map(|s| Box::new(s) as Box<dyn Read + Send>),
+    ]
+    .into_iter()
+    .flatten()
+    .map(|stream| {
+        let output = Arc::clone(&output);
+        std::thread::spawn(move || {
+            for line in BufReader::new(stream).lines().map_while(|line| line.ok()) {
+                let mut output = output.lock().unwrap();
+                output.push_str(&line);
+                output.push('\n');
+            }
+        })
+    })
+    .collect();
+
+    let job_result = Arc::clone(&result);
+    std::thread::spawn(move || {
+        for reader in readers {
+            let _ = reader.join();
+        }
+        let ok = child.wait().map(|status| status.success()).unwrap_or(false);
+        *job_result.lock().unwrap() = Some(ok);
+    });
+
+    // Only the newest run can still be going, so the ones dropped have finished
+    if jobs.len() >= MAX_JOBS {
+        let excess = jobs.len() + 1 - MAX_JOBS;
+        jobs.drain(..excess);
+    }
+    let id = jobs.last().map_or(1, |job| job.id + 1);
+    jobs.push(Job { id, args, output, result });
+    Response::json(202, json!({ "job": id }))
+}
+
+fn job_status(server: &Server, id: usize) -> Response {
+    let jobs = server.jobs.lock().unwrap();
+    match jobs.iter().find(|job| job.id == id) {
+        Some(job) => {
+            let result = *job.result.lock().unwrap();
+            Response::json(
+                200,
+                json!({
+                    "job": job.id,
+                    "args": job.args,
+                    "running": result.is_none(),
+                    "ok": result,
+                    "output": job.output.lock().unwrap().clone(),
+                }),
+            )
+        }
+        None => Response::error(404, "no such job"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn options(json: &str) -> std::result::Result<Vec<String>, String> {
+        serde_json::from_str::<RunOptions>(json).map_err(|e| e.to_string())?.to_args()
+    }
+
+    #[test]
+    fn run_options_become_the_command_line() {
+        assert_eq!(
+            options(r#"{"device": "/dev/sda", "root_size": "8G", "var_size": "4G", "yes": true}"#).unwrap(),
+            vec!["-d", "/dev/sda", "-r", "8G", "-v", "4G", "--yes"]
+        );
+        // Values that would be read as options or subcommands, and options not listed
+        assert!(options(r#"{"device": "--layout=/tmp/x", "root_size": "8G"}"#).is_err());
+        assert!(options(r#"{"device": "/dev/sda", "root_size": "-h"}"#).is_err());
+        assert!(options(r#"{"device": "self-update", "root_size": "8G"}"#).is_err());
+        assert!(options(r#"{"device": "/dev/sda", "root_size": "8G", "layout": "x.toml"}"#).is_err());
+        assert!(checked_value("device", "").is_err());
+    }
+
+    #[test]
+    fn requests_are_read_within_the_header_limit() {
+        let request = read_request(&b"POST /api/plan?x=1 HTTP/1.1\r\nAuthorization: Bearer abc\r\nContent-Length: 2\r\n\r\n{}"[..]).unwrap();
+        assert_eq!((request.method.as_str(), request.path.as_str(), request.query.as_str()), ("POST", "/api/plan", "x=1"));
+        assert_eq!((request.authorization.as_deref(), request.body.as_slice()), (Some("Bearer abc"), &b"{}"[..]));
+
+        // An endless header line stops at the limit instead of filling memory
+        let endless = std::io::repeat(b'a');
+        let head = b"GET / HTTP/1.1\r\nX-Filler: ".chain(endless);
+        assert!(read_request(head).err().unwrap().to_string().contains("too large"));
+        let big_body = format!("POST /api/plan HTTP/1.1\r\nContent-Length: {}\r\n\r\n", MAX_BODY_BYTES + 1);
+        assert!(read_request(big_body.as_bytes()).is_err());
+    }
+
+    #[test]
+    fn a_passed_deadline_ends_the_request() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (stream, _) = listener.accept().unwrap();
+        // Trickled data arrives, but the deadline is already over
+        client.write_all(b"GET / HTTP/1.1\r\n").unwrap();
+        let err = read_request(DeadlineStream { stream: &stream, deadline: Instant::now() }).err().unwrap();
+        assert!(err.to_string().contains("deadline"), "{}", err);
+    }
+}
@@ -0,0 +1,417 @@
+//! Shrinking a single ext4 or btrfs partition anywhere on a disk (`shrink-part`),
+//! with the filesystem check, resize and partition table edit the root shrink
+//! uses. The partition keeps its start and number; the space behind it is freed.
+
+use anyhow::{bail, Context, Result};
+use std::path::Path;
+use std::process::Command;
+
+use crate::{parse, prompt, sysfs, MountPaths, SECTOR_SIZE};
+
+/// Partition device named by `target` (a device, or the mount point of one) and
+/// where it is mounted, if anywhere
+fn resolve(target: &str) -> Result<(String, Option<String>)> {
+    let mounts = std::fs::read_to_string("/proc/mounts").context("Failed to read /proc/mounts")?;
+    let entries = parse::proc_mounts(&mounts);
+    if target.starts_with("/dev/") {
+        let device = std::fs::canonicalize(target).context(format!("Device {} does not exist", target))?;
+        let mounted_at = entries
+            .iter()
+            .find(|entry| std::fs::canonicalize(&entry.source).is_ok_and(|source| source == device))
+            .map(|entry| entry.target.clone());
+        return Ok((device.to_string_lossy().to_string(), mounted_at));
+    }
+    if !Path::new(target).is_dir() {
+        bail!("{} is neither a partition device nor a mount point", target);
+    }
+    Ok((crate::mounted_device(target)?, Some(target.to_string())))
+}
+
+/// The partition `target` names and the disk it is on, which the device policy checks
+pub fn policy_devices(target: &str) -> Result<Vec<String>> {
+    let (device, _) = resolve(target)?;
+    let disk = sysfs::partition_of(&device).map(|(disk, _)| disk);
+    Ok(std::iter::once(device).chain(disk).collect())
+}
+
+fn run(program: &str, args: &[&str]) -> Result<String> {
+    let output = Command::new(program).args(args).output().context(format!("Failed to run {}", program))?;
+    if !output.status.success() {
+        bail!("{} {} failed: {}", program, args.join(" "), String::from_utf8_lossy(&output.stderr).trim());
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).to_string())
+}
+
+/// Headroom on top of an ext minimum: resize2fs needs free blocks to move data
+/// into, and a root shrunk to the last block is full the moment it boots
+const EXT_MARGIN_PERCENT: u64 = 5;
+const EXT_MARGIN_BYTES: u64 = 64 * 1024 * 1024;
+
+/// What an ext2/3/4 filesystem needs at any size, in bytes
+#[derive(Debug, Clone, PartialEq)]
+pub struct ExtMinimum {
+    /// Blocks holding files, directories and extended attributes
+    pub data: u64,
+    pub journal: u64,
+    /// Inode tables, bitmaps, superblock copies and (reserved) group
+    /// descriptors of the groups left after the shrink
+    pub metadata: u64,
+    /// What `resize2fs -P` estimates, which may allow for more than the above
+    pub estimate: u64,
+    pub margin: u64,
+}
+
+impl ExtMinimum {
+    /// Size to shrink to no further than
+    pub fn bytes(&self) -> u64 {
+        (self.data + self.journal + self.metadata).max(self.estimate) + self.margin
+    }
+
+    /// Where the minimum comes from, for refusals
+    pub fn breakdown(&self) -> String {
+        use crate::timing::format_bytes;
+        format!(
+            "{} of data, {} journal, {} of inode tables, bitmaps and group descriptors, {} safety margin",
+            format_bytes(self.data),
+            format_bytes(self.journal),
+            format_bytes(self.metadata),
+            format_bytes(self.margin)
+        )
+    }
+}
+
+/// Groups among the first `groups` that carry a superblock and group
+/// descriptor copy: all of them, or with sparse_super group 0, 1 and the
+/// powers of 3, 5 and 7, or with sparse_super2 group 0 and two more
+fn backup_groups(groups: u64, features: &str) -> u64 {
+    let features: Vec<&str> = features.split_whitespace().collect();
+    if features.contains(&"sparse_super2") {
+        return groups.min(3);
+    }
+    if !features.contains(&"sparse_super") {
+        return groups;
+    }
+    let mut count = groups.min(2);
+    for base in [3u64, 5, 7] {
+        let mut group = base;
+        while group < groups {
+            count += 1;
+            group *= base;
+        }
+    }
+    count
+}
+
+/// The minimum from a `dumpe2fs -h` header and the block count `resize2fs -P`
+/// estimated. The data is what is in use less the metadata of the groups there
+/// are now; the filesystem then needs enough groups for that data, the journal,
+/// their own metadata and the inodes in use.
+fn ext_minimum_from(header: &str, estimate_blocks: u64) -> Option<ExtMinimum> {
+    let field = |name: &str| header.lines().find_map(|line| line.strip_prefix(name)).map(str::trim);
+    let number = |name: &str| field(name).and_then(|value| value.parse::<u64>().ok());
+    let block_size = number("Block size:")?;
+    let block_count = number("Block count:")?;
+    let free_blocks = number("Free blocks:")?;
+    let first_block = number("First block:").unwrap_or(0);
+    let blocks_per_group = number("Blocks per group:").filter(|&blocks| blocks > 0)?;
+    let inodes_per_group = number("Inodes per group:").filter(|&inodes| inodes > 0)?;
+    let inode_blocks = match number("Inode blocks per group:") {
+        Some(blocks) => blocks,
+        None => (inodes_per_group * number("Inode size:")?).div_ceil(block_size),
+    };
+    let used_inodes = number("Inode count:")?.saturating_sub(number("Free inodes:")?);
+    let reserved_gdt = number("Reserved GDT blocks:").unwrap_or(0);
+    let descriptor_size = number("Group descriptor size:").unwrap_or(32);
+    let features = field("Filesystem features:").unwrap_or_default();
+    // e2fsprogs 1.47 counts journal blocks; older versions print "Journal size: 128M"
+    let journal_blocks = number("Total journal blocks:")
+        .or_else(|| {
+            let size = field("Journal size:")?;
+            let (digits, unit) = size.split_at(size.find(|c: char| !c.is_ascii_digit()).unwrap_or(size.len()));
+            let scale = match unit.trim() {
+                "k" | "K" => 1024,
+                "M" => 1024 * 1024,
+                "G" => 1024 * 1024 * 1024,
+                _ => 1,
+            };
+            Some(digits.parse::<u64>().ok()? * scale / block_size)
+        })
+        .unwrap_or(0);
+
+    let metadata = |groups: u64| {
+        let descriptors = (groups * descriptor_size).div_ceil(block_size);
+        groups * (inode_blocks + 2) + backup_groups(groups, features) * (1 + descriptors + reserved_gdt)
+    };
+    let groups_now = (block_count - first_block).div_ceil(blocks_per_group);
+    let data = (block_count - free_blocks).saturating_sub(metadata(groups_now) + journal_blocks);
+    let mut groups = used_inodes.div_ceil(inodes_per_group).max(1);
+    while groups * blocks_per_group < data + journal_blocks + metadata(groups) {
+        groups += 1;
+    }
+
+    let estimate = estimate_blocks * block_size;
+    let needed = ((data + journal_blocks + metadata(groups)) * block_size).max(estimate);
+    Some(ExtMinimum {
+        data: data * block_size,
+        journal: journal_blocks * block_size,
+        metadata: metadata(groups) * block_size,
+        estimate,
+        margin: needed * EXT_MARGIN_PERCENT / 100 + EXT_MARGIN_BYTES,
+    })
+}
+
+/// What the ext2/3/4 filesystem on `device` needs, from its superblock and
+/// resize2fs's own estimate
+pub fn ext_minimum(device: &str) -> Result<ExtMinimum> {
+    let header = run("dumpe2fs", &["-h", device])?;
+    let estimate = run("resize2fs", &["-P", device])?;
+    let blocks: u64 = estimate
+        .lines()
+        .find_map(|line| line.split_once("minimum size of the filesystem:"))
+        .and_then(|(_, value)| value.trim().parse().ok())
+        .context(format!("resize2fs -P gave no minimum size for {}", device))?;
+    ext_minimum_from(&header, blocks).context(format!("Could not read the block and inode counts of {}", device))
+}
+
+/// Smallest size the ext2/3/4 filesystem on `device` can safely shrink to
+pub fn ext_minimum_bytes(device: &str) -> Result<u64> {
+    Ok(ext_minimum(device)?.bytes())
+}
+
+/// devid of the one device of the btrfs mounted at `mount_point`
+fn btrfs_devid(mount_point: &str) -> Result<String> {
+    let show = run("btrfs", &["filesystem", "show", mount_point])?;
+    let devids: Vec<&str> = show
+        .lines()
+        .filter_map(|line| line.trim().strip_prefix("devid"))
+        .filter_map(|rest| rest.split_whitespace().next())
+        .collect();
+    match devids.as_slice() {
+        [devid] => Ok(devid.to_string()),
+        _ => bail!("The btrfs at {} spans {} devices; shrink it with btrfs device remove/resize instead", mount_point, devids.len()),
+    }
+}
+
+/// Smallest size the btrfs device `devid` mounted at `mount_point` can shrink to
+fn btrfs_minimum_bytes(mount_point: &str, devid: &str) -> Result<u64> {
+    let output = run("btrfs", &["inspect-internal", "min-dev-size", "--id", devid, mount_point])?;
+    output
+        .split_whitespace()
+        .next()
+        .and_then(|bytes| bytes.parse().ok())
+        .context(format!("btrfs inspect-internal min-dev-size gave no size for {}", mount_point))
+}
+
+/// Run `f` with the btrfs on `device` mounted, at `mounted_at` if it already is
+fn with_btrfs<T>(device: &str, mounted_at: Option<&str>, mounts: &MountPaths, f: impl FnOnce(&str) -> Result<T>) -> Result<T> {
+    if let Some(mount_point) = mounted_at {
+        return f(mount_point);
+    }
+    let mount_point = mounts.path("shrink");
+    crate::mount_at(device, &mount_point)?;
+    let result = f(&mount_point);
+    crate::unmount_quiet(&mount_point);
+    result
+}
+
+/// GPT entry fields `mkpart` doesn't carry over: unique GUID, type GUID, name.
+/// Read from the table, not the partition, whose content may be mid-move.
+fn gpt_entry(disk: &str, number: u32) -> Result<(String, String, String)> {
+    let info = run("sgdisk", &["-i", &number.to_string(), disk])?;
+    let field = |prefix: &str| info.lines().find_map(|line| line.strip_prefix(prefix)).map(str::trim);
+    let kind = field("Partition GUID code:").and_then(|value| value.split_whitespace().next());
+    match (field("Partition unique GUID:"), kind) {
+        (Some(uuid), Some(kind)) => {
+            let name = field("Partition name:").unwrap_or_default().trim_matches('\'');
+            Ok((uuid.to_string(), kind.to_string(), name.to_string()))
+        }
+        _ => bail!("Could not read GPT entry {} of {}", number, disk),
+    }
+}
+
+pub fn is_ext(fstype: &str) -> bool {
+    matches!(fstype, "ext2" | "ext3" | "ext4")
+}
+
+/// Smallest size the filesystem on `device` can shrink to
+pub fn minimum_bytes(device: &str, fstype: &str, mounted_at: Option<&str>, mounts: &MountPaths) -> Result<u64> {
+    if is_ext(fstype) {
+        return ext_minimum_bytes(device);
+    }
+    with_btrfs(device, mounted_at, mounts, |mount_point| btrfs_minimum_bytes(mount_point, &btrfs_devid(mount_point)?))
+}
+
+/// Resize the ext2/3/4 or btrfs filesystem on `device` to `size`, or to fill its partition
+pub fn resize_filesystem(device: &str, fstype: &str, size: Option<u64>, mounted_at: Option<&str>, mounts: &MountPaths) -> Result<()> {
+    if is_ext(fstype) {
+        return match size {
+            Some(bytes) => crate::shrink_root_filesystem(device, bytes),
+            None => {
+                println!("  Growing filesystem on {} to fill its partition...", device);
+                run("resize2fs", &[device]).map(|_| ())
+            }
+        };
+    }
+    with_btrfs(device, mounted_at, mounts, |mount_point| {
+        let devid = btrfs_devid(mount_point)?;
+        let size = size.map_or("max".to_string(), |bytes| bytes.to_string());
+        println!("  Resizing btrfs device {} on {} to {}...", devid, mount_point, size);
+        run("btrfs", &["filesystem", "resize", &format!("{}:{}", devid, size), mount_point]).map(|_| ())
+    })
+}
+
+/// Recreate partition `number` from `start` to `end` like `recreate_partition`,
+/// putting its GPT GUID, type and name back. fstab and cmdline.txt may name the
+/// partition by PARTUUID, which a new GPT entry would change.
+pub fn recreate_keeping_entry(disk_info: &crate::DiskInfo, number: u32, fstype: &str, start: u64, end: u64) -> Result<()> {
+    let entry = if disk_info.partition_table == "gpt" { Some(gpt_entry(&disk_info.device, number)?) } else { None };
+    println!("  Resizing partition {} to sectors {} - {}...", number, start, end);
+    // parted's name for the filesystem sets the MBR type (fat32 0x0c, linux-swap 0x82, else 0x83)
+    let hint = match fstype {
+        "vfat" => "fat32",
+        "swap" => "linux-swap",
+        "btrfs" => "btrfs",
+        _ => "ext4",
+    };
+    crate::recreate_partition(disk_info, number, hint, start, end)?;
+    if let Some((uuid, kind, name)) = entry {
+        let part = number.to_string();
+        run(
+            "sgdisk",
+            &[
+                &format!("--partition-guid={}:{}", part, uuid),
+                &format!("--typecode={}:{}", part, kind),
+                &format!("--change-name={}:{}", part, name),
+                &disk_info.device,
+            ],
+        )?;
+        crate::reread_partitions(&disk_info.device);
+        println!("  Kept PARTUUID {}", uuid);
+    }
+    Ok(())
+}
+
+/// Shrink the ext4 or btrfs partition `target` (a device or a mount point) to `size`
+pub fn shrink_partition(target: &str, size: &str, dry_run: bool, mounts: &MountPaths) -> Result<()> {
+    let (device, mounted_at) = resolve(target)?;
+    let Some((disk, number)) = sysfs::partition_of(&device) else {
+        bail!("{} is not a partition", device);
+    };
+    let disk_info = crate::get_disk_info(&disk)?;
+    let (start, end) = crate::get_partition_bounds(&disk, number)?;
+    let current_bytes = (end - start + 1) * SECTOR_SIZE;
+    // Whole 4K blocks, which both resize2fs and btrfs work in
+    let new_bytes = crate::parse_size(size)? / 4096 * 4096;
+    if new_bytes >= current_bytes {
+        bail!(
+            "{} is {} MB; --to {} doesn't shrink it (grow partitions with parted and resize2fs/btrfs)",
+            device,
+            current_bytes / (1024 * 1024),
+            size
+        );
+    }
+    // The partition ends on an alignment boundary, holding the filesystem in full
+    let new_end = crate::align_sector(start + new_bytes / SECTOR_SIZE, disk_info.alignment()) - 1;
+    let new_end = new_end.min(end);
+
+    let fstype = crate::fstab::device_fstype(&device).context(format!("No filesystem found on {}", device))?;
+    if !is_ext(&fstype) && fstype != "btrfs" {
+        bail!("{} holds {}; shrink-part handles ext2/3/4 and btrfs", device, fstype);
+    }
+    if is_ext(&fstype) && let Some(ref mount_point) = mounted_at {
+        bail!("{} is mounted at {}; ext4 only shrinks unmounted, so unmount it first", device, mount_point);
+    }
+    if disk_info.partition_table == "gpt" && !crate::command_exists("sgdisk") {
+        bail!("Shrinking partitions on GPT disks needs sgdisk (gdisk package) to keep the PARTUUID");
+    }
+
+    println!("Partition {} of {} ({}, {}):", number, disk, device, fstype);
+    println!("  Sectors: {} - {} ({} MB)", start, end, current_bytes / (1024 * 1024));
+    println!("  New end: {} ({} MB filesystem, {} MB freed)", new_end, new_bytes / (1024 * 1024), (end - new_end) * SECTOR_SIZE / (1024 * 1024));
+    if let Some(ref mount_point) = mounted_at {
+        println!("  Mounted at {}; btrfs shrinks online", mount_point);
+    }
+
+    if is_ext(&fstype) && !dry_run {
+        println!("\nStep 1: Checking filesystem...");
+        crate::check_filesystem(&device)?;
+    }
+    // An unmounted btrfs would have to be mounted for its minimum; a dry run leaves it alone
+    if is_ext(&fstype) || mounted_at.is_some() || !dry_run {
+        let minimum = minimum_bytes(&device, &fstype, mounted_at.as_deref(), mounts)?;
+        println!("  Filesystem minimum: {} MB", minimum.div_ceil(1024 * 1024));
+        if new_bytes < minimum {
+            bail!("{} can't shrink below {} MB", device, minimum.div_ceil(1024 * 1024));
+        }
+    }
+    if dry_run {
+        println!("\nDry run: nothing changed");
+        return Ok(());
+    }
+
+    println!("\nWARNING: This will modify the partition table of {}!", disk);
+    prompt::wait_for_enter("Press Enter to continue or Ctrl+C to cancel...")?;
+
+    println!("\nStep 2: Shrinking the filesystem...");
+    resize_filesystem(&device, &fstype, Some(new_bytes), mounted_at.as_deref(), mounts)?;
+
+    println!("\nStep 3: Shrinking partition {}...", number);
+    recreate_keeping_entry(&disk_info, number, &fstype, start, new_end)?;
+    println!("  Partition resized successfully");
+
+    println!("\n=== {} shrunk to {} MB ===", device, (new_end - start + 1) * SECTOR_SIZE / (1024 * 1024));
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// `dumpe2fs -h` of a fresh 2G ext4 (e2fsprogs 1.47), trimmed
+    const HEADER: &str = "Filesystem features:      has_journal ext_attr resize_inode dir_index filetype extent 64bit flex_bg sparse_super large_file huge_file dir_nlink extra_isize metadata_csum
+Inode count:              131072
+Block count:              524288
+Reserved block count:     26214
+Free blocks:              498132
+Free inodes:              131061
+First block:              0
+Block size:               4096
+Group descriptor size:    64
+Reserved GDT blocks:      255
+Blocks per group:         32768
+Inodes per group:         8192
+Inode blocks per group:   512
+Inode size:\t          256
+Total journal size:       64M
+Total journal blocks:     16384
+";
+
+    #[test]
+    fn minimum_counts_journal_and_metadata_of_the_groups_kept() {
+        let minimum = ext_minimum_from(HEADER, 26205).unwrap();
+        // 16 groups of 514 metadata blocks, 6 backups of 257: the rest of the 26156 used is data
+        assert_eq!(minimum.data, 6 * 4096);
+        assert_eq!(minimum.journal, 16384 * 4096);
+        // One group left, holding the only superblock
+        assert_eq!(minimum.metadata, (514 + 257) * 4096);
+        // resize2fs -P asks for more here, and the margin goes on top of that
+        assert_eq!(minimum.bytes(), 26205 * 4096 + 26205 * 4096 * 5 / 100 + 64 * 1024 * 1024);
+
+        // 20011 inodes in use need three groups of 8192
+        let minimum = ext_minimum_from(&HEADER.replace("131061", "111061"), 26205).unwrap();
+        assert_eq!(minimum.metadata, (3 * 514 + 2 * 257) * 4096);
+
+        // Older e2fsprogs give the journal as a size
+        let old = HEADER.replace("Total journal blocks:     16384\n", "").replace("Total journal size:  ", "Journal size:        ");
+        assert_eq!(ext_minimum_from(&old, 26205).unwrap().journal, 64 * 1024 * 1024);
+    }
+
+    #[test]
+    fn superblock_copies_follow_sparse_super() {
+        // Groups 0, 1, 3, 5, 7, 9, 25, 27, 49
+        assert_eq!(backup_groups(50, "has_journal sparse_super"), 9);
+        assert_eq!(backup_groups(1, "sparse_super"), 1);
+        assert_eq!(backup_groups(50, "sparse_super2"), 3);
+        assert_eq!(backup_groups(50, "has_journal"), 50);
+    }
+}
@@ -0,0 +1,243 @@
+use crate::sysfs::derive_partition_path;
+use crate::{DiskInfo, PartitionLayout, SECTOR_SIZE};
+
+#[derive(Clone, Copy)]
+enum Kind {
+    Fat32,
+    Fat16,
+    Linux,
+    Swap,
+    Extended,
+}
+
+fn type_code(label: &str, kind: Kind) -> &'static str {
+    if label == "gpt" {
+        match kind {
+            Kind::Fat32 | Kind::Fat16 => "EBD0A0A2-B9E5-4433-87C0-68B6B72699C7",
+            Kind::Linux => "0FC63DAF-8483-4772-8E79-3D69D8477DE4",
+            Kind::Swap => "0657FD6D-A4AB-43C4-84E5-0933C84B4F4F",
+            Kind::Extended => unreachable!("GPT has no extended partitions"),
+        }
+    } else {
+        match kind {
+            Kind::Fat32 => "c",
+            Kind::Fat16 => "e",
+            Kind::Linux => "83",
+            Kind::Swap => "82",
+            Kind::Extended => "5",
+        }
+    }
+}
+
+/// A partition of the table the tool will leave behind
+pub struct PlannedPartition {
+    pub number: u32,
+    pub start: u64,
+    pub end: u64,
+    /// MBR type byte in hex ("83") or GPT type GUID, as sfdisk shows them
+    pub type_code: &'static str,
+}
+
+/// The table the tool will leave behind. Partition numbers follow creation
+/// order: boot and root keep 1 and 2, the rest take the next free number as they
+/// are created. Logical partitions behind an extended one are numbered from 5.
+pub fn planned_partitions(disk: &DiskInfo, boot: (u64, u64), layout: &PartitionLayout) -> Vec<PlannedPartition> {
+    let label = disk.partition_table.as_str();
+    let mut partitions = vec![
+        (boot.0, boot.1, Kind::Fat32),
+        (layout.root_start, layout.root_end, Kind::Linux),
+    ];
+    if layout.recovery_size_bytes > 0 {
+        partitions.push((layout.recovery_start, layout.recovery_end, Kind::Fat32));
+    }
+    let primaries = partitions.len() as u32;
+    if layout.logical() {
+        partitions.push((layout.extended_start, layout.extended_end, Kind::Extended));
+    }
+    if layout.swap_size_bytes > 0 {
+        partitions.push((layout.swap_start, layout.swap_end, Kind::Swap));
+    }
+    if layout.var_size_bytes > 0 {
+        partitions.push((layout.var_start, layout.var_end, Kind::Linux));
+    }
+    if layout.containers_size_bytes > 0 {
+        partitions.push((layout.containers_start, layout.containers_end, Kind::Linux));
+    }
+    if layout.cidata_size_bytes > 0 {
+        partitions.push((layout.cidata_start, layout.cidata_end, Kind::Fat16));
+    }
+    partitions.push((layout.home_start, layout.home_end, Kind::Linux));
+
+    partitions
+        .into_iter()
+        .enumerate()
+        .map(|(index, (start, end, kind))| {
+            let index = index as u32;
+            let number = if layout.logical() && index > primaries {
+                crate::mbr::FIRST_LOGICAL + index - primaries - 1
+            } else {
+                index + 1
+            };
+            PlannedPartition { number, start, end, type_code: type_code(label, kind) }
+        })
+        .collect()
+}
+
+/// Render the table the tool will leave behind as an `sfdisk --dump` style listing
+pub fn sfdisk_dump(disk: &DiskInfo, boot: (u64, u64), layout: &PartitionLayout) -> String {
+    let label = disk.partition_table.as_str();
+    let sfdisk_label = if label == "msdos" { "dos" } else { label };
+    let mut dump = format!(
+        "label: {}\ndevice: {}\nunit: sectors\nsector-size: {}\n\n",
+        sfdisk_label, disk.device, SECTOR_SIZE
+    );
+    for partition in planned_partitions(disk, boot, layout) {
+        dump.push_str(&format!(
+            "{} : start={:>12}, size={:>12}, type={}\n",
+            derive_partition_path(&disk.device, partition.number),
+            partition.start,
+            partition.end - partition.start + 1,
+            partition.type_code
+        ));
+    }
+    dump
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{compute_partition_layout, PartitionSizes};
+
+    const GIB: u64 = 1024 * 1024 * 1024;
+    const MIB: u64 = 1024 * 1024;
+
+    /// Raspberry Pi OS images: 512 MiB boot at sector 8192, root right behind it
+    const BOOT: (u64, u64) = (8192, 1056767);
+
+    #[derive(Clone)]
+    struct Case {
+        name: String,
+        disk_bytes: u64,
+        label: &'static str,
+        root: u64,
+        recovery: Option<u64>,
+        swap: Option<u64>,
+        var: Option<u64>,
+        containers: Option<u64>,
+        cidata: Option<u64>,
+        erase_block: Option<u64>,
+    }
+
+    fn disk(case: &Case) -> DiskInfo {
+        let device = if case.label == "gpt" { "/dev/nvme0n1" } else { "/dev/sda" };
+        DiskInfo {
+            device: device.to_string(),
+            size_bytes: case.disk_bytes,
+            size_sectors: case.disk_bytes / SECTOR_SIZE,
+            is_sd_card: false,
+            root_partition: derive_partition_path(device, 2),
+            roles: crate::roles::Roles { boot: 1, root: 2, found_by: "position" },
+            partition_table: case.label.to_string(),
+            erase_block_bytes: case.erase_block,
+            identity: Default::default(),
+        }
+    }
+
+    fn render(case: &Case) -> String {
+        let disk = disk(case);
+        let sizes = PartitionSizes {
+            root: case.root,
+            recovery: case.recovery,
+            swap: case.swap,
+            var: case.var,
+            containers: case.containers,
+            cidata: case.cidata,
+        };
+        // A fresh image keeps boot and root and has no extended partition yet
+        let logical = match case.label {
+            "msdos" => crate::mbr::plan_logical_with(&disk, &sizes, 2, None),
+            _ => Ok(false),
+        };
+        match logical.and_then(|logical| compute_partition_layout(&disk, BOOT.1 + 1, &sizes, logical)) {
+            Ok(layout) => sfdisk_dump(&disk, BOOT, &layout),
+            Err(e) => format!("error: {}\n", e),
+        }
+    }
+
+    fn cases() -> Vec<Case> {
+        let mut cases = Vec::new();
+        for (size_name, disk_bytes) in [("32g", 32 * GIB), ("sd32", 62_333_952 * SECTOR_SIZE), ("128g", 128 * GIB), ("1t", 1000 * GIB)]
+        {
+            for label in ["msdos", "gpt"] {
+                let base = Case {
+                    name: String::new(),
+                    disk_bytes,
+                    label,
+                    root: 8 * GIB,
+                    recovery: None,
+                    swap: None,
+                    var: None,
+                    containers: None,
+                    cidata: None,
+                    erase_block: None,
+                };
+                let name = |variant: &str| format!("{}-{}-{}", size_name, label, variant);
+                cases.push(Case { name: name("plain"), ..base.clone() });
+                cases.push(Case { name: name("swap-var"), swap: Some(2 * GIB), var: Some(4 * GIB), ..base.clone() });
+                cases.push(Case { name: name("recovery"), recovery: Some(256 * MIB), ..base.clone() });
+                cases.push(Case {
+                    name: name("containers"),
+                    var: Some(4 * GIB),
+                    containers: Some(8 * GIB),
+                    ..base.clone()
+                });
+                cases.push(Case {
+                    name: name("all"),
+                    root: 16 * GIB,
+                    recovery: Some(256 * MIB),
+                    swap: Some(2 * GIB),
+                    var: Some(4 * GIB),
+                    cidata: Some(64 * MIB),
+                    ..base.clone()
+                });
+                // A card reporting a 4 MiB erase block: starts and ends on it
+                if size_name == "sd32" {
+                    cases.push(Case {
+                        name: name("erase4m"),
+                        recovery: Some(254 * MIB),
+                        cidata: Some(62 * MIB),
+                        erase_block: Some(4 * MIB),
+                        ..base.clone()
+                    });
+                }
+            }
+        }
+        cases
+    }
+
+    /// Compare every layout in the matrix against tests/golden/layout/<case>.sfdisk.
+    /// Set UPDATE_GOLDEN=1 to rewrite the files after an intended layout change.
+    #[test]
+    fn layouts_match_golden_dumps() {
+        let dir = std::path::Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/golden/layout");
+        let update = std::env::var_os("UPDATE_GOLDEN").is_some();
+        let mut mismatches = Vec::new();
+
+        for case in cases() {
+            let actual = render(&case);
+            let path = dir.join(format!("{}.sfdisk", case.name));
+            if update {
+                std::fs::create_dir_all(&dir).unwrap();
+                std::fs::write(&path, &actual).unwrap();
+                continue;
+            }
+            let expected = std::fs::read_to_string(&path)
+                .unwrap_or_else(|_| panic!("missing golden file {}; run with UPDATE_GOLDEN=1", path.display()));
+            if expected != actual {
+                mismatches.push(format!("{}:\n--- expected\n{}--- actual\n{}", case.name, expected, actual));
+            }
+        }
+
+        assert!(mismatches.is_empty(), "layout changed:\n{}", mismatches.join("\n"));
+    }
+}
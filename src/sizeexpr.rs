@@ -0,0 +1,299 @@
+//! Size rules such as `clamp(2*ram, 1G, 8G)` or `10% of disk`, for sizes that
+//! depend on the target instead of being fixed.
+//!
+//! Sizes take the same units as everywhere else (`512M`, `8G`, a plain number
+//! is bytes). `ram` and `disk` are the target's RAM and capacity. `+ - * /`
+//! and parentheses work as usual, `N% of X` takes a share of X, and `min`,
+//! `max` and `clamp(value, low, high)` bound the result.
+
+use anyhow::{anyhow, bail, Result};
+
+/// What a rule can refer to; None for what isn't known about the target
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Inputs {
+    pub disk: Option<u64>,
+    pub ram: Option<u64>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Number(f64),
+    Percent,
+    Name(String),
+    Open,
+    Close,
+    Comma,
+    Plus,
+    Minus,
+    Times,
+    Divide,
+}
+
+fn unit_multiplier(unit: &str) -> Option<f64> {
+    let unit = unit.to_ascii_uppercase();
+    let unit = unit.strip_suffix('B').unwrap_or(&unit);
+    Some(match unit {
+        "" => 1.0,
+        "K" => 1024.0,
+        "M" => 1024.0 * 1024.0,
+        "G" => 1024.0 * 1024.0 * 1024.0,
+        "T" => 1024.0 * 1024.0 * 1024.0 * 1024.0,
+        _ => return None,
+    })
+}
+
+fn tokenize(text: &str) -> Result<Vec<Token>> {
+    let chars: Vec<char> = text.chars().collect();
+    let mut tokens = Vec::new();
+    let mut i = 0;
+    while i < chars.len() {
+        let c = chars[i];
+        let single = match c {
+            '%' => Some(Token::Percent),
+            '(' => Some(Token::Open),
+            ')' => Some(Token::Close),
+            ',' => Some(Token::Comma),
+            '+' => Some(Token::Plus),
+            '-' => Some(Token::Minus),
+            '*' | '×' => Some(Token::Times),
+            '/' => Some(Token::Divide),
+            _ => None,
+        };
+        if let Some(token) = single {
+            tokens.push(token);
+            i += 1;
+        } else if c.is_whitespace() {
+            i += 1;
+        } else if c.is_ascii_digit() || c == '.' {
+            let start = i;
+            while i < chars.len() && (chars[i].is_ascii_digit() || chars[i] == '.') {
+                i += 1;
+            }
+            let number: String = chars[start..i].iter().collect();
+            let number: f64 = number.parse().map_err(|_| anyhow!("Invalid number {} in \"{}\"", number, text))?;
+            // A unit is written right after the number: 512M, 1.5GB
+            let unit_start = i;
+            while i < chars.len() && chars[i].is_ascii_alphabetic() {
+                i += 1;
+            }
+            let unit: String = chars[unit_start..i].iter().collect();
+            let multiplier =
+                unit_multiplier(&unit).ok_or_else(|| anyhow!("Unknown size unit {} in \"{}\"", unit, text))?;
+            tokens.push(Token::Number(number * multiplier));
+        } else if c.is_ascii_alphabetic() {
+            let start = i;
+            while i < chars.len() && (chars[i].is_ascii_alphanumeric() || chars[i] == '_') {
+                i += 1;
+            }
+            tokens.push(Token::Name(chars[start..i].iter().collect::<String>().to_ascii_lowercase()));
+        } else {
+            bail!("Unexpected '{}' in \"{}\"", c, text);
+        }
+    }
+    Ok(tokens)
+}
+
+struct Parser<'a> {
+    tokens: Vec<Token>,
+    position: usize,
+    inputs: Inputs,
+    text: &'a str,
+}
+
+impl Parser<'_> {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.position)
+    }
+
+    fn next(&mut self) -> Option<Token> {
+        let token = self.tokens.get(self.position).cloned();
+        self.position += 1;
+        token
+    }
+
+    fn expect(&mut self, expected: Token, what: &str) -> Result<()> {
+        match self.next() {
+            Some(token) if token == expected => Ok(()),
+            _ => bail!("Expected {} in \"{}\"", what, self.text),
+        }
+    }
+
+    /// term { (+|-) term }
+    fn expression(&mut self) -> Result<f64> {
+        let mut value = self.term()?;
+        loop {
+            match self.peek() {
+                Some(Token::Plus) => {
+                    self.position += 1;
+                    value += self.term()?;
+                }
+                Some(Token::Minus) => {
+                    self.position += 1;
+                    value -= self.term()?;
+                }
+                _ => return Ok(value),
+            }
+        }
+    }
+
+    /// primary { (*|/) primary }
+    fn term(&mut self) -> Result<f64> {
+        let mut value = self.primary()?;
+        loop {
+            match self.peek() {
+                Some(Token::Times) => {
+                    self.position += 1;
+                    value *= self.primary()?;
+                }
+                Some(Token::Divide) => {
+                    self.position += 1;
+                    let divisor = self.primary()?;
+                    if divisor == 0.0 {
+                        bail!("Division by zero in \"{}\"", self.text);
+                    }
+                    value /= divisor;
+                }
+                _ => return Ok(value),
+            }
+        }
+    }
+
+    /// A number (or `N% of` something), a name, a function call or a parenthesized expression
+    fn primary(&mut self) -> Result<f64> {
+        match self.next() {
+            Some(Token::Number(number)) => {
+                if self.peek() != Some(&Token::Percent) {
+                    return Ok(number);
+                }
+                self.position += 1;
+                if self.next() != Some(Token::Name("of".to_string())) {
+                    bail!("Write a percentage as \"{}% of disk\" (or of ram) in \"{}\"", number, self.text);
+                }
+                Ok(number / 100.0 * self.primary()?)
+            }
+            Some(Token::Open) => {
+                let value = self.expression()?;
+                self.expect(Token::Close, "')'")?;
+                Ok(value)
+            }
+            Some(Token::Name(name)) if self.peek() == Some(&Token::Open) => {
+                self.position += 1;
+                let mut arguments = vec![self.expression()?];
+                while self.peek() == Some(&Token::Comma) {
+                    self.position += 1;
+                    arguments.push(self.expression()?);
+                }
+                self.expect(Token::Close, "')' after the arguments")?;
+                self.call(&name, &arguments)
+            }
+            Some(Token::Name(name)) => self.variable(&name),
+            Some(token) => bail!("Unexpected {:?} in \"{}\"", token, self.text),
+            None => bail!("\"{}\" ends too early", self.text),
+        }
+    }
+
+    fn variable(&self, name: &str) -> Result<f64> {
+        let value = match name {
+            "disk" => self.inputs.disk,
+            "ram" => self.inputs.ram,
+            "rest" => bail!("rest only goes with home, which always takes the rest of the disk"),
+            _ => bail!("Unknown name {} in \"{}\" (sizes can use disk and ram)", name, self.text),
+        };
+        value.map(|bytes| bytes as f64).ok_or_else(|| anyhow!("\"{}\" uses {}, which isn't known for this target", self.text, name))
+    }
+
+    fn call(&self, name: &str, arguments: &[f64]) -> Result<f64> {
+        match (name, arguments) {
+            ("min", _) => Ok(arguments.iter().copied().fold(f64::INFINITY, f64::min)),
+            ("max", _) => Ok(arguments.iter().copied().fold(f64::NEG_INFINITY, f64::max)),
+            ("clamp", &[value, low, high]) => {
+                if low > high {
+                    bail!("clamp in \"{}\" has a lower bound above its upper bound", self.text);
+                }
+                Ok(value.clamp(low, high))
+            }
+            ("clamp", _) => bail!("clamp takes a value, a lower and an upper bound in \"{}\"", self.text),
+            _ => bail!("Unknown function {} in \"{}\" (min, max and clamp are known)", name, self.text),
+        }
+    }
+}
+
+/// Evaluate a size rule to bytes
+pub fn evaluate(text: &str, inputs: Inputs) -> Result<u64> {
+    let mut parser = Parser { tokens: tokenize(text)?, position: 0, inputs, text };
+    let value = parser.expression()?;
+    if parser.position < parser.tokens.len() {
+        bail!("Unexpected {:?} in \"{}\"", parser.tokens[parser.position], text);
+    }
+    if !value.is_finite() || value < 0.0 {
+        bail!("\"{}\" comes out negative", text);
+    }
+    Ok(value as u64)
+}
+
+/// Whether a home size means what home always gets: the rest of the disk
+pub fn is_rest(text: &str) -> bool {
+    text.trim().eq_ignore_ascii_case("rest")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const M: u64 = 1024 * 1024;
+    const G: u64 = 1024 * M;
+
+    fn pi(ram: u64, disk: u64) -> Inputs {
+        Inputs { disk: Some(disk), ram: Some(ram) }
+    }
+
+    #[test]
+    fn plain_sizes_read_like_the_command_line() {
+        for (text, expected) in [("8G", 8 * G), ("512M", 512 * M), ("512MB", 512 * M), ("1.5g", 3 * G / 2), ("4096", 4096)] {
+            assert_eq!(evaluate(text, Inputs::default()).unwrap(), expected, "{}", text);
+        }
+    }
+
+    #[test]
+    fn clamp_bounds_twice_the_ram() {
+        let rule = "clamp(2*ram, 1G, 8G)";
+        assert_eq!(evaluate(rule, pi(G / 2, 32 * G)).unwrap(), G);
+        assert_eq!(evaluate(rule, pi(2 * G, 32 * G)).unwrap(), 4 * G);
+        assert_eq!(evaluate(rule, pi(8 * G, 32 * G)).unwrap(), 8 * G);
+    }
+
+    #[test]
+    fn percentages_take_a_share() {
+        assert_eq!(evaluate("10% of disk", pi(4 * G, 100 * G)).unwrap(), 10 * G);
+        assert_eq!(evaluate("50% of ram + 1G", pi(4 * G, 100 * G)).unwrap(), 3 * G);
+        assert_eq!(evaluate("min(25% of disk, 16G)", pi(4 * G, 500 * G)).unwrap(), 16 * G);
+        assert!(evaluate("10%", pi(4 * G, 100 * G)).is_err());
+    }
+
+    #[test]
+    fn arithmetic_follows_precedence() {
+        assert_eq!(evaluate("1G + 2 * 512M", Inputs::default()).unwrap(), 2 * G);
+        assert_eq!(evaluate("(1G + 1G) / 4", Inputs::default()).unwrap(), 512 * M);
+        assert_eq!(evaluate("max(ram, disk - 120G)", pi(8 * G, 128 * G)).unwrap(), 8 * G);
+        assert_eq!(evaluate("ram × 2", pi(G, 0)).unwrap(), 2 * G);
+    }
+
+    #[test]
+    fn mistakes_are_refused() {
+        for text in ["", "2ram", "8X", "clamp(ram, 8G, 1G)", "clamp(ram, 1G)", "avg(1G)", "1G - 2G", "1G / 0", "(1G", "1G 2G", "cpu"] {
+            assert!(evaluate(text, pi(4 * G, 32 * G)).is_err(), "{}", text);
+        }
+    }
+
+    #[test]
+    fn unknown_inputs_are_errors_only_when_used() {
+        assert_eq!(evaluate("8G", Inputs::default()).unwrap(), 8 * G);
+        assert!(evaluate("2 * ram", Inputs { disk: Some(G), ram: None }).is_err());
+    }
+
+    #[test]
+    fn rest_belongs_to_home() {
+        assert!(is_rest("rest") && is_rest(" REST "));
+        assert!(evaluate("rest", pi(G, G)).is_err());
+    }
+}
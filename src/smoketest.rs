@@ -0,0 +1,135 @@
+//! Quick checks run in the migrated root through chroot (`--smoke-test`), for
+//! breakage that only shows once the target's own programs look at the new
+//! layout: a package database they can't read now that /var moved, user
+//! lookups that fail, or mount units systemd would reject at boot. Problems are
+//! reported as W016 rather than stopping the run, since the data is already in
+//! place; they are worth fixing before the disk goes back into the Pi.
+
+use anyhow::Result;
+use std::path::Path;
+use std::process::Command;
+
+use crate::{findings, initramfs};
+
+/// Where the fstab generator writes its units, inside the target
+const UNIT_DIR: &str = "/tmp/rpi-fs-shrink-smoke-test";
+
+/// Generators systemd runs at boot to turn fstab into mount units
+const FSTAB_GENERATORS: [&str; 2] =
+    ["/usr/lib/systemd/system-generators/systemd-fstab-generator", "/lib/systemd/system-generators/systemd-fstab-generator"];
+
+/// Users with a home directory under /home in `passwd`
+fn home_users(passwd: &str) -> Vec<String> {
+    passwd
+        .lines()
+        .filter_map(|line| {
+            let fields: Vec<&str> = line.split(':').collect();
+            (fields.len() >= 6 && fields[5].starts_with("/home/")).then(|| fields[0].to_string())
+        })
+        .collect()
+}
+
+/// Run `args` in `root`; on failure, the first lines of what it printed
+fn run_in(root: &str, args: &[&str]) -> Result<(), String> {
+    let output = Command::new("chroot").arg(root).args(args).output().map_err(|err| format!("chroot: {}", err))?;
+    if output.status.success() {
+        return Ok(());
+    }
+    let text = format!("{}{}", String::from_utf8_lossy(&output.stderr), String::from_utf8_lossy(&output.stdout));
+    let lines: Vec<&str> = text.lines().map(str::trim).filter(|line| !line.is_empty()).take(5).collect();
+    Err(format!("{} exited with {}{}", args.join(" "), output.status, if lines.is_empty() { String::new() } else { format!(": {}", lines.join("; ")) }))
+}
+
+/// The target's package manager can read its database
+fn check_packages(root: &str) -> Option<Result<(), String>> {
+    let exists = |path: &str| Path::new(&format!("{}{}", root, path)).exists();
+    if exists("/usr/bin/dpkg-query") {
+        return Some(run_in(root, &["dpkg-query", "--show", "dpkg"]).and_then(|()| run_in(root, &["dpkg", "--audit"])));
+    }
+    if exists("/usr/bin/pacman") {
+        return Some(run_in(root, &["pacman", "-Q", "pacman"]));
+    }
+    None
+}
+
+/// root, its group and every user with a home under /home resolve through NSS
+fn check_users(root: &str) -> Result<(), String> {
+    run_in(root, &["getent", "passwd", "root"])?;
+    run_in(root, &["getent", "group", "root"])?;
+    let passwd = std::fs::read_to_string(format!("{}/etc/passwd", root)).map_err(|err| format!("/etc/passwd: {}", err))?;
+    for user in home_users(&passwd) {
+        run_in(root, &["getent", "passwd", &user])?;
+    }
+    Ok(())
+}
+
+/// fstab turned into mount units the way systemd does at boot, and those units
+/// checked with systemd-analyze verify
+fn check_mount_units(root: &str) -> Option<Result<(), String>> {
+    let generator = FSTAB_GENERATORS.iter().find(|path| Path::new(&format!("{}{}", root, path)).exists())?;
+    if !Path::new(&format!("{}/usr/bin/systemd-analyze", root)).exists() {
+        return None;
+    }
+    let host_dir = format!("{}{}", root, UNIT_DIR);
+    let result = (|| {
+        std::fs::create_dir_all(&host_dir).map_err(|err| format!("{}: {}", UNIT_DIR, err))?;
+        run_in(root, &[generator, UNIT_DIR, UNIT_DIR, UNIT_DIR])?;
+        let mut units: Vec<String> = std::fs::read_dir(&host_dir)
+            .map_err(|err| format!("{}: {}", UNIT_DIR, err))?
+            .flatten()
+            .map(|entry| entry.file_name().to_string_lossy().to_string())
+            .filter(|name| name.ends_with(".mount") || name.ends_with(".swap"))
+            .map(|name| format!("{}/{}", UNIT_DIR, name))
+            .collect();
+        if units.is_empty() {
+            return Err("the fstab generator made no mount units".to_string());
+        }
+        units.sort();
+        let mut args = vec!["systemd-analyze", "verify"];
+        args.extend(units.iter().map(String::as_str));
+        run_in(root, &args)
+    })();
+    let _ = std::fs::remove_dir_all(&host_dir);
+    Some(result)
+}
+
+/// Run the checks in the target root mounted at `root`, with the new /var
+/// mounted at `var` bound into it
+pub fn run(root: &str, var: Option<&str>) -> Result<()> {
+    if !crate::privilege::is_root() {
+        return findings::report("smoke-test", "the smoke test runs the target's programs in a chroot, which needs root; skipped");
+    }
+    if !initramfs::runs_here(root) {
+        return findings::report(
+            "smoke-test",
+            "the target's programs don't run on this machine (an arm64 target on x86 needs qemu-user-static with binfmt); skipped",
+        );
+    }
+    let mounted = initramfs::prepare_chroot(root, None, var)?;
+    let checks: Vec<(&str, Option<Result<(), String>>)> = vec![
+        ("package database", check_packages(root)),
+        ("user lookups", Some(check_users(root))),
+        ("fstab mount units", check_mount_units(root)),
+    ];
+    initramfs::release_chroot(&mounted);
+
+    for (name, outcome) in checks {
+        match outcome {
+            None => println!("  {}: not checked (the target doesn't have the tools)", name),
+            Some(Ok(())) => println!("  {}: ok", name),
+            Some(Err(problem)) => findings::report("smoke-test", &format!("{}: {}", name, problem))?,
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn users_with_homes_under_home_are_looked_up() {
+        let passwd = "root:x:0:0:root:/root:/bin/bash\npi:x:1000:1000:,,,:/home/pi:/bin/bash\nbob:x:1001:1001::/home/bob:/bin/sh\nnobody:x:65534:65534:nobody:/nonexistent:/usr/sbin/nologin\n";
+        assert_eq!(home_users(passwd), vec!["pi", "bob"]);
+    }
+}
@@ -0,0 +1,430 @@
+use anyhow::{anyhow, bail, Context, Result};
+use std::path::Path;
+use std::process::Command;
+
+use crate::{command_exists, shrink_root_filesystem};
+
+/// Name used when opening the LUKS container of the target root
+const LUKS_MAPPER_NAME: &str = "crpart_root";
+
+/// Headroom kept free between every layer of the stack
+const STACK_SAFETY_MARGIN: u64 = 4 * 1024 * 1024;
+
+#[derive(Debug, Clone)]
+pub struct LuksLayer {
+    pub mapper: String,
+    pub header_bytes: u64,
+}
+
+#[derive(Debug, Clone)]
+pub struct LvmLayer {
+    pub vg: String,
+    pub pv: String,
+    pub lv_path: String,
+    pub extent_bytes: u64,
+    pub pe_start_bytes: u64,
+    /// Extents allocated to every LV in the VG except the root LV
+    pub other_extents: u64,
+}
+
+/// Block device stack between the root partition and the root filesystem
+#[derive(Debug, Clone)]
+pub struct RootStack {
+    pub partition: String,
+    pub luks: Option<LuksLayer>,
+    pub lvm: Option<LvmLayer>,
+    pub fs_device: String,
+}
+
+/// Sizes of each layer once the stack fits a partition of a given size
+#[derive(Debug)]
+pub struct StackSizes {
+    pub partition_bytes: u64,
+    pub luks_bytes: u64,
+    pub pv_bytes: u64,
+    pub lv_bytes: u64,
+    pub fs_bytes: u64,
+}
+
+impl RootStack {
+    pub fn is_plain(&self) -> bool {
+        self.luks.is_none() && self.lvm.is_none()
+    }
+
+    /// Type of what the root partition itself holds, as blkid names it: the
+    /// outermost layer of the stack
+    pub fn partition_fstype(&self) -> &'static str {
+        match (&self.luks, &self.lvm) {
+            (Some(_), _) => "crypto_LUKS",
+            (None, Some(_)) => "LVM2_member",
+            // detect_root_stack takes nothing else
+            (None, None) => "ext4",
+        }
+    }
+}
+
+fn blkid_type(device: &str) -> Result<String> {
+    // Unreadable as a normal user; the fallback below covers that
+    let probed = crate::blkid::values(device).ok().and_then(|values| crate::blkid::value(&values, "TYPE"));
+
+    Ok(probed
+        .or_else(|| crate::privilege::fallback(device, "filesystem type", || crate::sysfs::fstype(device)))
+        .unwrap_or_default())
+}
+
+fn query(cmd: &str, args: &[&str]) -> Result<String> {
+    let output = Command::new(cmd)
+        .args(args)
+        .output()
+        .context(format!("Failed to run {}", cmd))?;
+
+    if !output.status.success() {
+        bail!("{} failed: {}", cmd, String::from_utf8_lossy(&output.stderr).trim());
+    }
+
+    Ok(String::from_utf8_lossy(&output.stdout).trim().to_string())
+}
+
+fn run(cmd: &str, args: &[&str]) -> Result<()> {
+    let status = Command::new(cmd)
+        .args(args)
+        .status()
+        .context(format!("Failed to run {}", cmd))?;
+
+    if !status.success() {
+        bail!("{} {} failed", cmd, args.join(" "));
+    }
+
+    Ok(())
+}
+
+/// Walk partition -> LUKS -> LVM -> filesystem, opening each layer as needed
+pub fn detect_root_stack(partition: &str) -> Result<RootStack> {
+    let mut device = partition.to_string();
+    let mut luks = None;
+    let mut lvm = None;
+
+    if blkid_type(&device)? == "crypto_LUKS" {
+        if !command_exists("cryptsetup") {
+            bail!("{} is a LUKS container but cryptsetup is not installed", partition);
+        }
+
+        let mapper = format!("/dev/mapper/{}", LUKS_MAPPER_NAME);
+        if !Path::new(&mapper).exists() {
+            crate::privilege::require("Opening a LUKS container")?;
+            println!("  Opening LUKS container {} (passphrase required)...", partition);
+            run("cryptsetup", &["open", partition, LUKS_MAPPER_NAME])?;
+        }
+
+        let header_bytes = luks_header_bytes(partition)?;
+        luks = Some(LuksLayer { mapper: mapper.clone(), header_bytes });
+        device = mapper;
+    }
+
+    if blkid_type(&device)? == "LVM2_member" {
+        if !command_exists("lvm") {
+            bail!("{} is an LVM physical volume but lvm2 is not installed", device);
+        }
+
+        crate::privilege::require("Inspecting LVM volumes")?;
+        let layer = detect_lvm(&device)?;
+        device = layer.lv_path.clone();
+        lvm = Some(layer);
+    }
+
+    let fs_type = blkid_type(&device)?;
+    if fs_type != "ext4" {
+        bail!("Root filesystem on {} is '{}', only ext4 can be shrunk", device, fs_type);
+    }
+
+    Ok(RootStack {
+        partition: partition.to_string(),
+        luks,
+        lvm,
+        fs_device: device,
+    })
+}
+
+fn luks_header_bytes(partition: &str) -> Result<u64> {
+    let dump = query("cryptsetup", &["luksDump", partition])?;
+
+    // LUKS2: "offset: 16777216 [bytes]" in the data segment section
+    let luks2 = regex::Regex::new(r"(?m)^\s*offset:\s*(\d+)\s*\[bytes\]")?;
+    if let Some(caps) = luks2.captures(&dump) {
+        return caps[1].parse::<u64>().context("Failed to parse LUKS2 data offset");
+    }
+
+    // LUKS1: "Payload offset: 4096" in sectors
+    let luks1 = regex::Regex::new(r"(?m)^Payload offset:\s*(\d+)")?;
+    if let Some(caps) = luks1.captures(&dump) {
+        let sectors = caps[1].parse::<u64>().context("Failed to parse LUKS1 payload offset")?;
+        return Ok(sectors * 512);
+    }
+
+    bail!("Could not determine LUKS data offset for {}", partition)
+}
+
+fn detect_lvm(pv: &str) -> Result<LvmLayer> {
+    let vg = query("pvs", &["--noheadings", "-o", "vg_name", pv])?;
+    if vg.is_empty() {
+        bail!("Physical volume {} does not belong to a volume group", pv);
+    }
+
+    println!("  Activating volume group {}...", vg);
+    run("vgchange", &["-ay", &vg])?;
+
+    let extent_bytes = query("vgs", &["--noheadings", "--units", "b", "--nosuffix", "-o", "vg_extent_size", &vg])?
+        .parse::<u64>()
+        .context("Failed to parse extent size")?;
+    let pe_start_bytes = query("pvs", &["--noheadings", "--units", "b", "--nosuffix", "-o", "pe_start", pv])?
+        .parse::<u64>()
+        .context("Failed to parse PV data start")?;
+
+    let lvs = query("lvs", &["--noheadings", "--separator", ":", "-o", "lv_path,lv_name,seg_size_pe", &vg])?;
+
+    let mut volumes: Vec<(String, String, u64)> = Vec::new();
+    for line in lvs.lines() {
+        let fields: Vec<&str> = line.trim().split(':').collect();
+        if fields.len() < 3 {
+            continue;
+        }
+        let extents = fields[2].trim().parse::<u64>().unwrap_or(0);
+        match volumes.iter_mut().find(|(path, _, _)| path == fields[0]) {
+            Some(existing) => existing.2 += extents,
+            None => volumes.push((fields[0].to_string(), fields[1].to_string(), extents)),
+        }
+    }
+
+    // Prefer an LV literally named root, otherwise the first ext4 LV
+    let root = volumes
+        .iter()
+        .find(|(_, name, _)| name == "root" || name.starts_with("root"))
+        .or_else(|| volumes.iter().find(|(path, _, _)| blkid_type(path).map(|t| t == "ext4").unwrap_or(false)))
+        .ok_or_else(|| anyhow!("Could not find a root logical volume in {}", vg))?
+        .0
+        .clone();
+
+    let other_extents = volumes
+        .iter()
+        .filter(|(path, _, _)| *path != root)
+        .map(|(_, _, extents)| extents)
+        .sum();
+
+    Ok(LvmLayer {
+        vg,
+        pv: pv.to_string(),
+        lv_path: root,
+        extent_bytes,
+        pe_start_bytes,
+        other_extents,
+    })
+}
+
+/// Work out the size of every layer for a root partition of `partition_bytes`
+pub fn plan_stack_sizes(stack: &RootStack, partition_bytes: u64) -> Result<StackSizes> {
+    let luks_bytes = match stack.luks {
+        Some(ref luks) => partition_bytes
+            .checked_sub(luks.header_bytes + STACK_SAFETY_MARGIN)
+            .ok_or_else(|| anyhow!("Root partition too small for the LUKS header"))?,
+        None => partition_bytes,
+    };
+
+    let (pv_bytes, lv_bytes) = match stack.lvm {
+        Some(ref lvm) => {
+            let usable = luks_bytes
+                .checked_sub(lvm.pe_start_bytes + STACK_SAFETY_MARGIN)
+                .ok_or_else(|| anyhow!("Root partition too small for the LVM metadata"))?;
+            // Keep one spare extent so pvresize never hits the exact boundary
+            let usable_extents = (usable / lvm.extent_bytes).saturating_sub(1);
+            let root_extents = usable_extents
+                .checked_sub(lvm.other_extents)
+                .filter(|e| *e > 0)
+                .ok_or_else(|| anyhow!("Other logical volumes in {} do not fit the new root size", lvm.vg))?;
+            let pv_bytes = lvm.pe_start_bytes + (usable_extents + 1) * lvm.extent_bytes;
+            (pv_bytes, root_extents * lvm.extent_bytes)
+        }
+        None => (luks_bytes, luks_bytes),
+    };
+
+    Ok(StackSizes {
+        partition_bytes,
+        luks_bytes,
+        pv_bytes,
+        lv_bytes,
+        fs_bytes: lv_bytes,
+    })
+}
+
+pub fn print_stack(stack: &RootStack, sizes: &StackSizes) {
+    println!("Root Stack:");
+    println!("  Partition: {} ({} MB)", stack.partition, sizes.partition_bytes / (1024 * 1024));
+    if let Some(ref luks) = stack.luks {
+        println!("  LUKS: {} ({} MB, header {} MB)", luks.mapper, sizes.luks_bytes / (1024 * 1024), luks.header_bytes / (1024 * 1024));
+    }
+    if let Some(ref lvm) = stack.lvm {
+        println!("  LVM PV: {} in VG {} ({} MB)", lvm.pv, lvm.vg, sizes.pv_bytes / (1024 * 1024));
+        println!("  LVM LV: {} ({} MB)", lvm.lv_path, sizes.lv_bytes / (1024 * 1024));
+    }
+    println!("  Filesystem: {} ({} MB)", stack.fs_device, sizes.fs_bytes / (1024 * 1024));
+}
+
+/// Shrink filesystem, LV, PV and LUKS container, innermost first
+pub fn shrink_root_stack(stack: &RootStack, sizes: &StackSizes) -> Result<()> {
+    shrink_root_filesystem(&stack.fs_device, sizes.fs_bytes)?;
+
+    if let Some(ref lvm) = stack.lvm {
+        println!("  Reducing logical volume {} to {} bytes...", lvm.lv_path, sizes.lv_bytes);
+        run("lvreduce", &["-f", "-L", &format!("{}b", sizes.lv_bytes), &lvm.lv_path])?;
+
+        compact_physical_volume(lvm, sizes.pv_bytes)?;
+
+        println!("  Resizing physical volume {} to {} bytes...", lvm.pv, sizes.pv_bytes);
+        run("pvresize", &["-y", "--setphysicalvolumesize", &format!("{}b", sizes.pv_bytes), &lvm.pv])?;
+    }
+
+    if let Some(ref luks) = stack.luks {
+        println!("  Resizing LUKS container {} to {} bytes...", luks.mapper, sizes.luks_bytes);
+        run("cryptsetup", &["resize", "--size", &(sizes.luks_bytes / 512).to_string(), LUKS_MAPPER_NAME])?;
+    }
+
+    println!("  Root stack shrunk successfully");
+    Ok(())
+}
+
+/// Move any extents allocated past the new PV end into free space below it
+fn compact_physical_volume(lvm: &LvmLayer, pv_bytes: u64) -> Result<()> {
+    let limit = pv_bytes
+        .checked_sub(lvm.pe_start_bytes)
+        .map(|bytes| bytes / lvm.extent_bytes)
+        .filter(|&limit| limit > 0)
+        .ok_or_else(|| anyhow!("A {} byte physical volume {} holds no extents past its metadata", pv_bytes, lvm.pv))?;
+    let segments = query("pvs", &["--segments", "--noheadings", "--separator", ":", "-o", "pvseg_start,pvseg_size,lv_name", &lvm.pv])?;
+
+    for line in segments.lines() {
+        let fields: Vec<&str> = line.trim().split(':').collect();
+        if fields.len() < 3 || fields[2].trim().is_empty() {
+            continue;
+        }
+        let start = fields[0].trim().parse::<u64>().context("Failed to parse segment start")?;
+        let size = fields[1].trim().parse::<u64>().context("Failed to parse segment size")?;
+        if start + size <= limit {
+            continue;
+        }
+
+        println!("  Moving extents {}-{} of {} below extent {}...", start, start + size - 1, fields[2].trim(), limit);
+        run(
+            "pvmove",
+            &[
+                "--alloc",
+                "anywhere",
+                &format!("{}:{}-{}", lvm.pv, start, start + size - 1),
+                &format!("{}:0-{}", lvm.pv, limit - 1),
+            ],
+        )?;
+    }
+
+    Ok(())
+}
+
+/// Deactivate the VG and close the LUKS mapping opened by detect_root_stack
+pub fn close_root_stack(stack: &RootStack) -> Result<()> {
+    if let Some(ref lvm) = stack.lvm {
+        println!("  Deactivating volume group {}...", lvm.vg);
+        run("vgchange", &["-an", &lvm.vg])?;
+    }
+
+    if stack.luks.is_some() {
+        println!("  Closing LUKS container...");
+        run("cryptsetup", &["close", LUKS_MAPPER_NAME])?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MIB: u64 = 1024 * 1024;
+
+    fn stack(luks: bool, lvm: Option<(u64, u64)>) -> RootStack {
+        RootStack {
+            partition: "/dev/sda2".to_string(),
+            luks: luks.then(|| LuksLayer { mapper: format!("/dev/mapper/{}", LUKS_MAPPER_NAME), header_bytes: 16 * MIB }),
+            lvm: lvm.map(|(extent_bytes, other_extents)| LvmLayer {
+                vg: "vg0".to_string(),
+                pv: "/dev/mapper/crpart_root".to_string(),
+                lv_path: "/dev/vg0/root".to_string(),
+                extent_bytes,
+                pe_start_bytes: MIB,
+                other_extents,
+            }),
+            fs_device: "/dev/vg0/root".to_string(),
+        }
+    }
+
+    #[test]
+    fn plain_and_luks_roots_lose_only_the_header() {
+        let sizes = plan_stack_sizes(&stack(false, None), 8192 * MIB).unwrap();
+        assert_eq!((sizes.luks_bytes, sizes.pv_bytes, sizes.lv_bytes, sizes.fs_bytes), (8192 * MIB, 8192 * MIB, 8192 * MIB, 8192 * MIB));
+
+        let sizes = plan_stack_sizes(&stack(true, None), 8192 * MIB).unwrap();
+        assert_eq!(sizes.luks_bytes, 8192 * MIB - 16 * MIB - STACK_SAFETY_MARGIN);
+        assert_eq!(sizes.fs_bytes, sizes.luks_bytes);
+    }
+
+    #[test]
+    fn lvm_keeps_a_spare_extent_and_the_other_volumes() {
+        // LVM straight on the partition: 4 MiB extents, a 512 MiB swap LV besides root
+        let sizes = plan_stack_sizes(&stack(false, Some((4 * MIB, 128))), 8192 * MIB).unwrap();
+        let usable_extents = (8192 * MIB - MIB - STACK_SAFETY_MARGIN) / (4 * MIB) - 1;
+        assert_eq!(sizes.luks_bytes, 8192 * MIB);
+        assert_eq!(sizes.lv_bytes, (usable_extents - 128) * 4 * MIB);
+        assert_eq!(sizes.pv_bytes, MIB + (usable_extents + 1) * 4 * MIB);
+        assert_eq!(sizes.fs_bytes, sizes.lv_bytes);
+    }
+
+    #[test]
+    fn physical_volumes_fit_inside_luks_with_any_extent_size() {
+        for extent in [4 * MIB, 32 * MIB, 128 * MIB] {
+            for partition in [1000 * MIB, 8192 * MIB + 3 * MIB, 65536 * MIB - 512] {
+                let sizes = plan_stack_sizes(&stack(true, Some((extent, 2))), partition).unwrap();
+                assert!(sizes.pv_bytes <= sizes.luks_bytes - STACK_SAFETY_MARGIN, "extent {} partition {}", extent, partition);
+                assert_eq!((sizes.pv_bytes - MIB) % extent, 0);
+                assert!(sizes.lv_bytes + 2 * extent < sizes.pv_bytes - MIB);
+                assert_eq!(sizes.lv_bytes % extent, 0);
+            }
+        }
+    }
+
+    #[test]
+    fn stacks_that_dont_fit_are_refused() {
+        let err = plan_stack_sizes(&stack(true, None), 16 * MIB).err().unwrap().to_string();
+        assert!(err.contains("too small for the LUKS header"), "{}", err);
+        let err = plan_stack_sizes(&stack(false, Some((4 * MIB, 0))), 4 * MIB).err().unwrap().to_string();
+        assert!(err.contains("too small for the LVM metadata"), "{}", err);
+        // 2 GiB of other LVs in a 1 GiB root
+        let err = plan_stack_sizes(&stack(true, Some((4 * MIB, 512))), 1024 * MIB).err().unwrap().to_string();
+        assert!(err.contains("Other logical volumes in vg0 do not fit"), "{}", err);
+        // Exactly enough for the other LVs leaves root no extent
+        let usable_extents = (1024 * MIB - MIB - STACK_SAFETY_MARGIN) / (4 * MIB) - 1;
+        assert!(plan_stack_sizes(&stack(false, Some((4 * MIB, usable_extents))), 1024 * MIB).is_err());
+        assert!(plan_stack_sizes(&stack(false, Some((4 * MIB, usable_extents - 1))), 1024 * MIB).is_ok());
+    }
+
+    #[test]
+    fn compacting_refuses_a_volume_without_extents() {
+        let lvm = stack(false, Some((4 * MIB, 0))).lvm.unwrap();
+        for pv_bytes in [0, MIB - 1, MIB, MIB + 4 * MIB - 1] {
+            let err = compact_physical_volume(&lvm, pv_bytes).err().unwrap().to_string();
+            assert!(err.contains("holds no extents"), "{}", err);
+        }
+    }
+
+    #[test]
+    fn partition_type_is_the_outermost_layer() {
+        assert_eq!(stack(false, None).partition_fstype(), "ext4");
+        assert_eq!(stack(true, Some((4 * MIB, 0))).partition_fstype(), "crypto_LUKS");
+        assert_eq!(stack(false, Some((4 * MIB, 0))).partition_fstype(), "LVM2_member");
+    }
+}
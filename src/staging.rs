@@ -0,0 +1,70 @@
+//! Shrinking ext4 in several smaller passes (`--shrink-strategy staged`).
+//! resize2fs moves every block above the new end in one go; on a large,
+//! fragmented filesystem that is one long pass with nothing to show for it if
+//! it fails. Staged passes each move a share of the blocks, with e2fsck in
+//! between, so a failure leaves a consistent filesystem that already shrank
+//! part of the way.
+
+use anyhow::{bail, Result};
+use clap::ValueEnum;
+use std::sync::OnceLock;
+
+/// A staged shrink makes one pass per this much it takes off
+const STAGE_BYTES: u64 = 8 * 1024 * 1024 * 1024;
+
+/// Passes a staged shrink makes at most, whatever the reduction
+const MAX_PASSES: u64 = 4;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum ShrinkStrategy {
+    /// One resize2fs run straight to the new size
+    Single,
+    /// One resize2fs run per 8G taken off, at most 4, with e2fsck between them
+    Staged,
+}
+
+static STRATEGY: OnceLock<ShrinkStrategy> = OnceLock::new();
+
+/// Choose how ext4 shrinks for the rest of the run; without a call, it shrinks in one pass
+pub fn configure(strategy: ShrinkStrategy) -> Result<()> {
+    if STRATEGY.set(strategy).is_err() {
+        bail!("The shrink strategy is already chosen");
+    }
+    Ok(())
+}
+
+pub fn strategy() -> ShrinkStrategy {
+    *STRATEGY.get_or_init(|| ShrinkStrategy::Single)
+}
+
+/// Sizes each pass shrinks a `current`-byte filesystem to on its way to
+/// `target`: equal steps in whole 4K blocks, the last one `target` itself
+pub fn stages(current: u64, target: u64) -> Vec<u64> {
+    if current <= target {
+        return vec![target];
+    }
+    let reduction = current - target;
+    let passes = reduction.div_ceil(STAGE_BYTES).clamp(1, MAX_PASSES);
+    (1..=passes).map(|pass| if pass == passes { target } else { (current - reduction * pass / passes) / 4096 * 4096 }).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const GIB: u64 = 1024 * 1024 * 1024;
+
+    #[test]
+    fn staged_passes_split_the_reduction_evenly() {
+        // 21G off a 29G root: three passes of 7G
+        assert_eq!(stages(29 * GIB, 8 * GIB), vec![22 * GIB, 15 * GIB, 8 * GIB]);
+        // Small shrinks stay one pass, large ones stop at four
+        assert_eq!(stages(12 * GIB, 8 * GIB), vec![8 * GIB]);
+        assert_eq!(stages(116 * GIB, 16 * GIB), vec![91 * GIB, 66 * GIB, 41 * GIB, 16 * GIB]);
+        // Odd sizes land on whole blocks, and the target exactly
+        let passes = stages(20 * GIB + 12345, 10 * GIB + 4096);
+        assert!(passes.iter().all(|size| size % 4096 == 0));
+        assert_eq!(passes.last(), Some(&(10 * GIB + 4096)));
+        assert_eq!(stages(8 * GIB, 8 * GIB), vec![8 * GIB]);
+    }
+}
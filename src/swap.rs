@@ -0,0 +1,523 @@
+use anyhow::{bail, Context, Result};
+use clap::ValueEnum;
+use std::os::unix::fs::PermissionsExt;
+use std::path::Path;
+use std::process::Command;
+
+use crate::firmware::BootLayout;
+use crate::fstab::fstab_line;
+use crate::{cmdline, mount_at, unmount_quiet, MountPaths};
+
+/// Swap file on the target's root, as the target sees it
+pub const SWAP_FILE: &str = "/swapfile";
+
+const ZRAM_SCRIPT: &str = "usr/local/sbin/rpi-fs-shrink-zram";
+const ZRAM_UNIT_NAME: &str = "rpi-fs-shrink-zram.service";
+
+/// The kind of swap a run gives the target (--swap-mode)
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SwapMode {
+    /// No swap is created
+    None,
+    /// A swap partition of --swap-size
+    Partition,
+    /// A /swapfile of --swap-size on root
+    File,
+    /// Compressed swap in RAM; --swap-size, or half the RAM when not given
+    Zram,
+    /// Only enable zswap on the kernel command line, in front of the target's existing swap
+    Zswap,
+}
+
+/// The swap a run sets up on the target
+#[derive(Debug, Clone)]
+pub struct SwapPlan {
+    pub mode: SwapMode,
+    pub size: Option<u64>,
+    /// fstab sources of the old swap entries to comment out
+    pub disabled: Vec<String>,
+    /// Resume from the swap partition after hibernation
+    pub hibernate: bool,
+}
+
+impl SwapPlan {
+    /// Without --swap-mode, a --swap-size means a partition, as it always has
+    pub fn new(mode: Option<SwapMode>, size: Option<u64>) -> Result<Self> {
+        let mode = mode.unwrap_or(if size.is_some() { SwapMode::Partition } else { SwapMode::None });
+        match (mode, size) {
+            (SwapMode::Partition | SwapMode::File, None) => {
+                bail!("--swap-mode {} needs --swap-size", mode.name())
+            }
+            (SwapMode::None | SwapMode::Zswap, Some(_)) => {
+                bail!("--swap-mode {} creates no swap space; drop --swap-size", mode.name())
+            }
+            _ => Ok(SwapPlan { mode, size, disabled: Vec::new(), hibernate: false }),
+        }
+    }
+
+    /// Refuse hibernation unless the plan has a swap partition that holds all of `ram`
+    pub fn check_hibernate(&self, ram: u64) -> Result<()> {
+        let size = match (self.mode, self.size) {
+            (SwapMode::Partition, Some(size)) => size,
+            _ => bail!("--hibernate needs a swap partition to hibernate to (e.g. --swap-size auto), not {}", self.describe()),
+        };
+        if size < ram {
+            bail!(
+                "A {} swap partition can't hold the {} of RAM a hibernation image may take;\n\
+                use --swap-size auto or at least {}",
+                crate::timing::format_bytes(size),
+                crate::timing::format_bytes(ram),
+                crate::timing::format_bytes(ram)
+            );
+        }
+        Ok(())
+    }
+
+    /// Size of the swap partition to lay out, if any
+    pub fn partition_size(&self) -> Option<u64> {
+        self.size.filter(|_| self.mode == SwapMode::Partition)
+    }
+
+    /// Whether the swap lives on the target disk and wears it
+    pub fn writes_to_disk(&self) -> bool {
+        matches!(self.mode, SwapMode::Partition | SwapMode::File)
+    }
+
+    /// fstab entry beyond the partitions' own
+    pub fn fstab_entry(&self) -> Option<String> {
+        (self.mode == SwapMode::File).then(|| fstab_line(SWAP_FILE, "none", "swap", "sw", 0))
+    }
+
+    pub fn describe(&self) -> String {
+        let size = self.size.map(crate::timing::format_bytes);
+        match self.mode {
+            SwapMode::None => "none".to_string(),
+            SwapMode::Partition => format!("{} partition", size.unwrap_or_default()),
+            SwapMode::File => format!("{} file at {}", size.unwrap_or_default(), SWAP_FILE),
+            SwapMode::Zram => format!("zram, {}", size.unwrap_or_else(|| "half the RAM".to_string())),
+            SwapMode::Zswap => "zswap on the existing swap".to_string(),
+        }
+    }
+}
+
+impl SwapMode {
+    pub fn name(self) -> String {
+        self.to_possible_value().map(|value| value.get_name().to_string()).unwrap_or_default()
+    }
+}
+
+const GIB: u64 = 1024 * 1024 * 1024;
+
+/// RAM of the target and where that came from: the --target-ram hint, or this
+/// machine when it is a Raspberry Pi (running on the device, or from a LiveUSB on
+/// it) and no other target model was named
+pub fn target_ram(hint: Option<u64>, other_model: bool) -> Result<(u64, &'static str)> {
+    if let Some(bytes) = hint {
+        return Ok((bytes, "--target-ram"));
+    }
+    if other_model {
+        bail!("--target-model names the target, but not its RAM; give that with --target-ram (e.g. 4G)");
+    }
+    let model = std::fs::read_to_string("/proc/device-tree/model").unwrap_or_default();
+    if !model.starts_with("Raspberry Pi") {
+        bail!("The target's RAM isn't known when running on another machine; give it with --target-ram (e.g. 4G)");
+    }
+    let bytes = crate::template::ram_bytes().ok_or_else(|| anyhow::anyhow!("Failed to read MemTotal from /proc/meminfo"))?;
+    Ok((installed_ram(bytes), "this Pi"))
+}
+
+/// Installed RAM from MemTotal, which leaves out the firmware's and the kernel's
+/// share: Pi memory sizes are powers of two, so the next one up
+fn installed_ram(mem_total: u64) -> u64 {
+    let mib = mem_total.div_ceil(1024 * 1024).max(1);
+    mib.next_power_of_two() * 1024 * 1024
+}
+
+/// Swap for `--swap-size auto`: twice small RAM sizes, as much as mid-sized ones,
+/// 8G beyond that; never less than the RAM when hibernating
+pub fn auto_size(ram: u64, hibernate: bool) -> u64 {
+    let size = if ram <= 2 * GIB {
+        2 * ram
+    } else if ram <= 8 * GIB {
+        ram
+    } else {
+        8 * GIB
+    };
+    if hibernate { size.max(ram) } else { size }
+}
+
+/// Point the target's kernel command line and initramfs at the swap partition to
+/// resume from. The kernel finds PARTUUID= without an initramfs; initramfs-tools
+/// reads its own UUID= setting.
+pub fn configure_resume(root: &str, swap_device: &str, boot_device: &str, mounts: &MountPaths) -> Result<()> {
+    let partuuid = crate::relocate::get_partuuid(swap_device)?;
+    let uuid = crate::get_uuid(swap_device)?;
+    let layout = BootLayout::detect(root);
+
+    let boot = mounts.boot();
+    mount_at(boot_device, &boot)?;
+    let result = (|| -> Result<()> {
+        let cmdline_path = format!("{}/cmdline.txt", boot);
+        let cmdline = std::fs::read_to_string(&cmdline_path).context("Failed to read cmdline.txt")?;
+        std::fs::write(&cmdline_path, cmdline::with_resume(&cmdline, &format!("PARTUUID={}", partuuid)))
+            .context("Failed to write cmdline.txt")?;
+        println!("  {}: resume=PARTUUID={}", layout.path("cmdline.txt"), partuuid);
+        Ok(())
+    })();
+    unmount_quiet(&boot);
+    result?;
+
+    let conf_dir = format!("{}/etc/initramfs-tools/conf.d", root);
+    if Path::new(&format!("{}/etc/initramfs-tools", root)).is_dir() {
+        std::fs::create_dir_all(&conf_dir).context(format!("Failed to create {}", conf_dir))?;
+        std::fs::write(format!("{}/resume", conf_dir), format!("RESUME=UUID={}\n", uuid))
+            .context("Failed to write initramfs-tools resume configuration")?;
+        println!("  /etc/initramfs-tools/conf.d/resume: RESUME=UUID={} (takes effect with the next initramfs update)", uuid);
+    }
+
+    // Kernels built without hibernation ignore resume=; say so where the config shows it
+    let configs: Vec<String> = std::fs::read_dir(format!("{}/boot", root))
+        .map(|entries| {
+            entries
+                .flatten()
+                .filter(|entry| entry.file_name().to_string_lossy().starts_with("config-"))
+                .map(|entry| std::fs::read_to_string(entry.path()).unwrap_or_default())
+                .collect()
+        })
+        .unwrap_or_default();
+    if configs.is_empty() {
+        println!("  Note: no kernel config in /boot to check for hibernation support; Raspberry Pi OS kernels are built without it");
+    } else if !configs.iter().any(|config| config.lines().any(|line| line == "CONFIG_HIBERNATION=y")) {
+        crate::findings::report(
+            "kernel-hibernation",
+            "the target's kernel is built without CONFIG_HIBERNATION; it can't hibernate until that changes",
+        )?;
+    }
+    Ok(())
+}
+
+/// Set up swap that is not a partition: the swap file on root (mounted at
+/// `root`), the zram service, or the zswap kernel parameters
+pub fn apply(plan: &SwapPlan, root: &str, boot_device: &str, mounts: &MountPaths) -> Result<()> {
+    match plan.mode {
+        SwapMode::File => create_swap_file(root, plan.size.unwrap_or(0)),
+        SwapMode::Zram => install_zram(root, plan.size),
+        SwapMode::Zswap => enable_zswap(boot_device, BootLayout::detect(root), mounts),
+        SwapMode::None | SwapMode::Partition => Ok(()),
+    }
+}
+
+fn create_swap_file(root: &str, size: u64) -> Result<()> {
+    let path = format!("{}{}", root, SWAP_FILE);
+    // Allocated, not sparse: the kernel refuses swap files with holes
+    let output = Command::new("fallocate")
+        .args(["--length", &size.to_string(), &path])
+        .output()
+        .context("Failed to run fallocate")?;
+    if !output.status.success() {
+        let _ = std::fs::remove_file(&path);
+        bail!(
+            "Failed to allocate {} for {} (is --root-size large enough?): {}",
+            crate::timing::format_bytes(size),
+            SWAP_FILE,
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+    std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o600))
+        .context(format!("Failed to set permissions on {}", path))?;
+
+    let output = Command::new("mkswap").arg(&path).output().context("Failed to run mkswap")?;
+    if !output.status.success() {
+        bail!("mkswap failed on {}: {}", path, String::from_utf8_lossy(&output.stderr).trim());
+    }
+    println!("  {} created ({})", SWAP_FILE, crate::timing::format_bytes(size));
+    Ok(())
+}
+
+/// A oneshot service that sets up a zram swap device at boot
+fn install_zram(root: &str, size: Option<u64>) -> Result<()> {
+    let size = match size {
+        Some(bytes) => bytes.to_string(),
+        // MemTotal is in KiB; half of it in bytes
+        None => "$(awk '/^MemTotal:/ { print $2 * 512 }' /proc/meminfo)".to_string(),
+    };
+    let script = format!(
+        "#!/bin/sh\n\
+        # Installed by rpi-fs-shrink: compressed swap in RAM\n\
+        set -e\n\
+        modprobe zram\n\
+        size={size}\n\
+        device=$(zramctl --find --size \"$size\" --algorithm zstd 2>/dev/null || zramctl --find --size \"$size\")\n\
+        mkswap \"$device\"\n\
+        swapon --priority 100 \"$device\"\n"
+    );
+    let script_path = format!("{}/{}", root, ZRAM_SCRIPT);
+    if let Some(dir) = Path::new(&script_path).parent() {
+        std::fs::create_dir_all(dir).context(format!("Failed to create {}", dir.display()))?;
+    }
+    std::fs::write(&script_path, script).context(format!("Failed to write {}", script_path))?;
+    std::fs::set_permissions(&script_path, std::fs::Permissions::from_mode(0o755))
+        .context(format!("Failed to set permissions on {}", script_path))?;
+
+    let unit = format!(
+        "[Unit]\n\
+        Description=Compressed swap in RAM (rpi-fs-shrink)\n\
+        \n\
+        [Service]\n\
+        Type=oneshot\n\
+        RemainAfterExit=yes\n\
+        ExecStart=/{}\n\
+        \n\
+        [Install]\n\
+        WantedBy=multi-user.target\n",
+        ZRAM_SCRIPT
+    );
+    let unit_dir = format!("{}/etc/systemd/system", root);
+    let wants_dir = format!("{}/multi-user.target.wants", unit_dir);
+    std::fs::create_dir_all(&wants_dir).context("Failed to create systemd unit directory")?;
+    std::fs::write(format!("{}/{}", unit_dir, ZRAM_UNIT_NAME), unit).context("Failed to write zram unit")?;
+
+    let link = format!("{}/{}", wants_dir, ZRAM_UNIT_NAME);
+    if std::fs::symlink_metadata(&link).is_err() {
+        std::os::unix::fs::symlink(format!("/etc/systemd/system/{}", ZRAM_UNIT_NAME), &link)
+            .context("Failed to enable zram unit")?;
+    }
+    println!("  zram swap service {} installed", ZRAM_UNIT_NAME);
+    Ok(())
+}
+
+/// Put the zswap settings on the kernel command line in the target's cmdline.txt
+fn enable_zswap(boot_device: &str, layout: BootLayout, mounts: &MountPaths) -> Result<()> {
+    let boot = mounts.boot();
+    mount_at(boot_device, &boot)?;
+    let result = (|| -> Result<()> {
+        let cmdline_path = format!("{}/cmdline.txt", boot);
+        let cmdline = std::fs::read_to_string(&cmdline_path).context("Failed to read cmdline.txt")?;
+        std::fs::write(&cmdline_path, cmdline::with_zswap(&cmdline)).context("Failed to write cmdline.txt")?;
+        println!("  zswap enabled in {}: {}", layout.path("cmdline.txt"), cmdline::ZSWAP_PARAMS.join(" "));
+        Ok(())
+    })();
+    unmount_quiet(&boot);
+    result
+}
+
+/// dphys-swapfile, the swap file service of Raspberry Pi OS
+const DPHYS_CONFIG: &str = "etc/dphys-swapfile";
+const DPHYS_DEFAULT_FILE: &str = "/var/swap";
+const DPHYS_UNIT: &str = "dphys-swapfile.service";
+
+/// Swap the target already has configured
+#[derive(Debug, Clone)]
+pub enum ExistingSwap {
+    /// dphys-swapfile and the file it manages, sized as it is now
+    Dphys { file: String, bytes: u64 },
+    /// A swap file listed in fstab
+    File { path: String, bytes: u64 },
+    /// A swap partition listed in fstab; `number` when it is on the target disk
+    Partition { source: String, number: Option<u32> },
+}
+
+impl ExistingSwap {
+    /// Whether a run with `plan` removes this swap. Partitions on the target disk
+    /// always go, the new layout takes their space; the rest only when replaced.
+    pub fn is_removed(&self, plan: &SwapPlan) -> bool {
+        match self {
+            ExistingSwap::Partition { number: Some(_), .. } => true,
+            _ => matches!(plan.mode, SwapMode::Partition | SwapMode::File | SwapMode::Zram),
+        }
+    }
+
+    /// fstab source of the entry that goes with this swap
+    pub fn fstab_source(&self) -> Option<&str> {
+        match self {
+            ExistingSwap::Dphys { .. } => None,
+            ExistingSwap::File { path, .. } => Some(path),
+            ExistingSwap::Partition { source, .. } => Some(source),
+        }
+    }
+
+    pub fn describe(&self) -> String {
+        match self {
+            ExistingSwap::Dphys { file, bytes } => format!("dphys-swapfile: {} ({})", file, crate::timing::format_bytes(*bytes)),
+            ExistingSwap::File { path, bytes } => format!("swap file {} ({})", path, crate::timing::format_bytes(*bytes)),
+            ExistingSwap::Partition { source, number: Some(n) } => format!("swap partition {} (partition {} of this disk)", source, n),
+            ExistingSwap::Partition { source, number: None } => format!("swap partition {} (not on this disk)", source),
+        }
+    }
+}
+
+/// Find the swap configured on the target root mounted at `root`; `disk` is the
+/// target disk, to tell its own swap partitions from others
+pub fn detect_existing(root: &str, disk: &str) -> Result<Vec<ExistingSwap>> {
+    let mut found = Vec::new();
+
+    if dphys_enabled(root) {
+        let config = std::fs::read_to_string(format!("{}/{}", root, DPHYS_CONFIG)).unwrap_or_default();
+        let file = config
+            .lines()
+            .filter_map(|line| line.trim().strip_prefix("CONF_SWAPFILE="))
+            .next_back()
+            .map(|value| value.trim_matches(['"', '\'']).to_string())
+            .unwrap_or_else(|| DPHYS_DEFAULT_FILE.to_string());
+        // The service creates the file on first boot, so it may not exist yet
+        let bytes = std::fs::metadata(format!("{}{}", root, file)).map(|m| m.len()).unwrap_or(0);
+        found.push(ExistingSwap::Dphys { file, bytes });
+    }
+
+    let fstab_path = format!("{}/etc/fstab", root);
+    let content = std::fs::read_to_string(&fstab_path).context(format!("Failed to read {}", fstab_path))?;
+    for entry in crate::fstab::parse_fstab(&content)? {
+        if entry.fstype != "swap" {
+            continue;
+        }
+        if entry.source.starts_with('/') && !entry.source.starts_with("/dev/") {
+            let bytes = std::fs::metadata(format!("{}{}", root, entry.source)).map(|m| m.len()).unwrap_or(0);
+            found.push(ExistingSwap::File { path: entry.source, bytes });
+        } else {
+            let number = crate::fstab::resolve_source(&entry.source).and_then(|device| partition_on_disk(disk, &device));
+            found.push(ExistingSwap::Partition { source: entry.source, number });
+        }
+    }
+    Ok(found)
+}
+
+/// Enabled through systemd or SysV links, and not masked
+fn dphys_enabled(root: &str) -> bool {
+    let unit = format!("{}/etc/systemd/system/{}", root, DPHYS_UNIT);
+    if std::fs::read_link(&unit).is_ok_and(|target| target == Path::new("/dev/null")) {
+        return false;
+    }
+    if Path::new(&format!("{}/etc/systemd/system/multi-user.target.wants/{}", root, DPHYS_UNIT)).exists() {
+        return true;
+    }
+    std::fs::read_dir(format!("{}/etc/rc2.d", root))
+        .map(|entries| {
+            entries
+                .flatten()
+                .any(|entry| entry.file_name().to_string_lossy().starts_with('S') && entry.file_name().to_string_lossy().ends_with("dphys-swapfile"))
+        })
+        .unwrap_or(false)
+}
+
+/// Number of `device` among the partitions of `disk`
+fn partition_on_disk(disk: &str, device: &str) -> Option<u32> {
+    let device = std::fs::canonicalize(device).ok()?;
+    (1..=128).find(|&n| std::fs::canonicalize(crate::sysfs::partition_path(disk, n)).is_ok_and(|path| path == device))
+}
+
+pub fn print_existing(existing: &[ExistingSwap], plan: &SwapPlan) -> Result<()> {
+    if existing.is_empty() {
+        return Ok(());
+    }
+    println!("\nExisting swap on the target:");
+    for swap in existing {
+        println!("  {}", swap.describe());
+        let outcome = match swap {
+            ExistingSwap::Partition { number: Some(_), .. } => "removed; the new layout reuses its space".to_string(),
+            ExistingSwap::Dphys { bytes, .. } | ExistingSwap::File { bytes, .. } if swap.is_removed(plan) => {
+                format!("disabled and deleted, freeing {} on root before it is shrunk", crate::timing::format_bytes(*bytes))
+            }
+            _ if swap.is_removed(plan) => "disabled in fstab".to_string(),
+            _ if plan.mode == SwapMode::Zswap => "kept; zswap sits in front of it".to_string(),
+            _ => "kept".to_string(),
+        };
+        println!("    {}", outcome);
+    }
+    let replaced = existing.iter().any(|swap| swap.is_removed(plan));
+    if plan.mode == SwapMode::Zswap && existing.iter().all(|swap| swap.is_removed(plan)) {
+        crate::findings::report("zswap-without-swap", "no swap is left for zswap to compress into")?;
+    } else if plan.mode == SwapMode::None && replaced {
+        println!("  The target will have no swap; use --swap-mode to create new swap");
+    }
+    Ok(())
+}
+
+/// Remove the swap files and services `plan` replaces from the target root
+/// mounted read-write at `root`; fstab entries are disabled with the fstab update
+pub fn remove_existing_files(existing: &[ExistingSwap], plan: &SwapPlan, root: &str) -> Result<()> {
+    for swap in existing.iter().filter(|swap| swap.is_removed(plan)) {
+        match swap {
+            ExistingSwap::Dphys { file, .. } => {
+                let unit_dir = format!("{}/etc/systemd/system", root);
+                let _ = std::fs::remove_file(format!("{}/multi-user.target.wants/{}", unit_dir, DPHYS_UNIT));
+                let mask = format!("{}/{}", unit_dir, DPHYS_UNIT);
+                let _ = std::fs::remove_file(&mask);
+                std::os::unix::fs::symlink("/dev/null", &mask).context("Failed to mask dphys-swapfile")?;
+                remove_file_if_present(root, file)?;
+                println!("  dphys-swapfile masked, {} deleted", file);
+            }
+            ExistingSwap::File { path, .. } => {
+                remove_file_if_present(root, path)?;
+                println!("  {} deleted", path);
+            }
+            ExistingSwap::Partition { .. } => {}
+        }
+    }
+    Ok(())
+}
+
+fn remove_file_if_present(root: &str, path: &str) -> Result<()> {
+    match std::fs::remove_file(format!("{}{}", root, path)) {
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e).context(format!("Failed to delete {}", path)),
+        _ => Ok(()),
+    }
+}
+
+/// Delete the target disk's own swap partitions; the new layout takes their space
+pub fn remove_partitions(existing: &[ExistingSwap], disk: &str) -> Result<()> {
+    let mut removed = Vec::new();
+    for swap in existing {
+        let ExistingSwap::Partition { number: Some(n), .. } = swap else {
+            continue;
+        };
+        removed.push(crate::sysfs::partition_path(disk, *n));
+        let output = Command::new("parted")
+            .args(["-s", disk, "rm", &n.to_string()])
+            .output()
+            .context("Failed to run parted rm")?;
+        if !output.status.success() {
+            bail!("Failed to remove swap partition {} of {}: {}", n, disk, String::from_utf8_lossy(&output.stderr).trim());
+        }
+        println!("  Swap partition {} removed", n);
+    }
+    crate::reread_partitions(disk);
+    // The later partitions are created after these nodes are gone, not alongside them
+    for node in removed {
+        crate::uevent::wait_for_node(&node, false, crate::uevent::NODE_TIMEOUT)?;
+    }
+    Ok(())
+}
+
+/// fstab sources of the swap entries a run with `plan` disables
+pub fn disabled_sources(existing: &[ExistingSwap], plan: &SwapPlan) -> Vec<String> {
+    existing
+        .iter()
+        .filter(|swap| swap.is_removed(plan))
+        .filter_map(|swap| swap.fstab_source().map(str::to_string))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn swap_plans_need_a_size_exactly_when_they_make_swap_space() {
+        // --swap-size alone still means a partition
+        let plan = SwapPlan::new(None, Some(2 * GIB)).unwrap();
+        assert_eq!((plan.mode, plan.partition_size()), (SwapMode::Partition, Some(2 * GIB)));
+        assert_eq!(SwapPlan::new(None, None).unwrap().mode, SwapMode::None);
+
+        let file = SwapPlan::new(Some(SwapMode::File), Some(GIB)).unwrap();
+        assert_eq!(file.partition_size(), None);
+        assert!(file.writes_to_disk() && file.fstab_entry().is_some());
+        // zram sizes itself from the RAM when not given one
+        assert!(SwapPlan::new(Some(SwapMode::Zram), None).is_ok());
+        assert!(!SwapPlan::new(Some(SwapMode::Zram), Some(GIB)).unwrap().writes_to_disk());
+
+        assert!(SwapPlan::new(Some(SwapMode::Partition), None).is_err());
+        assert!(SwapPlan::new(Some(SwapMode::File), None).is_err());
+        assert!(SwapPlan::new(Some(SwapMode::None), Some(GIB)).is_err());
+        assert!(SwapPlan::new(Some(SwapMode::Zswap), Some(GIB)).is_err());
+    }
+}
@@ -0,0 +1,330 @@
+//! World-readable disk information from sysfs and the udev database (through
+//! lsblk), for read-only paths that run without access to the device nodes.
+//! Sizes and offsets in sysfs are always in 512-byte sectors.
+
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+const SYS_BLOCK: &str = "/sys/class/block";
+
+/// Kernel name of a block device ("/dev/disk/by-id/..." -> "sda")
+fn block_name(device: &str) -> String {
+    let resolved = std::fs::canonicalize(device).unwrap_or_else(|_| device.into());
+    resolved
+        .file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_else(|| device.to_string())
+}
+
+fn read_u64(path: &Path) -> Option<u64> {
+    std::fs::read_to_string(path).ok()?.trim().parse().ok()
+}
+
+pub fn disk_size_bytes(device: &str) -> Option<u64> {
+    read_u64(&Path::new(SYS_BLOCK).join(block_name(device)).join("size")).map(|sectors| sectors * 512)
+}
+
+/// sysfs directory of partition `number` of the kernel block device `disk`
+fn partition_dir(sys_block: &Path, disk: &str, number: u32) -> Option<PathBuf> {
+    std::fs::read_dir(sys_block.join(disk))
+        .ok()?
+        .flatten()
+        .map(|entry| entry.path())
+        .find(|path| read_u64(&path.join("partition")) == Some(number as u64))
+}
+
+/// Start and end sector of partition `number` of `device`
+pub fn partition_bounds(device: &str, number: u32) -> Option<(u64, u64)> {
+    let path = partition_dir(Path::new(SYS_BLOCK), &block_name(device), number)?;
+    let start = read_u64(&path.join("start"))?;
+    let size = read_u64(&path.join("size"))?;
+    Some((start, start + size.max(1) - 1))
+}
+
+/// Disk and number of the partition `device` ("/dev/sda3" -> ("/dev/sda", 3))
+pub fn partition_of(device: &str) -> Option<(String, u32)> {
+    let path = std::fs::canonicalize(Path::new(SYS_BLOCK).join(block_name(device))).ok()?;
+    let number = read_u64(&path.join("partition"))?;
+    let disk = path.parent()?.file_name()?.to_string_lossy().into_owned();
+    Some((format!("/dev/{}", disk), number as u32))
+}
+
+/// Path of partition `number` of `device` by the naming rules alone, for
+/// partitions that don't exist yet: "-partN" for /dev/disk/by-* links, otherwise
+/// the kernel's rule of a "p" separator when the disk name ends in a digit
+/// (mmcblk0p2, nvme0n1p2, loop0p2, nbd0p2, but sda2). kpartx names partitions of
+/// /dev/mapper devices the same way.
+pub fn derive_partition_path(device: &str, number: u32) -> String {
+    if device.starts_with("/dev/disk/by-") {
+        format!("{}-part{}", device, number)
+    } else if device.ends_with(|c: char| c.is_ascii_digit()) {
+        format!("{}p{}", device, number)
+    } else {
+        format!("{}{}", device, number)
+    }
+}
+
+/// Kernel name of partition `number` of `disk` relative to /dev: a child entry
+/// of the disk in sysfs or, for device-mapper disks, a holder whose dm uuid marks
+/// it as that partition (kpartx, "partN-...")
+fn find_partition_name(sys_block: &Path, disk: &str, number: u32) -> Option<String> {
+    if let Some(dir) = partition_dir(sys_block, disk, number) {
+        return dir.file_name().map(|name| name.to_string_lossy().into_owned());
+    }
+
+    let prefix = format!("part{}-", number);
+    std::fs::read_dir(sys_block.join(disk).join("holders"))
+        .ok()?
+        .flatten()
+        .map(|entry| sys_block.join(entry.file_name()).join("dm"))
+        .find(|dm| std::fs::read_to_string(dm.join("uuid")).is_ok_and(|uuid| uuid.starts_with(&prefix)))
+        .and_then(|dm| std::fs::read_to_string(dm.join("name")).ok())
+        .map(|name| format!("mapper/{}", name.trim()))
+}
+
+/// Device path of partition `number` of `device`, as the kernel named it when the
+/// partition exists, else derived from the naming rules
+pub fn partition_path(device: &str, number: u32) -> String {
+    partition_path_in(Path::new(SYS_BLOCK), device, &block_name(device), number)
+}
+
+fn partition_path_in(sys_block: &Path, device: &str, disk: &str, number: u32) -> String {
+    if let Some(name) = find_partition_name(sys_block, disk, number) {
+        return format!("/dev/{}", name);
+    }
+    // kpartx names partitions after the mapper name, also when the disk is given as /dev/dm-N
+    match std::fs::read_to_string(sys_block.join(disk).join("dm/name")) {
+        Ok(name) => derive_partition_path(&format!("/dev/mapper/{}", name.trim()), number),
+        Err(_) => derive_partition_path(device, number),
+    }
+}
+
+/// Kernel names of the partitions of the block device `disk` that sysfs lists
+fn partition_names_in(sys_block: &Path, disk: &str) -> Vec<String> {
+    let Ok(entries) = std::fs::read_dir(sys_block.join(disk)) else {
+        return Vec::new();
+    };
+    entries
+        .flatten()
+        .filter(|entry| entry.path().join("partition").is_file())
+        .map(|entry| entry.file_name().to_string_lossy().into_owned())
+        .collect()
+}
+
+/// Whether the device path `source` (as /proc/mounts names it) is `device` or
+/// one of its partitions: listed in sysfs as such, or named like one by the
+/// kernel (`device` plus digits, or plus "p" and digits). /dev/sdab1 isn't on
+/// /dev/sda, nor /dev/mmcblk0boot0 on /dev/mmcblk0.
+pub fn is_on_device(device: &str, source: &str) -> bool {
+    let partitions = partition_names_in(Path::new(SYS_BLOCK), &block_name(device));
+    is_on_device_with(device, &partitions, source)
+}
+
+fn is_on_device_with(device: &str, partitions: &[String], source: &str) -> bool {
+    if source == device || source.strip_prefix("/dev/").is_some_and(|name| partitions.iter().any(|part| part == name)) {
+        return true;
+    }
+    let Some(rest) = source.strip_prefix(device) else {
+        return false;
+    };
+    let number = rest.strip_prefix('p').unwrap_or(rest);
+    !number.is_empty() && number.chars().all(|c| c.is_ascii_digit())
+}
+
+pub fn is_device_mapper(device: &str) -> bool {
+    Path::new(SYS_BLOCK).join(block_name(device)).join("dm").is_dir()
+}
+
+/// For loop devices, whether the kernel scans their partitions itself (losetup --partscan)
+pub fn loop_partscan(device: &str) -> Option<bool> {
+    let value = std::fs::read_to_string(Path::new(SYS_BLOCK).join(block_name(device)).join("loop/partscan")).ok()?;
+    Some(value.trim() == "1")
+}
+
+/// Whether device-mapper targets (kpartx partitions, LUKS, LVM) sit on `device`
+pub fn has_holders(device: &str) -> bool {
+    std::fs::read_dir(Path::new(SYS_BLOCK).join(block_name(device)).join("holders"))
+        .is_ok_and(|mut entries| entries.next().is_some())
+}
+
+/// Whether `device` accepts discard requests (TRIM, or erase on SD cards)
+pub fn discard_supported(device: &str) -> bool {
+    read_u64(&Path::new(SYS_BLOCK).join(block_name(device)).join("queue/discard_max_bytes")).is_some_and(|bytes| bytes > 0)
+}
+
+/// Erase block size of an SD card or eMMC in bytes, as the card reports it
+/// (mmc preferred_erase_size); None for other disks
+pub fn erase_block_bytes(device: &str) -> Option<u64> {
+    erase_block_in(Path::new(SYS_BLOCK), &block_name(device))
+}
+
+fn erase_block_in(sys_block: &Path, disk: &str) -> Option<u64> {
+    read_u64(&sys_block.join(disk).join("device/preferred_erase_size")).filter(|&bytes| bytes > 0)
+}
+
+pub fn lsblk_value(device: &str, column: &str) -> Option<String> {
+    let output = Command::new("lsblk").args(["-dno", column, device]).output().ok()?;
+    crate::parse::first_value(&String::from_utf8_lossy(&output.stdout))
+}
+
+/// Partition table type, named like parted does ("msdos", "gpt")
+pub fn partition_table_type(device: &str) -> Option<String> {
+    lsblk_value(device, "PTTYPE").map(|kind| if kind == "dos" { "msdos".to_string() } else { kind })
+}
+
+pub fn fstype(device: &str) -> Option<String> {
+    lsblk_value(device, "FSTYPE")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn derived_names_follow_kernel_rules() {
+        for (device, expected) in [
+            ("/dev/mmcblk0", "/dev/mmcblk0p2"),
+            ("/dev/nvme0n1", "/dev/nvme0n1p2"),
+            ("/dev/sda", "/dev/sda2"),
+            ("/dev/sdab", "/dev/sdab2"),
+            ("/dev/loop7", "/dev/loop7p2"),
+            ("/dev/nbd0", "/dev/nbd0p2"),
+            ("/dev/mapper/card", "/dev/mapper/card2"),
+            ("/dev/mapper/vg0-disk1", "/dev/mapper/vg0-disk1p2"),
+            ("/dev/disk/by-id/usb-Generic_SD_Reader-0:0", "/dev/disk/by-id/usb-Generic_SD_Reader-0:0-part2"),
+        ] {
+            assert_eq!(derive_partition_path(device, 2), expected, "{}", device);
+        }
+    }
+
+    /// A throwaway directory laid out like /sys/class/block
+    struct FakeSysfs(PathBuf);
+
+    impl FakeSysfs {
+        fn new(name: &str) -> Self {
+            let root = std::env::temp_dir().join(format!("rpi-fs-shrink-sysfs-{}-{}", name, std::process::id()));
+            let _ = std::fs::remove_dir_all(&root);
+            std::fs::create_dir_all(&root).unwrap();
+            FakeSysfs(root)
+        }
+
+        fn write(&self, path: &str, content: &str) {
+            let path = self.0.join(path);
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(path, content).unwrap();
+        }
+    }
+
+    impl Drop for FakeSysfs {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.0);
+        }
+    }
+
+    #[test]
+    fn partitions_found_as_disk_children() {
+        let sys = FakeSysfs::new("children");
+        for (disk, part) in [("mmcblk0", "mmcblk0p2"), ("nvme0n1", "nvme0n1p2"), ("sda", "sda2"), ("loop3", "loop3p2")] {
+            sys.write(&format!("{}/size", disk), "62333952\n");
+            sys.write(&format!("{}/{}/partition", disk, part), "2\n");
+            sys.write(&format!("{}/{}/start", disk, part), "1056768\n");
+            sys.write(&format!("{}/queue/partition", disk), "");
+            assert_eq!(find_partition_name(&sys.0, disk, 2).as_deref(), Some(part));
+            assert_eq!(find_partition_name(&sys.0, disk, 3), None);
+        }
+    }
+
+    #[test]
+    fn loop_partitions_with_and_without_partscan() {
+        let sys = FakeSysfs::new("loop");
+        // losetup --partscan: the kernel's own loop0p2
+        sys.write("loop0/loop/partscan", "1\n");
+        sys.write("loop0/loop0p2/partition", "2\n");
+        // No partition scanning (common in containers): kpartx maps loop1p2
+        sys.write("loop1/loop/partscan", "0\n");
+        sys.write("loop1/holders/dm-3", "");
+        sys.write("dm-3/dm/name", "loop1p2\n");
+        sys.write("dm-3/dm/uuid", "part2-loop1\n");
+
+        assert_eq!(partition_path_in(&sys.0, "/dev/loop0", "loop0", 2), "/dev/loop0p2");
+        assert_eq!(partition_path_in(&sys.0, "/dev/loop1", "loop1", 2), "/dev/mapper/loop1p2");
+        // Not created yet: the kernel's name
+        assert_eq!(partition_path_in(&sys.0, "/dev/loop1", "loop1", 3), "/dev/loop1p3");
+    }
+
+    #[test]
+    fn naming_matrix_before_partitions_exist() {
+        let sys = FakeSysfs::new("matrix");
+        sys.write("dm-5/dm/name", "usbcard\n");
+        sys.write("dm-6/dm/name", "vg0-disk1\n");
+        for (device, disk, expected) in [
+            ("/dev/mmcblk0", "mmcblk0", "/dev/mmcblk0p3"),
+            ("/dev/nvme1n1", "nvme1n1", "/dev/nvme1n1p3"),
+            ("/dev/sdb", "sdb", "/dev/sdb3"),
+            ("/dev/loop2", "loop2", "/dev/loop2p3"),
+            ("/dev/nbd1", "nbd1", "/dev/nbd1p3"),
+            ("/dev/dm-5", "dm-5", "/dev/mapper/usbcard3"),
+            ("/dev/mapper/usbcard", "dm-5", "/dev/mapper/usbcard3"),
+            ("/dev/dm-6", "dm-6", "/dev/mapper/vg0-disk1p3"),
+        ] {
+            assert_eq!(partition_path_in(&sys.0, device, disk, 3), expected, "{}", device);
+        }
+    }
+
+    #[test]
+    fn mounted_sources_on_a_device_are_it_or_its_partitions() {
+        let sys = FakeSysfs::new("mounted");
+        sys.write("mmcblk0/mmcblk0p1/partition", "1\n");
+        sys.write("mmcblk0/mmcblk0p2/partition", "2\n");
+        sys.write("mmcblk0/queue/rotational", "0\n");
+        let partitions = partition_names_in(&sys.0, "mmcblk0");
+        assert_eq!(partitions.len(), 2);
+
+        for (device, source, on) in [
+            ("/dev/mmcblk0", "/dev/mmcblk0", true),
+            ("/dev/mmcblk0", "/dev/mmcblk0p2", true),
+            ("/dev/mmcblk0", "/dev/mmcblk0boot0", false),
+            ("/dev/mmcblk0", "/dev/mmcblk01", true),
+            ("/dev/sda", "/dev/sda1", true),
+            ("/dev/sda", "/dev/sda12", true),
+            ("/dev/sda", "/dev/sdab1", false),
+            ("/dev/sda", "/dev/sdb1", false),
+            ("/dev/nvme0n1", "/dev/nvme0n1p3", true),
+            ("/dev/nvme0n1", "/dev/nvme0n10", true),
+            ("/dev/sda", "/dev/sdap", false),
+            ("/dev/sda", "tmpfs", false),
+        ] {
+            let partitions = if device == "/dev/mmcblk0" { partitions.as_slice() } else { &[] };
+            assert_eq!(is_on_device_with(device, partitions, source), on, "{} on {}", source, device);
+        }
+        // Partitions named otherwise, found through sysfs
+        assert!(is_on_device_with("/dev/mapper/card", &["dm-1".to_string()], "/dev/dm-1"));
+    }
+
+    #[test]
+    fn erase_block_only_for_mmc() {
+        let sys = FakeSysfs::new("erase");
+        sys.write("mmcblk0/device/preferred_erase_size", "4194304\n");
+        sys.write("mmcblk1/device/preferred_erase_size", "0\n");
+        sys.write("sda/device/model", "Flash Disk\n");
+        assert_eq!(erase_block_in(&sys.0, "mmcblk0"), Some(4 * 1024 * 1024));
+        assert_eq!(erase_block_in(&sys.0, "mmcblk1"), None);
+        assert_eq!(erase_block_in(&sys.0, "sda"), None);
+    }
+
+    #[test]
+    fn device_mapper_partitions_found_as_holders() {
+        let sys = FakeSysfs::new("dm");
+        sys.write("dm-0/dm/name", "card\n");
+        sys.write("dm-0/dm/uuid", "\n");
+        sys.write("dm-0/holders/dm-1", "");
+        sys.write("dm-0/holders/dm-2", "");
+        sys.write("dm-1/dm/name", "card1\n");
+        sys.write("dm-1/dm/uuid", "part1-card\n");
+        sys.write("dm-2/dm/name", "card2\n");
+        sys.write("dm-2/dm/uuid", "part2-card\n");
+        assert_eq!(find_partition_name(&sys.0, "dm-0", 2).as_deref(), Some("mapper/card2"));
+        assert_eq!(find_partition_name(&sys.0, "dm-0", 1).as_deref(), Some("mapper/card1"));
+        assert_eq!(find_partition_name(&sys.0, "dm-0", 3), None);
+    }
+}
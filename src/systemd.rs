@@ -0,0 +1,58 @@
+use anyhow::{Context, Result};
+
+/// First systemd release that understands x-systemd.before= in fstab
+pub const MIN_X_SYSTEMD_BEFORE: u32 = 233;
+
+/// Early-boot services that write under /var and must wait for a separate /var mount
+const VAR_CONSUMERS: &[&str] = &["systemd-journal-flush.service", "systemd-tmpfiles-setup.service"];
+
+/// systemd version of the target image, taken from its libsystemd-shared-NNN.so
+pub fn target_systemd_version(root: &str) -> Option<u32> {
+    for dir in ["usr/lib/systemd", "lib/systemd"] {
+        let Ok(entries) = std::fs::read_dir(format!("{}/{}", root, dir)) else {
+            continue;
+        };
+        for entry in entries.flatten() {
+            let name = entry.file_name().to_string_lossy().to_string();
+            if let Some(rest) = name.strip_prefix("libsystemd-shared-") {
+                let digits: String = rest.chars().take_while(|c| c.is_ascii_digit()).collect();
+                if let Ok(version) = digits.parse() {
+                    return Some(version);
+                }
+            }
+        }
+    }
+    None
+}
+
+/// Mount options for the /var fstab entry. With a new enough systemd the ordering
+/// is expressed directly in fstab; otherwise plain defaults and the caller installs
+/// drop-ins with `install_var_ordering_dropins`.
+pub fn var_mount_options(version: Option<u32>) -> String {
+    match version {
+        Some(v) if v >= MIN_X_SYSTEMD_BEFORE => {
+            let mut options = String::from("defaults");
+            for unit in VAR_CONSUMERS {
+                options.push_str(&format!(",x-systemd.before={}", unit));
+            }
+            options
+        }
+        _ => String::from("defaults"),
+    }
+}
+
+/// Order the /var consumers after the /var mount with RequiresMountsFor= drop-ins,
+/// which every systemd version supports
+pub fn install_var_ordering_dropins(root: &str) -> Result<()> {
+    for unit in VAR_CONSUMERS {
+        let dir = format!("{}/etc/systemd/system/{}.d", root, unit);
+        std::fs::create_dir_all(&dir).context(format!("Failed to create {}", dir))?;
+        std::fs::write(
+            format!("{}/rpi-fs-shrink-var.conf", dir),
+            "# Added by rpi-fs-shrink: /var is a separate partition\n[Unit]\nRequiresMountsFor=/var\n",
+        )
+        .context(format!("Failed to write drop-in for {}", unit))?;
+        println!("    {} ordered after /var (drop-in)", unit);
+    }
+    Ok(())
+}
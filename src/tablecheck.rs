@@ -0,0 +1,426 @@
+//! Reading the partition table back after the run wrote it. Every partition
+//! must have the start, end, type and flags the plan gave it, on the disk and in
+//! the kernel's view of it, and a GPT's primary and backup headers must agree
+//! and carry valid CRCs. A mismatch means parted, sgdisk or the kernel did
+//! something other than what was planned, and nothing should be formatted on it.
+
+use anyhow::{bail, Context, Result};
+use std::os::unix::fs::FileExt;
+use std::process::Command;
+
+use crate::simulate::planned_partitions;
+use crate::{sysfs, DiskInfo, PartitionLayout, SECTOR_SIZE};
+
+/// One entry of a partition table, as sfdisk shows it
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TableEntry {
+    pub number: u32,
+    pub start: u64,
+    pub end: u64,
+    /// MBR type byte in hex ("83") or GPT type GUID
+    pub type_code: String,
+    /// "bootable" on MBR, the attribute list on GPT; empty when none
+    pub flags: String,
+}
+
+#[derive(Debug, Clone)]
+pub struct Table {
+    /// "dos" or "gpt"
+    pub label: String,
+    pub entries: Vec<TableEntry>,
+}
+
+/// Parse `sfdisk --json` output
+pub fn parse_sfdisk_json(text: &str) -> Result<Table> {
+    let value: serde_json::Value = serde_json::from_str(text).context("sfdisk printed no JSON")?;
+    let table = &value["partitiontable"];
+    let label = table["label"].as_str().context("sfdisk JSON has no label")?.to_string();
+    let mut entries = Vec::new();
+    for partition in table["partitions"].as_array().map(Vec::as_slice).unwrap_or_default() {
+        let node = partition["node"].as_str().unwrap_or_default();
+        // /dev/sda3, /dev/mmcblk0p3, /dev/mapper/loop0p3: the number ends the name
+        let digits = node.len() - node.trim_end_matches(|c: char| c.is_ascii_digit()).len();
+        let number = node[node.len() - digits..].parse().context(format!("No partition number in {}", node))?;
+        let (Some(start), Some(size)) = (partition["start"].as_u64(), partition["size"].as_u64()) else {
+            bail!("sfdisk shows no start or size for {}", node);
+        };
+        let flags = match (partition["bootable"].as_bool(), partition["attrs"].as_str()) {
+            (Some(true), _) => "bootable".to_string(),
+            (_, Some(attrs)) => attrs.to_string(),
+            _ => String::new(),
+        };
+        entries.push(TableEntry {
+            number,
+            start,
+            end: start + size.max(1) - 1,
+            type_code: partition["type"].as_str().unwrap_or_default().to_string(),
+            flags,
+        });
+    }
+    Ok(Table { label, entries })
+}
+
+/// The partition table of `device` as written on it
+pub fn read_table(device: &str) -> Result<Table> {
+    let output = Command::new("sfdisk").args(["--json", device]).output().context("Failed to run sfdisk")?;
+    if !output.status.success() {
+        bail!("sfdisk could not read the partition table of {}: {}", device, String::from_utf8_lossy(&output.stderr).trim());
+    }
+    parse_sfdisk_json(&String::from_utf8_lossy(&output.stdout))
+}
+
+/// The table as one line per partition, for hashing: any change to a start,
+/// end, type or flag changes it
+fn canonical(table: &Table) -> String {
+    let mut text = format!("{}\n", table.label);
+    for entry in &table.entries {
+        text.push_str(&format!("{} {} {} {} {}\n", entry.number, entry.start, entry.end, entry.type_code.to_ascii_lowercase(), entry.flags));
+    }
+    text
+}
+
+/// SHA-256 of the partition table of `device`, to tell later whether it changed
+pub fn table_hash(device: &str) -> Result<String> {
+    crate::audit::sha256(canonical(&read_table(device)?).as_bytes())
+}
+
+/// MBR type bytes that differ only in CHS or LBA addressing, which parted picks on its own
+const MBR_ALIASES: &[&[&str]] = &[&["b", "c"], &["4", "6", "e"], &["5", "f", "85"]];
+
+fn same_type(planned: &str, actual: &str) -> bool {
+    let planned = planned.trim_start_matches("0x").to_ascii_lowercase();
+    let actual = actual.trim_start_matches("0x").to_ascii_lowercase();
+    planned == actual || MBR_ALIASES.iter().any(|group| group.contains(&planned.as_str()) && group.contains(&actual.as_str()))
+}
+
+fn is_extended(entry: &TableEntry) -> bool {
+    MBR_ALIASES[2].contains(&entry.type_code.trim_start_matches("0x").to_ascii_lowercase().as_str())
+}
+
+/// The table the run should have written. Boot and root keep the type and flags
+/// they had in `before`, unless the table was converted to GPT.
+pub fn expected(disk: &DiskInfo, boot: (u64, u64), layout: &PartitionLayout, before: &Table) -> Vec<TableEntry> {
+    planned_partitions(disk, boot, layout)
+        .into_iter()
+        .map(|planned| {
+            let kept = before
+                .entries
+                .iter()
+                .filter(|_| before.label != "dos" || disk.partition_table == "msdos")
+                .find(|entry| entry.number == planned.number && [disk.roles.boot, disk.roles.root].contains(&entry.number));
+            TableEntry {
+                number: planned.number,
+                start: planned.start,
+                end: planned.end,
+                type_code: kept.map_or(planned.type_code.to_string(), |entry| entry.type_code.clone()),
+                flags: kept.map(|entry| entry.flags.clone()).unwrap_or_default(),
+            }
+        })
+        .collect()
+}
+
+/// How `actual` differs from `expected`, one line per difference. Entries are
+/// matched by start sector.
+pub fn differences(expected: &[TableEntry], actual: &[TableEntry]) -> Vec<String> {
+    let mut found = Vec::new();
+    for planned in expected {
+        let Some(entry) = actual.iter().find(|entry| entry.start == planned.start) else {
+            found.push(format!("planned partition {} (sectors {}-{}) is missing", planned.number, planned.start, planned.end));
+            continue;
+        };
+        if entry.number != planned.number {
+            found.push(format!("partition at sector {} is number {}, planned {}", planned.start, entry.number, planned.number));
+        }
+        if entry.end != planned.end {
+            found.push(format!("partition {} ends at sector {}, planned {}", entry.number, entry.end, planned.end));
+        }
+        if !same_type(&planned.type_code, &entry.type_code) {
+            found.push(format!("partition {} has type {}, planned {}", entry.number, entry.type_code, planned.type_code));
+        }
+        if entry.flags != planned.flags {
+            let shown = |flags: &str| if flags.is_empty() { "none".to_string() } else { flags.to_string() };
+            found.push(format!("partition {} has flags {}, planned {}", entry.number, shown(&entry.flags), shown(&planned.flags)));
+        }
+    }
+    for entry in actual.iter().filter(|entry| !expected.iter().any(|planned| planned.start == entry.start)) {
+        found.push(format!("partition {} (sectors {}-{}) is not in the plan", entry.number, entry.start, entry.end));
+    }
+    found
+}
+
+/// Where the kernel's view of `device` differs from `table`. The kernel shows an
+/// extended partition as a stub, so only its existence counts.
+fn kernel_differences(device: &str, table: &Table) -> Vec<String> {
+    // Partitions of device-mapper disks and unscanned loop devices are kpartx mappings
+    if sysfs::is_device_mapper(device) || sysfs::loop_partscan(device) == Some(false) {
+        println!("  Skipped the kernel's view: {} has its partitions as kpartx mappings", device);
+        return Vec::new();
+    }
+    let mut found = Vec::new();
+    for entry in &table.entries {
+        match sysfs::partition_bounds(device, entry.number) {
+            None => found.push(format!("the kernel doesn't show partition {}", entry.number)),
+            Some(_) if is_extended(entry) => {}
+            Some((start, end)) if (start, end) != (entry.start, entry.end) => found.push(format!(
+                "the kernel has partition {} at sectors {}-{}, the table {}-{}",
+                entry.number, start, end, entry.start, entry.end
+            )),
+            Some(_) => {}
+        }
+    }
+    found
+}
+
+/// CRC-32 (IEEE 802.3), as the GPT headers use it
+pub fn crc32(data: &[u8]) -> u32 {
+    let mut crc = 0xFFFF_FFFFu32;
+    for &byte in data {
+        crc ^= u32::from(byte);
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 { (crc >> 1) ^ 0xEDB8_8320 } else { crc >> 1 };
+        }
+    }
+    !crc
+}
+
+/// The fields of a GPT header the checks need
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct GptHeader {
+    pub my_lba: u64,
+    pub alternate_lba: u64,
+    pub disk_guid: [u8; 16],
+    pub entries_lba: u64,
+    pub entries: u32,
+    pub entry_size: u32,
+    pub entries_crc: u32,
+}
+
+/// Parse the GPT header in `sector`, checking its signature and CRC
+pub fn parse_gpt_header(sector: &[u8]) -> Result<GptHeader> {
+    let u32_at = |offset: usize| u32::from_le_bytes(sector[offset..offset + 4].try_into().unwrap_or_default());
+    let u64_at = |offset: usize| u64::from_le_bytes(sector[offset..offset + 8].try_into().unwrap_or_default());
+    if sector.len() < 92 || &sector[..8] != b"EFI PART" {
+        bail!("no GPT signature");
+    }
+    let size = u32_at(12) as usize;
+    if !(92..=sector.len()).contains(&size) {
+        bail!("header size {} is out of range", size);
+    }
+    let mut header = sector[..size].to_vec();
+    header[16..20].fill(0);
+    let stored = u32_at(16);
+    let computed = crc32(&header);
+    if stored != computed {
+        bail!("header CRC is {:08x}, the header sums to {:08x}", stored, computed);
+    }
+    let entry_size = u32_at(84);
+    if entry_size < 128 || entry_size % 8 != 0 {
+        bail!("entry size {} is invalid", entry_size);
+    }
+    Ok(GptHeader {
+        my_lba: u64_at(24),
+        alternate_lba: u64_at(32),
+        disk_guid: sector[56..72].try_into().unwrap_or_default(),
+        entries_lba: u64_at(72),
+        entries: u32_at(80),
+        entry_size,
+        entries_crc: u32_at(88),
+    })
+}
+
+/// What is wrong with the primary and backup GPT of a disk of `sectors`
+/// sectors; `read(lba, count)` reads from the disk
+pub fn gpt_problems(sectors: u64, read: impl Fn(u64, u64) -> Result<Vec<u8>>) -> Vec<String> {
+    let mut found = Vec::new();
+    let mut headers = Vec::new();
+    for (name, lba) in [("primary", 1), ("backup", sectors - 1)] {
+        let header = match read(lba, 1).and_then(|sector| parse_gpt_header(&sector)) {
+            Ok(header) => header,
+            Err(err) => {
+                found.push(format!("{} GPT header at sector {}: {}", name, lba, err));
+                continue;
+            }
+        };
+        if header.my_lba != lba {
+            found.push(format!("{} GPT header at sector {} says it is at {}", name, lba, header.my_lba));
+        }
+        let bytes = u64::from(header.entries) * u64::from(header.entry_size);
+        if bytes > 1024 * 1024 {
+            found.push(format!("{} GPT header claims {} bytes of entries", name, bytes));
+            continue;
+        }
+        match read(header.entries_lba, bytes.div_ceil(SECTOR_SIZE)) {
+            Ok(array) => {
+                let computed = crc32(&array[..bytes as usize]);
+                if computed != header.entries_crc {
+                    found.push(format!(
+                        "{} GPT entry array at sector {} sums to {:08x}, its header says {:08x}",
+                        name, header.entries_lba, computed, header.entries_crc
+                    ));
+                }
+            }
+            Err(err) => found.push(format!("{} GPT entry array at sector {}: {}", name, header.entries_lba, err)),
+        }
+        headers.push(header);
+    }
+    if let [primary, backup] = &headers[..] {
+        if primary.alternate_lba != backup.my_lba || backup.alternate_lba != primary.my_lba {
+            found.push(format!(
+                "the GPT headers point at sectors {} and {}, not at each other",
+                primary.alternate_lba, backup.alternate_lba
+            ));
+        }
+        if primary.disk_guid != backup.disk_guid || primary.entries_crc != backup.entries_crc {
+            found.push("the primary and backup GPT describe different disks or partitions".to_string());
+        }
+    }
+    found
+}
+
+fn read_gpt_problems(device: &str, sectors: u64) -> Result<Vec<String>> {
+    let file = std::fs::File::open(device).context(format!("Failed to open {}", device))?;
+    Ok(gpt_problems(sectors, |lba, count| {
+        let mut buffer = vec![0u8; (count * SECTOR_SIZE) as usize];
+        file.read_exact_at(&mut buffer, lba * SECTOR_SIZE).context(format!("Failed to read sector {} of {}", lba, device))?;
+        Ok(buffer)
+    }))
+}
+
+/// Read the table of `disk` back and compare it with the plan; `before` is the
+/// table from before the run. A difference is a refusal: nothing is formatted yet.
+pub fn verify(disk: &DiskInfo, boot: (u64, u64), layout: &PartitionLayout, before: &Table) -> Result<()> {
+    let expected = expected(disk, boot, layout, before);
+    let table = read_table(&disk.device)?;
+    let mut found = differences(&expected, &table.entries);
+    found.extend(kernel_differences(&disk.device, &table));
+    if table.label == "gpt" {
+        found.extend(read_gpt_problems(&disk.device, disk.size_sectors)?);
+    }
+    if found.is_empty() {
+        println!("  {} partitions match the plan", expected.len());
+        if table.label == "gpt" {
+            println!("  Primary and backup GPT headers and entry arrays check out");
+        }
+        return Ok(());
+    }
+    for difference in &found {
+        println!("  {}", difference);
+    }
+    crate::findings::report(
+        "table-mismatch",
+        &format!("The partition table of {} differs from the plan in {} ways; nothing was formatted yet", disk.device, found.len()),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(number: u32, start: u64, end: u64, type_code: &str, flags: &str) -> TableEntry {
+        TableEntry { number, start, end, type_code: type_code.to_string(), flags: flags.to_string() }
+    }
+
+    #[test]
+    fn sfdisk_json_is_read() {
+        let text = r#"{"partitiontable": {"label": "dos", "id": "0x738a4d67", "device": "/dev/mmcblk0", "unit": "sectors",
+            "sectorsize": 512, "partitions": [
+                {"node": "/dev/mmcblk0p1", "start": 8192, "size": 1048576, "type": "c", "bootable": true},
+                {"node": "/dev/mmcblk0p2", "start": 1056768, "size": 33554432, "type": "83"}]}}"#;
+        let table = parse_sfdisk_json(text).unwrap();
+        assert_eq!(table.label, "dos");
+        assert_eq!(
+            table.entries,
+            vec![entry(1, 8192, 1056767, "c", "bootable"), entry(2, 1056768, 34611199, "83", "")]
+        );
+    }
+
+    #[test]
+    fn canonical_text_changes_with_any_field() {
+        let table = Table { label: "dos".to_string(), entries: vec![entry(1, 8192, 1056767, "c", ""), entry(2, 1056768, 34078719, "83", "")] };
+        assert_eq!(canonical(&table), "dos\n1 8192 1056767 c \n2 1056768 34078719 83 \n");
+        let mut moved = table.clone();
+        moved.entries[1].end += 8;
+        assert_ne!(canonical(&table), canonical(&moved));
+    }
+
+    #[test]
+    fn lba_and_chs_types_are_the_same() {
+        assert!(same_type("c", "b"));
+        assert!(same_type("5", "f"));
+        assert!(same_type("0FC63DAF-8483-4772-8E79-3D69D8477DE4", "0fc63daf-8483-4772-8e79-3d69d8477de4"));
+        assert!(!same_type("83", "82"));
+    }
+
+    #[test]
+    fn differences_name_each_field() {
+        let expected = [entry(1, 8192, 1056767, "c", "bootable"), entry(2, 1056768, 34611199, "83", ""), entry(3, 34611200, 62333951, "83", "")];
+        let actual = [entry(1, 8192, 1056767, "c", ""), entry(2, 1056768, 34613247, "83", ""), entry(4, 40000000, 62333951, "82", "")];
+        assert_eq!(
+            differences(&expected, &actual),
+            vec![
+                "partition 1 has flags none, planned bootable",
+                "partition 2 ends at sector 34613247, planned 34611199",
+                "planned partition 3 (sectors 34611200-62333951) is missing",
+                "partition 4 (sectors 40000000-62333951) is not in the plan",
+            ]
+        );
+        assert!(differences(&expected, &expected).is_empty());
+    }
+
+    #[test]
+    fn crc32_matches_the_reference_value() {
+        assert_eq!(crc32(b"123456789"), 0xCBF4_3926);
+    }
+
+    /// A disk of `sectors` sectors with a consistent GPT of 128 empty entries
+    fn gpt_disk(sectors: u64) -> Vec<u8> {
+        let mut disk = vec![0u8; (sectors * SECTOR_SIZE) as usize];
+        let entries_crc = crc32(&vec![0u8; 128 * 128]);
+        for (lba, alternate, entries_lba) in [(1, sectors - 1, 2), (sectors - 1, 1, sectors - 33)] {
+            let mut header = vec![0u8; 92];
+            header[..8].copy_from_slice(b"EFI PART");
+            header[8..12].copy_from_slice(&0x0001_0000u32.to_le_bytes());
+            header[12..16].copy_from_slice(&92u32.to_le_bytes());
+            header[24..32].copy_from_slice(&lba.to_le_bytes());
+            header[32..40].copy_from_slice(&alternate.to_le_bytes());
+            header[56..72].copy_from_slice(&[7u8; 16]);
+            header[72..80].copy_from_slice(&entries_lba.to_le_bytes());
+            header[80..84].copy_from_slice(&128u32.to_le_bytes());
+            header[84..88].copy_from_slice(&128u32.to_le_bytes());
+            header[88..92].copy_from_slice(&entries_crc.to_le_bytes());
+            let crc = crc32(&header);
+            header[16..20].copy_from_slice(&crc.to_le_bytes());
+            let offset = (lba * SECTOR_SIZE) as usize;
+            disk[offset..offset + 92].copy_from_slice(&header);
+        }
+        disk
+    }
+
+    fn problems(disk: &[u8]) -> Vec<String> {
+        let sectors = disk.len() as u64 / SECTOR_SIZE;
+        gpt_problems(sectors, |lba, count| {
+            let (start, end) = ((lba * SECTOR_SIZE) as usize, ((lba + count) * SECTOR_SIZE) as usize);
+            disk.get(start..end).map(<[u8]>::to_vec).context("past the end")
+        })
+    }
+
+    #[test]
+    fn consistent_gpt_has_no_problems() {
+        assert!(problems(&gpt_disk(2048)).is_empty());
+    }
+
+    #[test]
+    fn damaged_gpt_is_named() {
+        let mut disk = gpt_disk(2048);
+        // A changed entry in the primary array only
+        disk[2 * SECTOR_SIZE as usize] = 1;
+        assert_eq!(problems(&disk).len(), 1);
+        assert!(problems(&disk)[0].starts_with("primary GPT entry array at sector 2 sums to"));
+
+        let mut disk = gpt_disk(2048);
+        // A changed byte inside the backup header
+        disk[2047 * SECTOR_SIZE as usize + 60] ^= 0xFF;
+        assert_eq!(problems(&disk).len(), 1);
+        assert!(problems(&disk)[0].starts_with("backup GPT header at sector 2047: header CRC"));
+    }
+}
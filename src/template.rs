@@ -0,0 +1,127 @@
+//! `${NAME}` variables in layout files, filled in for the target device so one
+//! file serves cards and disks of different sizes.
+
+use anyhow::{anyhow, bail, Result};
+use std::collections::BTreeMap;
+use std::path::Path;
+
+/// Names a layout file can use, for error messages
+const KNOWN: &[&str] = &["DISK_SIZE", "RAM_SIZE", "SERIAL"];
+
+/// Variable values for one target; None for those it has no value for
+#[derive(Debug, Default)]
+pub struct Variables {
+    values: BTreeMap<&'static str, Option<String>>,
+}
+
+impl Variables {
+    /// Values for `device`: a disk, or an image file (which has no serial number);
+    /// `ram` is the target's if known, otherwise this machine's is used
+    pub fn for_device(device: &str, ram: Option<u64>) -> Self {
+        let disk_size = disk_bytes(device);
+        let serial = crate::identity::disk_serial(device);
+        Variables::new(disk_size, ram.or_else(ram_bytes), (!serial.is_empty()).then_some(serial))
+    }
+
+    pub fn new(disk_size: Option<u64>, ram: Option<u64>, serial: Option<String>) -> Self {
+        let mut values = BTreeMap::new();
+        values.insert("DISK_SIZE", disk_size.map(size_value));
+        values.insert("RAM_SIZE", ram.map(size_value));
+        values.insert("SERIAL", serial);
+        Variables { values }
+    }
+
+    /// Replace every `${NAME}` in `text`; `$$` is a literal `$`
+    pub fn expand(&self, text: &str) -> Result<String> {
+        let mut expanded = String::new();
+        let mut rest = text;
+        while let Some(at) = rest.find('$') {
+            expanded.push_str(&rest[..at]);
+            rest = &rest[at + 1..];
+            if let Some(after) = rest.strip_prefix('$') {
+                expanded.push('$');
+                rest = after;
+                continue;
+            }
+            let Some(inner) = rest.strip_prefix('{') else {
+                bail!("'$' in \"{}\" must start a ${{NAME}} variable (use $$ for a literal $)", text);
+            };
+            let close = inner.find('}').ok_or_else(|| anyhow!("Unclosed ${{ in \"{}\"", text))?;
+            let name = &inner[..close];
+            match self.values.get(name) {
+                Some(Some(value)) => expanded.push_str(value),
+                Some(None) => bail!("${{{}}} has no value for this target", name),
+                None => bail!("Unknown variable ${{{}}} in \"{}\" (known: {})", name, text, KNOWN.join(", ")),
+            }
+            rest = &inner[close + 1..];
+        }
+        expanded.push_str(rest);
+        Ok(expanded)
+    }
+}
+
+/// Sizes go in as whole MiB, which the size parser reads back
+fn size_value(bytes: u64) -> String {
+    format!("{}M", bytes / (1024 * 1024))
+}
+
+/// Capacity of a disk or image file
+pub fn disk_bytes(device: &str) -> Option<u64> {
+    if Path::new(device).is_file() {
+        std::fs::metadata(device).ok().map(|meta| meta.len())
+    } else {
+        crate::sysfs::disk_size_bytes(device)
+    }
+}
+
+/// RAM of the machine this runs on, from /proc/meminfo
+pub fn ram_bytes() -> Option<u64> {
+    let meminfo = std::fs::read_to_string("/proc/meminfo").ok()?;
+    let line = meminfo.lines().find(|line| line.starts_with("MemTotal:"))?;
+    let kib: u64 = line.split_whitespace().nth(1)?.parse().ok()?;
+    Some(kib * 1024)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const GIB: u64 = 1024 * 1024 * 1024;
+
+    fn variables() -> Variables {
+        Variables::new(Some(30 * GIB), Some(4 * GIB), None)
+    }
+
+    #[test]
+    fn variables_expand_to_sizes_the_size_parser_reads() {
+        let variables = variables();
+        assert_eq!(variables.expand("${DISK_SIZE}").unwrap(), "30720M");
+        assert_eq!(crate::parse_size(&variables.expand("${RAM_SIZE}").unwrap()).unwrap(), 4 * GIB);
+        assert_eq!(variables.expand("min(${RAM_SIZE}, 2G)").unwrap(), "min(4096M, 2G)");
+        assert_eq!(variables.expand("costs $$5, no variables").unwrap(), "costs $5, no variables");
+        assert_eq!(Variables::new(None, None, Some("0x1c2d3e4f".into())).expand("card-${SERIAL}").unwrap(), "card-0x1c2d3e4f");
+    }
+
+    #[test]
+    fn unknown_missing_and_malformed_variables_are_refused() {
+        let variables = variables();
+        let err = variables.expand("${DISK}").unwrap_err().to_string();
+        assert!(err.contains("Unknown variable ${DISK}") && err.contains("DISK_SIZE, RAM_SIZE, SERIAL"), "{}", err);
+        // An image file has no serial number
+        assert!(variables.expand("${SERIAL}").unwrap_err().to_string().contains("no value for this target"));
+        assert!(variables.expand("${RAM_SIZE").unwrap_err().to_string().contains("Unclosed"));
+        assert!(variables.expand("8G$").is_err());
+        assert!(variables.expand("$RAM_SIZE").is_err());
+    }
+
+    #[test]
+    fn bad_sizes_survive_expansion_and_fail_when_read() {
+        // Expansion only substitutes; the size parser and size rules still refuse the result
+        let expanded = variables().expand("${DISK_SIZE}X").unwrap();
+        assert_eq!(expanded, "30720MX");
+        assert!(crate::parse_size(&expanded).is_err());
+        let inputs = crate::sizeexpr::Inputs { disk: Some(30 * GIB), ram: Some(4 * GIB) };
+        assert!(crate::sizeexpr::evaluate(&expanded, inputs).is_err());
+        assert!(crate::sizeexpr::evaluate(&variables().expand("${RAM_SIZE} / 0").unwrap(), inputs).is_err());
+    }
+}
@@ -0,0 +1,194 @@
+use std::process::Command;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+/// How often the sensors are read
+const INTERVAL: Duration = Duration::from_secs(5);
+
+/// The Pi firmware starts capping the clock here
+const WARN_CELSIUS: f64 = 80.0;
+/// and throttles hard here, so a pausable copy stops
+const PAUSE_CELSIUS: f64 = 85.0;
+/// A paused copy resumes once the SoC cooled down to this
+const RESUME_CELSIUS: f64 = 75.0;
+/// and under-voltage hasn't been flagged for this long
+const RESUME_AFTER_UNDERVOLTAGE: Duration = Duration::from_secs(30);
+
+/// Bits of the firmware's get_throttled value
+const UNDERVOLTAGE_NOW: u32 = 1 << 0;
+const FREQUENCY_CAPPED_NOW: u32 = 1 << 1;
+const THROTTLED_NOW: u32 = 1 << 2;
+
+/// raspberrypi-hwmon's copy of get_throttled on newer kernels
+const THROTTLED_SYSFS: &str = "/sys/devices/platform/soc/soc:firmware/get_throttled";
+
+/// Processes the monitor may stop while the host is too hot or under-volted
+static PAUSABLE: Mutex<Vec<i32>> = Mutex::new(Vec::new());
+
+/// Highest temperature of the host's thermal zones, in °C
+fn max_temperature() -> Option<f64> {
+    std::fs::read_dir("/sys/class/thermal")
+        .ok()?
+        .flatten()
+        .filter(|entry| entry.file_name().to_string_lossy().starts_with("thermal_zone"))
+        .filter_map(|entry| std::fs::read_to_string(entry.path().join("temp")).ok())
+        .filter_map(|value| value.trim().parse::<f64>().ok())
+        .map(|millidegrees| millidegrees / 1000.0)
+        .reduce(f64::max)
+}
+
+/// The firmware's throttling flags, from sysfs or vcgencmd; None when the host isn't a Pi
+fn throttled() -> Option<u32> {
+    let value = match std::fs::read_to_string(THROTTLED_SYSFS) {
+        Ok(value) => value,
+        Err(_) if crate::command_exists("vcgencmd") => {
+            let output = Command::new("vcgencmd").arg("get_throttled").output().ok()?;
+            // throttled=0x50005
+            String::from_utf8_lossy(&output.stdout).trim().strip_prefix("throttled=")?.to_string()
+        }
+        Err(_) => return None,
+    };
+    u32::from_str_radix(value.trim().trim_start_matches("0x"), 16).ok()
+}
+
+fn describe_throttled(flags: u32) -> Vec<&'static str> {
+    let mut found = Vec::new();
+    if flags & UNDERVOLTAGE_NOW != 0 {
+        found.push("under-voltage");
+    }
+    if flags & FREQUENCY_CAPPED_NOW != 0 {
+        found.push("frequency capped");
+    }
+    if flags & THROTTLED_NOW != 0 {
+        found.push("throttled");
+    }
+    found
+}
+
+/// Let the monitor stop `pid` while the host is too hot; undone when the guard drops
+pub struct Pausable(i32);
+
+pub fn pausable(pid: u32) -> Pausable {
+    let pid = pid as i32;
+    PAUSABLE.lock().unwrap().push(pid);
+    Pausable(pid)
+}
+
+impl Drop for Pausable {
+    fn drop(&mut self) {
+        PAUSABLE.lock().unwrap().retain(|&pid| pid != self.0);
+    }
+}
+
+fn signal_pausable(signal: libc::c_int) {
+    for &pid in PAUSABLE.lock().unwrap().iter() {
+        // SAFETY: kill has no memory-safety requirements
+        unsafe {
+            libc::kill(pid, signal);
+        }
+    }
+}
+
+/// Watches temperature and the firmware's throttling flags on the host for as
+/// long as it lives
+pub struct Monitor {
+    stop: Arc<AtomicBool>,
+    handle: Option<JoinHandle<()>>,
+}
+
+/// Start watching the host; with `pause`, stop pausable copies while it is at the
+/// hard throttle temperature or under-volted. None when the host has no sensors.
+pub fn start(pause: bool) -> Option<Monitor> {
+    if max_temperature().is_none() && throttled().is_none() {
+        return None;
+    }
+    let stop = Arc::new(AtomicBool::new(false));
+    let stopping = stop.clone();
+    let handle = std::thread::spawn(move || watch(pause, &stopping));
+    Some(Monitor { stop, handle: Some(handle) })
+}
+
+impl Drop for Monitor {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+        if let Some(handle) = self.handle.take() {
+            let _ = handle.join();
+        }
+    }
+}
+
+fn watch(pause: bool, stop: &AtomicBool) {
+    let mut warned_hot = false;
+    let mut warned_flags = 0;
+    let mut paused = false;
+    let mut undervoltage_seen: Option<Instant> = None;
+
+    while !stop.load(Ordering::Relaxed) {
+        let temperature = max_temperature();
+        let flags = throttled().unwrap_or(0);
+        if flags & UNDERVOLTAGE_NOW != 0 {
+            undervoltage_seen = Some(Instant::now());
+        }
+
+        let hot = temperature.is_some_and(|t| t >= WARN_CELSIUS);
+        if hot && !warned_hot {
+            println!("\n  Warning: host at {:.0}°C, the CPU clock is being capped", temperature.unwrap_or(0.0));
+        }
+        warned_hot = hot;
+        let new_flags = flags & !warned_flags;
+        if new_flags != 0 {
+            println!("\n  Warning: firmware reports {}", describe_throttled(new_flags).join(", "));
+            if new_flags & UNDERVOLTAGE_NOW != 0 {
+                println!("  The power supply can't keep up; a brown-out during a write can corrupt the target");
+            }
+        }
+        warned_flags = flags;
+
+        if pause {
+            let critical = temperature.is_some_and(|t| t >= PAUSE_CELSIUS) || flags & UNDERVOLTAGE_NOW != 0;
+            let recovered = temperature.is_none_or(|t| t <= RESUME_CELSIUS)
+                && undervoltage_seen.is_none_or(|seen| seen.elapsed() >= RESUME_AFTER_UNDERVOLTAGE);
+            if critical && !paused {
+                println!("\n  Pausing the copy until the host recovers...");
+                signal_pausable(libc::SIGSTOP);
+                paused = true;
+            } else if paused && recovered {
+                println!("\n  Host recovered, resuming the copy");
+                signal_pausable(libc::SIGCONT);
+                paused = false;
+            } else if paused {
+                // Processes registered since the pause have to wait too
+                signal_pausable(libc::SIGSTOP);
+            }
+        }
+
+        let slept = Instant::now();
+        while slept.elapsed() < INTERVAL && !stop.load(Ordering::Relaxed) {
+            std::thread::sleep(Duration::from_millis(200));
+        }
+    }
+    if paused {
+        signal_pausable(libc::SIGCONT);
+    }
+}
+
+/// Whether the host had thermal or power trouble since boot, for a note before the run
+pub fn print_host_state() {
+    let temperature = max_temperature();
+    let Some(flags) = throttled() else {
+        return;
+    };
+    // The upper half of get_throttled says what happened since boot
+    let since_boot = describe_throttled(flags >> 16);
+    if let Some(temperature) = temperature {
+        println!("\nHost temperature: {:.0}°C", temperature);
+    }
+    if !since_boot.is_empty() {
+        println!("  Warning: since boot the firmware saw {}", since_boot.join(", "));
+        if flags >> 16 & UNDERVOLTAGE_NOW != 0 {
+            println!("  A long copy can brown out a marginal power supply; --thermal-pause stops the copy while it lasts");
+        }
+    }
+}
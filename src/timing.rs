@@ -0,0 +1,114 @@
+use serde_json::{json, Value};
+use std::time::{Duration, Instant};
+
+struct Step {
+    name: String,
+    started: Instant,
+    elapsed: Option<Duration>,
+    bytes: u64,
+}
+
+/// Wall time and data moved per step of a run
+pub struct StepTimings {
+    steps: Vec<Step>,
+}
+
+impl StepTimings {
+    pub fn new() -> Self {
+        StepTimings { steps: Vec::new() }
+    }
+
+    /// End the current step and start timing `name`
+    pub fn begin(&mut self, name: &str) {
+        self.finish();
+        self.steps.push(Step { name: name.to_string(), started: Instant::now(), elapsed: None, bytes: 0 });
+        crate::progress::begin_step(name);
+    }
+
+    /// Count `bytes` copied or moved towards the current step
+    pub fn add_bytes(&mut self, bytes: u64) {
+        if let Some(step) = self.steps.last_mut() {
+            step.bytes += bytes;
+            crate::progress::step_bytes(step.bytes);
+        }
+    }
+
+    pub fn finish(&mut self) {
+        if let Some(step) = self.steps.last_mut() {
+            step.elapsed.get_or_insert_with(|| step.started.elapsed());
+        }
+    }
+
+    fn elapsed(step: &Step) -> Duration {
+        step.elapsed.unwrap_or_else(|| step.started.elapsed())
+    }
+
+    fn total(&self) -> (Duration, u64) {
+        self.steps.iter().fold((Duration::ZERO, 0), |(time, bytes), step| (time + Self::elapsed(step), bytes + step.bytes))
+    }
+
+    /// Bytes copied or moved over all steps
+    pub fn total_bytes(&self) -> u64 {
+        self.total().1
+    }
+
+    pub fn print_table(&self) {
+        let (total_time, total_bytes) = self.total();
+        let width = self.steps.iter().map(|step| step.name.len()).max().unwrap_or(0).max(5);
+
+        println!("\nStep timings:");
+        println!("  {:<width$}  {:>9}  {:>6}  {:>10}  {:>10}", "Step", "Time", "Share", "Data", "Rate");
+        for step in &self.steps {
+            let elapsed = Self::elapsed(step);
+            let share = if total_time.is_zero() { 0.0 } else { elapsed.as_secs_f64() * 100.0 / total_time.as_secs_f64() };
+            let (data, rate) = if step.bytes > 0 {
+                (format_bytes(step.bytes), format!("{}/s", format_bytes((step.bytes as f64 / elapsed.as_secs_f64().max(0.001)) as u64)))
+            } else {
+                (String::new(), String::new())
+            };
+            println!(
+                "  {:<width$}  {:>9}  {:>5.1}%  {:>10}  {:>10}",
+                step.name,
+                format_duration(elapsed),
+                share,
+                data,
+                rate
+            );
+        }
+        println!("  {:<width$}  {:>9}  {:>6}  {:>10}", "Total", format_duration(total_time), "", format_bytes(total_bytes));
+    }
+
+    pub fn to_json(&self) -> Value {
+        let (total_time, total_bytes) = self.total();
+        let steps: Vec<Value> = self
+            .steps
+            .iter()
+            .map(|step| json!({ "step": step.name, "seconds": Self::elapsed(step).as_secs_f64(), "bytes": step.bytes }))
+            .collect();
+        json!({ "steps": steps, "total_seconds": total_time.as_secs_f64(), "total_bytes": total_bytes })
+    }
+}
+
+pub fn format_duration(duration: Duration) -> String {
+    let secs = duration.as_secs();
+    match secs {
+        0..60 => format!("{:.1}s", duration.as_secs_f64()),
+        60..3600 => format!("{}m {:02}s", secs / 60, secs % 60),
+        _ => format!("{}h {:02}m", secs / 3600, secs % 3600 / 60),
+    }
+}
+
+pub fn format_bytes(bytes: u64) -> String {
+    const UNITS: [&str; 5] = ["B", "KiB", "MiB", "GiB", "TiB"];
+    let mut value = bytes as f64;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{} B", bytes)
+    } else {
+        format!("{:.1} {}", value, UNITS[unit])
+    }
+}
@@ -0,0 +1,109 @@
+//! External tools from a local directory (`--tools-dir`) for air-gapped
+//! provisioning stations, where installing missing packages fails. The
+//! directory is checked against its SHA256SUMS and put in front of PATH.
+
+use anyhow::{bail, Context, Result};
+use std::collections::BTreeMap;
+use std::os::unix::fs::PermissionsExt;
+use std::path::Path;
+
+/// Checksum list of the directory, in `sha256sum` format
+const CHECKSUMS: &str = "SHA256SUMS";
+
+/// Read `sha256sum` output: file name -> hash
+fn parse_checksums(text: &str) -> Result<BTreeMap<String, String>> {
+    let mut sums = BTreeMap::new();
+    for (index, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        // "<hash>  <name>", or "<hash> *<name>" for binary mode
+        let Some((hash, name)) = line.split_once(char::is_whitespace) else {
+            bail!("{} line {}: expected \"<sha256>  <file>\"", CHECKSUMS, index + 1);
+        };
+        let name = name.trim_start().trim_start_matches('*');
+        if hash.len() != 64 || !hash.chars().all(|c| c.is_ascii_hexdigit()) || name.contains('/') {
+            bail!("{} line {}: expected \"<sha256>  <file>\" for a file in the directory", CHECKSUMS, index + 1);
+        }
+        sums.insert(name.to_string(), hash.to_ascii_lowercase());
+    }
+    Ok(sums)
+}
+
+/// Check every file of `dir` against its SHA256SUMS; an executable that isn't
+/// listed is refused, since it would shadow the system's tool of that name
+pub fn verify_dir(dir: &str) -> Result<usize> {
+    let list = format!("{}/{}", dir, CHECKSUMS);
+    let sums = parse_checksums(&std::fs::read_to_string(&list).context(format!(
+        "--tools-dir needs {} (create it with: cd {} && sha256sum * > {})",
+        list, dir, CHECKSUMS
+    ))?)?;
+
+    for entry in std::fs::read_dir(dir).context(format!("Failed to read {}", dir))?.flatten() {
+        let name = entry.file_name().to_string_lossy().to_string();
+        // Following symlinks: one pointing at any binary would otherwise land on PATH unchecked
+        let executable = std::fs::metadata(entry.path()).map(|meta| meta.is_file() && meta.permissions().mode() & 0o111 != 0).unwrap_or(false);
+        if executable && !sums.contains_key(&name) {
+            bail!("{}/{} isn't listed in {}", dir, name, CHECKSUMS);
+        }
+    }
+    for (name, expected) in &sums {
+        let path = format!("{}/{}", dir, name);
+        let data = std::fs::read(&path).context(format!("{} lists {}, which can't be read", CHECKSUMS, path))?;
+        let actual = crate::audit::sha256(&data)?;
+        if actual != *expected {
+            bail!("Checksum mismatch for {}: {} expects {}, the file has {}", path, CHECKSUMS, expected, actual);
+        }
+    }
+    Ok(sums.len())
+}
+
+/// Verify `dir` and put it in front of PATH for this process and every tool it runs.
+/// Must run before any thread is started.
+pub fn use_tools_dir(dir: &str) -> Result<()> {
+    let dir = std::fs::canonicalize(dir).context(format!("Tools directory {} does not exist", dir))?;
+    let dir = dir.to_string_lossy().to_string();
+    if !Path::new(&dir).is_dir() {
+        bail!("--tools-dir {} is not a directory", dir);
+    }
+    let count = verify_dir(&dir)?;
+    let path = std::env::var("PATH").unwrap_or_default();
+    // SAFETY: called before the run starts any thread, so nothing reads the environment concurrently
+    unsafe { std::env::set_var("PATH", if path.is_empty() { dir.clone() } else { format!("{}:{}", dir, path) }) };
+    println!("Using tools from {} ({} files verified against {})", dir, count, CHECKSUMS);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn unlisted_executables_are_refused_even_behind_a_symlink() {
+        let dir = std::env::temp_dir().join(format!("rpi-fs-shrink-tools-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let tool = dir.join("sgdisk");
+        std::fs::write(&tool, "#!/bin/sh\n").unwrap();
+        std::fs::set_permissions(&tool, std::fs::Permissions::from_mode(0o755)).unwrap();
+        let hash = crate::audit::sha256(b"#!/bin/sh\n").unwrap();
+        std::fs::write(dir.join(CHECKSUMS), format!("{}  sgdisk\n", hash)).unwrap();
+        let path = dir.to_string_lossy().into_owned();
+        assert_eq!(verify_dir(&path).unwrap(), 1);
+
+        std::os::unix::fs::symlink(&tool, dir.join("parted")).unwrap();
+        let err = verify_dir(&path).unwrap_err().to_string();
+        assert!(err.contains("parted isn't listed"), "{}", err);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn checksum_lines_name_files_in_the_directory() {
+        let hash = "9f86d081884c7d659a2feaa0c55ad015a3bf4f1b2b0b822cd15d6c15b0f00a08";
+        let sums = parse_checksums(&format!("# tools\n{}  sgdisk\n{} *e2fsck\n", hash, hash.to_uppercase())).unwrap();
+        assert_eq!(sums.keys().collect::<Vec<_>>(), ["e2fsck", "sgdisk"]);
+        assert_eq!(sums["e2fsck"], hash);
+        assert!(parse_checksums(&format!("{}  bin/sgdisk\n", hash)).is_err());
+        assert!(parse_checksums("abc  sgdisk\n").is_err());
+    }
+}
@@ -0,0 +1,243 @@
+use anyhow::{Context, Result};
+use std::os::unix::fs::PermissionsExt;
+use std::path::Path;
+
+use crate::configtxt::{self, ConfigTxt};
+use crate::firmware::BootLayout;
+use crate::{mount_at, unmount_quiet, MountPaths};
+
+/// Kernel command line flag that marks the trial boot
+const TRIAL_FLAG: &str = "rpi-fs-shrink.tryboot=1";
+
+const FSTAB_TRIAL: &str = "etc/fstab.tryboot";
+const GENERATOR: &str = "etc/systemd/system-generators/systemd-fstab-generator";
+const COMMIT_SCRIPT: &str = "usr/local/sbin/rpi-fs-shrink-commit";
+const COMMIT_UNIT_NAME: &str = "rpi-fs-shrink-commit.service";
+
+/// Options added to the new entries for the trial, so a partition that doesn't
+/// mount fails the commit instead of dropping the boot into emergency mode
+const TRIAL_MOUNT_OPTIONS: &str = "nofail,x-systemd.device-timeout=10s";
+
+/// Mark the entries rpi-fs-shrink appended as optional for the trial boot
+fn trial_fstab(fstab: &str) -> String {
+    let mut added = false;
+    let mut out = String::new();
+    for line in fstab.lines() {
+        match line.trim() {
+            crate::fstab::BLOCK_BEGIN => added = true,
+            crate::fstab::BLOCK_END => added = false,
+            _ => {}
+        }
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        if added && fields.len() >= 4 && !line.trim_start().starts_with('#') {
+            let mut fields: Vec<String> = fields.iter().map(|f| f.to_string()).collect();
+            fields[3] = format!("{},{}", fields[3], TRIAL_MOUNT_OPTIONS);
+            out.push_str(&fields.join("  "));
+        } else {
+            out.push_str(line);
+        }
+        out.push('\n');
+    }
+    out
+}
+
+/// Stage the layout for a trial boot instead of switching to it.
+///
+/// The new fstab moves to /etc/fstab.tryboot and `original_fstab` goes back to
+/// /etc/fstab, so normal boots keep the old layout, whose /var and /home are
+/// still in place on root. A trial boot (`sudo reboot '0 tryboot'`) has the
+/// firmware read tryboot.txt, whose command line carries a flag; an override of
+/// systemd's fstab generator then reads /etc/fstab.tryboot. Once /var and /home
+/// are up, a oneshot unit commits the layout. If the trial boot fails, the next
+/// boot is a normal one again.
+pub fn stage(root: &str, original_fstab: &str, migrated: &[&str], boot_device: &str, mounts: &MountPaths) -> Result<()> {
+    let layout = BootLayout::detect(root);
+
+    let fstab_path = format!("{}/etc/fstab", root);
+    let new_fstab = std::fs::read_to_string(&fstab_path).context(format!("Failed to read {}", fstab_path))?;
+    std::fs::write(format!("{}/{}", root, FSTAB_TRIAL), trial_fstab(&new_fstab))
+        .context("Failed to write /etc/fstab.tryboot")?;
+    std::fs::write(&fstab_path, original_fstab).context(format!("Failed to restore {}", fstab_path))?;
+    println!("  New fstab staged as /{}; /etc/fstab keeps the current layout", FSTAB_TRIAL);
+
+    write_executable(
+        &format!("{}/{}", root, GENERATOR),
+        &format!(
+            "#!/bin/sh\n\
+            # Installed by rpi-fs-shrink: read the staged fstab on the tryboot trial boot\n\
+            if grep -qw '{flag}' /proc/cmdline && [ -f /{trial} ]; then\n\
+            \x20   export SYSTEMD_FSTAB=/{trial}\n\
+            fi\n\
+            exec /usr/lib/systemd/system-generators/systemd-fstab-generator \"$@\"\n",
+            flag = TRIAL_FLAG,
+            trial = FSTAB_TRIAL
+        ),
+    )?;
+
+    write_executable(&format!("{}/{}", root, COMMIT_SCRIPT), &commit_script(layout, migrated))?;
+    install_commit_unit(root, migrated)?;
+    println!("  Commit service {} installed", COMMIT_UNIT_NAME);
+
+    let boot = mounts.boot();
+    mount_at(boot_device, &boot)?;
+    let result = write_tryboot_config(&boot, layout);
+    unmount_quiet(&boot);
+    result
+}
+
+fn write_executable(path: &str, content: &str) -> Result<()> {
+    if let Some(dir) = Path::new(path).parent() {
+        std::fs::create_dir_all(dir).context(format!("Failed to create {}", dir.display()))?;
+    }
+    std::fs::write(path, content).context(format!("Failed to write {}", path))?;
+    std::fs::set_permissions(path, std::fs::Permissions::from_mode(0o755))
+        .context(format!("Failed to set permissions on {}", path))
+}
+
+/// tryboot.txt is config.txt plus a command line with the trial flag
+fn write_tryboot_config(boot: &str, layout: BootLayout) -> Result<()> {
+    let config = std::fs::read_to_string(format!("{}/config.txt", boot)).context("Failed to read config.txt")?;
+    let cmdline = std::fs::read_to_string(format!("{}/cmdline.txt", boot)).context("Failed to read cmdline.txt")?;
+
+    configtxt::write_atomic(&format!("{}/cmdline-tryboot.txt", boot), &format!("{} {}\n", cmdline.trim(), TRIAL_FLAG))?;
+    let mut tryboot = ConfigTxt::parse(&config);
+    tryboot.set("cmdline", "cmdline-tryboot.txt");
+    configtxt::write_atomic(&format!("{}/tryboot.txt", boot), &tryboot.render())?;
+
+    println!("  {} and {} written", layout.path("tryboot.txt"), layout.path("cmdline-tryboot.txt"));
+    Ok(())
+}
+
+fn install_commit_unit(root: &str, migrated: &[&str]) -> Result<()> {
+    let mountpoints: Vec<String> = migrated.iter().map(|name| format!("/{}", name)).collect();
+    let unit = format!(
+        "[Unit]\n\
+        Description=Make the partition layout tried by rpi-fs-shrink permanent\n\
+        ConditionKernelCommandLine={flag}\n\
+        RequiresMountsFor={mounts}\n\
+        After=local-fs.target\n\
+        \n\
+        [Service]\n\
+        Type=oneshot\n\
+        ExecStart=/{script}\n\
+        # A trial that can't commit goes back to the old layout\n\
+        FailureAction=reboot\n\
+        \n\
+        [Install]\n\
+        WantedBy=multi-user.target\n",
+        flag = TRIAL_FLAG,
+        mounts = mountpoints.join(" "),
+        script = COMMIT_SCRIPT
+    );
+
+    let unit_dir = format!("{}/etc/systemd/system", root);
+    let wants_dir = format!("{}/multi-user.target.wants", unit_dir);
+    std::fs::create_dir_all(&wants_dir).context("Failed to create systemd unit directory")?;
+    std::fs::write(format!("{}/{}", unit_dir, COMMIT_UNIT_NAME), unit).context("Failed to write commit unit")?;
+
+    let link = format!("{}/{}", wants_dir, COMMIT_UNIT_NAME);
+    if std::fs::symlink_metadata(&link).is_err() {
+        std::os::unix::fs::symlink(format!("/etc/systemd/system/{}", COMMIT_UNIT_NAME), &link)
+            .context("Failed to enable commit unit")?;
+    }
+    Ok(())
+}
+
+/// Runs on the trial boot once the new mounts are up: switch fstab, remove the
+/// trial plumbing and delete the originals hidden below the new mounts
+fn commit_script(layout: BootLayout, migrated: &[&str]) -> String {
+    let mut script = String::from("#!/bin/sh\n# Installed by rpi-fs-shrink: commit the layout tried with tryboot\nset -e\n\n");
+    for name in migrated {
+        script.push_str(&format!("mountpoint -q /{}\n", name));
+    }
+
+    script.push_str(&format!(
+        "\ncp /etc/fstab /etc/fstab.pre-tryboot\n\
+        sed 's/,{options}//' /{trial} > /etc/fstab\n\
+        rm -f /{trial} /{generator}\n\
+        rm -f {boot}/tryboot.txt {boot}/cmdline-tryboot.txt\n\
+        \n\
+        # The original data is still on root below the new mounts\n\
+        old_root=/run/rpi-fs-shrink-root\n\
+        mkdir -p $old_root\n\
+        mount --bind / $old_root\n",
+        options = TRIAL_MOUNT_OPTIONS,
+        trial = FSTAB_TRIAL,
+        generator = GENERATOR,
+        boot = layout.mountpoint()
+    ));
+    for name in migrated {
+        script.push_str(&format!("find $old_root/{} -mindepth 1 -delete\n", name));
+    }
+    if migrated.contains(&"var") {
+        for (dir, mode) in crate::cleanup::VAR_SKELETON {
+            script.push_str(&format!("mkdir -p -m {:o} $old_root/var/{}\n", mode, dir));
+        }
+    }
+    script.push_str(&format!(
+        "umount $old_root\n\
+        \n\
+        rm -f /etc/systemd/system/multi-user.target.wants/{unit} /etc/systemd/system/{unit}\n\
+        rm -f /{script}\n\
+        echo 'rpi-fs-shrink: new partition layout committed'\n",
+        unit = COMMIT_UNIT_NAME,
+        script = COMMIT_SCRIPT
+    ));
+    script
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fstab::{BLOCK_BEGIN, BLOCK_END};
+
+    #[test]
+    fn only_the_new_entries_are_optional_on_the_trial_boot() {
+        let fstab = format!(
+            "proc            /proc           proc    defaults          0       0\n\
+            PARTUUID=6c586e13-01  /boot/firmware  vfat    defaults          0       2\n\
+            PARTUUID=6c586e13-02  /               ext4    defaults,noatime  0       1\n\
+            {}\n\
+            # /var and /home\n\
+            PARTUUID=6c586e13-03  /var  ext4  defaults,noatime  0  2\n\
+            PARTUUID=6c586e13-05  /home  ext4  defaults  0  2\n\
+            {}\n",
+            BLOCK_BEGIN, BLOCK_END
+        );
+        let trial = trial_fstab(&fstab);
+        let lines: Vec<&str> = trial.lines().collect();
+        let original: Vec<&str> = fstab.lines().collect();
+        // Entries before the block, the markers and comments stay as they were
+        assert_eq!(lines[..5], original[..5]);
+        assert_eq!(lines[7], BLOCK_END);
+        assert_eq!(lines[5], "PARTUUID=6c586e13-03  /var  ext4  defaults,noatime,nofail,x-systemd.device-timeout=10s  0  2");
+        assert_eq!(lines[6], "PARTUUID=6c586e13-05  /home  ext4  defaults,nofail,x-systemd.device-timeout=10s  0  2");
+        // What the commit script's sed does brings the new entries back
+        assert_eq!(trial.replace(&format!(",{}", TRIAL_MOUNT_OPTIONS), ""), fstab);
+        // Nothing rpi-fs-shrink added: nothing changes
+        let plain = "proc  /proc  proc  defaults  0  0\n";
+        assert_eq!(trial_fstab(plain), plain);
+    }
+
+    #[test]
+    fn commit_script_checks_mounts_and_cleans_the_boot_partition_it_uses() {
+        for layout in [BootLayout::Boot, BootLayout::Firmware] {
+            let script = commit_script(layout, &["var", "home"]);
+            assert!(script.starts_with("#!/bin/sh\n"));
+            assert!(script.contains("set -e\n"));
+            // Nothing is deleted unless the new partitions are mounted
+            let checks = script.find("mountpoint -q /var\nmountpoint -q /home\n").unwrap();
+            assert!(checks < script.find("-delete").unwrap());
+            assert!(script.contains(&format!("sed 's/,{}//' /{} > /etc/fstab\n", TRIAL_MOUNT_OPTIONS, FSTAB_TRIAL)));
+            let mountpoint = layout.mountpoint();
+            assert!(script.contains(&format!("rm -f {0}/tryboot.txt {0}/cmdline-tryboot.txt\n", mountpoint)), "{}", script);
+            assert!(script.contains("find $old_root/var -mindepth 1 -delete\nfind $old_root/home -mindepth 1 -delete\n"));
+            assert!(script.contains("mkdir -p -m 1777 $old_root/var/tmp\n"));
+            assert!(script.contains(&format!("rm -f /{}\n", COMMIT_SCRIPT)));
+        }
+        assert!(commit_script(BootLayout::Firmware, &["var"]).contains("rm -f /boot/firmware/tryboot.txt"));
+        assert!(!commit_script(BootLayout::Boot, &["var"]).contains("/boot/firmware"));
+        // Without /var there is no skeleton to put back
+        assert!(!commit_script(BootLayout::Firmware, &["home"]).contains("$old_root/var"));
+    }
+}
@@ -0,0 +1,109 @@
+use anyhow::{bail, Context, Result};
+use std::process::Command;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+const SERVICE: &str = "org.freedesktop.UDisks2";
+
+/// Formatting a large partition can take a while; gdbus defaults to 25 seconds
+const CALL_TIMEOUT_SECS: &str = "3600";
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+/// Route mounts and filesystem creation through UDisks2 for the rest of the run
+pub fn enable() -> Result<()> {
+    if !crate::command_exists("gdbus") {
+        bail!("--udisks needs gdbus (libglib2.0-bin package)");
+    }
+    let status = Command::new("gdbus")
+        .args(["introspect", "--system", "--dest", SERVICE, "--object-path", "/org/freedesktop/UDisks2/Manager"])
+        .output()
+        .context("Failed to run gdbus")?
+        .status;
+    if !status.success() {
+        bail!("UDisks2 is not running (udisks2 package, udisksd service)");
+    }
+    ENABLED.store(true, Ordering::SeqCst);
+    Ok(())
+}
+
+pub fn enabled() -> bool {
+    ENABLED.load(Ordering::SeqCst)
+}
+
+/// D-Bus object of a block device. UDisks2 escapes characters outside
+/// [A-Za-z0-9] in the kernel name as _xx.
+fn block_object(device: &str) -> String {
+    let resolved = std::fs::canonicalize(device).unwrap_or_else(|_| device.into());
+    let name = resolved.file_name().map(|n| n.to_string_lossy().into_owned()).unwrap_or_default();
+    let escaped: String = name
+        .bytes()
+        .map(|b| if b.is_ascii_alphanumeric() { (b as char).to_string() } else { format!("_{:02x}", b) })
+        .collect();
+    format!("/org/freedesktop/UDisks2/block_devices/{}", escaped)
+}
+
+/// Quote a string as a GVariant text literal
+fn variant_string(value: &str) -> String {
+    format!("'{}'", value.replace('\\', "\\\\").replace('\'', "\\'"))
+}
+
+/// gdbus arguments for calling `method` on the block device object of `device`.
+/// Interactive authorization lets polkit ask the desktop user for a password.
+fn call_args(device: &str, method: &str, args: &[String]) -> Vec<String> {
+    let mut all = vec![
+        "call".to_string(),
+        "--system".to_string(),
+        "--interactive".to_string(),
+        "--timeout".to_string(),
+        CALL_TIMEOUT_SECS.to_string(),
+        "--dest".to_string(),
+        SERVICE.to_string(),
+        "--object-path".to_string(),
+        block_object(device),
+        "--method".to_string(),
+        format!("{}.{}", SERVICE, method),
+    ];
+    all.extend(args.iter().cloned());
+    all
+}
+
+fn call(device: &str, method: &str, args: &[String]) -> Result<String> {
+    let output = Command::new("gdbus")
+        .args(call_args(device, method, args))
+        .output()
+        .context("Failed to run gdbus")?;
+
+    if !output.status.success() {
+        bail!("UDisks2 {} on {} failed: {}", method, device, String::from_utf8_lossy(&output.stderr).trim());
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).trim().to_string())
+}
+
+/// gdbus arguments that create a `fstype` filesystem on `device`, for running as a format job
+pub fn format_args(device: &str, fstype: &str, label: Option<&str>, discard: bool) -> Vec<String> {
+    let mut options = Vec::new();
+    if let Some(label) = label {
+        options.push(format!("'label': <{}>", variant_string(label)));
+    }
+    if !discard {
+        options.push("'no-discard': <true>".to_string());
+    }
+    let options = if options.is_empty() { "@a{sv} {}".to_string() } else { format!("{{{}}}", options.join(", ")) };
+    call_args(device, "Block.Format", &[variant_string(fstype), options])
+}
+
+/// Mount `device` wherever UDisks2 puts it (under /media or /run/media) and return the path
+pub fn mount(device: &str, read_only: bool) -> Result<String> {
+    let options = if read_only { "{'options': <'ro'>}" } else { "@a{sv} {}" };
+    let reply = call(device, "Filesystem.Mount", &[options.to_string()])?;
+    // The reply is a tuple holding the mount path: ('/media/user/rootfs',)
+    reply
+        .split('\'')
+        .nth(1)
+        .map(str::to_string)
+        .context(format!("Unexpected UDisks2 reply to mounting {}: {}", device, reply))
+}
+
+pub fn unmount(device: &str) -> Result<()> {
+    call(device, "Filesystem.Unmount", &["@a{sv} {}".to_string()]).map(|_| ())
+}
@@ -0,0 +1,180 @@
+//! Waiting for partition nodes through uevents instead of polling. The kernel
+//! announces each block device it adds or removes on a netlink socket, and
+//! udev announces it again once the node and its links are in /dev; each
+//! announcement is a cue to look at the node again, so the wait ends as soon
+//! as the node is there (or gone) and a timeout can say what was seen instead.
+
+use anyhow::{bail, Result};
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+/// How long the kernel and udev get to make or remove a partition node
+pub const NODE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Netlink multicast groups of kernel and udev uevents
+const KERNEL_GROUP: u32 = 1;
+const UDEV_GROUP: u32 = 2;
+
+/// One add, remove or change of a device
+#[derive(Debug, PartialEq, Eq)]
+pub struct Uevent {
+    pub action: String,
+    pub subsystem: String,
+    /// Node name without /dev/, e.g. `sda3`
+    pub devname: String,
+}
+
+/// Parse a uevent message, either the kernel's (`ACTION@DEVPATH` then
+/// `KEY=VALUE` fields) or udev's (a `libudev` header pointing at the fields).
+/// Fields are NUL-separated in both.
+pub fn parse(message: &[u8]) -> Option<Uevent> {
+    let fields = if message.starts_with(b"libudev\0") {
+        let word = |at: usize| message.get(at..at + 4).map(|b| u32::from_ne_bytes([b[0], b[1], b[2], b[3]]) as usize);
+        let (offset, length) = (word(16)?, word(20)?);
+        message.get(offset..offset + length)?
+    } else {
+        let header = message.iter().position(|&b| b == 0)?;
+        if !message[..header].contains(&b'@') {
+            return None;
+        }
+        &message[header + 1..]
+    };
+    let mut event = Uevent { action: String::new(), subsystem: String::new(), devname: String::new() };
+    for field in fields.split(|&b| b == 0) {
+        let field = String::from_utf8_lossy(field);
+        match field.split_once('=') {
+            Some(("ACTION", value)) => event.action = value.to_string(),
+            Some(("SUBSYSTEM", value)) => event.subsystem = value.to_string(),
+            Some(("DEVNAME", value)) => event.devname = value.trim_start_matches("/dev/").to_string(),
+            _ => {}
+        }
+    }
+    (!event.action.is_empty()).then_some(event)
+}
+
+/// A netlink socket subscribed to kernel and udev uevents
+pub struct Monitor {
+    socket: OwnedFd,
+}
+
+impl Monitor {
+    pub fn open() -> Result<Monitor> {
+        // SAFETY: socket takes no pointers; a valid descriptor is owned from here on
+        let fd = unsafe {
+            libc::socket(libc::AF_NETLINK, libc::SOCK_DGRAM | libc::SOCK_CLOEXEC, libc::NETLINK_KOBJECT_UEVENT)
+        };
+        if fd < 0 {
+            bail!("Failed to open a uevent socket: {}", std::io::Error::last_os_error());
+        }
+        let socket = unsafe { OwnedFd::from_raw_fd(fd) };
+        // SAFETY: sockaddr_nl is plain data, and bind reads exactly its size
+        let mut address: libc::sockaddr_nl = unsafe { std::mem::zeroed() };
+        address.nl_family = libc::AF_NETLINK as libc::sa_family_t;
+        address.nl_groups = KERNEL_GROUP | UDEV_GROUP;
+        let bound = unsafe {
+            libc::bind(
+                socket.as_raw_fd(),
+                &address as *const libc::sockaddr_nl as *const libc::sockaddr,
+                std::mem::size_of::<libc::sockaddr_nl>() as libc::socklen_t,
+            )
+        };
+        if bound != 0 {
+            bail!("Failed to subscribe to uevents: {}", std::io::Error::last_os_error());
+        }
+        Ok(Monitor { socket })
+    }
+
+    /// The next uevent, or None once `timeout` passes without one
+    fn next(&self, timeout: Duration) -> Result<Option<Uevent>> {
+        let deadline = Instant::now() + timeout;
+        loop {
+            let left = deadline.saturating_duration_since(Instant::now());
+            let mut poll = libc::pollfd { fd: self.socket.as_raw_fd(), events: libc::POLLIN, revents: 0 };
+            // SAFETY: one pollfd that outlives the call
+            let ready = unsafe { libc::poll(&mut poll, 1, left.as_millis().min(i32::MAX as u128) as i32) };
+            if ready < 0 {
+                let err = std::io::Error::last_os_error();
+                if err.kind() == std::io::ErrorKind::Interrupted {
+                    continue;
+                }
+                bail!("Failed to wait for uevents: {}", err);
+            }
+            if ready == 0 {
+                return Ok(None);
+            }
+            let mut buffer = [0u8; 8192];
+            // SAFETY: recv writes at most the buffer's length into it
+            let length = unsafe { libc::recv(self.socket.as_raw_fd(), buffer.as_mut_ptr().cast(), buffer.len(), 0) };
+            if length < 0 {
+                let err = std::io::Error::last_os_error();
+                if err.kind() == std::io::ErrorKind::Interrupted {
+                    continue;
+                }
+                // Events were dropped, which only means looking at the node again
+                if err.raw_os_error() == Some(libc::ENOBUFS) {
+                    return Ok(Some(Uevent { action: "overflow".to_string(), subsystem: "block".to_string(), devname: String::new() }));
+                }
+                bail!("Failed to read a uevent: {}", err);
+            }
+            if let Some(event) = parse(&buffer[..length as usize]) {
+                return Ok(Some(event));
+            }
+        }
+    }
+}
+
+/// Wait until `node` exists (`present`) or is gone. The socket is opened before
+/// the node is looked at, so an event between the two isn't missed.
+pub fn wait_for_node(node: &str, present: bool, timeout: Duration) -> Result<()> {
+    if Path::new(node).exists() == present {
+        return Ok(());
+    }
+    let monitor = Monitor::open()?;
+    let started = Instant::now();
+    let mut seen: Vec<String> = Vec::new();
+    while Path::new(node).exists() != present {
+        let left = timeout.saturating_sub(started.elapsed());
+        let Some(event) = monitor.next(left)? else {
+            bail!(
+                "Timed out after {}s waiting for {} to be {}; block device events seen meanwhile: {}",
+                timeout.as_secs(),
+                node,
+                if present { "created" } else { "removed" },
+                if seen.is_empty() { "none".to_string() } else { seen.join(", ") }
+            );
+        };
+        if event.subsystem == "block" && !event.devname.is_empty() {
+            seen.push(format!("{} {}", event.action, event.devname));
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn kernel_message_fields_are_read() {
+        let message = b"add@/devices/platform/mmc0/block/mmcblk0/mmcblk0p3\0ACTION=add\0DEVPATH=/devices/platform/mmc0/block/mmcblk0/mmcblk0p3\0SUBSYSTEM=block\0DEVNAME=mmcblk0p3\0DEVTYPE=partition\0SEQNUM=4242\0";
+        let event = parse(message).unwrap();
+        assert_eq!(event, Uevent { action: "add".into(), subsystem: "block".into(), devname: "mmcblk0p3".into() });
+        assert_eq!(parse(b"not a uevent"), None);
+    }
+
+    #[test]
+    fn udev_message_fields_follow_the_header() {
+        let fields = b"ACTION=remove\0SUBSYSTEM=block\0DEVNAME=/dev/sda4\0";
+        let mut message = b"libudev\0".to_vec();
+        message.extend(0xfeedcafeu32.to_be_bytes());
+        message.extend(40u32.to_ne_bytes());
+        message.extend(40u32.to_ne_bytes());
+        message.extend((fields.len() as u32).to_ne_bytes());
+        message.resize(40, 0);
+        message.extend(fields);
+        let event = parse(&message).unwrap();
+        assert_eq!((event.action.as_str(), event.devname.as_str()), ("remove", "sda4"));
+        assert_eq!(parse(&message[..30]), None);
+    }
+}
@@ -0,0 +1,126 @@
+//! How full each filesystem of the new layout is once the copies are done,
+//! read with statvfs while they are still mounted, so the split can be judged
+//! without mounting everything again after the run.
+
+use anyhow::{bail, Result};
+use serde_json::{json, Value};
+use std::ffi::CString;
+
+use crate::timing::format_bytes;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Usage {
+    /// Where the filesystem is mounted on the target: /, /var, /home, ...
+    pub target: String,
+    pub device: String,
+    pub size: u64,
+    pub used: u64,
+    /// What an unprivileged user can still write; root-reserved blocks aren't counted
+    pub available: u64,
+}
+
+impl Usage {
+    /// From statvfs counts in fragments of `fragment` bytes
+    fn from_counts(target: &str, device: &str, blocks: u64, free: u64, available: u64, fragment: u64) -> Usage {
+        Usage {
+            target: target.to_string(),
+            device: device.to_string(),
+            size: blocks * fragment,
+            used: blocks.saturating_sub(free) * fragment,
+            available: available * fragment,
+        }
+    }
+
+    /// Share of the usable space in use, rounded up the way df rounds it
+    pub fn percent_used(&self) -> u64 {
+        let usable = self.used + self.available;
+        if usable == 0 {
+            return 0;
+        }
+        (self.used * 100).div_ceil(usable)
+    }
+
+    pub fn to_json(&self) -> Value {
+        json!({
+            "target": self.target,
+            "device": self.device,
+            "size_bytes": self.size,
+            "used_bytes": self.used,
+            "available_bytes": self.available,
+        })
+    }
+}
+
+/// statvfs of the filesystem of `device` mounted at `path`, which is `target` on the new system
+pub fn measure(target: &str, device: &str, path: &str) -> Result<Usage> {
+    let c_path = CString::new(path)?;
+    // SAFETY: statvfs is plain data that the call fills in
+    let mut stat: libc::statvfs = unsafe { std::mem::zeroed() };
+    if unsafe { libc::statvfs(c_path.as_ptr(), &mut stat) } != 0 {
+        bail!("Failed to read the space used on {}: {}", path, std::io::Error::last_os_error());
+    }
+    Ok(Usage::from_counts(target, device, stat.f_blocks, stat.f_bfree, stat.f_bavail, stat.f_frsize))
+}
+
+/// The space the new layout ended up with, for the end of a run
+pub struct Summary {
+    pub filesystems: Vec<Usage>,
+    /// Root partition size before and after the run
+    pub root_before: u64,
+    pub root_after: u64,
+    /// Bytes the run copied or moved
+    pub data_bytes: u64,
+}
+
+impl Summary {
+    pub fn print(&self) {
+        println!("\nSpace after migration:");
+        let width = self.filesystems.iter().map(|usage| usage.target.len()).max().unwrap_or(0).max(5);
+        let device_width = self.filesystems.iter().map(|usage| usage.device.len()).max().unwrap_or(0).max(6);
+        println!("  {:<width$}  {:<device_width$}  {:>10}  {:>10}  {:>10}  {:>4}", "Mount", "Device", "Size", "Used", "Free", "Use%");
+        for usage in &self.filesystems {
+            println!(
+                "  {:<width$}  {:<device_width$}  {:>10}  {:>10}  {:>10}  {:>3}%",
+                usage.target,
+                usage.device,
+                format_bytes(usage.size),
+                format_bytes(usage.used),
+                format_bytes(usage.available),
+                usage.percent_used()
+            );
+        }
+        if self.root_before > self.root_after {
+            println!(
+                "  Root partition: {} before, {} now; {} went to the new partitions",
+                format_bytes(self.root_before),
+                format_bytes(self.root_after),
+                format_bytes(self.root_before - self.root_after)
+            );
+        }
+        println!("  Data copied or moved: {}", format_bytes(self.data_bytes));
+    }
+
+    pub fn to_json(&self) -> Value {
+        json!({
+            "filesystems": self.filesystems.iter().map(Usage::to_json).collect::<Vec<_>>(),
+            "root_bytes_before": self.root_before,
+            "root_bytes_after": self.root_after,
+            "data_bytes": self.data_bytes,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn counts_become_bytes_and_df_percentages() {
+        // 1000 4K blocks, 400 free of which 350 for unprivileged users
+        let usage = Usage::from_counts("/home", "/dev/sda4", 1000, 400, 350, 4096);
+        assert_eq!((usage.size, usage.used, usage.available), (4_096_000, 2_457_600, 1_433_600));
+        // 600 / 950, rounded up
+        assert_eq!(usage.percent_used(), 64);
+        assert_eq!(Usage::from_counts("/", "/dev/sda2", 0, 0, 0, 4096).percent_used(), 0);
+    }
+}
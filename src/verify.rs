@@ -0,0 +1,77 @@
+use anyhow::{bail, Context, Result};
+use std::collections::BTreeMap;
+use std::process::Command;
+
+/// Relative file path -> SHA-256 of its contents
+pub type Manifest = BTreeMap<String, String>;
+
+/// Hash every regular file below `root` (without crossing into other filesystems)
+pub fn build_manifest(root: &str) -> Result<Manifest> {
+    let script = "find . -xdev -type f -print0 | sort -z | xargs -0 -r sha256sum";
+    let output = Command::new("sh")
+        .args(["-c", script])
+        .current_dir(root)
+        .output()
+        .context(format!("Failed to hash files under {}", root))?;
+
+    if !output.status.success() {
+        bail!("Hashing files under {} failed: {}", root, String::from_utf8_lossy(&output.stderr).trim());
+    }
+
+    let mut manifest = Manifest::new();
+    for line in String::from_utf8_lossy(&output.stdout).lines() {
+        // "<hash>  ./path"; sha256sum prefixes the line with '\' when it escaped the name
+        let line = line.strip_prefix('\\').unwrap_or(line);
+        if let Some((hash, path)) = line.split_once("  ") {
+            manifest.insert(path.trim_start_matches("./").to_string(), hash.to_string());
+        }
+    }
+
+    Ok(manifest)
+}
+
+/// Entries of `manifest` below `prefix/`, with the prefix removed
+pub fn subtree(manifest: &Manifest, prefix: &str) -> Manifest {
+    let prefix = format!("{}/", prefix.trim_matches('/'));
+    manifest
+        .iter()
+        .filter_map(|(path, hash)| path.strip_prefix(&prefix).map(|rest| (rest.to_string(), hash.clone())))
+        .collect()
+}
+
+/// Human readable differences between two manifests
+pub fn compare_manifests(before: &Manifest, after: &Manifest) -> Vec<String> {
+    let mut differences = Vec::new();
+
+    for (path, hash) in before {
+        match after.get(path) {
+            None => differences.push(format!("missing: {}", path)),
+            Some(new_hash) if new_hash != hash => differences.push(format!("changed: {}", path)),
+            _ => {}
+        }
+    }
+    for path in after.keys() {
+        if !before.contains_key(path) {
+            differences.push(format!("added: {}", path));
+        }
+    }
+
+    differences
+}
+
+/// Fail with the first differences listed if the manifests don't match
+pub fn check_manifests(label: &str, before: &Manifest, after: &Manifest) -> Result<()> {
+    let differences = compare_manifests(before, after);
+    if differences.is_empty() {
+        println!("  {}: {} files verified, all checksums match", label, before.len());
+        return Ok(());
+    }
+
+    for difference in differences.iter().take(20) {
+        println!("    {}", difference);
+    }
+    if differences.len() > 20 {
+        println!("    ... and {} more", differences.len() - 20);
+    }
+    bail!("{}: {} differences found by deep verification", label, differences.len())
+}
@@ -0,0 +1,214 @@
+use anyhow::{Context, Result};
+
+use crate::timing::format_bytes;
+use crate::{sysfs, DiskInfo, PartitionLayout, SECTOR_SIZE};
+
+/// Characters across the bar that stands for the whole disk
+const BAR_WIDTH: u64 = 72;
+
+/// A partition, or space between partitions, in a drawn layout
+struct Segment {
+    name: String,
+    start: u64,
+    end: u64,
+    /// Fill character in the bar, and color in the SVG
+    fill: char,
+    color: &'static str,
+}
+
+fn segment(name: &str, start: u64, end: u64) -> Segment {
+    let (fill, color) = match name {
+        "boot" => ('B', "#e8a33d"),
+        "recovery" => ('C', "#b07cc6"),
+        "root" => ('R', "#d9534f"),
+        "swap" => ('S', "#8c8c8c"),
+        "/var" => ('V', "#5bc0de"),
+        "containers" => ('K', "#2e8b9a"),
+        "CIDATA" => ('I', "#f0d04b"),
+        "/home" => ('H', "#5cb85c"),
+        "free" => ('.', "#ffffff"),
+        _ => ('#', "#a0a0a0"),
+    };
+    Segment { name: name.to_string(), start, end, fill, color }
+}
+
+/// The partitions on the disk now, named by role where known
+fn current(disk_info: &DiskInfo) -> Vec<Segment> {
+    let extended = crate::mbr::existing_extended(&disk_info.device);
+    crate::roles::partition_numbers(&disk_info.device)
+        .into_iter()
+        .filter(|&n| Some(n) != extended)
+        .filter_map(|n| {
+            let (start, end) = crate::get_partition_bounds(&disk_info.device, n).ok()?;
+            let name = if n == disk_info.roles.boot {
+                "boot".to_string()
+            } else if n == disk_info.roles.root {
+                "root".to_string()
+            } else {
+                let fstype = sysfs::fstype(&sysfs::partition_path(&disk_info.device, n)).unwrap_or_default();
+                if fstype.is_empty() { format!("partition {}", n) } else { format!("partition {} ({})", n, fstype) }
+            };
+            Some(segment(&name, start, end))
+        })
+        .collect()
+}
+
+/// The partitions the plan leaves on the disk
+fn planned(boot: (u64, u64), layout: &PartitionLayout) -> Vec<Segment> {
+    let mut segments = vec![segment("boot", boot.0, boot.1)];
+    let optional = [
+        ("recovery", layout.recovery_size_bytes, layout.recovery_start, layout.recovery_end),
+        ("swap", layout.swap_size_bytes, layout.swap_start, layout.swap_end),
+        ("/var", layout.var_size_bytes, layout.var_start, layout.var_end),
+        ("containers", layout.containers_size_bytes, layout.containers_start, layout.containers_end),
+        ("CIDATA", layout.cidata_size_bytes, layout.cidata_start, layout.cidata_end),
+    ];
+    segments.push(segment("root", layout.root_start, layout.root_end));
+    for (name, bytes, start, end) in optional {
+        if bytes > 0 {
+            segments.push(segment(name, start, end));
+        }
+    }
+    segments.push(segment("/home", layout.home_start, layout.home_end));
+    segments.sort_by_key(|segment| segment.start);
+    segments
+}
+
+/// Partitions in disk order with the free space between them, and any pairs
+/// that share sectors. Gaps below one bar character are left out.
+fn with_free_space(segments: Vec<Segment>, disk_sectors: u64) -> (Vec<Segment>, Vec<String>) {
+    let mut segments = segments;
+    segments.sort_by_key(|segment| segment.start);
+    let visible = disk_sectors / BAR_WIDTH;
+    let mut filled = Vec::new();
+    let mut overlaps = Vec::new();
+    let mut position = 0;
+    for (index, segment) in segments.iter().enumerate() {
+        for earlier in &segments[..index] {
+            if segment.start <= earlier.end && earlier.start <= segment.end {
+                overlaps.push(format!(
+                    "{} and {} share sectors {} - {}",
+                    earlier.name,
+                    segment.name,
+                    segment.start.max(earlier.start),
+                    segment.end.min(earlier.end)
+                ));
+            }
+        }
+        if segment.start > position && segment.start - position >= visible {
+            filled.push(self::segment("free", position, segment.start - 1));
+        }
+        position = position.max(segment.end + 1);
+    }
+    if disk_sectors > position && disk_sectors - position >= visible {
+        filled.push(segment("free", position, disk_sectors - 1));
+    }
+    filled.extend(segments);
+    filled.sort_by_key(|segment| segment.start);
+    (filled, overlaps)
+}
+
+/// Bar column a sector falls in
+fn column(sector: u64, disk_sectors: u64) -> u64 {
+    (sector as u128 * BAR_WIDTH as u128 / disk_sectors.max(1) as u128) as u64
+}
+
+fn print_bar(title: &str, segments: Vec<Segment>, disk_sectors: u64, extended: Option<(u64, u64)>) {
+    let (segments, overlaps) = with_free_space(segments, disk_sectors);
+    println!("\n{}:", title);
+    let mut bar = String::new();
+    for segment in &segments {
+        // Every partition shows, however small; the bar grows by the odd column
+        let width = (column(segment.end + 1, disk_sectors) - column(segment.start, disk_sectors)).max(1);
+        bar.extend(std::iter::repeat_n(segment.fill, width as usize));
+    }
+    println!("  |{}|", bar);
+    if let Some((start, end)) = extended {
+        let from = column(start, disk_sectors);
+        let width = (column(end + 1, disk_sectors) - from).max(1);
+        println!("   {}{}  extended", " ".repeat(from as usize), "=".repeat(width as usize));
+    }
+    for segment in &segments {
+        println!(
+            "  {} {:<24} {:>12} - {:<12} {:>10}",
+            segment.fill,
+            segment.name,
+            segment.start,
+            segment.end,
+            format_bytes((segment.end - segment.start + 1) * SECTOR_SIZE)
+        );
+    }
+    for overlap in &overlaps {
+        println!("  Warning: {}", overlap);
+    }
+}
+
+/// Draw the current and the planned layout as bars scaled to the disk
+pub fn print_layouts(disk_info: &DiskInfo, boot: (u64, u64), layout: &PartitionLayout) {
+    print_bar(&format!("Current layout of {} ({})", disk_info.device, format_bytes(disk_info.size_bytes)), current(disk_info), disk_info.size_sectors, None);
+    let extended = layout.logical().then_some((layout.extended_start, layout.extended_end));
+    print_bar("Planned layout", planned(boot, layout), disk_info.size_sectors, extended);
+    let unused = disk_info.size_sectors - 1 - layout.home_end;
+    println!(
+        "  /home takes the rest: {} ({} unused behind it)",
+        format_bytes(layout.home_size_bytes),
+        format_bytes(unused * SECTOR_SIZE)
+    );
+}
+
+/// Render both layouts as an SVG, one bar each, for reports
+fn svg(disk_info: &DiskInfo, boot: (u64, u64), layout: &PartitionLayout) -> String {
+    const WIDTH: f64 = 900.0;
+    const BAR_HEIGHT: f64 = 40.0;
+    let scale = |sector: u64| sector as f64 * WIDTH / disk_info.size_sectors.max(1) as f64;
+    let mut svg = format!(
+        "<svg xmlns=\"http://www.w3.org/2000/svg\" width=\"{}\" height=\"220\" font-family=\"sans-serif\" font-size=\"12\">\n",
+        WIDTH + 20.0
+    );
+    svg += &format!("<text x=\"10\" y=\"18\" font-size=\"14\">{} ({})</text>\n", escape(&disk_info.device), format_bytes(disk_info.size_bytes));
+    let rows = [("Current", current(disk_info), 40.0), ("Planned", planned(boot, layout), 130.0)];
+    for (title, segments, y) in rows {
+        let (segments, _) = with_free_space(segments, disk_info.size_sectors);
+        svg += &format!("<text x=\"10\" y=\"{}\">{}</text>\n", y - 6.0, title);
+        for segment in &segments {
+            let x = 10.0 + scale(segment.start);
+            let width = (scale(segment.end + 1) - scale(segment.start)).max(1.0);
+            svg += &format!(
+                "<rect x=\"{:.1}\" y=\"{}\" width=\"{:.1}\" height=\"{}\" fill=\"{}\" stroke=\"#333\"><title>{}: sectors {} - {}, {}</title></rect>\n",
+                x,
+                y,
+                width,
+                BAR_HEIGHT,
+                segment.color,
+                escape(&segment.name),
+                segment.start,
+                segment.end,
+                format_bytes((segment.end - segment.start + 1) * SECTOR_SIZE)
+            );
+            // Names only where they fit
+            if width > segment.name.len() as f64 * 7.0 {
+                svg += &format!("<text x=\"{:.1}\" y=\"{}\">{}</text>\n", x + 3.0, y + 25.0, escape(&segment.name));
+            }
+        }
+    }
+    if layout.logical() {
+        let x = 10.0 + scale(layout.extended_start);
+        let width = scale(layout.extended_end + 1) - scale(layout.extended_start);
+        svg += &format!(
+            "<rect x=\"{:.1}\" y=\"174\" width=\"{:.1}\" height=\"6\" fill=\"#333\"/>\n<text x=\"{:.1}\" y=\"196\">extended</text>\n",
+            x, width, x
+        );
+    }
+    svg += "</svg>\n";
+    svg
+}
+
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;")
+}
+
+pub fn write_svg(path: &str, disk_info: &DiskInfo, boot: (u64, u64), layout: &PartitionLayout) -> Result<()> {
+    std::fs::write(path, svg(disk_info, boot, layout)).context(format!("Failed to write {}", path))?;
+    println!("\nLayout diagram written to {}", path);
+    Ok(())
+}
@@ -0,0 +1,74 @@
+use std::path::Path;
+
+/// Health indicators an MMC-class device exposes through sysfs
+#[derive(Debug)]
+pub struct WearReport {
+    /// "MMC" (eMMC) or "SD"
+    pub card_type: String,
+    /// Estimated life used per memory type, in 10% steps (1 = 0-10%, 11 = exceeded)
+    pub life_time: Vec<u8>,
+    /// Reserved block consumption: 1 normal, 2 warning (80%), 3 urgent (90%)
+    pub pre_eol: Option<u8>,
+}
+
+impl WearReport {
+    pub fn near_end_of_life(&self) -> bool {
+        self.pre_eol.is_some_and(|v| v >= 2) || self.life_time.iter().any(|&v| v >= 9)
+    }
+}
+
+fn parse_hex(value: &str) -> Option<u8> {
+    u8::from_str_radix(value.trim().trim_start_matches("0x"), 16).ok()
+}
+
+/// Read wear registers for an SD card or eMMC. Returns None for other disks.
+/// eMMC 5.0+ reports DEVICE_LIFE_TIME_EST and PRE_EOL_INFO; SD cards have no
+/// standard register, so only the card type comes back for them.
+pub fn read_wear(device: &str) -> Option<WearReport> {
+    let name = Path::new(device).file_name()?.to_string_lossy().to_string();
+    let sysfs = format!("/sys/block/{}/device", name);
+    let card_type = std::fs::read_to_string(format!("{}/type", sysfs)).ok()?.trim().to_string();
+
+    let life_time = std::fs::read_to_string(format!("{}/life_time", sysfs))
+        .map(|value| value.split_whitespace().filter_map(parse_hex).filter(|&v| v > 0).collect())
+        .unwrap_or_default();
+    let pre_eol = std::fs::read_to_string(format!("{}/pre_eol_info", sysfs))
+        .ok()
+        .and_then(|value| parse_hex(&value))
+        .filter(|&v| v > 0);
+
+    Some(WearReport { card_type, life_time, pre_eol })
+}
+
+fn describe_life(value: u8) -> String {
+    match value {
+        1..=10 => format!("{}-{}% used", (value - 1) * 10, value * 10),
+        11 => "exceeded estimated life".to_string(),
+        _ => format!("unknown (0x{:02x})", value),
+    }
+}
+
+pub fn print_wear(report: &WearReport) {
+    println!("  Media health ({}):", report.card_type);
+    if report.life_time.is_empty() && report.pre_eol.is_none() {
+        println!("    Not reported by this card");
+        return;
+    }
+
+    for (index, value) in report.life_time.iter().enumerate() {
+        println!("    Life time estimate {}: {}", (b'A' + index as u8) as char, describe_life(*value));
+    }
+    if let Some(pre_eol) = report.pre_eol {
+        let state = match pre_eol {
+            1 => "normal",
+            2 => "warning (80% of reserved blocks consumed)",
+            3 => "urgent (90% of reserved blocks consumed)",
+            _ => "unknown",
+        };
+        println!("    Pre-EOL: {}", state);
+    }
+
+    if report.near_end_of_life() {
+        println!("    WARNING: this media is near the end of its life; replace it instead of migrating onto it");
+    }
+}
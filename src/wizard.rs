@@ -0,0 +1,265 @@
+//! Guided setup (`wizard`): a few questions about the target, a proposed layout
+//! with the reason for each choice, the plan as a dry run, and the run itself on
+//! confirmation. The equivalent command line is printed, so the next disk can be
+//! done without the questions.
+
+use anyhow::{bail, Context, Result};
+use clap::Parser;
+use std::io::Write;
+use std::process::Command;
+
+const GIB: u64 = 1024 * 1024 * 1024;
+
+/// What the Pi is for, which decides how space is shared out
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Purpose {
+    Desktop,
+    Server,
+    Containers,
+    Appliance,
+}
+
+const PURPOSES: &[(Purpose, &str)] = &[
+    (Purpose::Desktop, "Desktop or general use"),
+    (Purpose::Server, "Server (web, files, databases)"),
+    (Purpose::Containers, "Docker or Podman host"),
+    (Purpose::Appliance, "Appliance or kiosk (writes little, runs for years)"),
+];
+
+/// Options of a run and why each was chosen
+#[derive(Debug, Default)]
+struct Proposal {
+    args: Vec<String>,
+    reasons: Vec<String>,
+}
+
+impl Proposal {
+    fn add(&mut self, option: &str, value: String, reason: String) {
+        self.args.push(option.to_string());
+        self.args.push(value);
+        self.reasons.push(reason);
+    }
+}
+
+/// A size as the command line takes it: whole GiB where it is one, else MiB
+fn size_text(bytes: u64) -> String {
+    if bytes % GIB == 0 { format!("{}G", bytes / GIB) } else { format!("{}M", bytes / (1024 * 1024)) }
+}
+
+/// The layout for a disk of `disk_bytes` holding a Pi with `ram` bytes used for `purpose`
+fn propose(purpose: Purpose, disk_bytes: u64, ram: u64, sd_card: bool) -> Proposal {
+    let mut proposal = Proposal::default();
+    let disk_gb = disk_bytes / GIB;
+
+    // Root holds the OS and installed software; what's left goes to /home
+    let root = match purpose {
+        Purpose::Desktop if disk_gb >= 128 => 32,
+        Purpose::Appliance => 8,
+        _ => 16,
+    };
+    let root = root.min((disk_gb / 2).max(crate::MIN_ROOT_SIZE_GB)).clamp(crate::MIN_ROOT_SIZE_GB, crate::MAX_ROOT_SIZE_GB);
+    let reason = match purpose {
+        Purpose::Desktop => "room for a desktop, browsers and office software",
+        Purpose::Appliance => "an appliance only needs the OS and its one program",
+        _ => "the OS and server software; data goes to /var and /home",
+    };
+    proposal.add("--root-size", format!("{}G", root), format!("root {}G: {}", root, reason));
+    let mut used = root * GIB;
+
+    if sd_card || purpose == Purpose::Appliance {
+        proposal.add(
+            "--swap-mode",
+            "zram".to_string(),
+            if sd_card {
+                "swap in compressed RAM (zram): swapping to an SD card wears it out".to_string()
+            } else {
+                "swap in compressed RAM (zram): an appliance should not wear its disk".to_string()
+            },
+        );
+    } else {
+        let swap = crate::swap::auto_size(ram, false);
+        proposal.add("--swap-size", size_text(swap), format!("swap partition {}: sized from {} of RAM", size_text(swap), size_text(ram)));
+        used += swap;
+    }
+
+    // The tool doesn't put /var on SD cards, whose random writes are slow
+    if sd_card {
+        proposal.reasons.push("no /var partition: /var stays on root on SD cards".to_string());
+        return proposal;
+    }
+    let var = match purpose {
+        Purpose::Desktop => 8,
+        Purpose::Server | Purpose::Containers => 16,
+        Purpose::Appliance => 4,
+    } * GIB;
+    // /home keeps at least a quarter of the disk
+    if used + var > disk_bytes * 3 / 4 {
+        proposal.reasons.push("no /var partition: the disk is too small to split further".to_string());
+        return proposal;
+    }
+    let reason = match purpose {
+        Purpose::Server => "logs and databases can't fill root",
+        Purpose::Containers => "logs and container state can't fill root",
+        _ => "logs and caches can't fill root",
+    };
+    proposal.add("--var-size", size_text(var), format!("/var {} (btrfs): {}", size_text(var), reason));
+    used += var;
+
+    if purpose == Purpose::Containers {
+        let containers = if disk_gb >= 500 { 64 * GIB } else { 32 * GIB };
+        if used + containers <= disk_bytes * 3 / 4 {
+            proposal.add(
+                "--container-size",
+                size_text(containers),
+                format!("container storage {}: images and volumes get their own filesystem", size_text(containers)),
+            );
+        } else {
+            proposal.reasons.push("no container partition: the disk is too small; images stay in /var".to_string());
+        }
+    }
+    proposal
+}
+
+/// Ask `question`; an empty answer takes `default`
+fn ask(question: &str, default: &str) -> Result<String> {
+    if default.is_empty() {
+        print!("{}: ", question);
+    } else {
+        print!("{} [{}]: ", question, default);
+    }
+    std::io::stdout().flush()?;
+    let mut answer = String::new();
+    if std::io::stdin().read_line(&mut answer)? == 0 {
+        bail!("No answer (end of input); the wizard needs a terminal");
+    }
+    let answer = answer.trim();
+    Ok(if answer.is_empty() { default.to_string() } else { answer.to_string() })
+}
+
+fn ask_purpose() -> Result<Purpose> {
+    println!("\nWhat is this Pi for?");
+    for (index, (_, description)) in PURPOSES.iter().enumerate() {
+        println!("  {}) {}", index + 1, description);
+    }
+    loop {
+        let answer = ask("Choose 1-4", "1")?;
+        if let Some((purpose, _)) = answer.parse::<usize>().ok().and_then(|n| n.checked_sub(1)).and_then(|n| PURPOSES.get(n)) {
+            return Ok(*purpose);
+        }
+        println!("  Please answer with a number from 1 to {}", PURPOSES.len());
+    }
+}
+
+/// Disks lsblk knows, for choosing the device
+fn list_disks() {
+    let output = Command::new("lsblk").args(["-d", "-e", "7", "-o", "NAME,SIZE,TRAN,MODEL"]).output();
+    if let Ok(output) = output
+        && output.status.success()
+    {
+        println!("Disks:");
+        for line in String::from_utf8_lossy(&output.stdout).lines() {
+            println!("  {}", line);
+        }
+    }
+}
+
+/// Ask the questions, propose a layout, show its plan and run it on confirmation
+pub fn run_wizard(device: Option<String>) -> Result<()> {
+    println!("rpi-fs-shrink wizard: a few questions, then a proposed layout to review.\n");
+    let device = match device {
+        Some(device) => device,
+        None => {
+            list_disks();
+            let device = ask("\nDisk or image to set up (e.g., /dev/sda)", "")?;
+            if device.is_empty() {
+                bail!("No disk given");
+            }
+            device
+        }
+    };
+    crate::policy::check_device(&crate::policy::load_config()?.devices, &device)?;
+    let disk_bytes = crate::template::disk_bytes(&device).context(format!("Could not read the size of {}", device))?;
+    println!("{}: {}", device, crate::timing::format_bytes(disk_bytes));
+
+    let purpose = ask_purpose()?;
+
+    let ram_default = crate::swap::target_ram(None, false).map(|(ram, _)| size_text(ram)).unwrap_or_else(|_| "4G".to_string());
+    let ram = loop {
+        let answer = ask("\nHow much RAM does the target Pi have?", &ram_default)?;
+        match crate::parse_size(&answer) {
+            Ok(ram) if ram > 0 => break ram,
+            _ => println!("  Please give a size such as 1G, 4G or 8G"),
+        }
+    };
+
+    let sd_default = if crpart::is_sd_card(&device) { "sd" } else { "ssd" };
+    let sd_card = loop {
+        let answer = ask("\nIs the disk an SD card or an SSD/USB disk? (sd/ssd)", sd_default)?;
+        match answer.to_ascii_lowercase().as_str() {
+            "sd" => break true,
+            "ssd" | "usb" | "nvme" => break false,
+            _ => println!("  Please answer sd or ssd"),
+        }
+    };
+
+    let mut proposal = propose(purpose, disk_bytes, ram, sd_card);
+    proposal.args.extend(["--target-ram".to_string(), size_text(ram)]);
+    println!("\nProposed layout:");
+    for reason in &proposal.reasons {
+        println!("  - {}", reason);
+    }
+    println!("  - /home: the rest of the disk");
+    let mut args = vec!["-d".to_string(), device.clone()];
+    args.extend(proposal.args);
+    println!("\nEquivalent command:\n  sudo rpi-fs-shrink {}", args.join(" "));
+
+    println!("\nThe plan for this layout (dry run, nothing changes yet):\n");
+    let parse = |dry_run: bool| -> Result<crate::Args> {
+        let mut parsed = crate::Args::try_parse_from(std::iter::once("rpi-fs-shrink".to_string()).chain(args.clone()))?;
+        parsed.dry_run = dry_run;
+        Ok(parsed)
+    };
+    crate::run_operation(parse(true)?)?;
+
+    let answer = ask(&format!("\nApply this layout to {}? Type 'yes' to go ahead", device), "no")?;
+    if !answer.eq_ignore_ascii_case("yes") {
+        println!("Nothing changed. Run the command above to apply it later.");
+        return Ok(());
+    }
+    crate::privilege::require("Applying the layout")?;
+    crate::run_operation(parse(false)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn option<'a>(proposal: &'a Proposal, name: &str) -> Option<&'a str> {
+        proposal.args.iter().position(|arg| arg == name).map(|index| proposal.args[index + 1].as_str())
+    }
+
+    #[test]
+    fn sd_cards_get_zram_and_no_var() {
+        let proposal = propose(Purpose::Server, 64 * GIB, 4 * GIB, true);
+        assert_eq!(option(&proposal, "--root-size"), Some("16G"));
+        assert_eq!(option(&proposal, "--swap-mode"), Some("zram"));
+        assert_eq!(option(&proposal, "--swap-size"), None);
+        assert_eq!(option(&proposal, "--var-size"), None);
+    }
+
+    #[test]
+    fn container_hosts_on_ssd_get_container_storage() {
+        let proposal = propose(Purpose::Containers, 256 * GIB, 8 * GIB, false);
+        assert_eq!(option(&proposal, "--swap-size"), Some("8G"));
+        assert_eq!(option(&proposal, "--var-size"), Some("16G"));
+        assert_eq!(option(&proposal, "--container-size"), Some("32G"));
+        assert_eq!(proposal.reasons.len(), 4);
+    }
+
+    #[test]
+    fn small_disks_keep_room_for_home() {
+        let proposal = propose(Purpose::Desktop, 16 * GIB, 2 * GIB, false);
+        assert_eq!(option(&proposal, "--root-size"), Some("8G"));
+        assert_eq!(option(&proposal, "--var-size"), None);
+    }
+}
@@ -0,0 +1,95 @@
+//! End-to-end run against a disk image file, the way a privileged CI container uses the tool.
+//!
+//! Needs root, loop device support, parted, dosfstools and e2fsprogs:
+//!
+//!     sudo -E cargo test --test container_image -- --ignored
+
+use std::path::Path;
+use std::process::{Command, Stdio};
+
+const IMAGE_SIZE: u64 = 20 * 1024 * 1024 * 1024;
+
+fn run(cmd: &str, args: &[&str]) -> String {
+    let output = Command::new(cmd)
+        .args(args)
+        .output()
+        .unwrap_or_else(|e| panic!("failed to run {}: {}", cmd, e));
+    assert!(
+        output.status.success(),
+        "{} {:?} failed: {}",
+        cmd,
+        args,
+        String::from_utf8_lossy(&output.stderr)
+    );
+    String::from_utf8_lossy(&output.stdout).trim().to_string()
+}
+
+/// Build a Raspberry Pi OS shaped image: FAT boot partition and an ext4 root using the rest
+fn build_image(image: &str, work: &str) {
+    let file = std::fs::File::create(image).unwrap();
+    file.set_len(IMAGE_SIZE).unwrap();
+
+    run("parted", &["-s", image, "mklabel", "msdos"]);
+    run("parted", &["-s", image, "mkpart", "primary", "fat32", "1MiB", "257MiB"]);
+    run("parted", &["-s", image, "mkpart", "primary", "ext4", "257MiB", "100%"]);
+
+    let loop_device = run("losetup", &["--find", "--show", "--partscan", image]);
+    run("mkfs.vfat", &[&format!("{}p1", loop_device)]);
+    run("mkfs.ext4", &["-F", "-q", &format!("{}p2", loop_device)]);
+
+    let root = format!("{}/build-root", work);
+    std::fs::create_dir_all(&root).unwrap();
+    run("mount", &[&format!("{}p2", loop_device), &root]);
+    std::fs::create_dir_all(format!("{}/etc", root)).unwrap();
+    std::fs::create_dir_all(format!("{}/home/pi", root)).unwrap();
+    std::fs::create_dir_all(format!("{}/var/log", root)).unwrap();
+    std::fs::write(format!("{}/etc/fstab", root), "proc  /proc  proc  defaults  0  0\n").unwrap();
+    std::fs::write(format!("{}/home/pi/marker", root), "hello\n").unwrap();
+    run("umount", &[&root]);
+    run("losetup", &["--detach", &loop_device]);
+}
+
+#[test]
+#[ignore = "needs root and loop devices; run with --ignored inside a privileged container"]
+fn shrinks_image_file_with_custom_mount_base() {
+    let work = std::env::temp_dir().join(format!("rpi-fs-shrink-e2e-{}", std::process::id()));
+    std::fs::create_dir_all(&work).unwrap();
+    let work = work.to_string_lossy().to_string();
+    let image = format!("{}/disk.img", work);
+    let mount_base = format!("{}/mnt", work);
+
+    build_image(&image, &work);
+
+    // No prompts: --unattended skips the Enter confirmations and --yes any deletion question,
+    // so a question added later fails the run instead of waiting on stdin
+    let status = Command::new(env!("CARGO_BIN_EXE_rpi-fs-shrink"))
+        .args(["-d", &image, "-r", "8G", "--mount-base", &mount_base, "--unattended", "--yes"])
+        .stdin(Stdio::null())
+        .status()
+        .expect("failed to start rpi-fs-shrink");
+    assert!(status.success(), "rpi-fs-shrink failed");
+
+    let table = run("parted", &["-s", &image, "unit", "s", "print"]);
+    let partitions = table
+        .lines()
+        .filter(|line| line.trim_start().chars().next().is_some_and(|c| c.is_ascii_digit()))
+        .count();
+    assert_eq!(partitions, 3, "expected boot, root and home:\n{}", table);
+
+    let loop_device = run("losetup", &["--find", "--show", "--partscan", &image]);
+    let check = format!("{}/check", work);
+    std::fs::create_dir_all(&check).unwrap();
+
+    run("mount", &[&format!("{}p2", loop_device), &check]);
+    let fstab = std::fs::read_to_string(format!("{}/etc/fstab", check)).unwrap();
+    run("umount", &[&check]);
+
+    run("mount", &[&format!("{}p3", loop_device), &check]);
+    let marker = Path::new(&check).join("pi/marker").exists();
+    run("umount", &[&check]);
+    run("losetup", &["--detach", &loop_device]);
+    let _ = std::fs::remove_dir_all(&work);
+
+    assert!(fstab.contains("/home"), "fstab has no /home entry:\n{}", fstab);
+    assert!(marker, "/home data was not migrated");
+}
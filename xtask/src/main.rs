@@ -0,0 +1,154 @@
+//! Build tasks, run as `cargo xtask <task>`.
+//!
+//! `oci-image` writes the recipe of the `crpart` container image: rpi-fs-shrink
+//! built with the toolchain the crate pins (`rust-version`) on a pinned Debian
+//! release, with the tools a run shells out to, so CI pipelines get the same
+//! parted, e2fsprogs and rsync on every run. With `--build` it also builds the
+//! image with podman or docker.
+
+use anyhow::{bail, Context, Result};
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+/// Debian release both stages are built on
+const DEBIAN: &str = "bookworm";
+
+/// Packages holding the tools rpi-fs-shrink checks for, plus those its options add
+const PACKAGES: &[&str] = &[
+    "btrfs-progs",
+    "ca-certificates",
+    "cpio",
+    "dosfstools",
+    "e2fsprogs",
+    "fdisk",
+    "gdisk",
+    "kpartx",
+    "mount",
+    "parted",
+    "rsync",
+    "tar",
+    "util-linux",
+    "xz-utils",
+    "zstd",
+];
+
+const USAGE: &str = "Usage: cargo xtask oci-image [--out DIR] [--tag NAME] [--build] [--engine podman|docker]
+
+  --out DIR       Where to write the Containerfile (default target/oci)
+  --tag NAME      Image name (default crpart:<version>)
+  --build         Build the image after writing the recipe
+  --engine NAME   podman or docker (default: podman if installed)";
+
+fn workspace_root() -> PathBuf {
+    Path::new(env!("CARGO_MANIFEST_DIR")).parent().expect("xtask lives in the workspace").to_path_buf()
+}
+
+/// `key = "value"` from the [package] table of the root Cargo.toml
+fn package_field(manifest: &str, key: &str) -> Option<String> {
+    manifest
+        .lines()
+        .skip_while(|line| line.trim() != "[package]")
+        .skip(1)
+        .take_while(|line| !line.trim_start().starts_with('['))
+        .find_map(|line| {
+            let (name, value) = line.split_once('=')?;
+            (name.trim() == key).then(|| value.trim().trim_matches('"').to_string())
+        })
+}
+
+fn containerfile(version: &str, rust_version: &str) -> String {
+    format!(
+        r#"# crpart {version}: rpi-fs-shrink and the tools it runs.
+# Generated by `cargo xtask oci-image`; change xtask/src/main.rs rather than this file.
+#
+#   podman run --rm --privileged -v "$PWD:/work" crpart:{version} -d /work/disk.img -r 8G
+#   podman run --rm --cap-add SYS_ADMIN --cap-add MKNOD --device /dev/sdb crpart:{version} -d /dev/sdb -r 16G
+#
+# Runs mount filesystems and create partition nodes, so they need --privileged, or
+# CAP_SYS_ADMIN and CAP_MKNOD with the disk passed in by --device; image files also need
+# the loop devices. The image sets RPI_FS_SHRINK_UNATTENDED=1, so runs don't stop at the
+# confirmations; deleting old data still needs --yes.
+
+FROM docker.io/library/rust:{rust_version}-slim-{DEBIAN} AS build
+WORKDIR /src
+COPY Cargo.toml ./
+COPY src src
+COPY gui gui
+COPY xtask xtask
+RUN cargo build --release --bin rpi-fs-shrink
+
+FROM docker.io/library/debian:{DEBIAN}-slim
+RUN apt-get update \
+    && apt-get install --yes --no-install-recommends {packages} \
+    && rm -rf /var/lib/apt/lists/*
+COPY --from=build /src/target/release/rpi-fs-shrink /usr/local/bin/rpi-fs-shrink
+ENV container=oci RPI_FS_SHRINK_UNATTENDED=1
+LABEL org.opencontainers.image.title="crpart" \
+      org.opencontainers.image.version="{version}" \
+      org.opencontainers.image.source="https://github.com/greenpdx/crpart" \
+      org.opencontainers.image.licenses="MIT"
+WORKDIR /work
+ENTRYPOINT ["rpi-fs-shrink"]
+"#,
+        packages = PACKAGES.join(" ")
+    )
+}
+
+fn oci_image(mut args: impl Iterator<Item = String>) -> Result<()> {
+    let root = workspace_root();
+    let mut out = root.join("target/oci");
+    let (mut tag, mut build, mut engine) = (None, false, None);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--out" => out = PathBuf::from(args.next().context("--out needs a directory")?),
+            "--tag" => tag = Some(args.next().context("--tag needs a name")?),
+            "--build" => build = true,
+            "--engine" => engine = Some(args.next().context("--engine needs podman or docker")?),
+            _ => bail!("Unknown option {}\n\n{}", arg, USAGE),
+        }
+    }
+
+    let manifest = std::fs::read_to_string(root.join("Cargo.toml")).context("Failed to read Cargo.toml")?;
+    let version = package_field(&manifest, "version").context("Cargo.toml has no package version")?;
+    let rust_version = package_field(&manifest, "rust-version").context("Cargo.toml has no rust-version to pin")?;
+    std::fs::create_dir_all(&out).context(format!("Failed to create {}", out.display()))?;
+    let recipe = out.join("Containerfile");
+    std::fs::write(&recipe, containerfile(&version, &rust_version)).context(format!("Failed to write {}", recipe.display()))?;
+    println!("Wrote {}", recipe.display());
+
+    let tag = tag.unwrap_or_else(|| format!("crpart:{}", version));
+    let engine = match engine {
+        Some(engine) => engine,
+        None if Command::new("podman").arg("--version").output().is_ok() => "podman".to_string(),
+        None => "docker".to_string(),
+    };
+    if !build {
+        println!("Build it with: {} build -f {} -t {} {}", engine, recipe.display(), tag, root.display());
+        return Ok(());
+    }
+    let status = Command::new(&engine)
+        .arg("build")
+        .arg("-f")
+        .arg(&recipe)
+        .args(["-t", &tag])
+        .arg(&root)
+        .status()
+        .context(format!("Failed to run {}", engine))?;
+    if !status.success() {
+        bail!("{} build failed", engine);
+    }
+    println!("Built {}", tag);
+    Ok(())
+}
+
+fn main() -> Result<()> {
+    let mut args = std::env::args().skip(1);
+    match args.next().as_deref() {
+        Some("oci-image") => oci_image(args),
+        Some("-h" | "--help") | None => {
+            println!("{}", USAGE);
+            Ok(())
+        }
+        Some(task) => bail!("Unknown task {}\n\n{}", task, USAGE),
+    }
+}